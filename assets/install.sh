@@ -19,6 +19,7 @@ install -Dm755  ${STRIP} ${CARGO_TARGET_DIR}/${TRIPLE}release/kumod -t ${PREFIX}
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/kcli -t ${PREFIX}/sbin
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/traffic-gen -t ${PREFIX}/sbin
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/tailer -t ${PREFIX}/sbin
+install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/spool-util -t ${PREFIX}/sbin
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/toml2jsonc -t ${PREFIX}/sbin
 install -Dsm755 ${CARGO_TARGET_DIR}/${TRIPLE}release/tls-probe -t ${PREFIX}/sbin
 install -Dm755 assets/accounting.sh -t ${PREFIX}/sbin
@@ -6,8 +6,13 @@ local utils = require 'policy-extras.policy_utils'
 local typing = require 'policy-extras.typing'
 local queue_module = require 'policy-extras.queue'
 
-local Any, Map, Option, Record, String =
-  typing.any, typing.map, typing.option, typing.record, typing.string
+local Any, Map, Number, Option, Record, String =
+  typing.any,
+  typing.map,
+  typing.number,
+  typing.option,
+  typing.record,
+  typing.string
 
 local QueueConfig = Record('QueueConfig', {
   _dynamic = queue_module.is_queue_config_option,
@@ -74,9 +79,9 @@ log_hooks:new {
 }
 
 ]]
-function mod:new(options)
-  local options = LogHookOptions(options)
-
+-- Wire up the log hook and the queue that delivers its records,
+-- using protocol to deliver them
+local function configure(options, protocol)
   if mod.CONFIGURED[options.name] then
     error(
       string.format(
@@ -99,8 +104,6 @@ function mod:new(options)
   -- legitimate TLD. This helps to avoid collision with real
   -- functioning SMTP domains
   local domain_name = string.format('%s.log_hook', options.name)
-  -- Now derive a constructor event name from that
-  local constructor_name = string.format('make.%s', domain_name)
 
   kumo.on('should_enqueue_log_record', function(msg, hook_name)
     if hook_name ~= options.name then
@@ -125,11 +128,7 @@ function mod:new(options)
     max_retry_interval = '20m',
   }
   utils.merge_into(options.queue_config, queue_config)
-  queue_config.protocol = {
-    custom_lua = {
-      constructor = constructor_name,
-    },
-  }
+  queue_config.protocol = protocol
 
   kumo.on(
     'get_queue_config',
@@ -139,11 +138,24 @@ function mod:new(options)
         return
       end
 
-      -- Use the `make.NAME.log_hook` event to handle delivery
-      -- of webhook log records
       return kumo.make_queue_config(queue_config)
     end
   )
+end
+
+function mod:new(options)
+  local options = LogHookOptions(options)
+
+  -- Derive a constructor event name from the name of the hook
+  local constructor_name = string.format('make.%s.log_hook', options.name)
+
+  -- Use the `make.NAME.log_hook` event to handle delivery
+  -- of webhook log records
+  configure(options, {
+    custom_lua = {
+      constructor = constructor_name,
+    },
+  })
 
   -- And connect up the constructor event to the user-provided constructor
   kumo.on(constructor_name, options.constructor)
@@ -154,6 +166,10 @@ local JsonLogHookOptions = Record('JsonLogHookOptions', {
   log_parameters = Option(Map(String, Any)),
   queue_config = Option(QueueConfig),
   url = String,
+  headers = Option(Map(String, String)),
+  timeout = Option(String),
+  batch_size = Option(Number),
+  compression = Option(String),
 })
 
 --[[
@@ -177,52 +193,121 @@ log_hooks:new_json {
   },
   -- The URL to POST the JSON to
   url = "http://10.0.0.1:4242/log",
+  -- Optional additional request headers
+  headers = {
+    Authorization = "Bearer xyz",
+  },
+  -- Optional request timeout; the default is "60s"
+  timeout = "60s",
+  -- When greater than 1, up to batch_size log records are POSTed
+  -- together as a JSON array
+  batch_size = 100,
+  -- Optionally compress the request body using "Gzip" or "Zstd"
+  compression = "Gzip",
 }
 ]]
 function mod:new_json(options)
-  local json_options = JsonLogHookOptions(options)
-  local url = json_options.url
-  options.url = nil
+  local options = JsonLogHookOptions(options)
 
-  options.constructor = function(domain, tenant, campaign)
-    local connection = {}
-    local client = kumo.http.build_client {}
-    function connection:send(message)
-      local response = client
-        :post(url)
-        :header('Content-Type', 'application/json')
-        :body(message:get_data())
-        :send()
+  -- The log record is already JSON, so it is used as the body as-is
+  local http_api = {
+    url = options.url,
+    body_template = '{{ data }}',
+    content_type = 'application/json',
+    headers = options.headers,
+    timeout = options.timeout,
+    batch_size = options.batch_size,
+    batch_format = 'JsonArray',
+    compression = options.compression,
+  }
 
-      local disposition = string.format(
-        '%d %s: %s',
-        response:status_code(),
-        response:status_reason(),
-        response:text()
-      )
+  configure(options, {
+    http_api = http_api,
+  })
+end
 
-      if response:status_is_success() then
-        return disposition
-      end
+local KafkaLogHookOptions = Record('KafkaLogHookOptions', {
+  name = String,
+  log_parameters = Option(Map(String, Any)),
+  queue_config = Option(QueueConfig),
+  producer_config = Map(String, String),
+  topic = String,
+  partition_key = Option(String),
+  timeout = Option(String),
+})
 
-      -- Signal that the webhook request failed.
-      -- In this case the 500 status prevents us from retrying
-      -- the webhook call again, but you could be more sophisticated
-      -- and analyze the disposition to determine if retrying it
-      -- would be useful and generate a 400 status instead.
-      -- In that case, the message we be retryed later, until
-      -- it reached it expiration.
-      kumo.reject(500, disposition)
-    end
+--[[
+local log_hooks = require 'policy-extras.log_hooks'
 
-    function connection:close()
-      client:close()
-    end
+-- Call this at the top level, outside of an event handler
+log_hooks:new_kafka {
+  name = "kafka",
+  -- log_parameters are combined with the name and
+  -- passed through to kumo.configure_log_hook
+  log_parameters = {
+    headers = { 'Subject', 'X-Customer-ID' },
+  },
+  -- librdkafka producer configuration
+  producer_config = {
+    ['bootstrap.servers'] = 'localhost:9092',
+  },
+  topic = 'kumomta-logs',
+  -- Optionally key the records by "Domain", "Tenant", "Campaign"
+  -- or "TenantAndCampaign" of the message that the record describes
+  partition_key = 'Tenant',
+}
+]]
+function mod:new_kafka(options)
+  local options = KafkaLogHookOptions(options)
 
-    return connection
-  end
+  configure(options, {
+    kafka = {
+      producer_config = options.producer_config,
+      topic = options.topic,
+      partition_key = options.partition_key,
+      timeout = options.timeout,
+    },
+  })
+end
 
-  return self:new(options)
+local AmqpLogHookOptions = Record('AmqpLogHookOptions', {
+  name = String,
+  log_parameters = Option(Map(String, Any)),
+  queue_config = Option(QueueConfig),
+  uri = String,
+  exchange = Option(String),
+  routing_key = Option(String),
+  partition_key = Option(String),
+  timeout = Option(String),
+})
+
+--[[
+local log_hooks = require 'policy-extras.log_hooks'
+
+-- Call this at the top level, outside of an event handler
+log_hooks:new_amqp {
+  name = "amqp",
+  uri = 'amqp://localhost:5672',
+  exchange = 'kumomta',
+  -- Either use a fixed routing key...
+  routing_key = 'logs',
+  -- ...or derive it from "Domain", "Tenant", "Campaign"
+  -- or "TenantAndCampaign" of the message that the record describes
+  -- partition_key = 'Tenant',
+}
+]]
+function mod:new_amqp(options)
+  local options = AmqpLogHookOptions(options)
+
+  configure(options, {
+    amqp = {
+      uri = options.uri,
+      exchange = options.exchange,
+      routing_key = options.routing_key,
+      partition_key = options.partition_key,
+      timeout = options.timeout,
+    },
+  })
 end
 
 return mod
@@ -176,4 +176,66 @@ function mod.split_ip_port(ip_port)
   return ip, port
 end
 
+-- Given an RFC 3339 timestamp, such as the expiry time of a suspension,
+-- return the number of seconds since the unix epoch that it represents.
+-- Timestamps must be compared by value rather than as strings, because
+-- their fractional seconds may have differing precision, and their
+-- offsets may differ.
+function mod.rfc3339_to_epoch(ts)
+  local year, month, day, hour, min, sec, frac, offset = string.match(
+    ts,
+    '^(%d%d%d%d)-(%d%d)-(%d%d)[Tt ](%d%d):(%d%d):(%d%d)(%.?%d*)(.*)$'
+  )
+  if not year then
+    error(string.format('invalid RFC 3339 timestamp %q', ts), 2)
+  end
+
+  local offset_seconds = 0
+  if offset ~= 'Z' and offset ~= 'z' then
+    local sign, offset_hour, offset_min =
+      string.match(offset, '^([+-])(%d%d):(%d%d)$')
+    if not sign then
+      error(string.format('invalid RFC 3339 timestamp %q', ts), 2)
+    end
+    offset_seconds = tonumber(offset_hour) * 3600 + tonumber(offset_min) * 60
+    if sign == '-' then
+      offset_seconds = -offset_seconds
+    end
+  end
+
+  -- Days since the epoch in the proleptic gregorian calendar; see
+  -- <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+  year, month, day = tonumber(year), tonumber(month), tonumber(day)
+  if month <= 2 then
+    year = year - 1
+  end
+  local era = year // 400
+  local year_of_era = year - era * 400
+  local day_of_year = (153 * ((month + 9) % 12) + 2) // 5 + day - 1
+  local day_of_era = year_of_era * 365
+    + year_of_era // 4
+    - year_of_era // 100
+    + day_of_year
+  local days = era * 146097 + day_of_era - 719468
+
+  return days * 86400
+    + tonumber(hour) * 3600
+    + tonumber(min) * 60
+    + tonumber(sec)
+    + (tonumber('0' .. frac) or 0)
+    - offset_seconds
+end
+
+function mod:test()
+  mod.assert_eq(mod.rfc3339_to_epoch '1970-01-01T00:00:00Z', 0)
+  mod.assert_eq(mod.rfc3339_to_epoch '2024-02-29T12:00:00Z', 1709208000)
+  mod.assert_eq(mod.rfc3339_to_epoch '2024-02-29T13:30:00+01:30', 1709208000)
+  mod.assert_eq(mod.rfc3339_to_epoch '2024-02-29T12:00:00.5Z', 1709208000.5)
+
+  -- The string comparison of these would order them the other way around
+  local coarse = mod.rfc3339_to_epoch '2024-02-29T12:00:01Z'
+  local fine = mod.rfc3339_to_epoch '2024-02-29T12:00:00.999999+00:00'
+  assert(coarse > fine, 'fractional seconds are ordered by value')
+end
+
 return mod
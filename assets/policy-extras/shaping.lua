@@ -194,6 +194,32 @@ local function apply_sched_q_suspension(item)
   end
 end
 
+local function apply_source_exclusion(item)
+  local reason =
+    string.format('%s (rule_hash=%s)', item.reason, item.rule_hash)
+
+  -- don't shorten an existing exclusion, whether it was
+  -- put in place by this rule or by some other means
+  local expires = utils.rfc3339_to_epoch(item.expires)
+  for _, v in
+    ipairs(kumo.source_exclusion.list {
+      source = item.source,
+      destination = item.destination,
+    })
+  do
+    if utils.rfc3339_to_epoch(v.expires) >= expires then
+      return
+    end
+  end
+
+  kumo.source_exclusion.exclude {
+    source = item.source,
+    destination = item.destination,
+    reason = reason,
+    expires = item.expires,
+  }
+end
+
 kumo.on('kumo.tsa.config.monitor', function(args)
   local last_hash = ''
   print 'TSA config monitor running'
@@ -230,6 +256,8 @@ local function process_suspension_subscriptions(url)
       apply_ready_q_suspension(data.ReadyQ)
     elseif data.SchedQ then
       apply_sched_q_suspension(data.SchedQ)
+    elseif data.SourceExclusion then
+      apply_source_exclusion(data.SourceExclusion)
     end
   end
 end
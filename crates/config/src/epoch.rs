@@ -1,3 +1,4 @@
+use anyhow::Context;
 use filenamegen::Glob;
 use once_cell::sync::Lazy;
 use parking_lot::FairMutex as Mutex;
@@ -7,6 +8,7 @@ use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::watch::{channel, Receiver, Sender};
 use tokio::task::spawn_blocking;
 
@@ -117,7 +119,9 @@ impl ConfigurationParams {
                             tracing::info!("config_epoch_task: config change detected {hash:?}");
                             current_hash = hash.clone();
 
-                            bump_current_epoch();
+                            if let Err(err) = validate_and_bump_current_epoch().await {
+                                tracing::error!("config_epoch_task: {err:#}");
+                            }
                         }
                     }
                     Err(err) => {
@@ -136,9 +140,22 @@ impl ConfigurationParams {
 
 pub fn bump_current_epoch() {
     let epoch = 1 + EPOCH.fetch_add(1, Ordering::SeqCst);
+    crate::forget_compiled_policy();
     CONFIG.lock().sender.send(ConfigEpoch(epoch)).ok();
 }
 
+/// Verifies that the policy can be loaded before bumping the epoch,
+/// so that a broken policy change doesn't cause the cached state that
+/// was built from the working policy to be discarded. Returns the
+/// new epoch.
+pub async fn validate_and_bump_current_epoch() -> anyhow::Result<ConfigEpoch> {
+    crate::validate_policy()
+        .await
+        .context("new policy failed to load; the configuration epoch was not changed")?;
+    bump_current_epoch();
+    Ok(get_current_epoch())
+}
+
 pub fn get_current_epoch() -> ConfigEpoch {
     ConfigEpoch(EPOCH.load(Ordering::SeqCst))
 }
@@ -170,4 +187,20 @@ pub fn start_monitor() {
             tracing::error!("config_epoch_task: {err:#}");
         }
     });
+    tokio::spawn(async move {
+        let mut sig_hup = match signal(SignalKind::hangup()) {
+            Ok(sig_hup) => sig_hup,
+            Err(err) => {
+                tracing::error!("config reload: failed to listen for SIGHUP: {err:#}");
+                return;
+            }
+        };
+        while sig_hup.recv().await.is_some() {
+            tracing::info!("config reload: SIGHUP received");
+            match validate_and_bump_current_epoch().await {
+                Ok(epoch) => tracing::info!("config reload: now at {epoch:?}"),
+                Err(err) => tracing::error!("config reload: {err:#}"),
+            }
+        }
+    });
 }
@@ -1,7 +1,14 @@
-use crate::pool::{pool_get, pool_put};
-pub use crate::pool::{set_gc_on_put, set_max_age, set_max_spare, set_max_use};
+use crate::epoch::{get_current_epoch, ConfigEpoch};
+use crate::limits::with_event_limits;
+pub use crate::limits::{
+    set_event_timeout, set_instruction_limit, set_memory_limit, EventLimit, EventLimitExceeded,
+};
+use crate::pool::{pool_get, pool_put, start_prewarm};
+pub use crate::pool::{set_gc_on_put, set_max_age, set_max_spare, set_max_use, set_min_spare};
 use anyhow::Context;
-use mlua::{FromLua, FromLuaMulti, IntoLuaMulti, Lua, LuaSerdeExt, RegistryKey, Table, Value};
+use mlua::{
+    ChunkMode, FromLua, FromLuaMulti, IntoLuaMulti, Lua, LuaSerdeExt, RegistryKey, Table, Value,
+};
 use once_cell::sync::Lazy;
 use parking_lot::FairMutex as Mutex;
 use prometheus::{CounterVec, HistogramTimer, HistogramVec};
@@ -10,9 +17,11 @@ use std::borrow::Cow;
 use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
 
 pub mod epoch;
+mod limits;
 mod pool;
 
 lazy_static::lazy_static! {
@@ -30,9 +39,22 @@ lazy_static::lazy_static! {
             "lua_count", "the number of lua contexts currently alive");
         metrics::gauge!("lua_count")
     };
+    static ref LUA_IN_USE_COUNT: metrics::Gauge = {
+        metrics::describe_gauge!(
+            "lua_in_use_count",
+            "the number of lua contexts currently being used to run \
+             event handlers, rather than sitting spare in the pool");
+        metrics::gauge!("lua_in_use_count")
+    };
     static ref CALLBACK_ALLOWS_MULTIPLE: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
 }
 
+/// The policy script compiled to lua bytecode at the first pool miss
+/// in the current configuration epoch, so that subsequent contexts
+/// created within the same epoch can skip reading and parsing the
+/// source. It is discarded when the epoch is bumped.
+static COMPILED_POLICY: Lazy<Mutex<Option<CompiledPolicy>>> = Lazy::new(|| Mutex::new(None));
+
 pub static VALIDATE_ONLY: AtomicBool = AtomicBool::new(false);
 pub static VALIDATION_FAILED: AtomicBool = AtomicBool::new(false);
 static LATENCY_HIST: Lazy<HistogramVec> = Lazy::new(|| {
@@ -65,11 +87,73 @@ fn latency_timer(label: &str) -> HistogramTimer {
         .start_timer()
 }
 
+struct CompiledPolicy {
+    path: PathBuf,
+    epoch: ConfigEpoch,
+    bytecode: Arc<Vec<u8>>,
+}
+
+/// Discards the compiled policy; called when the epoch is bumped
+pub(crate) fn forget_compiled_policy() {
+    COMPILED_POLICY.lock().take();
+}
+
+/// Loads the policy at path into lua as a function. If use_compiled_policy
+/// is true, the bytecode that is held in cache for the epoch is used,
+/// and otherwise the policy file is read and compiled, retaining the
+/// bytecode in the cache if use_compiled_policy is true.
+async fn load_policy<'lua>(
+    lua: &'lua Lua,
+    cache: &Mutex<Option<CompiledPolicy>>,
+    path: &PathBuf,
+    epoch: &ConfigEpoch,
+    use_compiled_policy: bool,
+) -> anyhow::Result<mlua::Function<'lua>> {
+    if use_compiled_policy {
+        let compiled = cache
+            .lock()
+            .as_ref()
+            .filter(|compiled| compiled.path == *path && compiled.epoch == *epoch)
+            .map(|compiled| compiled.bytecode.clone());
+        if let Some(bytecode) = compiled {
+            return Ok(lua
+                .load(bytecode.as_slice())
+                .set_name(path.to_string_lossy())
+                .set_mode(ChunkMode::Binary)
+                .into_function()?);
+        }
+    }
+
+    let code = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("reading policy file {path:?}"))?;
+    let func = lua
+        .load(&code)
+        .set_name(path.to_string_lossy())
+        .into_function()?;
+
+    if use_compiled_policy {
+        cache.lock().replace(CompiledPolicy {
+            path: path.clone(),
+            epoch: epoch.clone(),
+            // Retain the debug information, so that errors and
+            // tracebacks continue to report line numbers
+            bytecode: Arc::new(func.dump(false)),
+        });
+    }
+    Ok(func)
+}
+
 #[derive(Debug)]
 struct LuaConfigInner {
     lua: Lua,
     created: Instant,
     use_count: usize,
+    /// The configuration epoch in which the policy was loaded
+    epoch: ConfigEpoch,
+    /// Isolated contexts have had their environment altered, and
+    /// are discarded rather than returned to the pool
+    isolated: bool,
 }
 
 impl Drop for LuaConfigInner {
@@ -85,8 +169,11 @@ pub struct LuaConfig {
 
 impl Drop for LuaConfig {
     fn drop(&mut self) {
+        LUA_IN_USE_COUNT.decrement(1.);
         if let Some(inner) = self.inner.take() {
-            pool_put(inner);
+            if !inner.isolated {
+                pool_put(inner);
+            }
         }
     }
 }
@@ -94,6 +181,9 @@ impl Drop for LuaConfig {
 pub async fn set_policy_path(path: PathBuf) -> anyhow::Result<()> {
     POLICY_FILE.lock().replace(path);
     load_config().await?;
+    if !is_validating() {
+        start_prewarm();
+    }
     Ok(())
 }
 
@@ -121,9 +211,41 @@ pub async fn load_config() -> anyhow::Result<LuaConfig> {
         return Ok(pool);
     }
 
+    new_config(true, None).await
+}
+
+/// Loads the policy into a fresh lua context that is never shared
+/// with other callers. prepare is called once the modules have been
+/// registered, but before the policy is loaded, so that it can alter
+/// the environment seen by the policy.
+pub async fn load_isolated_config(
+    prepare: fn(&Lua) -> anyhow::Result<()>,
+) -> anyhow::Result<LuaConfig> {
+    new_config(true, Some(prepare)).await
+}
+
+/// Loads the policy into a fresh lua context, without consulting the
+/// pool or the compiled policy, to verify that it can be loaded
+/// successfully.
+pub async fn validate_policy() -> anyhow::Result<()> {
+    new_config(false, None).await?;
+    Ok(())
+}
+
+/// Creates a new lua context and loads the policy into it.
+/// If use_compiled_policy is true, the bytecode compiled from the
+/// policy by an earlier call in the current epoch will be used
+/// in preference to reading the policy file again.
+/// If prepare is provided, the context is isolated; see
+/// load_isolated_config.
+async fn new_config(
+    use_compiled_policy: bool,
+    prepare: Option<fn(&Lua) -> anyhow::Result<()>>,
+) -> anyhow::Result<LuaConfig> {
     LUA_LOAD_COUNT.increment(1);
     let lua = Lua::new();
     let created = Instant::now();
+    let epoch = get_current_epoch();
 
     {
         let globals = lua.globals();
@@ -154,29 +276,25 @@ pub async fn load_config() -> anyhow::Result<LuaConfig> {
         (func)(&lua)?;
     }
 
-    if let Some(policy) = get_policy_path() {
-        let code = tokio::fs::read_to_string(&policy)
-            .await
-            .with_context(|| format!("reading policy file {policy:?}"))?;
-
-        let func = {
-            let chunk = lua.load(&code);
-            let chunk = chunk.set_name(policy.to_string_lossy());
-            chunk.into_function()?
-        };
+    if let Some(prepare) = prepare {
+        (prepare)(&lua)?;
+    }
 
+    if let Some(policy) = get_policy_path() {
+        let func =
+            load_policy(&lua, &COMPILED_POLICY, &policy, &epoch, use_compiled_policy).await?;
         let _timer = latency_timer("context-creation");
         func.call_async(()).await?;
     }
     LUA_COUNT.increment(1.);
 
-    Ok(LuaConfig {
-        inner: Some(LuaConfigInner {
-            lua,
-            created,
-            use_count: 1,
-        }),
-    })
+    Ok(LuaConfig::from_inner(LuaConfigInner {
+        lua,
+        created,
+        use_count: 1,
+        epoch,
+        isolated: prepare.is_some(),
+    }))
 }
 
 pub fn register(func: RegisterFunc) {
@@ -184,6 +302,11 @@ pub fn register(func: RegisterFunc) {
 }
 
 impl LuaConfig {
+    pub(crate) fn from_inner(inner: LuaConfigInner) -> Self {
+        LUA_IN_USE_COUNT.increment(1.);
+        Self { inner: Some(inner) }
+    }
+
     fn set_current_event(&mut self, name: &str) -> mlua::Result<()> {
         self.inner
             .as_mut()
@@ -211,7 +334,7 @@ impl LuaConfig {
         {
             Ok(func) => {
                 let _timer = latency_timer(name);
-                Ok(func.call_async(args).await?)
+                with_event_limits(&lua.lua, name, async { Ok(func.call_async(args).await?) }).await
             }
             _ => anyhow::bail!("{name} has not been registered"),
         }
@@ -229,7 +352,7 @@ impl LuaConfig {
         let name = sig.name();
         self.set_current_event(name)?;
         let lua = self.inner.as_mut().unwrap();
-        async_call_callback(&lua.lua, sig, args).await
+        with_event_limits(&lua.lua, name, async_call_callback(&lua.lua, sig, args)).await
     }
 
     pub async fn async_call_callback_non_default<
@@ -244,7 +367,12 @@ impl LuaConfig {
         let name = sig.name();
         self.set_current_event(name)?;
         let lua = self.inner.as_mut().unwrap();
-        async_call_callback_non_default(&lua.lua, sig, args).await
+        with_event_limits(
+            &lua.lua,
+            name,
+            async_call_callback_non_default(&lua.lua, sig, args),
+        )
+        .await
     }
 
     pub async fn async_call_callback_non_default_opt<
@@ -257,51 +385,14 @@ impl LuaConfig {
         args: A,
     ) -> anyhow::Result<Option<R>> {
         let name = sig.name();
-        let decorated_name = sig.decorated_name();
         self.set_current_event(name)?;
         let lua = self.inner.as_mut().unwrap();
-
-        if sig.allow_multiple() {
-            return match lua
-                .lua
-                .named_registry_value::<mlua::Value>(&decorated_name)?
-            {
-                Value::Table(tbl) => {
-                    for func in tbl.sequence_values::<mlua::Function>() {
-                        let func = func?;
-                        let _timer = latency_timer(name);
-                        let result: mlua::MultiValue = func.call_async(args.clone()).await?;
-                        if result.is_empty() {
-                            // Continue with other handlers
-                            continue;
-                        }
-                        let result = R::from_lua_multi(result, &lua.lua)?;
-                        return Ok(Some(result));
-                    }
-                    Ok(None)
-                }
-                _ => Ok(None),
-            };
-        }
-
-        let opt_func: mlua::Value = lua.lua.named_registry_value(&decorated_name)?;
-
-        match opt_func {
-            Value::Nil => Ok(None),
-            Value::Function(func) => {
-                let _timer = latency_timer(name);
-                let value: Value = func.call_async(args.clone()).await?;
-
-                match value {
-                    Value::Nil => Ok(None),
-                    value => {
-                        let result = R::from_lua(value, &lua.lua)?;
-                        Ok(Some(result))
-                    }
-                }
-            }
-            _ => anyhow::bail!("invalid return type for {name} event"),
-        }
+        with_event_limits(
+            &lua.lua,
+            name,
+            async_call_callback_non_default_opt(&lua.lua, sig, args),
+        )
+        .await
     }
 
     pub fn remove_registry_value(&mut self, value: RegistryKey) -> anyhow::Result<()> {
@@ -336,7 +427,10 @@ impl LuaConfig {
             .named_registry_value::<mlua::Function>(&decorated_name)?;
 
         let _timer = latency_timer(name);
-        let value: Value = func.call_async(args.clone()).await?;
+        let value: Value = with_event_limits(&inner.lua, name, async {
+            Ok(func.call_async(args.clone()).await?)
+        })
+        .await?;
         drop(func);
 
         Ok(inner.lua.create_registry_value(value)?)
@@ -443,6 +537,58 @@ pub async fn async_call_callback_non_default<
     }
 }
 
+pub async fn async_call_callback_non_default_opt<
+    'lua,
+    A: IntoLuaMulti<'lua> + Clone,
+    R: FromLua<'lua>,
+>(
+    lua: &'lua Lua,
+    sig: &CallbackSignature<'lua, A, Option<R>>,
+    args: A,
+) -> anyhow::Result<Option<R>> {
+    let name = sig.name();
+    let decorated_name = sig.decorated_name();
+
+    if sig.allow_multiple() {
+        return match lua.named_registry_value::<mlua::Value>(&decorated_name)? {
+            Value::Table(tbl) => {
+                for func in tbl.sequence_values::<mlua::Function>() {
+                    let func = func?;
+                    let _timer = latency_timer(name);
+                    let result: mlua::MultiValue = func.call_async(args.clone()).await?;
+                    if result.is_empty() {
+                        // Continue with other handlers
+                        continue;
+                    }
+                    let result = R::from_lua_multi(result, lua)?;
+                    return Ok(Some(result));
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        };
+    }
+
+    let opt_func: mlua::Value = lua.named_registry_value(&decorated_name)?;
+
+    match opt_func {
+        Value::Nil => Ok(None),
+        Value::Function(func) => {
+            let _timer = latency_timer(name);
+            let value: Value = func.call_async(args.clone()).await?;
+
+            match value {
+                Value::Nil => Ok(None),
+                value => {
+                    let result = R::from_lua(value, lua)?;
+                    Ok(Some(result))
+                }
+            }
+        }
+        _ => anyhow::bail!("invalid return type for {name} event"),
+    }
+}
+
 pub fn get_or_create_module<'lua>(lua: &'lua Lua, name: &str) -> anyhow::Result<mlua::Table<'lua>> {
     let globals = lua.globals();
     let package: Table = globals.get("package")?;
@@ -611,3 +757,74 @@ pub fn serialize_options() -> mlua::SerializeOptions {
         .serialize_none_to_null(false)
         .serialize_unit_to_null(false)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn register_on(lua: &Lua) -> anyhow::Result<()> {
+        lua.globals().set(
+            "on",
+            lua.create_function(|lua, (name, func): (String, mlua::Function)| {
+                lua.set_named_registry_value(&decorate_callback_name(&name), func)
+            })?,
+        )?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compiled_policy_loads_in_safe_mode() {
+        // Use a cache and policy file of our own, rather than the
+        // global policy path and registered modules
+        let cache = Mutex::new(None);
+        let path = std::env::temp_dir().join(format!("kumo-policy-{}.lua", std::process::id()));
+        let epoch = get_current_epoch();
+        std::fs::write(&path, "on('double', function(n) return n * 2 end)").unwrap();
+        let load = |use_compiled_policy: bool, epoch: ConfigEpoch| {
+            let cache = &cache;
+            let path = &path;
+            async move {
+                // Lua::new is the same safe mode that new_config uses
+                let lua = Lua::new();
+                let sig = CallbackSignature::<i64, i64>::new("double");
+                register_on(&lua).unwrap();
+                load_policy(&lua, cache, path, &epoch, use_compiled_policy)
+                    .await
+                    .unwrap()
+                    .call_async::<_, ()>(())
+                    .await
+                    .unwrap();
+                async_call_callback(&lua, &sig, 21).await.unwrap()
+            }
+        };
+
+        assert_eq!(load(true, epoch.clone()).await, 42);
+        let bytecode = cache.lock().as_ref().unwrap().bytecode.clone();
+
+        // Subsequent contexts in the same epoch load the bytecode,
+        // rather than reading and compiling the file again
+        std::fs::write(&path, "on('double', function(n) return n * 3 end)").unwrap();
+        assert_eq!(load(true, epoch.clone()).await, 42);
+        assert!(Arc::ptr_eq(
+            &cache.lock().as_ref().unwrap().bytecode,
+            &bytecode
+        ));
+
+        // but validation always compiles the file, without caching it
+        assert_eq!(load(false, epoch.clone()).await, 63);
+        assert!(Arc::ptr_eq(
+            &cache.lock().as_ref().unwrap().bytecode,
+            &bytecode
+        ));
+
+        // and a new epoch compiles the file again
+        crate::epoch::bump_current_epoch();
+        assert_eq!(load(true, get_current_epoch()).await, 63);
+        assert!(!Arc::ptr_eq(
+            &cache.lock().as_ref().unwrap().bytecode,
+            &bytecode
+        ));
+
+        std::fs::remove_file(&path).ok();
+    }
+}
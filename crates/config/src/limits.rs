@@ -0,0 +1,355 @@
+//! Limits on the resources that may be consumed by a single invocation
+//! of a lua event handler. Without these, a handler that loops forever,
+//! or that allocates without bound, would wedge the task that called it.
+//!
+//! The instruction budget and the wall clock deadline are both checked
+//! from a hook that lua calls periodically while it is executing, so
+//! that a handler that never yields can still be interrupted. The
+//! deadline is additionally enforced around the whole call, so that a
+//! handler that is waiting on some async operation is also bounded.
+//!
+//! A context in which a limit was exceeded is not returned to the pool,
+//! as the handler may have left it in an inconsistent state.
+use mlua::{HookTriggers, Lua};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref LUA_LIMIT_EXCEEDED: metrics::Counter = {
+        metrics::describe_counter!(
+            "lua_event_limit_exceeded",
+            "how many lua event handlers were aborted because they \
+             exceeded a configured execution limit");
+        metrics::counter!("lua_event_limit_exceeded")
+    };
+}
+
+/// Maximum wall clock time that an event handler may run, in seconds.
+/// 0 means no limit.
+static EVENT_TIMEOUT: AtomicUsize = AtomicUsize::new(0);
+/// Maximum memory that a lua context may use, in bytes. 0 means no limit.
+static MEMORY_LIMIT: AtomicUsize = AtomicUsize::new(0);
+/// Maximum number of lua instructions that an event handler may execute.
+/// 0 means no limit.
+static INSTRUCTION_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// How many instructions are executed between checks of the budget
+const HOOK_INTERVAL: u32 = 1000;
+
+pub fn set_event_timeout(seconds: usize) {
+    EVENT_TIMEOUT.store(seconds, Ordering::Relaxed);
+}
+
+pub fn set_memory_limit(bytes: usize) {
+    MEMORY_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+pub fn set_instruction_limit(count: usize) {
+    INSTRUCTION_LIMIT.store(count, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventLimit {
+    /// The wall clock timeout, in seconds
+    Timeout(usize),
+    /// The memory ceiling, in bytes
+    Memory(usize),
+    /// The instruction budget
+    Instructions(usize),
+}
+
+impl std::fmt::Display for EventLimit {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Timeout(seconds) => write!(fmt, "timeout of {seconds} seconds"),
+            Self::Memory(bytes) => write!(fmt, "memory limit of {bytes} bytes"),
+            Self::Instructions(count) => write!(fmt, "instruction limit of {count}"),
+        }
+    }
+}
+
+/// The error produced when an event handler exceeds one of
+/// the configured limits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventLimitExceeded {
+    pub event: String,
+    pub limit: EventLimit,
+}
+
+impl std::fmt::Display for EventLimitExceeded {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "lua event handler {} exceeded its {}",
+            self.event, self.limit
+        )
+    }
+}
+
+impl std::error::Error for EventLimitExceeded {}
+
+impl EventLimitExceeded {
+    /// Returns the EventLimitExceeded error held by err, if any
+    pub fn from_anyhow(err: &anyhow::Error) -> Option<&Self> {
+        err.downcast_ref::<Self>()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    timeout: usize,
+    memory: usize,
+    instructions: usize,
+}
+
+impl Limits {
+    fn current() -> Self {
+        Self {
+            timeout: EVENT_TIMEOUT.load(Ordering::Relaxed),
+            memory: MEMORY_LIMIT.load(Ordering::Relaxed),
+            instructions: INSTRUCTION_LIMIT.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Tracks the consumption of the current event handler invocation;
+/// held in the app data of the lua context while the handler runs
+struct EventBudget {
+    deadline: Option<Instant>,
+    remaining: Option<usize>,
+    limits: Limits,
+    exceeded: Option<EventLimit>,
+}
+
+impl EventBudget {
+    fn new(limits: Limits) -> Self {
+        Self {
+            deadline: (limits.timeout > 0)
+                .then(|| Instant::now() + Duration::from_secs(limits.timeout as u64)),
+            remaining: (limits.instructions > 0).then_some(limits.instructions),
+            limits,
+            exceeded: None,
+        }
+    }
+
+    /// Account for the execution of `instructions` more instructions,
+    /// returning the limit that has been exceeded, if any
+    fn consume(&mut self, instructions: usize) -> Option<EventLimit> {
+        if let Some(remaining) = self.remaining.as_mut() {
+            if *remaining <= instructions {
+                self.exceeded = Some(EventLimit::Instructions(self.limits.instructions));
+                return self.exceeded;
+            }
+            *remaining -= instructions;
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                self.exceeded = Some(EventLimit::Timeout(self.limits.timeout));
+                return self.exceeded;
+            }
+        }
+        None
+    }
+}
+
+/// Marks a context in which a limit was exceeded
+struct Tainted;
+
+/// Returns true if a limit was exceeded by an event handler that
+/// ran in this context, which means that it must not be reused
+pub(crate) fn is_tainted(lua: &Lua) -> bool {
+    lua.app_data_ref::<Tainted>().is_some()
+}
+
+fn is_memory_error(err: &mlua::Error) -> bool {
+    match err {
+        mlua::Error::MemoryError(_) => true,
+        mlua::Error::CallbackError { cause, .. } => is_memory_error(cause),
+        mlua::Error::WithContext { cause, .. } => is_memory_error(cause),
+        _ => false,
+    }
+}
+
+/// Runs future, which is expected to be calling the handler for
+/// event in lua, subject to the currently configured limits.
+pub(crate) async fn with_event_limits<R, FUT>(
+    lua: &Lua,
+    event: &str,
+    future: FUT,
+) -> anyhow::Result<R>
+where
+    FUT: Future<Output = anyhow::Result<R>>,
+{
+    with_limits(lua, event, Limits::current(), future).await
+}
+
+async fn with_limits<R, FUT>(
+    lua: &Lua,
+    event: &str,
+    limits: Limits,
+    future: FUT,
+) -> anyhow::Result<R>
+where
+    FUT: Future<Output = anyhow::Result<R>>,
+{
+    // A handler may cause another event to be dispatched in the same
+    // context. That nested call is accounted to the budget of the
+    // outermost call, whose hook remains in place throughout; replacing
+    // the budget here, and then removing it when the nested call
+    // completes, would leave the rest of the outer handler unlimited.
+    if lua.app_data_ref::<EventBudget>().is_some() {
+        return future.await;
+    }
+
+    lua.set_memory_limit(limits.memory)?;
+
+    let use_hook = limits.timeout > 0 || limits.instructions > 0;
+    if use_hook {
+        lua.set_app_data(EventBudget::new(limits));
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+            |lua, _debug| {
+                let Some(mut budget) = lua.app_data_mut::<EventBudget>() else {
+                    return Ok(());
+                };
+                match budget.consume(HOOK_INTERVAL as usize) {
+                    Some(limit) => Err(mlua::Error::RuntimeError(format!(
+                        "event handler exceeded its {limit}"
+                    ))),
+                    None => Ok(()),
+                }
+            },
+        );
+    }
+
+    let result = if limits.timeout > 0 {
+        tokio::time::timeout(Duration::from_secs(limits.timeout as u64), future)
+            .await
+            .map_err(|_| EventLimit::Timeout(limits.timeout))
+    } else {
+        Ok(future.await)
+    };
+
+    if use_hook {
+        lua.remove_hook();
+    }
+    let budget = lua.remove_app_data::<EventBudget>();
+
+    let exceeded = match &result {
+        Err(limit) => Some(*limit),
+        Ok(Err(err)) => budget.and_then(|budget| budget.exceeded).or_else(|| {
+            (limits.memory > 0
+                && err
+                    .downcast_ref::<mlua::Error>()
+                    .map(is_memory_error)
+                    .unwrap_or(false))
+            .then_some(EventLimit::Memory(limits.memory))
+        }),
+        Ok(Ok(_)) => None,
+    };
+
+    match exceeded {
+        Some(limit) => {
+            let err = EventLimitExceeded {
+                event: event.to_string(),
+                limit,
+            };
+            tracing::error!(event = %err.event, limit = %err.limit, "{err}");
+            LUA_LIMIT_EXCEEDED.increment(1);
+            lua.set_app_data(Tainted);
+            Err(err.into())
+        }
+        None => result.expect("timeout was handled above"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn instruction_limit(instructions: usize) -> Limits {
+        Limits {
+            timeout: 0,
+            memory: 0,
+            instructions,
+        }
+    }
+
+    fn assert_exceeded<R: std::fmt::Debug>(result: anyhow::Result<R>, event: &str) {
+        let err = result.unwrap_err();
+        assert_eq!(
+            EventLimitExceeded::from_anyhow(&err),
+            Some(&EventLimitExceeded {
+                event: event.to_string(),
+                limit: EventLimit::Instructions(100_000),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn looping_handler_is_interrupted() {
+        let lua = Lua::new();
+        let func: mlua::Function = lua
+            .load("return function() while true do end end")
+            .eval()
+            .unwrap();
+
+        let result: anyhow::Result<()> =
+            with_limits(&lua, "looping", instruction_limit(100_000), async {
+                Ok(func.call_async(()).await?)
+            })
+            .await;
+        assert_exceeded(result, "looping");
+        assert!(is_tainted(&lua));
+        assert!(lua.app_data_ref::<EventBudget>().is_none());
+    }
+
+    #[tokio::test]
+    async fn nested_handler_keeps_outer_budget() {
+        let lua = Lua::new();
+        let inner = lua
+            .create_async_function(|lua, ()| async move {
+                let func: mlua::Function = lua.load("return function() return 1 end").eval()?;
+                with_limits(lua, "inner", instruction_limit(100_000), async {
+                    Ok(func.call_async::<_, i64>(()).await?)
+                })
+                .await
+                .map_err(mlua::Error::external)
+            })
+            .unwrap();
+        lua.globals().set("inner", inner).unwrap();
+
+        // Once the nested call has completed, the outer handler must
+        // still be subject to its own budget
+        let func: mlua::Function = lua
+            .load("return function() inner() while true do end end")
+            .eval()
+            .unwrap();
+        let result: anyhow::Result<()> =
+            with_limits(&lua, "outer", instruction_limit(100_000), async {
+                Ok(func.call_async(()).await?)
+            })
+            .await;
+        assert_exceeded(result, "outer");
+    }
+
+    #[test]
+    fn budget() {
+        let mut budget = EventBudget::new(Limits {
+            timeout: 0,
+            memory: 0,
+            instructions: 2500,
+        });
+        assert_eq!(budget.consume(1000), None);
+        assert_eq!(budget.consume(1000), None);
+        assert_eq!(budget.consume(1000), Some(EventLimit::Instructions(2500)));
+
+        let mut budget = EventBudget::new(Limits {
+            timeout: 0,
+            memory: 0,
+            instructions: 0,
+        });
+        assert_eq!(budget.consume(usize::MAX), None);
+    }
+}
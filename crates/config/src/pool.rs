@@ -1,8 +1,11 @@
+use crate::epoch::get_current_epoch;
+use crate::limits::is_tainted;
 use crate::{LuaConfig, LuaConfigInner};
 use parking_lot::FairMutex as Mutex;
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
+use tokio::sync::Notify;
 
 lazy_static::lazy_static! {
     static ref POOL: Mutex<Pool> = Mutex::new(Pool::new());
@@ -12,14 +15,40 @@ lazy_static::lazy_static! {
             "the number of lua contexts available for reuse in the pool");
         metrics::gauge!("lua_spare_count")
     };
+    static ref LUA_POOL_HIT_COUNT: metrics::Counter = {
+        metrics::describe_counter!(
+            "lua_pool_hit_count",
+            "how many times a spare lua context was taken from the pool");
+        metrics::counter!("lua_pool_hit_count")
+    };
+    static ref LUA_POOL_MISS_COUNT: metrics::Counter = {
+        metrics::describe_counter!(
+            "lua_pool_miss_count",
+            "how many times a lua context was needed but the pool \
+             had no spare contexts, so one had to be created");
+        metrics::counter!("lua_pool_miss_count")
+    };
+    static ref LUA_PREWARM_COUNT: metrics::Counter = {
+        metrics::describe_counter!(
+            "lua_prewarm_count",
+            "how many lua contexts have been created in the background \
+             in order to keep the pool topped up");
+        metrics::counter!("lua_prewarm_count")
+    };
 }
 
+/// Wakes up the prewarm task when the pool has been drawn down
+static PREWARM_WAKEUP: Notify = Notify::const_new();
+static PREWARM_STARTED: AtomicBool = AtomicBool::new(false);
+
 /// Maximum age of a lua context before we release it, in seconds
 static MAX_AGE: AtomicUsize = AtomicUsize::new(300);
 /// Maximum number of uses of a given lua context before we release it
 static MAX_USE: AtomicUsize = AtomicUsize::new(1024);
 /// Maximum number of spare lua contexts to maintain in the pool
 static MAX_SPARE: AtomicUsize = AtomicUsize::new(8192);
+/// Number of spare lua contexts that we try to keep ready in the pool
+static MIN_SPARE: AtomicUsize = AtomicUsize::new(8);
 static GC_ON_PUT: AtomicUsize = AtomicUsize::new(0);
 
 pub fn set_max_use(max_use: usize) {
@@ -30,6 +59,11 @@ pub fn set_max_spare(max_spare: usize) {
     MAX_SPARE.store(max_spare, Ordering::Relaxed);
 }
 
+pub fn set_min_spare(min_spare: usize) {
+    MIN_SPARE.store(min_spare, Ordering::Relaxed);
+    PREWARM_WAKEUP.notify_one();
+}
+
 pub fn set_max_age(max_age: usize) {
     MAX_AGE.store(max_age, Ordering::Relaxed);
 }
@@ -59,7 +93,9 @@ impl Pool {
     pub fn expire(&mut self) {
         let len_before = self.pool.len();
         let max_age = Duration::from_secs(MAX_AGE.load(Ordering::Relaxed) as u64);
-        self.pool.retain(|inner| inner.created.elapsed() < max_age);
+        let epoch = get_current_epoch();
+        self.pool
+            .retain(|inner| inner.created.elapsed() < max_age && inner.epoch == epoch);
         let len_after = self.pool.len();
         let diff = len_before - len_after;
         if diff > 0 {
@@ -69,12 +105,18 @@ impl Pool {
 
     pub fn get(&mut self) -> Option<LuaConfigInner> {
         let max_age = Duration::from_secs(MAX_AGE.load(Ordering::Relaxed) as u64);
+        let epoch = get_current_epoch();
         loop {
             let mut item = self.pool.pop_front()?;
             LUA_SPARE_COUNT.decrement(1.);
             if item.created.elapsed() > max_age {
                 continue;
             }
+            // Contexts that were loaded prior to the most recent epoch
+            // bump may be running an older version of the policy
+            if item.epoch != epoch {
+                continue;
+            }
             item.use_count += 1;
             return Some(item);
         }
@@ -86,6 +128,8 @@ impl Pool {
         }
         if config.created.elapsed() > Duration::from_secs(MAX_AGE.load(Ordering::Relaxed) as u64)
             || config.use_count + 1 > MAX_USE.load(Ordering::Relaxed)
+            || config.epoch != get_current_epoch()
+            || is_tainted(&config.lua)
         {
             return;
         }
@@ -106,11 +150,73 @@ impl Pool {
 }
 
 pub(crate) fn pool_get() -> Option<LuaConfig> {
-    POOL.lock()
-        .get()
-        .map(|inner| LuaConfig { inner: Some(inner) })
+    let mut pool = POOL.lock();
+    let result = pool.get();
+    if pool.pool.len() < MIN_SPARE.load(Ordering::Relaxed) {
+        PREWARM_WAKEUP.notify_one();
+    }
+    drop(pool);
+
+    match result {
+        Some(inner) => {
+            LUA_POOL_HIT_COUNT.increment(1);
+            Some(LuaConfig::from_inner(inner))
+        }
+        None => {
+            LUA_POOL_MISS_COUNT.increment(1);
+            None
+        }
+    }
 }
 
 pub(crate) fn pool_put(config: LuaConfigInner) {
     POOL.lock().put(config);
 }
+
+/// Returns the number of spare contexts that the prewarm task
+/// needs to create in order to reach the configured minimum
+fn prewarm_deficit() -> usize {
+    let target = MIN_SPARE
+        .load(Ordering::Relaxed)
+        .min(MAX_SPARE.load(Ordering::Relaxed));
+    let mut pool = POOL.lock();
+    pool.expire();
+    target.saturating_sub(pool.pool.len())
+}
+
+/// Keeps the pool topped up with at least MIN_SPARE contexts, so that
+/// bursts of activity, such as the flood of connections that follows
+/// a restart, don't have to wait for contexts to be created and for
+/// the policy to be loaded into them.
+async fn prewarm_task() {
+    let mut epoch_subscriber = crate::epoch::subscribe();
+    loop {
+        let deficit = prewarm_deficit();
+        for _ in 0..deficit {
+            match crate::new_config(true, None).await {
+                Ok(config) => {
+                    LUA_PREWARM_COUNT.increment(1);
+                    // Dropping the config returns it to the pool
+                    drop(config);
+                }
+                Err(err) => {
+                    tracing::error!("Error while prewarming lua context: {err:#}");
+                    break;
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = PREWARM_WAKEUP.notified() => {}
+            _ = epoch_subscriber.changed() => {}
+            _ = tokio::time::sleep(Duration::from_secs(30)) => {}
+        };
+    }
+}
+
+pub(crate) fn start_prewarm() {
+    if PREWARM_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    tokio::spawn(prewarm_task());
+}
@@ -6,6 +6,8 @@ use config::{any_err, from_lua_value, get_or_create_sub_module};
 use mlua::Lua;
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "impl")]
+use std::collections::HashMap;
+#[cfg(feature = "impl")]
 use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
 
 #[derive(Deserialize, Serialize, Clone, Hash, PartialEq, Eq, Debug)]
@@ -15,6 +17,9 @@ pub enum KeySource {
     Data {
         key_data: String,
     },
+    Env {
+        key_env: String,
+    },
     Vault {
         vault_address: Option<String>,
         vault_token: Option<String>,
@@ -29,6 +34,9 @@ impl KeySource {
         match self {
             Self::File(path) => Ok(tokio::fs::read(path).await?),
             Self::Data { key_data } => Ok(key_data.as_bytes().to_vec()),
+            Self::Env { key_env } => std::env::var(key_env)
+                .map(String::into_bytes)
+                .map_err(|err| anyhow!("environment variable {key_env} is not usable: {err:#}")),
             Self::Vault {
                 vault_address,
                 vault_token,
@@ -78,6 +86,167 @@ impl KeySource {
     }
 }
 
+/// The name of the field that marks a table in a configuration
+/// file as a placeholder to be replaced by the value of a secret
+pub const KEY_SOURCE_PLACEHOLDER: &str = "key_source";
+
+/// A parsed configuration file in which placeholders of the form
+/// `{ key_source = KEYSOURCE }` can be replaced by the values of
+/// the secrets that they reference.
+#[cfg(feature = "impl")]
+pub trait InterpolateSecrets {
+    /// Collect the sources referenced by the placeholders
+    fn collect_sources(&self, sources: &mut Vec<KeySource>) -> anyhow::Result<()>;
+
+    /// Replace the placeholders with the resolved values
+    fn replace_sources(&mut self, resolved: &HashMap<KeySource, String>);
+}
+
+#[cfg(feature = "impl")]
+impl InterpolateSecrets for serde_json::Value {
+    fn collect_sources(&self, sources: &mut Vec<KeySource>) -> anyhow::Result<()> {
+        match self {
+            Self::Object(map) => {
+                match map.get(KEY_SOURCE_PLACEHOLDER) {
+                    Some(source) if map.len() == 1 => {
+                        sources.push(serde_json::from_value(source.clone()).with_context(
+                            || format!("invalid {KEY_SOURCE_PLACEHOLDER} {source}"),
+                        )?);
+                    }
+                    _ => {
+                        for value in map.values() {
+                            value.collect_sources(sources)?;
+                        }
+                    }
+                }
+            }
+            Self::Array(values) => {
+                for value in values {
+                    value.collect_sources(sources)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn replace_sources(&mut self, resolved: &HashMap<KeySource, String>) {
+        match self {
+            Self::Object(map) => {
+                let replacement = match map.get(KEY_SOURCE_PLACEHOLDER) {
+                    Some(source) if map.len() == 1 => serde_json::from_value(source.clone())
+                        .ok()
+                        .and_then(|source: KeySource| resolved.get(&source)),
+                    _ => None,
+                };
+                match replacement {
+                    Some(value) => *self = Self::String(value.clone()),
+                    None => {
+                        for value in map.values_mut() {
+                            value.replace_sources(resolved);
+                        }
+                    }
+                }
+            }
+            Self::Array(values) => {
+                for value in values {
+                    value.replace_sources(resolved);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(feature = "impl")]
+impl InterpolateSecrets for toml::Value {
+    fn collect_sources(&self, sources: &mut Vec<KeySource>) -> anyhow::Result<()> {
+        match self {
+            Self::Table(map) => {
+                match map.get(KEY_SOURCE_PLACEHOLDER) {
+                    Some(source) if map.len() == 1 => {
+                        sources.push(source.clone().try_into().with_context(|| {
+                            format!("invalid {KEY_SOURCE_PLACEHOLDER} {source}")
+                        })?);
+                    }
+                    _ => {
+                        for value in map.values() {
+                            value.collect_sources(sources)?;
+                        }
+                    }
+                }
+            }
+            Self::Array(values) => {
+                for value in values {
+                    value.collect_sources(sources)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn replace_sources(&mut self, resolved: &HashMap<KeySource, String>) {
+        match self {
+            Self::Table(map) => {
+                let replacement = match map.get(KEY_SOURCE_PLACEHOLDER) {
+                    Some(source) if map.len() == 1 => source
+                        .clone()
+                        .try_into()
+                        .ok()
+                        .and_then(|source: KeySource| resolved.get(&source)),
+                    _ => None,
+                };
+                match replacement {
+                    Some(value) => *self = Self::String(value.clone()),
+                    None => {
+                        for value in map.values_mut() {
+                            value.replace_sources(resolved);
+                        }
+                    }
+                }
+            }
+            Self::Array(values) => {
+                for value in values {
+                    value.replace_sources(resolved);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Replace each `{ key_source = KEYSOURCE }` placeholder in value
+/// with the content of the referenced secret, as a string.
+/// A trailing line break is removed from the content, as it is
+/// typically an artifact of the secret having been saved to a file.
+#[cfg(feature = "impl")]
+pub async fn interpolate_secrets<V: InterpolateSecrets>(value: &mut V) -> anyhow::Result<()> {
+    let mut sources = vec![];
+    value.collect_sources(&mut sources)?;
+    if sources.is_empty() {
+        return Ok(());
+    }
+
+    let mut resolved = HashMap::new();
+    for source in sources {
+        if resolved.contains_key(&source) {
+            continue;
+        }
+        let data = source
+            .get()
+            .await
+            .with_context(|| format!("loading {KEY_SOURCE_PLACEHOLDER} {source:?}"))?;
+        let data = String::from_utf8(data)
+            .with_context(|| format!("{KEY_SOURCE_PLACEHOLDER} {source:?} is not UTF-8"))?;
+        let data = data.trim_end_matches(['\r', '\n']).to_string();
+        resolved.insert(source, data);
+    }
+
+    value.replace_sources(&resolved);
+    Ok(())
+}
+
 #[cfg(feature = "impl")]
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
     let secrets_mod = get_or_create_sub_module(lua, "secrets")?;
@@ -276,6 +445,43 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn test_interpolate() -> anyhow::Result<()> {
+        std::env::set_var("DATA_LOADER_TEST_SECRET", "from-env\n");
+
+        let mut value: toml::Value = toml::from_str(
+            r#"
+password = { key_source = { key_data = "s3cret" } }
+nested = [{ token = { key_source = { key_env = "DATA_LOADER_TEST_SECRET" } } }]
+other = { key_source = "not a placeholder", extra = true }
+"#,
+        )?;
+        interpolate_secrets(&mut value).await?;
+        assert_eq!(value["password"].as_str(), Some("s3cret"));
+        assert_eq!(value["nested"][0]["token"].as_str(), Some("from-env"));
+        assert_eq!(value["other"]["extra"].as_bool(), Some(true));
+
+        let mut value = serde_json::json!({
+            "password": {"key_source": {"key_data": "s3cret"}},
+            "list": [{"key_source": {"key_env": "DATA_LOADER_TEST_SECRET"}}],
+        });
+        interpolate_secrets(&mut value).await?;
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "password": "s3cret",
+                "list": ["from-env"],
+            })
+        );
+
+        let mut value = serde_json::json!({
+            "password": {"key_source": {"key_env": "DATA_LOADER_TEST_UNSET"}},
+        });
+        assert!(interpolate_secrets(&mut value).await.is_err());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_vault() -> anyhow::Result<()> {
         if which::which("vault").is_err() {
@@ -2,7 +2,7 @@ use crate::hash::LimitHasher;
 use memchr::memmem::Finder;
 use once_cell::sync::Lazy;
 
-#[derive(PartialEq, Clone, Debug, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Copy)]
 pub enum Type {
     Simple,
     Relaxed,
@@ -16,13 +16,6 @@ impl Type {
         }
     }
 
-    pub(crate) fn canon_body(&self, body: &[u8], hasher: &mut LimitHasher) {
-        match self {
-            Self::Simple => body_simple(body, hasher),
-            Self::Relaxed => body_relaxed(body, hasher),
-        }
-    }
-
     pub(crate) fn canon_header_into(&self, key: &str, value: &[u8], out: &mut Vec<u8>) {
         match self {
             Self::Simple => canonicalize_header_simple(key, value, out),
@@ -31,24 +24,99 @@ impl Type {
     }
 }
 
-fn do_body_simple<'a>(mut body: &'a [u8]) -> &'a [u8] {
-    if body.is_empty() {
-        return b"\r\n";
+/// Canonicalizes a message body that is supplied in chunks, feeding
+/// the result to a hasher, so that the body hash can be computed
+/// incrementally.
+pub(crate) struct BodyCanonicalizer {
+    canonicalization: Type,
+    /// The bytes following the last CRLF that we have seen
+    partial: Vec<u8>,
+    /// The number of empty lines that have yet to be emitted.
+    /// They are ignored if they turn out to be at the end of the body.
+    empty_lines: usize,
+    /// Whether any lines have been emitted
+    emitted: bool,
+}
+
+impl BodyCanonicalizer {
+    pub fn new(canonicalization: Type) -> Self {
+        Self {
+            canonicalization,
+            partial: vec![],
+            empty_lines: 0,
+            emitted: false,
+        }
     }
 
-    while body.ends_with(b"\r\n\r\n") {
-        body = &body[..body.len() - 2];
+    /// Canonicalize the next chunk of the body
+    pub fn update(&mut self, mut chunk: &[u8], hasher: &mut LimitHasher) {
+        if !self.partial.is_empty() {
+            // Complete the line that was started by a prior chunk,
+            // taking care of a CRLF that was split across the chunks
+            let end = if self.partial.ends_with(b"\r") && chunk.starts_with(b"\n") {
+                Some(1)
+            } else {
+                CRLF.find(chunk).map(|idx| idx + 2)
+            };
+            match end {
+                Some(end) => {
+                    self.partial.extend_from_slice(&chunk[..end]);
+                    let line = std::mem::take(&mut self.partial);
+                    self.line(&line, hasher);
+                    chunk = &chunk[end..];
+                }
+                None => {
+                    self.partial.extend_from_slice(chunk);
+                    return;
+                }
+            }
+        }
+
+        for line in iter_lines(chunk) {
+            if line.ends_with(b"\r\n") {
+                self.line(line, hasher);
+            } else {
+                self.partial.extend_from_slice(line);
+            }
+        }
     }
 
-    body
-}
+    /// Complete the canonicalization, once the whole body has been
+    /// passed to update
+    pub fn finish(mut self, hasher: &mut LimitHasher) {
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            self.line(&line, hasher);
+        } else if !self.emitted {
+            // A body that is empty, or that consists only of empty
+            // lines, is canonicalized to a single CRLF, except that
+            // relaxed canonicalization leaves an empty body empty
+            if self.canonicalization == Type::Simple || self.empty_lines > 0 {
+                hasher.hash(b"\r\n");
+            }
+        }
+    }
 
-/// Canonicalize body using the simple canonicalization algorithm.
-fn body_simple(body: &[u8], hasher: &mut LimitHasher) {
-    let body = do_body_simple(body);
-    hasher.hash(body);
+    /// Emit a line, which includes its CRLF unless it is the
+    /// final line of a body that doesn't end with CRLF
+    fn line(&mut self, line: &[u8], hasher: &mut LimitHasher) {
+        if line == b"\r\n" {
+            self.empty_lines += 1;
+            return;
+        }
+        for _ in 0..std::mem::take(&mut self.empty_lines) {
+            hasher.hash(b"\r\n");
+        }
+        self.emitted = true;
+        match self.canonicalization {
+            Type::Simple => hasher.hash(line),
+            Type::Relaxed => line_relaxed(line, hasher),
+        }
+    }
 }
 
+static CRLF: Lazy<Finder> = Lazy::new(|| memchr::memmem::Finder::new("\r\n"));
+
 /// Helper for iterating lines using memmem
 struct IterLines<'haystack> {
     haystack: &'haystack [u8],
@@ -85,7 +153,6 @@ impl<'haystack> Iterator for IterLines<'haystack> {
 }
 
 fn iter_lines(haystack: &[u8]) -> IterLines {
-    static CRLF: Lazy<Finder> = Lazy::new(|| memchr::memmem::Finder::new("\r\n"));
     IterLines {
         haystack,
         inner: CRLF.find_iter(haystack),
@@ -95,44 +162,34 @@ fn iter_lines(haystack: &[u8]) -> IterLines {
 }
 
 /// https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.3
-/// Canonicalize body using the relaxed canonicalization algorithm.
-fn body_relaxed(mut body: &[u8], hasher: &mut LimitHasher) {
-    if body.is_empty() {
-        return;
-    }
-
-    // Ignore empty lines at the end of the message body
-    while body.ends_with(b"\r\n\r\n") {
-        body = &body[..body.len() - 2];
-    }
-
-    for mut line in iter_lines(body) {
-        // Ignore all whitespace at the end of the line
-        line = trim_ws_end(line);
-
-        let mut prior = 0;
-        // Reduce all sequences of WSP within a line to a single SP character.
-        for idx in memchr::memchr2_iter(b' ', b'\t', line) {
-            if prior > 0 && idx == prior {
-                // Part of a run; ignore this one
-                prior = idx + 1;
-                continue;
-            }
-
-            // Found a new run of space(s).
-            // Emit the bytes ahead of this one
-            hasher.hash(&line[prior..idx]);
-            // and emit the canonical space
-            hasher.hash(b" ");
-
+/// Canonicalize a line of the body using the relaxed algorithm.
+/// Empty lines at the end of the body are ignored by BodyCanonicalizer.
+fn line_relaxed(mut line: &[u8], hasher: &mut LimitHasher) {
+    // Ignore all whitespace at the end of the line
+    line = trim_ws_end(line);
+
+    let mut prior = 0;
+    // Reduce all sequences of WSP within a line to a single SP character.
+    for idx in memchr::memchr2_iter(b' ', b'\t', line) {
+        if prior > 0 && idx == prior {
+            // Part of a run; ignore this one
             prior = idx + 1;
+            continue;
         }
-        // and emit the remainder
-        hasher.hash(&line[prior..]);
 
-        // and canonical newline
-        hasher.hash(b"\r\n");
+        // Found a new run of space(s).
+        // Emit the bytes ahead of this one
+        hasher.hash(&line[prior..idx]);
+        // and emit the canonical space
+        hasher.hash(b" ");
+
+        prior = idx + 1;
     }
+    // and emit the remainder
+    hasher.hash(&line[prior..]);
+
+    // and canonical newline
+    hasher.hash(b"\r\n");
 }
 
 // https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.1
@@ -218,24 +275,28 @@ mod tests {
         );
     }
 
-    fn body_relaxed(data: &[u8]) -> Vec<u8> {
+    /// Canonicalize data, passing it to the canonicalizer in
+    /// chunks of chunk_size bytes
+    fn canon_body_chunked(canonicalization: Type, data: &[u8], chunk_size: usize) -> Vec<u8> {
         let mut hasher = LimitHasher {
             hasher: crate::hash::HashImpl::copy_data(),
             limit: usize::MAX,
             hashed: 0,
         };
-        super::body_relaxed(data, &mut hasher);
+        let mut canon = BodyCanonicalizer::new(canonicalization);
+        for chunk in data.chunks(chunk_size) {
+            canon.update(chunk, &mut hasher);
+        }
+        canon.finish(&mut hasher);
         hasher.finalize_bytes()
     }
 
+    fn body_relaxed(data: &[u8]) -> Vec<u8> {
+        canon_body_chunked(Type::Relaxed, data, data.len().max(1))
+    }
+
     fn body_simple(data: &[u8]) -> Vec<u8> {
-        let mut hasher = LimitHasher {
-            hasher: crate::hash::HashImpl::copy_data(),
-            limit: usize::MAX,
-            hashed: 0,
-        };
-        super::body_simple(data, &mut hasher);
-        hasher.finalize_bytes()
+        canon_body_chunked(Type::Simple, data, data.len().max(1))
     }
 
     #[test]
@@ -254,4 +315,35 @@ mod tests {
             b" C \r\nD \t E\r\n"
         );
     }
+
+    #[test]
+    fn test_canonicalize_body_chunked() {
+        let bodies: &[&[u8]] = &[
+            b"",
+            b"\r\n",
+            b"\r\n\r\n\r\n",
+            b"hey",
+            b"hey  \r",
+            b"\r\n\r\nhey \t you\r\n\r\nthere\r\n\r\n\r\n",
+            b" C \r\nD \t E\r\n  \r\n\r\n",
+        ];
+        for body in bodies {
+            for canonicalization in [Type::Simple, Type::Relaxed] {
+                let whole = canon_body_chunked(canonicalization, body, body.len().max(1));
+                // Every chunk size splits the lines, and their CRLFs,
+                // at different points, none of which should matter
+                for chunk_size in 1..=body.len().max(1) {
+                    assert_eq!(
+                        canon_body_chunked(canonicalization, body, chunk_size),
+                        whole,
+                        "{canonicalization:?} {chunk_size} {}",
+                        String::from_utf8_lossy(body)
+                    );
+                }
+            }
+        }
+        assert_eq!(body_simple(b""), b"\r\n");
+        assert_eq!(body_relaxed(b""), b"");
+        assert_eq!(body_relaxed(b"hey  \r"), b"hey\r\n");
+    }
 }
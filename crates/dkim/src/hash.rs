@@ -1,3 +1,4 @@
+use crate::canonicalization::BodyCanonicalizer;
 use crate::header::HEADER;
 use crate::{canonicalization, DKIMError, DKIMHeader, ParsedEmail};
 use data_encoding::BASE64;
@@ -5,7 +6,7 @@ use sha1::{Digest as _, Sha1};
 use sha2::Sha256;
 use std::collections::HashMap;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum HashAlgo {
     RsaSha1,
     RsaSha256,
@@ -103,17 +104,73 @@ pub(crate) fn compute_body_hash<'a>(
     email: &'a ParsedEmail<'a>,
 ) -> Result<String, DKIMError> {
     let body = email.get_body();
-    let limit = length.unwrap_or(usize::MAX);
+    Ok(BodyHashKey::new(canonicalization_type, length, hash_algo).hash(body.as_bytes()))
+}
+
+/// Identifies a distinct body hash computation.  Signatures that
+/// produce the same key can share a single body hash; in particular,
+/// rsa-sha256 and ed25519-sha256 both use a SHA-256 body hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct BodyHashKey {
+    canonicalization: canonicalization::Type,
+    length: Option<usize>,
+    /// The algorithm used to compute the digest of the body,
+    /// which is independent of the signing algorithm
+    digest: HashAlgo,
+}
+
+impl BodyHashKey {
+    pub fn new(
+        canonicalization: canonicalization::Type,
+        length: Option<usize>,
+        hash_algo: HashAlgo,
+    ) -> Self {
+        let digest = match hash_algo {
+            HashAlgo::RsaSha1 => HashAlgo::RsaSha1,
+            HashAlgo::RsaSha256 | HashAlgo::Ed25519Sha256 => HashAlgo::RsaSha256,
+        };
+        Self {
+            canonicalization,
+            length,
+            digest,
+        }
+    }
+
+    pub fn hash(&self, body: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(body);
+        hasher.finalize()
+    }
+
+    /// Returns a hasher to which the body can be passed in chunks
+    pub fn hasher(&self) -> BodyHasher {
+        BodyHasher {
+            canon: BodyCanonicalizer::new(self.canonicalization),
+            hasher: LimitHasher {
+                hasher: HashImpl::from_algo(self.digest),
+                limit: self.length.unwrap_or(usize::MAX),
+                hashed: 0,
+            },
+        }
+    }
+}
 
-    let mut hasher = LimitHasher {
-        hasher: HashImpl::from_algo(hash_algo),
-        limit,
-        hashed: 0,
-    };
+/// Computes a body hash incrementally
+pub(crate) struct BodyHasher {
+    canon: BodyCanonicalizer,
+    hasher: LimitHasher,
+}
 
-    canonicalization_type.canon_body(body.as_bytes(), &mut hasher);
+impl BodyHasher {
+    /// Hash the next chunk of the body
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.canon.update(chunk, &mut self.hasher);
+    }
 
-    Ok(hasher.finalize())
+    pub fn finalize(mut self) -> String {
+        self.canon.finish(&mut self.hasher);
+        self.hasher.finalize()
+    }
 }
 
 /// Holds a list of header names, normalized to lower case
@@ -411,6 +468,21 @@ Hello Alice
         )
     }
 
+    #[test]
+    fn test_body_hash_key_shares_digest() {
+        let rsa = BodyHashKey::new(canonicalization::Type::Relaxed, None, HashAlgo::RsaSha256);
+        let ed = BodyHashKey::new(
+            canonicalization::Type::Relaxed,
+            None,
+            HashAlgo::Ed25519Sha256,
+        );
+        let sha1 = BodyHashKey::new(canonicalization::Type::Relaxed, None, HashAlgo::RsaSha1);
+        let simple = BodyHashKey::new(canonicalization::Type::Simple, None, HashAlgo::RsaSha256);
+        assert_eq!(rsa, ed);
+        assert_ne!(rsa, sha1);
+        assert_ne!(rsa, simple);
+    }
+
     #[test]
     fn test_compute_headers_hash_simple() {
         let email = r#"To: test@sauleau.com
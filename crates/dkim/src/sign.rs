@@ -1,7 +1,14 @@
+use crate::hash::BodyHashKey;
 use crate::header::DKIMHeaderBuilder;
 use crate::{canonicalization, hash, DKIMError, DkimPrivateKey, HeaderList, ParsedEmail, HEADER};
 use data_encoding::BASE64;
 use ed25519_dalek::Signer as _;
+use std::collections::HashMap;
+
+/// When signing with multiple signers that require distinct body hashes,
+/// the body is passed to each of the hashers in chunks of this size,
+/// so that each chunk is read from memory just once for all of them
+const BODY_HASH_CHUNK_SIZE: usize = 64 * 1024;
 
 /// Builder for the Signer
 pub struct SignerBuilder {
@@ -160,6 +167,53 @@ impl Signer {
     /// Sign a message
     /// As specified in <https://datatracker.ietf.org/doc/html/rfc6376#section-5>
     pub fn sign<'b>(&self, email: &'b ParsedEmail<'b>) -> Result<String, DKIMError> {
+        let body_hash = self.compute_body_hash(email)?;
+        self.sign_with_body_hash(email, &body_hash)
+    }
+
+    /// Sign a message with each of the provided signers, returning the
+    /// DKIM-Signature headers in the same order as `signers`.
+    /// The body hash is computed only once for each distinct combination
+    /// of body canonicalization and digest algorithm, so, for example,
+    /// signing with both an RSA and an Ed25519 key that use the same
+    /// canonicalization hashes the body just once.
+    pub fn sign_multiple<'b>(
+        signers: &[&Signer],
+        email: &'b ParsedEmail<'b>,
+    ) -> Result<Vec<String>, DKIMError> {
+        let body = email.get_body();
+        let body = body.as_bytes();
+
+        let mut keys: Vec<BodyHashKey> = vec![];
+        for signer in signers {
+            let key = signer.body_hash_key();
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        let mut hashers: Vec<_> = keys.iter().map(|key| key.hasher()).collect();
+        for chunk in body.chunks(BODY_HASH_CHUNK_SIZE) {
+            for hasher in &mut hashers {
+                hasher.update(chunk);
+            }
+        }
+        let hashes: HashMap<BodyHashKey, String> = keys
+            .into_iter()
+            .zip(hashers.into_iter().map(|hasher| hasher.finalize()))
+            .collect();
+
+        signers
+            .iter()
+            .map(|signer| signer.sign_with_body_hash(email, &hashes[&signer.body_hash_key()]))
+            .collect()
+    }
+
+    fn sign_with_body_hash<'b>(
+        &self,
+        email: &'b ParsedEmail<'b>,
+        body_hash: &str,
+    ) -> Result<String, DKIMError> {
         let over_sign_header_list;
         let effective_header_list = if self.over_sign {
             over_sign_header_list = self.signed_headers.compute_over_signed(email);
@@ -168,8 +222,7 @@ impl Signer {
             &self.signed_headers
         };
 
-        let body_hash = self.compute_body_hash(email)?;
-        let dkim_header_builder = self.dkim_header_builder(&body_hash, effective_header_list)?;
+        let dkim_header_builder = self.dkim_header_builder(body_hash, effective_header_list)?;
 
         let header_hash =
             self.compute_header_hash(email, effective_header_list, dkim_header_builder.clone())?;
@@ -264,6 +317,10 @@ impl Signer {
         Ok(builder)
     }
 
+    fn body_hash_key(&self) -> BodyHashKey {
+        BodyHashKey::new(self.body_canonicalization, None, self.hash_algo)
+    }
+
     fn compute_body_hash<'b>(&self, email: &'b ParsedEmail<'b>) -> Result<String, DKIMError> {
         let length = None;
         let canonicalization = self.body_canonicalization;
@@ -483,4 +540,59 @@ DKIM-Signature: v=1; a=ed25519-sha256; d=football.example.com; s=brisbane;\r
 "#
         );
     }
+
+    #[test]
+    fn test_sign_multiple() {
+        let raw_email = format!(
+            "From: Joe SixPack <joe@football.example.com>\r\n\
+             Subject: Is dinner ready?\r\n\
+             \r\n\
+             {}",
+            "We lost the game.  Are you hungry yet?\r\n".repeat(10_000)
+        );
+        let email = ParsedEmail::parse(raw_email).unwrap();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let file_decoded = BASE64
+            .decode(&fs::read("./test/keys/ed.private").unwrap())
+            .unwrap();
+        let mut key_bytes = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+        key_bytes.copy_from_slice(&file_decoded);
+
+        let make_signer = |key: DkimPrivateKey, canon: canonicalization::Type| {
+            SignerBuilder::new()
+                .with_signed_headers(["From", "Subject"])
+                .unwrap()
+                .with_private_key(key)
+                .with_body_canonicalization(canon)
+                .with_selector("s20")
+                .with_signing_domain("example.com")
+                .with_time(time)
+                .build()
+                .unwrap()
+        };
+
+        let rsa = make_signer(
+            DkimPrivateKey::rsa_key_file("./test/keys/2022.private").unwrap(),
+            canonicalization::Type::Relaxed,
+        );
+        let ed = make_signer(
+            DkimPrivateKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&key_bytes)),
+            canonicalization::Type::Relaxed,
+        );
+        let simple = make_signer(
+            DkimPrivateKey::rsa_key_file("./test/keys/2022.private").unwrap(),
+            canonicalization::Type::Simple,
+        );
+
+        let headers = Signer::sign_multiple(&[&rsa, &ed, &simple], &email).unwrap();
+        assert_eq!(
+            headers,
+            vec![
+                rsa.sign(&email).unwrap(),
+                ed.sign(&email).unwrap(),
+                simple.sign(&email).unwrap(),
+            ]
+        );
+    }
 }
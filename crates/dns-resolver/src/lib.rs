@@ -6,6 +6,7 @@ use hickory_resolver::proto::rr::RecordType;
 use hickory_resolver::Name;
 use kumo_log_types::ResolvedAddress;
 use lruttl::LruCacheWithTtl;
+use prometheus::IntCounterVec;
 use rand::prelude::SliceRandom;
 use serde::Serialize;
 use std::collections::BTreeMap;
@@ -21,6 +22,29 @@ lazy_static::lazy_static! {
     static ref IPV4_CACHE: StdMutex<LruCacheWithTtl<Name, Arc<Vec<IpAddr>>>> = StdMutex::new(LruCacheWithTtl::new(1024));
     static ref IPV6_CACHE: StdMutex<LruCacheWithTtl<Name, Arc<Vec<IpAddr>>>> = StdMutex::new(LruCacheWithTtl::new(1024));
     static ref IP_CACHE: StdMutex<LruCacheWithTtl<Name, Arc<Vec<IpAddr>>>> = StdMutex::new(LruCacheWithTtl::new(1024));
+    static ref PTR_CACHE: StdMutex<LruCacheWithTtl<IpAddr, Arc<Vec<String>>>> = StdMutex::new(LruCacheWithTtl::new(1024));
+    static ref CACHE_LOOKUP: IntCounterVec = prometheus::register_int_counter_vec!(
+        "dns_cache_lookup_count",
+        "how many dns cache lookups occurred",
+        &["cache"]).unwrap();
+    static ref CACHE_HIT: IntCounterVec = prometheus::register_int_counter_vec!(
+        "dns_cache_hit",
+        "how many dns cache lookups hit cache",
+        &["cache"]).unwrap();
+    static ref CACHE_MISS: IntCounterVec = prometheus::register_int_counter_vec!(
+        "dns_cache_miss",
+        "how many dns cache lookups missed cache",
+        &["cache"]).unwrap();
+}
+
+/// Record the outcome of a lookup in the named cache
+fn record_cache_lookup<T>(cache: &str, result: Option<T>) -> Option<T> {
+    CACHE_LOOKUP.with_label_values(&[cache]).inc();
+    match &result {
+        Some(_) => CACHE_HIT.with_label_values(&[cache]).inc(),
+        None => CACHE_MISS.with_label_values(&[cache]).inc(),
+    }
+    result
 }
 
 #[cfg(feature = "default-unbound")]
@@ -41,19 +65,23 @@ fn default_resolver() -> Resolver {
 }
 
 fn mx_cache_get(name: &Name) -> Option<Arc<MailExchanger>> {
-    MX_CACHE.lock().unwrap().get(name).clone()
+    record_cache_lookup("mx", MX_CACHE.lock().unwrap().get(name).clone())
 }
 
 fn ip_cache_get(ip: &Name) -> Option<(Arc<Vec<IpAddr>>, Instant)> {
-    IP_CACHE.lock().unwrap().get_with_expiry(ip)
+    record_cache_lookup("ip", IP_CACHE.lock().unwrap().get_with_expiry(ip))
 }
 
 fn ipv4_cache_get(ip: &Name) -> Option<(Arc<Vec<IpAddr>>, Instant)> {
-    IPV4_CACHE.lock().unwrap().get_with_expiry(ip)
+    record_cache_lookup("ipv4", IPV4_CACHE.lock().unwrap().get_with_expiry(ip))
 }
 
 fn ipv6_cache_get(ip: &Name) -> Option<(Arc<Vec<IpAddr>>, Instant)> {
-    IPV6_CACHE.lock().unwrap().get_with_expiry(ip)
+    record_cache_lookup("ipv6", IPV6_CACHE.lock().unwrap().get_with_expiry(ip))
+}
+
+fn ptr_cache_get(ip: &IpAddr) -> Option<Arc<Vec<String>>> {
+    record_cache_lookup("ptr", PTR_CACHE.lock().unwrap().get(ip).clone())
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -257,6 +285,12 @@ impl MailExchanger {
 
         let (by_pref, expires) = match lookup_mx_record(&name_fq).await {
             Ok((by_pref, expires)) => (by_pref, expires),
+            Err(err) if err.is::<NxDomain>() => {
+                return Err(NxDomain {
+                    domain_name: domain_name.to_string(),
+                }
+                .into());
+            }
             Err(err) => anyhow::bail!("MX lookup for {domain_name} failed: {err:#}"),
         };
 
@@ -354,6 +388,21 @@ impl MailExchanger {
     }
 }
 
+/// The error returned by MailExchanger::resolve when the
+/// domain does not exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NxDomain {
+    pub domain_name: String,
+}
+
+impl std::fmt::Display for NxDomain {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "MX lookup for {} failed: NXDOMAIN", self.domain_name)
+    }
+}
+
+impl std::error::Error for NxDomain {}
+
 #[derive(Debug, Clone, Serialize)]
 pub enum ResolvedMxAddresses {
     NullMx,
@@ -376,7 +425,10 @@ async fn lookup_mx_record(domain_name: &Name) -> anyhow::Result<(Vec<ByPreferenc
 
     if mx_records.is_empty() {
         if mx_lookup.nxdomain {
-            anyhow::bail!("NXDOMAIN");
+            return Err(NxDomain {
+                domain_name: domain_name.to_ascii(),
+            }
+            .into());
         }
 
         return Ok((
@@ -480,6 +532,9 @@ pub async fn ipv4_lookup(key: &str) -> anyhow::Result<(Arc<Vec<IpAddr>>, Instant
         .load()
         .resolve(key_fq.clone(), RecordType::A)
         .await?;
+    if answer.is_failure() {
+        anyhow::bail!("A lookup for {key} failed: {}", answer.response_code);
+    }
     let ips = answer.as_addr();
 
     let ips = Arc::new(ips);
@@ -501,6 +556,9 @@ pub async fn ipv6_lookup(key: &str) -> anyhow::Result<(Arc<Vec<IpAddr>>, Instant
         .load()
         .resolve(key_fq.clone(), RecordType::AAAA)
         .await?;
+    if answer.is_failure() {
+        anyhow::bail!("AAAA lookup for {key} failed: {}", answer.response_code);
+    }
     let ips = answer.as_addr();
 
     let ips = Arc::new(ips);
@@ -512,6 +570,35 @@ pub async fn ipv6_lookup(key: &str) -> anyhow::Result<(Arc<Vec<IpAddr>>, Instant
     Ok((ips, expires))
 }
 
+/// Resolves the PTR records for ip, returning the lowercased
+/// names without their trailing dot
+pub async fn ptr_lookup(ip: IpAddr) -> anyhow::Result<Arc<Vec<String>>> {
+    if let Some(value) = ptr_cache_get(&ip) {
+        return Ok(value);
+    }
+
+    let answer = RESOLVER
+        .load()
+        .resolve(Name::from(ip), RecordType::PTR)
+        .await?;
+    if answer.is_failure() {
+        anyhow::bail!("PTR lookup for {ip} failed: {}", answer.response_code);
+    }
+    let names = answer
+        .records
+        .iter()
+        .filter_map(|r| r.as_ptr())
+        .map(|ptr| ptr.0.to_ascii().trim_end_matches('.').to_ascii_lowercase())
+        .collect();
+
+    let names = Arc::new(names);
+    PTR_CACHE
+        .lock()
+        .unwrap()
+        .insert(ip, names.clone(), answer.expires);
+    Ok(names)
+}
+
 /// Given a list of host names, produce a pseudo-regex style alternation list
 /// of the different elements of the hostnames.
 /// The goal is to produce a more compact representation of the name list
@@ -870,6 +957,12 @@ Addresses(
         let err = MailExchanger::resolve("not-mairs.aasland.com")
             .await
             .unwrap_err();
+        k9::assert_equal!(
+            err.downcast_ref::<NxDomain>(),
+            Some(&NxDomain {
+                domain_name: "not-mairs.aasland.com".to_string()
+            })
+        );
         k9::snapshot!(err, "MX lookup for not-mairs.aasland.com failed: NXDOMAIN");
     }
 
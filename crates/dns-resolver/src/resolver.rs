@@ -34,6 +34,17 @@ impl Answer {
         result
     }
 
+    /// Returns true if there are no records because the lookup
+    /// failed, for example with SERVFAIL, rather than because the
+    /// name has no records of the requested type
+    pub fn is_failure(&self) -> bool {
+        self.records.is_empty()
+            && !matches!(
+                self.response_code,
+                ResponseCode::NoError | ResponseCode::NXDomain
+            )
+    }
+
     pub fn as_addr(&self) -> Vec<IpAddr> {
         let mut result = vec![];
         for r in &self.records {
@@ -3,7 +3,7 @@
 //! Wildcard keys are supported.
 use config::get_or_create_sub_module;
 use mlua::prelude::LuaUserData;
-use mlua::{FromLua, Lua, MetaMethod, UserDataMethods};
+use mlua::{FromLua, Lua, MetaMethod, UserDataMethods, UserDataRef};
 use mod_memoize::CacheValue;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
@@ -117,6 +117,31 @@ impl<V: Clone> DomainMap<V> {
         }
         None
     }
+
+    /// Resolves the entry whose key is the longest suffix of domain,
+    /// which is typically what is wanted for routing tables:
+    /// an entry for "example.com" matches "example.com" itself as well
+    /// as any name below it, such as "mx.eu.example.com", unless a more
+    /// specific entry, such as "eu.example.com", is present.
+    /// Wildcard entries match only names below them, and take precedence
+    /// over an entry for their parent domain.
+    pub fn get_longest_suffix(&self, domain: &str) -> Option<&V> {
+        let mut current = &self.top;
+        let mut best = None;
+        for seg in domain.rsplit('.') {
+            if let Some(value) = current.get("*").and_then(|wild| wild.value.as_ref()) {
+                best.replace(value);
+            }
+            let Some(node) = current.get(seg) else {
+                break;
+            };
+            if let Some(value) = node.value.as_ref() {
+                best.replace(value);
+            }
+            current = &node.children;
+        }
+        best
+    }
 }
 
 impl<V: Clone> From<BTreeMap<String, V>> for DomainMap<V> {
@@ -173,6 +198,18 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    dmap_mod.set(
+        "longest_suffix",
+        lua.create_function(
+            |lua, (dmap, domain): (UserDataRef<DomainMap<CacheValue>>, String)| match dmap
+                .get_longest_suffix(&domain)
+            {
+                Some(value) => Ok(Some(value.as_lua(lua)?)),
+                None => Ok(None),
+            },
+        )?,
+    )?;
+
     Ok(())
 }
 
@@ -223,4 +260,24 @@ mod tests {
 "#
         );
     }
+
+    #[test]
+    fn longest_suffix() {
+        let mut map: DomainMap<u32> = DomainMap::new();
+        map.insert("example.com", 1);
+        map.insert("eu.example.com", 2);
+        map.insert("*.us.example.com", 3);
+        map.insert("com", 4);
+
+        assert_eq!(map.get_longest_suffix("example.com"), Some(&1));
+        assert_eq!(map.get_longest_suffix("mx.example.com"), Some(&1));
+        assert_eq!(map.get_longest_suffix("eu.example.com"), Some(&2));
+        assert_eq!(map.get_longest_suffix("mx.eu.example.com"), Some(&2));
+        // The wildcard only matches below us.example.com
+        assert_eq!(map.get_longest_suffix("us.example.com"), Some(&1));
+        assert_eq!(map.get_longest_suffix("mx.us.example.com"), Some(&3));
+        assert_eq!(map.get_longest_suffix("a.b.us.example.com"), Some(&3));
+        assert_eq!(map.get_longest_suffix("example.net"), None);
+        assert_eq!(map.get_longest_suffix("other.com"), Some(&4));
+    }
 }
@@ -9,6 +9,11 @@ local WEBHOOK_PORT = os.getenv 'KUMOD_WEBHOOK_PORT'
 local AMQPHOOK_URL = os.getenv 'KUMOD_AMQPHOOK_URL'
 local AMQP_HOST_PORT = os.getenv 'KUMOD_AMQP_HOST_PORT'
 local LISTENER_MAP = os.getenv 'KUMOD_LISTENER_DOMAIN_MAP'
+-- Maps routing domains to the SMTP port of another node of the
+-- test cluster, so that messages can be relayed to that node by
+-- setting their routing_domain
+local ROUTING_DOMAIN_PORTS =
+  kumo.json_parse(os.getenv 'KUMOD_ROUTING_DOMAIN_PORTS' or '{}')
 
 kumo.on('init', function()
   kumo.configure_accounting_db_path(TEST_DIR .. '/accounting.db')
@@ -158,7 +163,7 @@ end)
 
 kumo.on('smtp_server_message_received', function(msg) end)
 
-kumo.on('get_queue_config', function(domain, _tenant, _campaign)
+kumo.on('get_queue_config', function(domain, _tenant, _campaign, routing_domain)
   if domain == 'webhook' then
     return kumo.make_queue_config {
       protocol = {
@@ -169,11 +174,20 @@ kumo.on('get_queue_config', function(domain, _tenant, _campaign)
     }
   end
 
+  -- Use a different name for the peer nodes, so that they get a
+  -- ready queue, and therefore an smtp_port, of their own.
+  -- All of the peers share that ready queue, so only a single
+  -- peer can be used at a time.
+  local mx = 'localhost'
+  if ROUTING_DOMAIN_PORTS[routing_domain] then
+    mx = '[127.0.0.1]'
+  end
+
   return kumo.make_queue_config {
     protocol = {
       -- Redirect traffic to the sink
       smtp = {
-        mx_list = { 'localhost' },
+        mx_list = { mx },
       },
     },
     retry_interval = os.getenv 'KUMOD_RETRY_INTERVAL',
@@ -181,11 +195,11 @@ kumo.on('get_queue_config', function(domain, _tenant, _campaign)
   }
 end)
 
-kumo.on('get_egress_path_config', function(_domain, _source_name, _site_name)
+kumo.on('get_egress_path_config', function(domain, _source_name, _site_name)
   -- Allow sending to a sink
   local params = {
     enable_tls = os.getenv 'KUMOD_ENABLE_TLS' or 'OpportunisticInsecure',
-    smtp_port = SINK_PORT,
+    smtp_port = ROUTING_DOMAIN_PORTS[domain] or SINK_PORT,
     prohibited_hosts = {},
   }
 
@@ -165,40 +165,14 @@ impl DaemonWithMaildir {
         &self,
         args: impl IntoIterator<Item = impl AsRef<std::ffi::OsStr>>,
     ) -> anyhow::Result<std::process::ExitStatus> {
-        let path = target_bin("kcli")?;
-        let mut cmd = Command::new(path);
-        cmd.args([
-            "--endpoint",
-            &format!("http://{}", self.source.listener("http")),
-        ]);
-        cmd.args(args);
-        let label = format!("{cmd:?}");
-        let status = cmd.status().await?;
-        anyhow::ensure!(status.success(), "{label}: {status:?}");
-        Ok(status)
+        self.source.kcli(args).await
     }
 
     pub async fn kcli_json<R: for<'a> serde::Deserialize<'a>>(
         &self,
         args: impl IntoIterator<Item = impl AsRef<std::ffi::OsStr>>,
     ) -> anyhow::Result<R> {
-        let path = target_bin("kcli")?;
-        let mut cmd = Command::new(path);
-        cmd.args([
-            "--endpoint",
-            &format!("http://{}", self.source.listener("http")),
-        ]);
-        cmd.args(args);
-        cmd.stdout(std::process::Stdio::piped());
-        let label = format!("{cmd:?}");
-        let child = cmd.spawn()?;
-        let output = child.wait_with_output().await?;
-        anyhow::ensure!(output.status.success(), "{label}: {:?}", output.status);
-        println!(
-            "kcli output is: {}",
-            String::from_utf8_lossy(&output.stdout)
-        );
-        Ok(serde_json::from_slice(&output.stdout)?)
+        self.source.kcli_json(args).await
     }
 
     pub fn extract_maildir_messages(&self) -> anyhow::Result<Vec<MailEntry>> {
@@ -287,6 +261,13 @@ impl KumoDaemon {
     }
 
     pub async fn spawn(args: KumoArgs) -> anyhow::Result<Self> {
+        let label = args.policy_file.clone();
+        Self::spawn_with_label(args, &label).await
+    }
+
+    /// Spawn the daemon, prefixing its output with label
+    /// rather than the name of its policy file
+    pub async fn spawn_with_label(args: KumoArgs, label: &str) -> anyhow::Result<Self> {
         let path = target_bin("kumod")?;
 
         let dir = tempfile::tempdir().context("make temp dir")?;
@@ -339,7 +320,7 @@ impl KumoDaemon {
             }
         }
 
-        let stdout_prefix = format!("{} stdout", &args.policy_file);
+        let stdout_prefix = format!("{label} stdout");
         tokio::spawn(async move {
             copy_stream_with_line_prefix(&stdout_prefix, &mut stdout, &mut tokio::io::stderr())
                 .await
@@ -373,7 +354,7 @@ impl KumoDaemon {
         }
 
         // Now just pipe the output through to the test harness
-        let stderr_prefix = format!("{} stderr", &args.policy_file);
+        let stderr_prefix = format!("{label} stderr");
         tokio::spawn(async move {
             copy_stream_with_line_prefix(&stderr_prefix, &mut stderr, &mut tokio::io::stderr())
                 .await
@@ -387,7 +368,10 @@ impl KumoDaemon {
     }
 
     pub async fn stop(&mut self) -> anyhow::Result<()> {
-        let id = self.child.id().ok_or_else(|| anyhow::anyhow!("no pid!?"))?;
+        let Some(id) = self.child.id() else {
+            // It has already exited
+            return Ok(());
+        };
         let pid = nix::unistd::Pid::from_raw(id as _);
         nix::sys::signal::kill(pid, nix::sys::signal::SIGINT)?;
         tokio::select! {
@@ -400,6 +384,50 @@ impl KumoDaemon {
         }
     }
 
+    /// Wait for the daemon to exit of its own accord,
+    /// returning false if it is still running after timeout
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> bool {
+        tokio::select! {
+            _ = self.child.wait() => true,
+            _ = tokio::time::sleep(timeout) => false,
+        }
+    }
+
+    pub async fn kcli(
+        &self,
+        args: impl IntoIterator<Item = impl AsRef<std::ffi::OsStr>>,
+    ) -> anyhow::Result<std::process::ExitStatus> {
+        let path = target_bin("kcli")?;
+        let mut cmd = Command::new(path);
+        cmd.args(["--endpoint", &format!("http://{}", self.listener("http"))]);
+        cmd.args(args);
+        let label = format!("{cmd:?}");
+        let status = cmd.status().await?;
+        anyhow::ensure!(status.success(), "{label}: {status:?}");
+        Ok(status)
+    }
+
+    pub async fn kcli_json<R: for<'a> serde::Deserialize<'a>>(
+        &self,
+        args: impl IntoIterator<Item = impl AsRef<std::ffi::OsStr>>,
+    ) -> anyhow::Result<R> {
+        let path = target_bin("kcli")?;
+        let mut cmd = Command::new(path);
+        cmd.args(["--endpoint", &format!("http://{}", self.listener("http"))]);
+        cmd.args(args);
+        cmd.stdout(std::process::Stdio::piped());
+        let label = format!("{cmd:?}");
+        let child = cmd.spawn()?;
+        let output = child.wait_with_output().await?;
+        anyhow::ensure!(output.status.success(), "{label}: {:?}", output.status);
+        serde_json::from_slice(&output.stdout).map_err(|err| {
+            anyhow::anyhow!(
+                "{label}: failed to parse output as json: {err:#}: {}",
+                String::from_utf8_lossy(&output.stdout)
+            )
+        })
+    }
+
     pub fn listener(&self, service: &str) -> SocketAddr {
         match self.listeners.get(service) {
             Some(addr) => *addr,
@@ -631,3 +659,194 @@ impl DaemonWithTsa {
         Ok(())
     }
 }
+
+/// Describes one of the nodes of a KumoCluster
+#[derive(Debug, Default, Clone)]
+pub struct KumoNodeArgs {
+    /// Identifies the node to the other nodes, and in the output
+    /// of the test harness
+    pub name: String,
+    pub policy_file: String,
+    /// The name of a previously spawned node to which this node
+    /// relays its mail. This is passed to the policy as
+    /// `KUMOD_SMTP_SINK_PORT`.
+    pub smarthost: Option<String>,
+    /// The names of previously spawned nodes to which this node
+    /// can relay mail by setting the routing_domain of a message to
+    /// the name of the node. This is passed to the policy as the
+    /// `KUMOD_ROUTING_DOMAIN_PORTS` json object.
+    pub peers: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+impl KumoNodeArgs {
+    /// A node that captures the mail that it receives into its maildir
+    pub fn maildir(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            policy_file: "maildir-sink.lua".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// A node that relays the mail that it receives to smarthost
+    pub fn relay(name: &str, smarthost: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            policy_file: "source.lua".to_string(),
+            smarthost: Some(smarthost.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A log record, along with the name of the node that logged it
+#[derive(Debug)]
+pub struct ClusterLogRecord {
+    pub node: String,
+    pub record: JsonLogRecord,
+}
+
+/// A set of kumod processes that relay mail between one another
+pub struct KumoCluster {
+    pub nodes: Vec<(String, KumoDaemon)>,
+}
+
+impl KumoCluster {
+    /// Spawn the nodes in order; a node can only relay to nodes
+    /// that appear before it in the list
+    pub async fn spawn(nodes: Vec<KumoNodeArgs>) -> anyhow::Result<Self> {
+        let mut cluster = Self { nodes: vec![] };
+        for args in nodes {
+            let mut env = args.env.clone();
+            if let Some(smarthost) = &args.smarthost {
+                let smtp = cluster.node(smarthost).listener("smtp");
+                env.push(("KUMOD_SMTP_SINK_PORT".to_string(), smtp.port().to_string()));
+            }
+            if !args.peers.is_empty() {
+                let ports: BTreeMap<&str, u16> = args
+                    .peers
+                    .iter()
+                    .map(|peer| (peer.as_str(), cluster.node(peer).listener("smtp").port()))
+                    .collect();
+                env.push((
+                    "KUMOD_ROUTING_DOMAIN_PORTS".to_string(),
+                    serde_json::to_string(&ports)?,
+                ));
+            }
+
+            let daemon = KumoDaemon::spawn_with_label(
+                KumoArgs {
+                    policy_file: args.policy_file.clone(),
+                    env,
+                },
+                &args.name,
+            )
+            .await
+            .with_context(|| format!("spawn {}", args.name))?;
+            cluster.nodes.push((args.name, daemon));
+        }
+        Ok(cluster)
+    }
+
+    /// Spawn a maildir sink named "sink", followed by a chain of
+    /// relays named "relay1" through "relayN", each of which relays
+    /// to the node before it. Mail injected into the last relay
+    /// passes through each of the others before reaching the sink.
+    pub async fn chain(relays: usize, env: Vec<(&str, &str)>) -> anyhow::Result<Self> {
+        let env: Vec<(String, String)> = env
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut nodes = vec![KumoNodeArgs::maildir("sink")];
+        for i in 1..=relays {
+            let smarthost = nodes.last().expect("sink was added").name.clone();
+            nodes.push(KumoNodeArgs {
+                env: env.clone(),
+                ..KumoNodeArgs::relay(&format!("relay{i}"), &smarthost)
+            });
+        }
+        Self::spawn(nodes).await
+    }
+
+    pub fn node(&self, name: &str) -> &KumoDaemon {
+        match self.nodes.iter().find(|(n, _)| n == name) {
+            Some((_, daemon)) => daemon,
+            None => panic!("node {name} is not defined"),
+        }
+    }
+
+    pub fn node_mut(&mut self, name: &str) -> &mut KumoDaemon {
+        match self.nodes.iter_mut().find(|(n, _)| n == name) {
+            Some((_, daemon)) => daemon,
+            None => panic!("node {name} is not defined"),
+        }
+    }
+
+    /// The most recently spawned node; for a chain, this is
+    /// where mail should be injected
+    pub fn entry(&self) -> &KumoDaemon {
+        &self.nodes.last().expect("cluster has nodes").1
+    }
+
+    pub async fn smtp_client(&self) -> anyhow::Result<SmtpClient> {
+        self.entry().smtp_client().await
+    }
+
+    /// Stop all of the nodes, starting with the most recently spawned
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        for (name, daemon) in self.nodes.iter_mut().rev() {
+            daemon
+                .stop()
+                .await
+                .with_context(|| format!("stopping {name}"))?;
+        }
+        eprintln!("stopped cluster");
+        Ok(())
+    }
+
+    /// Collect the logs of all of the nodes, ordered by timestamp
+    pub fn collect_logs(&self) -> anyhow::Result<Vec<ClusterLogRecord>> {
+        let mut records = vec![];
+        for (name, daemon) in &self.nodes {
+            for record in daemon.collect_logs()? {
+                records.push(ClusterLogRecord {
+                    node: name.clone(),
+                    record,
+                });
+            }
+        }
+        records.sort_by(|a, b| a.record.timestamp.cmp(&b.record.timestamp));
+        Ok(records)
+    }
+
+    /// Returns the number of records of each type logged by each node
+    pub fn dump_logs(&self) -> anyhow::Result<BTreeMap<String, BTreeMap<RecordType, usize>>> {
+        let mut counts = BTreeMap::new();
+        for (name, daemon) in &self.nodes {
+            eprintln!("{name} logs:");
+            counts.insert(name.clone(), daemon.dump_logs()?);
+        }
+        Ok(counts)
+    }
+
+    pub async fn wait_for_summary<F>(&self, name: &str, mut func: F, timeout: Duration) -> bool
+    where
+        F: FnMut(&BTreeMap<RecordType, usize>) -> bool,
+    {
+        let daemon = self.node(name);
+        tokio::select! {
+            _ = async {
+                loop {
+                    if let Ok(summary) = daemon.dump_logs() {
+                        if (func)(&summary) {
+                            return;
+                        }
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            } => true,
+            _ = tokio::time::sleep(timeout) => false,
+        }
+    }
+}
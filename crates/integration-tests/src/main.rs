@@ -15,6 +15,7 @@ mod test {
     use super::kumod::*;
     use anyhow::Context;
     use k9::assert_equal;
+    use kumo_api_types::drain::DrainV1Status;
     use kumo_api_types::{SuspendReadyQueueV1ListEntry, SuspendV1ListEntry, SuspendV1Response};
     use kumo_log_types::RecordType;
     use kumo_log_types::RecordType::{Bounce, Delivery, Reception, TransientFailure};
@@ -1522,4 +1523,149 @@ DeliverySummary {
         );
         Ok(())
     }
+
+    #[tokio::test]
+    async fn relay_chain() -> anyhow::Result<()> {
+        let mut cluster = KumoCluster::chain(2, vec![]).await?;
+        let mut client = cluster.smtp_client().await?;
+
+        let response = MailGenParams::default().send(&mut client).await?;
+        eprintln!("{response:?}");
+        anyhow::ensure!(response.code == 250);
+
+        cluster
+            .node("sink")
+            .wait_for_maildir_count(1, Duration::from_secs(10))
+            .await;
+
+        cluster.stop().await?;
+
+        let received_headers = {
+            let mut messages = vec![];
+            for entry in cluster.node("sink").maildir().list_new() {
+                messages.push(entry?);
+            }
+            assert_equal!(messages.len(), 1);
+            let parsed = messages[0].parsed()?;
+            parsed.headers().iter_named("Received").count()
+        };
+        // One for each hop
+        assert_equal!(received_headers, 3);
+
+        k9::snapshot!(
+            cluster.dump_logs()?,
+            r#"
+{
+    "relay1": {
+        Reception: 1,
+        Delivery: 1,
+    },
+    "relay2": {
+        Reception: 1,
+        Delivery: 1,
+    },
+    "sink": {
+        Reception: 1,
+        Delivery: 1,
+    },
+}
+"#
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drain_hand_off() -> anyhow::Result<()> {
+        // Reserve a port that nothing is listening on, so that
+        // node "a" is unable to deliver directly
+        let unreachable_port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port()
+            .to_string();
+
+        let mut cluster = KumoCluster::spawn(vec![
+            KumoNodeArgs::maildir("sink"),
+            KumoNodeArgs::relay("b", "sink"),
+            KumoNodeArgs {
+                name: "a".to_string(),
+                policy_file: "source.lua".to_string(),
+                peers: vec!["b".to_string()],
+                env: vec![("KUMOD_SMTP_SINK_PORT".to_string(), unreachable_port)],
+                ..Default::default()
+            },
+        ])
+        .await?;
+        let mut client = cluster.node("a").smtp_client().await?;
+
+        let response = MailGenParams::default().send(&mut client).await?;
+        eprintln!("{response:?}");
+        anyhow::ensure!(response.code == 250);
+
+        cluster
+            .wait_for_summary(
+                "a",
+                |summary| summary.get(&TransientFailure).copied().unwrap_or(0) > 0,
+                Duration::from_secs(5),
+            )
+            .await;
+
+        let status: DrainV1Status = cluster
+            .node("a")
+            .kcli_json([
+                "drain",
+                "--reason",
+                "testing",
+                "--peer",
+                "b",
+                "--grace-period",
+                "1s",
+                "--retry-interval",
+                "1s",
+                "--shutdown",
+            ])
+            .await?;
+        assert!(status.draining);
+
+        // New mail is refused while draining
+        let mut client = SmtpClient::new(
+            cluster.node("a").listener("smtp"),
+            SmtpClientTimeouts::short_timeouts(),
+        )
+        .await?;
+        let connect_timeout = client.timeouts().connect_timeout;
+        let banner = client.read_response(None, connect_timeout).await?;
+        assert_equal!(banner.code, 421);
+
+        assert!(
+            cluster
+                .node("sink")
+                .wait_for_maildir_count(1, Duration::from_secs(20))
+                .await
+        );
+        assert!(
+            cluster
+                .node_mut("a")
+                .wait_for_exit(Duration::from_secs(20))
+                .await,
+            "node a should shut down once drained"
+        );
+
+        cluster.stop().await?;
+
+        let records = cluster.collect_logs()?;
+        let count = |node: &str, kind: RecordType| {
+            records
+                .iter()
+                .filter(|r| r.node == node && r.record.kind == kind)
+                .count()
+        };
+        assert_equal!(count("a", RecordType::AdminRebind), 1);
+        assert_equal!(count("a", Delivery), 1);
+        assert_equal!(count("b", Reception), 1);
+        assert_equal!(count("b", Delivery), 1);
+        assert_equal!(count("sink", Delivery), 1);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,72 @@
+use clap::Parser;
+use kumo_api_types::drain::{DrainV1Request, DrainV1Status};
+use reqwest::Url;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+/// Put the node into drain mode, typically ahead of a restart.
+///
+/// While draining, new messages are refused, and the queued
+/// messages are periodically made eligible for immediate delivery.
+///
+/// Once the grace period has elapsed, any messages that remain
+/// are relayed to the `--peer` node, if specified.  With
+/// `--shutdown`, the node shuts down once it has been drained.
+///
+/// The drain runs asynchronously; this command returns immediately
+/// and prints the status of the drain.
+///
+/// ## Examples
+///
+/// Drain for up to 10 minutes, then hand off to 10.0.0.2 and
+/// shut down once the hand-off is complete:
+///
+///    kcli drain --reason upgrade --grace-period 10m --peer '[10.0.0.2]' --shutdown
+///
+pub struct DrainCommand {
+    /// The reason for draining the node
+    #[arg(long)]
+    reason: String,
+
+    /// The routing domain of a peer node to which any messages
+    /// that remain after the grace period are relayed.
+    /// Use a domain literal such as `[10.0.0.2]` to address the
+    /// peer by IP.
+    #[arg(long)]
+    peer: Option<String>,
+
+    /// How long to accelerate delivery before handing off to
+    /// the peer. The default is '5m'.
+    #[arg(long, value_parser=humantime::parse_duration)]
+    grace_period: Option<Duration>,
+
+    /// How often to make the queued messages eligible for
+    /// immediate delivery. The default is '1m'.
+    #[arg(long, value_parser=humantime::parse_duration)]
+    retry_interval: Option<Duration>,
+
+    /// Shut down once the node has been drained
+    #[arg(long)]
+    shutdown: bool,
+}
+
+impl DrainCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: DrainV1Status = crate::request_with_json_response(
+            reqwest::Method::POST,
+            endpoint.join("/api/admin/drain/v1")?,
+            &DrainV1Request {
+                reason: self.reason.clone(),
+                peer: self.peer.clone(),
+                grace_period: self.grace_period,
+                retry_interval: self.retry_interval,
+                shutdown: self.shutdown,
+            },
+        )
+        .await?;
+
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
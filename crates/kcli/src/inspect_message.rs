@@ -8,6 +8,10 @@ pub struct InspectMessageCommand {
     #[arg(long)]
     pub want_body: bool,
 
+    /// Include the message headers in the output
+    #[arg(long)]
+    pub want_headers: bool,
+
     pub id: String,
 }
 
@@ -17,6 +21,7 @@ impl InspectMessageCommand {
         let request = InspectMessageV1Request {
             id: self.id.clone().try_into()?,
             want_body: self.want_body,
+            want_headers: self.want_headers,
         };
         request.apply_to_url(&mut url);
 
@@ -0,0 +1,29 @@
+use clap::Parser;
+use kumo_api_types::{InspectScheduledQueuesV1Request, InspectScheduledQueuesV1Response};
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Returns the depth, age histogram and next due time of the
+/// scheduled queues
+pub struct InspectSchedQCommand {
+    /// Only show information about the named scheduled queue
+    #[arg(long)]
+    pub queue_name: Option<String>,
+}
+
+impl InspectSchedQCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut url = endpoint.join("/api/admin/inspect-sched-q/v1")?;
+        let request = InspectScheduledQueuesV1Request {
+            queue_name: self.queue_name.clone(),
+        };
+        request.apply_to_url(&mut url);
+
+        let result: InspectScheduledQueuesV1Response =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
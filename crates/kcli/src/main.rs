@@ -7,11 +7,14 @@ use std::time::Duration;
 mod bounce;
 mod bounce_cancel;
 mod bounce_list;
+mod drain;
 mod inspect_message;
+mod inspect_sched_q;
 mod logfilter;
 mod provider_summary;
 mod queue_summary;
 mod rebind;
+mod simulate;
 mod suspend;
 mod suspend_cancel;
 mod suspend_list;
@@ -56,7 +59,9 @@ enum SubCommand {
     Bounce(bounce::BounceCommand),
     BounceList(bounce_list::BounceListCommand),
     BounceCancel(bounce_cancel::BounceCancelCommand),
+    Drain(drain::DrainCommand),
     Rebind(rebind::RebindCommand),
+    Simulate(simulate::SimulateCommand),
     Suspend(suspend::SuspendCommand),
     SuspendList(suspend_list::SuspendListCommand),
     SuspendCancel(suspend_cancel::SuspendCancelCommand),
@@ -65,6 +70,7 @@ enum SubCommand {
     SuspendReadyQCancel(suspend_ready_q_cancel::SuspendReadyQCancelCommand),
     SetLogFilter(logfilter::SetLogFilterCommand),
     InspectMessage(inspect_message::InspectMessageCommand),
+    InspectSchedQ(inspect_sched_q::InspectSchedQCommand),
     ProviderSummary(provider_summary::ProviderSummaryCommand),
     QueueSummary(queue_summary::QueueSummaryCommand),
     TraceSmtpClient(trace_smtp_client::TraceSmtpClientCommand),
@@ -116,7 +122,9 @@ impl SubCommand {
             Self::Bounce(cmd) => cmd.run(endpoint).await,
             Self::BounceCancel(cmd) => cmd.run(endpoint).await,
             Self::BounceList(cmd) => cmd.run(endpoint).await,
+            Self::Drain(cmd) => cmd.run(endpoint).await,
             Self::Rebind(cmd) => cmd.run(endpoint).await,
+            Self::Simulate(cmd) => cmd.run(endpoint).await,
             Self::Suspend(cmd) => cmd.run(endpoint).await,
             Self::SuspendCancel(cmd) => cmd.run(endpoint).await,
             Self::SuspendList(cmd) => cmd.run(endpoint).await,
@@ -125,6 +133,7 @@ impl SubCommand {
             Self::SuspendReadyQList(cmd) => cmd.run(endpoint).await,
             Self::SetLogFilter(cmd) => cmd.run(endpoint).await,
             Self::InspectMessage(cmd) => cmd.run(endpoint).await,
+            Self::InspectSchedQ(cmd) => cmd.run(endpoint).await,
             Self::ProviderSummary(cmd) => cmd.run(endpoint).await,
             Self::QueueSummary(cmd) => cmd.run(endpoint).await,
             Self::TraceSmtpClient(cmd) => cmd.run(endpoint).await,
@@ -98,6 +98,11 @@ pub struct RebindCommand {
     #[arg(long)]
     always_flush: bool,
 
+    /// Retain the current due time of messages that move to a different
+    /// queue, rather than making them immediately eligible for delivery
+    #[arg(long)]
+    preserve_due_time: bool,
+
     /// Match all queues.
     #[arg(long)]
     everything: bool,
@@ -178,6 +183,7 @@ impl RebindCommand {
                 suppress_logging: self.suppress_logging,
                 data,
                 always_flush: self.always_flush,
+                preserve_due_time: self.preserve_due_time,
                 trigger_rebind_event: self.trigger_rebind_event,
             },
         )
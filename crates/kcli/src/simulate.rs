@@ -0,0 +1,103 @@
+use clap::Parser;
+use kumo_api_types::simulate::{SimulateReception, SimulateV1Request, SimulateV1Response};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+/// Reports what would happen to a hypothetical message.
+///
+/// The message is passed through the reception event of your policy,
+/// followed by the `get_queue_config`, `get_egress_pool` and
+/// `get_egress_path_config` events, and the resulting scheduled queue,
+/// metadata, DKIM signatures, queue configuration, egress pool and
+/// the effective shaping configuration of each egress source are printed
+/// as JSON.
+///
+/// The message is not spooled or delivered. The policy runs in an
+/// isolated lua context in which functions with side effects, such as
+/// HTTP requests, key/value store writes and queue administration, are
+/// stubbed out. The message will have its `simulated` meta item set to
+/// `true`, which your policy may use to skip any other side effects.
+/// The simulation endpoint must first be enabled in the server via
+/// `kumo.set_simulation_enabled`.
+///
+/// ## Examples
+///
+///    kcli simulate --sender sender@example.com --recipient user@example.net --tenant mytenant
+///
+pub struct SimulateCommand {
+    /// The envelope sender
+    #[arg(long)]
+    sender: String,
+
+    /// The envelope recipient
+    #[arg(long)]
+    recipient: String,
+
+    /// The tenant to assign prior to calling the reception event
+    #[arg(long)]
+    tenant: Option<String>,
+
+    /// The campaign to assign prior to calling the reception event
+    #[arg(long)]
+    campaign: Option<String>,
+
+    /// A JSON object of additional metadata to assign prior to
+    /// calling the reception event
+    #[arg(long)]
+    meta: Option<String>,
+
+    /// Read the message content, including headers, from this file.
+    /// Otherwise, a minimal message is synthesized.
+    #[arg(long)]
+    content: Option<PathBuf>,
+
+    /// Simulate reception via HTTP injection, triggering the
+    /// `http_message_generated` event rather than the
+    /// `smtp_server_message_received` event
+    #[arg(long)]
+    http: bool,
+
+    /// The address of the simulated client
+    #[arg(long)]
+    peer_address: Option<IpAddr>,
+}
+
+impl SimulateCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let meta: HashMap<String, serde_json::Value> = match &self.meta {
+            Some(meta) => serde_json::from_str(meta)?,
+            None => HashMap::new(),
+        };
+        let content = match &self.content {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => None,
+        };
+
+        let result: SimulateV1Response = crate::request_with_json_response(
+            reqwest::Method::POST,
+            endpoint.join("/api/admin/simulate/v1")?,
+            &SimulateV1Request {
+                sender: self.sender.clone(),
+                recipient: self.recipient.clone(),
+                tenant: self.tenant.clone(),
+                campaign: self.campaign.clone(),
+                meta,
+                content,
+                reception: if self.http {
+                    SimulateReception::Http
+                } else {
+                    SimulateReception::Esmtp
+                },
+                peer_address: self.peer_address,
+            },
+        )
+        .await?;
+
+        println!("{}", serde_json::to_string_pretty(&result)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+
+/// Registers a campaign, or updates the settings of a campaign
+/// that was previously registered with the same campaign and tenant.
+/// Updating a campaign preserves its progress and state.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct CampaignV1Request {
+    /// The campaign identifier, which is matched against the
+    /// `campaign` meta value of messages
+    #[schema(example = "spring-sale")]
+    pub campaign: String,
+
+    /// The tenant to which the campaign belongs, which is matched
+    /// against the `tenant` meta value of messages
+    #[serde(default)]
+    #[schema(example = "mytenant")]
+    pub tenant: Option<String>,
+
+    /// Meta values that are set on messages that belong to the
+    /// campaign, unless the message already has a value for them
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub metadata: BTreeMap<String, serde_json::Value>,
+
+    /// Messages received before this time are held until it arrives
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+
+    /// Messages received after this time are rejected
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+
+    /// Messages of campaigns with a higher priority are dispatched
+    /// ahead of those with a lower priority when they share a ready
+    /// queue. Also copied to the `campaign_priority` meta value of
+    /// the messages that belong to the campaign.
+    #[serde(default)]
+    pub priority: Option<i32>,
+
+    /// The maximum number of messages that will be accepted for
+    /// the campaign. Further messages are rejected.
+    #[serde(default)]
+    pub budget: Option<usize>,
+}
+
+/// Identifies a registered campaign
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct CampaignV1Selector {
+    #[schema(example = "spring-sale")]
+    pub campaign: String,
+
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum CampaignV1State {
+    /// The start time has not yet been reached
+    Scheduled,
+    Active,
+    /// Delivery of the queued messages is suspended
+    Paused,
+    /// The queued messages were bounced, and no more messages
+    /// will be accepted
+    Cancelled,
+    /// The end time has passed, or the budget has been used up
+    Ended,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum CampaignV1Action {
+    /// Suspend delivery of the messages that belong to the campaign.
+    /// Messages continue to be accepted and queued.
+    Pause,
+    /// Undo a prior Pause
+    Resume,
+    /// Bounce the queued messages that belong to the campaign,
+    /// and reject any further messages
+    Cancel,
+}
+
+/// Applies an action to a registered campaign
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CampaignV1ActionRequest {
+    #[schema(example = "spring-sale")]
+    pub campaign: String,
+
+    #[serde(default)]
+    pub tenant: Option<String>,
+
+    pub action: CampaignV1Action,
+
+    /// The reason for the action, which is recorded in the
+    /// suspension or bounce that implements it
+    #[serde(default)]
+    #[schema(example = "waiting for approval of the revised content")]
+    pub reason: Option<String>,
+
+    /// How long a Pause remains in effect. If omitted, the campaign
+    /// remains paused until it is resumed.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "2h")]
+    pub duration: Option<Duration>,
+}
+
+/// The aggregate progress of a campaign
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct CampaignV1Progress {
+    /// The number of messages that were accepted
+    pub received: usize,
+    pub delivered: usize,
+    /// The number of transient failures; a message may
+    /// experience several of these before it is delivered
+    pub transient_failures: usize,
+    pub bounced: usize,
+    pub expired: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct CampaignV1Entry {
+    #[serde(flatten)]
+    pub params: CampaignV1Request,
+
+    pub state: CampaignV1State,
+
+    /// The reason given for the current Paused or Cancelled state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// When the campaign was first registered
+    pub created: DateTime<Utc>,
+
+    pub progress: CampaignV1Progress,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, IntoParams)]
+pub struct CampaignV1ListRequest {
+    /// Only return campaigns that belong to this tenant
+    #[serde(default)]
+    pub tenant: Option<String>,
+
+    /// Only return the campaign with this identifier
+    #[serde(default)]
+    pub campaign: Option<String>,
+}
@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum ConnectionFilterAction {
+    /// Permit the connection to proceed to the listener
+    Allow,
+    /// Close the connection immediately, without a response
+    Deny,
+}
+
+/// An entry in the inbound connection filter
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionFilterV1Entry {
+    /// The address or CIDR block to match
+    #[schema(example = "10.0.0.0/8")]
+    pub cidr: String,
+
+    /// What to do with connections from matching addresses
+    pub action: ConnectionFilterAction,
+
+    /// An optional note explaining why the entry exists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "dictionary attack")]
+    pub reason: Option<String>,
+}
+
+/// Adds entries to the inbound connection filter. An entry
+/// with the same cidr as an existing entry replaces it.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ConnectionFilterV1Request {
+    pub entries: Vec<ConnectionFilterV1Entry>,
+}
+
+/// Removes entries from the inbound connection filter
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ConnectionFilterV1CancelRequest {
+    /// The cidr of each entry to remove
+    #[schema(example = json!(["10.0.0.0/8"]))]
+    pub cidrs: Vec<String>,
+}
@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Puts the node into drain mode, typically ahead of a restart
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DrainV1Request {
+    /// The reason for draining the node
+    #[schema(example = "rolling upgrade")]
+    pub reason: String,
+
+    /// If set, once the grace period has elapsed, any messages that
+    /// remain are relayed to this routing domain, which is expected to
+    /// resolve to another kumomta node.
+    /// Use a domain literal such as `[10.0.0.2]` to address a peer by IP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "[10.0.0.2]")]
+    pub peer: Option<String>,
+
+    /// How long to accelerate delivery of the queued messages before
+    /// handing off the remainder to the peer, or considering the node
+    /// to be drained if there is no peer. Defaults to 5 minutes.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "10m")]
+    pub grace_period: Option<Duration>,
+
+    /// How often the queued messages are made eligible for immediate
+    /// delivery while draining. Defaults to 1 minute.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "30s")]
+    pub retry_interval: Option<Duration>,
+
+    /// If true, the node shuts down once it has been drained
+    #[serde(default)]
+    pub shutdown: bool,
+}
+
+impl DrainV1Request {
+    pub fn default_grace_period() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    pub fn default_retry_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum DrainV1Phase {
+    /// Delivery of the queued messages is being accelerated
+    Delivering,
+    /// The remaining messages are being relayed to the peer
+    HandingOff,
+    /// There is nothing more for the drain to do
+    Drained,
+    /// The node has been drained and is shutting down
+    ShuttingDown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct DrainV1Status {
+    /// Whether the node is draining. While draining, no new
+    /// messages are accepted.
+    pub draining: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// When the node started draining
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started: Option<DateTime<Utc>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<DrainV1Phase>,
+
+    /// Whether the node will shut down once it has been drained
+    #[serde(default)]
+    pub shutdown: bool,
+
+    /// The number of messages in scheduled queues
+    pub scheduled: usize,
+
+    /// The number of messages in ready queues
+    pub ready: usize,
+}
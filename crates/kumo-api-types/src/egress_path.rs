@@ -10,8 +10,9 @@ use rustls::SupportedCipherSuite;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::time::Duration;
 use throttle::ThrottleSpec;
+use utoipa::ToSchema;
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Copy)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Copy, ToSchema)]
 pub enum Tls {
     /// Use it if available. If the peer has invalid or self-signed certificates, then
     /// delivery will fail. Will NOT fallback to not using TLS if the peer advertises
@@ -46,6 +47,17 @@ impl Default for Tls {
     }
 }
 
+/// What to do when the certificate presented by the peer does not
+/// match any of the `tls_pinned_spki_sha256` pins.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Copy, Default, ToSchema)]
+pub enum TlsPinMismatch {
+    /// Abandon the connection; the messages will be retried later
+    #[default]
+    TempFail,
+    /// Log the mismatch and continue with the delivery
+    Alert,
+}
+
 pub fn parse_openssl_options(option_list: &str) -> anyhow::Result<SslOptions> {
     let mut result = SslOptions::empty();
 
@@ -126,7 +138,41 @@ pub fn find_rustls_cipher_suite(name: &str) -> Option<SupportedCipherSuite> {
     None
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Default, Copy, PartialEq, Eq)]
+/// How the number of connections for a ready queue is derived from the
+/// number of messages that are ready for delivery. The result is always
+/// bounded by `connection_limit` and by the number of ready messages.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+pub enum ConnectionStrategy {
+    /// Follow an exponential curve that approaches `connection_limit`
+    /// as the number of ready messages grows
+    #[default]
+    Exponential,
+    /// Open `connection_limit` connections
+    Fixed,
+    /// Open one connection per `messages_per_connection` ready messages,
+    /// but no fewer than `min_connections` and no more than
+    /// `max_connections`
+    Proportional {
+        messages_per_connection: usize,
+        #[serde(default)]
+        min_connections: usize,
+        #[serde(default)]
+        max_connections: Option<usize>,
+    },
+    /// Scale the `Exponential` result by the ratio of `target_latency`
+    /// to the average time taken to deliver a message, so that fewer
+    /// connections are opened to slow destinations and more are
+    /// opened to fast destinations
+    LatencyAware {
+        #[serde(with = "duration_serde")]
+        #[schema(value_type = String)]
+        target_latency: Duration,
+        #[serde(default)]
+        min_connections: usize,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, Copy, PartialEq, Eq, ToSchema)]
 #[cfg_attr(feature = "lua", derive(FromLua))]
 pub enum ConfigRefreshStrategy {
     #[default]
@@ -134,7 +180,8 @@ pub enum ConfigRefreshStrategy {
     Epoch,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+/// The parameters accepted by `kumo.make_egress_path`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, ToSchema)]
 #[cfg_attr(feature = "lua", derive(FromLua))]
 #[serde(deny_unknown_fields)]
 pub struct EgressPathConfig {
@@ -142,8 +189,12 @@ pub struct EgressPathConfig {
     pub connection_limit: usize,
 
     #[serde(default)]
+    #[schema(value_type = Object)]
     pub additional_connection_limits: OrderMap<String, usize>,
 
+    #[serde(default)]
+    pub connection_strategy: ConnectionStrategy,
+
     #[serde(default)]
     pub enable_tls: Tls,
 
@@ -156,6 +207,16 @@ pub struct EgressPathConfig {
     #[serde(default)]
     pub tls_prefer_openssl: bool,
 
+    /// Base64 encoded SHA-256 hashes of the SubjectPublicKeyInfo of
+    /// certificates that the peer is expected to present. If non-empty,
+    /// at least one certificate in the presented chain must match one
+    /// of these pins.
+    #[serde(default)]
+    pub tls_pinned_spki_sha256: Vec<String>,
+
+    #[serde(default)]
+    pub tls_pin_mismatch: TlsPinMismatch,
+
     #[serde(default)]
     pub openssl_cipher_list: Option<String>,
     #[serde(default)]
@@ -165,6 +226,7 @@ pub struct EgressPathConfig {
         deserialize_with = "deserialize_ssl_options",
         skip_serializing // FIXME
     )]
+    #[schema(value_type = Option<String>)]
     pub openssl_options: Option<SslOptions>,
 
     #[serde(
@@ -172,6 +234,7 @@ pub struct EgressPathConfig {
         deserialize_with = "deserialize_supported_ciphersuite",
         skip_serializing // FIXME
     )]
+    #[schema(value_type = Vec<String>)]
     pub rustls_cipher_suites: Vec<SupportedCipherSuite>,
 
     #[serde(flatten)]
@@ -190,27 +253,66 @@ pub struct EgressPathConfig {
     pub smtp_auth_plain_username: Option<String>,
 
     #[serde(default)]
+    #[schema(value_type = Option<Object>)]
     pub smtp_auth_plain_password: Option<KeySource>,
 
     #[serde(default)]
     pub allow_smtp_auth_plain_without_tls: bool,
 
     #[serde(default)]
+    #[schema(value_type = Option<String>)]
     pub max_message_rate: Option<ThrottleSpec>,
 
     #[serde(default)]
+    #[schema(value_type = Object)]
     pub additional_message_rate_throttles: OrderMap<String, ThrottleSpec>,
 
     #[serde(default)]
+    #[schema(value_type = Option<String>)]
     pub max_connection_rate: Option<ThrottleSpec>,
 
     #[serde(default = "EgressPathConfig::default_max_deliveries_per_connection")]
     pub max_deliveries_per_connection: usize,
 
+    /// The maximum number of recipients to include in a single
+    /// transaction. Messages with the same sender and content,
+    /// such as the per-recipient copies of a multi-recipient
+    /// message, are combined into a single transaction up to
+    /// this limit. The default of 1 sends each message in its
+    /// own transaction.
+    #[serde(default = "EgressPathConfig::default_max_recipients_per_batch")]
+    pub max_recipients_per_batch: usize,
+
+    /// If set, connections that have been established for longer
+    /// than this duration will be closed rather than reused
+    #[serde(default, with = "duration_serde")]
+    #[schema(value_type = Option<String>)]
+    pub max_connection_age: Option<Duration>,
+
+    /// If set, idle connections are placed into a pool rather
+    /// than being closed, so that a subsequent dispatcher for the
+    /// same ready queue can reuse them without establishing a new
+    /// connection and TLS session.  This is how long a connection
+    /// may remain idle in the pool before it is closed.
+    #[serde(default, with = "duration_serde")]
+    #[schema(value_type = Option<String>)]
+    pub connection_pool_idle_timeout: Option<Duration>,
+
+    /// How long the ESMTP capabilities that were advertised by a
+    /// destination host are retained in the EHLO capability cache
+    #[serde(
+        default = "EgressPathConfig::default_ehlo_capability_cache_ttl",
+        with = "duration_serde"
+    )]
+    #[schema(value_type = String)]
+    pub ehlo_capability_cache_ttl: Duration,
+
     #[serde(default = "CidrSet::default_prohibited_hosts")]
+    #[schema(value_type = Vec<String>)]
     pub prohibited_hosts: CidrSet,
 
     #[serde(default)]
+    #[schema(value_type = Vec<String>)]
     pub skip_hosts: CidrSet,
 
     #[serde(default)]
@@ -234,6 +336,7 @@ pub struct EgressPathConfig {
         default = "EgressPathConfig::default_refresh_interval",
         with = "duration_serde"
     )]
+    #[schema(value_type = String)]
     pub refresh_interval: Duration,
     #[serde(default)]
     pub refresh_strategy: ConfigRefreshStrategy,
@@ -252,7 +355,10 @@ impl Default for EgressPathConfig {
     fn default() -> Self {
         Self {
             connection_limit: Self::default_connection_limit(),
+            connection_strategy: ConnectionStrategy::default(),
             tls_prefer_openssl: false,
+            tls_pinned_spki_sha256: vec![],
+            tls_pin_mismatch: TlsPinMismatch::default(),
             enable_tls: Tls::default(),
             enable_mta_sts: Self::default_enable_mta_sts(),
             enable_dane: Self::default_enable_dane(),
@@ -263,6 +369,10 @@ impl Default for EgressPathConfig {
             max_message_rate: None,
             max_connection_rate: None,
             max_deliveries_per_connection: Self::default_max_deliveries_per_connection(),
+            max_recipients_per_batch: Self::default_max_recipients_per_batch(),
+            max_connection_age: None,
+            connection_pool_idle_timeout: None,
+            ehlo_capability_cache_ttl: Self::default_ehlo_capability_cache_ttl(),
             client_timeouts: SmtpClientTimeouts::default(),
             prohibited_hosts: CidrSet::default_prohibited_hosts(),
             skip_hosts: CidrSet::default(),
@@ -286,6 +396,15 @@ impl Default for EgressPathConfig {
 }
 
 impl EgressPathConfig {
+    /// Returns true if no pins are configured, or if any of the
+    /// hashes in `peer_spki_sha256` match one of the configured pins
+    pub fn tls_pins_match(&self, peer_spki_sha256: &[String]) -> bool {
+        self.tls_pinned_spki_sha256.is_empty()
+            || peer_spki_sha256
+                .iter()
+                .any(|spki| self.tls_pinned_spki_sha256.contains(spki))
+    }
+
     fn default_connection_limit() -> usize {
         32
     }
@@ -314,7 +433,34 @@ impl EgressPathConfig {
         1024
     }
 
+    fn default_max_recipients_per_batch() -> usize {
+        1
+    }
+
     fn default_refresh_interval() -> Duration {
         Duration::from_secs(60)
     }
+
+    fn default_ehlo_capability_cache_ttl() -> Duration {
+        Duration::from_secs(3600)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tls_pins() {
+        let mut config = EgressPathConfig::default();
+        let leaf = "leaf".to_string();
+        let ca = "ca".to_string();
+        assert!(config.tls_pins_match(&[]));
+        assert!(config.tls_pins_match(&[leaf.clone()]));
+
+        config.tls_pinned_spki_sha256 = vec![ca.clone()];
+        assert!(!config.tls_pins_match(&[]));
+        assert!(!config.tls_pins_match(&[leaf.clone()]));
+        assert!(config.tls_pins_match(&[leaf, ca]));
+    }
 }
@@ -0,0 +1,57 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::{IntoParams, ToSchema};
+
+/// The ESMTP capabilities most recently advertised by a destination host
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct EhloCapabilitiesV1Entry {
+    /// The name of the destination host, usually from its MX record
+    #[schema(example = "mx.example.com")]
+    pub host: String,
+
+    #[schema(example = "10.0.0.1")]
+    pub address: String,
+
+    #[schema(example = 25)]
+    pub port: u16,
+
+    /// The capabilities advertised in response to the most recent
+    /// EHLO, which will have been issued after STARTTLS if TLS was
+    /// enabled. Each capability keyword maps to its parameters, if any.
+    #[schema(example = json!({"PIPELINING": null, "SIZE": "52428800", "CHUNKING": null}))]
+    pub capabilities: BTreeMap<String, Option<String>>,
+
+    /// Whether STARTTLS was advertised in response to the initial EHLO
+    pub starttls: bool,
+
+    /// Whether TLS was enabled on the connection
+    pub tls: bool,
+
+    /// When the capabilities were most recently observed
+    pub observed: DateTime<Utc>,
+
+    /// When the entry will be removed from the cache,
+    /// unless the capabilities are observed again
+    pub expires: DateTime<Utc>,
+
+    /// The number of times that the observed capabilities have
+    /// differed from those that were previously cached
+    pub changes: usize,
+
+    /// When the capabilities last differed from those that
+    /// were previously cached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_changed: Option<DateTime<Utc>>,
+
+    /// The capabilities that were cached prior to the most recent change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_capabilities: Option<BTreeMap<String, Option<String>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, IntoParams)]
+pub struct EhloCapabilitiesV1ListRequest {
+    /// Only return entries for this destination host name
+    #[serde(default)]
+    pub host: Option<String>,
+}
@@ -8,9 +8,17 @@ use url::Url;
 use utoipa::{IntoParams, ToResponse, ToSchema};
 use uuid::Uuid;
 
+pub mod campaign;
+pub mod connection_filter;
+pub mod drain;
 pub mod egress_path;
+pub mod ehlo_capabilities;
 pub mod rebind;
+pub mod reputation;
 pub mod shaping;
+pub mod simulate;
+pub mod source_exclusion;
+pub mod suppression;
 pub mod tsa;
 
 /// Describes which messages should be bounced.
@@ -75,26 +83,20 @@ pub struct BounceV1Response {
     /// This can be used later to delete the rule if desired.
     #[schema(example = "552016f1-08e7-4e90-9da3-fd5c25acd069")]
     pub id: Uuid,
-    /// Deprecated: this field is no longer populated, as bounces
-    /// are now always asynchronous. In earlier versions the following
-    /// applies:
-    ///
-    /// A map of queue name to number of bounced messages that
-    /// were processed as part of the initial sweep.
+    /// A map of queue name to the number of messages that were in
+    /// that queue at the time of the request, and which are being
+    /// bounced asynchronously by the initial sweep.
     /// Additional bounces may be generated if/when other messages
-    /// that match the rule are discovered, but those obviously
-    /// cannot be reported in the context of the initial request.
-    #[schema(deprecated, example=json!({
+    /// that match the rule are discovered; use the list API to
+    /// review the running totals.
+    #[schema(example=json!({
         "gmail.com": 200,
         "yahoo.com": 100
     }))]
     pub bounced: HashMap<String, usize>,
-    /// Deprecated: this field is no longer populated, as bounces are
-    /// now always asynchronous. In earlier versions the following applies:
-    ///
-    /// The sum of the number of bounced messages reported by
+    /// The sum of the number of messages reported by
     /// the `bounced` field.
-    #[schema(deprecated, example = 300)]
+    #[schema(example = 300)]
     pub total_bounced: usize,
 }
 
@@ -278,6 +280,10 @@ pub struct InspectMessageV1Request {
     /// metadata
     #[serde(default)]
     pub want_body: bool,
+    /// If true, return the parsed message headers in addition
+    /// to the metadata
+    #[serde(default)]
+    pub want_headers: bool,
 }
 
 impl InspectMessageV1Request {
@@ -287,6 +293,9 @@ impl InspectMessageV1Request {
         if self.want_body {
             query.append_pair("want_body", "true");
         }
+        if self.want_headers {
+            query.append_pair("want_headers", "true");
+        }
     }
 }
 
@@ -315,6 +324,75 @@ pub struct MessageInformation {
     /// holds the message body
     #[serde(default)]
     pub data: Option<String>,
+    /// If `want_headers` was set in the original request,
+    /// holds the message headers as a list of name, value pairs
+    /// in the order that they appear in the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type=Option<Vec<Vec<String>>>, example=json!([
+        ["Subject", "Hello"],
+        ["From", "sender@sender.example.com"]
+    ]))]
+    pub headers: Option<Vec<(String, String)>>,
+    /// The number of delivery attempts that have been made
+    #[serde(default)]
+    pub num_attempts: u16,
+    /// When the next delivery attempt is due, if the message
+    /// has been delayed
+    #[serde(default)]
+    pub due: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, IntoParams)]
+pub struct InspectScheduledQueuesV1Request {
+    /// If set, only the scheduled queue with this name will be
+    /// returned, otherwise all scheduled queues are returned
+    #[serde(default)]
+    pub queue_name: Option<String>,
+}
+
+impl InspectScheduledQueuesV1Request {
+    pub fn apply_to_url(&self, url: &mut Url) {
+        let mut query = url.query_pairs_mut();
+        if let Some(queue_name) = &self.queue_name {
+            query.append_pair("queue_name", queue_name);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToResponse, ToSchema)]
+pub struct InspectScheduledQueuesV1Response {
+    /// The scheduled queues, ordered by decreasing depth
+    pub queues: Vec<ScheduledQueueV1Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ScheduledQueueV1Entry {
+    /// The name of the scheduled queue
+    #[schema(example = "campaign:tenant@example.com")]
+    pub name: String,
+    /// The number of messages in the scheduled queue
+    pub depth: usize,
+    /// The earliest time at which a message in the queue is due
+    /// for a delivery attempt. Omitted when the queue is empty.
+    #[serde(default)]
+    pub next_due: Option<DateTime<Utc>>,
+    /// The number of messages in the queue, grouped by age.
+    pub age_histogram: Vec<AgeHistogramBucket>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct AgeHistogramBucket {
+    /// The exclusive upper bound on the age of the messages counted
+    /// in this bucket. The final bucket has no upper bound.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(example = "1h")]
+    pub max_age: Option<Duration>,
+    /// The number of messages in this bucket
+    pub count: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug, ToSchema)]
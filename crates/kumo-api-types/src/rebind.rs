@@ -53,6 +53,13 @@ pub struct RebindV1Request {
     /// queue has changed will be made immediately eligible.
     #[serde(default)]
     pub always_flush: bool,
+
+    /// If true, messages that are moved to a different queue will
+    /// retain their current due time, rather than being made
+    /// immediately eligible for delivery.
+    /// `always_flush` takes precedence over this option.
+    #[serde(default)]
+    pub preserve_due_time: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, ToSchema, ToResponse)]
@@ -0,0 +1,46 @@
+#[cfg(feature = "lua")]
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use utoipa::{ToResponse, ToSchema};
+
+/// The rolling delivery statistics for a sender domain or tenant
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ReputationV1Entry {
+    /// The sender domain or tenant name
+    #[schema(example = "example.com")]
+    pub name: String,
+
+    /// The number of messages that were delivered within the window
+    pub delivered: usize,
+
+    /// The number of messages that permanently failed, expired,
+    /// or were reported by an out-of-band bounce within the window
+    pub bounced: usize,
+
+    /// The number of complaints (ARF feedback reports) that were
+    /// received within the window
+    pub complaints: usize,
+
+    /// `bounced / (delivered + bounced)`, or 0 if there is no volume
+    #[schema(example = 0.02)]
+    pub bounce_rate: f64,
+
+    /// `complaints / delivered`, or 0 if nothing was delivered
+    #[schema(example = 0.001)]
+    pub complaint_rate: f64,
+}
+
+#[cfg(feature = "lua")]
+impl<'lua> IntoLua<'lua> for ReputationV1Entry {
+    fn into_lua(self, lua: &'lua Lua) -> LuaResult<LuaValue<'lua>> {
+        lua.to_value(&self)
+    }
+}
+
+/// The current rolling statistics for all of the sender domains
+/// and tenants that have had activity within the configured window
+#[derive(Serialize, Deserialize, Debug, ToSchema, ToResponse)]
+pub struct ReputationV1Response {
+    pub sender_domains: Vec<ReputationV1Entry>,
+    pub tenants: Vec<ReputationV1Entry>,
+}
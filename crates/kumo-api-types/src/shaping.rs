@@ -6,6 +6,8 @@ use config::any_err;
 #[cfg(feature = "lua")]
 use config::serialize_options;
 #[cfg(feature = "lua")]
+use data_loader::{interpolate_secrets, InterpolateSecrets, KeySource, KEY_SOURCE_PLACEHOLDER};
+#[cfg(feature = "lua")]
 use dns_resolver::{fully_qualify, MailExchanger};
 #[cfg(feature = "lua")]
 use kumo_log_types::JsonLogRecord;
@@ -14,6 +16,8 @@ use mlua::prelude::LuaUserData;
 #[cfg(feature = "lua")]
 use mlua::{LuaSerdeExt, UserDataMethods};
 use ordermap::OrderMap;
+#[cfg(feature = "lua")]
+use serde::de::DeserializeOwned;
 use serde::de::{SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_with::formats::PreferOne;
@@ -197,6 +201,7 @@ pub enum Action {
     SetConfig(EgressPathConfigValue),
     SuspendTenant,
     SuspendCampaign,
+    ExcludeSource,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Hash, Default)]
@@ -345,10 +350,15 @@ impl ShapingInner {
         // mx site_name.
         // NOTE: this is coupled with the logic in
         // ReadyQueueManager::compute_queue_name
-        let site_name = record
-            .site
+        let site = match record.site.rsplit_once('#') {
+            // Remove any shaping_tier suffix
+            Some((site, _tier)) => site,
+            None => record.site.as_str(),
+        };
+        let site_name = site
             .trim_start_matches(&format!("{source}->"))
             .trim_end_matches("@smtp_client")
+            .trim_end_matches("@lmtp_client")
             .to_string();
 
         Ok(self.match_rules_impl(record, &domain, &site_name).await)
@@ -423,16 +433,113 @@ pub struct Shaping {
     inner: Arc<ShapingInner>,
 }
 
+/// A json value that, unlike `serde_json::Value`, retains the
+/// order of the keys of its objects. The order is significant
+/// for the `OrderMap` fields of the shaping file.
+#[cfg(feature = "lua")]
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum OrderedJson {
+    Null,
+    Bool(bool),
+    Number(serde_json::Number),
+    String(String),
+    Array(Vec<OrderedJson>),
+    Object(OrderMap<String, OrderedJson>),
+}
+
+#[cfg(feature = "lua")]
+impl OrderedJson {
+    fn key_source(&self) -> Option<&OrderedJson> {
+        match self {
+            Self::Object(map) if map.len() == 1 => map.get(KEY_SOURCE_PLACEHOLDER),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "lua")]
+impl InterpolateSecrets for OrderedJson {
+    fn collect_sources(&self, sources: &mut Vec<KeySource>) -> anyhow::Result<()> {
+        if let Some(source) = self.key_source() {
+            let json = serde_json::to_string(source)?;
+            sources.push(
+                serde_json::from_str(&json)
+                    .with_context(|| format!("invalid {KEY_SOURCE_PLACEHOLDER} {json}"))?,
+            );
+            return Ok(());
+        }
+        match self {
+            Self::Object(map) => {
+                for value in map.values() {
+                    value.collect_sources(sources)?;
+                }
+            }
+            Self::Array(values) => {
+                for value in values {
+                    value.collect_sources(sources)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn replace_sources(&mut self, resolved: &std::collections::HashMap<KeySource, String>) {
+        let replacement = self
+            .key_source()
+            .and_then(|source| serde_json::to_string(source).ok())
+            .and_then(|json| serde_json::from_str::<KeySource>(&json).ok())
+            .and_then(|source| resolved.get(&source));
+        if let Some(value) = replacement {
+            *self = Self::String(value.clone());
+            return;
+        }
+        match self {
+            Self::Object(map) => {
+                for value in map.values_mut() {
+                    value.replace_sources(resolved);
+                }
+            }
+            Self::Array(values) => {
+                for value in values {
+                    value.replace_sources(resolved);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parses json, resolving any secret placeholders before
+/// deserializing the result into T
 #[cfg(feature = "lua")]
-fn from_json<'a, T: Deserialize<'a>>(json: &'a str) -> anyhow::Result<T> {
-    let d = &mut serde_json::Deserializer::from_str(json);
-    Ok(serde_path_to_error::deserialize(d)?)
+async fn from_json<T: DeserializeOwned>(json: &str) -> anyhow::Result<T> {
+    let mut value: OrderedJson = serde_json::from_str(json)?;
+    let mut sources = vec![];
+    value.collect_sources(&mut sources)?;
+    let interpolated;
+    let json = if sources.is_empty() {
+        // Deserialize the original text, so that errors
+        // report the line and column from the file
+        json
+    } else {
+        interpolate_secrets(&mut value).await?;
+        interpolated = serde_json::to_string_pretty(&value)?;
+        &interpolated
+    };
+    Ok(serde_path_to_error::deserialize(
+        &mut serde_json::Deserializer::from_str(json),
+    )?)
 }
 
+/// Parses toml, resolving any secret placeholders before
+/// deserializing the result into T
 #[cfg(feature = "lua")]
-fn from_toml<'a, T: Deserialize<'a>>(toml: &'a str) -> anyhow::Result<T> {
-    let d = toml::Deserializer::new(toml);
-    Ok(serde_path_to_error::deserialize(d)?)
+async fn from_toml<T: DeserializeOwned>(toml: &str) -> anyhow::Result<T> {
+    let mut value: toml::Value = toml::from_str(toml)?;
+    interpolate_secrets(&mut value).await?;
+    Ok(serde_path_to_error::deserialize(value)?)
 }
 
 #[cfg(feature = "lua")]
@@ -466,17 +573,21 @@ impl Shaping {
         };
 
         if path.ends_with(".toml") {
-            from_toml(&data).with_context(|| format!("parsing toml from file {path}"))
+            from_toml(&data)
+                .await
+                .with_context(|| format!("parsing toml from file {path}"))
         } else if path.ends_with(".json") {
-            from_json(&data).with_context(|| format!("parsing json from file {path}"))
+            from_json(&data)
+                .await
+                .with_context(|| format!("parsing json from file {path}"))
         } else {
             // Try parsing both ways and see which wins
             let mut errors = vec![];
-            match from_toml(&data) {
+            match from_toml(&data).await {
                 Ok(s) => return Ok(s),
                 Err(err) => errors.push(format!("as toml: {err:#}")),
             }
-            match from_json(&data) {
+            match from_json(&data).await {
                 Ok(s) => return Ok(s),
                 Err(err) => errors.push(format!("as json: {err:#}")),
             }
@@ -1287,6 +1398,69 @@ additional_message_rate_throttles = {"second"="100/hr"}
         );
     }
 
+    #[tokio::test]
+    async fn test_secret_interpolation() {
+        let shaping = make_shaping_configs(&[r#"
+["example.com"]
+mx_rollup = false
+smtp_auth_plain_username = { key_source = { key_data = "scott\n" } }
+        "#])
+        .await;
+
+        let resolved = shaping
+            .get_egress_path_config("example.com", "invalid.source", "invalid.site")
+            .await
+            .finish()
+            .unwrap();
+
+        assert_eq!(
+            resolved.params.smtp_auth_plain_username.as_deref(),
+            Some("scott")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_order_and_secrets() {
+        // Both providers match; the last one in file order wins
+        let shaping = make_shaping_configs(&[r#"{
+    "example.com": {
+        "mx_rollup": false,
+        "smtp_auth_plain_username": {"key_source": {"key_data": "scott\n"}}
+    },
+    "provider": {
+        "Zeta": {"match": [{"DomainSuffix": ".example.com"}]},
+        "Alpha": {"match": [{"DomainSuffix": ".example.com"}]}
+    }
+}"#])
+        .await;
+
+        let resolved = shaping
+            .get_egress_path_config("example.com", "invalid.source", "invalid.site")
+            .await
+            .finish()
+            .unwrap();
+
+        k9::assert_equal!(resolved.params.provider_name.unwrap(), "Alpha");
+        assert_eq!(
+            resolved.params.smtp_auth_plain_username.as_deref(),
+            Some("scott")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_error_position() {
+        let err = from_json::<ShapingFile>(
+            r#"{
+    "example.com": {"mx_rollup": 1}
+}"#,
+        )
+        .await
+        .unwrap_err();
+        // The error refers to a location in the file
+        let err = format!("{err:#}");
+        assert!(err.contains(" at line "), "{err}");
+    }
+
     #[tokio::test]
     async fn test_provider() {
         let shaping = make_shaping_configs(&[r#"
@@ -1389,6 +1563,7 @@ duration = "1hr"
                 egress_source: None,
                 source_address: None,
                 feedback_report: None,
+                original_message_id: None,
                 meta: Default::default(),
                 headers: Default::default(),
                 delivery_protocol: None,
@@ -1534,10 +1709,13 @@ MergedEntry {
     params: EgressPathConfig {
         connection_limit: 10,
         additional_connection_limits: {},
+        connection_strategy: Exponential,
         enable_tls: Opportunistic,
         enable_mta_sts: true,
         enable_dane: false,
         tls_prefer_openssl: false,
+        tls_pinned_spki_sha256: [],
+        tls_pin_mismatch: TempFail,
         openssl_cipher_list: None,
         openssl_cipher_suites: None,
         openssl_options: None,
@@ -1569,6 +1747,10 @@ MergedEntry {
             100/m,
         ),
         max_deliveries_per_connection: 100,
+        max_recipients_per_batch: 1,
+        max_connection_age: None,
+        connection_pool_idle_timeout: None,
+        ehlo_capability_cache_ttl: 3600s,
         prohibited_hosts: CidrSet(
             CidrMap {
                 root: Some(
@@ -1695,10 +1877,13 @@ MergedEntry {
     params: EgressPathConfig {
         connection_limit: 3,
         additional_connection_limits: {},
+        connection_strategy: Exponential,
         enable_tls: Opportunistic,
         enable_mta_sts: true,
         enable_dane: false,
         tls_prefer_openssl: false,
+        tls_pinned_spki_sha256: [],
+        tls_pin_mismatch: TempFail,
         openssl_cipher_list: None,
         openssl_cipher_suites: None,
         openssl_options: None,
@@ -1730,6 +1915,10 @@ MergedEntry {
             100/m,
         ),
         max_deliveries_per_connection: 100,
+        max_recipients_per_batch: 1,
+        max_connection_age: None,
+        connection_pool_idle_timeout: None,
+        ehlo_capability_cache_ttl: 3600s,
         prohibited_hosts: CidrSet(
             CidrMap {
                 root: Some(
@@ -1775,10 +1964,13 @@ MergedEntry {
         "my source name": EgressPathConfig {
             connection_limit: 5,
             additional_connection_limits: {},
+            connection_strategy: Exponential,
             enable_tls: Opportunistic,
             enable_mta_sts: true,
             enable_dane: false,
             tls_prefer_openssl: false,
+            tls_pinned_spki_sha256: [],
+            tls_pin_mismatch: TempFail,
             openssl_cipher_list: None,
             openssl_cipher_suites: None,
             openssl_options: None,
@@ -1806,6 +1998,10 @@ MergedEntry {
             additional_message_rate_throttles: {},
             max_connection_rate: None,
             max_deliveries_per_connection: 1024,
+            max_recipients_per_batch: 1,
+            max_connection_age: None,
+            connection_pool_idle_timeout: None,
+            ehlo_capability_cache_ttl: 3600s,
             prohibited_hosts: CidrSet(
                 CidrMap {
                     root: Some(
@@ -1938,10 +2134,13 @@ MergedEntry {
     params: EgressPathConfig {
         connection_limit: 10,
         additional_connection_limits: {},
+        connection_strategy: Exponential,
         enable_tls: Opportunistic,
         enable_mta_sts: true,
         enable_dane: false,
         tls_prefer_openssl: false,
+        tls_pinned_spki_sha256: [],
+        tls_pin_mismatch: TempFail,
         openssl_cipher_list: None,
         openssl_cipher_suites: None,
         openssl_options: None,
@@ -1973,6 +2172,10 @@ MergedEntry {
             100/m,
         ),
         max_deliveries_per_connection: 20,
+        max_recipients_per_batch: 1,
+        max_connection_age: None,
+        connection_pool_idle_timeout: None,
+        ehlo_capability_cache_ttl: 3600s,
         prohibited_hosts: CidrSet(
             CidrMap {
                 root: Some(
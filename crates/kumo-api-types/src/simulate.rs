@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use utoipa::{ToResponse, ToSchema};
+
+/// Which reception path to simulate
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub enum SimulateReception {
+    /// Trigger the `smtp_server_message_received` event
+    #[default]
+    Esmtp,
+    /// Trigger the `http_message_generated` event
+    Http,
+}
+
+/// Describes a hypothetical message to evaluate against the policy
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SimulateV1Request {
+    /// The envelope sender
+    #[schema(example = "sender@example.com")]
+    pub sender: String,
+
+    /// The envelope recipient
+    #[schema(example = "recipient@example.com")]
+    pub recipient: String,
+
+    /// The tenant to assign to the message prior to calling the
+    /// reception event
+    #[serde(default)]
+    pub tenant: Option<String>,
+
+    /// The campaign to assign to the message prior to calling the
+    /// reception event
+    #[serde(default)]
+    pub campaign: Option<String>,
+
+    /// Additional metadata to assign to the message prior to calling
+    /// the reception event
+    #[serde(default)]
+    pub meta: HashMap<String, serde_json::Value>,
+
+    /// The complete message content, including headers. If omitted,
+    /// a minimal message is synthesized from the sender and recipient.
+    #[serde(default)]
+    pub content: Option<String>,
+
+    #[serde(default)]
+    pub reception: SimulateReception,
+
+    /// The address of the simulated client
+    #[serde(default)]
+    #[schema(value_type=Option<String>, example = "10.0.0.1")]
+    pub peer_address: Option<IpAddr>,
+}
+
+/// A DKIM signature that was added by the policy
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SimulateV1DkimSignature {
+    /// The signing domain (`d=`)
+    pub domain: String,
+    /// The selector (`s=`)
+    pub selector: String,
+}
+
+/// The outcome of resolving one of the egress sources of the pool
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SimulateV1Source {
+    /// The name of the egress source
+    pub name: String,
+    /// The weight of the source within the pool
+    pub weight: u32,
+    /// The name of the ready queue that would be used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ready_queue: Option<String>,
+    /// The site name that would be used for shaping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+    /// The effective egress path configuration, which includes
+    /// the shaping parameters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_config: Option<serde_json::Value>,
+    /// Set if the configuration could not be resolved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema, ToResponse)]
+pub struct SimulateV1Response {
+    /// Set if the policy rejected the message during reception;
+    /// none of the remaining fields are populated in that case.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "550 5.7.1 relaying not permitted")]
+    pub rejected: Option<String>,
+
+    /// The scheduled queue to which the message would be assigned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "campaign:tenant@example.com")]
+    pub queue: Option<String>,
+
+    /// The message metadata after the reception event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<serde_json::Value>,
+
+    /// The DKIM signatures that were added by the policy
+    #[serde(default)]
+    pub dkim_signatures: Vec<SimulateV1DkimSignature>,
+
+    /// The effective queue configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_config: Option<serde_json::Value>,
+
+    /// The name of the egress pool
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress_pool: Option<String>,
+
+    /// The egress sources of the pool
+    #[serde(default)]
+    pub sources: Vec<SimulateV1Source>,
+}
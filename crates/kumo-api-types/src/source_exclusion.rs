@@ -0,0 +1,89 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+
+/// Temporarily takes an egress source out of rotation for a destination
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SourceExclusionV1Request {
+    /// The name of the egress source to exclude
+    #[schema(example = "ip-1")]
+    pub source: String,
+
+    /// The destination from which the source is excluded. This is
+    /// matched against both the provider name and the site name of
+    /// the ready queue for the source.
+    #[schema(example = "gmail")]
+    pub destination: String,
+
+    /// The reason for the exclusion
+    #[schema(example = "listed on a blocklist by the destination")]
+    pub reason: String,
+
+    /// How long the exclusion remains in effect.
+    /// Defaults to 1 hour.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "2h")]
+    pub duration: Option<Duration>,
+
+    /// When the exclusion ends. Takes precedence over duration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl SourceExclusionV1Request {
+    pub fn default_duration() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    /// Returns the time at which the exclusion should end
+    pub fn expires(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        if let Some(expires) = self.expires {
+            return expires;
+        }
+        let duration = self.duration.unwrap_or_else(Self::default_duration);
+        chrono::Duration::from_std(duration)
+            .ok()
+            .and_then(|duration| now.checked_add_signed(duration))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+}
+
+/// Identifies an exclusion, so that it can be lifted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SourceExclusionV1CancelRequest {
+    #[schema(example = "ip-1")]
+    pub source: String,
+    #[schema(example = "gmail")]
+    pub destination: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SourceExclusionV1Entry {
+    #[schema(example = "ip-1")]
+    pub source: String,
+    #[schema(example = "gmail")]
+    pub destination: String,
+    pub reason: String,
+    /// When the exclusion was put in place
+    pub created: DateTime<Utc>,
+    /// When the source will be restored to rotation
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, IntoParams)]
+pub struct SourceExclusionV1ListRequest {
+    /// Only return exclusions for this egress source
+    #[serde(default)]
+    pub source: Option<String>,
+
+    /// Only return exclusions for this destination
+    #[serde(default)]
+    pub destination: Option<String>,
+}
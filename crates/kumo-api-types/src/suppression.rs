@@ -0,0 +1,111 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum SuppressionReason {
+    /// Delivery to the recipient failed permanently
+    Bounce,
+    /// The recipient reported a message as spam
+    Complaint,
+    /// The entry was added by an operator or by policy
+    Manual,
+}
+
+impl SuppressionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Bounce => "Bounce",
+            Self::Complaint => "Complaint",
+            Self::Manual => "Manual",
+        }
+    }
+}
+
+impl std::str::FromStr for SuppressionReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "Bounce" => Ok(Self::Bounce),
+            "Complaint" => Ok(Self::Complaint),
+            "Manual" => Ok(Self::Manual),
+            _ => Err(format!("invalid suppression reason '{s}'")),
+        }
+    }
+}
+
+/// An entry in the suppression list
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SuppressionV1Entry {
+    /// The suppressed recipient address
+    #[schema(example = "user@example.com")]
+    pub recipient: String,
+
+    /// Why the recipient was suppressed
+    pub reason: SuppressionReason,
+
+    /// Further detail, such as the response that caused the bounce
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "550 5.1.1 no such user")]
+    pub description: Option<String>,
+
+    /// When the entry was added
+    pub created: DateTime<Utc>,
+}
+
+/// A recipient to be added to the suppression list
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SuppressionV1AddEntry {
+    #[schema(example = "user@example.com")]
+    pub recipient: String,
+
+    #[serde(default = "SuppressionV1AddEntry::default_reason")]
+    pub reason: SuppressionReason,
+
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+impl SuppressionV1AddEntry {
+    fn default_reason() -> SuppressionReason {
+        SuppressionReason::Manual
+    }
+}
+
+/// Adds recipients to the suppression list. Adding a recipient
+/// that is already suppressed replaces its entry.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SuppressionV1Request {
+    pub entries: Vec<SuppressionV1AddEntry>,
+}
+
+/// Removes recipients from the suppression list
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SuppressionV1CancelRequest {
+    #[schema(example = json!(["user@example.com"]))]
+    pub recipients: Vec<String>,
+}
+
+/// Queries the suppression list
+#[derive(Serialize, Deserialize, Debug, IntoParams)]
+pub struct SuppressionV1ListRequest {
+    /// Only return the entry for this recipient
+    #[serde(default)]
+    pub recipient: Option<String>,
+
+    /// Only return entries for recipients in this domain
+    #[serde(default)]
+    pub domain: Option<String>,
+
+    /// The maximum number of entries to return
+    #[serde(default = "SuppressionV1ListRequest::default_limit")]
+    pub limit: usize,
+}
+
+impl SuppressionV1ListRequest {
+    fn default_limit() -> usize {
+        1000
+    }
+}
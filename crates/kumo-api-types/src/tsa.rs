@@ -5,6 +5,7 @@ use serde::Serialize;
 pub struct Suspensions {
     pub ready_q: Vec<ReadyQSuspension>,
     pub sched_q: Vec<SchedQSuspension>,
+    pub source_exclusions: Vec<SourceExclusion>,
 }
 
 #[derive(Serialize, Default, Clone)]
@@ -26,8 +27,20 @@ pub struct SchedQSuspension {
     pub expires: DateTime<Utc>,
 }
 
+#[derive(Serialize, Default, Clone)]
+pub struct SourceExclusion {
+    pub rule_hash: String,
+    pub source: String,
+    /// The provider name, or the site name if the destination
+    /// is not associated with a provider
+    pub destination: String,
+    pub reason: String,
+    pub expires: DateTime<Utc>,
+}
+
 #[derive(Serialize, Clone)]
 pub enum SuspensionEntry {
     ReadyQ(ReadyQSuspension),
     SchedQ(SchedQSuspension),
+    SourceExclusion(SourceExclusion),
 }
@@ -87,6 +87,11 @@ pub struct JsonLogRecord {
 
     pub feedback_report: Option<ARFReport>,
 
+    /// For OOB records, the Message-ID of the original message,
+    /// if it was included in the delivery status report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_message_id: Option<String>,
+
     pub meta: HashMap<String, Value>,
     pub headers: HashMap<String, Value>,
 
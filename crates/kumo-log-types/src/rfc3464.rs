@@ -5,7 +5,7 @@ use crate::rfc5965::{
 };
 use anyhow::{anyhow, Context};
 use chrono::{DateTime, Utc};
-use mailparsing::MimePart;
+use mailparsing::{Header, HeaderParseResult, MimePart};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::str::FromStr;
@@ -223,6 +223,10 @@ impl PerMessageReportEntry {
 pub struct Report {
     pub per_message: PerMessageReportEntry,
     pub per_recipient: Vec<PerRecipientReportEntry>,
+    /// The Message-ID of the original message, if it was included
+    /// in the report
+    #[serde(default)]
+    pub original_message_id: Option<String>,
     pub original_message: Option<String>,
 }
 
@@ -231,6 +235,20 @@ pub(crate) fn content_type(part: &MimePart) -> Option<String> {
     Some(ct.value)
 }
 
+/// Extracts the Message-ID from the headers of the original message
+/// part of a report, without its angle brackets
+pub(crate) fn original_message_id(part: &MimePart) -> Option<String> {
+    let HeaderParseResult { headers, .. } = Header::parse_headers(part.raw_body()).ok()?;
+    let id = headers.get_first("Message-ID")?.get_raw_value().trim();
+    let id = id.strip_prefix('<').unwrap_or(id);
+    let id = id.strip_suffix('>').unwrap_or(id);
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_string())
+    }
+}
+
 impl Report {
     pub fn parse(input: &[u8]) -> anyhow::Result<Option<Self>> {
         let mail = MimePart::parse(input).with_context(|| {
@@ -245,11 +263,13 @@ impl Report {
         }
 
         let mut original_message = None;
+        let mut original_message_id = None;
 
         for part in mail.child_parts() {
             let ct = content_type(part);
             let ct = ct.as_deref();
             if ct == Some("message/rfc822") || ct == Some("text/rfc822-headers") {
+                original_message_id = self::original_message_id(part);
                 original_message = Some(part.raw_body().replace("\r\n", "\n"));
             }
         }
@@ -259,14 +279,22 @@ impl Report {
             let ct = ct.as_deref();
             if ct == Some("message/delivery-status") || ct == Some("message/global-delivery-status")
             {
-                return Ok(Some(Self::parse_inner(part, original_message)?));
+                return Ok(Some(Self::parse_inner(
+                    part,
+                    original_message_id,
+                    original_message,
+                )?));
             }
         }
 
         anyhow::bail!("delivery-status part missing");
     }
 
-    fn parse_inner(part: &MimePart, original_message: Option<String>) -> anyhow::Result<Self> {
+    fn parse_inner(
+        part: &MimePart,
+        original_message_id: Option<String>,
+        original_message: Option<String>,
+    ) -> anyhow::Result<Self> {
         let body = part.raw_body();
         let body = body.replace("\r\n", "\n");
         let mut parts = body.trim().split("\n\n");
@@ -284,6 +312,7 @@ impl Report {
         Ok(Self {
             per_message,
             per_recipient,
+            original_message_id,
             original_message,
         })
     }
@@ -346,6 +375,7 @@ Some(
                 extensions: {},
             },
         ],
+        original_message_id: None,
         original_message: Some(
             "[original message goes here]
 
@@ -477,6 +507,7 @@ Some(
                 extensions: {},
             },
         ],
+        original_message_id: None,
         original_message: Some(
             "[original message goes here]
 
@@ -531,6 +562,7 @@ Some(
                 extensions: {},
             },
         ],
+        original_message_id: None,
         original_message: None,
     },
 )
@@ -581,10 +613,84 @@ Some(
                 extensions: {},
             },
         ],
+        original_message_id: None,
         original_message: None,
     },
 )
 "#
         );
     }
+
+    fn make_report(original_content_type: &str, original: &str) -> String {
+        format!(
+            "From: MAILER-DAEMON@example.com\r\n\
+             Subject: Delivery Status Notification\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: multipart/report; report-type=delivery-status;\r\n\
+             \tboundary=\"BOUNDARY\"\r\n\
+             \r\n\
+             --BOUNDARY\r\n\
+             Content-Type: message/delivery-status\r\n\
+             \r\n\
+             Reporting-MTA: dns; mx.example.com\r\n\
+             \r\n\
+             Final-Recipient: rfc822;user@example.com\r\n\
+             Action: failed\r\n\
+             Status: 5.1.1\r\n\
+             \r\n\
+             --BOUNDARY\r\n\
+             Content-Type: {original_content_type}\r\n\
+             \r\n\
+             {original}\
+             \r\n\
+             --BOUNDARY--\r\n"
+        )
+    }
+
+    #[test]
+    fn original_message_id() {
+        let report = make_report(
+            "message/rfc822",
+            "From: sender@example.net\r\n\
+             To: user@example.com\r\n\
+             Message-ID: <abc.123@example.net>\r\n\
+             Subject: hello\r\n\
+             \r\n\
+             hello there\r\n",
+        );
+        let result = Report::parse(report.as_bytes()).unwrap().unwrap();
+        k9::assert_equal!(
+            result.original_message_id.as_deref(),
+            Some("abc.123@example.net")
+        );
+    }
+
+    #[test]
+    fn original_message_id_from_headers_only() {
+        let report = make_report(
+            "text/rfc822-headers",
+            "From: sender@example.net\r\n\
+             Message-Id:   <headers.only@example.net>  \r\n\
+             \r\n",
+        );
+        let result = Report::parse(report.as_bytes()).unwrap().unwrap();
+        k9::assert_equal!(
+            result.original_message_id.as_deref(),
+            Some("headers.only@example.net")
+        );
+    }
+
+    #[test]
+    fn no_original_message_id() {
+        let report = make_report(
+            "message/rfc822",
+            "From: sender@example.net\r\n\
+             To: user@example.com\r\n\
+             Subject: hello\r\n\
+             \r\n\
+             hello there\r\n",
+        );
+        let result = Report::parse(report.as_bytes()).unwrap().unwrap();
+        k9::assert_equal!(result.original_message_id, None);
+    }
 }
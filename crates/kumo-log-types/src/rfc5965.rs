@@ -1,5 +1,5 @@
 //! ARF reports
-use crate::rfc3464::{content_type, RemoteMta};
+use crate::rfc3464::{content_type, original_message_id, RemoteMta};
 use anyhow::anyhow;
 use chrono::{DateTime, Utc};
 use mailparsing::{Header, HeaderParseResult, MimePart};
@@ -37,6 +37,10 @@ pub struct ARFReport {
 
     pub extensions: BTreeMap<String, Vec<String>>,
 
+    /// The Message-ID of the original message, if it was included
+    /// in the report
+    #[serde(default)]
+    pub original_message_id: Option<String>,
     pub original_message: Option<String>,
     pub supplemental_trace: Option<serde_json::Value>,
 }
@@ -59,6 +63,7 @@ impl ARFReport {
         }
 
         let mut original_message = None;
+        let mut original_message_id = None;
         let mut supplemental_trace = None;
 
         for part in mail.child_parts() {
@@ -96,6 +101,7 @@ impl ARFReport {
                     }
                 }
 
+                original_message_id = self::original_message_id(part);
                 original_message = Some(part.raw_body().replace("\r\n", "\n"));
             }
         }
@@ -106,6 +112,7 @@ impl ARFReport {
             if ct == Some("message/feedback-report") {
                 return Ok(Some(Self::parse_inner(
                     part,
+                    original_message_id,
                     original_message,
                     supplemental_trace,
                 )?));
@@ -117,6 +124,7 @@ impl ARFReport {
 
     fn parse_inner(
         part: &MimePart,
+        original_message_id: Option<String>,
         original_message: Option<String>,
         supplemental_trace: Option<serde_json::Value>,
     ) -> anyhow::Result<Self> {
@@ -156,6 +164,7 @@ impl ARFReport {
             reported_domain,
             reported_uri,
             extensions,
+            original_message_id,
             original_message,
             supplemental_trace,
         })
@@ -305,6 +314,9 @@ Some(
         reported_domain: [],
         reported_uri: [],
         extensions: {},
+        original_message_id: Some(
+            "8787KJKJ3K4J3K4J3K4J3.mail@example.net",
+        ),
         original_message: Some(
             "Received: from mailserver.example.net
     (mailserver.example.net [192.0.2.1])
@@ -377,6 +389,9 @@ Some(
                 "user@example.com",
             ],
         },
+        original_message_id: Some(
+            "8787KJKJ3K4J3K4J3K4J3.mail@example.net",
+        ),
         original_message: Some(
             "From: <somespammer@example.net>
 Received: from mailserver.example.net (mailserver.example.net
@@ -439,6 +454,7 @@ Some(
         ],
         reported_uri: [],
         extensions: {},
+        original_message_id: None,
         original_message: Some(
             "Date: Thu, 14 Dec 2023 16:16:14 +0000
 To: user@example.com
@@ -497,6 +513,7 @@ Some(
                 "https://fbl.returnpath.net/manage/subscriptions/xxxx",
             ],
         },
+        original_message_id: None,
         original_message: Some(
             "Date: Thu, 14 Dec 2023 16:16:14 +0000
 To: user@example.com
@@ -102,6 +102,25 @@ impl AtomicCounter {
     pub fn sub(&self, n: usize) {
         self.0.fetch_sub(n, Ordering::Relaxed);
     }
+
+    /// Increment by n, returning the updated value
+    #[inline]
+    pub fn add_and_get(&self, n: usize) -> usize {
+        self.0.fetch_add(n, Ordering::Relaxed).wrapping_add(n)
+    }
+
+    /// Decrement by n, saturating at zero rather than wrapping,
+    /// returning the updated value
+    #[inline]
+    pub fn saturating_sub_and_get(&self, n: usize) -> usize {
+        match self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                Some(v.saturating_sub(n))
+            }) {
+            Ok(prior) | Err(prior) => prior.saturating_sub(n),
+        }
+    }
 }
 
 impl Default for AtomicCounter {
@@ -278,4 +278,72 @@ where
 
         result
     }
+
+    /// Like get_or_create, but if there is no counter for key and the
+    /// registry already holds max_cardinality counters, resolves the
+    /// counter for overflow_key instead.  This bounds the number of
+    /// distinct label sets for counters whose labels are derived from
+    /// unbounded inputs, such as domain names.
+    pub fn get_or_create_capped<'a, Q: ?Sized>(
+        &self,
+        key: &'a Q,
+        overflow_key: &'a Q,
+        max_cardinality: usize,
+    ) -> AtomicCounter
+    where
+        K: Borrow<Q> + From<&'a Q>,
+        Q: Hash + Eq,
+    {
+        {
+            let map = self.inner.map.read();
+            if let Some(strong) = map.get(key).and_then(|weak| weak.resolve()) {
+                return strong;
+            }
+            if map.len() >= max_cardinality {
+                drop(map);
+                return self.get_or_create(overflow_key);
+            }
+        }
+        self.get_or_create(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    label_key! {
+        pub struct DomainKey {
+            pub domain: String,
+        }
+    }
+
+    #[test]
+    fn capped_cardinality() {
+        let registry: CounterRegistry<DomainKey> =
+            CounterRegistry::register("test_capped_cardinality", "test");
+        let overflow = BorrowedDomainKey { domain: "other" };
+
+        for domain in ["a", "b", "c", "a"] {
+            let key = BorrowedDomainKey { domain };
+            registry
+                .get_or_create_capped(
+                    &key as &dyn DomainKeyTrait,
+                    &overflow as &dyn DomainKeyTrait,
+                    2,
+                )
+                .inc();
+        }
+
+        let get = |domain| {
+            let key = BorrowedDomainKey { domain };
+            registry
+                .get(&key as &dyn DomainKeyTrait)
+                .map(|counter| counter.get())
+        };
+        assert_eq!(get("a"), Some(2));
+        assert_eq!(get("b"), Some(1));
+        assert_eq!(get("c"), None);
+        assert_eq!(get("other"), Some(1));
+    }
 }
@@ -1,4 +1,4 @@
-use crate::http_server::AppState;
+use crate::http_server::{AppState, ProxiedClientAddress};
 use axum::async_trait;
 use axum::extract::{FromRequestParts, Request, State};
 use axum::http::StatusCode;
@@ -119,6 +119,20 @@ fn is_auth_exempt(uri: &axum::http::Uri) -> bool {
     }
 }
 
+/// Returns the address of the client that made the request,
+/// taking into account the PROXY protocol header sent by a
+/// trusted proxy, if any.
+pub fn client_address(request: &Request) -> Option<SocketAddr> {
+    let extensions = request.extensions();
+    if let Some(Some(ProxiedClientAddress(addr))) = extensions.get::<Option<ProxiedClientAddress>>()
+    {
+        return Some(*addr);
+    }
+    extensions
+        .get::<axum::extract::ConnectInfo<SocketAddr>>()
+        .map(|ci| ci.0)
+}
+
 pub async fn auth_middleware(
     State(state): State<AppState>,
     mut request: Request,
@@ -131,11 +145,7 @@ pub async fn auth_middleware(
     // Get authorization header
     match request.headers().get(axum::http::header::AUTHORIZATION) {
         None => {
-            if let Some(remote_addr) = request
-                .extensions()
-                .get::<axum::extract::ConnectInfo<SocketAddr>>()
-                .map(|ci| ci.0)
-            {
+            if let Some(remote_addr) = client_address(&request) {
                 let ip = remote_addr.ip();
                 if state.is_trusted_host(ip) {
                     request.extensions_mut().insert(AuthKind::TrustedIp(ip));
@@ -1,22 +1,32 @@
 use crate::diagnostic_logging::set_diagnostic_log_filter;
+use crate::tls_helpers::{
+    default_tls_reload_interval, make_server_config, TlsCertificateParams, TlsServerParams,
+};
 use anyhow::Context;
 use axum::extract::{DefaultBodyLimit, Json, Query};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::Router;
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::accept::{Accept, DefaultAcceptor};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use axum_streams::{HttpHeaderValue, StreamBodyAsOptions};
 use cidr_map::CidrSet;
 use data_loader::KeySource;
 use kumo_server_runtime::spawn;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
 use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tower_http::add_extension::AddExtension;
 use tower_http::compression::CompressionLayer;
 use tower_http::trace::TraceLayer;
 use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
-use utoipa::OpenApi;
+use utoipa::{OpenApi, ToSchema};
 use utoipa_rapidoc::RapiDoc;
 // Avoid referencing api types as crate::name in the utoipa macros,
 // otherwise it generates namespaced names in the openapi.json, which
@@ -59,7 +69,8 @@ impl utoipa::Modify for OptionalAuth {
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+/// The parameters accepted by `kumo.start_http_listener`
+#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct HttpListenerParams {
     #[serde(default = "HttpListenerParams::default_hostname")]
@@ -75,12 +86,101 @@ pub struct HttpListenerParams {
     pub request_body_limit: Option<usize>,
 
     #[serde(default)]
+    #[schema(value_type = Option<Object>)]
     pub tls_certificate: Option<KeySource>,
     #[serde(default)]
+    #[schema(value_type = Option<Object>)]
     pub tls_private_key: Option<KeySource>,
+    /// Certificates to present to clients that request a
+    /// particular host name via SNI
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub tls_sni_certificates: HashMap<String, TlsCertificateParams>,
+    /// How often to check the certificate sources for changes
+    #[serde(default = "default_tls_reload_interval", with = "duration_serde")]
+    #[schema(value_type = String)]
+    pub tls_reload_interval: Duration,
 
     #[serde(default = "CidrSet::default_trusted_hosts")]
+    #[schema(value_type = Vec<String>)]
     pub trusted_hosts: CidrSet,
+
+    /// Connections from these hosts must begin with a PROXY
+    /// protocol header describing the original client address
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    pub trusted_proxies: CidrSet,
+}
+
+/// The original client address conveyed by a PROXY protocol header.
+/// Requests carry an `Option<ProxiedClientAddress>` extension, which
+/// is `Some` only for connections that were made via a trusted proxy.
+#[derive(Clone, Copy, Debug)]
+pub struct ProxiedClientAddress(pub SocketAddr);
+
+/// An acceptor that consumes the PROXY protocol header from
+/// connections that originate from a trusted proxy, and makes
+/// the original client address available to handlers as a
+/// `ProxiedClientAddress` request extension.
+#[derive(Clone)]
+struct ProxyProtocolAcceptor<A> {
+    inner: A,
+    trusted_proxies: Arc<CidrSet>,
+}
+
+impl<A> ProxyProtocolAcceptor<A> {
+    /// How long to wait for the proxy to send its header
+    const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn new(inner: A, trusted_proxies: CidrSet) -> Self {
+        Self {
+            inner,
+            trusted_proxies: Arc::new(trusted_proxies),
+        }
+    }
+}
+
+impl<A, S> Accept<TcpStream, S> for ProxyProtocolAcceptor<A>
+where
+    A: Accept<TcpStream, AddExtension<S, Option<ProxiedClientAddress>>> + Clone + Send + 'static,
+    A::Future: Send,
+    S: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future =
+        Pin<Box<dyn Future<Output = std::io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+        let acceptor = self.clone();
+        Box::pin(async move {
+            let peer_address = stream.peer_addr()?;
+            let client_address = if acceptor.trusted_proxies.contains(peer_address.ip()) {
+                tokio::time::timeout(
+                    Self::HEADER_TIMEOUT,
+                    crate::proxy_protocol::read_header(&mut stream),
+                )
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("timed out reading PROXY header from {peer_address}"),
+                    )
+                })?
+                .map_err(|err| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("reading PROXY header from {peer_address}: {err:#}"),
+                    )
+                })?
+            } else {
+                None
+            };
+
+            let service = AddExtension::new(service, client_address.map(ProxiedClientAddress));
+            acceptor.inner.accept(stream, service).await
+        })
+    }
 }
 
 pub struct RouterAndDocs {
@@ -172,10 +272,14 @@ impl HttpListenerParams {
             .with_context(|| format!("listen on {}", self.listen))?;
         let addr = socket.local_addr()?;
 
+        let proxy_acceptor =
+            ProxyProtocolAcceptor::new(DefaultAcceptor, self.trusted_proxies.clone());
+
         if self.use_tls {
             let config = self.tls_config().await?;
             tracing::info!("https listener on {addr:?}");
-            let server = axum_server::from_tcp_rustls(socket, config);
+            let server = axum_server::from_tcp(socket)
+                .acceptor(RustlsAcceptor::new(config).acceptor(proxy_acceptor));
             spawn(format!("https {addr:?}"), async move {
                 server
                     .serve(app.into_make_service_with_connect_info::<SocketAddr>())
@@ -183,7 +287,7 @@ impl HttpListenerParams {
             })?;
         } else {
             tracing::info!("http listener on {addr:?}");
-            let server = axum_server::from_tcp(socket);
+            let server = axum_server::from_tcp(socket).acceptor(proxy_acceptor);
             spawn(format!("http {addr:?}"), async move {
                 server
                     .serve(app.into_make_service_with_connect_info::<SocketAddr>())
@@ -194,11 +298,13 @@ impl HttpListenerParams {
     }
 
     async fn tls_config(&self) -> anyhow::Result<RustlsConfig> {
-        let config = crate::tls_helpers::make_server_config(
-            &self.hostname,
-            &self.tls_private_key,
-            &self.tls_certificate,
-        )
+        let config = make_server_config(TlsServerParams {
+            hostname: &self.hostname,
+            tls_private_key: &self.tls_private_key,
+            tls_certificate: &self.tls_certificate,
+            tls_sni_certificates: &self.tls_sni_certificates,
+            tls_reload_interval: self.tls_reload_interval,
+        })
         .await?;
         Ok(RustlsConfig::from_config(config))
     }
@@ -231,18 +337,24 @@ where
 
 /// Allows the system operator to trigger a configuration epoch bump,
 /// which causes various configs that are using the Epoch strategy to
-/// be re-evaluated by triggering the appropriate callbacks.
+/// be re-evaluated by triggering the appropriate callbacks, and cached
+/// lua contexts to be discarded. The policy is loaded before the
+/// epoch is bumped, and the bump is refused if that fails.
 #[utoipa::path(
     post,
     tag="config",
     path="/api/admin/bump-config-epoch",
     responses(
-        (status=200, description = "bump successful")
+        (status=200, description = "bump successful"),
+        (status=400, description = "the policy failed to load, so the epoch was not bumped"),
     ),
 )]
-async fn bump_config_epoch(_: TrustedIpRequired) -> Result<(), AppError> {
-    config::epoch::bump_current_epoch();
-    Ok(())
+async fn bump_config_epoch(_: TrustedIpRequired) -> Response {
+    match config::epoch::validate_and_bump_current_epoch().await {
+        Ok(epoch) => (StatusCode::OK, format!("bumped to {epoch:?}")),
+        Err(err) => (StatusCode::BAD_REQUEST, format!("{err:#}")),
+    }
+    .into_response()
 }
 
 #[derive(Deserialize)]
@@ -12,7 +12,9 @@ pub mod diagnostic_logging;
 pub mod disk_space;
 pub mod http_server;
 pub mod nodeid;
+pub mod otel;
 pub mod panic;
+pub mod proxy_protocol;
 pub mod start;
 pub mod tls_helpers;
 
@@ -25,14 +27,19 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         cidr_map::register,
         domain_map::register,
         mod_amqp::register,
+        mod_counter::register,
         mod_filesystem::register,
         mod_http::register,
         mod_regex::register,
         mod_serde::register,
+        mod_sql::register,
         mod_sqlite::register,
         mod_string::register,
+        mod_template::register,
         mod_dns_resolver::register,
         mod_kafka::register,
+        mod_kv::register,
+        mod_ldap::register,
         mod_memoize::register,
         mod_uuid::register,
         kumo_api_types::shaping::register,
@@ -169,6 +176,14 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "configure_opentelemetry",
+        lua.create_function(move |lua, params: Value| {
+            let config: otel::OtlpConfig = from_lua_value(lua, params)?;
+            otel::configure(config).map_err(any_err)
+        })?,
+    )?;
+
     kumo_mod.set(
         "set_max_spare_lua_contexts",
         lua.create_function(move |_, limit: usize| {
@@ -177,6 +192,14 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "set_min_spare_lua_contexts",
+        lua.create_function(move |_, limit: usize| {
+            config::set_min_spare(limit);
+            Ok(())
+        })?,
+    )?;
+
     kumo_mod.set(
         "set_max_lua_context_use_count",
         lua.create_function(move |_, limit: usize| {
@@ -193,6 +216,30 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "set_lua_event_timeout",
+        lua.create_function(move |_, seconds: usize| {
+            config::set_event_timeout(seconds);
+            Ok(())
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "set_lua_memory_limit",
+        lua.create_function(move |_, bytes: usize| {
+            config::set_memory_limit(bytes);
+            Ok(())
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "set_lua_instruction_limit",
+        lua.create_function(move |_, limit: usize| {
+            config::set_instruction_limit(limit);
+            Ok(())
+        })?,
+    )?;
+
     kumo_mod.set(
         "set_lua_gc_on_put",
         lua.create_function(move |_, enable: u8| {
@@ -0,0 +1,125 @@
+//! Exports spans to an OpenTelemetry collector using OTLP.
+//!
+//! Nothing is exported until policy calls `kumo.configure_opentelemetry`;
+//! until then the global tracer provider is the no-op provider, and
+//! `is_enabled` allows callers to skip building spans entirely.
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use opentelemetry_sdk::trace::{Config, Sampler};
+use opentelemetry_sdk::Resource;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    /// OTLP over gRPC, usually on port 4317
+    #[default]
+    Grpc,
+    /// OTLP using protobuf over HTTP, usually on port 4318
+    HttpBinary,
+}
+
+/// The parameters accepted by `kumo.configure_opentelemetry`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// The URL of the collector
+    #[serde(default = "OtlpConfig::default_endpoint")]
+    pub endpoint: String,
+
+    #[serde(default)]
+    pub protocol: OtlpProtocol,
+
+    /// Reported as the `service.name` resource attribute
+    #[serde(default = "OtlpConfig::default_service_name")]
+    pub service_name: String,
+
+    /// The fraction of traces to export, between 0.0 and 1.0
+    #[serde(default = "OtlpConfig::default_sampling_ratio")]
+    pub sampling_ratio: f64,
+
+    /// How long to wait for the collector to accept a batch of spans
+    #[serde(default = "OtlpConfig::default_timeout", with = "duration_serde")]
+    pub timeout: Duration,
+}
+
+impl OtlpConfig {
+    fn default_endpoint() -> String {
+        "http://localhost:4317".to_string()
+    }
+
+    fn default_service_name() -> String {
+        "kumomta".to_string()
+    }
+
+    fn default_sampling_ratio() -> f64 {
+        1.0
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+/// Returns true if an exporter has been configured
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Installs an OTLP exporter as the global tracer provider,
+/// replacing any previously configured exporter.
+/// Must be called from within a tokio runtime.
+pub fn configure(config: OtlpConfig) -> anyhow::Result<()> {
+    if !(0.0..=1.0).contains(&config.sampling_ratio) {
+        anyhow::bail!(
+            "sampling_ratio {} must be between 0.0 and 1.0",
+            config.sampling_ratio
+        );
+    }
+
+    let exporter: SpanExporterBuilder = match config.protocol {
+        OtlpProtocol::Grpc => opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(&config.endpoint)
+            .with_timeout(config.timeout)
+            .into(),
+        OtlpProtocol::HttpBinary => opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(&config.endpoint)
+            .with_timeout(config.timeout)
+            .into(),
+    };
+
+    // Sampling is based on the trace id rather than on the parent, so
+    // that callers that derive the trace id from something stable get
+    // a consistent decision for all of the spans that share it
+    let trace_config = Config::default()
+        .with_sampler(Sampler::TraceIdRatioBased(config.sampling_ratio))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.service_name,
+        )]));
+
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace_config)
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+    opentelemetry::global::set_tracer_provider(provider);
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Flushes any buffered spans and stops the exporter
+pub async fn shutdown() {
+    if ENABLED.swap(false, Ordering::Relaxed) {
+        // Shutting down the batch processor blocks until it has flushed
+        tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider)
+            .await
+            .ok();
+    }
+}
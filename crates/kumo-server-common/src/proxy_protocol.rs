@@ -0,0 +1,124 @@
+//! Support for accepting the haproxy PROXY protocol on listeners.
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+//!
+//! When kumomta sits behind a layer 4 load balancer, the peer address of
+//! an incoming connection is that of the load balancer rather than the
+//! actual client. A load balancer that supports the PROXY protocol sends
+//! a header at the start of the connection that describes the original
+//! client address.
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_FIXED_LEN: usize = 16;
+
+/// Read a v1 or v2 PROXY protocol header from the start of `stream`.
+/// Only the bytes that comprise the header are consumed, so that the
+/// stream can then be handed off to the protocol implementation.
+///
+/// Returns the original client address conveyed by the header, or
+/// `None` if the header indicates that the connection originated from
+/// the proxy itself (`LOCAL` or `UNKNOWN`), in which case the socket
+/// peer address should be used.
+pub async fn read_header<S>(stream: &mut S) -> anyhow::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = vec![0u8; V1_PREFIX.len()];
+    stream.read_exact(&mut header).await?;
+
+    if header == V1_PREFIX {
+        // The v1 header is terminated by CRLF. We read a byte at
+        // a time so that we don't consume any of the data that follows.
+        while !header.ends_with(b"\r\n") {
+            anyhow::ensure!(
+                header.len() < V1_MAX_LEN,
+                "PROXY v1 header exceeds {V1_MAX_LEN} bytes"
+            );
+            header.push(stream.read_u8().await?);
+        }
+    } else if V2_SIGNATURE.starts_with(&header) {
+        header.resize(V2_FIXED_LEN, 0);
+        stream.read_exact(&mut header[V1_PREFIX.len()..]).await?;
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        header.resize(V2_FIXED_LEN + len, 0);
+        stream.read_exact(&mut header[V2_FIXED_LEN..]).await?;
+    } else {
+        anyhow::bail!("expected a PROXY protocol header");
+    }
+
+    parse_header(&header)
+}
+
+fn parse_header(header: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    if header.starts_with(V1_PREFIX) {
+        use ppp::v1::{Addresses, Header};
+        let header = Header::try_from(std::str::from_utf8(header)?)
+            .map_err(|err| anyhow::anyhow!("invalid PROXY v1 header: {err}"))?;
+        Ok(match header.addresses {
+            Addresses::Tcp4(addr) => Some((addr.source_address, addr.source_port).into()),
+            Addresses::Tcp6(addr) => Some((addr.source_address, addr.source_port).into()),
+            Addresses::Unknown => None,
+        })
+    } else {
+        use ppp::v2::{Addresses, Command, Header};
+        let header = Header::try_from(header)
+            .map_err(|err| anyhow::anyhow!("invalid PROXY v2 header: {err}"))?;
+        if matches!(header.command, Command::Local) {
+            return Ok(None);
+        }
+        Ok(match header.addresses {
+            Addresses::IPv4(addr) => Some((addr.source_address, addr.source_port).into()),
+            Addresses::IPv6(addr) => Some((addr.source_address, addr.source_port).into()),
+            Addresses::Unix(_) | Addresses::Unspecified => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn v1() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.168.1.1 10.0.0.1 56324 25\r\nEHLO";
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.168.1.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"EHLO");
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+
+        let mut stream: &[u8] = b"EHLO there\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        use ppp::v2::{Addresses, Builder, Command, IPv6, Protocol, Version};
+        let mut data = Builder::with_addresses(
+            Version::Two | Command::Proxy,
+            Protocol::Stream,
+            Addresses::IPv6(IPv6::new(
+                "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap(),
+                "2001:db8::2".parse::<std::net::Ipv6Addr>().unwrap(),
+                4242,
+                443,
+            )),
+        )
+        .build()
+        .unwrap();
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+
+        let mut stream: &[u8] = &data;
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("[2001:db8::1]:4242".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+    }
+}
@@ -70,6 +70,7 @@ impl<'a> StartConfig<'a> {
         // after waiting for those to idle out, shut down logging
         let shutdown_future = (broadcast_shutdown)();
         shutdown_future.await;
+        crate::otel::shutdown().await;
 
         tracing::info!("Shutdown completed OK!");
 
@@ -1,40 +1,276 @@
+//! Builds the rustls configuration used by our TLS listeners.
+//!
+//! Rather than baking a single certificate into the configuration,
+//! the configuration resolves the certificate for each handshake via
+//! a `ReloadingCertResolver`, which allows selecting a certificate
+//! based on the SNI sent by the client, and allows the certificates
+//! to be replaced while the listener is running. Handshakes that are
+//! already complete are unaffected by a reload.
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use data_loader::KeySource;
+use rustls::crypto::aws_lc_rs::sign::any_supported_type;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
 use rustls::ServerConfig;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
-pub async fn make_server_config(
-    hostname: &str,
-    tls_private_key: &Option<KeySource>,
-    tls_certificate: &Option<KeySource>,
-) -> anyhow::Result<Arc<ServerConfig>> {
-    let mut certificates = vec![];
-    let private_key = match tls_private_key {
-        Some(key) => {
-            let data = key.get().await?;
-            load_private_key(&data).with_context(|| format!("loading private key from {key:?}"))?
-        }
-        None => {
-            let key = rcgen::generate_simple_self_signed(vec![hostname.to_string()])?;
-            certificates.push(CertificateDer::from_slice(key.cert.der()).into_owned());
-            PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key.key_pair.serialize_der()))
-        }
-    };
+/// A certificate and its private key, as used for `tls_sni_certificates`
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsCertificateParams {
+    pub certificate: KeySource,
+    pub private_key: KeySource,
+}
 
-    if let Some(cert_file) = tls_certificate {
-        let data = cert_file.get().await?;
-        certificates = load_certs(&data)
-            .with_context(|| format!("loading certificates from {cert_file:?}"))?;
-    }
+/// The TLS related parameters of a listener
+pub struct TlsServerParams<'a> {
+    pub hostname: &'a str,
+    pub tls_private_key: &'a Option<KeySource>,
+    pub tls_certificate: &'a Option<KeySource>,
+    /// Certificates to present when the client requests a particular
+    /// host name via SNI. The key may be a wildcard such as
+    /// `*.example.com`, which matches a single leading label.
+    pub tls_sni_certificates: &'a HashMap<String, TlsCertificateParams>,
+    /// How often to check the key sources for changes. The key
+    /// sources are also checked whenever the config epoch changes.
+    pub tls_reload_interval: Duration,
+}
+
+pub fn default_tls_reload_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+pub async fn make_server_config(params: TlsServerParams<'_>) -> anyhow::Result<Arc<ServerConfig>> {
+    let resolver = Arc::new(ReloadingCertResolver::new(&params).await?);
+
+    tokio::spawn(reload_task(
+        Arc::downgrade(&resolver),
+        params.tls_reload_interval,
+    ));
 
     let config = ServerConfig::builder()
         .with_no_client_auth()
-        .with_single_cert(certificates, private_key)?;
+        .with_cert_resolver(resolver);
 
     Ok(Arc::new(config))
 }
 
+/// Periodically, and whenever the config epoch changes, reload the
+/// certificates until the resolver is no longer in use
+async fn reload_task(resolver: Weak<ReloadingCertResolver>, interval: Duration) {
+    let mut epoch_subscriber = config::epoch::subscribe();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = epoch_subscriber.changed() => {}
+        };
+
+        let Some(resolver) = resolver.upgrade() else {
+            return;
+        };
+        if let Err(err) = resolver.reload().await {
+            tracing::error!(
+                "failed to reload TLS certificates for {}, \
+                 continuing to use the previous certificates: {err:#}",
+                resolver.config.hostname
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CertSource {
+    private_key: Option<KeySource>,
+    certificate: Option<KeySource>,
+}
+
+/// The raw data loaded from a CertSource, retained so
+/// that we can tell whether anything has changed
+#[derive(Debug, PartialEq)]
+struct CertData {
+    private_key: Option<Vec<u8>>,
+    certificate: Option<Vec<u8>>,
+}
+
+impl CertSource {
+    async fn load(&self) -> anyhow::Result<CertData> {
+        Ok(CertData {
+            private_key: match &self.private_key {
+                Some(key) => Some(key.get().await?),
+                None => None,
+            },
+            certificate: match &self.certificate {
+                Some(cert) => Some(cert.get().await?),
+                None => None,
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+struct LoadedCerts {
+    data: Vec<CertData>,
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// Where the certificates for a listener come from
+#[derive(Debug)]
+struct CertConfig {
+    hostname: String,
+    /// The default certificate is first, followed by those for SNI
+    sources: Vec<CertSource>,
+    names: Vec<String>,
+    /// Generated once, so that a listener without a configured
+    /// key presents a stable certificate across reloads
+    self_signed: Option<(PrivateKeyDer<'static>, CertificateDer<'static>)>,
+}
+
+#[derive(Debug)]
+pub struct ReloadingCertResolver {
+    config: CertConfig,
+    certs: ArcSwap<LoadedCerts>,
+}
+
+impl CertConfig {
+    fn new(params: &TlsServerParams<'_>) -> anyhow::Result<Self> {
+        let mut sources = vec![CertSource {
+            private_key: params.tls_private_key.clone(),
+            certificate: params.tls_certificate.clone(),
+        }];
+        let mut names = vec![];
+        for (name, cert) in params.tls_sni_certificates {
+            names.push(name.to_ascii_lowercase());
+            sources.push(CertSource {
+                private_key: Some(cert.private_key.clone()),
+                certificate: Some(cert.certificate.clone()),
+            });
+        }
+
+        let self_signed = if params.tls_private_key.is_none() {
+            let key = rcgen::generate_simple_self_signed(vec![params.hostname.to_string()])?;
+            Some((
+                PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key.key_pair.serialize_der())),
+                CertificateDer::from_slice(key.cert.der()).into_owned(),
+            ))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            hostname: params.hostname.to_string(),
+            sources,
+            names,
+            self_signed,
+        })
+    }
+
+    async fn load_data(&self) -> anyhow::Result<Vec<CertData>> {
+        let mut data = vec![];
+        for source in &self.sources {
+            data.push(source.load().await?);
+        }
+        Ok(data)
+    }
+
+    fn build(&self, data: Vec<CertData>) -> anyhow::Result<LoadedCerts> {
+        let mut keys = vec![];
+        for (source, data) in self.sources.iter().zip(data.iter()) {
+            keys.push(self.certified_key(source, data)?);
+        }
+        let mut keys = keys.into_iter();
+        let default = keys.next().expect("default certificate is always present");
+        let by_name = self.names.iter().cloned().zip(keys).collect();
+        Ok(LoadedCerts {
+            data,
+            default,
+            by_name,
+        })
+    }
+
+    fn certified_key(
+        &self,
+        source: &CertSource,
+        data: &CertData,
+    ) -> anyhow::Result<Arc<CertifiedKey>> {
+        let (private_key, mut certificates) = match (&data.private_key, &self.self_signed) {
+            (Some(key), _) => (
+                load_private_key(key).with_context(|| {
+                    format!("loading private key from {:?}", source.private_key)
+                })?,
+                vec![],
+            ),
+            (None, Some((key, cert))) => (key.clone_key(), vec![cert.clone()]),
+            (None, None) => anyhow::bail!("no private key is configured"),
+        };
+
+        if let Some(cert) = &data.certificate {
+            certificates = load_certs(cert)
+                .with_context(|| format!("loading certificates from {:?}", source.certificate))?;
+        }
+        anyhow::ensure!(
+            !certificates.is_empty(),
+            "no certificates found in {:?}",
+            source.certificate
+        );
+
+        let signing_key = any_supported_type(&private_key)
+            .with_context(|| format!("loading private key from {:?}", source.private_key))?;
+        Ok(Arc::new(CertifiedKey::new(certificates, signing_key)))
+    }
+}
+
+impl ReloadingCertResolver {
+    async fn new(params: &TlsServerParams<'_>) -> anyhow::Result<Self> {
+        let config = CertConfig::new(params)?;
+        let certs = config.build(config.load_data().await?)?;
+        Ok(Self {
+            config,
+            certs: ArcSwap::from_pointee(certs),
+        })
+    }
+
+    /// Load the certificates from their sources, and use them for
+    /// subsequent handshakes if they have changed
+    async fn reload(&self) -> anyhow::Result<()> {
+        let data = self.config.load_data().await?;
+        if data == self.certs.load().data {
+            return Ok(());
+        }
+        let certs = self.config.build(data)?;
+        self.certs.store(Arc::new(certs));
+        tracing::info!("reloaded TLS certificates for {}", self.config.hostname);
+        Ok(())
+    }
+
+    fn lookup(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let certs = self.certs.load();
+        if let Some(name) = server_name {
+            let name = name.to_ascii_lowercase();
+            if let Some(key) = certs.by_name.get(&name) {
+                return key.clone();
+            }
+            if let Some((_, parent)) = name.split_once('.') {
+                if let Some(key) = certs.by_name.get(&format!("*.{parent}")) {
+                    return key.clone();
+                }
+            }
+        }
+        certs.default.clone()
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.lookup(client_hello.server_name()))
+    }
+}
+
 fn load_certs(data: &[u8]) -> anyhow::Result<Vec<CertificateDer<'static>>> {
     let mut certs = vec![];
     let mut reader = std::io::BufReader::new(data);
@@ -49,7 +285,7 @@ fn load_private_key(data: &[u8]) -> anyhow::Result<PrivateKeyDer<'static>> {
     let mut reader = std::io::BufReader::new(data);
 
     loop {
-        match rustls_pemfile::read_one(&mut reader).expect("cannot parse private key .pem file") {
+        match rustls_pemfile::read_one(&mut reader).context("cannot parse private key data")? {
             Some(rustls_pemfile::Item::Pkcs8Key(key)) => return Ok(key.into()),
             Some(rustls_pemfile::Item::Pkcs1Key(key)) => return Ok(key.into()),
             Some(rustls_pemfile::Item::Sec1Key(key)) => return Ok(key.into()),
@@ -60,3 +296,93 @@ fn load_private_key(data: &[u8]) -> anyhow::Result<PrivateKeyDer<'static>> {
 
     anyhow::bail!("no keys found in key data (encrypted keys not supported)",);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestCert {
+        cert: KeySource,
+        key: KeySource,
+        der: CertificateDer<'static>,
+    }
+
+    fn write_cert(dir: &std::path::Path, name: &str) -> TestCert {
+        let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert = dir.join(format!("{name}.crt"));
+        let key = dir.join(format!("{name}.key"));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+        TestCert {
+            cert: KeySource::File(cert.display().to_string()),
+            key: KeySource::File(key.display().to_string()),
+            der: CertificateDer::from_slice(generated.cert.der()).into_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn sni_and_reload() {
+        let dir = std::env::temp_dir().join(format!("kumo-tls-helpers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let default = write_cert(&dir, "default.example.com");
+        let mail = write_cert(&dir, "mail.example.com");
+        let wild = write_cert(&dir, "wild.example.net");
+
+        let mut sni = HashMap::new();
+        sni.insert(
+            "Mail.Example.com".to_string(),
+            TlsCertificateParams {
+                certificate: mail.cert.clone(),
+                private_key: mail.key.clone(),
+            },
+        );
+        sni.insert(
+            "*.example.net".to_string(),
+            TlsCertificateParams {
+                certificate: wild.cert.clone(),
+                private_key: wild.key.clone(),
+            },
+        );
+
+        let resolver = ReloadingCertResolver::new(&TlsServerParams {
+            hostname: "default.example.com",
+            tls_private_key: &Some(default.key.clone()),
+            tls_certificate: &Some(default.cert.clone()),
+            tls_sni_certificates: &sni,
+            tls_reload_interval: default_tls_reload_interval(),
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(resolver.lookup(None).cert[0], default.der);
+        assert_eq!(resolver.lookup(Some("mail.example.com")).cert[0], mail.der);
+        assert_eq!(resolver.lookup(Some("mx.example.net")).cert[0], wild.der);
+        assert_eq!(
+            resolver.lookup(Some("a.mx.example.net")).cert[0],
+            default.der
+        );
+        assert_eq!(
+            resolver.lookup(Some("other.example.com")).cert[0],
+            default.der
+        );
+
+        // Rotating the files on disk is picked up by a reload
+        let rotated = write_cert(&dir, "mail.example.com");
+        resolver.reload().await.unwrap();
+        assert_eq!(
+            resolver.lookup(Some("mail.example.com")).cert[0],
+            rotated.der
+        );
+
+        // A broken certificate leaves the previous one in place
+        std::fs::write(dir.join("mail.example.com.key"), "garbage").unwrap();
+        assert!(resolver.reload().await.is_err());
+        assert_eq!(
+            resolver.lookup(Some("mail.example.com")).cert[0],
+            rotated.der
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
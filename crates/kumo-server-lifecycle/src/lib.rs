@@ -13,6 +13,7 @@ use uuid::Uuid;
 
 static ACTIVE: OnceCell<Mutex<Option<Activity>>> = OnceCell::new();
 static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static SIGHUP_RELOADS: AtomicBool = AtomicBool::new(false);
 static STOPPING: OnceCell<ShutdownState> = OnceCell::new();
 
 static ACTIVE_LABELS: Lazy<Mutex<HashMap<Uuid, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
@@ -88,6 +89,12 @@ pub fn is_shutting_down() -> bool {
     SHUTTING_DOWN.load(Ordering::Relaxed)
 }
 
+/// Indicate that SIGHUP is handled elsewhere as a request to reload
+/// the configuration, rather than as a request to shut down.
+pub fn set_sighup_reloads_config() {
+    SIGHUP_RELOADS.store(true, Ordering::SeqCst);
+}
+
 struct ShutdownState {
     tx: WatchSender<()>,
     rx: WatchReceiver<()>,
@@ -194,12 +201,19 @@ impl LifeCycle {
         let mut sig_hup =
             tokio::signal::unix::signal(SignalKind::hangup()).expect("listen for SIGUP");
 
-        tokio::select! {
-            _ = sig_term.recv() => {}
-            _ = sig_hup.recv() => {}
-            _ = tokio::signal::ctrl_c() => {}
-            _ = self.request_shutdown_rx.recv() => {}
-        };
+        loop {
+            tokio::select! {
+                _ = sig_term.recv() => {}
+                _ = sig_hup.recv() => {
+                    if SIGHUP_RELOADS.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => {}
+                _ = self.request_shutdown_rx.recv() => {}
+            };
+            break;
+        }
         tracing::debug!("wait_for_shutdown: shutdown requested!");
         tracing::info!(
             "Shutdown requested, please wait while in-flight messages are delivered \
@@ -0,0 +1,219 @@
+use crate::delivery_metrics::MetricsWrappedConnection;
+use crate::kafka_deliver::PartitionKey;
+use crate::ready_queue::{Dispatcher, QueueDispatcher};
+use async_trait::async_trait;
+use kumo_log_types::ResolvedAddress;
+use message::Message;
+use mod_amqp::{AMQPClient, ConfirmStatus};
+use rfc5321::Response;
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AmqpDeliveryProtocol {
+    /// The AMQP URI of the broker, such as `amqp://localhost:5672`
+    pub uri: String,
+
+    /// The exchange to which each message will be published.
+    /// The default is the nameless default exchange.
+    #[serde(default)]
+    pub exchange: String,
+
+    /// The routing key to use when partition_key is not set
+    #[serde(default)]
+    pub routing_key: String,
+
+    /// When set, the routing key is computed from the queue
+    /// of each message rather than using routing_key
+    #[serde(default)]
+    pub partition_key: Option<PartitionKey>,
+
+    /// The content type property of the published messages
+    #[serde(default = "AmqpDeliveryProtocol::default_content_type")]
+    pub content_type: String,
+
+    /// How long to wait for the broker to confirm each message
+    #[serde(
+        default = "AmqpDeliveryProtocol::default_timeout",
+        with = "duration_serde"
+    )]
+    pub timeout: Duration,
+}
+
+impl AmqpDeliveryProtocol {
+    fn default_content_type() -> String {
+        "application/json".to_string()
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    /// The uri with any credentials removed, so that it is
+    /// suitable for use in logs and metrics
+    pub fn broker_name(&self) -> &str {
+        let without_scheme = match self.uri.split_once("://") {
+            Some((_scheme, rest)) => rest,
+            None => &self.uri,
+        };
+        match without_scheme.rsplit_once('@') {
+            Some((_userinfo, rest)) => rest,
+            None => without_scheme,
+        }
+    }
+}
+
+struct Client(AMQPClient);
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Client").finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct AmqpQueueDispatcher {
+    proto_config: AmqpDeliveryProtocol,
+    connection: Option<MetricsWrappedConnection<Client>>,
+    peer_address: ResolvedAddress,
+}
+
+impl AmqpQueueDispatcher {
+    pub fn new(proto_config: AmqpDeliveryProtocol) -> Self {
+        let peer_address = ResolvedAddress {
+            name: format!(
+                "AMQP exchange '{}' via {}",
+                proto_config.exchange,
+                proto_config.broker_name()
+            ),
+            addr: Ipv4Addr::UNSPECIFIED.into(),
+        };
+
+        Self {
+            proto_config,
+            connection: None,
+            peer_address,
+        }
+    }
+
+    /// Publish msg and map the broker confirmation to an SMTP style
+    /// response. A nack, or failing to obtain a confirmation within
+    /// the timeout, is a transient failure.
+    async fn try_send(&self, msg: &Message) -> anyhow::Result<Response> {
+        let client = self
+            .connection
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no connection"))?;
+
+        let routing_key = match &self.proto_config.partition_key {
+            Some(key) => key.key_for(msg)?,
+            None => self.proto_config.routing_key.clone(),
+        };
+        let data = msg.get_data();
+
+        let publish = client.0.publish_confirmed(
+            &self.proto_config.exchange,
+            &routing_key,
+            &data,
+            &self.proto_config.content_type,
+        );
+
+        let (code, content) = match tokio::time::timeout(self.proto_config.timeout, publish).await {
+            Err(_) => (
+                421,
+                "KumoMTA internal: timed out waiting for AMQP confirmation".to_string(),
+            ),
+            Ok(Err(err)) => (
+                421,
+                format!("KumoMTA internal: failed to publish to AMQP: {err:#}"),
+            ),
+            Ok(Ok(result)) => match result.status {
+                ConfirmStatus::Ack | ConfirmStatus::NotRequested => {
+                    (250, format!("published with routing key '{routing_key}'"))
+                }
+                ConfirmStatus::Nack => (
+                    421,
+                    format!(
+                        "AMQP broker rejected the message: {} {}",
+                        result.reply_code.unwrap_or(0),
+                        result.reply_text.unwrap_or_default()
+                    ),
+                ),
+            },
+        };
+
+        Ok(Response {
+            code,
+            enhanced_code: None,
+            content,
+            command: None,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueDispatcher for AmqpQueueDispatcher {
+    async fn close_connection(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
+        match self.connection.take() {
+            Some(client) => {
+                if let Err(err) = client.0.close().await {
+                    tracing::debug!("error closing AMQP connection: {err:#}");
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn attempt_connection(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<()> {
+        if self.connection.is_none() {
+            let client = mod_amqp::build_client(self.proto_config.uri.clone()).await?;
+            client.confirm_select().await?;
+            self.connection
+                .replace(dispatcher.metrics.wrap_connection(Client(client)));
+        }
+        Ok(())
+    }
+
+    async fn have_more_connection_candidates(&mut self, _dispatcher: &mut Dispatcher) -> bool {
+        false
+    }
+
+    async fn deliver_message(
+        &mut self,
+        msg: Message,
+        dispatcher: &mut Dispatcher,
+    ) -> anyhow::Result<()> {
+        msg.load_meta_if_needed().await?;
+        msg.load_data_if_needed().await?;
+
+        let response = self.try_send(&msg).await?;
+        tracing::debug!("AMQP response for {}: {response:?}", dispatcher.name);
+
+        match dispatcher.msg.take() {
+            Some(msg) => {
+                dispatcher
+                    .record_delivery_responses(vec![(msg, response)], &self.peer_address, "Amqp")
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn broker_name() {
+        let mut proto: AmqpDeliveryProtocol =
+            serde_json::from_value(serde_json::json!({"uri": "amqp://localhost:5672"})).unwrap();
+        assert_eq!(proto.broker_name(), "localhost:5672");
+
+        proto.uri = "amqps://user:p@ss@broker.example.com/vhost".to_string();
+        assert_eq!(proto.broker_name(), "broker.example.com/vhost");
+    }
+}
@@ -0,0 +1,147 @@
+//! Maps between the external bounce domains that appear in the envelope
+//! sender of outbound mail and the internal return path domains that
+//! policy assigns to messages.
+//!
+//! Outbound messages have their envelope sender rewritten from the internal
+//! domain to the external domain at delivery time, and inbound mail that is
+//! addressed to an external bounce domain, such as an out-of-band DSN, has
+//! its recipient translated back to the internal domain on reception so that
+//! it is processed according to the listener domain configuration of the
+//! internal domain.
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use message::EnvelopeAddress;
+use mlua::{Lua, LuaSerdeExt, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+static ALIASES: Lazy<Mutex<Aliases>> = Lazy::new(|| Mutex::new(Aliases::default()));
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BounceDomainAlias {
+    /// The return path domain assigned to messages by policy
+    pub internal: String,
+    /// The bounce domain that is presented to the outside world
+    pub external: String,
+}
+
+#[derive(Default, Debug)]
+struct Aliases {
+    entries: Vec<BounceDomainAlias>,
+    to_internal: HashMap<String, String>,
+    to_external: HashMap<String, String>,
+}
+
+impl Aliases {
+    fn new(entries: Vec<BounceDomainAlias>) -> anyhow::Result<Self> {
+        let mut to_internal = HashMap::new();
+        let mut to_external = HashMap::new();
+
+        for entry in &entries {
+            let internal = entry.internal.to_ascii_lowercase();
+            let external = entry.external.to_ascii_lowercase();
+            anyhow::ensure!(
+                to_internal
+                    .insert(external.clone(), internal.clone())
+                    .is_none(),
+                "external bounce domain {external} is aliased more than once"
+            );
+            anyhow::ensure!(
+                to_external.insert(internal.clone(), external).is_none(),
+                "internal return path domain {internal} is aliased more than once"
+            );
+        }
+
+        Ok(Self {
+            entries,
+            to_internal,
+            to_external,
+        })
+    }
+}
+
+fn rewrite(map: &HashMap<String, String>, addr: &EnvelopeAddress) -> Option<EnvelopeAddress> {
+    let domain = map.get(&addr.domain().to_ascii_lowercase())?;
+    EnvelopeAddress::parse(&format!("{}@{domain}", addr.user())).ok()
+}
+
+/// If addr belongs to an internal return path domain that has an
+/// external alias, returns the equivalent external address
+pub fn to_external(addr: &EnvelopeAddress) -> Option<EnvelopeAddress> {
+    rewrite(&ALIASES.lock().to_external, addr)
+}
+
+/// If addr belongs to an external bounce domain, returns the
+/// equivalent address in the internal return path domain
+pub fn to_internal(addr: &EnvelopeAddress) -> Option<EnvelopeAddress> {
+    rewrite(&ALIASES.lock().to_internal, addr)
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "bounce_alias")?;
+
+    module.set(
+        "configure",
+        lua.create_function(|lua, params: Value| {
+            let entries: Vec<BounceDomainAlias> = from_lua_value(lua, params)?;
+            let aliases = Aliases::new(entries).map_err(any_err)?;
+            *ALIASES.lock() = aliases;
+            Ok(())
+        })?,
+    )?;
+
+    module.set(
+        "list",
+        lua.create_function(|lua, ()| {
+            let entries = ALIASES.lock().entries.clone();
+            lua.to_value(&entries)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn alias(internal: &str, external: &str) -> BounceDomainAlias {
+        BounceDomainAlias {
+            internal: internal.to_string(),
+            external: external.to_string(),
+        }
+    }
+
+    #[test]
+    fn rewrite_both_ways() {
+        let aliases =
+            Aliases::new(vec![alias("bounces.internal", "Bounce.Client.Example")]).unwrap();
+
+        let internal = EnvelopeAddress::parse("b-123@bounces.internal").unwrap();
+        let external = rewrite(&aliases.to_external, &internal).unwrap();
+        assert_eq!(external.to_string(), "b-123@bounce.client.example");
+
+        let external = EnvelopeAddress::parse("b-123@BOUNCE.client.example").unwrap();
+        assert_eq!(rewrite(&aliases.to_internal, &external).unwrap(), internal);
+
+        let other = EnvelopeAddress::parse("user@example.com").unwrap();
+        assert_eq!(rewrite(&aliases.to_external, &other), None);
+        assert_eq!(rewrite(&aliases.to_internal, &other), None);
+    }
+
+    #[test]
+    fn duplicates() {
+        assert!(Aliases::new(vec![
+            alias("a.internal", "x.example"),
+            alias("b.internal", "x.example")
+        ])
+        .is_err());
+        assert!(Aliases::new(vec![
+            alias("a.internal", "x.example"),
+            alias("a.internal", "y.example")
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,878 @@
+//! Campaigns that have been registered via the admin API.
+//!
+//! Messages whose `campaign` and `tenant` meta values match a registered
+//! campaign inherit its settings at reception, are rejected once the
+//! campaign has been cancelled, has ended or has used up its budget,
+//! reserve their share of its budget when they are checked, which is
+//! counted as received once they have been spooled, and are counted
+//! towards its aggregate progress as their dispositions pass through
+//! `log_disposition`.
+//!
+//! Pausing and cancelling a campaign are implemented in terms of the
+//! existing administrative suspensions and bounces, which match the
+//! scheduled queues of the campaign. For a campaign without a tenant,
+//! those exclude the tenants that have registered their own campaign
+//! with the same identifier.
+//!
+//! The registered campaigns are held in memory. Changes to their
+//! registration and state are written to an sqlite database as they
+//! are made, and their progress is written periodically, so that
+//! they survive a restart.
+use crate::http_server::admin_bounce_v1::start_bounce;
+use crate::http_server::admin_suspend_v1::AdminSuspendEntry;
+use crate::smtp_server::RejectError;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use kumo_api_types::campaign::{
+    CampaignV1Action, CampaignV1ActionRequest, CampaignV1Entry, CampaignV1ListRequest,
+    CampaignV1Progress, CampaignV1Request, CampaignV1Selector, CampaignV1State,
+};
+use kumo_api_types::BounceV1Request;
+use kumo_log_types::RecordType;
+use kumo_server_lifecycle::ShutdownSubcription;
+use kumo_server_runtime::get_main_runtime;
+use message::Message;
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use sqlite::Connection;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+static CAMPAIGNS: Lazy<Mutex<HashMap<CampaignKey, Campaign>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static FLUSHER: Lazy<JoinHandle<()>> = Lazy::new(|| tokio::task::spawn(flusher()));
+/// Serializes writes to the database, so that an older snapshot
+/// of the campaigns cannot overwrite a newer one
+static FLUSH_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+pub static DB_PATH: Lazy<Mutex<String>> =
+    Lazy::new(|| Mutex::new("/var/spool/kumomta/campaign.db".to_string()));
+
+/// How long a pause lasts when no duration was specified;
+/// effectively until the campaign is resumed
+const PAUSE_UNTIL_RESUMED: Duration = Duration::from_secs(10 * 365 * 86400);
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct CampaignKey {
+    campaign: String,
+    tenant: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Pause {
+    /// The id of the suspension that implements the pause
+    id: Uuid,
+    reason: String,
+    until: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+struct Campaign {
+    params: CampaignV1Request,
+    created: DateTime<Utc>,
+    paused: Option<Pause>,
+    cancelled: Option<String>,
+    progress: CampaignV1Progress,
+    /// The number of messages that have reserved their share of the
+    /// budget, but whose reception has not yet completed
+    reserved: usize,
+}
+
+impl Campaign {
+    fn is_paused(&self) -> bool {
+        match &self.paused {
+            Some(pause) => AdminSuspendEntry::get_all()
+                .iter()
+                .any(|entry| entry.id == pause.id),
+            None => false,
+        }
+    }
+
+    fn budget_exhausted(&self) -> bool {
+        self.params
+            .budget
+            .map(|budget| self.progress.received + self.reserved >= budget)
+            .unwrap_or(false)
+    }
+
+    fn state(&self, now: DateTime<Utc>) -> CampaignV1State {
+        if self.cancelled.is_some() {
+            CampaignV1State::Cancelled
+        } else if self.params.end.map(|end| end <= now).unwrap_or(false) || self.budget_exhausted()
+        {
+            CampaignV1State::Ended
+        } else if self.is_paused() {
+            CampaignV1State::Paused
+        } else if self.params.start.map(|start| start > now).unwrap_or(false) {
+            CampaignV1State::Scheduled
+        } else {
+            CampaignV1State::Active
+        }
+    }
+
+    fn entry(&self, now: DateTime<Utc>) -> CampaignV1Entry {
+        let state = self.state(now);
+        let reason = match state {
+            CampaignV1State::Cancelled => self.cancelled.clone(),
+            CampaignV1State::Paused => self.paused.as_ref().map(|pause| pause.reason.clone()),
+            _ => None,
+        };
+        CampaignV1Entry {
+            params: self.params.clone(),
+            state,
+            reason,
+            created: self.created,
+            progress: self.progress.clone(),
+        }
+    }
+
+    /// Returns the response with which to reject a new message
+    /// for this campaign, if it should be rejected
+    fn rejection(&self, now: DateTime<Utc>) -> Option<RejectError> {
+        let name = &self.params.campaign;
+        let message = if self.cancelled.is_some() {
+            format!("5.7.1 campaign {name} has been cancelled")
+        } else if self.params.end.map(|end| end <= now).unwrap_or(false) {
+            format!("5.7.1 campaign {name} has ended")
+        } else if self.budget_exhausted() {
+            format!("5.7.1 campaign {name} has exhausted its budget")
+        } else {
+            return None;
+        };
+        Some(RejectError { code: 550, message })
+    }
+}
+
+/// Locate the campaign to which a message with the given campaign and
+/// tenant belongs. A campaign that was registered without a tenant
+/// matches messages from any tenant that has not registered a campaign
+/// with the same identifier.
+fn find_key(
+    campaigns: &HashMap<CampaignKey, Campaign>,
+    campaign: &str,
+    tenant: Option<&str>,
+) -> Option<CampaignKey> {
+    let exact = CampaignKey {
+        campaign: campaign.to_string(),
+        tenant: tenant.map(|t| t.to_string()),
+    };
+    if campaigns.contains_key(&exact) {
+        return Some(exact);
+    }
+    let any_tenant = CampaignKey {
+        campaign: campaign.to_string(),
+        tenant: None,
+    };
+    campaigns.contains_key(&any_tenant).then_some(any_tenant)
+}
+
+/// The tenants whose messages do not belong to the campaign identified
+/// by key, because they have registered their own campaign with the
+/// same identifier
+fn excluded_tenants(campaigns: &HashMap<CampaignKey, Campaign>, key: &CampaignKey) -> Vec<String> {
+    if key.tenant.is_some() {
+        return vec![];
+    }
+    let mut tenants: Vec<String> = campaigns
+        .keys()
+        .filter(|k| k.campaign == key.campaign)
+        .filter_map(|k| k.tenant.clone())
+        .collect();
+    tenants.sort();
+    tenants
+}
+
+fn message_key(campaigns: &HashMap<CampaignKey, Campaign>, msg: &Message) -> Option<CampaignKey> {
+    let campaign = msg.get_meta_string("campaign").ok().flatten()?;
+    let tenant = msg.get_meta_string("tenant").ok().flatten();
+    find_key(campaigns, &campaign, tenant.as_deref())
+}
+
+pub async fn register(request: CampaignV1Request) -> anyhow::Result<()> {
+    if let (Some(start), Some(end)) = (request.start, request.end) {
+        anyhow::ensure!(
+            start < end,
+            "campaign {} start {start} must be before its end {end}",
+            request.campaign
+        );
+    }
+
+    let key = CampaignKey {
+        campaign: request.campaign.clone(),
+        tenant: request.tenant.clone(),
+    };
+    {
+        let mut campaigns = CAMPAIGNS.lock();
+        match campaigns.get_mut(&key) {
+            Some(existing) => {
+                existing.params = request;
+            }
+            None => {
+                campaigns.insert(
+                    key,
+                    Campaign {
+                        params: request,
+                        created: Utc::now(),
+                        paused: None,
+                        cancelled: None,
+                        progress: CampaignV1Progress::default(),
+                        reserved: 0,
+                    },
+                );
+            }
+        }
+    }
+    save().await;
+    Ok(())
+}
+
+/// Forget a registered campaign, lifting any pause
+pub async fn unregister(selector: CampaignV1Selector) -> bool {
+    let key = CampaignKey {
+        campaign: selector.campaign,
+        tenant: selector.tenant,
+    };
+    let Some(campaign) = CAMPAIGNS.lock().remove(&key) else {
+        return false;
+    };
+    if let Some(pause) = campaign.paused {
+        AdminSuspendEntry::remove_by_id(&pause.id);
+    }
+    save().await;
+    true
+}
+
+pub fn list(request: CampaignV1ListRequest) -> Vec<CampaignV1Entry> {
+    let now = Utc::now();
+    let campaigns = CAMPAIGNS.lock();
+    let mut entries: Vec<_> = campaigns
+        .iter()
+        .filter(|(key, _)| {
+            request
+                .campaign
+                .as_ref()
+                .map(|c| *c == key.campaign)
+                .unwrap_or(true)
+                && request
+                    .tenant
+                    .as_ref()
+                    .map(|t| key.tenant.as_ref() == Some(t))
+                    .unwrap_or(true)
+        })
+        .map(|(_, campaign)| campaign.entry(now))
+        .collect();
+    entries.sort_by(|a, b| {
+        (&a.params.tenant, &a.params.campaign).cmp(&(&b.params.tenant, &b.params.campaign))
+    });
+    entries
+}
+
+pub async fn apply_action(request: CampaignV1ActionRequest) -> anyhow::Result<()> {
+    let key = CampaignKey {
+        campaign: request.campaign.clone(),
+        tenant: request.tenant.clone(),
+    };
+    let reason = request
+        .reason
+        .clone()
+        .unwrap_or_else(|| format!("campaign {} {:?}", request.campaign, request.action));
+
+    let bounce = {
+        let mut campaigns = CAMPAIGNS.lock();
+        let excluded_tenants = excluded_tenants(&campaigns, &key);
+        let Some(campaign) = campaigns.get_mut(&key) else {
+            anyhow::bail!("campaign {} is not registered", request.campaign);
+        };
+
+        match request.action {
+            CampaignV1Action::Pause => {
+                anyhow::ensure!(
+                    campaign.cancelled.is_none(),
+                    "campaign {} has been cancelled",
+                    request.campaign
+                );
+                let duration = request.duration.unwrap_or(PAUSE_UNTIL_RESUMED);
+                let pause = Pause {
+                    id: Uuid::new_v4(),
+                    reason,
+                    until: Utc::now()
+                        + chrono::Duration::from_std(duration)
+                            .context("pause duration is out of range")?,
+                };
+                // Adding replaces any prior suspension with the same criteria
+                AdminSuspendEntry::add(pause_entry(&key, &pause, excluded_tenants, duration));
+                campaign.paused.replace(pause);
+                None
+            }
+            CampaignV1Action::Resume => {
+                if let Some(pause) = campaign.paused.take() {
+                    AdminSuspendEntry::remove_by_id(&pause.id);
+                }
+                None
+            }
+            CampaignV1Action::Cancel => {
+                if let Some(pause) = campaign.paused.take() {
+                    AdminSuspendEntry::remove_by_id(&pause.id);
+                }
+                campaign.cancelled.replace(reason.clone());
+                Some((
+                    BounceV1Request {
+                        campaign: Some(request.campaign.clone()),
+                        tenant: request.tenant.clone(),
+                        domain: None,
+                        routing_domain: None,
+                        reason,
+                        duration: None,
+                        suppress_logging: false,
+                    },
+                    excluded_tenants,
+                ))
+            }
+        }
+    };
+
+    save().await;
+
+    if let Some((bounce, excluded_tenants)) = bounce {
+        start_bounce(bounce, excluded_tenants, "campaign action").await?;
+    }
+    Ok(())
+}
+
+/// Produce the administrative suspension that implements pause
+fn pause_entry(
+    key: &CampaignKey,
+    pause: &Pause,
+    excluded_tenants: Vec<String>,
+    duration: Duration,
+) -> AdminSuspendEntry {
+    AdminSuspendEntry {
+        id: pause.id,
+        campaign: Some(key.campaign.clone()),
+        tenant: key.tenant.clone(),
+        domain: None,
+        excluded_tenants,
+        reason: pause.reason.clone(),
+        expires: Instant::now() + duration,
+    }
+}
+
+/// A message's share of the budget of its campaign, reserved by
+/// check_message. It is counted as received by `commit`, and is
+/// given back to the campaign if it is dropped without having been
+/// committed, such as when the message is subsequently rejected or
+/// cannot be spooled.
+#[must_use]
+pub struct CampaignReservation {
+    key: Option<CampaignKey>,
+}
+
+impl CampaignReservation {
+    /// Count the message as received by its campaign.
+    /// This is called once the message has been spooled.
+    pub fn commit(mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        if let Some(campaign) = CAMPAIGNS.lock().get_mut(&key) {
+            campaign.reserved = campaign.reserved.saturating_sub(1);
+            campaign.progress.received += 1;
+        }
+        // Ensure that the progress will be persisted
+        Lazy::force(&FLUSHER);
+    }
+}
+
+impl Drop for CampaignReservation {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            if let Some(campaign) = CAMPAIGNS.lock().get_mut(&key) {
+                campaign.reserved = campaign.reserved.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// If msg belongs to a registered campaign, either reserve its share
+/// of the budget of the campaign and apply the settings of the campaign
+/// to it, or return a RejectError if the campaign is no longer
+/// accepting messages.
+/// The budget is checked and reserved under the same lock, so that
+/// concurrent receptions cannot exceed it.
+pub async fn check_message(msg: &Message) -> anyhow::Result<CampaignReservation> {
+    let now = Utc::now();
+    let (reservation, params) = {
+        let mut campaigns = CAMPAIGNS.lock();
+        let Some(key) = message_key(&campaigns, msg) else {
+            return Ok(CampaignReservation { key: None });
+        };
+        let Some(campaign) = campaigns.get_mut(&key) else {
+            return Ok(CampaignReservation { key: None });
+        };
+        if let Some(rej) = campaign.rejection(now) {
+            return Err(rej.into());
+        }
+        campaign.reserved += 1;
+        (
+            CampaignReservation { key: Some(key) },
+            campaign.params.clone(),
+        )
+    };
+
+    for (name, value) in params.metadata {
+        if msg.get_meta(name.as_str())?.is_null() {
+            msg.set_meta(name, value)?;
+        }
+    }
+    if let Some(priority) = params.priority {
+        msg.set_meta("campaign_priority", priority)?;
+        msg.set_priority(priority)?;
+    }
+    if let Some(start) = params.start {
+        if start > now {
+            msg.set_due(Some(start)).await?;
+        }
+    }
+
+    Ok(reservation)
+}
+
+/// Count the outcome of a delivery attempt towards the campaign
+/// of msg. This is called from `log_disposition`.
+pub fn record_disposition(kind: RecordType, msg: &Message) {
+    let mut campaigns = CAMPAIGNS.lock();
+    if campaigns.is_empty() {
+        return;
+    }
+    let Some(key) = message_key(&campaigns, msg) else {
+        return;
+    };
+    let Some(campaign) = campaigns.get_mut(&key) else {
+        return;
+    };
+    let progress = &mut campaign.progress;
+    match kind {
+        RecordType::Delivery => progress.delivered += 1,
+        RecordType::TransientFailure => progress.transient_failures += 1,
+        RecordType::Bounce => progress.bounced += 1,
+        RecordType::Expiration => progress.expired += 1,
+        _ => {}
+    }
+    drop(campaigns);
+    Lazy::force(&FLUSHER);
+}
+
+fn open_db() -> anyhow::Result<Connection> {
+    let path = DB_PATH.lock().clone();
+    let mut db =
+        Connection::open(&path).with_context(|| format!("opening campaign database {path}"))?;
+    db.set_busy_timeout(500)?;
+    init_db(&db)?;
+    Ok(db)
+}
+
+fn init_db(db: &Connection) -> anyhow::Result<()> {
+    let query = r#"
+CREATE TABLE IF NOT EXISTS campaign (
+    campaign text NOT NULL,
+    tenant text,
+    params text NOT NULL,
+    created DATETIME NOT NULL,
+    paused_id text,
+    paused_reason text,
+    paused_until DATETIME,
+    cancelled text,
+    progress text NOT NULL
+);
+    "#;
+    db.execute(query)?;
+    Ok(())
+}
+
+fn read_campaigns(db: &Connection) -> anyhow::Result<HashMap<CampaignKey, Campaign>> {
+    let mut stmt = db.prepare("SELECT * FROM campaign")?;
+    let mut campaigns = HashMap::new();
+    while let sqlite::State::Row = stmt.next()? {
+        let params: String = stmt.read("params")?;
+        let params: CampaignV1Request = serde_json::from_str(&params)?;
+        let created: String = stmt.read("created")?;
+        let progress: String = stmt.read("progress")?;
+
+        let paused_id: Option<String> = stmt.read("paused_id")?;
+        let paused_reason: Option<String> = stmt.read("paused_reason")?;
+        let paused_until: Option<String> = stmt.read("paused_until")?;
+        let paused = match (paused_id, paused_until) {
+            (Some(id), Some(until)) => Some(Pause {
+                id: id.parse()?,
+                reason: paused_reason.unwrap_or_default(),
+                until: DateTime::parse_from_rfc3339(&until)?.to_utc(),
+            }),
+            _ => None,
+        };
+
+        campaigns.insert(
+            CampaignKey {
+                campaign: params.campaign.clone(),
+                tenant: params.tenant.clone(),
+            },
+            Campaign {
+                params,
+                created: DateTime::parse_from_rfc3339(&created)?.to_utc(),
+                paused,
+                cancelled: stmt.read("cancelled")?,
+                progress: serde_json::from_str(&progress)?,
+                reserved: 0,
+            },
+        );
+    }
+    Ok(campaigns)
+}
+
+fn write_campaigns(
+    db: &Connection,
+    campaigns: &HashMap<CampaignKey, Campaign>,
+) -> anyhow::Result<()> {
+    db.execute("BEGIN")?;
+    let result = (|| {
+        db.execute("DELETE FROM campaign")?;
+        let mut insert = db.prepare(
+            "INSERT INTO campaign
+                (campaign, tenant, params, created, paused_id,
+                 paused_reason, paused_until, cancelled, progress)
+                VALUES ($campaign, $tenant, $params, $created, $paused_id,
+                 $paused_reason, $paused_until, $cancelled, $progress)",
+        )?;
+        for (key, campaign) in campaigns {
+            let pause = campaign.paused.as_ref();
+            insert.reset()?;
+            insert.bind(("$campaign", key.campaign.as_str()))?;
+            insert.bind(("$tenant", key.tenant.as_deref()))?;
+            insert.bind(("$params", serde_json::to_string(&campaign.params)?.as_str()))?;
+            insert.bind(("$created", campaign.created.to_rfc3339().as_str()))?;
+            insert.bind(("$paused_id", pause.map(|p| p.id.to_string()).as_deref()))?;
+            insert.bind(("$paused_reason", pause.map(|p| p.reason.as_str())))?;
+            insert.bind((
+                "$paused_until",
+                pause.map(|p| p.until.to_rfc3339()).as_deref(),
+            ))?;
+            insert.bind(("$cancelled", campaign.cancelled.as_deref()))?;
+            insert.bind((
+                "$progress",
+                serde_json::to_string(&campaign.progress)?.as_str(),
+            ))?;
+            insert.next()?;
+        }
+        Ok(())
+    })();
+    match result {
+        Ok(()) => {
+            db.execute("COMMIT")?;
+            Ok(())
+        }
+        Err(err) => {
+            db.execute("ROLLBACK").ok();
+            Err(err)
+        }
+    }
+}
+
+/// Write the current state of the campaigns to the database
+pub fn flush() -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    let _serialize = FLUSH_LOCK.lock();
+    let db = open_db()?;
+    // Snapshot the state while holding the lock, so that it is
+    // consistent, but don't hold it while writing
+    let campaigns = CAMPAIGNS.lock().clone();
+    write_campaigns(&db, &campaigns)
+}
+
+/// Persist a change made via the admin API. If that fails, the
+/// change remains in effect and the flusher will retry it.
+async fn save() {
+    Lazy::force(&FLUSHER);
+    match get_main_runtime().spawn_blocking(flush).await {
+        Ok(Err(err)) => tracing::error!("Error saving campaigns: {err:#}"),
+        Err(err) => tracing::error!("Error saving campaigns: {err:#}"),
+        Ok(Ok(())) => {}
+    }
+}
+
+/// Load the campaigns that were persisted by a prior run, and
+/// reinstate the pauses that have not yet expired.
+/// This is called once the init event has configured the path
+/// of the database.
+pub async fn load() -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    let mut loaded = get_main_runtime()
+        .spawn_blocking(|| read_campaigns(&open_db()?))
+        .await??;
+    if loaded.is_empty() {
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    let mut pauses = vec![];
+    for (key, campaign) in loaded.iter_mut() {
+        let Some(pause) = campaign.paused.take() else {
+            continue;
+        };
+        if let Ok(remaining) = (pause.until - now).to_std() {
+            pauses.push((key.clone(), remaining));
+            campaign.paused.replace(pause);
+        }
+    }
+    for (key, remaining) in pauses {
+        let excluded_tenants = excluded_tenants(&loaded, &key);
+        if let Some(pause) = loaded.get(&key).and_then(|c| c.paused.as_ref()) {
+            AdminSuspendEntry::add(pause_entry(&key, pause, excluded_tenants, remaining));
+        }
+    }
+
+    tracing::info!("loaded {} campaigns", loaded.len());
+    *CAMPAIGNS.lock() = loaded;
+    Lazy::force(&FLUSHER);
+    Ok(())
+}
+
+async fn flusher() {
+    let mut shutdown = ShutdownSubcription::get();
+    loop {
+        let shutting_down = tokio::select! {
+            _ = shutdown.shutting_down() => true,
+            _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => false,
+        };
+
+        match get_main_runtime().spawn_blocking(flush).await {
+            Ok(Err(err)) => tracing::error!("Error flushing campaigns: {err:#}"),
+            Err(err) => tracing::error!("Error flushing campaigns: {err:#}"),
+            Ok(Ok(())) => {}
+        }
+
+        if shutting_down {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn campaign(params: CampaignV1Request) -> Campaign {
+        Campaign {
+            params,
+            created: Utc::now(),
+            paused: None,
+            cancelled: None,
+            progress: CampaignV1Progress::default(),
+            reserved: 0,
+        }
+    }
+
+    fn params(name: &str, tenant: Option<&str>) -> CampaignV1Request {
+        CampaignV1Request {
+            campaign: name.to_string(),
+            tenant: tenant.map(|t| t.to_string()),
+            metadata: Default::default(),
+            start: None,
+            end: None,
+            priority: None,
+            budget: None,
+        }
+    }
+
+    #[test]
+    fn states() {
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+
+        let mut c = campaign(params("c", None));
+        assert_eq!(c.state(now), CampaignV1State::Active);
+        assert!(c.rejection(now).is_none());
+
+        c.params.start.replace(now + hour);
+        assert_eq!(c.state(now), CampaignV1State::Scheduled);
+        assert!(c.rejection(now).is_none());
+
+        c.params.start.take();
+        c.params.budget.replace(2);
+        c.progress.received = 2;
+        assert_eq!(c.state(now), CampaignV1State::Ended);
+        assert_eq!(
+            c.rejection(now).unwrap().message,
+            "5.7.1 campaign c has exhausted its budget"
+        );
+
+        c.params.budget.take();
+        c.params.end.replace(now - hour);
+        assert_eq!(c.state(now), CampaignV1State::Ended);
+
+        c.cancelled.replace("no longer wanted".to_string());
+        assert_eq!(c.state(now), CampaignV1State::Cancelled);
+        assert_eq!(
+            c.rejection(now).unwrap().message,
+            "5.7.1 campaign c has been cancelled"
+        );
+    }
+
+    #[test]
+    fn persistence() {
+        let db = Connection::open(":memory:").unwrap();
+        init_db(&db).unwrap();
+
+        let mut campaigns = HashMap::new();
+        let mut shared = campaign(params("shared", None));
+        shared.params.budget.replace(100);
+        shared.progress.received = 42;
+        shared.progress.delivered = 40;
+        shared.paused.replace(Pause {
+            id: Uuid::new_v4(),
+            reason: "waiting for approval".to_string(),
+            until: Utc::now() + chrono::Duration::hours(1),
+        });
+        let mut own = campaign(params("own", Some("t1")));
+        own.cancelled.replace("no longer wanted".to_string());
+        for c in [shared, own] {
+            campaigns.insert(
+                CampaignKey {
+                    campaign: c.params.campaign.clone(),
+                    tenant: c.params.tenant.clone(),
+                },
+                c,
+            );
+        }
+
+        write_campaigns(&db, &campaigns).unwrap();
+        // Writing again replaces rather than duplicates the rows
+        write_campaigns(&db, &campaigns).unwrap();
+        let loaded = read_campaigns(&db).unwrap();
+        assert_eq!(loaded.len(), 2);
+
+        let now = Utc::now();
+        for (key, original) in &campaigns {
+            let restored = &loaded[key];
+            assert_eq!(restored.params, original.params);
+            assert_eq!(restored.progress, original.progress);
+            assert_eq!(restored.cancelled, original.cancelled);
+            assert_eq!(
+                restored.created.timestamp_micros(),
+                original.created.timestamp_micros()
+            );
+            assert_eq!(restored.entry(now).state, original.entry(now).state);
+            assert_eq!(
+                restored.paused.as_ref().map(|p| (p.id, &p.reason)),
+                original.paused.as_ref().map(|p| (p.id, &p.reason))
+            );
+        }
+
+        campaigns.retain(|key, _| key.tenant.is_some());
+        write_campaigns(&db, &campaigns).unwrap();
+        let loaded = read_campaigns(&db).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert!(loaded.values().all(|c| c.params.campaign == "own"));
+    }
+
+    #[tokio::test]
+    async fn budget_is_reserved() {
+        let key = CampaignKey {
+            campaign: "reserved".to_string(),
+            tenant: Some("t-reserved".to_string()),
+        };
+        let mut p = params(&key.campaign, key.tenant.as_deref());
+        p.budget.replace(2);
+        p.priority.replace(7);
+        CAMPAIGNS.lock().insert(key.clone(), campaign(p));
+
+        let msg = || {
+            Message::new_dirty(
+                spool::SpoolId::new(),
+                message::EnvelopeAddress::parse("sender@example.com").unwrap(),
+                message::EnvelopeAddress::parse("recip@example.com").unwrap(),
+                serde_json::json!({"campaign": "reserved", "tenant": "t-reserved"}),
+                std::sync::Arc::new(vec![].into_boxed_slice()),
+            )
+            .unwrap()
+        };
+
+        let first_msg = msg();
+        let first = check_message(&first_msg).await.unwrap();
+        assert_eq!(first_msg.get_priority(), 7);
+        assert_eq!(
+            first_msg.get_meta("campaign_priority").unwrap(),
+            serde_json::json!(7)
+        );
+        let second = check_message(&msg()).await.unwrap();
+
+        // The budget is used up by the reservations, even though
+        // neither message has completed its reception
+        let err = check_message(&msg()).await.err().unwrap();
+        assert_eq!(
+            RejectError::from_anyhow(&err).unwrap().message,
+            "5.7.1 campaign reserved has exhausted its budget"
+        );
+
+        // A failed reception gives its share back
+        drop(second);
+        let third = check_message(&msg()).await.unwrap();
+        assert_eq!(CAMPAIGNS.lock()[&key].reserved, 2);
+
+        drop(first);
+        drop(third);
+        let campaign = CAMPAIGNS.lock().remove(&key).unwrap();
+        assert_eq!(campaign.reserved, 0);
+        assert_eq!(campaign.progress.received, 0);
+    }
+
+    #[test]
+    fn lookup() {
+        let mut campaigns = HashMap::new();
+        for (name, tenant) in [
+            ("shared", None),
+            ("shared", Some("t1")),
+            ("own", Some("t1")),
+        ] {
+            let p = params(name, tenant);
+            campaigns.insert(
+                CampaignKey {
+                    campaign: p.campaign.clone(),
+                    tenant: p.tenant.clone(),
+                },
+                campaign(p),
+            );
+        }
+
+        let key = find_key(&campaigns, "shared", Some("t1")).unwrap();
+        assert_eq!(key.tenant.as_deref(), Some("t1"));
+        let key = find_key(&campaigns, "shared", Some("t2")).unwrap();
+        assert_eq!(key.tenant, None);
+        assert!(find_key(&campaigns, "own", Some("t1")).is_some());
+        assert!(find_key(&campaigns, "own", Some("t2")).is_none());
+        assert!(find_key(&campaigns, "other", None).is_none());
+
+        // Acting on the campaign without a tenant must not affect
+        // the tenant that has its own campaign
+        let shared = find_key(&campaigns, "shared", Some("t2")).unwrap();
+        assert_eq!(excluded_tenants(&campaigns, &shared), vec!["t1"]);
+        let own = find_key(&campaigns, "own", Some("t1")).unwrap();
+        assert!(excluded_tenants(&campaigns, &own).is_empty());
+
+        let entry = AdminSuspendEntry {
+            id: Uuid::new_v4(),
+            campaign: Some("shared".to_string()),
+            tenant: None,
+            domain: None,
+            excluded_tenants: excluded_tenants(&campaigns, &shared),
+            reason: "paused".to_string(),
+            expires: Instant::now() + PAUSE_UNTIL_RESUMED,
+        };
+        assert!(entry.matches(Some("shared"), Some("t2"), Some("example.com")));
+        assert!(entry.matches(Some("shared"), None, Some("example.com")));
+        assert!(!entry.matches(Some("shared"), Some("t1"), Some("example.com")));
+    }
+}
@@ -0,0 +1,150 @@
+//! Describes the structures that are accepted by the lua configuration
+//! functions, such as `kumo.make_egress_path`, as an OpenAPI components
+//! document, so that external tooling can validate policy and offer
+//! completions to policy authors.
+use crate::egress_source::{EgressPool, EgressPoolEntry, EgressSource};
+use crate::queue::{QueueConfig, QueueStrategy};
+use crate::smtp_server::{ConnectionClass, EsmtpListenerParams, TraceHeaders};
+use kumo_api_types::egress_path::{
+    ConfigRefreshStrategy, ConnectionStrategy, EgressPathConfig, Tls, TlsPinMismatch,
+};
+use kumo_server_common::http_server::HttpListenerParams;
+use rfc5321::SmtpClientTimeouts;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "KumoMTA configuration"),
+    components(schemas(
+        ConfigRefreshStrategy,
+        ConnectionClass,
+        ConnectionStrategy,
+        EgressPathConfig,
+        EgressPool,
+        EgressPoolEntry,
+        EgressSource,
+        EsmtpListenerParams,
+        HttpListenerParams,
+        QueueConfig,
+        QueueStrategy,
+        SmtpClientTimeouts,
+        Tls,
+        TlsPinMismatch,
+        TraceHeaders,
+    ))
+)]
+struct ConfigSchema;
+
+/// Produce the configuration schema as pretty printed JSON.
+pub fn dump() -> anyhow::Result<String> {
+    let mut doc = serde_json::to_value(ConfigSchema::openapi())?;
+    let schemas = doc
+        .pointer_mut("/components/schemas")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow::anyhow!("schema has no components"))?;
+
+    apply_defaults::<ConnectionClass>(
+        schemas,
+        "ConnectionClass",
+        json!({"name": "", "hosts": [], "max_connections": 0}),
+    )?;
+    apply_defaults::<EgressPathConfig>(schemas, "EgressPathConfig", json!({}))?;
+    apply_defaults::<EgressPool>(schemas, "EgressPool", json!({"name": "", "entries": []}))?;
+    apply_defaults::<EgressPoolEntry>(schemas, "EgressPoolEntry", json!({"name": ""}))?;
+    apply_defaults::<EgressSource>(schemas, "EgressSource", json!({"name": ""}))?;
+    apply_defaults::<EsmtpListenerParams>(schemas, "EsmtpListenerParams", json!({}))?;
+    apply_defaults::<HttpListenerParams>(schemas, "HttpListenerParams", json!({}))?;
+    apply_defaults::<QueueConfig>(schemas, "QueueConfig", json!({}))?;
+    apply_defaults::<SmtpClientTimeouts>(schemas, "SmtpClientTimeouts", json!({}))?;
+    apply_defaults::<TraceHeaders>(schemas, "TraceHeaders", json!({}))?;
+
+    Ok(serde_json::to_string_pretty(&doc)?)
+}
+
+/// The derived schemas don't know about the values produced by
+/// `#[serde(default)]`, so we obtain them by deserializing `minimal`,
+/// which holds just the required fields, and recording the resulting
+/// value of each optional property.
+fn apply_defaults<T: DeserializeOwned + Serialize>(
+    schemas: &mut Map<String, Value>,
+    name: &str,
+    minimal: Value,
+) -> anyhow::Result<()> {
+    let value: T = serde_json::from_value(minimal)?;
+    let defaults = serde_json::to_value(value)?;
+
+    let schema = schemas
+        .get_mut(name)
+        .ok_or_else(|| anyhow::anyhow!("no schema for {name}"))?;
+
+    // Flattened fields cause the schema to be expressed as
+    // an allOf list rather than a simple object
+    let objects: Vec<&mut Value> = match schema {
+        Value::Object(map) if map.contains_key("allOf") => map
+            .get_mut("allOf")
+            .and_then(Value::as_array_mut)
+            .map(|items| items.iter_mut().collect())
+            .unwrap_or_default(),
+        other => vec![other],
+    };
+
+    for object in objects {
+        let required: Vec<Value> = object
+            .get("required")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        let Some(properties) = object.get_mut("properties").and_then(Value::as_object_mut) else {
+            continue;
+        };
+        for (prop_name, prop) in properties.iter_mut() {
+            if required.iter().any(|r| r.as_str() == Some(prop_name)) {
+                continue;
+            }
+            match defaults.get(prop_name) {
+                None | Some(Value::Null) => {}
+                Some(default) => {
+                    if let Some(prop) = prop.as_object_mut() {
+                        prop.insert("default".to_string(), default.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let doc: Value = serde_json::from_str(&dump().unwrap()).unwrap();
+        let schemas = &doc["components"]["schemas"];
+
+        assert_eq!(
+            schemas["QueueConfig"]["properties"]["retry_interval"]["default"],
+            json!("20m")
+        );
+        assert_eq!(
+            schemas["SmtpClientTimeouts"]["properties"]["rset_timeout"]["default"],
+            json!("5s")
+        );
+        assert_eq!(
+            schemas["EgressPoolEntry"]["properties"]["weight"]["default"],
+            json!(1)
+        );
+        assert_eq!(
+            schemas["EsmtpListenerParams"]["properties"]["banner"]["default"],
+            json!("KumoMTA")
+        );
+        assert!(schemas["EgressPool"]["properties"]["name"]
+            .get("default")
+            .is_none());
+    }
+}
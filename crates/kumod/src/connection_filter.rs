@@ -0,0 +1,162 @@
+//! A filter that is consulted as soon as an inbound SMTP connection is
+//! accepted, before any policy runs, so that connections from abusive
+//! networks can be dropped as cheaply as possible.
+//!
+//! The entries are compiled into a prefix trie; the most specific entry
+//! that contains the peer address determines whether the connection is
+//! allowed or denied, which allows a subnet of a denied block to be
+//! exempted. Addresses that match no entry are allowed.
+use cidr_map::{parse_cidr, AnyIpCidr, CidrMap};
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use kumo_api_types::connection_filter::{ConnectionFilterAction, ConnectionFilterV1Entry};
+use mlua::{Lua, LuaSerdeExt, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+static FILTER: Lazy<Mutex<ConnectionFilter>> =
+    Lazy::new(|| Mutex::new(ConnectionFilter::default()));
+
+#[derive(Default)]
+struct ConnectionFilter {
+    entries: BTreeMap<AnyIpCidr, ConnectionFilterV1Entry>,
+    trie: CidrMap<ConnectionFilterAction>,
+}
+
+impl ConnectionFilter {
+    fn add(&mut self, entries: Vec<ConnectionFilterV1Entry>) -> anyhow::Result<()> {
+        // Parse everything up front so that a bad entry doesn't
+        // leave us with a partially applied update
+        let parsed = entries
+            .into_iter()
+            .map(|entry| Ok((parse_cidr(&entry.cidr)?, entry)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.entries.extend(parsed);
+        self.rebuild();
+        Ok(())
+    }
+
+    /// Returns the number of entries that were removed
+    fn remove(&mut self, cidrs: &[String]) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for cidr in cidrs {
+            if self.entries.remove(&parse_cidr(cidr)?).is_some() {
+                removed += 1;
+            }
+        }
+        self.rebuild();
+        Ok(removed)
+    }
+
+    fn rebuild(&mut self) {
+        // Inserting into the trie overwrites any overlapping portion of
+        // an existing prefix, so insert the broadest prefixes first in
+        // order for the more specific entries to take precedence
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(cidr, _)| cidr.network_length().unwrap_or(0));
+
+        let mut trie = CidrMap::new();
+        for (cidr, entry) in entries {
+            trie.insert(*cidr, entry.action);
+        }
+        self.trie = trie;
+    }
+
+    fn is_denied(&self, ip: IpAddr) -> bool {
+        self.trie.get_prefix_match(ip) == Some(&ConnectionFilterAction::Deny)
+    }
+}
+
+/// Returns true if connections from ip should be dropped
+pub fn is_denied(ip: IpAddr) -> bool {
+    FILTER.lock().is_denied(ip)
+}
+
+pub fn add_entries(entries: Vec<ConnectionFilterV1Entry>) -> anyhow::Result<()> {
+    FILTER.lock().add(entries)
+}
+
+/// Removes the entries with the specified cidrs, returning the
+/// number of entries that were removed
+pub fn remove_entries(cidrs: &[String]) -> anyhow::Result<usize> {
+    FILTER.lock().remove(cidrs)
+}
+
+pub fn list_entries() -> Vec<ConnectionFilterV1Entry> {
+    FILTER.lock().entries.values().cloned().collect()
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "connection_filter")?;
+
+    module.set(
+        "configure",
+        lua.create_function(|lua, params: Value| {
+            let entries: Vec<ConnectionFilterV1Entry> = from_lua_value(lua, params)?;
+            let mut filter = ConnectionFilter::default();
+            filter.add(entries).map_err(any_err)?;
+            *FILTER.lock() = filter;
+            Ok(())
+        })?,
+    )?;
+
+    module.set(
+        "list",
+        lua.create_function(|lua, ()| lua.to_value(&list_entries()))?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(cidr: &str, action: ConnectionFilterAction) -> ConnectionFilterV1Entry {
+        ConnectionFilterV1Entry {
+            cidr: cidr.to_string(),
+            action,
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn most_specific_wins() {
+        let mut filter = ConnectionFilter::default();
+        filter
+            .add(vec![
+                entry("10.1.2.0/24", ConnectionFilterAction::Allow),
+                entry("10.0.0.0/8", ConnectionFilterAction::Deny),
+                entry("10.1.2.3", ConnectionFilterAction::Deny),
+            ])
+            .unwrap();
+
+        let denied = |ip: &str| filter.is_denied(ip.parse().unwrap());
+        assert!(denied("10.9.9.9"));
+        assert!(!denied("10.1.2.4"));
+        assert!(denied("10.1.2.3"));
+        assert!(!denied("192.168.1.1"));
+        assert!(!denied("::1"));
+
+        assert_eq!(
+            filter
+                .remove(&["10.1.2.0/24".to_string(), "10.2.0.0/16".to_string()])
+                .unwrap(),
+            1
+        );
+        assert!(filter.is_denied("10.1.2.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_entries_are_not_applied() {
+        let mut filter = ConnectionFilter::default();
+        assert!(filter
+            .add(vec![
+                entry("10.0.0.0/8", ConnectionFilterAction::Deny),
+                entry("not-an-address", ConnectionFilterAction::Deny),
+            ])
+            .is_err());
+        assert!(!filter.is_denied("10.0.0.1".parse().unwrap()));
+    }
+}
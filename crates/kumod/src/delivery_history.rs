@@ -0,0 +1,311 @@
+//! Keeps a bounded, in-memory history of the most recent delivery
+//! outcomes per tenant and destination domain or provider, based on
+//! the dispositions that pass through `log_disposition`, so that
+//! reception-time policy can take recent outcomes into account.
+use chrono::{DateTime, Utc};
+use config::{from_lua_value, get_or_create_sub_module};
+use kumo_log_types::RecordType;
+use lru_cache::LruCache;
+use message::Message;
+use mlua::{Lua, LuaSerdeExt, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use rfc5321::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+static CONFIG: Lazy<Mutex<DeliveryHistoryConfig>> =
+    Lazy::new(|| Mutex::new(DeliveryHistoryConfig::default()));
+static HISTORY: Lazy<Mutex<LruCache<Key, VecDeque<DeliveryOutcome>>>> =
+    Lazy::new(|| Mutex::new(LruCache::new(DeliveryHistoryConfig::default_max_keys())));
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DeliveryHistoryConfig {
+    /// How many outcomes to retain for each tenant and destination
+    #[serde(default = "DeliveryHistoryConfig::default_max_entries")]
+    pub max_entries: usize,
+
+    /// How many tenant and destination combinations to track;
+    /// the least recently used are discarded first
+    #[serde(default = "DeliveryHistoryConfig::default_max_keys")]
+    pub max_keys: usize,
+}
+
+impl Default for DeliveryHistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: Self::default_max_entries(),
+            max_keys: Self::default_max_keys(),
+        }
+    }
+}
+
+impl DeliveryHistoryConfig {
+    fn default_max_entries() -> usize {
+        100
+    }
+
+    fn default_max_keys() -> usize {
+        10_000
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Destination {
+    Domain(String),
+    Provider(String),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Key {
+    tenant: Option<String>,
+    destination: Destination,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutcomeKind {
+    Delivery,
+    TransientFailure,
+    Bounce,
+    Expiration,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DeliveryOutcome {
+    pub kind: OutcomeKind,
+    pub code: u16,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A summary of the retained outcomes, along with the outcomes
+/// themselves, most recent first
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct DeliveryHistory {
+    pub total: usize,
+    pub delivered: usize,
+    pub transient_failures: usize,
+    pub bounced: usize,
+    pub expired: usize,
+    pub outcomes: Vec<DeliveryOutcome>,
+}
+
+impl DeliveryHistory {
+    fn from_outcomes(outcomes: &VecDeque<DeliveryOutcome>, limit: Option<usize>) -> Self {
+        let mut history = Self::default();
+        for outcome in outcomes.iter().rev().take(limit.unwrap_or(outcomes.len())) {
+            history.total += 1;
+            match outcome.kind {
+                OutcomeKind::Delivery => history.delivered += 1,
+                OutcomeKind::TransientFailure => history.transient_failures += 1,
+                OutcomeKind::Bounce => history.bounced += 1,
+                OutcomeKind::Expiration => history.expired += 1,
+            }
+            history.outcomes.push(outcome.clone());
+        }
+        history
+    }
+}
+
+fn record(
+    history: &mut LruCache<Key, VecDeque<DeliveryOutcome>>,
+    key: Key,
+    outcome: DeliveryOutcome,
+    max_entries: usize,
+) {
+    if !history.contains_key(&key) {
+        history.insert(key.clone(), VecDeque::new());
+    }
+    if let Some(outcomes) = history.get_mut(&key) {
+        while outcomes.len() >= max_entries.max(1) {
+            outcomes.pop_front();
+        }
+        outcomes.push_back(outcome);
+    }
+}
+
+/// Record the outcome of a delivery attempt.
+/// This is called from `log_disposition`.
+pub fn record_disposition(
+    kind: RecordType,
+    msg: &Message,
+    response: &Response,
+    provider: Option<&str>,
+) {
+    let kind = match kind {
+        RecordType::Delivery => OutcomeKind::Delivery,
+        RecordType::TransientFailure => OutcomeKind::TransientFailure,
+        RecordType::Bounce => OutcomeKind::Bounce,
+        RecordType::Expiration => OutcomeKind::Expiration,
+        _ => return,
+    };
+    let outcome = DeliveryOutcome {
+        kind,
+        code: response.code,
+        timestamp: Utc::now(),
+    };
+    let tenant = msg.get_meta_string("tenant").ok().flatten();
+
+    let mut destinations = vec![];
+    if let Ok(recipient) = msg.recipient() {
+        destinations.push(Destination::Domain(recipient.domain().to_ascii_lowercase()));
+    }
+    if let Some(provider) = provider {
+        destinations.push(Destination::Provider(provider.to_string()));
+    }
+
+    let max_entries = CONFIG.lock().max_entries;
+    let mut history = HISTORY.lock();
+    for destination in destinations {
+        record(
+            &mut history,
+            Key {
+                tenant: tenant.clone(),
+                destination,
+            },
+            outcome.clone(),
+            max_entries,
+        );
+    }
+}
+
+fn lookup(
+    destination: Destination,
+    tenant: Option<String>,
+    limit: Option<usize>,
+) -> Option<DeliveryHistory> {
+    let mut history = HISTORY.lock();
+    let outcomes = history.get_mut(&Key {
+        tenant,
+        destination,
+    })?;
+    Some(DeliveryHistory::from_outcomes(outcomes, limit))
+}
+
+fn configure(config: DeliveryHistoryConfig) {
+    let mut history = HISTORY.lock();
+    history.set_capacity(config.max_keys);
+    *CONFIG.lock() = config;
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "delivery_history")?;
+
+    module.set(
+        "configure",
+        lua.create_function(|lua, params: Value| {
+            let config: DeliveryHistoryConfig = from_lua_value(lua, params)?;
+            configure(config);
+            Ok(())
+        })?,
+    )?;
+
+    module.set(
+        "get_domain",
+        lua.create_function(
+            |lua, (domain, tenant, limit): (String, Option<String>, Option<usize>)| {
+                let history = lookup(
+                    Destination::Domain(domain.to_ascii_lowercase()),
+                    tenant,
+                    limit,
+                );
+                lua.to_value(&history)
+            },
+        )?,
+    )?;
+
+    module.set(
+        "get_provider",
+        lua.create_function(
+            |lua, (provider, tenant, limit): (String, Option<String>, Option<usize>)| {
+                let history = lookup(Destination::Provider(provider), tenant, limit);
+                lua.to_value(&history)
+            },
+        )?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn outcome(kind: OutcomeKind, code: u16) -> DeliveryOutcome {
+        DeliveryOutcome {
+            kind,
+            code,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn key(tenant: &str, domain: &str) -> Key {
+        Key {
+            tenant: Some(tenant.to_string()),
+            destination: Destination::Domain(domain.to_string()),
+        }
+    }
+
+    #[test]
+    fn bounded_history() {
+        let mut history = LruCache::new(2);
+        record(
+            &mut history,
+            key("t", "example.com"),
+            outcome(OutcomeKind::Delivery, 250),
+            3,
+        );
+        record(
+            &mut history,
+            key("t", "example.com"),
+            outcome(OutcomeKind::TransientFailure, 421),
+            3,
+        );
+        record(
+            &mut history,
+            key("t", "example.com"),
+            outcome(OutcomeKind::Bounce, 550),
+            3,
+        );
+        record(
+            &mut history,
+            key("t", "example.com"),
+            outcome(OutcomeKind::Bounce, 551),
+            3,
+        );
+
+        let summary = DeliveryHistory::from_outcomes(
+            history.get_mut(&key("t", "example.com")).unwrap(),
+            None,
+        );
+        assert_eq!(summary.total, 3);
+        assert_eq!(summary.delivered, 0);
+        assert_eq!(summary.transient_failures, 1);
+        assert_eq!(summary.bounced, 2);
+        // Most recent first
+        assert_eq!(summary.outcomes[0].code, 551);
+
+        let summary = DeliveryHistory::from_outcomes(
+            history.get_mut(&key("t", "example.com")).unwrap(),
+            Some(1),
+        );
+        assert_eq!(summary.total, 1);
+        assert_eq!(summary.bounced, 1);
+
+        // The least recently updated key is evicted
+        record(
+            &mut history,
+            key("t", "example.net"),
+            outcome(OutcomeKind::Delivery, 250),
+            3,
+        );
+        record(
+            &mut history,
+            key("other", "example.com"),
+            outcome(OutcomeKind::Delivery, 250),
+            3,
+        );
+        assert!(history.get_mut(&key("t", "example.com")).is_none());
+        assert!(history.get_mut(&key("t", "example.net")).is_some());
+    }
+}
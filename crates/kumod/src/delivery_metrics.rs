@@ -11,7 +11,9 @@ use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use prometheus::Histogram;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 counter_bundle! {
     pub struct ReadyCountBundle {
@@ -52,6 +54,43 @@ pub struct DeliveryMetrics {
     fail: DispositionBundle,
 
     pub deliver_message_rollup: Histogram,
+
+    pub delivery_latency: Arc<DeliveryLatency>,
+}
+
+/// An exponentially weighted moving average of the time taken to
+/// deliver a message, which is used by the LatencyAware connection
+/// strategy
+#[derive(Debug, Default)]
+pub struct DeliveryLatency {
+    /// The average in microseconds, or 0 if nothing has been recorded
+    average_us: AtomicU64,
+}
+
+impl DeliveryLatency {
+    /// How much weight a new sample carries
+    const WEIGHT: f64 = 0.1;
+
+    pub fn record(&self, latency: Duration) {
+        let sample = latency.as_micros().min(u64::MAX as u128) as f64;
+        self.average_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                let average = if average == 0 {
+                    sample
+                } else {
+                    (average as f64 * (1. - Self::WEIGHT)) + (sample * Self::WEIGHT)
+                };
+                Some((average as u64).max(1))
+            })
+            .ok();
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        match self.average_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
 }
 
 impl std::fmt::Debug for DeliveryMetrics {
@@ -203,6 +242,7 @@ impl DeliveryMetrics {
             delivered,
             transfail,
             fail,
+            delivery_latency: Arc::new(DeliveryLatency::default()),
         }
     }
 
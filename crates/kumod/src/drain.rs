@@ -0,0 +1,285 @@
+//! Implements drain mode, which is used to empty a node ahead of a
+//! restart so that its mail isn't stranded while it is down.
+//!
+//! While draining, no new messages are accepted via SMTP or HTTP
+//! injection, and the scheduled queues are periodically flushed so
+//! that the queued messages are retried without waiting for their
+//! retry schedule. Once the grace period has elapsed, any messages
+//! that remain are rebound to the configured peer, and the node
+//! optionally shuts down once its queues are empty.
+use crate::http_server::admin_rebind_v1::AdminRebindEntry;
+use crate::queue::QueueManager;
+use crate::ready_queue::ReadyQueueManager;
+use chrono::{DateTime, Utc};
+use kumo_api_types::drain::{DrainV1Phase, DrainV1Request, DrainV1Status};
+use kumo_api_types::rebind::RebindV1Request;
+use kumo_server_lifecycle::LifeCycle;
+use kumo_server_runtime::rt_spawn_non_blocking;
+use message::message::QueueNameComponents;
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static STATE: Lazy<Mutex<Option<DrainState>>> = Lazy::new(|| Mutex::new(None));
+
+struct DrainState {
+    request: DrainV1Request,
+    started: DateTime<Utc>,
+    phase: DrainV1Phase,
+    /// Distinguishes this drain from any that it replaced,
+    /// so that their tasks know to stop
+    generation: usize,
+}
+
+/// Returns true if the node is draining and should not accept
+/// any new messages
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Start draining the node. If it is already draining, the drain
+/// continues with the new parameters, but its start time, and
+/// therefore the end of its grace period, is unchanged.
+pub fn start(request: DrainV1Request) -> anyhow::Result<DrainV1Status> {
+    let generation = {
+        let mut state = STATE.lock();
+        let (started, generation) = match state.as_ref() {
+            Some(existing) => (existing.started, existing.generation + 1),
+            None => (Utc::now(), 0),
+        };
+        tracing::info!(
+            "draining: {} (peer={:?}, shutdown={})",
+            request.reason,
+            request.peer,
+            request.shutdown
+        );
+        state.replace(DrainState {
+            request,
+            started,
+            phase: DrainV1Phase::Delivering,
+            generation,
+        });
+        DRAINING.store(true, Ordering::SeqCst);
+        generation
+    };
+
+    rt_spawn_non_blocking("drain".to_string(), move || {
+        Ok(async move { run(generation).await })
+    })?;
+
+    Ok(status())
+}
+
+/// Stop draining, so that new messages are accepted again.
+/// Returns false if the node was not draining.
+pub fn cancel() -> bool {
+    let mut state = STATE.lock();
+    match state.take() {
+        Some(state) => {
+            tracing::info!("drain cancelled: {}", state.request.reason);
+            DRAINING.store(false, Ordering::SeqCst);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn status() -> DrainV1Status {
+    let (scheduled, ready) = count_messages();
+    let state = STATE.lock();
+    match state.as_ref() {
+        Some(state) => DrainV1Status {
+            draining: true,
+            reason: Some(state.request.reason.clone()),
+            started: Some(state.started),
+            peer: state.request.peer.clone(),
+            phase: Some(state.phase),
+            shutdown: state.request.shutdown,
+            scheduled,
+            ready,
+        },
+        None => DrainV1Status {
+            draining: false,
+            reason: None,
+            started: None,
+            peer: None,
+            phase: None,
+            shutdown: false,
+            scheduled,
+            ready,
+        },
+    }
+}
+
+fn count_messages() -> (usize, usize) {
+    let scheduled = QueueManager::all_queue_names()
+        .iter()
+        .filter_map(|name| QueueManager::get_opt(name))
+        .map(|queue| queue.depth())
+        .sum();
+    let ready = ReadyQueueManager::all_queues()
+        .iter()
+        .map(|queue| queue.ready_count())
+        .sum();
+    (scheduled, ready)
+}
+
+/// Decide what the drain should do next
+fn next_phase(grace_period_elapsed: bool, has_peer: bool, remaining: usize) -> DrainV1Phase {
+    if remaining == 0 {
+        DrainV1Phase::Drained
+    } else if !grace_period_elapsed {
+        DrainV1Phase::Delivering
+    } else if has_peer {
+        DrainV1Phase::HandingOff
+    } else {
+        DrainV1Phase::Drained
+    }
+}
+
+/// Returns the current request and start time if generation
+/// is still the active drain
+fn current(generation: usize) -> Option<(DrainV1Request, DateTime<Utc>)> {
+    STATE
+        .lock()
+        .as_ref()
+        .filter(|state| state.generation == generation)
+        .map(|state| (state.request.clone(), state.started))
+}
+
+fn set_phase(generation: usize, phase: DrainV1Phase) {
+    if let Some(state) = STATE
+        .lock()
+        .as_mut()
+        .filter(|state| state.generation == generation)
+    {
+        if state.phase != phase {
+            tracing::info!("drain: {:?} -> {phase:?}", state.phase);
+            state.phase = phase;
+        }
+    }
+}
+
+fn rebind_entry(reason: String, data: HashMap<String, String>) -> Arc<AdminRebindEntry> {
+    let suppress_logging = data.is_empty();
+    Arc::new(AdminRebindEntry {
+        request: RebindV1Request {
+            campaign: None,
+            tenant: None,
+            domain: None,
+            routing_domain: None,
+            reason,
+            suppress_logging,
+            data,
+            trigger_rebind_event: false,
+            always_flush: true,
+            preserve_due_time: false,
+        },
+    })
+}
+
+async fn run(generation: usize) {
+    loop {
+        let Some((request, started)) = current(generation) else {
+            return;
+        };
+        let grace_period = request
+            .grace_period
+            .unwrap_or_else(DrainV1Request::default_grace_period);
+        let retry_interval = request
+            .retry_interval
+            .unwrap_or_else(DrainV1Request::default_retry_interval);
+        let grace_end = started
+            + chrono::Duration::from_std(grace_period).unwrap_or(kumo_chrono_helper::MINUTE * 5);
+
+        let (scheduled, ready) = count_messages();
+        let grace_period_elapsed = Utc::now() >= grace_end;
+        let phase = next_phase(
+            grace_period_elapsed,
+            request.peer.is_some(),
+            scheduled + ready,
+        );
+        set_phase(generation, phase);
+
+        match phase {
+            DrainV1Phase::Delivering | DrainV1Phase::HandingOff => {
+                let flush = rebind_entry(format!("drain: {}", request.reason), HashMap::new());
+                let handoff = match (&request.peer, phase) {
+                    (Some(peer), DrainV1Phase::HandingOff) => Some((
+                        peer.as_str(),
+                        rebind_entry(
+                            format!("drain hand-off to {peer}: {}", request.reason),
+                            [("routing_domain".to_string(), peer.to_string())]
+                                .into_iter()
+                                .collect(),
+                        ),
+                    )),
+                    _ => None,
+                };
+
+                for name in QueueManager::all_queue_names() {
+                    if current(generation).is_none() {
+                        return;
+                    }
+                    let Some(queue) = QueueManager::get_opt(&name) else {
+                        continue;
+                    };
+                    let entry = match &handoff {
+                        Some((peer, handoff))
+                            if QueueNameComponents::parse(&name).routing_domain != Some(*peer) =>
+                        {
+                            handoff
+                        }
+                        _ => &flush,
+                    };
+                    queue.rebind_all(entry).await;
+                }
+            }
+            DrainV1Phase::Drained | DrainV1Phase::ShuttingDown => {
+                if request.shutdown {
+                    set_phase(generation, DrainV1Phase::ShuttingDown);
+                    tracing::info!(
+                        "drain complete with {scheduled} scheduled and {ready} \
+                         ready messages remaining; shutting down"
+                    );
+                    LifeCycle::request_shutdown().await;
+                } else {
+                    tracing::info!(
+                        "drain complete with {scheduled} scheduled and {ready} \
+                         ready messages remaining"
+                    );
+                }
+                return;
+            }
+        }
+
+        // Wake up at the end of the grace period, if that
+        // is sooner than the next retry
+        let mut delay = retry_interval;
+        if !grace_period_elapsed {
+            if let Ok(until_grace_end) = (grace_end - Utc::now()).to_std() {
+                delay = delay.min(until_grace_end);
+            }
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn phases() {
+        assert_eq!(next_phase(false, true, 10), DrainV1Phase::Delivering);
+        assert_eq!(next_phase(false, false, 10), DrainV1Phase::Delivering);
+        assert_eq!(next_phase(true, true, 10), DrainV1Phase::HandingOff);
+        assert_eq!(next_phase(true, false, 10), DrainV1Phase::Drained);
+        // An empty node is drained regardless of the grace period
+        assert_eq!(next_phase(false, true, 0), DrainV1Phase::Drained);
+        assert_eq!(next_phase(true, true, 0), DrainV1Phase::Drained);
+    }
+}
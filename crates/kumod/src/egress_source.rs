@@ -1,7 +1,9 @@
 use crate::http_server::admin_suspend_ready_q_v1::AdminSuspendReadyQEntry;
 use crate::queue::QueueConfig;
 use crate::ready_queue::{ReadyQueueManager, ReadyQueueName};
+use crate::warmup::{WarmupOverflow, WarmupStatus};
 use anyhow::Context;
+use config::epoch::{get_current_epoch, ConfigEpoch};
 use config::{CallbackSignature, LuaConfig};
 use data_loader::KeySource;
 use gcd::Gcd;
@@ -20,13 +22,17 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpSocket, TcpStream};
+use utoipa::ToSchema;
 
 lazy_static::lazy_static! {
-    static ref SOURCES: Mutex<LruCacheWithTtl<String, EgressSource>> = Mutex::new(LruCacheWithTtl::new(128));
-    static ref POOLS: Mutex<LruCacheWithTtl<String, EgressPool>> = Mutex::new(LruCacheWithTtl::new(128));
+    // These are keyed by the configuration epoch as well as the name,
+    // so that bumping the epoch causes them to be resolved again
+    static ref SOURCES: Mutex<LruCacheWithTtl<(ConfigEpoch, String), EgressSource>> = Mutex::new(LruCacheWithTtl::new(128));
+    static ref POOLS: Mutex<LruCacheWithTtl<(ConfigEpoch, String), EgressPool>> = Mutex::new(LruCacheWithTtl::new(128));
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq, Eq, mlua::FromLua)]
+/// The parameters accepted by `kumo.make_egress_source`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, mlua::FromLua, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct EgressSource {
     /// Give it a friendly name for use in reporting and referencing
@@ -39,6 +45,7 @@ pub struct EgressSource {
     pub ehlo_domain: Option<String>,
 
     /// Bind to this local address prior to issuing a connect(2) syscall
+    #[schema(value_type = Option<String>)]
     pub source_address: Option<IpAddr>,
 
     /// Override the default destination port number with this value
@@ -46,23 +53,29 @@ pub struct EgressSource {
     pub remote_port: Option<u16>,
 
     /// The host:port of the haproxy that should be used
+    #[schema(value_type = Option<String>)]
     pub ha_proxy_server: Option<SocketAddr>,
 
     /// Ask ha_proxy to bind to this address when it is making
     /// a connection
+    #[schema(value_type = Option<String>)]
     pub ha_proxy_source_address: Option<IpAddr>,
 
     /// The host:port of the SOCKS5 server that should be used
+    #[schema(value_type = Option<String>)]
     pub socks5_proxy_server: Option<SocketAddr>,
 
     /// Ask the SOCKS5 proxy to bind to this address when it is making
     /// a connection
+    #[schema(value_type = Option<String>)]
     pub socks5_proxy_source_address: Option<IpAddr>,
 
     pub socks5_proxy_username: Option<String>,
+    #[schema(value_type = Option<Object>)]
     pub socks5_proxy_password: Option<KeySource>,
 
     #[serde(default = "default_ttl", with = "duration_serde")]
+    #[schema(value_type = String)]
     pub ttl: Duration,
 }
 
@@ -70,7 +83,8 @@ impl LuaUserData for EgressSource {}
 
 impl EgressSource {
     pub async fn resolve(name: &str, config: &mut LuaConfig) -> anyhow::Result<Self> {
-        if let Some(source) = SOURCES.lock().get(name) {
+        let cache_key = (get_current_epoch(), name.to_string());
+        if let Some(source) = SOURCES.lock().get(&cache_key) {
             return Ok(source.clone());
         }
 
@@ -96,11 +110,9 @@ impl EgressSource {
                 .with_context(|| format!("get_egress_source '{name}'"))?
         };
 
-        SOURCES.lock().insert(
-            name.to_string(),
-            source.clone(),
-            Instant::now() + source.ttl,
-        );
+        SOURCES
+            .lock()
+            .insert(cache_key, source.clone(), Instant::now() + source.ttl);
 
         Ok(source)
     }
@@ -212,7 +224,7 @@ impl EgressSource {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, mlua::FromLua)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, mlua::FromLua, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct EgressPoolEntry {
     /// Name of an EgressSource to include in this pool
@@ -235,7 +247,8 @@ impl EgressPoolEntry {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, mlua::FromLua)]
+/// The parameters accepted by `kumo.make_egress_pool`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, mlua::FromLua, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct EgressPool {
     /// Name of the pool
@@ -245,6 +258,7 @@ pub struct EgressPool {
     pub entries: Vec<EgressPoolEntry>,
 
     #[serde(default = "default_ttl", with = "duration_serde")]
+    #[schema(value_type = String)]
     pub ttl: Duration,
 }
 
@@ -253,8 +267,9 @@ impl LuaUserData for EgressPool {}
 impl EgressPool {
     pub async fn resolve(name: Option<&str>, config: &mut LuaConfig) -> anyhow::Result<Self> {
         let name = name.unwrap_or("unspecified");
+        let cache_key = (get_current_epoch(), name.to_string());
 
-        if let Some(pool) = POOLS.lock().get(name) {
+        if let Some(pool) = POOLS.lock().get(&cache_key) {
             return Ok(pool.clone());
         }
 
@@ -285,7 +300,7 @@ impl EgressPool {
 
         POOLS
             .lock()
-            .insert(name.to_string(), pool.clone(), Instant::now() + pool.ttl);
+            .insert(cache_key, pool.clone(), Instant::now() + pool.ttl);
 
         Ok(pool)
     }
@@ -325,6 +340,9 @@ pub enum RoundRobinResult {
     /// All pathways are suspended. The smallest time until one
     /// of them is enabled is this delay
     Delay(chrono::Duration),
+    /// The daily warm-up limits of the available sources have been
+    /// reached. The limits reset after this delay
+    WarmupDelay(chrono::Duration),
     /// No sources are configured, or all sources have zero weight
     NoSources,
 }
@@ -456,14 +474,32 @@ impl EgressPoolRoundRobin {
 
         let mut entries = vec![];
         let mut min_delay = None;
+        let provider_name = queue_config.borrow().provider_name.clone();
 
-        // filter to non-suspended pathways
+        // filter to healthy, non-suspended pathways
         for entry in &self.entries {
             match self
                 .compute_ready_queue_name(queue_name, queue_config, &entry.name)
                 .await
             {
                 Ok(ready_name) => {
+                    let destination = destination(&ready_name, provider_name.as_deref());
+                    if crate::source_health::is_unhealthy(&entry.name, &destination) {
+                        if let Some(duration) =
+                            crate::source_health::quarantine_remaining(&entry.name, &destination)
+                        {
+                            min_delay.replace(min_delay.unwrap_or(duration).min(duration));
+                        }
+                        continue;
+                    }
+                    if let Some(duration) = crate::source_exclusion::excluded_remaining(
+                        &entry.name,
+                        &ready_name.name.site_name,
+                        provider_name.as_deref(),
+                    ) {
+                        min_delay.replace(min_delay.unwrap_or(duration).min(duration));
+                        continue;
+                    }
                     match AdminSuspendReadyQEntry::get_for_queue_name(&ready_name.name.name) {
                         Some(suspend) => {
                             let duration = suspend.get_duration_chrono();
@@ -489,7 +525,30 @@ impl EgressPoolRoundRobin {
             }
         }
 
-        match self.next_impl(&entries) {
+        let mut selected = self.next_impl(&entries);
+        if let Some((name, Some(ready_name))) = &selected {
+            // The warm-up allowance is only consumed once the message is
+            // dispatched; here we avoid assigning it to a source that has
+            // already used up its allowance for the day
+            let destination = destination(ready_name, provider_name.as_deref());
+            match crate::warmup::status(name, &destination) {
+                WarmupStatus::Available => {}
+                WarmupStatus::Exhausted(WarmupOverflow::Defer) => {
+                    return RoundRobinResult::WarmupDelay(crate::warmup::time_until_reset());
+                }
+                WarmupStatus::Exhausted(WarmupOverflow::Spill) => {
+                    // The overflow goes to the mature sources in the pool,
+                    // rather than to other sources that are warming up
+                    entries.retain(|(entry, _)| !crate::warmup::is_warming(&entry.name));
+                    selected = self.next_impl(&entries);
+                    if selected.is_none() {
+                        return RoundRobinResult::WarmupDelay(crate::warmup::time_until_reset());
+                    }
+                }
+            }
+        }
+
+        match selected {
             Some((name, ready_queue_name)) => RoundRobinResult::Source {
                 name,
                 ready_queue_name,
@@ -502,6 +561,17 @@ impl EgressPoolRoundRobin {
     }
 }
 
+/// The destination for which the health and warm-up usage of a source
+/// are tracked when it is used via ready_name. This is taken from the
+/// ready queue if it already exists, as that is what its dispatcher uses.
+fn destination(ready_name: &CachedReadyQueueName, provider_name: Option<&str>) -> String {
+    ReadyQueueManager::get_by_ready_queue_name(&ready_name.name)
+        .map(|queue| queue.destination())
+        .unwrap_or_else(|| {
+            crate::source_health::destination(&ready_name.name.site_name, provider_name).to_string()
+        })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
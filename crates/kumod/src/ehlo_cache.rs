@@ -0,0 +1,361 @@
+//! Caches the ESMTP capabilities that were advertised by each
+//! destination host, keyed by the host name and address and then
+//! by port, so that they can be inspected via the admin API without
+//! having to connect to the host.
+//!
+//! Each time a connection is established the observed capabilities
+//! are compared with those that were previously cached, so that
+//! hosts whose capabilities flap between connections, for example
+//! because they are load balanced across differently configured
+//! servers, can be diagnosed.
+//!
+//! The cache also remembers hosts that advertised CHUNKING but then
+//! rejected BDAT, so that subsequent connections to them use DATA
+//! from the outset rather than failing a transaction to find out.
+use chrono::{DateTime, Utc};
+use kumo_api_types::ehlo_capabilities::{EhloCapabilitiesV1Entry, EhloCapabilitiesV1ListRequest};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use rfc5321::EsmtpCapability;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Beyond this many entries, expired entries are discarded
+/// rather than being retained to detect changes, and if that
+/// isn't enough, the least recently observed entries are evicted
+const MAX_ENTRIES: usize = 64 * 1024;
+
+static CACHE: Lazy<Mutex<Cache>> = Lazy::new(|| Mutex::new(Cache::default()));
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct HostKey {
+    host: String,
+    address: IpAddr,
+}
+
+/// The entries for each host, keyed by port. Grouping the ports
+/// by host allows everything we know about a host to be found
+/// with a single lookup
+#[derive(Default)]
+struct Cache {
+    hosts: HashMap<HostKey, HashMap<u16, CacheEntry>>,
+    len: usize,
+}
+
+impl Cache {
+    fn get(&self, key: &HostKey, port: u16) -> Option<&CacheEntry> {
+        self.hosts.get(key).and_then(|ports| ports.get(&port))
+    }
+
+    fn get_mut(&mut self, key: &HostKey, port: u16) -> Option<&mut CacheEntry> {
+        self.hosts
+            .get_mut(key)
+            .and_then(|ports| ports.get_mut(&port))
+    }
+
+    fn insert(&mut self, key: HostKey, port: u16, entry: CacheEntry) {
+        if self
+            .hosts
+            .entry(key)
+            .or_default()
+            .insert(port, entry)
+            .is_none()
+        {
+            self.len += 1;
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&CacheEntry) -> bool) {
+        let mut len = 0;
+        self.hosts.retain(|_, ports| {
+            ports.retain(|_, entry| keep(entry));
+            len += ports.len();
+            !ports.is_empty()
+        });
+        self.len = len;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&HostKey, u16, &CacheEntry)> {
+        self.hosts
+            .iter()
+            .flat_map(|(key, ports)| ports.iter().map(move |(port, entry)| (key, *port, entry)))
+    }
+
+    fn remove(&mut self, key: &HostKey, port: u16) {
+        if let Some(ports) = self.hosts.get_mut(key) {
+            if ports.remove(&port).is_some() {
+                self.len -= 1;
+            }
+            if ports.is_empty() {
+                self.hosts.remove(key);
+            }
+        }
+    }
+
+    /// Remove the count least recently observed entries
+    fn evict_oldest(&mut self, count: usize) {
+        let mut by_age: Vec<_> = self
+            .iter()
+            .map(|(key, port, entry)| (entry.observed, key.clone(), port))
+            .collect();
+        by_age.sort_unstable_by_key(|a| a.0);
+        for (_, key, port) in by_age.into_iter().take(count) {
+            self.remove(&key, port);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    capabilities: BTreeMap<String, Option<String>>,
+    starttls: bool,
+    tls: bool,
+    observed: DateTime<Utc>,
+    expires: DateTime<Utc>,
+    changes: usize,
+    last_changed: Option<DateTime<Utc>>,
+    previous_capabilities: Option<BTreeMap<String, Option<String>>>,
+    /// The host advertised CHUNKING but rejected BDAT
+    bdat_rejected: bool,
+}
+
+impl CacheEntry {
+    /// Update the entry with newly observed capabilities
+    fn update(
+        &mut self,
+        capabilities: BTreeMap<String, Option<String>>,
+        starttls: bool,
+        tls: bool,
+        now: DateTime<Utc>,
+        expires: DateTime<Utc>,
+    ) -> bool {
+        let changed =
+            self.capabilities != capabilities || self.starttls != starttls || self.tls != tls;
+        if changed {
+            self.changes += 1;
+            self.last_changed.replace(now);
+            self.previous_capabilities
+                .replace(std::mem::replace(&mut self.capabilities, capabilities));
+            self.starttls = starttls;
+            self.tls = tls;
+            // The host may have been reconfigured, so give BDAT
+            // another chance
+            self.bdat_rejected = false;
+        }
+        self.observed = now;
+        self.expires = expires;
+        changed
+    }
+}
+
+fn capability_map(
+    capabilities: &HashMap<String, EsmtpCapability>,
+) -> BTreeMap<String, Option<String>> {
+    capabilities
+        .iter()
+        .map(|(name, cap)| (name.clone(), cap.param.clone()))
+        .collect()
+}
+
+/// Record the capabilities that were advertised by host via the
+/// connection that has just been established. `starttls` indicates
+/// whether STARTTLS was advertised in response to the initial EHLO,
+/// and `tls` whether TLS was subsequently enabled.
+pub fn record(
+    host: &str,
+    address: IpAddr,
+    port: u16,
+    starttls: bool,
+    tls: bool,
+    capabilities: &HashMap<String, EsmtpCapability>,
+    ttl: Duration,
+) {
+    let now = Utc::now();
+    let expires = now + chrono::Duration::from_std(ttl).unwrap_or(kumo_chrono_helper::HOUR);
+    let capabilities = capability_map(capabilities);
+    let key = HostKey {
+        host: host.to_string(),
+        address,
+    };
+
+    let mut cache = CACHE.lock();
+    match cache.get_mut(&key, port) {
+        Some(entry) => {
+            let was_current = entry.expires > now;
+            if entry.update(capabilities, starttls, tls, now, expires) && was_current {
+                tracing::debug!(
+                    "ESMTP capabilities of {host} ({address}) port {port} changed \
+                     from {:?} to {:?}",
+                    entry.previous_capabilities,
+                    entry.capabilities
+                );
+            }
+        }
+        None => {
+            if cache.len >= MAX_ENTRIES {
+                cache.retain(|entry| entry.expires > now);
+            }
+            if cache.len >= MAX_ENTRIES {
+                let excess = cache.len + 1 - MAX_ENTRIES;
+                cache.evict_oldest(excess);
+            }
+            cache.insert(
+                key,
+                port,
+                CacheEntry {
+                    capabilities,
+                    starttls,
+                    tls,
+                    observed: now,
+                    expires,
+                    changes: 0,
+                    last_changed: None,
+                    previous_capabilities: None,
+                    bdat_rejected: false,
+                },
+            );
+        }
+    }
+}
+
+/// Returns true if the host is known to have rejected BDAT within
+/// the configured ttl, in which case CHUNKING should not be used
+/// for new connections to it
+pub fn bdat_rejected(host: &str, address: IpAddr, port: u16) -> bool {
+    let key = HostKey {
+        host: host.to_string(),
+        address,
+    };
+    CACHE
+        .lock()
+        .get(&key, port)
+        .map(|entry| entry.expires > Utc::now() && entry.bdat_rejected)
+        .unwrap_or(false)
+}
+
+/// Record that the host rejected BDAT, despite advertising CHUNKING.
+/// This applies to every port of the host that we have connected to.
+pub fn record_bdat_rejected(host: &str, address: IpAddr) {
+    let key = HostKey {
+        host: host.to_string(),
+        address,
+    };
+    if let Some(ports) = CACHE.lock().hosts.get_mut(&key) {
+        for entry in ports.values_mut() {
+            entry.bdat_rejected = true;
+        }
+    }
+}
+
+pub fn list(request: EhloCapabilitiesV1ListRequest) -> Vec<EhloCapabilitiesV1Entry> {
+    let now = Utc::now();
+    let cache = CACHE.lock();
+    let mut entries: Vec<_> = cache
+        .iter()
+        .filter(|(key, _port, entry)| {
+            entry.expires > now
+                && request
+                    .host
+                    .as_ref()
+                    .map(|host| key.host.eq_ignore_ascii_case(host))
+                    .unwrap_or(true)
+        })
+        .map(|(key, port, entry)| EhloCapabilitiesV1Entry {
+            host: key.host.clone(),
+            address: key.address.to_string(),
+            port,
+            capabilities: entry.capabilities.clone(),
+            starttls: entry.starttls,
+            tls: entry.tls,
+            observed: entry.observed,
+            expires: entry.expires,
+            changes: entry.changes,
+            last_changed: entry.last_changed,
+            previous_capabilities: entry.previous_capabilities.clone(),
+        })
+        .collect();
+    entries.sort_by(|a, b| (&a.host, &a.address, a.port).cmp(&(&b.host, &b.address, b.port)));
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn caps(names: &[(&str, Option<&str>)]) -> BTreeMap<String, Option<String>> {
+        names
+            .iter()
+            .map(|(name, param)| (name.to_string(), param.map(|p| p.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn changes() {
+        let now = Utc::now();
+        let later = now + kumo_chrono_helper::MINUTE;
+        let initial = caps(&[("PIPELINING", None), ("CHUNKING", None)]);
+        let mut entry = CacheEntry {
+            capabilities: initial.clone(),
+            starttls: true,
+            tls: true,
+            observed: now,
+            expires: later,
+            changes: 0,
+            last_changed: None,
+            previous_capabilities: None,
+            bdat_rejected: true,
+        };
+
+        assert!(!entry.update(initial.clone(), true, true, later, later));
+        assert!(entry.bdat_rejected);
+        assert_eq!(entry.changes, 0);
+        assert_eq!(entry.observed, later);
+
+        let without_chunking = caps(&[("PIPELINING", None)]);
+        assert!(entry.update(without_chunking.clone(), true, true, later, later));
+        assert_eq!(entry.changes, 1);
+        assert_eq!(entry.last_changed, Some(later));
+        assert_eq!(entry.previous_capabilities, Some(initial));
+        assert_eq!(entry.capabilities, without_chunking);
+        assert!(!entry.bdat_rejected);
+
+        // A failed TLS handshake is also a change
+        assert!(entry.update(without_chunking, true, false, later, later));
+        assert_eq!(entry.changes, 2);
+    }
+
+    #[test]
+    fn eviction() {
+        let now = Utc::now();
+        let mut cache = Cache::default();
+        for i in 0..4u8 {
+            let observed = now + chrono::Duration::seconds(i.into());
+            cache.insert(
+                HostKey {
+                    host: format!("mx{i}"),
+                    address: IpAddr::from([10, 0, 0, i]),
+                },
+                25,
+                CacheEntry {
+                    capabilities: BTreeMap::new(),
+                    starttls: false,
+                    tls: false,
+                    observed,
+                    expires: observed + kumo_chrono_helper::HOUR,
+                    changes: 0,
+                    last_changed: None,
+                    previous_capabilities: None,
+                    bdat_rejected: false,
+                },
+            );
+        }
+
+        // Every entry is current, so the oldest are evicted
+        cache.evict_oldest(2);
+        assert_eq!(cache.len, 2);
+        let mut hosts: Vec<_> = cache.iter().map(|(key, _, _)| key.host.as_str()).collect();
+        hosts.sort();
+        assert_eq!(hosts, vec!["mx2", "mx3"]);
+    }
+}
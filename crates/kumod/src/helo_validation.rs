@@ -0,0 +1,373 @@
+//! Validation of the domain that the client presents in HELO/EHLO.
+//!
+//! The checks are cumulative: each level includes the checks of the
+//! levels below it, and evaluation stops at the first check that
+//! fails. The outcome is recorded in the `helo_validation` connection
+//! meta value, so that policy can take it into account even when the
+//! listener is not configured to reject invalid domains.
+//!
+//! A DNS lookup that fails, rather than finding that the records
+//! don't exist, doesn't tell us anything about the domain, so the
+//! outcome is marked as transient and rejected with a 4xx response.
+use rfc5321::Domain;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+#[derive(
+    Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ToSchema,
+)]
+pub enum HeloValidationLevel {
+    /// No validation is performed
+    #[default]
+    None,
+    /// The domain must be a syntactically valid host name, or
+    /// a valid IPv4 or IPv6 address literal
+    Syntax,
+    /// The domain must be fully qualified; an address literal
+    /// must match the address of the client
+    Fqdn,
+    /// The domain must resolve to at least one address
+    Resolvable,
+    /// The domain must be one of the PTR names of the client address
+    MatchesPtr,
+}
+
+/// The outcome of validating a HELO/EHLO domain
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HeloValidation {
+    /// The level that was requested
+    pub level: HeloValidationLevel,
+    /// Whether the domain satisfied the requested level
+    pub valid: bool,
+    /// Why the domain failed validation
+    pub reason: Option<String>,
+    /// Whether the failure is due to a DNS error rather
+    /// than a problem with the domain
+    pub transient: bool,
+    /// The result of each check; checks that were not
+    /// evaluated are None
+    pub syntax: Option<bool>,
+    pub fqdn: Option<bool>,
+    pub resolvable: Option<bool>,
+    pub matches_ptr: Option<bool>,
+}
+
+fn check_syntax(domain: &Domain) -> Result<(), String> {
+    match domain {
+        Domain::Name(name) => {
+            let name = name.strip_suffix('.').unwrap_or(name);
+            if name.len() > 253 {
+                return Err(format!("HELO domain {name} is too long"));
+            }
+            for label in name.split('.') {
+                if label.is_empty() || label.len() > 63 {
+                    return Err(format!("HELO domain {name} has an invalid label length"));
+                }
+                if label.starts_with('-') || label.ends_with('-') {
+                    return Err(format!(
+                        "HELO domain {name} has a label that begins or ends with a hyphen"
+                    ));
+                }
+            }
+            Ok(())
+        }
+        Domain::V4(addr) => addr
+            .parse::<std::net::Ipv4Addr>()
+            .map(|_| ())
+            .map_err(|_| format!("HELO address literal [{addr}] is invalid")),
+        Domain::V6(addr) => addr
+            .parse::<std::net::Ipv6Addr>()
+            .map(|_| ())
+            .map_err(|_| format!("HELO address literal [IPv6:{addr}] is invalid")),
+        // A general address literal is syntactically valid,
+        // but it can never satisfy the stricter levels
+        Domain::Tagged { .. } => Ok(()),
+    }
+}
+
+fn literal_address(domain: &Domain) -> Option<IpAddr> {
+    match domain {
+        Domain::V4(addr) => addr.parse().ok(),
+        Domain::V6(addr) => addr.parse().ok(),
+        _ => None,
+    }
+}
+
+fn check_fqdn(domain: &Domain, peer: IpAddr) -> Result<(), String> {
+    match domain {
+        Domain::Name(name) => {
+            let name = name.strip_suffix('.').unwrap_or(name);
+            match name.rsplit_once('.') {
+                Some((_, tld)) if !tld.chars().all(|c| c.is_ascii_digit()) => Ok(()),
+                _ => Err(format!("HELO domain {name} is not fully qualified")),
+            }
+        }
+        Domain::Tagged { .. } => Err(format!(
+            "HELO address literal {} is not supported",
+            domain.to_string()
+        )),
+        _ => {
+            if literal_address(domain) == Some(peer) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "HELO address literal {} does not match {peer}",
+                    domain.to_string()
+                ))
+            }
+        }
+    }
+}
+
+/// Why a check did not pass
+#[derive(Debug, PartialEq)]
+enum CheckFailure {
+    /// The domain does not satisfy the check
+    Invalid(String),
+    /// The check could not be completed
+    Transient(String),
+}
+
+impl From<String> for CheckFailure {
+    fn from(reason: String) -> Self {
+        Self::Invalid(reason)
+    }
+}
+
+fn resolvable_outcome(
+    name: &str,
+    lookup: anyhow::Result<Arc<Vec<IpAddr>>>,
+) -> Result<(), CheckFailure> {
+    match lookup {
+        Ok(addrs) if !addrs.is_empty() => Ok(()),
+        Ok(_) => Err(CheckFailure::Invalid(format!(
+            "HELO domain {name} does not resolve"
+        ))),
+        Err(err) => Err(CheckFailure::Transient(format!(
+            "unable to resolve HELO domain {name}: {err:#}"
+        ))),
+    }
+}
+
+fn matches_ptr_outcome(
+    name: &str,
+    peer: IpAddr,
+    lookup: anyhow::Result<Arc<Vec<String>>>,
+) -> Result<(), CheckFailure> {
+    match lookup {
+        Ok(names) if names.iter().any(|n| n == name) => Ok(()),
+        Ok(_) => Err(CheckFailure::Invalid(format!(
+            "HELO domain {name} does not match the PTR for {peer}"
+        ))),
+        Err(err) => Err(CheckFailure::Transient(format!(
+            "unable to resolve the PTR for {peer}: {err:#}"
+        ))),
+    }
+}
+
+async fn check_resolvable(domain: &Domain) -> Result<(), CheckFailure> {
+    let Domain::Name(name) = domain else {
+        // An address literal was matched against the
+        // peer address by check_fqdn
+        return Ok(());
+    };
+    let lookup = dns_resolver::ip_lookup(name).await.map(|(addrs, _)| addrs);
+    resolvable_outcome(name, lookup)
+}
+
+async fn check_matches_ptr(domain: &Domain, peer: IpAddr) -> Result<(), CheckFailure> {
+    let Domain::Name(name) = domain else {
+        return Ok(());
+    };
+    let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+    matches_ptr_outcome(&name, peer, dns_resolver::ptr_lookup(peer).await)
+}
+
+impl HeloValidation {
+    pub async fn evaluate(level: HeloValidationLevel, domain: &Domain, peer: IpAddr) -> Self {
+        let mut result = Self {
+            level,
+            valid: true,
+            reason: None,
+            transient: false,
+            syntax: None,
+            fqdn: None,
+            resolvable: None,
+            matches_ptr: None,
+        };
+
+        if level >= HeloValidationLevel::Syntax
+            && !result.record(check_syntax(domain).map_err(Into::into), |r| &mut r.syntax)
+        {
+            return result;
+        }
+        if level >= HeloValidationLevel::Fqdn
+            && !result.record(check_fqdn(domain, peer).map_err(Into::into), |r| {
+                &mut r.fqdn
+            })
+        {
+            return result;
+        }
+        if level >= HeloValidationLevel::Resolvable
+            && !result.record(check_resolvable(domain).await, |r| &mut r.resolvable)
+        {
+            return result;
+        }
+        if level >= HeloValidationLevel::MatchesPtr {
+            result.record(check_matches_ptr(domain, peer).await, |r| {
+                &mut r.matches_ptr
+            });
+        }
+
+        result
+    }
+
+    /// Records the outcome of a check, returning true if it passed
+    fn record<F: FnOnce(&mut Self) -> &mut Option<bool>>(
+        &mut self,
+        outcome: Result<(), CheckFailure>,
+        field: F,
+    ) -> bool {
+        let passed = outcome.is_ok();
+        field(self).replace(passed);
+        if let Err(failure) = outcome {
+            self.valid = false;
+            let reason = match failure {
+                CheckFailure::Invalid(reason) => reason,
+                CheckFailure::Transient(reason) => {
+                    self.transient = true;
+                    reason
+                }
+            };
+            self.reason.replace(reason);
+        }
+        passed
+    }
+
+    /// The response with which to reject the HELO/EHLO command
+    pub fn rejection(&self) -> (u16, String) {
+        let reason = self.reason.as_deref().unwrap_or("HELO domain is invalid");
+        if self.syntax == Some(false) {
+            (501, format!("5.5.4 {reason}"))
+        } else if self.transient {
+            (451, format!("4.4.3 {reason}"))
+        } else {
+            (550, format!("5.7.1 {reason}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn name(s: &str) -> Domain {
+        Domain::Name(s.to_string())
+    }
+
+    #[test]
+    fn syntax() {
+        assert!(check_syntax(&name("mail.example.com")).is_ok());
+        assert!(check_syntax(&name("mail.example.com.")).is_ok());
+        assert!(check_syntax(&name("mail-.example.com")).is_err());
+        assert!(check_syntax(&name(&format!("{}.com", "a".repeat(64)))).is_err());
+        assert!(check_syntax(&Domain::V4("10.0.0.1".to_string())).is_ok());
+        assert!(check_syntax(&Domain::V4("10.0.0.300".to_string())).is_err());
+        assert!(check_syntax(&Domain::V6("::1".to_string())).is_ok());
+        assert!(check_syntax(&Domain::V6("::1::2".to_string())).is_err());
+    }
+
+    #[test]
+    fn fqdn() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(check_fqdn(&name("mail.example.com"), peer).is_ok());
+        assert!(check_fqdn(&name("localhost"), peer).is_err());
+        assert!(check_fqdn(&name("10.0.0.1"), peer).is_err());
+        assert!(check_fqdn(&Domain::V4("10.0.0.1".to_string()), peer).is_ok());
+        assert!(check_fqdn(&Domain::V4("10.0.0.2".to_string()), peer).is_err());
+    }
+
+    #[tokio::test]
+    async fn evaluate_stops_at_first_failure() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let result =
+            HeloValidation::evaluate(HeloValidationLevel::MatchesPtr, &name("localhost"), peer)
+                .await;
+        assert_eq!(
+            result,
+            HeloValidation {
+                level: HeloValidationLevel::MatchesPtr,
+                valid: false,
+                reason: Some("HELO domain localhost is not fully qualified".to_string()),
+                transient: false,
+                syntax: Some(true),
+                fqdn: Some(false),
+                resolvable: None,
+                matches_ptr: None,
+            }
+        );
+        assert_eq!(result.rejection().0, 550);
+
+        let result =
+            HeloValidation::evaluate(HeloValidationLevel::Fqdn, &name("mx.example.com"), peer)
+                .await;
+        assert!(result.valid);
+        assert_eq!(result.resolvable, None);
+    }
+
+    #[test]
+    fn dns_errors_are_transient() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        fn servfail<T>() -> anyhow::Result<T> {
+            Err(anyhow::anyhow!(
+                "lookup for mx.example.com failed: SERVFAIL"
+            ))
+        }
+
+        assert_eq!(
+            resolvable_outcome("mx.example.com", Ok(Arc::new(vec![peer]))),
+            Ok(())
+        );
+        assert!(matches!(
+            resolvable_outcome("mx.example.com", Ok(Arc::new(vec![]))),
+            Err(CheckFailure::Invalid(_))
+        ));
+        assert!(matches!(
+            resolvable_outcome("mx.example.com", servfail()),
+            Err(CheckFailure::Transient(_))
+        ));
+        assert!(matches!(
+            matches_ptr_outcome(
+                "mx.example.com",
+                peer,
+                Ok(Arc::new(vec!["other.example.com".to_string()]))
+            ),
+            Err(CheckFailure::Invalid(_))
+        ));
+        assert!(matches!(
+            matches_ptr_outcome("mx.example.com", peer, servfail()),
+            Err(CheckFailure::Transient(_))
+        ));
+
+        let mut result = HeloValidation {
+            level: HeloValidationLevel::Resolvable,
+            valid: true,
+            reason: None,
+            transient: false,
+            syntax: Some(true),
+            fqdn: Some(true),
+            resolvable: None,
+            matches_ptr: None,
+        };
+        assert!(
+            !result.record(resolvable_outcome("mx.example.com", servfail()), |r| {
+                &mut r.resolvable
+            })
+        );
+        assert!(!result.valid);
+        assert!(result.transient);
+        assert_eq!(result.rejection().0, 451);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::delivery_metrics::MetricsWrappedConnection;
+use crate::ready_queue::{BatchAdmission, Dispatcher, QueueDispatcher};
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use kumo_log_types::ResolvedAddress;
+use kumo_server_runtime::spawn_local;
+use message::message::QueueNameComponents;
+use message::Message;
+use once_cell::sync::Lazy;
+use reqwest::{Client, StatusCode};
+use rfc5321::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+static CLIENT: Lazy<Client> = Lazy::new(Client::new);
+
+/// Limit how much of the response body we retain for logging
+const MAX_RESPONSE_CONTENT: usize = 1024;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HttpApiDeliveryProtocol {
+    /// The URL to which each message will be POSTed
+    pub url: String,
+
+    /// A template that will be expanded to produce the request body
+    /// for each message
+    pub body_template: String,
+
+    /// Additional headers to include in the request, such as
+    /// `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    /// The Content-Type of the expanded body_template
+    #[serde(default = "HttpApiDeliveryProtocol::default_content_type")]
+    pub content_type: String,
+
+    /// How long to wait for the request to complete
+    #[serde(
+        default = "HttpApiDeliveryProtocol::default_timeout",
+        with = "duration_serde"
+    )]
+    pub timeout: Duration,
+
+    /// The maximum number of messages to send in a single request.
+    /// When greater than 1, the expanded bodies of the messages are
+    /// combined according to batch_format, even if only a single
+    /// message is ready.
+    #[serde(default = "HttpApiDeliveryProtocol::default_batch_size")]
+    pub batch_size: usize,
+
+    #[serde(default)]
+    pub batch_format: HttpApiBatchFormat,
+
+    /// How to compress the request body, if at all
+    #[serde(default)]
+    pub compression: Option<HttpApiCompression>,
+}
+
+impl HttpApiDeliveryProtocol {
+    fn default_content_type() -> String {
+        "application/json".to_string()
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_batch_size() -> usize {
+        1
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpApiBatchFormat {
+    /// The bodies are combined into a JSON array
+    #[default]
+    JsonArray,
+    /// Each body is followed by a newline
+    NewlineDelimited,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpApiCompression {
+    Gzip,
+    Zstd,
+}
+
+impl HttpApiCompression {
+    fn content_encoding(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(body)?;
+                Ok(encoder.finish()?)
+            }
+            Self::Zstd => Ok(zstd::encode_all(body, 0)?),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpApiQueueDispatcher {
+    proto_config: HttpApiDeliveryProtocol,
+    connection: Option<MetricsWrappedConnection<()>>,
+    peer_address: ResolvedAddress,
+}
+
+impl HttpApiQueueDispatcher {
+    pub fn new(proto_config: HttpApiDeliveryProtocol) -> Self {
+        let peer_address = ResolvedAddress {
+            name: format!("HTTP API via {}", proto_config.url),
+            addr: Ipv4Addr::UNSPECIFIED.into(),
+        };
+
+        Self {
+            proto_config,
+            connection: None,
+            peer_address,
+        }
+    }
+
+    /// Expand the body template for msg
+    fn build_body(&self, msg: &Message) -> anyhow::Result<String> {
+        let queue_name = msg.get_queue_name()?;
+        let components = QueueNameComponents::parse(&queue_name);
+        let data = msg.get_data();
+
+        let context = json!({
+            "id": msg.id().to_string(),
+            "sender": msg.sender()?.to_string(),
+            "recipient": msg.recipient()?.to_string(),
+            "domain": components.domain,
+            "tenant": components.tenant,
+            "campaign": components.campaign,
+            "meta": msg.get_meta_obj()?,
+            "data": String::from_utf8_lossy(&data),
+        });
+
+        mod_template::render(&self.proto_config.body_template, &context)
+    }
+
+    /// Combine the expanded bodies of a batch into a single request body
+    fn combine_bodies(&self, bodies: Vec<String>) -> String {
+        if self.proto_config.batch_size <= 1 {
+            return bodies.concat();
+        }
+        match self.proto_config.batch_format {
+            HttpApiBatchFormat::JsonArray => format!("[{}]", bodies.join(",")),
+            HttpApiBatchFormat::NewlineDelimited => {
+                let mut combined = String::new();
+                for body in bodies {
+                    combined.push_str(body.trim_end_matches(['\r', '\n']));
+                    combined.push('\n');
+                }
+                combined
+            }
+        }
+    }
+
+    /// Perform the request and map the outcome to an SMTP style response.
+    /// 2xx is a successful delivery, while 408, 429 and 5xx are considered
+    /// to be transient failures. Any other status is a permanent failure.
+    async fn try_send(&self, bodies: Vec<String>) -> anyhow::Result<Response> {
+        let mut body = self.combine_bodies(bodies).into_bytes();
+
+        let mut request = CLIENT
+            .post(&self.proto_config.url)
+            .timeout(self.proto_config.timeout)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                &self.proto_config.content_type,
+            );
+        if let Some(compression) = &self.proto_config.compression {
+            body = compression.compress(&body)?;
+            request = request.header(
+                reqwest::header::CONTENT_ENCODING,
+                compression.content_encoding(),
+            );
+        }
+        request = request.body(body);
+        for (name, value) in &self.proto_config.headers {
+            request = request.header(name, value);
+        }
+
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(err) => {
+                return Ok(Response {
+                    code: 421,
+                    enhanced_code: None,
+                    content: format!("KumoMTA internal: HTTP request failed: {err:#}"),
+                    command: None,
+                })
+            }
+        };
+
+        let status = response.status();
+        let mut content = response.text().await.unwrap_or_default();
+        if content.len() > MAX_RESPONSE_CONTENT {
+            let mut end = MAX_RESPONSE_CONTENT;
+            while !content.is_char_boundary(end) {
+                end -= 1;
+            }
+            content.truncate(end);
+        }
+
+        let code = if status.is_success() {
+            250
+        } else if status == StatusCode::REQUEST_TIMEOUT
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status.is_server_error()
+        {
+            451
+        } else {
+            554
+        };
+
+        Ok(Response {
+            code,
+            enhanced_code: None,
+            content: format!("HTTP {status}: {}", content.trim()),
+            command: None,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueDispatcher for HttpApiQueueDispatcher {
+    async fn close_connection(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
+        match self.connection.take() {
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    async fn attempt_connection(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<()> {
+        if self.connection.is_none() {
+            self.connection
+                .replace(dispatcher.metrics.wrap_connection(()));
+        }
+        Ok(())
+    }
+
+    async fn have_more_connection_candidates(&mut self, _dispatcher: &mut Dispatcher) -> bool {
+        false
+    }
+
+    async fn deliver_message(
+        &mut self,
+        msg: Message,
+        dispatcher: &mut Dispatcher,
+    ) -> anyhow::Result<()> {
+        msg.load_meta_if_needed().await?;
+        msg.load_data_if_needed().await?;
+
+        // msg is a clone of the message held by the dispatcher; from this
+        // point on we are responsible for the disposition of each message
+        // in the batch, including that one
+        dispatcher.msg.take();
+        let mut batch = vec![msg];
+        // The traffic shaping leases of the other members of the batch,
+        // which must be held until the request has completed
+        let mut _shaping_leases = vec![];
+        while batch.len() < self.proto_config.batch_size {
+            let Some(msg) = dispatcher.ready.pop() else {
+                break;
+            };
+            let loaded: anyhow::Result<()> = async {
+                msg.load_meta_if_needed().await?;
+                msg.load_data_if_needed().await
+            }
+            .await;
+            if let Err(err) = loaded {
+                tracing::error!(
+                    "failed to load {} for {}: {err:#}",
+                    msg.id(),
+                    dispatcher.name
+                );
+                spawn_local(
+                    "requeue message".to_string(),
+                    Dispatcher::requeue_message(msg, false, None),
+                )?;
+                continue;
+            }
+            match dispatcher.gate_batch_message(msg).await {
+                Ok(BatchAdmission::Admit(msg, leases)) => {
+                    batch.push(msg);
+                    _shaping_leases.push(leases);
+                }
+                Ok(BatchAdmission::Skip) => {}
+                Ok(BatchAdmission::Stop) => break,
+                Err(err) => {
+                    tracing::error!("failed to extend batch for {}: {err:#}", dispatcher.name);
+                    break;
+                }
+            }
+        }
+        dispatcher.delivered_this_connection += batch.len() - 1;
+
+        // Messages whose body could not be built are failed permanently,
+        // while the rest share the response to the request
+        let mut dispositions = vec![];
+        let mut messages = Vec::with_capacity(batch.len());
+        let mut bodies = Vec::with_capacity(batch.len());
+        for msg in batch {
+            match self.build_body(&msg) {
+                Ok(body) => {
+                    messages.push(msg);
+                    bodies.push(body);
+                }
+                Err(err) => {
+                    let response = Response {
+                        code: 554,
+                        enhanced_code: None,
+                        content: format!("KumoMTA internal: failed to build request body: {err:#}"),
+                        command: None,
+                    };
+                    dispositions.push((msg, response));
+                }
+            }
+        }
+
+        if !messages.is_empty() {
+            let response = match self.try_send(bodies).await {
+                Ok(response) => response,
+                Err(err) => Response {
+                    code: 421,
+                    enhanced_code: None,
+                    content: format!("KumoMTA internal: failed to build request: {err:#}"),
+                    command: None,
+                },
+            };
+            tracing::debug!(
+                "HTTP API response for {} ({} messages): {response:?}",
+                dispatcher.name,
+                messages.len()
+            );
+            dispositions.extend(messages.into_iter().map(|msg| (msg, response.clone())));
+        }
+
+        dispatcher
+            .record_delivery_responses(dispositions, &self.peer_address, "HttpApi")
+            .await
+    }
+}
@@ -3,12 +3,14 @@ use crate::queue::QueueManager;
 use axum::extract::Json;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
+use config::{any_err, get_or_create_sub_module};
 use kumo_api_types::{BounceV1CancelRequest, BounceV1ListEntry, BounceV1Request, BounceV1Response};
-use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::auth::{AuthKind, TrustedIpRequired};
 use kumo_server_common::http_server::AppError;
 use kumo_server_runtime::rt_spawn_non_blocking;
 use message::message::QueueNameComponents;
 use message::Message;
+use mlua::{Lua, LuaSerdeExt, Value};
 use parking_lot::FairMutex as Mutex;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -26,6 +28,9 @@ pub struct AdminBounceEntry {
     pub tenant: Option<String>,
     pub domain: Option<String>,
     pub routing_domain: Option<String>,
+    /// Tenants that are not matched even though the other criteria
+    /// match; used by campaigns that were registered without a tenant
+    pub excluded_tenants: Vec<String>,
     pub reason: String,
     pub suppress_logging: bool,
     pub expires: Instant,
@@ -56,6 +61,31 @@ impl AdminBounceEntry {
         entries.clone()
     }
 
+    pub fn get_all_v1() -> Vec<BounceV1ListEntry> {
+        let now = Instant::now();
+        Self::get_all()
+            .into_iter()
+            .filter_map(|entry| {
+                let bounced = entry.bounced.lock().clone();
+                let total_bounced = bounced.values().sum();
+                entry
+                    .expires
+                    .checked_duration_since(now)
+                    .map(|duration| BounceV1ListEntry {
+                        id: entry.id,
+                        campaign: entry.campaign,
+                        tenant: entry.tenant,
+                        domain: entry.domain,
+                        routing_domain: entry.routing_domain,
+                        reason: entry.reason,
+                        bounced,
+                        total_bounced,
+                        duration,
+                    })
+            })
+            .collect()
+    }
+
     pub fn remove_by_id(id: &Uuid) -> bool {
         let mut entries = ENTRIES.lock();
         let len_before = entries.len();
@@ -74,7 +104,8 @@ impl AdminBounceEntry {
                 && !(ent.campaign == entry.campaign
                     && ent.tenant == entry.tenant
                     && ent.domain == entry.domain
-                    && ent.routing_domain == entry.routing_domain)
+                    && ent.routing_domain == entry.routing_domain
+                    && ent.excluded_tenants == entry.excluded_tenants)
         });
 
         entries.push(entry);
@@ -93,6 +124,11 @@ impl AdminBounceEntry {
         if !match_criteria(tenant, self.tenant.as_deref()) {
             return false;
         }
+        if let Some(tenant) = tenant {
+            if self.excluded_tenants.iter().any(|t| t == tenant) {
+                return false;
+            }
+        }
         if !match_criteria(domain, self.domain.as_deref()) {
             return false;
         }
@@ -196,18 +232,48 @@ impl AdminBounceEntry {
 )]
 pub async fn bounce_v1(
     _: TrustedIpRequired,
+    auth: AuthKind,
     // Note: Json<> must be last in the param list
     Json(request): Json<BounceV1Request>,
 ) -> Result<Json<BounceV1Response>, AppError> {
+    let response = start_bounce(request, vec![], &auth.summarize()).await?;
+    Ok(Json(response))
+}
+
+/// Register the bounce described by `request` and start bouncing
+/// the messages in the matching queues in the background.
+/// Queues that belong to `excluded_tenants` are left alone.
+/// `requested_by` identifies the originator of the request in the
+/// audit log.
+/// The response holds the number of messages that were in each of
+/// the matching queues at the time of the request.
+/// This is shared between the HTTP and lua entrypoints.
+pub(crate) async fn start_bounce(
+    request: BounceV1Request,
+    excluded_tenants: Vec<String>,
+    requested_by: &str,
+) -> anyhow::Result<BounceV1Response> {
     let duration = request.duration();
 
     let id = Uuid::new_v4();
+    tracing::info!(
+        "admin bounce {id} requested by {requested_by}: campaign={:?} tenant={:?} \
+         domain={:?} routing_domain={:?} excluded_tenants={excluded_tenants:?} \
+         duration={duration:?} reason={:?}",
+        request.campaign,
+        request.tenant,
+        request.domain,
+        request.routing_domain,
+        request.reason,
+    );
+
     let entry = AdminBounceEntry {
         id,
         campaign: request.campaign,
         tenant: request.tenant,
         domain: request.domain,
         routing_domain: request.routing_domain,
+        excluded_tenants,
         reason: request.reason,
         suppress_logging: request.suppress_logging,
         expires: Instant::now() + duration,
@@ -217,6 +283,11 @@ pub async fn bounce_v1(
     AdminBounceEntry::add(entry.clone());
 
     let queue_names = entry.list_matching_queues().await;
+    let bounced: HashMap<String, usize> = queue_names
+        .iter()
+        .filter_map(|name| Some((name.clone(), QueueManager::get_opt(name)?.depth())))
+        .collect();
+    let total_bounced = bounced.values().sum();
 
     // Move into a lua-capable thread so that logging related
     // lua events can be triggered by log_disposition.
@@ -227,14 +298,20 @@ pub async fn bounce_v1(
                     q.bounce_all(&entry).await;
                 }
             }
+            let bounced = entry.bounced.lock().clone();
+            tracing::info!(
+                "admin bounce {} bounced {} messages in its initial sweep: {bounced:?}",
+                entry.id,
+                bounced.values().sum::<usize>()
+            );
         })
     })?;
 
-    Ok(Json(BounceV1Response {
+    Ok(BounceV1Response {
         id,
-        bounced: Default::default(),
-        total_bounced: 0,
-    }))
+        bounced,
+        total_bounced,
+    })
 }
 
 /// Allows the system operator to list all currently active administrative bounces that have been
@@ -250,30 +327,7 @@ pub async fn bounce_v1(
 pub async fn bounce_v1_list(
     _: TrustedIpRequired,
 ) -> Result<Json<Vec<BounceV1ListEntry>>, AppError> {
-    let now = Instant::now();
-    Ok(Json(
-        AdminBounceEntry::get_all()
-            .into_iter()
-            .filter_map(|entry| {
-                let bounced = entry.bounced.lock().clone();
-                let total_bounced = bounced.values().sum();
-                entry
-                    .expires
-                    .checked_duration_since(now)
-                    .map(|duration| BounceV1ListEntry {
-                        id: entry.id,
-                        campaign: entry.campaign,
-                        tenant: entry.tenant,
-                        domain: entry.domain,
-                        routing_domain: entry.routing_domain,
-                        reason: entry.reason,
-                        bounced,
-                        total_bounced,
-                        duration,
-                    })
-            })
-            .collect(),
-    ))
+    Ok(Json(AdminBounceEntry::get_all_v1()))
 }
 
 /// Allows the system operator to delete an administrative bounce entry by its id.
@@ -301,3 +355,37 @@ pub async fn bounce_v1_delete(
     }
     .into_response()
 }
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "api.admin.bounce")?;
+
+    module.set(
+        "list",
+        lua.create_function(move |lua, ()| {
+            let result = AdminBounceEntry::get_all_v1();
+            lua.to_value(&result)
+        })?,
+    )?;
+
+    module.set(
+        "bounce",
+        lua.create_async_function(|lua, request: Value| async move {
+            let request: BounceV1Request = lua.from_value(request)?;
+            let response = start_bounce(request, vec![], "lua policy")
+                .await
+                .map_err(any_err)?;
+            lua.to_value(&response.id)
+        })?,
+    )?;
+
+    module.set(
+        "delete",
+        lua.create_function(move |lua, id: Value| {
+            let id: Uuid = lua.from_value(id)?;
+            let removed = AdminBounceEntry::remove_by_id(&id);
+            Ok(removed)
+        })?,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use axum::extract::{Json, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::campaign::{
+    CampaignV1ActionRequest, CampaignV1Entry, CampaignV1ListRequest, CampaignV1Request,
+    CampaignV1Selector,
+};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Register a campaign, or update the settings of an existing campaign.
+/// The settings take effect for new messages immediately.
+#[utoipa::path(
+    post,
+    tag="campaign",
+    path="/api/admin/campaign/v1",
+    responses(
+        (status = 200, description = "Registered the campaign"),
+        (status = 400, description = "The campaign settings were invalid"),
+    ),
+)]
+pub async fn add(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<CampaignV1Request>,
+) -> Response {
+    let campaign = request.campaign.clone();
+    match crate::campaign::register(request).await {
+        Ok(()) => (StatusCode::OK, format!("registered campaign {campaign}")),
+        Err(err) => (StatusCode::BAD_REQUEST, format!("{err:#}")),
+    }
+    .into_response()
+}
+
+/// List the registered campaigns, along with their state and progress
+#[utoipa::path(
+    get,
+    tag="campaign",
+    path="/api/admin/campaign/v1",
+    params(CampaignV1ListRequest),
+    responses(
+        (status = 200, description = "The matching campaigns", body=[CampaignV1Entry]),
+    ),
+)]
+pub async fn list(
+    _: TrustedIpRequired,
+    Query(request): Query<CampaignV1ListRequest>,
+) -> Result<Json<Vec<CampaignV1Entry>>, AppError> {
+    Ok(Json(crate::campaign::list(request)))
+}
+
+/// Forget a registered campaign. Messages that belong to it are no
+/// longer subject to its settings, and any pause is lifted.
+#[utoipa::path(
+    delete,
+    tag="campaign",
+    path="/api/admin/campaign/v1",
+    responses(
+        (status = 200, description = "Removed the campaign"),
+        (status = 404, description = "The campaign is not registered"),
+    ),
+)]
+pub async fn delete(_: TrustedIpRequired, Json(request): Json<CampaignV1Selector>) -> Response {
+    let campaign = request.campaign.clone();
+    if crate::campaign::unregister(request).await {
+        (StatusCode::OK, format!("removed campaign {campaign}"))
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("campaign {campaign} is not registered"),
+        )
+    }
+    .into_response()
+}
+
+/// Pause, resume or cancel a registered campaign
+#[utoipa::path(
+    post,
+    tag="campaign",
+    path="/api/admin/campaign-action/v1",
+    responses(
+        (status = 200, description = "Applied the action"),
+        (status = 400, description = "The action could not be applied"),
+    ),
+)]
+pub async fn action(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<CampaignV1ActionRequest>,
+) -> Response {
+    let summary = format!("{:?} campaign {}", request.action, request.campaign);
+    match crate::campaign::apply_action(request).await {
+        Ok(()) => (StatusCode::OK, summary),
+        Err(err) => (StatusCode::BAD_REQUEST, format!("{err:#}")),
+    }
+    .into_response()
+}
@@ -0,0 +1,68 @@
+use axum::extract::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::connection_filter::{
+    ConnectionFilterV1CancelRequest, ConnectionFilterV1Entry, ConnectionFilterV1Request,
+};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Add or replace inbound connection filter entries.
+/// The entries take effect for new connections immediately.
+#[utoipa::path(
+    post,
+    tag="connection-filter",
+    path="/api/admin/connection-filter/v1",
+    responses(
+        (status = 200, description = "Updated the filter"),
+        (status = 400, description = "One or more entries were invalid; no changes were made"),
+    ),
+)]
+pub async fn add(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<ConnectionFilterV1Request>,
+) -> Response {
+    let count = request.entries.len();
+    match crate::connection_filter::add_entries(request.entries) {
+        Ok(()) => (StatusCode::OK, format!("added {count} entries")),
+        Err(err) => (StatusCode::BAD_REQUEST, format!("{err:#}")),
+    }
+    .into_response()
+}
+
+/// List the inbound connection filter entries
+#[utoipa::path(
+    get,
+    tag="connection-filter",
+    path="/api/admin/connection-filter/v1",
+    responses(
+        (status = 200, description = "The current filter entries", body=[ConnectionFilterV1Entry]),
+    ),
+)]
+pub async fn list(_: TrustedIpRequired) -> Result<Json<Vec<ConnectionFilterV1Entry>>, AppError> {
+    Ok(Json(crate::connection_filter::list_entries()))
+}
+
+/// Remove inbound connection filter entries
+#[utoipa::path(
+    delete,
+    tag="connection-filter",
+    path="/api/admin/connection-filter/v1",
+    responses(
+        (status = 200, description = "Removed the entries"),
+        (status = 400, description = "One or more cidrs were invalid"),
+        (status = 404, description = "None of the cidrs matched an entry"),
+    ),
+)]
+pub async fn delete(
+    _: TrustedIpRequired,
+    Json(request): Json<ConnectionFilterV1CancelRequest>,
+) -> Response {
+    match crate::connection_filter::remove_entries(&request.cidrs) {
+        Ok(0) => (StatusCode::NOT_FOUND, "no matching entries".to_string()),
+        Ok(count) => (StatusCode::OK, format!("removed {count} entries")),
+        Err(err) => (StatusCode::BAD_REQUEST, format!("{err:#}")),
+    }
+    .into_response()
+}
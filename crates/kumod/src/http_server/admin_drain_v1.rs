@@ -0,0 +1,59 @@
+use axum::extract::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::drain::{DrainV1Request, DrainV1Status};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Puts the node into drain mode: new messages are refused, delivery
+/// of the queued messages is accelerated and, once the grace period
+/// has elapsed, any remaining messages are relayed to the peer node,
+/// if one was specified. The node can optionally shut down once
+/// it has been drained.
+#[utoipa::path(
+    post,
+    tag="drain",
+    path="/api/admin/drain/v1",
+    responses(
+        (status = 200, description = "Draining", body=DrainV1Status),
+    ),
+)]
+pub async fn drain(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<DrainV1Request>,
+) -> Result<Json<DrainV1Status>, AppError> {
+    Ok(Json(crate::drain::start(request)?))
+}
+
+/// Returns the progress of the drain
+#[utoipa::path(
+    get,
+    tag="drain",
+    path="/api/admin/drain/v1",
+    responses(
+        (status = 200, description = "The status of the drain", body=DrainV1Status),
+    ),
+)]
+pub async fn status(_: TrustedIpRequired) -> Result<Json<DrainV1Status>, AppError> {
+    Ok(Json(crate::drain::status()))
+}
+
+/// Stops draining the node, so that it accepts new messages again
+#[utoipa::path(
+    delete,
+    tag="drain",
+    path="/api/admin/drain/v1",
+    responses(
+        (status = 200, description = "No longer draining"),
+        (status = 404, description = "The node was not draining"),
+    ),
+)]
+pub async fn cancel(_: TrustedIpRequired) -> Response {
+    if crate::drain::cancel() {
+        (StatusCode::OK, "no longer draining".to_string())
+    } else {
+        (StatusCode::NOT_FOUND, "not draining".to_string())
+    }
+    .into_response()
+}
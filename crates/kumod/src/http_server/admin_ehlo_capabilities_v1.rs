@@ -0,0 +1,22 @@
+use axum::extract::{Json, Query};
+use kumo_api_types::ehlo_capabilities::{EhloCapabilitiesV1Entry, EhloCapabilitiesV1ListRequest};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Returns the ESMTP capabilities that were most recently advertised
+/// by each destination host, along with a history of changes to them.
+#[utoipa::path(
+    get,
+    tag="inspect",
+    path="/api/admin/ehlo-capabilities/v1",
+    params(EhloCapabilitiesV1ListRequest),
+    responses(
+        (status = 200, description = "The cached capabilities", body=[EhloCapabilitiesV1Entry]),
+    ),
+)]
+pub async fn list(
+    _: TrustedIpRequired,
+    Query(request): Query<EhloCapabilitiesV1ListRequest>,
+) -> Result<Json<Vec<EhloCapabilitiesV1Entry>>, AppError> {
+    Ok(Json(crate::ehlo_cache::list(request)))
+}
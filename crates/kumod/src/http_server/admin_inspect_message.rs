@@ -24,13 +24,22 @@ pub async fn inspect_v1(
     let sender = msg.sender()?.to_string();
     let meta = msg.get_meta_obj()?;
 
-    let data = if request.want_body {
+    if request.want_body || request.want_headers {
         msg.load_data_if_needed().await?;
+    }
+
+    let data = if request.want_body {
         Some(String::from_utf8_lossy(&msg.get_data()).into())
     } else {
         None
     };
 
+    let headers = if request.want_headers {
+        Some(msg.get_all_headers()?)
+    } else {
+        None
+    };
+
     Ok(Json(InspectMessageV1Response {
         id: request.id,
         message: MessageInformation {
@@ -38,6 +47,9 @@ pub async fn inspect_v1(
             recipient,
             meta,
             data,
+            headers,
+            num_attempts: msg.get_num_attempts(),
+            due: msg.get_due(),
         },
     }))
 }
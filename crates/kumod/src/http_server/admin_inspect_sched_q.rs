@@ -0,0 +1,37 @@
+use crate::queue::QueueManager;
+use axum::extract::{Json, Query};
+use kumo_api_types::{InspectScheduledQueuesV1Request, InspectScheduledQueuesV1Response};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Retrieve the depth, age histogram and next due time of each
+/// scheduled queue, or of a specific scheduled queue.
+#[utoipa::path(
+    get,
+    tag="inspect",
+    path="/api/admin/inspect-sched-q/v1",
+    params(InspectScheduledQueuesV1Request),
+    responses(
+        (status = 200, description = "Obtained scheduled queue information", body=InspectScheduledQueuesV1Response),
+    ),
+)]
+pub async fn inspect_sched_q_v1(
+    _: TrustedIpRequired,
+    Query(request): Query<InspectScheduledQueuesV1Request>,
+) -> Result<Json<InspectScheduledQueuesV1Response>, AppError> {
+    let names = match request.queue_name {
+        Some(name) => vec![name],
+        None => QueueManager::all_queue_names(),
+    };
+
+    let mut queues = vec![];
+    for name in names {
+        if let Some(queue) = QueueManager::get_opt(&name) {
+            queues.push(queue.inspect().await);
+        }
+    }
+
+    queues.sort_by(|a, b| b.depth.cmp(&a.depth).then_with(|| a.name.cmp(&b.name)));
+
+    Ok(Json(InspectScheduledQueuesV1Response { queues }))
+}
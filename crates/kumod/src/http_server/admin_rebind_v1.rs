@@ -1,10 +1,12 @@
 use crate::queue::QueueManager;
 use axum::extract::Json;
+use config::{any_err, get_or_create_sub_module};
 use kumo_api_types::rebind::{RebindV1Request, RebindV1Response};
 use kumo_server_common::http_server::auth::TrustedIpRequired;
 use kumo_server_common::http_server::AppError;
 use kumo_server_runtime::rt_spawn_non_blocking;
 use message::message::QueueNameComponents;
+use mlua::{Lua, LuaSerdeExt, Value};
 use std::sync::Arc;
 
 #[derive(Debug)]
@@ -83,6 +85,13 @@ pub async fn rebind_v1(
     // Note: Json<> must be last in the param list
     Json(request): Json<RebindV1Request>,
 ) -> Result<Json<RebindV1Response>, AppError> {
+    start_rebind(request).await?;
+    Ok(Json(RebindV1Response {}))
+}
+
+/// Start processing `request` in the background.
+/// This is shared between the HTTP and lua entrypoints.
+async fn start_rebind(request: RebindV1Request) -> anyhow::Result<()> {
     let entry = Arc::new(AdminRebindEntry { request });
 
     let queue_names = entry.list_matching_queues().await;
@@ -99,5 +108,19 @@ pub async fn rebind_v1(
         })
     })?;
 
-    Ok(Json(RebindV1Response {}))
+    Ok(())
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "api.admin.rebind")?;
+
+    module.set(
+        "rebind_v1",
+        lua.create_async_function(|lua, request: Value| async move {
+            let request: RebindV1Request = lua.from_value(request)?;
+            start_rebind(request).await.map_err(any_err)
+        })?,
+    )?;
+
+    Ok(())
 }
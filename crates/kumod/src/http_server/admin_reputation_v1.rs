@@ -0,0 +1,19 @@
+use axum::extract::Json;
+use kumo_api_types::reputation::ReputationV1Response;
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Returns the rolling delivery, bounce and complaint statistics
+/// for each sender domain and tenant that has had activity
+/// within the configured reputation window.
+#[utoipa::path(
+    get,
+    tag="reputation",
+    path="/api/admin/reputation/v1",
+    responses(
+        (status = 200, description = "Returned the current reputation statistics", body=ReputationV1Response)
+    ),
+)]
+pub async fn reputation_v1(_: TrustedIpRequired) -> Result<Json<ReputationV1Response>, AppError> {
+    Ok(Json(crate::reputation::snapshot()))
+}
@@ -0,0 +1,655 @@
+use crate::egress_source::EgressPool;
+use crate::queue::{DeliveryProto, Queue};
+use crate::ready_queue::ReadyQueueManager;
+use crate::smtp_server::{ConnectionMetaData, RejectError};
+use axum::extract::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use config::{load_isolated_config, CallbackSignature};
+use kumo_api_types::simulate::{
+    SimulateReception, SimulateV1DkimSignature, SimulateV1Request, SimulateV1Response,
+    SimulateV1Source,
+};
+use kumo_server_common::config_handle::ConfigHandle;
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+use mailparsing::{Header, SharedString};
+use message::{EnvelopeAddress, Message};
+use mlua::Lua;
+use spool::SpoolId;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The simulation runs the real policy events, so it must be
+/// explicitly enabled
+static SIMULATION_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Restricts the lua context that is used for the simulation to the
+/// functions that are known to have no effect beyond the simulated
+/// message, before the policy is loaded into it.
+/// Every function in a registered module that is not explicitly
+/// allowed is replaced: the configuration functions become no-ops,
+/// and everything else raises an error when called.
+/// Key/value stores, counters, throttles and memoize are replaced
+/// with versions whose state is local to the simulation.
+const SANDBOX: &str = r#"
+local loaded = package.loaded
+local kumo = loaded.kumo or {}
+
+-- Modules whose functions are all free of side effects
+local ALLOWED_MODULES = {
+  ['kumo.cidr'] = true,
+  ['kumo.digest'] = true,
+  ['kumo.dkim'] = true,
+  ['kumo.domain_map'] = true,
+  ['kumo.encode'] = true,
+  ['kumo.regex'] = true,
+  ['kumo.regex_set'] = true,
+  ['kumo.regex_set_map'] = true,
+  ['kumo.secrets'] = true,
+  ['kumo.serde'] = true,
+  ['kumo.string'] = true,
+  ['kumo.template'] = true,
+  ['kumo.uuid'] = true,
+}
+
+-- Individual functions that only read state
+local ALLOWED = {}
+for _, path in ipairs {
+  'kumo.available_parallelism',
+  'kumo.eval_config_monitor_globs',
+  'kumo.get_event_registrars',
+  'kumo.glob',
+  'kumo.invoke_get_egress_path_config',
+  'kumo.json_encode',
+  'kumo.json_encode_pretty',
+  'kumo.json_load',
+  'kumo.json_parse',
+  'kumo.make_egress_path',
+  'kumo.make_egress_pool',
+  'kumo.make_egress_source',
+  'kumo.make_listener_domain',
+  'kumo.make_message',
+  'kumo.make_queue_config',
+  'kumo.on',
+  'kumo.read_dir',
+  'kumo.reject',
+  'kumo.sleep',
+  'kumo.toml_encode',
+  'kumo.toml_encode_pretty',
+  'kumo.toml_load',
+  'kumo.toml_parse',
+  'kumo.traceback',
+  'kumo.uncached_glob',
+  'kumo.validation_failed',
+  'kumo.api.admin.bounce.list',
+  'kumo.api.admin.suspend.list',
+  'kumo.api.admin.suspend_ready_q.list',
+  'kumo.bounce_alias.list',
+  'kumo.connection_filter.list',
+  'kumo.delivery_history.get_domain',
+  'kumo.delivery_history.get_provider',
+  'kumo.dns.lookup_addr',
+  'kumo.dns.lookup_mx',
+  'kumo.dns.lookup_txt',
+  'kumo.http.build_url',
+  'kumo.ldap.escape_filter',
+  'kumo.reputation.get_sender_domain',
+  'kumo.reputation.get_tenant',
+  'kumo.reputation.list',
+  'kumo.shaping.list_overrides',
+  'kumo.shaping.load',
+  'kumo.source_exclusion.list',
+  'kumo.source_health.get',
+  'kumo.suppression.lookup',
+  'kumo.warmup.list',
+} do
+  ALLOWED[path] = true
+end
+
+-- Throttles report that they were not reached, without
+-- consuming any of their capacity
+if kumo.make_throttle then
+  local make_throttle = kumo.make_throttle
+  kumo.make_throttle = function(name, spec)
+    -- Validate the spec
+    make_throttle(name, spec)
+    return {
+      throttle = function()
+        return { throttled = false, limit = 0, remaining = 0, reset_after = 0 }
+      end,
+      sleep_if_throttled = function()
+        return false
+      end,
+      delay_message_if_throttled = function()
+        return false
+      end,
+    }
+  end
+  ALLOWED['kumo.make_throttle'] = true
+end
+
+-- Results are not cached across simulations
+if kumo.memoize then
+  kumo.memoize = function(func)
+    return func
+  end
+  ALLOWED['kumo.memoize'] = true
+end
+
+-- Key/value stores start out empty, and are held in
+-- the memory of the simulation
+if kumo.kv then
+  kumo.kv.open = function()
+    local data = {}
+    return {
+      get = function(_, key)
+        return data[key]
+      end,
+      set = function(_, key, value)
+        data[key] = value
+      end,
+      delete = function(_, key)
+        data[key] = nil
+      end,
+      incr = function(_, key, amount)
+        local value = (tonumber(data[key]) or 0) + (amount or 1)
+        data[key] = tostring(value)
+        return value
+      end,
+    }
+  end
+  ALLOWED['kumo.kv.open'] = true
+end
+
+-- Counters and gauges start from zero, and changes to
+-- them are not visible outside of the simulation
+if kumo.counter then
+  local function make()
+    local value = 0
+    return {
+      inc = function(_, amount)
+        value = value + (amount or 1)
+      end,
+      dec = function(_, amount)
+        value = value - (amount or 1)
+      end,
+      set = function(_, v)
+        value = v
+      end,
+      get = function()
+        return value
+      end,
+    }
+  end
+  kumo.counter.counter = make
+  kumo.counter.gauge = make
+  ALLOWED['kumo.counter.counter'] = true
+  ALLOWED['kumo.counter.gauge'] = true
+end
+
+local function is_configuration(name)
+  return name:match '^configure' or name:match '^set_' or name:match '^start_'
+    or name == 'define_spool'
+end
+
+local function restrict(module, prefix, seen)
+  if seen[module] or ALLOWED_MODULES[prefix] then
+    return
+  end
+  seen[module] = true
+  for name, value in pairs(module) do
+    if type(name) == 'string' then
+      local path = prefix .. '.' .. name
+      if type(value) == 'table' then
+        restrict(value, path, seen)
+      elseif type(value) == 'function' and not ALLOWED[path] then
+        if is_configuration(name) then
+          module[name] = function() end
+        else
+          module[name] = function()
+            error(path .. ' is not available during policy simulation', 2)
+          end
+        end
+      end
+    end
+  end
+end
+
+local STANDARD = {
+  _G = true,
+  package = true,
+  coroutine = true,
+  table = true,
+  io = true,
+  os = true,
+  string = true,
+  math = true,
+  utf8 = true,
+  debug = true,
+}
+
+local seen = {}
+for name, module in pairs(loaded) do
+  if not STANDARD[name] and type(module) == 'table' then
+    restrict(module, name, seen)
+  end
+end
+
+-- Files may be read, but not written, and no processes spawned
+local function deny(module, prefix, names)
+  if module then
+    for _, name in ipairs(names) do
+      if module[name] then
+        local path = prefix .. '.' .. name
+        module[name] = function()
+          error(path .. ' is not available during policy simulation', 2)
+        end
+      end
+    end
+  end
+end
+deny(os, 'os', { 'execute', 'exit', 'remove', 'rename', 'tmpname' })
+deny(io, 'io', { 'popen', 'output' })
+if io and io.open then
+  local open = io.open
+  io.open = function(path, mode)
+    if mode and not mode:match '^r[b]?$' then
+      error('io.open with mode ' .. mode .. ' is not available during policy simulation', 2)
+    end
+    return open(path, mode)
+  end
+end
+"#;
+
+fn sandbox(lua: &Lua) -> anyhow::Result<()> {
+    lua.load(SANDBOX).set_name("simulation sandbox").exec()?;
+    Ok(())
+}
+
+pub fn set_simulation_enabled(enabled: bool) {
+    SIMULATION_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Evaluates the policy for a hypothetical message and reports
+/// what would happen to it, without spooling or delivering it.
+#[utoipa::path(
+    post,
+    tag="simulate",
+    path="/api/admin/simulate/v1",
+    responses(
+        (status = 200, description = "Simulated the message", body=SimulateV1Response),
+        (status = 403, description = "Simulation has not been enabled"),
+    ),
+)]
+pub async fn simulate_v1(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<SimulateV1Request>,
+) -> Result<Response, AppError> {
+    if !SIMULATION_ENABLED.load(Ordering::Relaxed) {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            "policy simulation is disabled; see kumo.set_simulation_enabled",
+        )
+            .into_response());
+    }
+    Ok(Json(simulate(request).await?).into_response())
+}
+
+async fn simulate(request: SimulateV1Request) -> anyhow::Result<SimulateV1Response> {
+    let sender = EnvelopeAddress::parse(&request.sender)?;
+    let recipient = EnvelopeAddress::parse(&request.recipient)?;
+    let content = request.content.unwrap_or_else(|| {
+        format!(
+            "From: <{}>\r\nTo: <{}>\r\nSubject: simulation\r\n\r\nThis is a simulated message.\r\n",
+            request.sender, request.recipient
+        )
+    });
+    let content = mailparsing::normalize_crlf(content.as_bytes());
+    let original_signatures = dkim_signatures(&content)?;
+
+    let msg = Message::new_dirty(
+        SpoolId::new(),
+        sender,
+        recipient,
+        serde_json::json!({}),
+        Arc::new(content.into_boxed_slice()),
+    )?;
+    for (key, value) in request.meta {
+        msg.set_meta(key, value)?;
+    }
+    if let Some(tenant) = request.tenant {
+        msg.set_meta("tenant", tenant)?;
+    }
+    if let Some(campaign) = request.campaign {
+        msg.set_meta("campaign", campaign)?;
+    }
+    // Allow policy to recognize, and skip any side effects for,
+    // simulated messages
+    msg.set_meta("simulated", true)?;
+    msg.set_content_meta(true)?;
+
+    let peer_address = request
+        .peer_address
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    // The context is discarded after use, so the sandbox doesn't
+    // leak into the handling of real messages
+    let mut config = load_isolated_config(sandbox).await?;
+    let result = match request.reception {
+        SimulateReception::Esmtp => {
+            let mut conn_meta = ConnectionMetaData::new();
+            conn_meta.set_meta("reception_protocol", "ESMTP");
+            conn_meta.set_meta("received_from", peer_address.to_string());
+            msg.set_meta("reception_protocol", "ESMTP")?;
+            msg.set_meta("received_from", peer_address.to_string())?;
+            let sig = CallbackSignature::<(Message, ConnectionMetaData), ()>::new(
+                "smtp_server_message_received",
+            );
+            config
+                .async_call_callback(&sig, (msg.clone(), conn_meta))
+                .await
+        }
+        SimulateReception::Http => {
+            msg.set_meta("reception_protocol", "HTTP")?;
+            msg.set_meta("received_from", peer_address.to_string())?;
+            let sig = CallbackSignature::<Message, ()>::new("http_message_generated");
+            config.async_call_callback(&sig, msg.clone()).await
+        }
+    };
+
+    if let Err(err) = result {
+        return match RejectError::from_anyhow(&err) {
+            Some(rej) => Ok(SimulateV1Response {
+                rejected: Some(rej.to_string()),
+                queue: None,
+                meta: None,
+                dkim_signatures: vec![],
+                queue_config: None,
+                egress_pool: None,
+                sources: vec![],
+            }),
+            None => Err(err),
+        };
+    }
+
+    let dkim_signatures = dkim_signatures(&msg.get_data())?
+        .into_iter()
+        .filter(|(raw, _)| !original_signatures.iter().any(|(orig, _)| orig == raw))
+        .map(|(_, sig)| sig)
+        .collect();
+
+    let queue_name = msg.get_queue_name()?;
+    let mut response = SimulateV1Response {
+        rejected: None,
+        queue: Some(queue_name.clone()),
+        meta: Some(msg.get_meta_obj()?),
+        dkim_signatures,
+        queue_config: None,
+        egress_pool: None,
+        sources: vec![],
+    };
+
+    if queue_name == "null" {
+        return Ok(response);
+    }
+
+    let queue_config = Queue::call_get_queue_config(&queue_name, &mut config).await?;
+    response.queue_config = Some(serde_json::to_value(&queue_config)?);
+
+    if matches!(
+        queue_config.protocol,
+        DeliveryProto::Maildir { .. } | DeliveryProto::Mailbox { .. }
+    ) {
+        // Mailbox delivery doesn't use egress sources
+        return Ok(response);
+    }
+
+    let pool = EgressPool::resolve(queue_config.egress_pool.as_deref(), &mut config).await?;
+    response.egress_pool.replace(pool.name.clone());
+
+    let queue_config = ConfigHandle::new(queue_config);
+    for entry in pool.entries {
+        let mut source = SimulateV1Source {
+            name: entry.name.clone(),
+            weight: entry.weight,
+            ready_queue: None,
+            site_name: None,
+            path_config: None,
+            error: None,
+        };
+        match ReadyQueueManager::compute_config_with(
+            &queue_name,
+            &queue_config,
+            &entry.name,
+            &mut config,
+        )
+        .await
+        {
+            Ok(ready) => {
+                source.ready_queue.replace(ready.name);
+                source.site_name.replace(ready.site_name);
+                source
+                    .path_config
+                    .replace(serde_json::to_value(&ready.path_config)?);
+            }
+            Err(err) => {
+                source.error.replace(format!("{err:#}"));
+            }
+        }
+        response.sources.push(source);
+    }
+
+    Ok(response)
+}
+
+/// Returns the raw value of each DKIM-Signature header in data,
+/// along with its signing domain and selector
+fn dkim_signatures(data: &[u8]) -> anyhow::Result<Vec<(String, SimulateV1DkimSignature)>> {
+    let bytes = SharedString::try_from(data)?;
+    let parsed = Header::parse_headers(bytes)?;
+
+    Ok(parsed
+        .headers
+        .iter_named("DKIM-Signature")
+        .map(|header| {
+            let raw = header.get_raw_value().to_string();
+            let mut sig = SimulateV1DkimSignature {
+                domain: String::new(),
+                selector: String::new(),
+            };
+            for tag in raw.split(';') {
+                let Some((name, value)) = tag.split_once('=') else {
+                    continue;
+                };
+                let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+                match name.trim() {
+                    "d" => sig.domain = value,
+                    "s" => sig.selector = value,
+                    _ => {}
+                }
+            }
+            (raw, sig)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_dkim_signatures() {
+        let data = b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com;\r\n\
+            \ts=sel1; h=from:to; bh=abc; b=def\r\n\
+            From: someone@example.com\r\n\
+            \r\n\
+            body\r\n";
+        let sigs = dkim_signatures(data).unwrap();
+        assert_eq!(sigs.len(), 1);
+        assert_eq!(
+            sigs[0].1,
+            SimulateV1DkimSignature {
+                domain: "example.com".to_string(),
+                selector: "sel1".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn sandbox_stubs_side_effects() {
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            CALLED = {}
+            local function fake(path)
+              return function()
+                CALLED[path] = true
+                return path
+              end
+            end
+            package.loaded.kumo = {
+              on = fake 'kumo.on',
+              spawn_task = fake 'kumo.spawn_task',
+              configure_accounting_db_path = fake 'kumo.configure_accounting_db_path',
+              memoize = fake 'kumo.memoize',
+              kv = { open = fake 'kumo.kv.open' },
+              counter = {
+                counter = fake 'kumo.counter.counter',
+                gauge = fake 'kumo.counter.gauge',
+              },
+              sql = { open = fake 'kumo.sql.open' },
+              rspamd = { scan_message = fake 'kumo.rspamd.scan_message' },
+              ldap = { open = fake 'kumo.ldap.open' },
+              http = {
+                build_url = fake 'kumo.http.build_url',
+                build_client = fake 'kumo.http.build_client',
+              },
+              api = { admin = { bounce = { bounce = fake 'kumo.api.admin.bounce.bounce' } } },
+              string = { trim = fake 'kumo.string.trim' },
+            }
+            package.loaded.redis = { open = fake 'redis.open' }
+            package.loaded.sqlite = { open = fake 'sqlite.open' }
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        sandbox(&lua).unwrap();
+
+        // A handler that is loaded after the sandbox, the way that
+        // the policy is loaded for the simulation
+        lua.load(
+            r#"
+            local kumo = require 'kumo'
+            local redis = require 'redis'
+            local sqlite = require 'sqlite'
+
+            function handler(call)
+              if call == 'redis.open' then return redis.open {} end
+              if call == 'sqlite.open' then return sqlite.open ':memory:' end
+              if call == 'kumo.sql.open' then return kumo.sql.open {} end
+              if call == 'kumo.rspamd.scan_message' then return kumo.rspamd.scan_message {} end
+              if call == 'kumo.ldap.open' then return kumo.ldap.open {} end
+              if call == 'kumo.http.build_client' then return kumo.http.build_client {} end
+              if call == 'kumo.spawn_task' then return kumo.spawn_task {} end
+              if call == 'kumo.api.admin.bounce.bounce' then
+                return kumo.api.admin.bounce.bounce {}
+              end
+              if call == 'kv' then
+                local kv = kumo.kv.open { memcache = { servers = {} } }
+                local before = kv:get 'a'
+                kv:set('a', '41')
+                return tostring(before) .. ' ' .. kv:get 'a' .. ' ' .. kv:incr('a', 2)
+              end
+              if call == 'counter' then
+                local counter = kumo.counter.counter 'c'
+                counter:inc(5)
+                local gauge = kumo.counter.gauge 'g'
+                gauge:set(3)
+                gauge:dec()
+                return counter:get() .. ' ' .. gauge:get()
+              end
+              if call == 'memoize' then
+                return kumo.memoize(function() return 'not cached' end, {})()
+              end
+              if call == 'configure' then
+                return kumo.configure_accounting_db_path '/tmp/db'
+              end
+              if call == 'allowed' then
+                return kumo.string.trim ' x ' .. ' ' .. kumo.http.build_url 'x'
+              end
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+        let handler: mlua::Function = lua.globals().get("handler").unwrap();
+
+        for call in [
+            "redis.open",
+            "sqlite.open",
+            "kumo.sql.open",
+            "kumo.rspamd.scan_message",
+            "kumo.ldap.open",
+            "kumo.http.build_client",
+            "kumo.spawn_task",
+            "kumo.api.admin.bounce.bounce",
+        ] {
+            let err = handler
+                .call::<_, mlua::Value>(call)
+                .unwrap_err()
+                .to_string();
+            assert!(
+                err.contains(&format!("{call} is not available during policy simulation")),
+                "{call}: {err}"
+            );
+        }
+
+        let result = |call: &str| -> Option<String> { handler.call(call).unwrap() };
+        assert_eq!(result("kv").as_deref(), Some("nil 41 43"));
+        assert_eq!(result("counter").as_deref(), Some("5 2"));
+        assert_eq!(result("memoize").as_deref(), Some("not cached"));
+        assert_eq!(result("configure"), None);
+        assert_eq!(
+            result("allowed").as_deref(),
+            Some("kumo.string.trim kumo.http.build_url")
+        );
+
+        // Only the allowed functions reached the real implementation
+        let called: Vec<String> = lua
+            .load(
+                r#"
+                local called = {}
+                for path in pairs(CALLED) do
+                  table.insert(called, path)
+                end
+                table.sort(called)
+                return called
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(called, vec!["kumo.http.build_url", "kumo.string.trim"]);
+    }
+
+    #[test]
+    fn sandbox_denies_file_writes() {
+        let lua = Lua::new();
+        sandbox(&lua).unwrap();
+        for call in [
+            "io.open('/tmp/simulated', 'w')",
+            "io.popen('true')",
+            "os.execute('true')",
+            "os.remove('/tmp/simulated')",
+        ] {
+            let err = lua.load(call).exec().unwrap_err().to_string();
+            assert!(
+                err.contains("is not available during policy simulation"),
+                "{call}: {err}"
+            );
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use axum::extract::{Json, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::source_exclusion::{
+    SourceExclusionV1CancelRequest, SourceExclusionV1Entry, SourceExclusionV1ListRequest,
+    SourceExclusionV1Request,
+};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Take an egress source out of rotation for a destination until
+/// the exclusion expires. Replaces any existing exclusion of the
+/// same source from the same destination.
+#[utoipa::path(
+    post,
+    tag="source-exclusion",
+    path="/api/admin/source-exclusion/v1",
+    responses(
+        (status = 200, description = "Excluded the source", body=SourceExclusionV1Entry),
+    ),
+)]
+pub async fn add(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<SourceExclusionV1Request>,
+) -> Result<Json<SourceExclusionV1Entry>, AppError> {
+    Ok(Json(crate::source_exclusion::exclude(request)))
+}
+
+/// List the egress sources that are currently excluded
+#[utoipa::path(
+    get,
+    tag="source-exclusion",
+    path="/api/admin/source-exclusion/v1",
+    params(SourceExclusionV1ListRequest),
+    responses(
+        (status = 200, description = "The matching exclusions", body=[SourceExclusionV1Entry]),
+    ),
+)]
+pub async fn list(
+    _: TrustedIpRequired,
+    Query(request): Query<SourceExclusionV1ListRequest>,
+) -> Result<Json<Vec<SourceExclusionV1Entry>>, AppError> {
+    Ok(Json(crate::source_exclusion::list(request)))
+}
+
+/// Restore an excluded egress source to rotation before
+/// its exclusion expires
+#[utoipa::path(
+    delete,
+    tag="source-exclusion",
+    path="/api/admin/source-exclusion/v1",
+    responses(
+        (status = 200, description = "Restored the source"),
+        (status = 404, description = "The source is not excluded from the destination"),
+    ),
+)]
+pub async fn delete(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<SourceExclusionV1CancelRequest>,
+) -> Response {
+    let summary = format!("{} from {}", request.source, request.destination);
+    if crate::source_exclusion::restore(request) {
+        (StatusCode::OK, format!("restored {summary}"))
+    } else {
+        (StatusCode::NOT_FOUND, format!("{summary} is not excluded"))
+    }
+    .into_response()
+}
@@ -0,0 +1,71 @@
+use axum::extract::{Json, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::suppression::{
+    SuppressionV1CancelRequest, SuppressionV1Entry, SuppressionV1ListRequest, SuppressionV1Request,
+};
+use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Add or replace suppression list entries.
+/// The entries take effect for new messages immediately.
+#[utoipa::path(
+    post,
+    tag="suppression",
+    path="/api/admin/suppression/v1",
+    responses(
+        (status = 200, description = "Updated the suppression list"),
+        (status = 400, description = "One or more entries were invalid; no changes were made"),
+    ),
+)]
+pub async fn add(
+    _: TrustedIpRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<SuppressionV1Request>,
+) -> Response {
+    let count = request.entries.len();
+    match crate::suppression::add_entries(request.entries).await {
+        Ok(()) => (StatusCode::OK, format!("added {count} entries")),
+        Err(err) => (StatusCode::BAD_REQUEST, format!("{err:#}")),
+    }
+    .into_response()
+}
+
+/// Query the suppression list
+#[utoipa::path(
+    get,
+    tag="suppression",
+    path="/api/admin/suppression/v1",
+    params(SuppressionV1ListRequest),
+    responses(
+        (status = 200, description = "The matching entries", body=[SuppressionV1Entry]),
+    ),
+)]
+pub async fn list(
+    _: TrustedIpRequired,
+    Query(request): Query<SuppressionV1ListRequest>,
+) -> Result<Json<Vec<SuppressionV1Entry>>, AppError> {
+    Ok(Json(crate::suppression::list_entries(request).await?))
+}
+
+/// Remove recipients from the suppression list
+#[utoipa::path(
+    delete,
+    tag="suppression",
+    path="/api/admin/suppression/v1",
+    responses(
+        (status = 200, description = "Removed the entries"),
+        (status = 404, description = "None of the recipients were suppressed"),
+    ),
+)]
+pub async fn delete(
+    _: TrustedIpRequired,
+    Json(request): Json<SuppressionV1CancelRequest>,
+) -> Response {
+    match crate::suppression::remove_entries(request.recipients).await {
+        Ok(0) => (StatusCode::NOT_FOUND, "no matching entries".to_string()),
+        Ok(count) => (StatusCode::OK, format!("removed {count} entries")),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")),
+    }
+    .into_response()
+}
@@ -23,6 +23,9 @@ pub struct AdminSuspendEntry {
     pub campaign: Option<String>,
     pub tenant: Option<String>,
     pub domain: Option<String>,
+    /// Tenants that are not matched even though the other criteria
+    /// match; used by campaigns that were registered without a tenant
+    pub excluded_tenants: Vec<String>,
     pub reason: String,
     pub expires: Instant,
 }
@@ -95,7 +98,8 @@ impl AdminSuspendEntry {
             ent.expires > now
                 && !(ent.campaign == entry.campaign
                     && ent.tenant == entry.tenant
-                    && ent.domain == entry.domain)
+                    && ent.domain == entry.domain
+                    && ent.excluded_tenants == entry.excluded_tenants)
         });
 
         entries.push(entry);
@@ -113,6 +117,11 @@ impl AdminSuspendEntry {
         if !match_criteria(tenant, self.tenant.as_deref()) {
             return false;
         }
+        if let Some(tenant) = tenant {
+            if self.excluded_tenants.iter().any(|t| t == tenant) {
+                return false;
+            }
+        }
         if !match_criteria(domain, self.domain.as_deref()) {
             return false;
         }
@@ -160,6 +169,7 @@ pub async fn suspend(
         campaign: request.campaign,
         tenant: request.tenant,
         domain: request.domain,
+        excluded_tenants: vec![],
         reason: request.reason,
         expires: Instant::now() + duration,
     };
@@ -228,6 +238,7 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
                 campaign: request.campaign,
                 tenant: request.tenant,
                 domain: request.domain,
+                excluded_tenants: vec![],
                 reason: request.reason,
                 expires: Instant::now() + duration,
             };
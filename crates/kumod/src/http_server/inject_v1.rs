@@ -1,23 +1,23 @@
 use crate::delivery_metrics::MetricsWrappedConnection;
 use crate::logging::disposition::{log_disposition, LogDisposition, RecordType};
+use crate::message_tracing::{save_to_spool, StageSpan};
 use crate::queue::{DeliveryProto, QueueConfig, QueueManager};
 use crate::ready_queue::{Dispatcher, QueueDispatcher};
 use crate::spool::SpoolManager;
 use anyhow::Context;
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use axum::extract::Json;
+use axum::extract::{Extension, Json};
 use axum_client_ip::InsecureClientIp;
 use config::{any_err, get_or_create_sub_module, load_config, CallbackSignature, LuaConfig};
 use kumo_log_types::ResolvedAddress;
 use kumo_prometheus::AtomicCounter;
 use kumo_server_common::http_server::auth::AuthKind;
-use kumo_server_common::http_server::AppError;
+use kumo_server_common::http_server::{AppError, ProxiedClientAddress};
 use kumo_server_runtime::{Runtime, RUNTIME};
 use mailparsing::{AddrSpec, Address, EncodeHeaderValue, Mailbox, MessageBuilder, MimePart};
 use message::{EnvelopeAddress, Message};
 use minijinja::{Environment, Template};
-use minijinja_contrib::add_to_environment;
 use mlua::{Lua, LuaSerdeExt};
 use once_cell::sync::Lazy;
 use rfc5321::Response;
@@ -352,8 +352,7 @@ impl InjectV1Request {
     }
 
     fn compile(&self) -> anyhow::Result<Compiled> {
-        let mut env = Environment::new();
-        add_to_environment(&mut env);
+        let mut env = mod_template::new_environment();
         let mut id = 0;
 
         // Pass 1: create the templates
@@ -497,6 +496,12 @@ async fn process_recipient<'a>(
 
     // build into a Message
     let id = SpoolId::new();
+    // Covers the reception through to the insertion into the queue
+    let mut span = StageSpan::start(&id, "reception");
+    if span.is_recording() {
+        span.set_attribute("protocol", "HTTP");
+        span.set_attribute("peer_address", peer_address.to_string());
+    }
     let message = Message::new_dirty(
         id,
         sender.clone(),
@@ -508,18 +513,23 @@ async fn process_recipient<'a>(
     message.set_meta("http_auth", auth.summarize())?;
     message.set_meta("reception_protocol", "HTTP")?;
     message.set_meta("received_from", peer_address.to_string())?;
+    message.set_content_meta(true)?;
 
     // call callback to assign to queue
     let sig = CallbackSignature::<message::Message, ()>::new("http_message_generated");
     config.async_call_callback(&sig, message.clone()).await?;
 
+    crate::suppression::check_message(&message).await?;
+    let reservation = crate::campaign::check_message(&message).await?;
+
     // spool and insert to queue
     let queue_name = message.get_queue_name()?;
 
     if queue_name != "null" {
-        if !request.deferred_spool {
-            message.save().await?;
+        if !request.deferred_spool && !QueueManager::is_deferred_spool(&queue_name).await? {
+            save_to_spool(&message).await?;
         }
+        reservation.commit();
         log_disposition(LogDisposition {
             kind: RecordType::Reception,
             msg: message.clone(),
@@ -572,7 +582,7 @@ async fn queue_deferred(
     message.set_meta("received_from", peer_address.to_string())?;
     message.set_meta("queue", GENERATOR_QUEUE_NAME)?;
     if !request.deferred_spool {
-        message.save().await?;
+        save_to_spool(&message).await?;
     }
     log_disposition(LogDisposition {
         kind: RecordType::Reception,
@@ -699,9 +709,15 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
 pub async fn inject_v1(
     auth: AuthKind,
     InsecureClientIp(peer_address): InsecureClientIp,
+    Extension(proxied_address): Extension<Option<ProxiedClientAddress>>,
     // Note: Json<> must be last in the param list
     Json(request): Json<InjectV1Request>,
 ) -> Result<Json<InjectV1Response>, AppError> {
+    let peer_address = match proxied_address {
+        Some(ProxiedClientAddress(addr)) => addr.ip(),
+        None => peer_address,
+    };
+
     if kumo_server_memory::get_headroom() == 0 {
         // Using too much memory
         return Err(anyhow::anyhow!("load shedding").into());
@@ -709,6 +725,9 @@ pub async fn inject_v1(
     if kumo_server_common::disk_space::is_over_limit() {
         return Err(anyhow::anyhow!("disk is too full").into());
     }
+    if crate::drain::is_draining() {
+        return Err(anyhow::anyhow!("draining").into());
+    }
 
     let limit = LIMIT.load();
     if let Some(limit) = limit.as_ref() {
@@ -1,15 +1,32 @@
 use axum::routing::{delete, get, post};
 use axum::Router;
 use inject_v1::*;
+use kumo_api_types::campaign::*;
+use kumo_api_types::connection_filter::*;
+use kumo_api_types::drain::*;
+use kumo_api_types::ehlo_capabilities::*;
 use kumo_api_types::rebind::*;
+use kumo_api_types::reputation::*;
+use kumo_api_types::simulate::*;
+use kumo_api_types::source_exclusion::*;
+use kumo_api_types::suppression::*;
 use kumo_api_types::*;
 use kumo_server_common::http_server::RouterAndDocs;
 use spool::SpoolId;
 use utoipa::OpenApi;
 
 pub mod admin_bounce_v1;
+pub mod admin_campaign_v1;
+pub mod admin_connection_filter_v1;
+pub mod admin_drain_v1;
+pub mod admin_ehlo_capabilities_v1;
 pub mod admin_inspect_message;
+pub mod admin_inspect_sched_q;
 pub mod admin_rebind_v1;
+pub mod admin_reputation_v1;
+pub mod admin_simulate_v1;
+pub mod admin_source_exclusion_v1;
+pub mod admin_suppression_v1;
 pub mod admin_suspend_ready_q_v1;
 pub mod admin_suspend_v1;
 pub mod admin_trace_smtp_client_v1;
@@ -25,8 +42,28 @@ pub mod inject_v1;
         admin_bounce_v1::bounce_v1,
         admin_bounce_v1::bounce_v1_list,
         admin_bounce_v1::bounce_v1_delete,
+        admin_campaign_v1::add,
+        admin_campaign_v1::list,
+        admin_campaign_v1::delete,
+        admin_campaign_v1::action,
+        admin_connection_filter_v1::add,
+        admin_connection_filter_v1::list,
+        admin_connection_filter_v1::delete,
+        admin_drain_v1::drain,
+        admin_drain_v1::status,
+        admin_drain_v1::cancel,
+        admin_ehlo_capabilities_v1::list,
         admin_inspect_message::inspect_v1,
+        admin_inspect_sched_q::inspect_sched_q_v1,
         admin_rebind_v1::rebind_v1,
+        admin_reputation_v1::reputation_v1,
+        admin_simulate_v1::simulate_v1,
+        admin_source_exclusion_v1::add,
+        admin_source_exclusion_v1::list,
+        admin_source_exclusion_v1::delete,
+        admin_suppression_v1::add,
+        admin_suppression_v1::list,
+        admin_suppression_v1::delete,
         admin_suspend_ready_q_v1::suspend,
         admin_suspend_ready_q_v1::list,
         admin_suspend_ready_q_v1::delete,
@@ -49,10 +86,43 @@ pub mod inject_v1;
             BounceV1Response,
             BounceV1ListEntry,
             BounceV1CancelRequest,
+            CampaignV1Action,
+            CampaignV1ActionRequest,
+            CampaignV1Entry,
+            CampaignV1Progress,
+            CampaignV1Request,
+            CampaignV1Selector,
+            CampaignV1State,
+            ConnectionFilterAction,
+            ConnectionFilterV1CancelRequest,
+            ConnectionFilterV1Entry,
+            ConnectionFilterV1Request,
+            DrainV1Phase,
+            DrainV1Request,
+            DrainV1Status,
+            EhloCapabilitiesV1Entry,
             InspectMessageV1Response,
             MessageInformation,
+            InspectScheduledQueuesV1Response,
+            ScheduledQueueV1Entry,
+            AgeHistogramBucket,
             RebindV1Request,
             RebindV1Response,
+            ReputationV1Entry,
+            ReputationV1Response,
+            SimulateReception,
+            SimulateV1DkimSignature,
+            SimulateV1Request,
+            SimulateV1Response,
+            SimulateV1Source,
+            SourceExclusionV1CancelRequest,
+            SourceExclusionV1Entry,
+            SourceExclusionV1Request,
+            SuppressionReason,
+            SuppressionV1AddEntry,
+            SuppressionV1CancelRequest,
+            SuppressionV1Entry,
+            SuppressionV1Request,
             SuspendReadyQueueV1Request,
             SuspendV1Response,
             SuspendReadyQueueV1ListEntry,
@@ -60,7 +130,12 @@ pub mod inject_v1;
             SuspendV1ListEntry,
             SuspendV1Request,
         ),
-        responses(InjectV1Response, BounceV1Response, InspectMessageV1Response),
+        responses(
+            InjectV1Response,
+            BounceV1Response,
+            InspectMessageV1Response,
+            InspectScheduledQueuesV1Response
+        ),
     )
 )]
 struct ApiDoc;
@@ -79,7 +154,52 @@ pub fn make_router() -> RouterAndDocs {
                 "/api/admin/bounce/v1",
                 delete(admin_bounce_v1::bounce_v1_delete),
             )
+            .route("/api/admin/campaign/v1", post(admin_campaign_v1::add))
+            .route("/api/admin/campaign/v1", get(admin_campaign_v1::list))
+            .route("/api/admin/campaign/v1", delete(admin_campaign_v1::delete))
+            .route(
+                "/api/admin/campaign-action/v1",
+                post(admin_campaign_v1::action),
+            )
+            .route(
+                "/api/admin/connection-filter/v1",
+                post(admin_connection_filter_v1::add),
+            )
+            .route(
+                "/api/admin/connection-filter/v1",
+                get(admin_connection_filter_v1::list),
+            )
+            .route(
+                "/api/admin/connection-filter/v1",
+                delete(admin_connection_filter_v1::delete),
+            )
             .route("/api/admin/rebind/v1", post(admin_rebind_v1::rebind_v1))
+            .route(
+                "/api/admin/reputation/v1",
+                get(admin_reputation_v1::reputation_v1),
+            )
+            .route(
+                "/api/admin/simulate/v1",
+                post(admin_simulate_v1::simulate_v1),
+            )
+            .route(
+                "/api/admin/source-exclusion/v1",
+                post(admin_source_exclusion_v1::add),
+            )
+            .route(
+                "/api/admin/source-exclusion/v1",
+                get(admin_source_exclusion_v1::list),
+            )
+            .route(
+                "/api/admin/source-exclusion/v1",
+                delete(admin_source_exclusion_v1::delete),
+            )
+            .route("/api/admin/suppression/v1", post(admin_suppression_v1::add))
+            .route("/api/admin/suppression/v1", get(admin_suppression_v1::list))
+            .route(
+                "/api/admin/suppression/v1",
+                delete(admin_suppression_v1::delete),
+            )
             .route("/api/admin/suspend/v1", post(admin_suspend_v1::suspend))
             .route("/api/admin/suspend/v1", get(admin_suspend_v1::list))
             .route("/api/admin/suspend/v1", delete(admin_suspend_v1::delete))
@@ -99,6 +219,17 @@ pub fn make_router() -> RouterAndDocs {
                 "/api/admin/inspect-message/v1",
                 get(admin_inspect_message::inspect_v1),
             )
+            .route(
+                "/api/admin/inspect-sched-q/v1",
+                get(admin_inspect_sched_q::inspect_sched_q_v1),
+            )
+            .route(
+                "/api/admin/ehlo-capabilities/v1",
+                get(admin_ehlo_capabilities_v1::list),
+            )
+            .route("/api/admin/drain/v1", post(admin_drain_v1::drain))
+            .route("/api/admin/drain/v1", get(admin_drain_v1::status))
+            .route("/api/admin/drain/v1", delete(admin_drain_v1::cancel))
             .route(
                 "/api/admin/trace-smtp-client/v1",
                 get(admin_trace_smtp_client_v1::trace),
@@ -0,0 +1,229 @@
+use crate::delivery_metrics::MetricsWrappedConnection;
+use crate::ready_queue::{Dispatcher, QueueDispatcher};
+use async_trait::async_trait;
+use kumo_log_types::ResolvedAddress;
+use message::message::QueueNameComponents;
+use message::Message;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use rdkafka::ClientConfig;
+use rfc5321::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaDeliveryProtocol {
+    /// The librdkafka producer configuration, which must include
+    /// at least `bootstrap.servers`
+    pub producer_config: BTreeMap<String, String>,
+
+    /// The topic to which each message will be produced
+    pub topic: String,
+
+    /// How to compute the key of each record, which determines
+    /// the partition to which it is assigned
+    #[serde(default)]
+    pub partition_key: Option<PartitionKey>,
+
+    /// How long to keep trying to produce a record before
+    /// considering the attempt to have failed
+    #[serde(
+        default = "KafkaDeliveryProtocol::default_timeout",
+        with = "duration_serde"
+    )]
+    pub timeout: Duration,
+}
+
+impl KafkaDeliveryProtocol {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+/// Selects a component of the queue name to use as the key of a
+/// published record. For log records produced by a log hook, the
+/// queue is that of the message that the record describes, rather
+/// than the queue of the log hook itself.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKey {
+    Domain,
+    Tenant,
+    Campaign,
+    /// `tenant:campaign`
+    TenantAndCampaign,
+}
+
+impl PartitionKey {
+    pub fn key_for(&self, msg: &Message) -> anyhow::Result<String> {
+        let log_record = msg.get_meta("log_record")?;
+        let queue_name = match log_record.get("queue").and_then(|q| q.as_str()) {
+            Some(queue) => queue.to_string(),
+            None => msg.get_queue_name()?,
+        };
+        Ok(self.key_for_queue(&queue_name))
+    }
+
+    fn key_for_queue(&self, queue_name: &str) -> String {
+        let components = QueueNameComponents::parse(queue_name);
+        let tenant = components.tenant.unwrap_or("");
+        let campaign = components.campaign.unwrap_or("");
+        match self {
+            Self::Domain => components.domain.to_string(),
+            Self::Tenant => tenant.to_string(),
+            Self::Campaign => campaign.to_string(),
+            Self::TenantAndCampaign => format!("{tenant}:{campaign}"),
+        }
+    }
+}
+
+struct Producer(FutureProducer);
+
+impl std::fmt::Debug for Producer {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Producer").finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct KafkaQueueDispatcher {
+    proto_config: KafkaDeliveryProtocol,
+    connection: Option<MetricsWrappedConnection<Producer>>,
+    peer_address: ResolvedAddress,
+}
+
+impl KafkaQueueDispatcher {
+    pub fn new(proto_config: KafkaDeliveryProtocol) -> Self {
+        let servers = proto_config
+            .producer_config
+            .get("bootstrap.servers")
+            .map(String::as_str)
+            .unwrap_or("");
+        let peer_address = ResolvedAddress {
+            name: format!("Kafka topic {} via {servers}", proto_config.topic),
+            addr: Ipv4Addr::UNSPECIFIED.into(),
+        };
+
+        Self {
+            proto_config,
+            connection: None,
+            peer_address,
+        }
+    }
+
+    /// Produce msg and map the outcome to an SMTP style response.
+    /// Failing to produce the record within the timeout is a
+    /// transient failure.
+    async fn try_send(&self, msg: &Message) -> anyhow::Result<Response> {
+        let producer = self
+            .connection
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no producer"))?;
+
+        let key = match &self.proto_config.partition_key {
+            Some(key) => Some(key.key_for(msg)?),
+            None => None,
+        };
+        let data = msg.get_data();
+
+        let record = FutureRecord {
+            topic: &self.proto_config.topic,
+            partition: None,
+            payload: Some(&data[..]),
+            key: key.as_ref(),
+            headers: None,
+            timestamp: None,
+        };
+
+        let response = match producer
+            .0
+            .send(record, Timeout::After(self.proto_config.timeout))
+            .await
+        {
+            Ok((partition, offset)) => Response {
+                code: 250,
+                enhanced_code: None,
+                content: format!("produced to partition {partition} at offset {offset}"),
+                command: None,
+            },
+            Err((err, _record)) => Response {
+                code: 421,
+                enhanced_code: None,
+                content: format!("KumoMTA internal: failed to produce to Kafka: {err:#}"),
+                command: None,
+            },
+        };
+        Ok(response)
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueDispatcher for KafkaQueueDispatcher {
+    async fn close_connection(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
+        match self.connection.take() {
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    async fn attempt_connection(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<()> {
+        if self.connection.is_none() {
+            let mut config = ClientConfig::new();
+            for (k, v) in &self.proto_config.producer_config {
+                config.set(k, v);
+            }
+            let producer: FutureProducer = config.create()?;
+            self.connection
+                .replace(dispatcher.metrics.wrap_connection(Producer(producer)));
+        }
+        Ok(())
+    }
+
+    async fn have_more_connection_candidates(&mut self, _dispatcher: &mut Dispatcher) -> bool {
+        false
+    }
+
+    async fn deliver_message(
+        &mut self,
+        msg: Message,
+        dispatcher: &mut Dispatcher,
+    ) -> anyhow::Result<()> {
+        msg.load_meta_if_needed().await?;
+        msg.load_data_if_needed().await?;
+
+        let response = self.try_send(&msg).await?;
+        tracing::debug!("Kafka response for {}: {response:?}", dispatcher.name);
+
+        match dispatcher.msg.take() {
+            Some(msg) => {
+                dispatcher
+                    .record_delivery_responses(vec![(msg, response)], &self.peer_address, "Kafka")
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partition_keys() {
+        let queue = "camp:tenant@example.com";
+        assert_eq!(PartitionKey::Domain.key_for_queue(queue), "example.com");
+        assert_eq!(PartitionKey::Tenant.key_for_queue(queue), "tenant");
+        assert_eq!(PartitionKey::Campaign.key_for_queue(queue), "camp");
+        assert_eq!(
+            PartitionKey::TenantAndCampaign.key_for_queue(queue),
+            "tenant:camp"
+        );
+        assert_eq!(
+            PartitionKey::TenantAndCampaign.key_for_queue("example.com"),
+            ":"
+        );
+    }
+}
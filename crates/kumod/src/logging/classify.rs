@@ -170,22 +170,25 @@ pub async fn apply_classification(record: &mut JsonLogRecord) {
         return;
     }
 
+    if let Some(result) = classify_response(&record.response).await {
+        record.bounce_classification = result;
+    }
+}
+
+/// Classifies response, returning None if no classifier
+/// has been configured
+pub async fn classify_response(response: &Response) -> Option<BounceClass> {
     // If you have no classifier, you pay no cost
-    let Some(classifier) = CLASSIFY.get() else {
-        return;
-    };
+    let classifier = CLASSIFY.get()?;
 
     let _timer = CLASSIFY_LATENCY.start_timer();
 
     // Check the caches before we commit any serious resources to
     // classifying this response
-    if let Some(result) = classifier.check_cache(&record.response) {
-        record.bounce_classification = result;
-        return;
+    if let Some(result) = classifier.check_cache(response) {
+        return Some(result);
     }
 
     // clone data and pass to the classifier thread pool
-    if let Ok(result) = classifier.classify(record.response.clone()).await {
-        record.bounce_classification = result;
-    }
+    classifier.classify(response.clone()).await.ok()
 }
@@ -1,9 +1,10 @@
 use crate::logging::Logger;
+use crate::message_tracing::{end_waiting, StageSpan};
 use crate::smtp_server::RelayDisposition;
 use bounce_classify::BounceClass;
 use chrono::Utc;
 use config::{load_config, CallbackSignature};
-use kumo_log_types::rfc3464::ReportAction;
+use kumo_log_types::rfc3464::{PerRecipientReportEntry, ReportAction};
 use kumo_log_types::MaybeProxiedSourceAddress;
 pub use kumo_log_types::*;
 use message::Message;
@@ -25,6 +26,38 @@ pub struct LogDisposition<'a> {
     pub provider: Option<&'a str>,
 }
 
+/// Produces the response for a failed recipient in an incoming
+/// bounce report
+pub(crate) fn oob_response(recip: &PerRecipientReportEntry) -> Response {
+    let enhanced_code = EnhancedStatusCode {
+        class: recip.status.class,
+        subject: recip.status.subject,
+        detail: recip.status.detail,
+    };
+
+    let (code, content) = match &recip.diagnostic_code {
+        Some(diag) if diag.diagnostic_type == "smtp" => {
+            if let Some((code, content)) = diag.diagnostic.split_once(' ') {
+                if let Ok(code) = code.parse() {
+                    (code, content.to_string())
+                } else {
+                    (550, diag.diagnostic.to_string())
+                }
+            } else {
+                (550, diag.diagnostic.to_string())
+            }
+        }
+        _ => (550, "".to_string()),
+    };
+
+    Response {
+        code,
+        enhanced_code: Some(enhanced_code),
+        content,
+        command: None,
+    }
+}
+
 pub async fn log_disposition(args: LogDisposition<'_>) {
     let LogDisposition {
         mut kind,
@@ -42,17 +75,15 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
     } = args;
 
     let loggers = Logger::get_loggers();
-    if loggers.is_empty() {
-        return;
-    }
-
     let mut feedback_report = None;
 
     msg.load_meta_if_needed().await.ok();
 
-    let reception_protocol = msg.get_meta_string("reception_protocol").unwrap_or(None);
-
-    if kind == RecordType::Reception {
+    // The report is only of interest to the loggers and to the
+    // suppression list, so avoid parsing it if neither is in use
+    if kind == RecordType::Reception
+        && (!loggers.is_empty() || crate::suppression::suppresses_complaints())
+    {
         if let Some(RelayDisposition { log_arf: true, .. }) = relay_disposition {
             if let Ok(Some(report)) = msg.parse_rfc5965() {
                 feedback_report.replace(report);
@@ -61,13 +92,80 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
         }
     }
 
+    // The span covers the processing of the disposition,
+    // through to submitting it to the loggers
+    let mut span = StageSpan::start(msg.id(), "disposition");
+    if span.is_recording() {
+        span.set_attribute("kind", format!("{kind:?}"));
+        span.set_attribute("site", site.to_string());
+        span.set_attribute("response.code", response.code as i64);
+        span.set_attribute("response.content", response.content.clone());
+        if let Some(peer) = peer_address {
+            span.set_attribute("peer_address", peer.addr.to_string());
+        }
+    }
+    if matches!(
+        kind,
+        RecordType::Delivery
+            | RecordType::Bounce
+            | RecordType::Expiration
+            | RecordType::AdminBounce
+    ) {
+        // The message won't be queued again
+        end_waiting(msg.id());
+    }
+
+    if !loggers.is_empty() {
+        crate::reputation::record_disposition(
+            kind,
+            &msg,
+            relay_disposition,
+            feedback_report.as_ref(),
+        )
+        .await;
+    }
+    crate::suppression::record_disposition(
+        kind,
+        &msg,
+        &response,
+        relay_disposition,
+        feedback_report.as_ref(),
+    )
+    .await;
+    crate::metrics_helper::record_disposition_by_queue_components(kind, &msg);
+    crate::delivery_history::record_disposition(kind, &msg, &response, provider);
+    crate::campaign::record_disposition(kind, &msg);
+    crate::source_health::record_disposition(kind, egress_source, site, provider, &response).await;
+    crate::warmup::record_disposition(kind, *msg.id());
+
+    if kind == RecordType::Delivery {
+        // Let the scheduled queue know that it is making progress,
+        // so that it won't be considered to be stale
+        if let Ok(queue_name) = msg.get_queue_name() {
+            if let Some(queue) = crate::queue::QueueManager::get_opt(&queue_name) {
+                queue.note_progress();
+            }
+        }
+    }
+
+    if loggers.is_empty() {
+        return;
+    }
+
+    let reception_protocol = msg.get_meta_string("reception_protocol").unwrap_or(None);
+
     let now = Utc::now();
     let nodeid = kumo_server_common::nodeid::NodeId::get_uuid();
 
+    let is_seed = crate::seeds::is_seed(&msg);
+
     for logger in loggers.iter() {
         if !logger.record_is_enabled(kind) {
             continue;
         }
+        if is_seed && !logger.include_seeds {
+            continue;
+        }
         if let Some(name) = &logger.filter_event {
             match load_config().await {
                 Ok(mut lua_config) => {
@@ -98,6 +196,9 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
         }
 
         match kind {
+            // Seed messages are not accounted, as they are not
+            // customer traffic
+            _ if is_seed => {}
             RecordType::Reception => {
                 crate::accounting::account_reception(
                     &reception_protocol.as_deref().unwrap_or("unknown"),
@@ -147,6 +248,7 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
             egress_source: egress_source.map(|s| s.to_string()),
             bounce_classification: BounceClass::default(),
             feedback_report: feedback_report.clone(),
+            original_message_id: None,
             headers: headers.clone(),
             meta: meta.clone(),
             delivery_protocol: delivery_protocol.map(|s| s.to_string()),
@@ -180,27 +282,6 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
                             continue;
                         }
 
-                        let enhanced_code = EnhancedStatusCode {
-                            class: recip.status.class,
-                            subject: recip.status.subject,
-                            detail: recip.status.detail,
-                        };
-
-                        let (code, content) = match &recip.diagnostic_code {
-                            Some(diag) if diag.diagnostic_type == "smtp" => {
-                                if let Some((code, content)) = diag.diagnostic.split_once(' ') {
-                                    if let Ok(code) = code.parse() {
-                                        (code, content.to_string())
-                                    } else {
-                                        (550, diag.diagnostic.to_string())
-                                    }
-                                } else {
-                                    (550, diag.diagnostic.to_string())
-                                }
-                            }
-                            _ => (550, "".to_string()),
-                        };
-
                         let record = JsonLogRecord {
                             kind: RecordType::OOB,
                             id: msg.id().to_string(),
@@ -220,12 +301,7 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
                                     .map(|a| a.addr)
                                     .unwrap_or_else(|| Ipv4Addr::UNSPECIFIED.into()),
                             }),
-                            response: Response {
-                                code,
-                                enhanced_code: Some(enhanced_code),
-                                content,
-                                command: None,
-                            },
+                            response: oob_response(recip),
                             timestamp: recip.last_attempt_date.unwrap_or_else(|| Utc::now()),
                             created: msg.id().created(),
                             num_attempts: 0,
@@ -233,6 +309,7 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
                             egress_source: None,
                             bounce_classification: BounceClass::default(),
                             feedback_report: None,
+                            original_message_id: report.original_message_id.clone(),
                             headers: headers.clone(),
                             meta: meta.clone(),
                             delivery_protocol: None,
@@ -1,4 +1,4 @@
-use crate::logging::{LogCommand, LogRecordParams};
+use crate::logging::{default_true, resolve_fields, select_fields, LogCommand, LogRecordParams};
 use anyhow::Context;
 use async_channel::Receiver;
 use chrono::Utc;
@@ -53,6 +53,11 @@ pub struct LogFileParams {
     #[serde(default)]
     pub filter_event: Option<String>,
 
+    /// Whether to log records for messages injected from
+    /// a seed list
+    #[serde(default = "default_true")]
+    pub include_seeds: bool,
+
     #[serde(default)]
     pub min_free_space: MinFree,
     #[serde(default)]
@@ -321,6 +326,12 @@ impl LogThreadState {
                 Self::resolve_template(&self.params, &self.template_engine, record.kind)
             {
                 template.render_to_write(&record, &mut record_text)?;
+            } else if let Some(fields) = resolve_fields(&self.params.per_record, record.kind) {
+                serde_json::to_writer(
+                    &mut record_text,
+                    &select_fields(&serde_json::to_value(&record)?, fields)?,
+                )
+                .context("serializing record")?;
             } else {
                 serde_json::to_writer(&mut record_text, &record).context("serializing record")?;
             }
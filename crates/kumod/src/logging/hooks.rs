@@ -1,5 +1,5 @@
 use crate::logging::files::LogFileParams;
-use crate::logging::{LogCommand, LogRecordParams, LOGGING_RUNTIME};
+use crate::logging::{resolve_fields, select_fields, LogCommand, LogRecordParams, LOGGING_RUNTIME};
 use crate::queue::QueueManager;
 use anyhow::Context;
 use async_channel::Receiver;
@@ -52,6 +52,11 @@ pub struct LogHookParams {
 
     #[serde(default)]
     pub deferred_spool: bool,
+
+    /// Whether to log records for messages injected from
+    /// a seed list
+    #[serde(default)]
+    pub include_seeds: bool,
 }
 
 pub struct LogHookState {
@@ -142,6 +147,12 @@ impl LogHookState {
             Self::resolve_template(&self.params, &self.template_engine, record.kind)
         {
             template.render_to_write(&record, &mut record_text)?;
+        } else if let Some(fields) = resolve_fields(&self.params.per_record, record.kind) {
+            serde_json::to_writer(
+                &mut record_text,
+                &select_fields(&serde_json::to_value(&record)?, fields)?,
+            )
+            .context("serializing record")?;
         } else {
             serde_json::to_writer(&mut record_text, &record).context("serializing record")?;
         }
@@ -8,8 +8,6 @@ pub use kumo_log_types::*;
 use kumo_server_common::disk_space::MonitoredPath;
 use kumo_server_runtime::Runtime;
 use message::Message;
-use minijinja::Environment;
-use minijinja_contrib::add_to_environment;
 use mlua::{Lua, Value as LuaValue};
 use once_cell::sync::Lazy;
 use parking_lot::FairMutex as Mutex;
@@ -75,15 +73,65 @@ pub struct LogRecordParams {
     #[serde(default)]
     pub template: Option<String>,
 
+    /// Instead of logging the complete json object, log an object
+    /// holding just these fields. Nested fields can be selected
+    /// using a dotted path such as `response.code`.
+    /// Ignored when template is set.
+    #[serde(default)]
+    pub fields: Option<Vec<String>>,
+
     /// Written to the start of each newly created log file segment
     #[serde(default)]
     pub segment_header: String,
 }
 
-fn default_true() -> bool {
+pub(crate) fn default_true() -> bool {
     true
 }
 
+/// Returns the field selection that applies to records of type kind
+pub(crate) fn resolve_fields(
+    per_record: &HashMap<RecordType, LogRecordParams>,
+    kind: RecordType,
+) -> Option<&[String]> {
+    per_record
+        .get(&kind)
+        .or_else(|| per_record.get(&RecordType::Any))
+        .and_then(|pr| pr.fields.as_deref())
+}
+
+/// Produce an object holding just the specified fields of record.
+/// Fields that are not present in the record are set to null, so
+/// that each line has the same layout.
+pub(crate) fn select_fields(record: &Value, fields: &[String]) -> anyhow::Result<Value> {
+    let mut result = Value::Object(Default::default());
+
+    for field in fields {
+        let value = field
+            .split('.')
+            .try_fold(record, |value, name| value.get(name))
+            .cloned()
+            .unwrap_or(Value::Null);
+
+        let mut target = &mut result;
+        let mut path = field.split('.').peekable();
+        while let Some(name) = path.next() {
+            let Value::Object(map) = target else {
+                anyhow::bail!("field {field} conflicts with another selected field");
+            };
+            if path.peek().is_none() {
+                map.insert(name.to_string(), value);
+                break;
+            }
+            target = map
+                .entry(name.to_string())
+                .or_insert_with(|| Value::Object(Default::default()));
+        }
+    }
+
+    Ok(result)
+}
+
 #[derive(Debug)]
 pub(crate) enum LogCommand {
     Record(JsonLogRecord),
@@ -97,6 +145,7 @@ pub struct Logger {
     headers: Vec<String>,
     enabled: HashMap<RecordType, bool>,
     filter_event: Option<String>,
+    include_seeds: bool,
     hook_name: Option<String>,
     #[allow(unused)]
     name: String,
@@ -121,8 +170,7 @@ impl Logger {
             );
         }
 
-        let mut template_engine = Environment::new();
-        add_to_environment(&mut template_engine);
+        let mut template_engine = mod_template::new_environment();
 
         for (kind, per_rec) in &params.per_record {
             if let Some(template_source) = &per_rec.template {
@@ -144,6 +192,7 @@ impl Logger {
         let headers = params.headers.clone();
         let meta = params.meta.clone();
         let hook_name = params.name.to_string();
+        let include_seeds = params.include_seeds;
         let name = format!("hook-{hook_name}");
         let (sender, receiver) = async_channel::bounded(params.back_pressure);
 
@@ -167,6 +216,7 @@ impl Logger {
             headers,
             enabled,
             filter_event: None,
+            include_seeds,
             hook_name: Some(hook_name),
             name,
             submit_latency,
@@ -177,8 +227,7 @@ impl Logger {
     }
 
     pub async fn init(params: LogFileParams) -> anyhow::Result<()> {
-        let mut template_engine = Environment::new();
-        add_to_environment(&mut template_engine);
+        let mut template_engine = mod_template::new_environment();
 
         for (kind, per_rec) in &params.per_record {
             if let Some(template_source) = &per_rec.template {
@@ -204,6 +253,7 @@ impl Logger {
         let meta = params.meta.clone();
         let (sender, receiver) = async_channel::bounded(params.back_pressure);
         let filter_event = params.filter_event.clone();
+        let include_seeds = params.include_seeds;
         let name = format!("dir-{}", params.log_dir.display());
 
         MonitoredPath {
@@ -238,6 +288,7 @@ impl Logger {
             headers,
             enabled,
             filter_event,
+            include_seeds,
             hook_name: None,
             name,
             submit_latency,
@@ -395,3 +446,33 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn field_selection() {
+        let record = json!({
+            "id": "abc",
+            "sender": "sender@example.com",
+            "response": {"code": 250, "content": "OK"},
+        });
+        let fields: Vec<String> = ["id", "response.code", "meta.tenant"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            select_fields(&record, &fields).unwrap(),
+            json!({
+                "id": "abc",
+                "response": {"code": 250},
+                "meta": {"tenant": null},
+            })
+        );
+
+        let conflicting = vec!["id".to_string(), "id.nested".to_string()];
+        assert!(select_fields(&record, &conflicting).is_err());
+    }
+}
@@ -47,6 +47,7 @@ pub async fn log_rejection(args: LogRejection) {
             egress_source: None,
             bounce_classification: BounceClass::default(),
             feedback_report: None,
+            original_message_id: None,
             headers: HashMap::new(),
             meta,
             delivery_protocol: None,
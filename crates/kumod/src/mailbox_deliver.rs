@@ -0,0 +1,581 @@
+//! Final delivery of messages into local mailboxes, stored either in
+//! maildir or mbox format.
+//!
+//! Each message is written such that it is durable before we report
+//! success: maildir messages are written and fsync'd in `tmp` before
+//! being renamed into `new`, and mbox messages are appended and fsync'd
+//! while holding an exclusive flock on the mailbox file. A failed mbox
+//! append is truncated away so that a partial message is never left
+//! behind for the MUA to find.
+use message::EnvelopeAddress;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use rfc5321::{EnhancedStatusCode, Response};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static MAILDIR_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MailboxFormat {
+    #[default]
+    Maildir,
+    Mbox,
+}
+
+impl MailboxFormat {
+    pub fn metrics_protocol_name(&self) -> &'static str {
+        match self {
+            Self::Maildir => "maildir",
+            Self::Mbox => "mbox",
+        }
+    }
+
+    pub fn delivery_protocol(&self) -> &'static str {
+        match self {
+            Self::Maildir => "Maildir",
+            Self::Mbox => "Mbox",
+        }
+    }
+}
+
+/// The path to a mailbox, with `%u`, `%d` and `%a` placeholders
+/// that are replaced by the local part, domain and full address
+/// of the recipient, respectively. `%%` produces a literal `%`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct MailboxPathTemplate(String);
+
+impl TryFrom<String> for MailboxPathTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, String> {
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            if c == '%' {
+                match chars.next() {
+                    Some('u' | 'd' | 'a' | '%') => {}
+                    Some(other) => {
+                        return Err(format!(
+                            "invalid placeholder %{other} in mailbox path {template}"
+                        ))
+                    }
+                    None => return Err(format!("mailbox path {template} ends with %")),
+                }
+            }
+        }
+        Ok(Self(template))
+    }
+}
+
+impl From<MailboxPathTemplate> for String {
+    fn from(template: MailboxPathTemplate) -> String {
+        template.0
+    }
+}
+
+impl std::fmt::Display for MailboxPathTemplate {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(fmt)
+    }
+}
+
+/// Returns the lowercased text if it is safe to use as a path
+/// component, or None if it could be used to escape the mailbox
+/// directory or to address a hidden file or maildir++ folder
+fn path_component(text: &str) -> Option<String> {
+    if text.is_empty() || text.starts_with('.') || text.contains(['/', '\0']) {
+        return None;
+    }
+    Some(text.to_lowercase())
+}
+
+impl MailboxPathTemplate {
+    /// Returns a template that expands to path, unchanged
+    pub fn literal(path: &Path) -> Self {
+        Self(path.display().to_string().replace('%', "%%"))
+    }
+
+    pub fn expand(&self, recipient: &EnvelopeAddress) -> Result<PathBuf, DeliveryError> {
+        let invalid = || DeliveryError::InvalidAddress(recipient.to_string());
+        let mut result = String::new();
+        let mut chars = self.0.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                result.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('u') => {
+                    result.push_str(&path_component(recipient.user()).ok_or_else(invalid)?)
+                }
+                Some('d') => {
+                    result.push_str(&path_component(recipient.domain()).ok_or_else(invalid)?)
+                }
+                Some('a') => {
+                    let user = path_component(recipient.user()).ok_or_else(invalid)?;
+                    let domain = path_component(recipient.domain()).ok_or_else(invalid)?;
+                    result.push_str(&format!("{user}@{domain}"));
+                }
+                // Placeholders were validated when the template was parsed
+                _ => result.push('%'),
+            }
+        }
+        Ok(result.into())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MailboxDeliveryProtocol {
+    /// Where to store the messages for each recipient
+    pub path: MailboxPathTemplate,
+    #[serde(default)]
+    pub format: MailboxFormat,
+    /// Deliveries that would cause the mailbox to exceed this
+    /// size, in bytes, are deferred
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    /// Deliveries to a maildir that already holds this many
+    /// messages are deferred
+    #[serde(default)]
+    pub quota_messages: Option<u64>,
+    /// Whether to fsync messages before reporting them as delivered
+    #[serde(default = "MailboxDeliveryProtocol::default_fsync")]
+    pub fsync: bool,
+}
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// The recipient cannot be mapped to a mailbox path
+    InvalidAddress(String),
+    /// Storing the message would exceed the quota of the mailbox
+    QuotaExceeded { path: PathBuf, reason: String },
+    Io {
+        context: String,
+        err: std::io::Error,
+    },
+}
+
+impl DeliveryError {
+    fn io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> Self {
+        let context = context.into();
+        move |err| Self::Io { context, err }
+    }
+
+    fn is_storage_full(err: &std::io::Error) -> bool {
+        matches!(
+            err.raw_os_error().map(Errno::from_raw),
+            Some(Errno::ENOSPC | Errno::EDQUOT)
+        )
+    }
+
+    /// Returns the response that is logged for this error.
+    /// Only an unusable address is a permanent failure.
+    pub fn response(&self) -> Response {
+        let (code, subject, detail, content) = match self {
+            Self::InvalidAddress(address) => {
+                (550, 1, 3, format!("cannot map {address} to a mailbox path"))
+            }
+            Self::QuotaExceeded { path, reason } => (
+                452,
+                2,
+                2,
+                format!("mailbox {} is full: {reason}", path.display()),
+            ),
+            Self::Io { context, err } if Self::is_storage_full(err) => {
+                (452, 3, 1, format!("{context}: {err:#}"))
+            }
+            Self::Io { context, err } => (451, 3, 0, format!("{context}: {err:#}")),
+        };
+        Response {
+            code,
+            enhanced_code: Some(EnhancedStatusCode {
+                class: (code / 100) as u8,
+                subject,
+                detail,
+            }),
+            content,
+            command: None,
+        }
+    }
+}
+
+impl MailboxDeliveryProtocol {
+    fn default_fsync() -> bool {
+        true
+    }
+
+    /// The configuration used for the `maildir_path` protocol
+    pub fn maildir(path: &Path) -> Self {
+        Self {
+            path: MailboxPathTemplate::literal(path),
+            format: MailboxFormat::Maildir,
+            quota_bytes: None,
+            quota_messages: None,
+            fsync: true,
+        }
+    }
+
+    /// Stores data in the mailbox of recipient, returning the
+    /// response that describes the outcome
+    pub fn deliver(
+        &self,
+        sender: &EnvelopeAddress,
+        recipient: &EnvelopeAddress,
+        data: &[u8],
+    ) -> Response {
+        let result = self
+            .path
+            .expand(recipient)
+            .and_then(|path| match self.format {
+                MailboxFormat::Maildir => self.deliver_maildir(&path, data),
+                MailboxFormat::Mbox => self.deliver_mbox(&path, sender, data),
+            });
+        match result {
+            Ok(content) => Response {
+                code: 250,
+                enhanced_code: Some(EnhancedStatusCode {
+                    class: 2,
+                    subject: 0,
+                    detail: 0,
+                }),
+                content,
+                command: None,
+            },
+            Err(err) => err.response(),
+        }
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<(), DeliveryError> {
+        if self.fsync {
+            File::open(dir)
+                .and_then(|d| d.sync_all())
+                .map_err(DeliveryError::io(format!("syncing {}", dir.display())))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the total size and number of the messages in the maildir
+    fn maildir_usage(dir: &Path) -> std::io::Result<(u64, u64)> {
+        let mut bytes = 0;
+        let mut count = 0;
+        for sub in ["new", "cur"] {
+            for entry in std::fs::read_dir(dir.join(sub))? {
+                let meta = entry?.metadata()?;
+                if meta.is_file() {
+                    bytes += meta.len();
+                    count += 1;
+                }
+            }
+        }
+        Ok((bytes, count))
+    }
+
+    fn check_maildir_quota(&self, dir: &Path, size: u64) -> Result<(), DeliveryError> {
+        if self.quota_bytes.is_none() && self.quota_messages.is_none() {
+            return Ok(());
+        }
+        let (bytes, count) = Self::maildir_usage(dir).map_err(DeliveryError::io(format!(
+            "computing usage of maildir {}",
+            dir.display()
+        )))?;
+        let reason = match (self.quota_bytes, self.quota_messages) {
+            (Some(limit), _) if bytes + size > limit => {
+                format!("{bytes} bytes in use, {size} more would exceed quota of {limit}")
+            }
+            (_, Some(limit)) if count >= limit => {
+                format!("{count} messages in use, quota is {limit}")
+            }
+            _ => return Ok(()),
+        };
+        Err(DeliveryError::QuotaExceeded {
+            path: dir.to_path_buf(),
+            reason,
+        })
+    }
+
+    fn deliver_maildir(&self, dir: &Path, data: &[u8]) -> Result<String, DeliveryError> {
+        for sub in ["tmp", "new", "cur"] {
+            let path = dir.join(sub);
+            std::fs::create_dir_all(&path)
+                .map_err(DeliveryError::io(format!("creating {}", path.display())))?;
+        }
+
+        self.check_maildir_quota(dir, data.len() as u64)?;
+
+        let name = maildir_unique_name(data.len());
+        let tmp = dir.join("tmp").join(&name);
+        let new = dir.join("new").join(&name);
+
+        let write = || -> std::io::Result<()> {
+            let mut file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+            file.write_all(data)?;
+            if self.fsync {
+                file.sync_all()?;
+            }
+            Ok(())
+        };
+        if let Err(err) = write() {
+            std::fs::remove_file(&tmp).ok();
+            return Err(DeliveryError::io(format!("writing {}", tmp.display()))(err));
+        }
+
+        if let Err(err) = std::fs::rename(&tmp, &new) {
+            std::fs::remove_file(&tmp).ok();
+            return Err(DeliveryError::io(format!(
+                "renaming {} to {}",
+                tmp.display(),
+                new.display()
+            ))(err));
+        }
+        self.sync_dir(&dir.join("new"))?;
+
+        Ok(format!("wrote to maildir with id={name}"))
+    }
+
+    fn deliver_mbox(
+        &self,
+        path: &Path,
+        sender: &EnvelopeAddress,
+        data: &[u8],
+    ) -> Result<String, DeliveryError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(DeliveryError::io(format!("creating {}", parent.display())))?;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(DeliveryError::io(format!("opening {}", path.display())))?;
+        let mut file = Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, errno)| {
+            DeliveryError::io(format!("locking {}", path.display()))(errno.into())
+        })?;
+
+        let len = file
+            .metadata()
+            .map_err(DeliveryError::io(format!(
+                "reading size of {}",
+                path.display()
+            )))?
+            .len();
+
+        let entry = mbox_entry(sender, data, SystemTime::now());
+        if let Some(limit) = self.quota_bytes {
+            if len + entry.len() as u64 > limit {
+                return Err(DeliveryError::QuotaExceeded {
+                    path: path.to_path_buf(),
+                    reason: format!(
+                        "{len} bytes in use, {} more would exceed quota of {limit}",
+                        entry.len()
+                    ),
+                });
+            }
+        }
+
+        let mut append = || -> std::io::Result<()> {
+            use std::io::{Seek, SeekFrom};
+            file.seek(SeekFrom::Start(len))?;
+            file.write_all(&entry)?;
+            if self.fsync {
+                file.sync_all()?;
+            }
+            Ok(())
+        };
+        if let Err(err) = append() {
+            // Don't leave a partial message in the mailbox
+            file.set_len(len).ok();
+            return Err(DeliveryError::io(format!(
+                "appending to {}",
+                path.display()
+            ))(err));
+        }
+
+        Ok(format!(
+            "appended {} bytes to mbox {}",
+            entry.len(),
+            path.display()
+        ))
+    }
+}
+
+/// Produces a name that is unique to this delivery, following
+/// the conventions described in <https://cr.yp.to/proto/maildir.html>
+fn maildir_unique_name(size: usize) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let host = gethostname::gethostname()
+        .to_string_lossy()
+        .replace('/', "\\057")
+        .replace(':', "\\072");
+    format!(
+        "{}.M{}P{}Q{}.{host},S={size}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        MAILDIR_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Formats a message for appending to an mbox. Uses the mboxrd
+/// convention of quoting any line that matches `^>*From ` with an
+/// additional `>`, so that the quoting can be reversed unambiguously.
+/// Line endings are normalized to LF.
+fn mbox_entry(sender: &EnvelopeAddress, data: &[u8], when: SystemTime) -> Vec<u8> {
+    let sender = match sender.to_string() {
+        s if s.is_empty() => "MAILER-DAEMON".to_string(),
+        s => s,
+    };
+    let when: chrono::DateTime<chrono::Utc> = when.into();
+
+    let mut entry = Vec::with_capacity(data.len() + 128);
+    entry.extend_from_slice(
+        format!("From {sender} {}\n", when.format("%a %b %e %H:%M:%S %Y")).as_bytes(),
+    );
+
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        let line = line
+            .strip_suffix(b"\n")
+            .map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+            .unwrap_or(line);
+        let unquoted = &line[line.iter().take_while(|&&b| b == b'>').count()..];
+        if unquoted.starts_with(b"From ") {
+            entry.push(b'>');
+        }
+        entry.extend_from_slice(line);
+        entry.push(b'\n');
+    }
+
+    // Messages are separated by a blank line
+    entry.push(b'\n');
+    entry
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(s: &str) -> EnvelopeAddress {
+        EnvelopeAddress::parse(s).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "kumod-mailbox-{name}-{}-{}",
+            std::process::id(),
+            MAILDIR_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn path_template() {
+        let template =
+            MailboxPathTemplate::try_from("/var/mail/%d/%u/100%%/%a".to_string()).unwrap();
+        assert_eq!(
+            template.expand(&addr("Joe@Example.com")).unwrap(),
+            PathBuf::from("/var/mail/example.com/joe/100%/joe@example.com")
+        );
+
+        for bad in [
+            "../x@example.com",
+            ".Trash@example.com",
+            "joe@..",
+            "a/b@example.com",
+        ] {
+            let err = template.expand(&addr(bad)).unwrap_err();
+            assert_eq!(err.response().code, 550, "{bad}");
+        }
+
+        assert!(MailboxPathTemplate::try_from("/var/mail/%x".to_string()).is_err());
+        assert!(MailboxPathTemplate::try_from("/var/mail/%".to_string()).is_err());
+
+        let literal = MailboxPathTemplate::literal(Path::new("/tmp/100%u"));
+        assert_eq!(
+            literal.expand(&addr("joe@example.com")).unwrap(),
+            PathBuf::from("/tmp/100%u")
+        );
+    }
+
+    #[test]
+    fn mbox_quoting() {
+        let entry = mbox_entry(
+            &EnvelopeAddress::null_sender(),
+            b"Subject: hi\r\n\r\nFrom here\r\n>From there\r\nFromage\r\nno newline",
+            UNIX_EPOCH,
+        );
+        k9::assert_equal!(
+            String::from_utf8(entry).unwrap(),
+            "From MAILER-DAEMON Thu Jan  1 00:00:00 1970\n\
+             Subject: hi\n\
+             \n\
+             >From here\n\
+             >>From there\n\
+             Fromage\n\
+             no newline\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn maildir_delivery_and_quota() {
+        let dir = temp_dir("maildir");
+        let mut proto = MailboxDeliveryProtocol::maildir(&dir);
+        proto.quota_messages = Some(2);
+
+        let sender = addr("sender@example.com");
+        let recip = addr("joe@example.com");
+        for _ in 0..2 {
+            assert_eq!(
+                proto
+                    .deliver(&sender, &recip, b"Subject: hi\r\n\r\nhello\r\n")
+                    .code,
+                250
+            );
+        }
+        let response = proto.deliver(&sender, &recip, b"Subject: hi\r\n\r\nhello\r\n");
+        assert_eq!(response.code, 452);
+
+        let md = maildir::Maildir::from(dir.clone());
+        assert_eq!(md.count_new(), 2);
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn mbox_delivery_and_quota() {
+        let dir = temp_dir("mbox");
+        let proto = MailboxDeliveryProtocol {
+            path: MailboxPathTemplate::try_from(format!("{}/%u", dir.display())).unwrap(),
+            format: MailboxFormat::Mbox,
+            quota_bytes: Some(150),
+            quota_messages: None,
+            fsync: true,
+        };
+
+        let sender = addr("sender@example.com");
+        let recip = addr("joe@example.com");
+        let data = b"Subject: hi\r\n\r\nhello\r\n";
+        assert_eq!(proto.deliver(&sender, &recip, data).code, 250);
+        assert_eq!(proto.deliver(&sender, &recip, data).code, 250);
+        assert_eq!(proto.deliver(&sender, &recip, data).code, 452);
+
+        let mbox = std::fs::read_to_string(dir.join("joe")).unwrap();
+        assert_eq!(mbox.matches("\nFrom sender@example.com ").count(), 1);
+        assert!(mbox.starts_with("From sender@example.com "));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
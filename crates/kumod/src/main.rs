@@ -3,7 +3,7 @@ use clap::Parser;
 use config::CallbackSignature;
 use kumo_server_common::diagnostic_logging::{DiagnosticFormat, LoggingConfig};
 use kumo_server_common::start::StartConfig;
-use kumo_server_lifecycle::LifeCycle;
+use kumo_server_lifecycle::{set_sighup_reloads_config, LifeCycle};
 use nix::sys::resource::{getrlimit, setrlimit, Resource};
 use nix::unistd::{Uid, User};
 use once_cell::sync::Lazy;
@@ -17,18 +17,41 @@ pub static VALIDATE_SIG: Lazy<CallbackSignature<(), ()>> =
     Lazy::new(|| CallbackSignature::new_with_multiple("validate_config"));
 
 mod accounting;
+mod amqp_deliver;
+mod bounce_alias;
+mod campaign;
+mod config_schema;
+mod connection_filter;
+mod delivery_history;
 mod delivery_metrics;
+mod drain;
 mod egress_source;
+mod ehlo_cache;
+mod helo_validation;
+mod http_api_deliver;
 mod http_server;
+mod kafka_deliver;
 mod logging;
 mod lua_deliver;
+mod mailbox_deliver;
+mod message_tracing;
 mod metrics_helper;
 mod mod_kumo;
 mod queue;
+mod queue_spill;
 mod ready_queue;
+mod reputation;
+mod rspamd;
+mod seeds;
+mod smtp_connection_pool;
 mod smtp_dispatcher;
 mod smtp_server;
+mod source_exclusion;
+mod source_health;
 mod spool;
+mod suppression;
+mod traffic_shaping;
+mod warmup;
 
 /// KumoMTA Daemon.
 ///
@@ -76,6 +99,12 @@ struct Opt {
     #[arg(long)]
     dump_openapi_spec: bool,
 
+    /// Instead of running the daemon, output the schema of the
+    /// structures accepted by the lua configuration functions
+    /// as json to stdout.
+    #[arg(long)]
+    dump_config_schema: bool,
+
     /// Required if started as root; specifies which user to run as once
     /// privileges have been dropped.
     ///
@@ -148,6 +177,11 @@ fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
+    if opts.dump_config_schema {
+        println!("{}", crate::config_schema::dump()?);
+        return Ok(());
+    }
+
     // This MUST happen before we spawn any threads,
     // which is why we manually set up the tokio
     // runtime after we've called it.
@@ -244,12 +278,15 @@ fn perform_init(opts: Opt) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>>
 
             LifeCycle::request_shutdown().await;
         } else {
+            crate::campaign::load().await.context("load campaigns")?;
+
             crate::spool::SpoolManager::get()
                 .start_spool()
                 .await
                 .context("start_spool")?;
 
             config::epoch::start_monitor();
+            set_sighup_reloads_config();
         }
 
         Ok(())
@@ -292,6 +329,9 @@ async fn run(opts: Opt) -> anyhow::Result<()> {
     if let Err(err) = crate::accounting::ACCT.flush() {
         tracing::error!("error flushing ACCT: {err:#}");
     }
+    if let Err(err) = crate::campaign::flush() {
+        tracing::error!("error flushing campaigns: {err:#}");
+    }
 
     res
 }
@@ -0,0 +1,131 @@
+//! Produces OpenTelemetry spans for the stages of the lifecycle of
+//! a message: reception, spooling, scheduling, readiness for delivery,
+//! delivery attempts and the logged dispositions.
+//!
+//! Rather than holding a single span open across the whole life of a
+//! message, each stage produces its own span.  The stages in which the
+//! message waits in a queue are held open in `WAITING` until the next
+//! stage begins, so that their spans cover the time spent waiting.
+//! All of the spans for a message use the message id as their trace id,
+//! so that the complete history of a message can be found in the tracing
+//! backend by searching for that id.
+use kumo_server_common::otel;
+use message::Message;
+use once_cell::sync::Lazy;
+use opentelemetry::global::BoxedSpan;
+use opentelemetry::trace::{
+    Span, SpanContext, SpanId, Status, TraceContextExt, TraceFlags, TraceId, TraceState, Tracer,
+};
+use opentelemetry::{Context, KeyValue, Value};
+use parking_lot::Mutex;
+use spool::SpoolId;
+use std::collections::HashMap;
+
+/// The spans of the messages that are currently waiting in a
+/// scheduled or ready queue. Only sampled spans are held here.
+static WAITING: Lazy<Mutex<HashMap<SpoolId, BoxedSpan>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A span describing one stage in the lifecycle of a message.
+/// The span ends when this is dropped.
+pub struct StageSpan(Option<BoxedSpan>);
+
+impl StageSpan {
+    /// Starts a span for stage. If no exporter has been configured,
+    /// this is a no-op.
+    pub fn start(id: &SpoolId, stage: &'static str) -> Self {
+        if !otel::is_enabled() {
+            return Self(None);
+        }
+        let tracer = opentelemetry::global::tracer("kumod");
+        let parent = Context::new().with_remote_span_context(message_span_context(id));
+        let mut span = tracer.start_with_context(stage, &parent);
+        if !span.is_recording() {
+            // Not sampled
+            return Self(None);
+        }
+        span.set_attribute(KeyValue::new("message.id", id.to_string()));
+        Self(Some(span))
+    }
+
+    /// Keeps the span open while the message waits in a queue, rather
+    /// than ending it when this is dropped. The span ends when the next
+    /// waiting stage begins, or end_waiting is called for the message.
+    pub fn wait(&mut self, id: &SpoolId) {
+        if let Some(span) = self.0.take() {
+            let prior = WAITING.lock().insert(*id, span);
+            drop(prior);
+        }
+    }
+
+    /// Returns false if the span is a no-op, so that callers can
+    /// avoid computing attributes that would be discarded
+    pub fn is_recording(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if let Some(span) = &mut self.0 {
+            span.set_attribute(KeyValue::new(key, value));
+        }
+    }
+
+    pub fn set_error(&mut self, err: &anyhow::Error) {
+        if let Some(span) = &mut self.0 {
+            span.set_status(Status::error(format!("{err:#}")));
+        }
+    }
+}
+
+/// Ends the span of the stage in which the message was waiting, if any.
+/// This is called when the message is taken from its ready queue for
+/// delivery, and when it reaches a final disposition.
+pub fn end_waiting(id: &SpoolId) {
+    if !otel::is_enabled() {
+        return;
+    }
+    let span = WAITING.lock().remove(id);
+    drop(span);
+}
+
+/// Saves msg to the spool, recording a `spool` span
+pub async fn save_to_spool(msg: &Message) -> anyhow::Result<()> {
+    let mut span = StageSpan::start(msg.id(), "spool");
+    let result = msg.save().await;
+    if let Err(err) = &result {
+        span.set_error(err);
+    }
+    result
+}
+
+/// Produces the context of the notional root span of the trace for
+/// a message. That span is never itself exported; it exists only so
+/// that the spans for each stage share the same trace id.
+fn message_span_context(id: &SpoolId) -> SpanContext {
+    let bytes = id.as_bytes();
+    let mut span_id = [0u8; 8];
+    span_id.copy_from_slice(&bytes[8..]);
+    if span_id == [0u8; 8] {
+        // An all-zero span id is invalid
+        span_id[7] = 1;
+    }
+    SpanContext::new(
+        TraceId::from_bytes(*bytes),
+        SpanId::from_bytes(span_id),
+        TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trace_id_is_message_id() {
+        let id = SpoolId::new();
+        let ctx = message_span_context(&id);
+        assert!(ctx.is_valid());
+        assert_eq!(ctx.trace_id().to_string(), id.to_string());
+    }
+}
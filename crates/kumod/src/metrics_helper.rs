@@ -1,6 +1,10 @@
+use kumo_log_types::RecordType;
 use kumo_prometheus::{label_key, AtomicCounter, CounterRegistry, PruningCounterRegistry};
+use message::message::QueueNameComponents;
+use message::Message;
 use once_cell::sync::Lazy;
 use prometheus::{Histogram, HistogramVec, IntCounter};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 label_key! {
     pub struct ServiceKey {
@@ -25,6 +29,18 @@ label_key! {
         pub pool: String,
     }
 }
+label_key! {
+    pub struct QueueComponentsKey {
+        pub domain: String,
+        pub tenant: String,
+        pub campaign: String,
+    }
+}
+
+/// The label value used for all of the components of the queue
+/// once the cardinality limit has been reached
+const OVERFLOW_LABEL: &str = "__overflow__";
+static QUEUE_METRICS_CARDINALITY_LIMIT: AtomicUsize = AtomicUsize::new(10_000);
 
 pub static CONN_GAUGE: Lazy<PruningCounterRegistry<ServiceKey>> = Lazy::new(|| {
     PruningCounterRegistry::register_gauge("connection_count", "number of active connections")
@@ -48,6 +64,12 @@ pub static CONN_DENIED: Lazy<PruningCounterRegistry<ServiceKey>> = Lazy::new(||
         "total number of connections rejected due to load shedding or concurrency limits",
     )
 });
+pub static CONN_FILTERED: Lazy<PruningCounterRegistry<ServiceKey>> = Lazy::new(|| {
+    PruningCounterRegistry::register(
+        "total_connections_filtered",
+        "total number of connections dropped by the connection filter",
+    )
+});
 
 pub static TOTAL_CONN: Lazy<PruningCounterRegistry<ServiceKey>> = Lazy::new(|| {
     PruningCounterRegistry::register(
@@ -122,6 +144,35 @@ pub static TOTAL_MSGS_FAIL_BY_PROVIDER_AND_SOURCE: Lazy<
     )
 });
 
+pub static TOTAL_MSGS_RECVD_BY_QUEUE_COMPONENTS: Lazy<CounterRegistry<QueueComponentsKey>> =
+    Lazy::new(|| {
+        CounterRegistry::register(
+            "total_messages_received_by_queue_components",
+            "total number of messages ever received",
+        )
+    });
+pub static TOTAL_MSGS_DELIVERED_BY_QUEUE_COMPONENTS: Lazy<CounterRegistry<QueueComponentsKey>> =
+    Lazy::new(|| {
+        CounterRegistry::register(
+            "total_messages_delivered_by_queue_components",
+            "total number of messages ever delivered",
+        )
+    });
+pub static TOTAL_MSGS_TRANSFAIL_BY_QUEUE_COMPONENTS: Lazy<CounterRegistry<QueueComponentsKey>> =
+    Lazy::new(|| {
+        CounterRegistry::register(
+            "total_messages_transfail_by_queue_components",
+            "total number of message delivery attempts that transiently failed",
+        )
+    });
+pub static TOTAL_MSGS_FAIL_BY_QUEUE_COMPONENTS: Lazy<CounterRegistry<QueueComponentsKey>> =
+    Lazy::new(|| {
+        CounterRegistry::register(
+            "total_messages_fail_by_queue_components",
+            "total number of message delivery attempts that permanently failed",
+        )
+    });
+
 pub static READY_COUNT_GAUGE: Lazy<PruningCounterRegistry<ServiceKey>> = Lazy::new(|| {
     PruningCounterRegistry::register_gauge("ready_count", "number of messages in the ready queue")
 });
@@ -181,6 +232,11 @@ pub fn connection_denied_for_service(service: &str) -> AtomicCounter {
     CONN_DENIED.get_or_create(&service as &dyn ServiceKeyTrait)
 }
 
+pub fn connection_filtered_for_service(service: &str) -> AtomicCounter {
+    let service = BorrowedServiceKey { service };
+    CONN_FILTERED.get_or_create(&service as &dyn ServiceKeyTrait)
+}
+
 pub fn ready_full_counter_for_service(service: &str) -> AtomicCounter {
     let service = BorrowedServiceKey { service };
     READY_FULL_COUNTER.get_or_create(&service as &dyn ServiceKeyTrait)
@@ -220,3 +276,40 @@ pub fn total_msgs_fail_for_service(service: &str) -> AtomicCounter {
     let service = BorrowedServiceKey { service };
     TOTAL_MSGS_FAIL.get_or_create(&service as &dyn ServiceKeyTrait)
 }
+
+pub fn set_queue_metrics_cardinality_limit(limit: usize) {
+    QUEUE_METRICS_CARDINALITY_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Count a disposition against the domain, tenant and campaign
+/// of the queue of msg
+pub fn record_disposition_by_queue_components(kind: RecordType, msg: &Message) {
+    let registry = match kind {
+        RecordType::Reception => &*TOTAL_MSGS_RECVD_BY_QUEUE_COMPONENTS,
+        RecordType::Delivery => &*TOTAL_MSGS_DELIVERED_BY_QUEUE_COMPONENTS,
+        RecordType::TransientFailure => &*TOTAL_MSGS_TRANSFAIL_BY_QUEUE_COMPONENTS,
+        RecordType::Bounce => &*TOTAL_MSGS_FAIL_BY_QUEUE_COMPONENTS,
+        _ => return,
+    };
+    let Ok(queue_name) = msg.get_queue_name() else {
+        return;
+    };
+    let components = QueueNameComponents::parse(&queue_name);
+    let key = BorrowedQueueComponentsKey {
+        domain: components.domain,
+        tenant: components.tenant.unwrap_or(""),
+        campaign: components.campaign.unwrap_or(""),
+    };
+    let overflow = BorrowedQueueComponentsKey {
+        domain: OVERFLOW_LABEL,
+        tenant: OVERFLOW_LABEL,
+        campaign: OVERFLOW_LABEL,
+    };
+    registry
+        .get_or_create_capped(
+            &key as &dyn QueueComponentsKeyTrait,
+            &overflow as &dyn QueueComponentsKeyTrait,
+            QUEUE_METRICS_CARDINALITY_LIMIT.load(Ordering::Relaxed),
+        )
+        .inc();
+}
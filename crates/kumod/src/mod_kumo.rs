@@ -21,6 +21,19 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     crate::logging::hooks::SHOULD_ENQ_LOG_RECORD_SIG.register();
     crate::PRE_INIT_SIG.register();
     crate::VALIDATE_SIG.register();
+    crate::reputation::register(lua)?;
+    crate::delivery_history::register(lua)?;
+    crate::seeds::register(lua)?;
+    crate::warmup::register(lua)?;
+    crate::source_exclusion::register(lua)?;
+    crate::source_health::register(lua)?;
+    crate::traffic_shaping::register(lua)?;
+    crate::bounce_alias::register(lua)?;
+    crate::connection_filter::register(lua)?;
+    crate::suppression::register(lua)?;
+    crate::rspamd::register(lua)?;
+    crate::http_server::admin_bounce_v1::register(lua)?;
+    crate::http_server::admin_rebind_v1::register(lua)?;
     crate::http_server::admin_suspend_ready_q_v1::register(lua)?;
     crate::http_server::admin_suspend_v1::register(lua)?;
     crate::http_server::inject_v1::register(lua)?;
@@ -75,6 +88,14 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "set_simulation_enabled",
+        lua.create_function(move |_, enabled: bool| {
+            crate::http_server::admin_simulate_v1::set_simulation_enabled(enabled);
+            Ok(())
+        })?,
+    )?;
+
     kumo_mod.set(
         "set_smtpsrv_threads",
         lua.create_function(move |_, limit: usize| {
@@ -115,6 +136,14 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "set_queue_metrics_cardinality_limit",
+        lua.create_function(move |_, limit: usize| {
+            crate::metrics_helper::set_queue_metrics_cardinality_limit(limit);
+            Ok(())
+        })?,
+    )?;
+
     kumo_mod.set(
         "reject",
         lua.create_function(move |_lua, (code, message): (u16, String)| {
@@ -141,11 +170,17 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     kumo_mod.set(
         "invoke_get_egress_path_config",
         lua.create_async_function(
-            |lua, (routing_domain, egress_source, site_name): (String, String, String)| async move {
+            |lua,
+             (routing_domain, egress_source, site_name, shaping_tier): (
+                String,
+                String,
+                String,
+                Option<String>,
+            )| async move {
                 let path_config: EgressPathConfig = config::async_call_callback(
                     lua,
                     &GET_EGRESS_PATH_CONFIG_SIG,
-                    (routing_domain, egress_source, site_name),
+                    (routing_domain, egress_source, site_name, shaping_tier),
                 )
                 .await
                 .map_err(any_err)?;
@@ -187,6 +222,22 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "configure_campaign_db_path",
+        lua.create_function(|_lua, file_name: String| {
+            *crate::campaign::DB_PATH.lock() = file_name;
+            Ok(())
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_mta_sts_cache_path",
+        lua.create_function(|_lua, path: Option<String>| {
+            mta_sts::set_cache_path(path.map(Into::into));
+            Ok(())
+        })?,
+    )?;
+
     kumo_mod.set(
         "make_throttle",
         lua.create_function(move |_lua, (name, spec): (String, String)| {
@@ -1,35 +1,46 @@
+use crate::amqp_deliver::AmqpDeliveryProtocol;
 use crate::egress_source::{EgressPool, EgressPoolRoundRobin, RoundRobinResult};
+use crate::http_api_deliver::HttpApiDeliveryProtocol;
 use crate::http_server::admin_bounce_v1::AdminBounceEntry;
 use crate::http_server::admin_rebind_v1::AdminRebindEntry;
 use crate::http_server::admin_suspend_v1::AdminSuspendEntry;
 use crate::http_server::inject_v1::{make_generate_queue_config, GENERATOR_QUEUE_NAME};
+use crate::kafka_deliver::KafkaDeliveryProtocol;
 use crate::logging::disposition::{log_disposition, LogDisposition, RecordType};
 use crate::lua_deliver::LuaDeliveryProtocol;
+use crate::mailbox_deliver::MailboxDeliveryProtocol;
+use crate::message_tracing::{save_to_spool, StageSpan};
 use crate::metrics_helper::{
     BorrowedProviderAndPoolKey, BorrowedProviderKey, ProviderAndPoolKeyTrait, ProviderKeyTrait,
     QUEUED_COUNT_GAUGE_BY_PROVIDER, QUEUED_COUNT_GAUGE_BY_PROVIDER_AND_POOL,
 };
+use crate::queue_spill::{self, SpilledEntry};
 use crate::ready_queue::ReadyQueueManager;
 use crate::smtp_dispatcher::SmtpProtocol;
 use crate::spool::SpoolManager;
-use anyhow::Context;
 use chrono::{DateTime, Utc};
 use config::epoch::{get_current_epoch, ConfigEpoch};
 use config::{load_config, CallbackSignature, LuaConfig};
 use crossbeam_skiplist::SkipSet;
+use data_encoding::HEXLOWER;
+use dns_resolver::NxDomain;
 use kumo_api_types::egress_path::ConfigRefreshStrategy;
+use kumo_api_types::rebind::RebindV1Request;
+use kumo_api_types::{AgeHistogramBucket, ScheduledQueueV1Entry};
 use kumo_prometheus::{counter_bundle, label_key, AtomicCounter, PruningCounterRegistry};
 use kumo_server_common::config_handle::ConfigHandle;
 use kumo_server_lifecycle::{is_shutting_down, Activity, ShutdownSubcription};
 use kumo_server_runtime::{get_main_runtime, spawn, spawn_blocking_on, Runtime};
 use message::message::{QueueNameComponents, WeakMessage};
+use message::scheduling::{ScheduleRestriction, Scheduling};
 use message::Message;
 use mlua::prelude::*;
 use once_cell::sync::{Lazy, OnceCell};
 use parking_lot::FairMutex as StdMutex;
 use prometheus::{Histogram, IntCounter, IntGauge};
-use rfc5321::{EnhancedStatusCode, Response};
+use rfc5321::{DeliverByMode, EnhancedStatusCode, Response};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Once};
@@ -39,6 +50,7 @@ use throttle::{ThrottleResult, ThrottleSpec};
 use timeq::{PopResult, TimeQ, TimerError};
 use tokio::sync::Notify;
 use tracing::instrument;
+use utoipa::ToSchema;
 
 lazy_static::lazy_static! {
     static ref MANAGER: StdMutex<QueueManager> = StdMutex::new(QueueManager::new());
@@ -68,6 +80,8 @@ lazy_static::lazy_static! {
         ()> = CallbackSignature::new_with_multiple("throttle_insert_ready_queue");
     static ref REBIND_MESSAGE_SIG: CallbackSignature::<'static,
         (Message, HashMap<String, String>), ()> = CallbackSignature::new("rebind_message");
+    static ref QUEUE_STALE_SIG: CallbackSignature::<'static,
+        (String, &'static str, usize), StaleQueueAction> = CallbackSignature::new("queue_stale");
 
     static ref SINGLETON_WHEEL: Arc<StdMutex<TimeQ<WeakMessage>>> = Arc::new(StdMutex::new(TimeQ::new()));
 }
@@ -144,6 +158,13 @@ static DELAY_DUE_TO_MESSAGE_RATE_THROTTLE_COUNTER: Lazy<PruningCounterRegistry<Q
             "number of times a message was delayed due to max_message_rate",
         )
     });
+static DELAY_DUE_TO_RECIPIENT_RATE_THROTTLE_COUNTER: Lazy<PruningCounterRegistry<QueueKey>> =
+    Lazy::new(|| {
+        PruningCounterRegistry::register(
+            "delayed_due_to_recipient_rate_throttle",
+            "number of times a message was delayed due to max_message_rate_per_recipient",
+        )
+    });
 static DELAY_DUE_TO_THROTTLE_INSERT_READY_COUNTER: Lazy<PruningCounterRegistry<QueueKey>> =
     Lazy::new(|| {
         PruningCounterRegistry::register(
@@ -151,6 +172,12 @@ static DELAY_DUE_TO_THROTTLE_INSERT_READY_COUNTER: Lazy<PruningCounterRegistry<Q
             "number of times a message was delayed due throttle_insert_ready_queue event",
         )
     });
+static DELAY_DUE_TO_SEND_WINDOW_COUNTER: Lazy<PruningCounterRegistry<QueueKey>> = Lazy::new(|| {
+    PruningCounterRegistry::register(
+            "delayed_due_to_send_window",
+            "number of times a message was delayed because it was outside of the send_window for its queue",
+        )
+});
 static RESOLVE_LATENCY: Lazy<Histogram> = Lazy::new(|| {
     prometheus::register_histogram!(
         "queue_resolve_latency",
@@ -173,6 +200,8 @@ const ONE_SECOND: Duration = Duration::from_secs(1);
 const ONE_DAY: Duration = Duration::from_secs(86400);
 const ONE_MINUTE: Duration = Duration::from_secs(60);
 const TEN_MINUTES: Duration = Duration::from_secs(10 * 60);
+/// How many spilled messages are paged back in at a time
+const SPILL_PAGE_SIZE: usize = 1024;
 
 counter_bundle! {
     pub struct ScheduledCountBundle {
@@ -189,8 +218,10 @@ struct ScheduledMetrics {
     by_tenant: Option<AtomicCounter>,
     by_tenant_campaign: Option<AtomicCounter>,
     delay_due_to_message_rate_throttle: OnceCell<AtomicCounter>,
+    delay_due_to_recipient_rate_throttle: OnceCell<AtomicCounter>,
     delay_due_to_throttle_insert_ready: OnceCell<AtomicCounter>,
     delay_due_to_ready_queue_full: OnceCell<AtomicCounter>,
+    delay_due_to_send_window: OnceCell<AtomicCounter>,
 }
 
 impl ScheduledMetrics {
@@ -248,8 +279,10 @@ impl ScheduledMetrics {
             by_tenant_campaign,
             scheduled,
             delay_due_to_message_rate_throttle: OnceCell::new(),
+            delay_due_to_recipient_rate_throttle: OnceCell::new(),
             delay_due_to_throttle_insert_ready: OnceCell::new(),
             delay_due_to_ready_queue_full: OnceCell::new(),
+            delay_due_to_send_window: OnceCell::new(),
         }
     }
 
@@ -261,6 +294,14 @@ impl ScheduledMetrics {
             DELAY_DUE_TO_MESSAGE_RATE_THROTTLE_COUNTER.get_or_create(&key as &dyn QueueKeyTrait)
         })
     }
+    pub fn delay_due_to_recipient_rate_throttle(&self) -> &AtomicCounter {
+        self.delay_due_to_recipient_rate_throttle.get_or_init(|| {
+            let key = BorrowedQueueKey {
+                queue: self.name.as_str(),
+            };
+            DELAY_DUE_TO_RECIPIENT_RATE_THROTTLE_COUNTER.get_or_create(&key as &dyn QueueKeyTrait)
+        })
+    }
     pub fn delay_due_to_throttle_insert_ready(&self) -> &AtomicCounter {
         self.delay_due_to_throttle_insert_ready.get_or_init(|| {
             let key = BorrowedQueueKey {
@@ -278,6 +319,14 @@ impl ScheduledMetrics {
             DELAY_DUE_TO_READY_QUEUE_FULL_COUNTER.get_or_create(&key as &dyn QueueKeyTrait)
         })
     }
+    pub fn delay_due_to_send_window(&self) -> &AtomicCounter {
+        self.delay_due_to_send_window.get_or_init(|| {
+            let key = BorrowedQueueKey {
+                queue: self.name.as_str(),
+            };
+            DELAY_DUE_TO_SEND_WINDOW_COUNTER.get_or_create(&key as &dyn QueueKeyTrait)
+        })
+    }
 
     pub fn inc(&self) {
         TOTAL_DELAY_GAUGE.inc();
@@ -298,12 +347,25 @@ pub fn set_qmaint_threads(n: usize) {
     QMAINT_THREADS.store(n, Ordering::SeqCst);
 }
 
+/// Computes the throttle key for max_message_rate_per_recipient.
+/// The address is hashed so that recipient addresses are not
+/// exposed as keys in a shared redis instance.
+fn recipient_rate_throttle_key(recipient: &str) -> String {
+    let digest = Sha256::digest(recipient.to_lowercase().as_bytes());
+    format!("kumomta.recipient_rate.{}", HEXLOWER.encode(&digest))
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum DeliveryProto {
     Smtp { smtp: SmtpProtocol },
+    Lmtp { lmtp: SmtpProtocol },
     Maildir { maildir_path: std::path::PathBuf },
+    Mailbox { mailbox: MailboxDeliveryProtocol },
     Lua { custom_lua: LuaDeliveryProtocol },
+    HttpApi { http_api: HttpApiDeliveryProtocol },
+    Kafka { kafka: KafkaDeliveryProtocol },
+    Amqp { amqp: AmqpDeliveryProtocol },
     HttpInjectionGenerator,
 }
 
@@ -311,8 +373,13 @@ impl DeliveryProto {
     pub fn metrics_protocol_name(&self) -> &'static str {
         match self {
             Self::Smtp { .. } => "smtp_client",
+            Self::Lmtp { .. } => "lmtp_client",
             Self::Maildir { .. } => "maildir",
+            Self::Mailbox { mailbox } => mailbox.format.metrics_protocol_name(),
             Self::Lua { .. } => "lua",
+            Self::HttpApi { .. } => "http_api",
+            Self::Kafka { .. } => "kafka",
+            Self::Amqp { .. } => "amqp",
             Self::HttpInjectionGenerator { .. } => "httpinject",
         }
     }
@@ -320,9 +387,21 @@ impl DeliveryProto {
     pub fn ready_queue_name(&self) -> String {
         let proto_name = self.metrics_protocol_name();
         match self {
-            Self::Smtp { .. } => proto_name.to_string(),
+            Self::Smtp { .. } | Self::Lmtp { .. } => proto_name.to_string(),
             Self::Maildir { maildir_path } => format!("{proto_name}:{}", maildir_path.display()),
+            Self::Mailbox { mailbox } => format!("{proto_name}:{}", mailbox.path),
             Self::Lua { custom_lua } => format!("{proto_name}:{}", custom_lua.constructor),
+            Self::HttpApi { http_api } => format!("{proto_name}:{}", http_api.url),
+            Self::Kafka { kafka } => format!(
+                "{proto_name}:{}/{}",
+                kafka
+                    .producer_config
+                    .get("bootstrap.servers")
+                    .map(String::as_str)
+                    .unwrap_or(""),
+                kafka.topic
+            ),
+            Self::Amqp { amqp } => format!("{proto_name}:{}/{}", amqp.broker_name(), amqp.exchange),
             Self::HttpInjectionGenerator => format!("{proto_name}:generator"),
         }
     }
@@ -336,7 +415,7 @@ impl Default for DeliveryProto {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, FromLua, Default, Copy, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, FromLua, Default, Copy, PartialEq, Eq, ToSchema)]
 pub enum QueueStrategy {
     TimerWheel,
     SkipList,
@@ -344,7 +423,8 @@ pub enum QueueStrategy {
     SingletonTimerWheel,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, FromLua)]
+/// The parameters accepted by `kumo.make_queue_config`
+#[derive(Deserialize, Serialize, Debug, Clone, FromLua, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct QueueConfig {
     /// Base retry interval to use in exponential backoff
@@ -352,16 +432,19 @@ pub struct QueueConfig {
         default = "QueueConfig::default_retry_interval",
         with = "duration_serde"
     )]
+    #[schema(value_type = String)]
     pub retry_interval: Duration,
 
     /// Optional cap on the computed retry interval.
     /// Set to the same number as retry_interval to
     /// prevent using exponential backoff
     #[serde(default, with = "duration_serde")]
+    #[schema(value_type = Option<String>)]
     pub max_retry_interval: Option<Duration>,
 
     /// Limits how long a message can remain in the queue
     #[serde(default = "QueueConfig::default_max_age", with = "duration_serde")]
+    #[schema(value_type = String)]
     pub max_age: Duration,
 
     /// Specifies which egress pool should be used when
@@ -372,9 +455,20 @@ pub struct QueueConfig {
     /// The rate at which messages are allowed to move from
     /// the scheduled queue and into the ready queue
     #[serde(default)]
+    #[schema(value_type = Option<String>)]
     pub max_message_rate: Option<ThrottleSpec>,
 
+    /// The rate at which messages addressed to any individual
+    /// recipient mailbox are allowed to move from the scheduled
+    /// queue and into the ready queue.  The throttle is keyed on
+    /// a hash of the recipient address, so it is shared across all
+    /// queues that use the same throttle spec.
     #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub max_message_rate_per_recipient: Option<ThrottleSpec>,
+
+    #[serde(default)]
+    #[schema(value_type = Object)]
     pub protocol: DeliveryProto,
 
     /// How long to wait after the queue is idle before reaping
@@ -383,6 +477,7 @@ pub struct QueueConfig {
         default = "QueueConfig::default_reap_interval",
         with = "duration_serde"
     )]
+    #[schema(value_type = String)]
     pub reap_interval: Duration,
 
     /// How long to wait between calls to get_queue_config for
@@ -393,9 +488,11 @@ pub struct QueueConfig {
         default = "QueueConfig::default_refresh_interval",
         with = "duration_serde"
     )]
+    #[schema(value_type = String)]
     pub refresh_interval: Duration,
 
     #[serde(with = "duration_serde", default)]
+    #[schema(value_type = Option<String>)]
     pub timerwheel_tick_interval: Option<Duration>,
 
     #[serde(default)]
@@ -411,6 +508,53 @@ pub struct QueueConfig {
     /// routing_domain for this queue, will be used instead.
     #[serde(default)]
     pub provider_name: Option<String>,
+
+    /// If true, messages received into this queue are retained in
+    /// memory rather than being written to the spool before the
+    /// reception is acknowledged. They are written to the spool after
+    /// their first transient failure, or when kumod is shutting down.
+    #[serde(default)]
+    pub deferred_spool: bool,
+
+    /// If set, once every attempt to resolve the destination of this
+    /// queue has failed with NXDOMAIN for at least this long, the
+    /// `queue_stale` event is triggered to decide what to do with
+    /// the queued messages.
+    #[serde(default, with = "duration_serde")]
+    #[schema(value_type = Option<String>)]
+    pub stale_after_nxdomain: Option<Duration>,
+
+    /// If set, once this queue has held messages without successfully
+    /// delivering any of them for at least this long, the `queue_stale`
+    /// event is triggered to decide what to do with the queued messages.
+    #[serde(default, with = "duration_serde")]
+    #[schema(value_type = Option<String>)]
+    pub stale_after_no_progress: Option<Duration>,
+
+    /// If set, messages in this queue are only moved to the ready
+    /// queue during these local times and days of the week; at other
+    /// times they are held in the scheduled queue until the window
+    /// next opens.
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    pub send_window: Option<ScheduleRestriction>,
+
+    /// If set, the queue is delivered via a separate ready queue from
+    /// other queues with the same egress source and destination site,
+    /// and the tier is passed to `get_egress_path_config` so that it
+    /// can be shaped independently. Useful for eg: holding large
+    /// messages to a lower rate than the rest of the traffic for
+    /// the same provider.
+    #[serde(default)]
+    pub shaping_tier: Option<String>,
+
+    /// If set, once the scheduled queue holds this many messages,
+    /// additional delayed messages are released from memory and
+    /// recorded in an on-disk index ordered by due time, from which
+    /// they are paged back in as they become due. Has no effect
+    /// for the SingletonTimerWheel strategy.
+    #[serde(default)]
+    pub max_scheduled_in_memory: Option<usize>,
 }
 
 impl LuaUserData for QueueConfig {}
@@ -424,17 +568,36 @@ impl Default for QueueConfig {
             egress_pool: None,
             protocol: DeliveryProto::default(),
             max_message_rate: None,
+            max_message_rate_per_recipient: None,
             reap_interval: Self::default_reap_interval(),
             refresh_interval: Self::default_refresh_interval(),
             strategy: QueueStrategy::default(),
             timerwheel_tick_interval: None,
             refresh_strategy: ConfigRefreshStrategy::default(),
             provider_name: None,
+            deferred_spool: false,
+            stale_after_nxdomain: None,
+            stale_after_no_progress: None,
+            send_window: None,
+            shaping_tier: None,
+            max_scheduled_in_memory: None,
         }
     }
 }
 
 impl QueueConfig {
+    /// If send_window is set and now is outside of it, returns
+    /// the time at which the window next opens
+    pub fn next_send_window(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let scheduling = Scheduling {
+            restriction: Some(self.send_window?),
+            first_attempt: None,
+            expires: None,
+        };
+        let next = scheduling.adjust_for_schedule(now);
+        (next > now).then_some(next)
+    }
+
     fn default_retry_interval() -> Duration {
         Duration::from_secs(60 * 20) // 20 minutes
     }
@@ -507,10 +670,76 @@ impl QueueConfig {
     }
 }
 
+/// The upper bounds of the buckets used by age_histogram
+const AGE_HISTOGRAM_BOUNDS: [Duration; 5] = [
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(6 * 60 * 60),
+    Duration::from_secs(24 * 60 * 60),
+    Duration::from_secs(3 * 24 * 60 * 60),
+];
+
+/// Count the supplied message ages into AGE_HISTOGRAM_BOUNDS,
+/// plus a final unbounded bucket
+fn age_histogram(ages: impl Iterator<Item = chrono::Duration>) -> Vec<AgeHistogramBucket> {
+    let mut buckets: Vec<AgeHistogramBucket> = AGE_HISTOGRAM_BOUNDS
+        .iter()
+        .map(|&bound| AgeHistogramBucket {
+            max_age: Some(bound),
+            count: 0,
+        })
+        .chain(std::iter::once(AgeHistogramBucket {
+            max_age: None,
+            count: 0,
+        }))
+        .collect();
+
+    for age in ages {
+        let age = age.to_std().unwrap_or(Duration::ZERO);
+        let idx = AGE_HISTOGRAM_BOUNDS
+            .iter()
+            .position(|&bound| age < bound)
+            .unwrap_or(AGE_HISTOGRAM_BOUNDS.len());
+        buckets[idx].count += 1;
+    }
+
+    buckets
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn age_buckets() {
+        let counts: Vec<(Option<Duration>, usize)> = age_histogram(
+            [
+                chrono::Duration::seconds(-5),
+                chrono::Duration::seconds(10),
+                chrono::Duration::minutes(5),
+                chrono::Duration::hours(2),
+                chrono::Duration::days(10),
+                chrono::Duration::days(30),
+            ]
+            .into_iter(),
+        )
+        .into_iter()
+        .map(|bucket| (bucket.max_age, bucket.count))
+        .collect();
+
+        assert_eq!(
+            counts,
+            vec![
+                (Some(Duration::from_secs(300)), 2),
+                (Some(Duration::from_secs(3600)), 1),
+                (Some(Duration::from_secs(6 * 3600)), 1),
+                (Some(Duration::from_secs(86400)), 0),
+                (Some(Duration::from_secs(3 * 86400)), 0),
+                (None, 2),
+            ]
+        );
+    }
+
     /// Returns the list of delays up until the max_age would be reached
     fn compute_schedule(config: &QueueConfig) -> Vec<i64> {
         let mut schedule = vec![];
@@ -526,6 +755,192 @@ mod test {
         unreachable!()
     }
 
+    #[test]
+    fn stale_queue_action() {
+        let lua = Lua::new();
+        let action: StaleQueueAction = lua.load("return nil").eval().unwrap();
+        assert_eq!(action, StaleQueueAction::Wait);
+
+        let action: StaleQueueAction = lua
+            .load("return {action='Bounce', reason='gone'}")
+            .eval()
+            .unwrap();
+        assert_eq!(
+            action,
+            StaleQueueAction::Bounce {
+                reason: Some("gone".to_string())
+            }
+        );
+
+        let action: StaleQueueAction = lua
+            .load("return {action='Reroute', queue='example.com'}")
+            .eval()
+            .unwrap();
+        assert_eq!(
+            action,
+            StaleQueueAction::Reroute {
+                queue: "example.com".to_string()
+            }
+        );
+
+        assert!(lua
+            .load("return {action='Explode'}")
+            .eval::<StaleQueueAction>()
+            .is_err());
+    }
+
+    #[test]
+    fn stale_reason() {
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        let mut state = StaleState::new(start);
+        let nxdomain_after = Some(10 * minute);
+        let no_progress_after = Some(30 * minute);
+
+        // Unconfigured thresholds never trigger
+        state.note_resolution(true, start);
+        assert_eq!(state.reason(false, None, None, start + 60 * minute), None);
+
+        assert_eq!(
+            state.reason(false, nxdomain_after, no_progress_after, start + 5 * minute),
+            None
+        );
+        assert_eq!(
+            state.reason(
+                false,
+                nxdomain_after,
+                no_progress_after,
+                start + 10 * minute
+            ),
+            Some(StaleReason::Nxdomain)
+        );
+
+        // A successful resolution clears the NXDOMAIN condition,
+        // but the queue still isn't making progress
+        state.note_resolution(false, start + 11 * minute);
+        assert_eq!(
+            state.reason(
+                false,
+                nxdomain_after,
+                no_progress_after,
+                start + 20 * minute
+            ),
+            None
+        );
+        assert_eq!(
+            state.reason(
+                false,
+                nxdomain_after,
+                no_progress_after,
+                start + 30 * minute
+            ),
+            Some(StaleReason::NoProgress)
+        );
+
+        // An empty queue is never stale, and restarts the progress clock
+        assert_eq!(
+            state.reason(true, nxdomain_after, no_progress_after, start + 31 * minute),
+            None
+        );
+        assert_eq!(
+            state.reason(
+                false,
+                nxdomain_after,
+                no_progress_after,
+                start + 40 * minute
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn check_stale_wait_restarts_clocks() {
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        let mut state = StaleState::new(start);
+        let nxdomain_after = Some(10 * minute);
+        let no_progress_after = Some(30 * minute);
+
+        state.note_resolution(true, start);
+        // Subsequent failures don't move the start of the NXDOMAIN period
+        state.note_resolution(true, start + 5 * minute);
+        assert_eq!(
+            state.reason(
+                false,
+                nxdomain_after,
+                no_progress_after,
+                start + 10 * minute
+            ),
+            Some(StaleReason::Nxdomain)
+        );
+
+        // This is what check_stale does when the event returns Wait
+        state.restart(start + 10 * minute);
+        assert_eq!(
+            state.reason(
+                false,
+                nxdomain_after,
+                no_progress_after,
+                start + 15 * minute
+            ),
+            None
+        );
+        assert_eq!(
+            state.reason(
+                false,
+                nxdomain_after,
+                no_progress_after,
+                start + 20 * minute
+            ),
+            Some(StaleReason::Nxdomain)
+        );
+    }
+
+    #[test]
+    fn next_send_window() {
+        let config: QueueConfig = serde_json::from_value(serde_json::json!({
+            "send_window": {
+                "dow": "Mon,Tue,Wed,Thu,Fri",
+                "tz": "America/Phoenix",
+                "start": "09:00:00",
+                "end": "17:00:00",
+            }
+        }))
+        .unwrap();
+
+        // Wednesday 10:00 in Phoenix, which is UTC-7
+        let inside = DateTime::parse_from_rfc3339("2024-07-10T17:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(config.next_send_window(inside), None);
+
+        // Friday 18:00 in Phoenix is held until Monday 09:00
+        let after = DateTime::parse_from_rfc3339("2024-07-13T01:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            config.next_send_window(after).unwrap().to_rfc3339(),
+            "2024-07-15T16:00:00+00:00"
+        );
+
+        assert_eq!(QueueConfig::default().next_send_window(after), None);
+    }
+
+    #[test]
+    fn recipient_rate_key() {
+        assert_eq!(
+            recipient_rate_throttle_key("User@Example.com"),
+            recipient_rate_throttle_key("user@example.com")
+        );
+        assert_ne!(
+            recipient_rate_throttle_key("user@example.com"),
+            recipient_rate_throttle_key("other@example.com")
+        );
+        assert!(
+            recipient_rate_throttle_key("user@example.com").starts_with("kumomta.recipient_rate.")
+        );
+    }
+
     #[test]
     fn calc_due() {
         let config = QueueConfig {
@@ -812,6 +1227,31 @@ impl QueueStructure {
         }
     }
 
+    /// Returns the messages currently held by the queue, without
+    /// removing them.
+    /// The timer wheel cannot be iterated, so its contents are drained
+    /// and re-inserted while holding its lock. Any messages that have
+    /// become due in the meantime cannot be re-inserted into the wheel
+    /// and are returned in the second element of the tuple; the caller
+    /// is responsible for promoting them.
+    fn snapshot(&self) -> (Vec<Message>, Vec<Message>) {
+        match self {
+            Self::TimerWheel(q) => {
+                let mut q = q.lock();
+                let msgs = q.drain();
+                let mut due = vec![];
+                for msg in &msgs {
+                    if let Err(TimerError::Expired(msg)) = q.insert(msg.clone()) {
+                        due.push(msg);
+                    }
+                }
+                (msgs, due)
+            }
+            Self::SkipList(q) => (q.iter().map(|entry| entry.0.clone()).collect(), vec![]),
+            Self::SingletonTimerWheel(q) => (q.lock().iter().cloned().collect(), vec![]),
+        }
+    }
+
     fn insert(&self, msg: Message) -> QueueInsertResult {
         match self {
             Self::TimerWheel(q) => match q.lock().insert(msg) {
@@ -894,6 +1334,129 @@ impl QueueStructure {
     }
 }
 
+/// Why a queue was considered to be stale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleReason {
+    /// The destination domain has been returning NXDOMAIN
+    Nxdomain,
+    /// The queue has a backlog but nothing has been delivered
+    NoProgress,
+}
+
+impl StaleReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Nxdomain => "Nxdomain",
+            Self::NoProgress => "NoProgress",
+        }
+    }
+
+    fn enhanced_code(&self) -> EnhancedStatusCode {
+        match self {
+            // Bad destination system address
+            Self::Nxdomain => EnhancedStatusCode {
+                class: 5,
+                subject: 4,
+                detail: 4,
+            },
+            // Delivery time expired
+            Self::NoProgress => EnhancedStatusCode {
+                class: 5,
+                subject: 4,
+                detail: 7,
+            },
+        }
+    }
+}
+
+/// Tracks the conditions that can cause a queue to become stale
+#[derive(Debug)]
+struct StaleState {
+    /// When we first saw the destination fail to resolve with NXDOMAIN,
+    /// reset whenever it resolves successfully
+    nxdomain_since: Option<Instant>,
+    /// When we last delivered a message from this queue, or when
+    /// the queue was last observed to be empty
+    last_progress: Instant,
+}
+
+impl StaleState {
+    fn new(now: Instant) -> Self {
+        Self {
+            nxdomain_since: None,
+            last_progress: now,
+        }
+    }
+
+    /// Record the outcome of resolving the destination
+    fn note_resolution(&mut self, nxdomain: bool, now: Instant) {
+        if nxdomain {
+            self.nxdomain_since.get_or_insert(now);
+        } else {
+            self.nxdomain_since.take();
+        }
+    }
+
+    /// Returns the reason that the queue is stale, if any
+    fn reason(
+        &mut self,
+        is_empty: bool,
+        nxdomain_after: Option<Duration>,
+        no_progress_after: Option<Duration>,
+        now: Instant,
+    ) -> Option<StaleReason> {
+        if is_empty {
+            // Nothing is waiting, so there is no lack of progress
+            self.last_progress = now;
+            return None;
+        }
+
+        if let (Some(after), Some(since)) = (nxdomain_after, self.nxdomain_since) {
+            if now >= since + after {
+                return Some(StaleReason::Nxdomain);
+            }
+        }
+        if let Some(after) = no_progress_after {
+            if now >= self.last_progress + after {
+                return Some(StaleReason::NoProgress);
+            }
+        }
+        None
+    }
+
+    /// Restart the clocks so that the queue is not considered
+    /// stale again until another threshold period has elapsed
+    fn restart(&mut self, now: Instant) {
+        self.last_progress = now;
+        if let Some(since) = self.nxdomain_since.as_mut() {
+            *since = now;
+        }
+    }
+}
+
+/// The value returned from the `queue_stale` event
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "action", deny_unknown_fields)]
+pub enum StaleQueueAction {
+    /// Leave the messages alone. The event will be triggered again
+    /// if the queue remains stale for another threshold period.
+    #[default]
+    Wait,
+    /// Bounce all of the messages in the queue
+    Bounce { reason: Option<String> },
+    /// Move all of the messages in the queue to the named queue
+    Reroute { queue: String },
+}
+
+impl<'lua> FromLua<'lua> for StaleQueueAction {
+    fn from_lua(value: LuaValue<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil => Ok(Self::Wait),
+            value => config::from_lua_value(lua, value),
+        }
+    }
+}
+
 pub struct Queue {
     name: Arc<String>,
     queue: QueueStructure,
@@ -907,10 +1470,17 @@ pub struct Queue {
     warned_strategy_change: AtomicBool,
     config_epoch: StdMutex<ConfigEpoch>,
     site_name: String,
+    stale: StdMutex<StaleState>,
+    /// The number of messages recorded in the spill index
+    /// rather than being held in memory
+    spilled: AtomicUsize,
+    /// The earliest due time of the spilled messages, as
+    /// last observed by the maintainer
+    spill_wakeup: StdMutex<Option<DateTime<Utc>>>,
 }
 
 impl Queue {
-    async fn call_get_queue_config(
+    pub async fn call_get_queue_config(
         name: &str,
         config: &mut LuaConfig,
     ) -> anyhow::Result<QueueConfig> {
@@ -974,6 +1544,9 @@ impl Queue {
             warned_strategy_change: AtomicBool::new(false),
             config_epoch: StdMutex::new(epoch),
             site_name,
+            stale: StdMutex::new(StaleState::new(Instant::now())),
+            spilled: AtomicUsize::new(0),
+            spill_wakeup: StdMutex::new(None),
         });
 
         if !matches!(strategy, QueueStrategy::SingletonTimerWheel) {
@@ -1025,6 +1598,7 @@ impl Queue {
         let names = QueueManager::all_queue_names();
         let mut num_due = 0;
         let mut num_reaped = 0;
+        let mut num_stale = 0;
         let num_queues = names.len();
 
         for name in names {
@@ -1034,7 +1608,12 @@ impl Queue {
             if let Some(queue) = QueueManager::get_opt(&name) {
                 if queue.check_reap(now) {
                     num_reaped += 1;
-                } else if queue
+                    continue;
+                }
+                if queue.check_stale(now).await {
+                    num_stale += 1;
+                }
+                if queue
                     .perform_config_refresh_if_due(now, epoch, epoch_changed)
                     .await
                 {
@@ -1044,14 +1623,14 @@ impl Queue {
         }
 
         tracing::debug!(
-            "refreshed {num_due} configs, reaped {num_reaped} \
-             out of {num_queues} scheduled queues in {:?}",
+            "refreshed {num_due} configs, reaped {num_reaped}, \
+             found {num_stale} stale out of {num_queues} scheduled queues in {:?}",
             now.elapsed()
         );
     }
 
     fn check_reap(&self, now: Instant) -> bool {
-        if !self.queue.is_empty() {
+        if !self.is_empty() {
             return false;
         }
 
@@ -1063,7 +1642,7 @@ impl Queue {
             // be atomic wrt. another resolve operation
             let mut mgr = MANAGER.lock();
 
-            if !self.queue.is_empty() {
+            if !self.is_empty() {
                 // Raced with an insert, cannot reap now
                 return false;
             }
@@ -1078,6 +1657,145 @@ impl Queue {
         false
     }
 
+    /// Record that a message from this queue was delivered
+    pub fn note_progress(&self) {
+        self.stale.lock().last_progress = Instant::now();
+    }
+
+    /// Returns the reason that the queue is stale, if any
+    fn stale_reason(&self, now: Instant) -> Option<StaleReason> {
+        let (nxdomain_after, no_progress_after) = {
+            let config = self.queue_config.borrow();
+            (config.stale_after_nxdomain, config.stale_after_no_progress)
+        };
+        self.stale
+            .lock()
+            .reason(self.is_empty(), nxdomain_after, no_progress_after, now)
+    }
+
+    /// Checks whether the queue is stale, and if so, triggers the
+    /// `queue_stale` event and applies the action that it returns.
+    /// Returns true if the queue was stale.
+    async fn check_stale(&self, now: Instant) -> bool {
+        let Some(reason) = self.stale_reason(now) else {
+            return false;
+        };
+
+        let depth = self.depth();
+        let action = match load_config().await {
+            Ok(mut config) => {
+                match config
+                    .async_call_callback(
+                        &QUEUE_STALE_SIG,
+                        (self.name.to_string(), reason.as_str(), depth),
+                    )
+                    .await
+                {
+                    Ok(action) => action,
+                    Err(err) => {
+                        tracing::error!("error calling queue_stale for {}: {err:#}", self.name);
+                        StaleQueueAction::Wait
+                    }
+                }
+            }
+            Err(err) => {
+                tracing::error!("failed to load config for queue_stale: {err:#}");
+                StaleQueueAction::Wait
+            }
+        };
+
+        tracing::info!(
+            "queue {} with {depth} messages is stale ({}): {action:?}",
+            self.name,
+            reason.as_str()
+        );
+
+        match action {
+            StaleQueueAction::Wait => {
+                // Check back in after another threshold period has elapsed
+                self.stale.lock().restart(now);
+            }
+            StaleQueueAction::Bounce {
+                reason: bounce_reason,
+            } => {
+                let bounce_reason = bounce_reason
+                    .unwrap_or_else(|| format!("queue is stale ({})", reason.as_str()));
+                self.bounce_stale(reason, bounce_reason).await;
+            }
+            StaleQueueAction::Reroute { queue } => {
+                let rebind = Arc::new(AdminRebindEntry {
+                    request: RebindV1Request {
+                        campaign: None,
+                        tenant: None,
+                        domain: None,
+                        routing_domain: None,
+                        reason: format!("queue is stale ({})", reason.as_str()),
+                        suppress_logging: false,
+                        data: [("queue".to_string(), queue)].into_iter().collect(),
+                        trigger_rebind_event: false,
+                        always_flush: false,
+                        preserve_due_time: false,
+                    },
+                });
+                self.rebind_all(&rebind).await;
+            }
+        }
+
+        true
+    }
+
+    /// Bounces all of the messages in the queue, logging a Bounce
+    /// record with the provided reason for each of them
+    async fn bounce_stale(&self, stale_reason: StaleReason, reason: String) {
+        self.bounce_stale_batch(self.drain_timeq(), stale_reason, reason.clone());
+        while let Some(msgs) = self.unspill_batch().await {
+            self.bounce_stale_batch(msgs, stale_reason, reason.clone());
+        }
+    }
+
+    fn bounce_stale_batch(&self, msgs: Vec<Message>, stale_reason: StaleReason, reason: String) {
+        let count = msgs.len();
+        if count == 0 {
+            return;
+        }
+        let provider = self.queue_config.borrow().provider_name.clone();
+        // As in bounce_all, perform the spool removal in a separate task
+        let result = QMAINT_RUNTIME.spawn_non_blocking(
+            "bounce_stale remove_from_spool".to_string(),
+            move || {
+                Ok(async move {
+                    for msg in msgs {
+                        let id = *msg.id();
+                        log_disposition(LogDisposition {
+                            kind: RecordType::Bounce,
+                            msg,
+                            site: "",
+                            peer_address: None,
+                            response: Response {
+                                code: 550,
+                                enhanced_code: Some(stale_reason.enhanced_code()),
+                                content: format!("KumoMTA internal: {reason}"),
+                                command: None,
+                            },
+                            egress_pool: None,
+                            egress_source: None,
+                            relay_disposition: None,
+                            delivery_protocol: None,
+                            tls_info: None,
+                            source_address: None,
+                            provider: provider.as_deref(),
+                        })
+                        .await;
+                        SpoolManager::remove_from_spool(id).await.ok();
+                    }
+                })
+            },
+        );
+        if let Err(err) = result {
+            tracing::error!("Unable to schedule spool removal for {count} messages! {err:#}");
+        }
+    }
+
     fn get_config_epoch(&self) -> ConfigEpoch {
         self.config_epoch.lock().clone()
     }
@@ -1168,6 +1886,162 @@ impl Queue {
         msgs
     }
 
+    /// Returns true if the queue holds no messages, either
+    /// in memory or in the spill index
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty() && self.spilled.load(Ordering::SeqCst) == 0
+    }
+
+    /// Returns the number of messages in the queue, including
+    /// those in the spill index
+    pub fn depth(&self) -> usize {
+        self.queue.len() + self.spilled.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if a newly delayed message should be recorded
+    /// in the spill index rather than being held in memory
+    fn should_spill(&self) -> bool {
+        match self.queue_config.borrow().max_scheduled_in_memory {
+            Some(max) => {
+                self.queue.strategy() != QueueStrategy::SingletonTimerWheel
+                    && self.queue.len() >= max
+            }
+            None => false,
+        }
+    }
+
+    /// Saves the message and records it in the spill index,
+    /// so that the in-memory copy can be released
+    async fn spill(&self, msg: &Message, due: DateTime<Utc>) -> anyhow::Result<()> {
+        Self::save_if_needed(msg).await?;
+
+        // Count it before it is visible to the maintainer,
+        // so that the count cannot transiently underflow
+        self.spilled.fetch_add(1, Ordering::SeqCst);
+        if let Err(err) = queue_spill::spill(
+            &self.name,
+            SpilledEntry {
+                id: *msg.id(),
+                due,
+                num_attempts: msg.get_num_attempts(),
+            },
+        )
+        .await
+        {
+            self.spilled.fetch_sub(1, Ordering::SeqCst);
+            return Err(err);
+        }
+        self.metrics().inc();
+
+        let mut wakeup = self.spill_wakeup.lock();
+        if wakeup.map(|wakeup| due < wakeup).unwrap_or(true) {
+            wakeup.replace(due);
+            self.notify_maintainer.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Removes up to `limit` entries that are due no later than
+    /// `due_by` from the spill index, and loads their messages
+    async fn unspill(
+        &self,
+        due_by: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Message>> {
+        let entries = queue_spill::take_due(&self.name, due_by, limit).await?;
+        if entries.is_empty() {
+            return Ok(vec![]);
+        }
+        self.spilled.fetch_sub(entries.len(), Ordering::SeqCst);
+        self.metrics().sub(entries.len());
+
+        let mut msgs = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match Message::new_with_id(entry.id).await {
+                Ok(msg) => {
+                    msg.set_num_attempts(entry.num_attempts);
+                    if let Err(err) = msg.set_due(Some(entry.due)).await {
+                        tracing::error!("{}: setting due for {}: {err:#}", self.name, entry.id);
+                    }
+                    msgs.push(msg);
+                }
+                Err(err) => {
+                    tracing::error!(
+                        "{}: failed to load spilled message {}: {err:#}",
+                        self.name,
+                        entry.id
+                    );
+                }
+            }
+        }
+        Ok(msgs)
+    }
+
+    /// Removes the next page of entries from the spill index,
+    /// regardless of their due time, returning None once
+    /// there are no more
+    async fn unspill_batch(&self) -> Option<Vec<Message>> {
+        if self.spilled.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        match self.unspill(None, SPILL_PAGE_SIZE).await {
+            Ok(msgs) if !msgs.is_empty() => Some(msgs),
+            Ok(_) => None,
+            Err(err) => {
+                tracing::error!("{}: failed to read spill index: {err:#}", self.name);
+                None
+            }
+        }
+    }
+
+    /// Moves the spilled messages that are now due into the ready
+    /// queue, returning the time at which the next spilled message
+    /// will become due
+    async fn page_in_spilled(&self) -> anyhow::Result<Option<Instant>> {
+        let msgs = match self.unspill(Some(Utc::now()), SPILL_PAGE_SIZE).await {
+            Ok(msgs) => msgs,
+            Err(err) => {
+                tracing::error!("{}: failed to read spill index: {err:#}", self.name);
+                return Ok(Some(Instant::now() + ONE_SECOND));
+            }
+        };
+        if !msgs.is_empty() {
+            tracing::debug!("{} {} spilled msgs are now ready", self.name, msgs.len());
+            for msg in msgs {
+                self.insert_ready(msg).await?;
+            }
+        }
+
+        let next_due = match queue_spill::next_due(&self.name).await {
+            Ok(next_due) => next_due,
+            Err(err) => {
+                tracing::error!("{}: failed to read spill index: {err:#}", self.name);
+                return Ok(Some(Instant::now() + ONE_SECOND));
+            }
+        };
+        *self.spill_wakeup.lock() = next_due;
+        Ok(next_due
+            .map(|due| Instant::now() + (due - Utc::now()).to_std().unwrap_or(ZERO_DURATION)))
+    }
+
+    /// Discards the spill index entries of this queue. Their messages
+    /// remain in the spool, and will be picked up again by the spool
+    /// enumeration at the next startup.
+    async fn forget_spilled(&self) {
+        if self.spilled.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        match queue_spill::remove_queue(&self.name).await {
+            Ok(count) => {
+                self.spilled.fetch_sub(count, Ordering::SeqCst);
+                self.metrics().sub(count);
+            }
+            Err(err) => {
+                tracing::error!("{}: failed to clear spill index: {err:#}", self.name);
+            }
+        }
+    }
+
     async fn do_rebind(&self, msg: Message, rebind: &Arc<AdminRebindEntry>) {
         async fn try_apply(msg: &Message, rebind: &Arc<AdminRebindEntry>) -> anyhow::Result<()> {
             if !msg.is_meta_loaded() {
@@ -1229,12 +2103,19 @@ impl Queue {
             }
         };
 
-        // If we changed queues, make the message immediately eligible for delivery
-        if rebind.request.always_flush || queue.name != self.name {
+        // If we changed queues, make the message immediately eligible for delivery,
+        // unless we were asked to preserve the due time
+        if rebind.request.always_flush
+            || (queue.name != self.name && !rebind.request.preserve_due_time)
+        {
             // Avoid adding jitter as part of the queue change
             delay = Some(chrono::Duration::zero());
             // and ensure that the message is due now
             msg.set_due(None).await.ok();
+        } else if let Some(due) = msg.get_due() {
+            // Otherwise, retain the existing due time rather than
+            // having requeue_message pick a new one
+            delay = Some((due - Utc::now()).max(chrono::Duration::zero()));
         }
 
         // If we changed queues, log an AdminRebind operation so that it is possible
@@ -1282,11 +2163,22 @@ impl Queue {
                 self.do_rebind(msg, rebind).await;
             }
         }
+        while let Some(msgs) = self.unspill_batch().await {
+            for msg in msgs {
+                self.do_rebind(msg, rebind).await;
+            }
+        }
     }
 
     #[instrument(skip(self))]
     pub async fn bounce_all(&self, bounce: &AdminBounceEntry) {
-        let msgs = self.drain_timeq();
+        self.bounce_batch(self.drain_timeq(), bounce);
+        while let Some(msgs) = self.unspill_batch().await {
+            self.bounce_batch(msgs, bounce);
+        }
+    }
+
+    fn bounce_batch(&self, msgs: Vec<Message>, bounce: &AdminBounceEntry) {
         let count = msgs.len();
         if count > 0 {
             let name = self.name.clone();
@@ -1315,11 +2207,52 @@ impl Queue {
         }
     }
 
+    /// Produce a summary of the current contents of the queue,
+    /// for the inspect-sched-q API
+    pub async fn inspect(&self) -> ScheduledQueueV1Entry {
+        let (msgs, due) = self.queue.snapshot();
+        for msg in due {
+            if let Err(err) = self.insert(msg).await {
+                tracing::error!("{}: failed to promote due message: {err:#}", self.name);
+            }
+        }
+
+        let now = Utc::now();
+        let mut next_due = msgs.iter().filter_map(|msg| msg.get_due()).min();
+
+        let mut spilled = vec![];
+        if self.spilled.load(Ordering::SeqCst) > 0 {
+            match queue_spill::ids(&self.name).await {
+                Ok(ids) => spilled = ids,
+                Err(err) => {
+                    tracing::error!("{}: failed to read spill index: {err:#}", self.name);
+                }
+            }
+            if let Ok(Some(due)) = queue_spill::next_due(&self.name).await {
+                next_due = Some(next_due.map(|next| next.min(due)).unwrap_or(due));
+            }
+        }
+
+        ScheduledQueueV1Entry {
+            name: self.name.to_string(),
+            depth: msgs.len() + spilled.len(),
+            next_due,
+            age_histogram: age_histogram(
+                msgs.iter()
+                    .map(|msg| msg.age(now))
+                    .chain(spilled.iter().map(|id| id.age(now))),
+            ),
+        }
+    }
+
     async fn increment_attempts_and_update_delay(
         &self,
         msg: Message,
     ) -> anyhow::Result<Option<Message>> {
         let id = *msg.id();
+        // The scheduling constraints and deliver_by are held in the
+        // metadata, which may have been shrunk away
+        msg.load_meta_if_needed().await?;
         // Pre-calculate the delay, prior to incrementing the number of attempts,
         // as the delay_for_attempt uses a zero-based attempt number to figure
         // the interval
@@ -1341,8 +2274,36 @@ impl Queue {
         let max_age = self.queue_config.borrow().get_max_age();
         let age = msg.age(now);
         let delayed_age = age + delay;
-        if delayed_age > max_age {
-            tracing::debug!("expiring {id} {delayed_age} > {max_age}");
+
+        let expires = msg.get_scheduling()?.and_then(|sched| sched.expires);
+
+        let expiration_reason = if delayed_age > max_age {
+            Some(format!("Next delivery time {delayed_age} > {max_age}"))
+        } else if let Some(expires) = expires.filter(|expires| now + delay > *expires) {
+            Some(format!(
+                "Next delivery time {} > scheduled expiration {}",
+                (now + delay).to_rfc3339(),
+                expires.to_rfc3339()
+            ))
+        } else {
+            match msg.get_deliver_by(now)? {
+                // A DELIVERBY request in Return mode means that the sender
+                // would rather have the message bounced than delivered late
+                Some((deadline, by))
+                    if by.mode == DeliverByMode::Return && now + delay > deadline =>
+                {
+                    Some(format!(
+                        "Next delivery time {} > DELIVERBY deadline {}",
+                        (now + delay).to_rfc3339(),
+                        deadline.to_rfc3339()
+                    ))
+                }
+                _ => None,
+            }
+        };
+
+        if let Some(reason) = expiration_reason {
+            tracing::debug!("expiring {id}: {reason}");
             log_disposition(LogDisposition {
                 kind: RecordType::Expiration,
                 msg,
@@ -1355,7 +2316,7 @@ impl Queue {
                         subject: 4,
                         detail: 7,
                     }),
-                    content: format!("Next delivery time {delayed_age} > {max_age}"),
+                    content: reason,
                     command: None,
                 },
                 egress_pool: self.queue_config.borrow().egress_pool.as_deref(),
@@ -1411,8 +2372,40 @@ impl Queue {
                 if due <= now {
                     Ok(InsertResult::Ready(msg))
                 } else {
+                    // The span remains open until the message is due and
+                    // is moved to its ready queue. It is registered before
+                    // the message becomes visible to the maintainer, so
+                    // that it cannot replace the span of the next stage.
+                    let mut span = StageSpan::start(msg.id(), "scheduled");
+                    if span.is_recording() {
+                        span.set_attribute("queue", self.name.to_string());
+                        span.set_attribute("due", due.to_rfc3339());
+                        span.set_attribute("num_attempts", msg.get_num_attempts() as i64);
+                    }
+
+                    if self.should_spill() {
+                        if span.is_recording() {
+                            span.set_attribute("spilled", true);
+                        }
+                        span.wait(msg.id());
+                        match self.spill(&msg, due).await {
+                            Ok(()) => {
+                                return Ok(InsertResult::Delayed);
+                            }
+                            Err(err) => {
+                                // Fall back to holding it in memory
+                                tracing::error!(
+                                    "{}: failed to spill {}: {err:#}",
+                                    self.name,
+                                    msg.id()
+                                );
+                            }
+                        }
+                    }
+
                     tracing::trace!("insert_delayed, locking timeq {}", msg.id());
 
+                    span.wait(msg.id());
                     match self.timeq_insert(msg.clone()) {
                         Ok(_) => {
                             if let Err(err) = self.did_insert_delayed(msg.clone()).await {
@@ -1427,6 +2420,42 @@ impl Queue {
         }
     }
 
+    /// Logs a transient failure explaining why none of the sources
+    /// of the pool can currently be used, and delays the message
+    async fn delay_for_unavailable_sources(
+        &self,
+        msg: Message,
+        duration: chrono::Duration,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        log_disposition(LogDisposition {
+            kind: RecordType::TransientFailure,
+            msg: msg.clone(),
+            site: "",
+            peer_address: None,
+            response: Response {
+                code: 451,
+                enhanced_code: Some(EnhancedStatusCode {
+                    class: 4,
+                    subject: 4,
+                    detail: 4,
+                }),
+                content: format!("all possible sources for {} {reason}", self.name),
+                command: None,
+            },
+            egress_pool: None,
+            egress_source: None,
+            relay_disposition: None,
+            delivery_protocol: None,
+            tls_info: None,
+            source_address: None,
+            provider: self.queue_config.borrow().provider_name.as_deref(),
+        })
+        .await;
+        msg.delay_by_and_jitter(duration).await?;
+        self.force_into_delayed(msg).await
+    }
+
     #[instrument(skip(self, msg))]
     async fn force_into_delayed(&self, msg: Message) -> anyhow::Result<()> {
         tracing::trace!("force_into_delayed {}", msg.id());
@@ -1447,7 +2476,7 @@ impl Queue {
     pub async fn save_if_needed(msg: &Message) -> anyhow::Result<()> {
         tracing::trace!("save_if_needed {}", msg.id());
         if msg.needs_save() {
-            msg.save().await?;
+            save_to_spool(msg).await?;
         }
         msg.shrink()?;
         Ok(())
@@ -1475,6 +2504,21 @@ impl Queue {
         }
     }
 
+    async fn check_recipient_rate_throttle(
+        &self,
+        msg: &Message,
+    ) -> anyhow::Result<Option<ThrottleResult>> {
+        let throttle = match &self.queue_config.borrow().max_message_rate_per_recipient {
+            Some(throttle) => *throttle,
+            None => return Ok(None),
+        };
+        // The metadata may have been shrunk away while the
+        // message was waiting in the scheduled queue
+        msg.load_meta_if_needed().await?;
+        let key = recipient_rate_throttle_key(&msg.recipient()?.to_string());
+        Ok(Some(throttle.throttle(key).await?))
+    }
+
     fn metrics(&self) -> &ScheduledMetrics {
         self.metrics.get_or_init(|| {
             let queue_config = self.queue_config.borrow();
@@ -1489,6 +2533,16 @@ impl Queue {
 
     #[instrument(skip(self, msg))]
     async fn insert_ready(&self, msg: Message) -> anyhow::Result<()> {
+        let next_window = self.queue_config.borrow().next_send_window(Utc::now());
+        if let Some(due) = next_window {
+            tracing::trace!("{} outside of send_window, due={due:?}", self.name);
+            msg.set_due(Some(due)).await?;
+
+            self.metrics().delay_due_to_send_window().inc();
+
+            return self.force_into_delayed(msg).await;
+        }
+
         if let Some(result) = self.check_message_rate_throttle().await? {
             if let Some(delay) = result.retry_after {
                 tracing::trace!("{} throttled message rate, delay={delay:?}", self.name);
@@ -1504,6 +2558,21 @@ impl Queue {
             }
         }
 
+        if let Some(result) = self.check_recipient_rate_throttle(&msg).await? {
+            if let Some(delay) = result.retry_after {
+                tracing::trace!(
+                    "{} throttled recipient message rate, delay={delay:?}",
+                    self.name
+                );
+                let delay = chrono::Duration::from_std(delay).unwrap_or(kumo_chrono_helper::MINUTE);
+                msg.delay_by(delay).await?;
+
+                self.metrics().delay_due_to_recipient_rate_throttle().inc();
+
+                return self.force_into_delayed(msg).await;
+            }
+        }
+
         let mut config = load_config().await?;
         config
             .async_call_callback(&THROTTLE_INSERT_READY_SIG, msg.clone())
@@ -1547,7 +2616,11 @@ impl Queue {
 
         match &self.queue_config.borrow().protocol {
             DeliveryProto::Smtp { .. }
+            | DeliveryProto::Lmtp { .. }
             | DeliveryProto::Lua { .. }
+            | DeliveryProto::HttpApi { .. }
+            | DeliveryProto::Kafka { .. }
+            | DeliveryProto::Amqp { .. }
             | DeliveryProto::HttpInjectionGenerator => {
                 let (egress_source, ready_name) = match self
                     .rr
@@ -1559,35 +2632,18 @@ impl Queue {
                         ready_queue_name,
                     } => (name, ready_queue_name),
                     RoundRobinResult::Delay(duration) => {
-                        log_disposition(LogDisposition {
-                            kind: RecordType::TransientFailure,
-                            msg: msg.clone(),
-                            site: "",
-                            peer_address: None,
-                            response: Response {
-                                code: 451,
-                                enhanced_code: Some(EnhancedStatusCode {
-                                    class: 4,
-                                    subject: 4,
-                                    detail: 4,
-                                }),
-                                content: format!(
-                                    "all possible sources for {} are suspended",
-                                    self.name
-                                ),
-                                command: None,
-                            },
-                            egress_pool: None,
-                            egress_source: None,
-                            relay_disposition: None,
-                            delivery_protocol: None,
-                            tls_info: None,
-                            source_address: None,
-                            provider: self.queue_config.borrow().provider_name.as_deref(),
-                        })
-                        .await;
-                        msg.delay_by_and_jitter(duration).await?;
-                        return self.force_into_delayed(msg).await;
+                        return self
+                            .delay_for_unavailable_sources(msg, duration, "are suspended")
+                            .await;
+                    }
+                    RoundRobinResult::WarmupDelay(duration) => {
+                        return self
+                            .delay_for_unavailable_sources(
+                                msg,
+                                duration,
+                                "have reached their daily warm-up limit",
+                            )
+                            .await;
                     }
                     RoundRobinResult::NoSources => {
                         log_disposition(LogDisposition {
@@ -1622,137 +2678,140 @@ impl Queue {
                 };
 
                 // Hot path: use cached source -> ready queue mapping
-                if let Some(ready_name) = &ready_name {
-                    if let Some(site) = ReadyQueueManager::get_by_ready_queue_name(&ready_name.name)
-                    {
-                        return site.insert(msg).map_err(|_| ReadyQueueFull.into());
+                let cached = ready_name.as_ref().and_then(|ready_name| {
+                    ReadyQueueManager::get_by_ready_queue_name(&ready_name.name)
+                });
+                let site = match cached {
+                    Some(site) => site,
+                    None => {
+                        // Miss: compute and establish a new queue
+                        match ReadyQueueManager::resolve_by_queue_name(
+                            &self.name,
+                            &self.queue_config,
+                            &egress_source,
+                            &self.rr.name,
+                            self.get_config_epoch(),
+                        )
+                        .await
+                        {
+                            Ok(site) => {
+                                self.stale.lock().note_resolution(false, Instant::now());
+                                site
+                            }
+                            Err(err) => {
+                                let nxdomain = err.chain().any(|cause| cause.is::<NxDomain>());
+                                self.stale.lock().note_resolution(nxdomain, Instant::now());
+                                log_disposition(LogDisposition {
+                                    kind: RecordType::TransientFailure,
+                                    msg: msg.clone(),
+                                    site: "",
+                                    peer_address: None,
+                                    response: Response {
+                                        code: 451,
+                                        enhanced_code: Some(EnhancedStatusCode {
+                                            class: 4,
+                                            subject: 4,
+                                            detail: 4,
+                                        }),
+                                        content: format!(
+                                            "failed to resolve queue {}: {err:#}",
+                                            self.name
+                                        ),
+                                        command: None,
+                                    },
+                                    egress_pool: None,
+                                    egress_source: None,
+                                    relay_disposition: None,
+                                    delivery_protocol: None,
+                                    tls_info: None,
+                                    source_address: None,
+                                    provider: self.queue_config.borrow().provider_name.as_deref(),
+                                })
+                                .await;
+                                anyhow::bail!("failed to resolve queue {}: {err:#}", self.name);
+                            }
+                        }
                     }
-                }
+                };
 
-                // Miss: compute and establish a new queue
-                match ReadyQueueManager::resolve_by_queue_name(
-                    &self.name,
-                    &self.queue_config,
-                    &egress_source,
-                    &self.rr.name,
-                    self.get_config_epoch(),
-                )
-                .await
-                {
-                    Ok(site) => {
-                        return site.insert(msg).map_err(|_| ReadyQueueFull.into());
-                    }
-                    Err(err) => {
-                        log_disposition(LogDisposition {
-                            kind: RecordType::TransientFailure,
-                            msg: msg.clone(),
-                            site: "",
-                            peer_address: None,
-                            response: Response {
-                                code: 451,
-                                enhanced_code: Some(EnhancedStatusCode {
-                                    class: 4,
-                                    subject: 4,
-                                    detail: 4,
-                                }),
-                                content: format!("failed to resolve queue {}: {err:#}", self.name),
-                                command: None,
-                            },
-                            egress_pool: None,
-                            egress_source: None,
-                            relay_disposition: None,
-                            delivery_protocol: None,
-                            tls_info: None,
-                            source_address: None,
-                            provider: self.queue_config.borrow().provider_name.as_deref(),
-                        })
-                        .await;
-                        anyhow::bail!("failed to resolve queue {}: {err:#}", self.name);
-                    }
+                let mut span = StageSpan::start(msg.id(), "ready");
+                if span.is_recording() {
+                    span.set_attribute("queue", self.name.to_string());
+                    span.set_attribute("ready_queue", site.name().to_string());
                 }
+                // The span remains open until the message is dispatched
+                span.wait(msg.id());
+                site.insert(msg).map_err(|_| ReadyQueueFull.into())
             }
             DeliveryProto::Maildir { maildir_path } => {
-                tracing::trace!(
-                    "Deliver msg {} to maildir at {}",
-                    msg.id(),
-                    maildir_path.display()
-                );
-                let maildir_path = maildir_path.to_path_buf();
-
-                msg.load_data_if_needed().await?;
-                let name = self.name.to_string();
-                let result: anyhow::Result<String> = spawn_blocking_on(
-                    "write to maildir",
-                    {
-                        let msg = msg.clone();
-                        move || {
-                            let md = maildir::Maildir::from(maildir_path.clone());
-                            md.create_dirs().with_context(|| {
-                                format!(
-                                    "creating dirs for maildir {maildir_path:?} in queue {}",
-                                    name
-                                )
-                            })?;
-                            Ok(md.store_new(&msg.get_data())?)
-                        }
-                    },
-                    &get_main_runtime(),
-                )?
-                .await?;
-
-                match result {
-                    Ok(id) => {
-                        log_disposition(LogDisposition {
-                            kind: RecordType::Delivery,
-                            msg: msg.clone(),
-                            site: "",
-                            peer_address: None,
-                            response: Response {
-                                code: 200,
-                                enhanced_code: None,
-                                content: format!("wrote to maildir with id={id}"),
-                                command: None,
-                            },
-                            egress_pool: None,
-                            egress_source: None,
-                            relay_disposition: None,
-                            delivery_protocol: Some("Maildir"),
-                            tls_info: None,
-                            source_address: None,
-                            provider: None,
-                        })
-                        .await;
-                        spawn("remove from spool", async move {
-                            SpoolManager::remove_from_spool(*msg.id()).await
-                        })?;
-                        Ok(())
-                    }
-                    Err(err) => {
-                        log_disposition(LogDisposition {
-                            kind: RecordType::TransientFailure,
-                            msg: msg.clone(),
-                            site: "",
-                            peer_address: None,
-                            response: Response {
-                                code: 400,
-                                enhanced_code: None,
-                                content: format!("failed to write to maildir: {err:#}"),
-                                command: None,
-                            },
-                            egress_pool: None,
-                            egress_source: None,
-                            relay_disposition: None,
-                            delivery_protocol: Some("Maildir"),
-                            tls_info: None,
-                            source_address: None,
-                            provider: None,
-                        })
-                        .await;
-                        anyhow::bail!("failed maildir store: {err:#}");
-                    }
-                }
+                self.deliver_to_mailbox(msg, MailboxDeliveryProtocol::maildir(maildir_path))
+                    .await
             }
+            DeliveryProto::Mailbox { mailbox } => {
+                self.deliver_to_mailbox(msg, mailbox.clone()).await
+            }
+        }
+    }
+
+    /// Stores msg into a local mailbox and logs the outcome.
+    /// Transient failures are returned as errors so that the
+    /// message is delayed according to the retry schedule.
+    async fn deliver_to_mailbox(
+        &self,
+        msg: Message,
+        mailbox: MailboxDeliveryProtocol,
+    ) -> anyhow::Result<()> {
+        tracing::trace!("Deliver msg {} to mailbox at {}", msg.id(), mailbox.path);
+
+        msg.load_meta_if_needed().await?;
+        msg.load_data_if_needed().await?;
+        let sender = msg.sender()?;
+        let recipient = msg.recipient()?;
+        let site = self.queue_config.borrow().protocol.ready_queue_name();
+        let delivery_protocol = mailbox.format.delivery_protocol();
+
+        let response = spawn_blocking_on(
+            "write to mailbox",
+            {
+                let msg = msg.clone();
+                move || mailbox.deliver(&sender, &recipient, &msg.get_data())
+            },
+            &get_main_runtime(),
+        )?
+        .await?;
+
+        let kind = if response.is_transient() {
+            RecordType::TransientFailure
+        } else if response.is_permanent() {
+            RecordType::Bounce
+        } else {
+            RecordType::Delivery
+        };
+
+        log_disposition(LogDisposition {
+            kind,
+            msg: msg.clone(),
+            site: &site,
+            peer_address: None,
+            response: response.clone(),
+            egress_pool: None,
+            egress_source: None,
+            relay_disposition: None,
+            delivery_protocol: Some(delivery_protocol),
+            tls_info: None,
+            source_address: None,
+            provider: self.queue_config.borrow().provider_name.as_deref(),
+        })
+        .await;
+
+        if kind == RecordType::TransientFailure {
+            anyhow::bail!("failed mailbox store: {}", response.to_single_line());
         }
+
+        spawn("remove from spool", async move {
+            SpoolManager::remove_from_spool(*msg.id()).await
+        })?;
+        Ok(())
     }
 
     #[instrument(fields(self.name), skip(self, msg))]
@@ -1919,7 +2978,8 @@ async fn queue_meta_maintainer() -> anyhow::Result<()> {
                 for msg in queue.drain_timeq() {
                     Queue::save_if_needed_and_log(&msg).await;
                 }
-                if queue.queue.is_empty() && ReadyQueueManager::number_of_queues() == 0 {
+                queue.forget_spilled().await;
+                if queue.is_empty() && ReadyQueueManager::number_of_queues() == 0 {
                     tracing::debug!(
                         "{name}: there are no more queues and the scheduled queue is empty, reaping"
                     );
@@ -2012,6 +3072,14 @@ impl QueueManager {
         }
     }
 
+    /// Returns true if the configuration for the named queue permits
+    /// deferring writing newly received messages to the spool
+    pub async fn is_deferred_spool(name: &str) -> anyhow::Result<bool> {
+        let queue = Self::resolve(name).await?;
+        let deferred = queue.queue_config.borrow().deferred_spool;
+        Ok(deferred)
+    }
+
     pub fn get_opt(name: &str) -> Option<QueueHandle> {
         let mgr = MANAGER.lock();
         match mgr.named.get(name)? {
@@ -2066,6 +3134,7 @@ async fn maintain_named_queue(q: &QueueHandle) -> anyhow::Result<()> {
                     Queue::save_if_needed_and_log(&msg).await;
                     drop(msg);
                 }
+                q.forget_spilled().await;
 
                 // Bow out and let the queue_meta_maintainer finish up
                 return Ok(());
@@ -2108,6 +3177,12 @@ async fn maintain_named_queue(q: &QueueHandle) -> anyhow::Result<()> {
                     q.insert_ready(msg).await?;
                 }
             }
+
+            if q.spilled.load(Ordering::SeqCst) > 0 {
+                if let Some(spill_due) = q.page_in_spilled().await? {
+                    next_item_due = next_item_due.min(spill_due);
+                }
+            }
         }
     }
 }
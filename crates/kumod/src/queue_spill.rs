@@ -0,0 +1,265 @@
+//! An on-disk index of scheduled messages that have been spilled
+//! out of memory because their scheduled queue grew beyond its
+//! configured `max_scheduled_in_memory` threshold.
+//!
+//! Only the spool id, due time and number of attempts are recorded;
+//! the message itself remains in the spool. The index is held in a
+//! private temporary sqlite database that is discarded when kumod
+//! exits: the spool is enumerated again at startup, so there is no
+//! need for the index to survive a restart.
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use spool::SpoolId;
+use sqlite::Connection;
+
+static STORE: Lazy<Mutex<Option<SpillStore>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpilledEntry {
+    pub id: SpoolId,
+    pub due: DateTime<Utc>,
+    pub num_attempts: u16,
+}
+
+struct SpillStore {
+    db: Connection,
+}
+
+impl SpillStore {
+    fn open() -> anyhow::Result<Self> {
+        // An empty path causes sqlite to create a private temporary
+        // on-disk database that is deleted when it is closed
+        Self::with_connection(Connection::open("")?)
+    }
+
+    fn with_connection(db: Connection) -> anyhow::Result<Self> {
+        let query = r#"
+PRAGMA journal_mode = OFF;
+PRAGMA synchronous = OFF;
+
+CREATE TABLE IF NOT EXISTS spilled (
+    queue text NOT NULL,
+    id text NOT NULL,
+    due integer NOT NULL,
+    num_attempts integer NOT NULL,
+    PRIMARY KEY (queue, id)
+);
+
+CREATE INDEX IF NOT EXISTS spilled_due ON spilled (queue, due);
+    "#;
+
+        db.execute(query)?;
+
+        Ok(Self { db })
+    }
+
+    fn spill(&self, queue: &str, entry: &SpilledEntry) -> anyhow::Result<()> {
+        let mut stmt = self.db.prepare(
+            "INSERT OR REPLACE INTO spilled (queue, id, due, num_attempts)
+                VALUES ($queue, $id, $due, $num_attempts)",
+        )?;
+        stmt.bind(("$queue", queue))?;
+        stmt.bind(("$id", entry.id.to_string().as_str()))?;
+        stmt.bind(("$due", entry.due.timestamp_millis()))?;
+        stmt.bind(("$num_attempts", entry.num_attempts as i64))?;
+        stmt.next()?;
+        Ok(())
+    }
+
+    /// Removes and returns up to `limit` entries, earliest first,
+    /// that are due no later than `due_by`.
+    fn take_due(
+        &self,
+        queue: &str,
+        due_by: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SpilledEntry>> {
+        let mut entries = vec![];
+        self.db.execute("BEGIN")?;
+        let result = (|| {
+            let mut stmt = self.db.prepare(
+                "SELECT id, due, num_attempts FROM spilled
+                    WHERE queue = $queue AND due <= $due_by
+                    ORDER BY due LIMIT $limit",
+            )?;
+            stmt.bind(("$queue", queue))?;
+            stmt.bind((
+                "$due_by",
+                due_by.map(|due| due.timestamp_millis()).unwrap_or(i64::MAX),
+            ))?;
+            stmt.bind(("$limit", limit.min(i64::MAX as usize) as i64))?;
+
+            while let sqlite::State::Row = stmt.next()? {
+                let id: String = stmt.read("id")?;
+                let due: i64 = stmt.read("due")?;
+                let num_attempts: i64 = stmt.read("num_attempts")?;
+                let Some(id) = SpoolId::from_str(&id) else {
+                    anyhow::bail!("invalid spool id {id} in spill index");
+                };
+                let due = DateTime::from_timestamp_millis(due).unwrap_or_else(Utc::now);
+                entries.push(SpilledEntry {
+                    id,
+                    due,
+                    num_attempts: num_attempts.try_into().unwrap_or(u16::MAX),
+                });
+            }
+
+            let mut delete = self
+                .db
+                .prepare("DELETE FROM spilled WHERE queue = $queue AND id = $id")?;
+            for entry in &entries {
+                delete.reset()?;
+                delete.bind(("$queue", queue))?;
+                delete.bind(("$id", entry.id.to_string().as_str()))?;
+                delete.next()?;
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => {
+                self.db.execute("COMMIT")?;
+                Ok(entries)
+            }
+            Err(err) => {
+                self.db.execute("ROLLBACK").ok();
+                Err(err)
+            }
+        }
+    }
+
+    fn next_due(&self, queue: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT MIN(due) AS due FROM spilled WHERE queue = $queue")?;
+        stmt.bind(("$queue", queue))?;
+        if let sqlite::State::Row = stmt.next()? {
+            let due: Option<i64> = stmt.read("due")?;
+            return Ok(due.and_then(DateTime::from_timestamp_millis));
+        }
+        Ok(None)
+    }
+
+    fn ids(&self, queue: &str) -> anyhow::Result<Vec<SpoolId>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT id FROM spilled WHERE queue = $queue")?;
+        stmt.bind(("$queue", queue))?;
+        let mut ids = vec![];
+        while let sqlite::State::Row = stmt.next()? {
+            let id: String = stmt.read("id")?;
+            if let Some(id) = SpoolId::from_str(&id) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn remove_queue(&self, queue: &str) -> anyhow::Result<usize> {
+        let mut stmt = self
+            .db
+            .prepare("DELETE FROM spilled WHERE queue = $queue")?;
+        stmt.bind(("$queue", queue))?;
+        stmt.next()?;
+        Ok(self.db.change_count())
+    }
+}
+
+async fn with_store<T, F>(func: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&SpillStore) -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut store = STORE.lock();
+        if store.is_none() {
+            store.replace(SpillStore::open()?);
+        }
+        func(store.as_ref().expect("opened above"))
+    })
+    .await?
+}
+
+/// Record entry in the index for the named queue
+pub async fn spill(queue: &str, entry: SpilledEntry) -> anyhow::Result<()> {
+    let queue = queue.to_string();
+    with_store(move |store| store.spill(&queue, &entry)).await
+}
+
+/// Removes and returns up to `limit` of the entries of the named
+/// queue that are due no later than `due_by`, earliest first.
+/// If `due_by` is None, entries are returned regardless of
+/// their due time.
+pub async fn take_due(
+    queue: &str,
+    due_by: Option<DateTime<Utc>>,
+    limit: usize,
+) -> anyhow::Result<Vec<SpilledEntry>> {
+    let queue = queue.to_string();
+    with_store(move |store| store.take_due(&queue, due_by, limit)).await
+}
+
+/// Returns the earliest due time of the entries of the named queue
+pub async fn next_due(queue: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let queue = queue.to_string();
+    with_store(move |store| store.next_due(&queue)).await
+}
+
+/// Returns the ids of the entries of the named queue
+pub async fn ids(queue: &str) -> anyhow::Result<Vec<SpoolId>> {
+    let queue = queue.to_string();
+    with_store(move |store| store.ids(&queue)).await
+}
+
+/// Removes all of the entries of the named queue,
+/// returning the number that were removed
+pub async fn remove_queue(queue: &str) -> anyhow::Result<usize> {
+    let queue = queue.to_string();
+    with_store(move |store| store.remove_queue(&queue)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_store() -> SpillStore {
+        SpillStore::with_connection(Connection::open(":memory:").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn spill_and_take() {
+        let store = new_store();
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let entries: Vec<SpilledEntry> = (0..5)
+            .map(|i| SpilledEntry {
+                id: SpoolId::new(),
+                due: now + kumo_chrono_helper::MINUTE * i,
+                num_attempts: i as u16,
+            })
+            .collect();
+
+        // Insert out of order to verify that they come back sorted
+        for entry in entries.iter().rev() {
+            store.spill("a.example.com", entry).unwrap();
+        }
+        store.spill("b.example.com", &entries[0]).unwrap();
+
+        assert_eq!(store.next_due("a.example.com").unwrap(), Some(now));
+        assert_eq!(store.next_due("c.example.com").unwrap(), None);
+
+        let due = store
+            .take_due("a.example.com", Some(now + kumo_chrono_helper::MINUTE), 10)
+            .unwrap();
+        assert_eq!(due, entries[0..2].to_vec());
+
+        let due = store.take_due("a.example.com", None, 2).unwrap();
+        assert_eq!(due, entries[2..4].to_vec());
+
+        assert_eq!(store.ids("a.example.com").unwrap(), vec![entries[4].id]);
+        assert_eq!(store.remove_queue("a.example.com").unwrap(), 1);
+        assert_eq!(store.next_due("a.example.com").unwrap(), None);
+
+        // The other queue is unaffected
+        assert_eq!(store.ids("b.example.com").unwrap(), vec![entries[0].id]);
+    }
+}
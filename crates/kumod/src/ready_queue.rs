@@ -1,33 +1,41 @@
+use crate::amqp_deliver::AmqpQueueDispatcher;
 use crate::delivery_metrics::{DeliveryMetrics, ReadyCountBundle};
 use crate::egress_source::EgressSource;
+use crate::http_api_deliver::HttpApiQueueDispatcher;
 use crate::http_server::admin_bounce_v1::AdminBounceEntry;
 use crate::http_server::admin_suspend_ready_q_v1::{
     AdminSuspendReadyQEntry, AdminSuspendReadyQEntryRef,
 };
 use crate::http_server::inject_v1::HttpInjectionGeneratorDispatcher;
+use crate::kafka_deliver::KafkaQueueDispatcher;
 use crate::logging::disposition::{log_disposition, LogDisposition, RecordType};
 use crate::lua_deliver::LuaQueueDispatcher;
+use crate::message_tracing::{end_waiting, StageSpan};
 use crate::metrics_helper::TOTAL_READYQ_RUNS;
 use crate::queue::{DeliveryProto, Queue, QueueConfig, QueueManager, QMAINT_RUNTIME};
-use crate::smtp_dispatcher::{MxListEntry, OpportunisticInsecureTlsHandshakeError, SmtpDispatcher};
+use crate::smtp_connection_pool::PooledConnection;
+use crate::smtp_dispatcher::{OpportunisticInsecureTlsHandshakeError, SmtpDispatcher};
 use crate::spool::SpoolManager;
+use crate::traffic_shaping::{self, ShapingResult};
+use crate::warmup::{WarmupOverflow, WarmupStatus};
 use anyhow::Context;
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use config::epoch::ConfigEpoch;
-use config::{load_config, CallbackSignature};
+use config::{load_config, CallbackSignature, LuaConfig};
 use crossbeam_queue::ArrayQueue;
 use dns_resolver::MailExchanger;
-use kumo_api_types::egress_path::{ConfigRefreshStrategy, EgressPathConfig};
+use kumo_api_types::egress_path::{ConfigRefreshStrategy, ConnectionStrategy, EgressPathConfig};
+use kumo_log_types::ResolvedAddress;
 use kumo_server_common::config_handle::ConfigHandle;
 use kumo_server_lifecycle::{is_shutting_down, Activity, ShutdownSubcription};
 use kumo_server_memory::{get_headroom, low_memory, subscribe_to_memory_status_changes};
-use kumo_server_runtime::{spawn, Runtime};
+use kumo_server_runtime::{spawn, spawn_local, Runtime};
 use message::message::QueueNameComponents;
 use message::Message;
 use parking_lot::FairMutex as StdMutex;
 use rfc5321::{EnhancedStatusCode, Response};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::Debug;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -45,7 +53,7 @@ lazy_static::lazy_static! {
     pub static ref READYQ_RUNTIME: Runtime = Runtime::new(
         "readyq", |cpus| cpus / 2, &READYQ_THREADS).unwrap();
     pub static ref GET_EGRESS_PATH_CONFIG_SIG: CallbackSignature<'static,
-        (String, String, String), EgressPathConfig> = CallbackSignature::new("get_egress_path_config");
+        (String, String, String, Option<String>), EgressPathConfig> = CallbackSignature::new("get_egress_path_config");
 }
 
 const ONE_MINUTE: Duration = Duration::from_secs(60);
@@ -56,8 +64,18 @@ pub fn set_readyq_threads(n: usize) {
     READYQ_THREADS.store(n, Ordering::SeqCst);
 }
 
+/// The ready messages of a ReadyQueue.
+/// Messages with the default priority of 0 are held in a lock-free
+/// ArrayQueue. Messages with any other priority are held in bands
+/// ordered by priority: those with a positive priority are popped
+/// ahead of the ArrayQueue, and those with a negative priority
+/// once the ArrayQueue is empty.
 pub struct Fifo {
     queue: ArcSwap<ArrayQueue<Message>>,
+    prioritized: StdMutex<BTreeMap<i32, VecDeque<Message>>>,
+    /// The number of messages in prioritized, so that the common
+    /// case of no prioritized messages doesn't need to lock it
+    num_prioritized: AtomicUsize,
     count: ReadyCountBundle,
 }
 
@@ -65,30 +83,89 @@ impl Fifo {
     pub fn new(capacity: usize, count: ReadyCountBundle) -> Self {
         Self {
             queue: Arc::new(ArrayQueue::new(capacity)).into(),
+            prioritized: StdMutex::new(BTreeMap::new()),
+            num_prioritized: AtomicUsize::new(0),
             count,
         }
     }
 
     pub fn push(&self, msg: Message) -> Result<(), Message> {
-        self.queue.load().push(msg)?;
+        let priority = msg.get_priority();
+        if priority == 0 {
+            if self.num_prioritized.load(Ordering::Relaxed) > 0 && self.is_full() {
+                return Err(msg);
+            }
+            self.queue.load().push(msg)?;
+        } else {
+            let mut bands = self.prioritized.lock();
+            if self.is_full() {
+                return Err(msg);
+            }
+            bands.entry(priority).or_default().push_back(msg);
+            self.num_prioritized.fetch_add(1, Ordering::Relaxed);
+        }
         self.count.inc();
         Ok(())
     }
 
     #[must_use]
     pub fn pop(&self) -> Option<Message> {
-        let msg = self.queue.load().pop()?;
+        let msg = self
+            .pop_prioritized(|priority| priority > 0)
+            .or_else(|| self.queue.load().pop())
+            .or_else(|| self.pop_prioritized(|_| true))?;
         self.count.dec();
         Some(msg)
     }
 
+    /// Pop the oldest message of the highest priority band,
+    /// if that priority satisfies `wanted`
+    fn pop_prioritized(&self, wanted: impl Fn(i32) -> bool) -> Option<Message> {
+        if self.num_prioritized.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let mut bands = self.prioritized.lock();
+        let mut band = bands.last_entry()?;
+        if !wanted(*band.key()) {
+            return None;
+        }
+        let msg = band.get_mut().pop_front();
+        if band.get().is_empty() {
+            band.remove();
+        }
+        if msg.is_some() {
+            self.num_prioritized.fetch_sub(1, Ordering::Relaxed);
+        }
+        msg
+    }
+
+    fn is_full(&self) -> bool {
+        self.len() >= self.queue.load().capacity()
+    }
+
+    /// Removes all of the messages, in the order in which
+    /// they would have been popped
     #[must_use]
     pub fn drain(&self) -> Vec<Message> {
         let queue = self.queue.load();
-        let mut messages = Vec::with_capacity(queue.len());
+        let mut messages = Vec::with_capacity(self.len());
+        let (high, low) = {
+            let mut bands = self.prioritized.lock();
+            let high = bands.split_off(&1);
+            (high, std::mem::take(&mut *bands))
+        };
+        let num_prioritized = high
+            .values()
+            .chain(low.values())
+            .map(|band| band.len())
+            .sum();
+        self.num_prioritized
+            .fetch_sub(num_prioritized, Ordering::Relaxed);
+        messages.extend(high.into_values().rev().flatten());
         while let Some(msg) = queue.pop() {
             messages.push(msg);
         }
+        messages.extend(low.into_values().rev().flatten());
         self.count.sub(messages.len());
         messages
     }
@@ -100,7 +177,9 @@ impl Fifo {
     /// The old queue is then drained into the new queue.
     /// Any messages that won't fit into the new queue are
     /// returned to the caller, who is responsible for re-inserting
-    /// those messages into the scheduled queue
+    /// those messages into the scheduled queue.
+    /// Prioritized messages are not moved, but count towards the
+    /// capacity when new messages are pushed.
     #[must_use]
     pub fn update_capacity(&self, capacity: usize) -> Vec<Message> {
         let queue = self.queue.load();
@@ -126,7 +205,7 @@ impl Fifo {
     }
 
     pub fn len(&self) -> usize {
-        self.queue.load().len()
+        self.queue.load().len() + self.num_prioritized.load(Ordering::Relaxed)
     }
 }
 
@@ -202,23 +281,13 @@ impl ReadyQueueManager {
         // or tenant because those have no bearing from the perspective of
         // the recipient.
         let site_name = match &queue_config.borrow().protocol {
-            DeliveryProto::Smtp { smtp } => {
-                if smtp.mx_list.is_empty() {
-                    mx.replace(MailExchanger::resolve(routing_domain).await?);
-                    mx.as_ref().unwrap().site_name.to_string()
-                } else {
-                    let mut mx_list = vec![];
-                    for a in smtp.mx_list.iter() {
-                        match a {
-                            MxListEntry::Name(a) => {
-                                mx_list.push(a.clone());
-                            }
-                            MxListEntry::Resolved(addr) => {
-                                mx_list.push(addr.addr.to_string());
-                            }
-                        }
+            DeliveryProto::Smtp { smtp } | DeliveryProto::Lmtp { lmtp: smtp } => {
+                match smtp.explicit_site_name() {
+                    Some(site_name) => site_name,
+                    None => {
+                        mx.replace(MailExchanger::resolve(routing_domain).await?);
+                        mx.as_ref().unwrap().site_name.to_string()
                     }
-                    format!("mx_list:{}", mx_list.join(","))
                 }
             }
             _ => routing_domain.to_string(),
@@ -231,10 +300,17 @@ impl ReadyQueueManager {
         // tsa-daemon/src/http_server that reverses/extracts the site_name
         // portion of this string.
         // If you change this then you must update that other logic accordingly.
-        let name = format!(
+        let mut name = format!(
             "{egress_source}->{site_name}@{}",
             queue_config.borrow().protocol.ready_queue_name()
         );
+        // Queues with a shaping_tier are kept apart from the rest of
+        // the traffic to the same site, so that they can be shaped
+        // independently.
+        if let Some(tier) = &queue_config.borrow().shaping_tier {
+            name.push('#');
+            name.push_str(tier);
+        }
 
         Ok(ReadyQueueName {
             name,
@@ -243,10 +319,22 @@ impl ReadyQueueManager {
         })
     }
 
-    async fn compute_config(
+    pub async fn compute_config(
         queue_name: &str,
         queue_config: &ConfigHandle<QueueConfig>,
         egress_source: &str,
+    ) -> anyhow::Result<ReadyQueueConfig> {
+        let mut config = load_config().await?;
+        Self::compute_config_with(queue_name, queue_config, egress_source, &mut config).await
+    }
+
+    /// Like compute_config, but calls the policy via the provided
+    /// lua context
+    pub async fn compute_config_with(
+        queue_name: &str,
+        queue_config: &ConfigHandle<QueueConfig>,
+        egress_source: &str,
+        config: &mut LuaConfig,
     ) -> anyhow::Result<ReadyQueueConfig> {
         let ReadyQueueName {
             name,
@@ -260,9 +348,7 @@ impl ReadyQueueManager {
             .as_deref()
             .unwrap_or(&components.domain);
 
-        let mut config = load_config().await?;
-
-        let egress_source = EgressSource::resolve(egress_source, &mut config).await?;
+        let egress_source = EgressSource::resolve(egress_source, config).await?;
 
         let path_config: EgressPathConfig = config
             .async_call_callback(
@@ -271,6 +357,7 @@ impl ReadyQueueManager {
                     routing_domain.to_string(),
                     egress_source.name.to_string(),
                     site_name.clone(),
+                    queue_config.borrow().shaping_tier.clone(),
                 ),
             )
             .await
@@ -306,7 +393,7 @@ impl ReadyQueueManager {
     ) -> anyhow::Result<ReadyQueueHandle> {
         let ReadyQueueConfig {
             name,
-            site_name: _,
+            site_name,
             path_config,
             egress_source,
             mx,
@@ -345,6 +432,7 @@ impl ReadyQueueManager {
 
             Arc::new(ReadyQueue {
                 name: name.clone(),
+                site_name,
                 queue_name_for_config_change_purposes_only: queue_name.to_string(),
                 ready,
                 mx,
@@ -499,6 +587,7 @@ pub type ReadyQueueHandle = Arc<ReadyQueue>;
 
 pub struct ReadyQueue {
     name: String,
+    site_name: String,
     queue_name_for_config_change_purposes_only: String,
     ready: Arc<Fifo>,
     mx: Option<Arc<MailExchanger>>,
@@ -522,6 +611,20 @@ impl ReadyQueue {
         &self.name
     }
 
+    pub fn site_name(&self) -> &str {
+        &self.site_name
+    }
+
+    /// The destination for which the health and warm-up usage of
+    /// our egress source are tracked; see `source_health::destination`
+    pub fn destination(&self) -> String {
+        crate::source_health::destination(
+            &self.site_name,
+            self.path_config.borrow().provider_name.as_deref(),
+        )
+        .to_string()
+    }
+
     pub fn insert(&self, msg: Message) -> Result<(), Message> {
         if low_memory() {
             msg.shrink().ok();
@@ -551,9 +654,15 @@ impl ReadyQueue {
         } else if suspend.is_some() {
             0
         } else {
-            let n = ideal_connection_count(
+            let mut connection_limit = self.path_config.borrow().connection_limit;
+            if let Some(limit) = traffic_shaping::site_connection_limit(&self.site_name) {
+                connection_limit = connection_limit.min(limit);
+            }
+            let n = strategy_connection_count(
+                &self.path_config.borrow().connection_strategy,
                 self.ready_count(),
-                self.path_config.borrow().connection_limit,
+                connection_limit,
+                self.metrics.delivery_latency.average(),
             );
             if n > 0 && get_headroom() == 0 {
                 n.min(2)
@@ -689,6 +798,41 @@ impl ReadyQueue {
             return;
         }
 
+        if crate::source_health::is_unhealthy(
+            &self.egress_source.name,
+            crate::source_health::destination(
+                &self.site_name,
+                self.path_config.borrow().provider_name.as_deref(),
+            ),
+        ) {
+            tracing::trace!(
+                "{}: egress source {} is unhealthy, rebalancing ready queue",
+                self.name,
+                self.egress_source.name
+            );
+            self.reinsert_ready_queue("unhealthy source").await;
+            self.notify_dispatcher.notify_waiters();
+            return;
+        }
+
+        if crate::source_exclusion::excluded_remaining(
+            &self.egress_source.name,
+            &self.site_name,
+            path_config.provider_name.as_deref(),
+        )
+        .is_some()
+        {
+            tracing::trace!(
+                "{}: egress source {} is excluded from {}, rebalancing ready queue",
+                self.name,
+                self.egress_source.name,
+                self.site_name
+            );
+            self.reinsert_ready_queue("excluded source").await;
+            self.notify_dispatcher.notify_waiters();
+            return;
+        }
+
         let ideal = self.ideal_connection_count(suspend);
         tracing::trace!(
             "maintain {}: computed ideal connection count as {ideal} \
@@ -722,34 +866,53 @@ impl ReadyQueue {
             limits.sort_by_key(|(_, LimitSpec { limit, .. })| *limit);
 
             'new_dispatcher: for _ in current_connection_count..ideal {
-                let mut leases = vec![];
-                for (label, limit) in &limits {
-                    match limit.acquire_lease(label).await {
-                        Ok(lease) => {
-                            leases.push(lease);
-                        }
-                        Err(err @ throttle::Error::TooManyLeases(_)) => {
-                            // Over budget; we'll try again later
-                            tracing::debug!(
-                                "maintain {}: could not acquire connection lease {label}: {err:#}",
-                                self.name
-                            );
-                            break 'new_dispatcher;
-                        }
-                        Err(err) => {
-                            // Some kind of error trying to acquire the lease, could be
-                            // a redis/connectivity error, let's surface it
-                            tracing::error!(
-                                "maintain {}: could not acquire connection lease {label}: {err:#}",
-                                self.name
-                            );
-                            break 'new_dispatcher;
+                // Prefer to hand an idle pooled connection, along with the
+                // leases that it holds, to the new dispatcher, so that the
+                // pool continues to be used when the limits are exhausted
+                let mut pooled = path_config
+                    .connection_pool_idle_timeout
+                    .and_then(|max_idle| {
+                        crate::smtp_connection_pool::check_out(
+                            &self.name,
+                            max_idle,
+                            path_config.max_connection_age,
+                        )
+                    });
+                let leases = match &mut pooled {
+                    Some(conn) => conn.take_leases().await,
+                    None => {
+                        let mut leases = vec![];
+                        for (label, limit) in &limits {
+                            match limit.acquire_lease(label).await {
+                                Ok(lease) => {
+                                    leases.push(lease);
+                                }
+                                Err(err @ throttle::Error::TooManyLeases(_)) => {
+                                    // Over budget; we'll try again later
+                                    tracing::debug!(
+                                        "maintain {}: could not acquire connection lease {label}: {err:#}",
+                                        self.name
+                                    );
+                                    break 'new_dispatcher;
+                                }
+                                Err(err) => {
+                                    // Some kind of error trying to acquire the lease, could be
+                                    // a redis/connectivity error, let's surface it
+                                    tracing::error!(
+                                        "maintain {}: could not acquire connection lease {label}: {err:#}",
+                                        self.name
+                                    );
+                                    break 'new_dispatcher;
+                                }
+                            }
                         }
+                        leases
                     }
-                }
+                };
 
                 // Open a new connection
                 let name = self.name.clone();
+                let site_name = self.site_name.clone();
                 let queue_name_for_config_change_purposes_only =
                     self.queue_name_for_config_change_purposes_only.clone();
                 let mx = self.mx.clone();
@@ -768,6 +931,7 @@ impl ReadyQueue {
                         Ok(async move {
                             if let Err(err) = Dispatcher::run(
                                 &name,
+                                site_name,
                                 queue_name_for_config_change_purposes_only,
                                 mx,
                                 ready,
@@ -779,6 +943,7 @@ impl ReadyQueue {
                                 egress_source,
                                 egress_pool,
                                 leases,
+                                pooled,
                             )
                             .await
                             {
@@ -882,6 +1047,19 @@ impl Drop for ReadyQueue {
     }
 }
 
+/// The outcome of Dispatcher::gate_batch_message
+pub enum BatchAdmission {
+    /// The message may join the batch. The leases must be held
+    /// until the delivery attempt has completed.
+    Admit(Message, Vec<LimitLease>),
+    /// The message was bounced or delayed, and is no longer the
+    /// responsibility of the caller
+    Skip,
+    /// The message was returned to the ready queue, and no further
+    /// messages should be added to the batch at this time
+    Stop,
+}
+
 #[async_trait(?Send)]
 pub trait QueueDispatcher: Debug + Send {
     async fn deliver_message(
@@ -913,11 +1091,23 @@ pub struct Dispatcher {
     pub activity: Activity,
     pub egress_source: EgressSource,
     pub egress_pool: String,
+    /// The site name of the destination, which is used to match
+    /// traffic shaping overrides
+    pub site_name: String,
     pub delivered_this_connection: usize,
+    /// The MAILMAX limit advertised by the peer of the current
+    /// connection via the LIMITS extension, if any
+    pub peer_mail_max: Option<usize>,
+    /// When the current connection was established, if known.
+    /// Used to enforce max_connection_age.
+    pub connection_established: Option<Instant>,
     pub msg: Option<Message>,
     pub delivery_protocol: String,
     pub suspended: Option<AdminSuspendReadyQEntryRef>,
     leases: Vec<LimitLease>,
+    /// An idle connection from the connection pool that was handed
+    /// to this dispatcher when it was started, along with its leases
+    pub pooled_connection: Option<PooledConnection>,
 }
 
 impl Drop for Dispatcher {
@@ -953,9 +1143,10 @@ impl Drop for Dispatcher {
 }
 
 impl Dispatcher {
-    #[instrument(skip(ready, metrics, notify_dispatcher))]
+    #[instrument(skip(ready, metrics, notify_dispatcher, pooled_connection))]
     async fn run(
         name: &str,
+        site_name: String,
         queue_name_for_config_change_purposes_only: String,
         mx: Option<Arc<MailExchanger>>,
         ready: Arc<Fifo>,
@@ -967,18 +1158,25 @@ impl Dispatcher {
         egress_source: EgressSource,
         egress_pool: String,
         leases: Vec<LimitLease>,
+        pooled_connection: Option<PooledConnection>,
     ) -> anyhow::Result<()> {
         let activity = Activity::get(format!("ready_queue Dispatcher {name}"))?;
 
         let delivery_protocol = match &queue_config.borrow().protocol {
             DeliveryProto::Smtp { .. } => "ESMTP".to_string(),
+            DeliveryProto::Lmtp { .. } => "LMTP".to_string(),
             DeliveryProto::Lua { .. } => "Lua".to_string(),
+            DeliveryProto::HttpApi { .. } => "HttpApi".to_string(),
+            DeliveryProto::Kafka { .. } => "Kafka".to_string(),
+            DeliveryProto::Amqp { .. } => "Amqp".to_string(),
             DeliveryProto::Maildir { .. } => "Maildir".to_string(),
+            DeliveryProto::Mailbox { mailbox } => mailbox.format.delivery_protocol().to_string(),
             DeliveryProto::HttpInjectionGenerator => "HttpInjectionGenerator".to_string(),
         };
 
         let mut dispatcher = Self {
             name: name.to_string(),
+            site_name,
             queue_name_for_config_change_purposes_only,
             ready,
             notify_dispatcher,
@@ -990,14 +1188,23 @@ impl Dispatcher {
             egress_source,
             egress_pool,
             delivered_this_connection: 0,
+            peer_mail_max: None,
+            connection_established: None,
             delivery_protocol,
             leases,
+            pooled_connection,
             suspended: None,
         };
 
         let mut queue_dispatcher: Box<dyn QueueDispatcher> = match &queue_config.borrow().protocol {
             DeliveryProto::Smtp { smtp } => {
-                match SmtpDispatcher::init(&mut dispatcher, smtp).await? {
+                match SmtpDispatcher::init(&mut dispatcher, smtp, false).await? {
+                    Some(disp) => Box::new(disp),
+                    None => return Ok(()),
+                }
+            }
+            DeliveryProto::Lmtp { lmtp } => {
+                match SmtpDispatcher::init(&mut dispatcher, lmtp, true).await? {
                     Some(disp) => Box::new(disp),
                     None => return Ok(()),
                 }
@@ -1008,9 +1215,17 @@ impl Dispatcher {
                 let lua_config = load_config().await?;
                 Box::new(LuaQueueDispatcher::new(lua_config, proto_config.clone()))
             }
+            DeliveryProto::HttpApi { http_api } => {
+                Box::new(HttpApiQueueDispatcher::new(http_api.clone()))
+            }
+            DeliveryProto::Kafka { kafka } => Box::new(KafkaQueueDispatcher::new(kafka.clone())),
+            DeliveryProto::Amqp { amqp } => Box::new(AmqpQueueDispatcher::new(amqp.clone())),
             DeliveryProto::Maildir { .. } => {
                 anyhow::bail!("Should not reach Dispatcher::run with DeliveryProto::Maildir")
             }
+            DeliveryProto::Mailbox { .. } => {
+                anyhow::bail!("Should not reach Dispatcher::run with DeliveryProto::Mailbox")
+            }
             DeliveryProto::HttpInjectionGenerator => {
                 Box::new(HttpInjectionGeneratorDispatcher::new())
             }
@@ -1027,7 +1242,11 @@ impl Dispatcher {
             if dispatcher.msg.is_none() {
                 // We raced with another dispatcher and there is no
                 // more work to be done; no need to open a new connection.
+                // If we were handed a pooled connection, this returns
+                // it to the pool.
+                let result = queue_dispatcher.close_connection(&mut dispatcher).await;
                 dispatcher.release_leases().await;
+                result?;
                 return Ok(());
             }
         }
@@ -1042,10 +1261,12 @@ impl Dispatcher {
                 .await?
             {
                 // No more messages within our idle time; we can close
-                // the connection
+                // the connection. If it is pooled, it takes our leases
+                // with it, so close it before releasing them.
                 tracing::debug!("{} Idling out connection", dispatcher.name);
+                let result = queue_dispatcher.close_connection(&mut dispatcher).await;
                 dispatcher.release_leases().await;
-                queue_dispatcher.close_connection(&mut dispatcher).await?;
+                result?;
                 return Ok(());
             }
 
@@ -1168,6 +1389,150 @@ impl Dispatcher {
         }
     }
 
+    /// The message rate throttles that apply to this path, ordered
+    /// from smallest to largest so that we avoid taking up a slot
+    /// from a larger one only to hit a smaller one and not do
+    /// anything useful with the larger one
+    fn message_rate_throttles<'a>(
+        &self,
+        path_config: &'a EgressPathConfig,
+    ) -> Vec<(String, &'a ThrottleSpec)> {
+        let mut throttles = vec![];
+        if let Some(throttle) = &path_config.max_message_rate {
+            throttles.push((format!("kumomta.max_message_rate.{}", self.name), throttle));
+        }
+        for (key, throttle) in &path_config.additional_message_rate_throttles {
+            throttles.push((key.to_string(), throttle));
+        }
+        throttles.sort_by_key(|(_, spec)| {
+            ((spec.limit as f64 / spec.period as f64) * 1_000_000.0) as u64
+        });
+        throttles
+    }
+
+    /// Applies the admin bounce, suspension, message rate throttle and
+    /// traffic shaping checks that deliver_message applies to self.msg
+    /// to msg, which the caller has popped from the ready queue in order
+    /// to send it in the same delivery attempt as self.msg.
+    /// The metadata of msg must already be loaded.
+    pub async fn gate_batch_message(&mut self, msg: Message) -> anyhow::Result<BatchAdmission> {
+        if let Ok(queue_name) = msg.get_queue_name() {
+            if let Some(entry) = AdminBounceEntry::get_for_queue_name(&queue_name) {
+                entry.log(msg.clone(), None).await;
+                SpoolManager::remove_from_spool(*msg.id()).await.ok();
+                return Ok(BatchAdmission::Skip);
+            }
+        }
+
+        if AdminSuspendReadyQEntry::get_for_queue_name(&self.name).is_some() {
+            self.return_to_ready(msg)?;
+            return Ok(BatchAdmission::Stop);
+        }
+
+        // Unlike deliver_message, we don't wait for a throttle to
+        // permit the message; the batch is sent without it instead
+        let path_config = self.path_config.borrow();
+        for (key, throttle) in self.message_rate_throttles(&path_config) {
+            let result = match throttle.throttle(&key).await {
+                Ok(result) => result,
+                Err(err) => {
+                    self.return_to_ready(msg)?;
+                    return Err(err).with_context(|| format!("apply {key} throttle"));
+                }
+            };
+            if result.retry_after.is_some() {
+                self.return_to_ready(msg)?;
+                return Ok(BatchAdmission::Stop);
+            }
+        }
+
+        let shaping = traffic_shaping::check_message(
+            &self.site_name,
+            &msg,
+            path_config.client_timeouts.total_message_send_duration(),
+        )
+        .await;
+        match shaping {
+            Ok(ShapingResult::Proceed(leases)) => {
+                if !self.consume_warmup(&msg)? {
+                    return Ok(BatchAdmission::Skip);
+                }
+                Ok(BatchAdmission::Admit(msg, leases))
+            }
+            Ok(ShapingResult::Delay(delay)) => {
+                tracing::trace!(
+                    "{} traffic shaping override delays {} by {delay:?}",
+                    self.name,
+                    msg.id()
+                );
+                let delay = chrono::Duration::from_std(delay).unwrap_or(kumo_chrono_helper::MINUTE);
+                spawn_local(
+                    "requeue message".to_string(),
+                    Self::requeue_message(msg, false, Some(delay)),
+                )?;
+                Ok(BatchAdmission::Skip)
+            }
+            Err(err) => {
+                self.return_to_ready(msg)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Counts msg against the warm-up allowance of our egress source
+    /// for this destination. If that has been exhausted, msg is either
+    /// reinserted into its scheduled queue, so that it can be assigned to
+    /// a mature source, or delayed until the limits reset, according to
+    /// the overflow setting of the schedule, and false is returned.
+    fn consume_warmup(&self, msg: &Message) -> anyhow::Result<bool> {
+        let path_config = self.path_config.borrow();
+        let destination = crate::source_health::destination(
+            &self.site_name,
+            path_config.provider_name.as_deref(),
+        );
+        let overflow =
+            match crate::warmup::try_consume(*msg.id(), &self.egress_source.name, destination) {
+                WarmupStatus::Available => return Ok(true),
+                WarmupStatus::Exhausted(overflow) => overflow,
+            };
+        tracing::trace!(
+            "{}: egress source {} has reached its warm-up limit for {destination}, {overflow:?}",
+            self.name,
+            self.egress_source.name
+        );
+        match overflow {
+            WarmupOverflow::Spill => {
+                spawn_local(
+                    "reinsert message".to_string(),
+                    Self::reinsert_message(msg.clone()),
+                )?;
+            }
+            WarmupOverflow::Defer => {
+                spawn_local(
+                    "requeue message".to_string(),
+                    Self::requeue_message(
+                        msg.clone(),
+                        false,
+                        Some(crate::warmup::time_until_reset()),
+                    ),
+                )?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Put msg back into the ready queue, or if that has since
+    /// filled up, back into its scheduled queue
+    pub fn return_to_ready(&self, msg: Message) -> anyhow::Result<()> {
+        if let Err(msg) = self.ready.push(msg) {
+            spawn_local(
+                "requeue message".to_string(),
+                Self::requeue_message(msg, false, None),
+            )?;
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     async fn deliver_message(
         &mut self,
@@ -1188,43 +1553,51 @@ impl Dispatcher {
         // guard, so that a delay due to throttling doesn't result
         // in a delay of shutdown
         let path_config = self.path_config.borrow();
-        let num_throttles = if path_config.max_message_rate.is_some() {
-            1
-        } else {
-            0
-        } + path_config.additional_message_rate_throttles.len();
-        if num_throttles > 0 {
-            let mut throttles = Vec::with_capacity(num_throttles);
-            let message_rate_name;
-
-            if let Some(throttle) = &path_config.max_message_rate {
-                message_rate_name = format!("kumomta.max_message_rate.{}", self.name);
-                throttles.push((&message_rate_name, throttle));
-            }
-            for (key, throttle) in &path_config.additional_message_rate_throttles {
-                throttles.push((key, throttle));
+        for (key, throttle) in self.message_rate_throttles(&path_config) {
+            if self
+                .check_throttle(throttle, &key, &key, &path_config)
+                .await?
+            {
+                return Ok(());
             }
+        }
 
-            // Check throttles from smallest to largest so that we avoid
-            // taking up a slot from a larger one only to hit a smaller
-            // one and not do anything useful with the larger one
-            throttles.sort_by_key(|(_, spec)| {
-                ((spec.limit as f64 / spec.period as f64) * 1_000_000.0) as u64
-            });
+        let msg = self.msg.as_ref().unwrap().clone();
 
-            for (key, throttle) in throttles {
-                if self
-                    .check_throttle(&throttle, key, key, &path_config)
-                    .await?
-                {
-                    return Ok(());
+        msg.load_meta_if_needed().await?;
+
+        let _shaping_leases = match traffic_shaping::check_message(
+            &self.site_name,
+            &msg,
+            path_config.client_timeouts.total_message_send_duration(),
+        )
+        .await?
+        {
+            ShapingResult::Proceed(leases) => leases,
+            ShapingResult::Delay(delay) => {
+                tracing::trace!(
+                    "{} traffic shaping override delays {} by {delay:?}",
+                    self.name,
+                    msg.id()
+                );
+                if let Some(msg) = self.msg.take() {
+                    let delay =
+                        chrono::Duration::from_std(delay).unwrap_or(kumo_chrono_helper::MINUTE);
+                    if let Err(err) = Self::requeue_message(msg, false, Some(delay)).await {
+                        tracing::error!("error requeuing message: {err:#}");
+                    }
                 }
+                return Ok(());
             }
-        }
+        };
 
-        let msg = self.msg.as_ref().unwrap().clone();
+        if !self.consume_warmup(&msg)? {
+            // It is now the responsibility of the task spawned
+            // by consume_warmup
+            self.msg.take();
+            return Ok(());
+        }
 
-        msg.load_meta_if_needed().await?;
         msg.load_data_if_needed().await?;
 
         let activity = match Activity::get_opt(format!(
@@ -1239,7 +1612,18 @@ impl Dispatcher {
 
         self.delivered_this_connection += 1;
 
+        // The message is no longer waiting in the ready queue
+        end_waiting(msg.id());
+        let mut span = StageSpan::start(msg.id(), "delivery");
+        if span.is_recording() {
+            span.set_attribute("ready_queue", self.name.clone());
+            span.set_attribute("egress_source", self.egress_source.name.clone());
+            span.set_attribute("site", self.site_name.clone());
+        }
+
+        let start = Instant::now();
         if let Err(err) = queue_dispatcher.deliver_message(msg.clone(), self).await {
+            span.set_error(&err);
             // Transient failure; continue with another host
             tracing::debug!(
                 "failed to send message id {:?} to {}: {err:#}",
@@ -1248,12 +1632,111 @@ impl Dispatcher {
             );
             return Err(err.into());
         }
+        self.metrics.delivery_latency.record(start.elapsed());
 
         drop(activity);
 
         Ok(())
     }
 
+    /// The number of messages that may be delivered over the current
+    /// connection before it is closed: the configured
+    /// max_deliveries_per_connection, reduced to the MAILMAX limit
+    /// advertised by the peer, or to a site-wide traffic shaping
+    /// override, if those are smaller.
+    pub fn max_deliveries_per_connection(&self) -> usize {
+        let configured = self.path_config.borrow().max_deliveries_per_connection;
+        [
+            self.peer_mail_max,
+            traffic_shaping::site_max_deliveries_per_connection(&self.site_name),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(configured, usize::min)
+    }
+
+    /// Records the outcome of a delivery attempt made by one of the
+    /// non-SMTP delivery protocols, where each message has a single
+    /// response. The disposition is logged, the delivery metrics are
+    /// updated and each message is then either requeued (for a
+    /// transient failure) or removed from the spool.
+    /// 2xx responses are deliveries, 4xx responses are transient
+    /// failures and anything else is a permanent failure.
+    /// Every message is dealt with, even if doing so fails for one
+    /// of the others; the errors are logged and the first is returned.
+    pub async fn record_delivery_responses(
+        &mut self,
+        dispositions: Vec<(Message, Response)>,
+        peer_address: &ResolvedAddress,
+        delivery_protocol: &str,
+    ) -> anyhow::Result<()> {
+        let mut first_error = None;
+        for (msg, response) in dispositions {
+            let id = *msg.id();
+            if let Err(err) = self
+                .record_delivery_response(msg, response, peer_address, delivery_protocol)
+                .await
+            {
+                tracing::error!(
+                    "error recording {delivery_protocol} disposition of {id} for {}: {err:#}",
+                    self.name
+                );
+                first_error.get_or_insert(err);
+            }
+        }
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    async fn record_delivery_response(
+        &mut self,
+        msg: Message,
+        response: Response,
+        peer_address: &ResolvedAddress,
+        delivery_protocol: &str,
+    ) -> anyhow::Result<()> {
+        let kind = match response.code {
+            200..=299 => RecordType::Delivery,
+            400..=499 => RecordType::TransientFailure,
+            _ => RecordType::Bounce,
+        };
+
+        log_disposition(LogDisposition {
+            kind,
+            msg: msg.clone(),
+            site: &self.name,
+            peer_address: Some(peer_address),
+            response,
+            egress_pool: Some(&self.egress_pool),
+            egress_source: Some(&self.egress_source.name),
+            relay_disposition: None,
+            delivery_protocol: Some(delivery_protocol),
+            tls_info: None,
+            source_address: None,
+            provider: self.path_config.borrow().provider_name.as_deref(),
+        })
+        .await;
+
+        match kind {
+            RecordType::Delivery => self.metrics.inc_delivered(),
+            RecordType::TransientFailure => self.metrics.inc_transfail(),
+            _ => self.metrics.inc_fail(),
+        }
+
+        if kind == RecordType::TransientFailure {
+            spawn_local(
+                "requeue message".to_string(),
+                Self::requeue_message(msg, true, None),
+            )?;
+        } else {
+            SpoolManager::remove_from_spool(*msg.id()).await?;
+        }
+
+        Ok(())
+    }
+
     #[instrument(skip(msg))]
     pub async fn requeue_message(
         msg: Message,
@@ -1538,12 +2021,11 @@ impl Dispatcher {
             return Ok(false);
         }
 
-        if self.delivered_this_connection >= self.path_config.borrow().max_deliveries_per_connection
-        {
+        if self.delivered_this_connection >= self.max_deliveries_per_connection() {
             tracing::trace!(
                 "Sent {} and limit is {}, close and make a new connection",
                 self.delivered_this_connection,
-                self.path_config.borrow().max_deliveries_per_connection,
+                self.max_deliveries_per_connection(),
             );
             let closed = queue_dispatcher.close_connection(self).await?;
             if closed {
@@ -1551,6 +2033,23 @@ impl Dispatcher {
             }
         }
 
+        if let (Some(established), Some(max_age)) = (
+            self.connection_established,
+            self.path_config.borrow().max_connection_age,
+        ) {
+            if established.elapsed() >= max_age {
+                tracing::trace!(
+                    "Connection age {:?} exceeds max_connection_age {max_age:?}, \
+                     close and make a new connection",
+                    established.elapsed(),
+                );
+                let closed = queue_dispatcher.close_connection(self).await?;
+                if closed {
+                    return Ok(false);
+                }
+            }
+        }
+
         if let Some(suspend) = self.get_suspension() {
             let duration = suspend.get_duration();
             tracing::trace!(
@@ -1562,6 +2061,39 @@ impl Dispatcher {
             return Ok(false);
         }
 
+        if crate::source_health::is_unhealthy(
+            &self.egress_source.name,
+            crate::source_health::destination(
+                &self.site_name,
+                self.path_config.borrow().provider_name.as_deref(),
+            ),
+        ) {
+            tracing::trace!(
+                "{}: egress source {} is unhealthy, rebalancing ready queue",
+                self.name,
+                self.egress_source.name
+            );
+            self.reinsert_ready_queue().await;
+            return Ok(false);
+        }
+
+        if crate::source_exclusion::excluded_remaining(
+            &self.egress_source.name,
+            &self.site_name,
+            self.path_config.borrow().provider_name.as_deref(),
+        )
+        .is_some()
+        {
+            tracing::trace!(
+                "{}: egress source {} is excluded from {}, rebalancing ready queue",
+                self.name,
+                self.egress_source.name,
+                self.site_name
+            );
+            self.reinsert_ready_queue().await;
+            return Ok(false);
+        }
+
         for lease in &self.leases {
             if lease
                 .extend(
@@ -1637,6 +2169,19 @@ impl Dispatcher {
             lease.release().await;
         }
     }
+
+    /// Extend our connection limit leases so that they remain valid for
+    /// duration, and hand them over to the caller, which is taking over
+    /// the connection. Returns None, keeping the leases, if they could
+    /// not be extended.
+    pub async fn transfer_leases(&mut self, duration: Duration) -> Option<Vec<LimitLease>> {
+        for lease in &self.leases {
+            if lease.extend(duration).await.is_err() {
+                return None;
+            }
+        }
+        Some(std::mem::take(&mut self.leases))
+    }
 }
 
 /// Use an exponential decay curve in the increasing form, asymptotic up to connection_limit,
@@ -1650,10 +2195,89 @@ pub fn ideal_connection_count(queue_size: usize, connection_limit: usize) -> usi
     goal.ceil().min(queue_size as f32) as usize
 }
 
+/// Computes the number of connections called for by strategy, given the
+/// number of ready messages, the connection limit and the average time
+/// taken to deliver a message, if known.
+pub fn strategy_connection_count(
+    strategy: &ConnectionStrategy,
+    queue_size: usize,
+    connection_limit: usize,
+    latency: Option<Duration>,
+) -> usize {
+    let n = match strategy {
+        ConnectionStrategy::Exponential => ideal_connection_count(queue_size, connection_limit),
+        ConnectionStrategy::Fixed => connection_limit,
+        ConnectionStrategy::Proportional {
+            messages_per_connection,
+            min_connections,
+            max_connections,
+        } => {
+            let per_connection = (*messages_per_connection).max(1);
+            let n = (queue_size + per_connection - 1) / per_connection;
+            n.max(*min_connections)
+                .min(max_connections.unwrap_or(connection_limit))
+        }
+        ConnectionStrategy::LatencyAware {
+            target_latency,
+            min_connections,
+        } => {
+            let n = ideal_connection_count(queue_size, connection_limit);
+            match latency {
+                Some(latency) if !latency.is_zero() => {
+                    let factor = target_latency.as_secs_f64() / latency.as_secs_f64();
+                    ((n as f64 * factor).ceil() as usize).max(*min_connections)
+                }
+                _ => n,
+            }
+        }
+    };
+    n.min(connection_limit).min(queue_size)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn connection_strategies() {
+        let proportional = ConnectionStrategy::Proportional {
+            messages_per_connection: 10,
+            min_connections: 2,
+            max_connections: Some(8),
+        };
+        let latency_aware = ConnectionStrategy::LatencyAware {
+            target_latency: Duration::from_millis(500),
+            min_connections: 1,
+        };
+        let count = |strategy: &ConnectionStrategy, queue_size, latency_ms: Option<u64>| {
+            strategy_connection_count(
+                strategy,
+                queue_size,
+                32,
+                latency_ms.map(Duration::from_millis),
+            )
+        };
+
+        assert_eq!(count(&ConnectionStrategy::Exponential, 64, None), 25);
+        assert_eq!(count(&ConnectionStrategy::Fixed, 0, None), 0);
+        assert_eq!(count(&ConnectionStrategy::Fixed, 5, None), 5);
+        assert_eq!(count(&ConnectionStrategy::Fixed, 64, None), 32);
+
+        assert_eq!(count(&proportional, 0, None), 0);
+        assert_eq!(count(&proportional, 5, None), 2);
+        assert_eq!(count(&proportional, 41, None), 5);
+        assert_eq!(count(&proportional, 1000, None), 8);
+
+        // Without any latency information it behaves like Exponential
+        assert_eq!(count(&latency_aware, 64, None), 25);
+        assert_eq!(count(&latency_aware, 64, Some(500)), 25);
+        // A slow destination gets fewer connections
+        assert_eq!(count(&latency_aware, 64, Some(5000)), 3);
+        // and a fast one gets more, up to the connection limit
+        assert_eq!(count(&latency_aware, 64, Some(250)), 32);
+        assert_eq!(count(&latency_aware, 20, Some(400)), 15);
+    }
+
     fn compute_targets_for_limit(max_connections: usize) -> Vec<(usize, usize)> {
         let sizes = [
             0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 20, 32, 64, 128, 256, 400, 512, 1024,
@@ -1726,4 +2350,56 @@ mod test {
             targets
         );
     }
+
+    #[test]
+    fn fifo_pops_by_priority() {
+        let metrics = DeliveryMetrics::new(
+            "fifo-test",
+            "smtp_client",
+            "pool",
+            "source",
+            &None,
+            "fifo-test",
+        );
+        let fifo = Fifo::new(4, metrics.ready_count.clone());
+        let msg = |priority| {
+            let msg = Message::new_dirty(
+                spool::SpoolId::new(),
+                message::EnvelopeAddress::parse("sender@example.com").unwrap(),
+                message::EnvelopeAddress::parse("recip@example.com").unwrap(),
+                serde_json::json!({}),
+                Arc::new(vec![].into_boxed_slice()),
+            )
+            .unwrap();
+            msg.set_priority(priority).unwrap();
+            msg
+        };
+
+        let normal = msg(0);
+        let low = msg(-1);
+        let high = msg(1);
+        let higher = msg(5);
+        for m in [&normal, &low, &high, &higher] {
+            fifo.push(m.clone()).unwrap();
+        }
+        assert_eq!(fifo.len(), 4);
+        assert!(fifo.push(msg(10)).is_err(), "prioritized count to capacity");
+        assert!(fifo.push(msg(0)).is_err(), "prioritized count to capacity");
+
+        assert_eq!(
+            fifo.drain(),
+            vec![higher.clone(), high.clone(), normal.clone(), low.clone()]
+        );
+        assert_eq!(fifo.len(), 0);
+
+        for m in [&low, &normal, &high, &higher] {
+            fifo.push(m.clone()).unwrap();
+        }
+        let mut popped = vec![];
+        while let Some(msg) = fifo.pop() {
+            popped.push(msg);
+        }
+        assert_eq!(popped, vec![higher, high, normal, low]);
+        assert_eq!(fifo.len(), 0);
+    }
 }
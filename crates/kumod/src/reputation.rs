@@ -0,0 +1,482 @@
+//! Tracks rolling bounce and complaint rates per sender domain and
+//! per tenant, based on the dispositions that pass through
+//! `log_disposition`, so that policy and operators can identify and
+//! react to senders whose reputation is deteriorating.
+use crate::smtp_server::RelayDisposition;
+use config::{from_lua_value, get_or_create_sub_module, load_config, CallbackSignature};
+use kumo_api_types::reputation::{ReputationV1Entry, ReputationV1Response};
+use kumo_log_types::rfc3464::ReportAction;
+use kumo_log_types::rfc5965::ARFReport;
+use kumo_log_types::RecordType;
+use message::Message;
+use mlua::{Lua, LuaSerdeExt, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The window is divided into this many buckets; older buckets
+/// are discarded as time passes.
+const NUM_BUCKETS: u64 = 60;
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+static CONFIG: Lazy<Mutex<ReputationConfig>> =
+    Lazy::new(|| Mutex::new(ReputationConfig::default()));
+static TRACKERS: Lazy<Mutex<HashMap<(Subject, String), Tracker>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// The bucket index as of the last time that TRACKERS was pruned
+static LAST_PRUNE: AtomicU64 = AtomicU64::new(0);
+
+pub static REPUTATION_THRESHOLD_SIG: Lazy<
+    CallbackSignature<(String, String, ReputationV1Entry), ()>,
+> = Lazy::new(|| CallbackSignature::new_with_multiple("reputation_threshold_exceeded"));
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReputationConfig {
+    /// The rates are computed over this rolling window
+    #[serde(default = "ReputationConfig::default_window", with = "duration_serde")]
+    pub window: Duration,
+
+    /// The thresholds are only considered once a sender domain or
+    /// tenant has at least this many delivered and bounced messages
+    /// within the window
+    #[serde(default = "ReputationConfig::default_min_volume")]
+    pub min_volume: usize,
+
+    #[serde(default)]
+    pub bounce_rate_threshold: Option<f64>,
+
+    #[serde(default)]
+    pub complaint_rate_threshold: Option<f64>,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            window: Self::default_window(),
+            min_volume: Self::default_min_volume(),
+            bounce_rate_threshold: None,
+            complaint_rate_threshold: None,
+        }
+    }
+}
+
+impl ReputationConfig {
+    fn default_window() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn default_min_volume() -> usize {
+        100
+    }
+
+    fn bucket_index(&self) -> u64 {
+        let bucket_secs = (self.window.as_secs() / NUM_BUCKETS).max(1);
+        START.elapsed().as_secs() / bucket_secs
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Subject {
+    SenderDomain,
+    Tenant,
+}
+
+impl Subject {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::SenderDomain => "sender_domain",
+            Self::Tenant => "tenant",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Outcome {
+    Delivered,
+    Bounced,
+    Complaint,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+struct Counts {
+    delivered: usize,
+    bounced: usize,
+    complaints: usize,
+}
+
+impl Counts {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Delivered => self.delivered += 1,
+            Outcome::Bounced => self.bounced += 1,
+            Outcome::Complaint => self.complaints += 1,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+struct Tracker {
+    buckets: VecDeque<(u64, Counts)>,
+    /// Whether the thresholds were exceeded as of the last
+    /// check, so that we only notify on the transition
+    exceeded: bool,
+}
+
+impl Tracker {
+    fn trim(&mut self, index: u64) {
+        while let Some((bucket, _)) = self.buckets.front() {
+            if bucket + NUM_BUCKETS > index {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    fn record(&mut self, index: u64, outcome: Outcome) {
+        self.trim(index);
+        match self.buckets.back_mut() {
+            Some((bucket, counts)) if *bucket == index => counts.add(outcome),
+            _ => {
+                let mut counts = Counts::default();
+                counts.add(outcome);
+                self.buckets.push_back((index, counts));
+            }
+        }
+    }
+
+    fn totals(&self) -> Counts {
+        let mut totals = Counts::default();
+        for (_, counts) in &self.buckets {
+            totals.delivered += counts.delivered;
+            totals.bounced += counts.bounced;
+            totals.complaints += counts.complaints;
+        }
+        totals
+    }
+
+    fn entry(&self, name: &str) -> ReputationV1Entry {
+        let totals = self.totals();
+        let volume = totals.delivered + totals.bounced;
+        let rate = |n: usize, d: usize| if d == 0 { 0. } else { n as f64 / d as f64 };
+        ReputationV1Entry {
+            name: name.to_string(),
+            delivered: totals.delivered,
+            bounced: totals.bounced,
+            complaints: totals.complaints,
+            bounce_rate: rate(totals.bounced, volume),
+            complaint_rate: rate(totals.complaints, totals.delivered),
+        }
+    }
+
+    /// Re-evaluate the thresholds, returning true if they have
+    /// just been exceeded
+    fn check_thresholds(&mut self, entry: &ReputationV1Entry, config: &ReputationConfig) -> bool {
+        let exceeded = entry.delivered + entry.bounced >= config.min_volume
+            && (config
+                .bounce_rate_threshold
+                .map(|t| entry.bounce_rate > t)
+                .unwrap_or(false)
+                || config
+                    .complaint_rate_threshold
+                    .map(|t| entry.complaint_rate > t)
+                    .unwrap_or(false));
+        let triggered = exceeded && !self.exceeded;
+        self.exceeded = exceeded;
+        triggered
+    }
+}
+
+/// Extract the domain from an address such as `<user@example.com>`
+fn domain_of(address: &str) -> Option<&str> {
+    let (_, domain) = address
+        .trim()
+        .trim_matches(&['<', '>'][..])
+        .rsplit_once('@')?;
+    if domain.is_empty() {
+        None
+    } else {
+        Some(domain)
+    }
+}
+
+/// Update the statistics for the disposition described by the parameters.
+/// This is called from `log_disposition`.
+pub async fn record_disposition(
+    kind: RecordType,
+    msg: &Message,
+    relay_disposition: Option<RelayDisposition>,
+    feedback_report: Option<&ARFReport>,
+) {
+    let mut updates = vec![];
+
+    match kind {
+        RecordType::Delivery | RecordType::Bounce | RecordType::Expiration => {
+            let outcome = if kind == RecordType::Delivery {
+                Outcome::Delivered
+            } else {
+                Outcome::Bounced
+            };
+            if let Ok(sender) = msg.sender() {
+                if !sender.domain().is_empty() {
+                    updates.push((Subject::SenderDomain, sender.domain().to_string(), outcome));
+                }
+            }
+            if let Ok(Some(tenant)) = msg.get_meta_string("tenant") {
+                updates.push((Subject::Tenant, tenant, outcome));
+            }
+        }
+        RecordType::Feedback => {
+            if let Some(domain) = feedback_report
+                .and_then(|report| report.original_mail_from.as_deref())
+                .and_then(domain_of)
+            {
+                updates.push((
+                    Subject::SenderDomain,
+                    domain.to_string(),
+                    Outcome::Complaint,
+                ));
+            }
+        }
+        RecordType::Reception => {
+            if let Some(RelayDisposition { log_oob: true, .. }) = relay_disposition {
+                // An incoming bounce report is addressed to the
+                // envelope sender of the original message
+                if let (Ok(Some(report)), Ok(recipient)) = (msg.parse_rfc3464(), msg.recipient()) {
+                    for recip in &report.per_recipient {
+                        if recip.action == ReportAction::Failed {
+                            updates.push((
+                                Subject::SenderDomain,
+                                recipient.domain().to_string(),
+                                Outcome::Bounced,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+
+    if updates.is_empty() {
+        return;
+    }
+
+    let config = CONFIG.lock().clone();
+    let index = config.bucket_index();
+    let mut triggered = vec![];
+    {
+        let mut trackers = TRACKERS.lock();
+        // Discard the state for anything that has no activity within
+        // the window, at most once per bucket, so that the map doesn't
+        // grow without bound
+        if LAST_PRUNE.swap(index, Ordering::Relaxed) != index {
+            prune(&mut trackers, index);
+        }
+        for (subject, name, outcome) in updates {
+            let tracker = trackers.entry((subject, name.clone())).or_default();
+            tracker.record(index, outcome);
+            let entry = tracker.entry(&name);
+            if tracker.check_thresholds(&entry, &config) {
+                triggered.push((subject, entry));
+            }
+        }
+    }
+
+    for (subject, entry) in triggered {
+        tracing::warn!(
+            "reputation threshold exceeded for {} {}: {entry:?}",
+            subject.as_str(),
+            entry.name
+        );
+        match load_config().await {
+            Ok(mut lua_config) => {
+                if let Err(err) = lua_config
+                    .async_call_callback(
+                        &REPUTATION_THRESHOLD_SIG,
+                        (subject.as_str().to_string(), entry.name.clone(), entry),
+                    )
+                    .await
+                {
+                    tracing::error!("error while calling reputation_threshold_exceeded: {err:#}");
+                }
+            }
+            Err(err) => {
+                tracing::error!(
+                    "failed to load lua config while attempting to \
+                     call reputation_threshold_exceeded: {err:#}"
+                );
+            }
+        }
+    }
+}
+
+fn lookup(subject: Subject, name: &str) -> Option<ReputationV1Entry> {
+    let index = CONFIG.lock().bucket_index();
+    let mut trackers = TRACKERS.lock();
+    let tracker = trackers.get_mut(&(subject, name.to_string()))?;
+    tracker.trim(index);
+    Some(tracker.entry(name))
+}
+
+/// Discards the state for anything that has no activity within the window
+fn prune(trackers: &mut HashMap<(Subject, String), Tracker>, index: u64) {
+    trackers.retain(|_, tracker| {
+        tracker.trim(index);
+        !tracker.buckets.is_empty()
+    });
+}
+
+/// Returns the statistics for everything that has activity within the
+/// current window, discarding the state for anything that has none
+pub fn snapshot() -> ReputationV1Response {
+    let index = CONFIG.lock().bucket_index();
+    let mut trackers = TRACKERS.lock();
+    prune(&mut trackers, index);
+
+    let mut sender_domains = vec![];
+    let mut tenants = vec![];
+    for ((subject, name), tracker) in trackers.iter() {
+        let entry = tracker.entry(name);
+        match subject {
+            Subject::SenderDomain => sender_domains.push(entry),
+            Subject::Tenant => tenants.push(entry),
+        }
+    }
+    sender_domains.sort_by(|a, b| a.name.cmp(&b.name));
+    tenants.sort_by(|a, b| a.name.cmp(&b.name));
+
+    ReputationV1Response {
+        sender_domains,
+        tenants,
+    }
+}
+
+fn configure(config: ReputationConfig) {
+    let mut current = CONFIG.lock();
+    if current.window != config.window {
+        // The bucket indices are not comparable across
+        // different window sizes, so start over
+        TRACKERS.lock().clear();
+    }
+    *current = config;
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    REPUTATION_THRESHOLD_SIG.register();
+
+    let module = get_or_create_sub_module(lua, "reputation")?;
+
+    module.set(
+        "configure",
+        lua.create_function(|lua, params: Value| {
+            let config: ReputationConfig = from_lua_value(lua, params)?;
+            configure(config);
+            Ok(())
+        })?,
+    )?;
+
+    module.set(
+        "get_sender_domain",
+        lua.create_function(|_, domain: String| Ok(lookup(Subject::SenderDomain, &domain)))?,
+    )?;
+
+    module.set(
+        "get_tenant",
+        lua.create_function(|_, tenant: String| Ok(lookup(Subject::Tenant, &tenant)))?,
+    )?;
+
+    module.set(
+        "list",
+        lua.create_function(|lua, ()| {
+            let snapshot = snapshot();
+            lua.to_value(&snapshot)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rates_and_window() {
+        let mut tracker = Tracker::default();
+        for _ in 0..8 {
+            tracker.record(0, Outcome::Delivered);
+        }
+        tracker.record(0, Outcome::Bounced);
+        tracker.record(1, Outcome::Bounced);
+        tracker.record(1, Outcome::Complaint);
+
+        let entry = tracker.entry("example.com");
+        assert_eq!(entry.delivered, 8);
+        assert_eq!(entry.bounced, 2);
+        assert_eq!(entry.complaints, 1);
+        assert_eq!(entry.bounce_rate, 0.2);
+        assert_eq!(entry.complaint_rate, 0.125);
+
+        // Bucket 0 falls out of the window
+        tracker.trim(NUM_BUCKETS);
+        let entry = tracker.entry("example.com");
+        assert_eq!(entry.delivered, 0);
+        assert_eq!(entry.bounced, 1);
+        assert_eq!(entry.bounce_rate, 1.0);
+        assert_eq!(entry.complaint_rate, 0.);
+    }
+
+    #[test]
+    fn thresholds() {
+        let config = ReputationConfig {
+            min_volume: 10,
+            bounce_rate_threshold: Some(0.1),
+            ..ReputationConfig::default()
+        };
+        let mut tracker = Tracker::default();
+        for _ in 0..8 {
+            tracker.record(0, Outcome::Bounced);
+        }
+        // Not enough volume yet
+        let entry = tracker.entry("t");
+        assert!(!tracker.check_thresholds(&entry, &config));
+
+        tracker.record(0, Outcome::Bounced);
+        tracker.record(0, Outcome::Delivered);
+        let entry = tracker.entry("t");
+        assert!(tracker.check_thresholds(&entry, &config));
+        // Only triggers on the transition
+        assert!(!tracker.check_thresholds(&entry, &config));
+    }
+
+    #[test]
+    fn prune_idle() {
+        let mut trackers = HashMap::new();
+        let mut active = Tracker::default();
+        active.record(NUM_BUCKETS, Outcome::Delivered);
+        let mut idle = Tracker::default();
+        idle.record(0, Outcome::Delivered);
+        trackers.insert((Subject::Tenant, "active".to_string()), active);
+        trackers.insert((Subject::Tenant, "idle".to_string()), idle);
+
+        prune(&mut trackers, NUM_BUCKETS);
+        assert_eq!(
+            trackers
+                .keys()
+                .map(|(_, name)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["active"]
+        );
+    }
+
+    #[test]
+    fn address_domain() {
+        assert_eq!(domain_of("<user@example.com>"), Some("example.com"));
+        assert_eq!(domain_of("user@example.com"), Some("example.com"));
+        assert_eq!(domain_of("<>"), None);
+    }
+}
@@ -0,0 +1,313 @@
+//! Provides `kumo.rspamd`, which submits messages to an
+//! [rspamd](https://rspamd.com) instance for scanning using its
+//! HTTP protocol, and returns the verdict to lua so that policy
+//! can decide whether to reject, tag or quarantine the message.
+//!
+//! The message is sent to rspamd directly from the data that is
+//! already held by the message, rather than being copied into lua
+//! or into a separate request buffer first, and requests to the same
+//! rspamd instance share a pool of connections.
+use bytes::Bytes;
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use message::Message;
+use mlua::{Lua, LuaSerdeExt, UserData, UserDataMethods, Value};
+use once_cell::sync::Lazy;
+use reqwest::{Body, Client};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+static CLIENTS: Lazy<Mutex<HashMap<RspamdParams, Client>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct RspamdParams {
+    /// eg: `http://127.0.0.1:11333`
+    #[serde(default = "RspamdParams::default_base_url")]
+    pub base_url: String,
+    /// Sent as the `Password` header, which is required if
+    /// the requests are made via the controller worker
+    #[serde(default)]
+    pub password: Option<String>,
+    /// How long to wait for the scan to complete
+    #[serde(default = "RspamdParams::default_timeout", with = "duration_serde")]
+    pub timeout: Duration,
+    /// The maximum number of idle connections to retain
+    #[serde(default)]
+    pub pool_size: Option<usize>,
+    /// Messages larger than this are not scanned, and produce
+    /// a result with `is_skipped` set
+    #[serde(default)]
+    pub max_message_size: Option<usize>,
+}
+
+impl RspamdParams {
+    fn default_base_url() -> String {
+        "http://127.0.0.1:11333".to_string()
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn get_client(&self) -> anyhow::Result<Client> {
+        let mut clients = CLIENTS.lock().unwrap();
+        if let Some(client) = clients.get(self) {
+            return Ok(client.clone());
+        }
+
+        let mut builder = Client::builder().timeout(self.timeout);
+        if let Some(size) = self.pool_size {
+            builder = builder.pool_max_idle_per_host(size);
+        }
+        let client = builder.build()?;
+
+        clients.insert(self.clone(), client.clone());
+        Ok(client)
+    }
+
+    pub fn open(&self) -> anyhow::Result<RspamdClient> {
+        self.get_client()?;
+        Ok(RspamdClient(Arc::new(self.clone())))
+    }
+}
+
+/// Information about the message that is passed to rspamd
+/// as request headers. Fields that are not specified are
+/// derived from the message and its metadata, if possible.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ScanOptions {
+    #[serde(default)]
+    ip: Option<String>,
+    #[serde(default)]
+    helo: Option<String>,
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    settings_id: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct RspamdSymbol {
+    #[serde(default)]
+    pub score: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+/// The parts of the rspamd response that are returned to lua
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct RspamdResult {
+    #[serde(default)]
+    pub is_skipped: bool,
+    #[serde(default)]
+    pub score: f64,
+    #[serde(default)]
+    pub required_score: f64,
+    /// One of `no action`, `greylist`, `add header`,
+    /// `rewrite subject`, `soft reject` or `reject`
+    #[serde(default = "RspamdResult::default_action")]
+    pub action: String,
+    #[serde(default)]
+    pub symbols: HashMap<String, RspamdSymbol>,
+    /// The replacement subject, if action is `rewrite subject`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// Headers that rspamd would like to add or remove
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub milter: Option<serde_json::Value>,
+}
+
+impl RspamdResult {
+    fn default_action() -> String {
+        "no action".to_string()
+    }
+
+    fn skipped() -> Self {
+        Self {
+            is_skipped: true,
+            action: Self::default_action(),
+            ..Self::default()
+        }
+    }
+}
+
+/// Allows the message data to back a `Bytes` without copying it
+struct SharedData(Arc<Box<[u8]>>);
+
+impl AsRef<[u8]> for SharedData {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// The request body, which refers to the data held by the message
+fn message_body(data: Arc<Box<[u8]>>) -> Bytes {
+    Bytes::from_owner(SharedData(data))
+}
+
+/// The address of the client, without the port
+fn peer_ip(received_from: &str) -> Option<String> {
+    received_from
+        .parse::<SocketAddr>()
+        .ok()
+        .map(|addr| addr.ip().to_string())
+}
+
+#[derive(Clone)]
+pub struct RspamdClient(Arc<RspamdParams>);
+
+impl RspamdClient {
+    async fn scan(&self, msg: &Message, options: ScanOptions) -> anyhow::Result<RspamdResult> {
+        msg.load_meta_if_needed().await?;
+        msg.load_data_if_needed().await?;
+        let data = msg.get_data();
+        if let Some(limit) = self.0.max_message_size {
+            if data.len() > limit {
+                return Ok(RspamdResult::skipped());
+            }
+        }
+
+        let client = self.0.get_client()?;
+        let url = format!("{}/checkv2", self.0.base_url.trim_end_matches('/'));
+        let mut request = client
+            .post(url)
+            .header("Content-Length", data.len())
+            .header("Queue-Id", msg.id().to_string())
+            .header("From", msg.sender()?.to_string())
+            .header("Rcpt", msg.recipient()?.to_string());
+
+        let ip = match options.ip {
+            Some(ip) => Some(ip),
+            None => msg
+                .get_meta_string("received_from")?
+                .and_then(|from| peer_ip(&from)),
+        };
+        let helo = match options.helo {
+            Some(helo) => Some(helo),
+            None => msg.get_meta_string("ehlo_domain")?,
+        };
+        let user = match options.user {
+            Some(user) => Some(user),
+            None => msg.get_meta_string("authn_id")?,
+        };
+        for (name, value) in [
+            ("IP", ip),
+            ("Helo", helo),
+            ("Hostname", options.hostname),
+            ("User", user),
+            ("Settings-ID", options.settings_id),
+            ("Password", self.0.password.clone()),
+        ] {
+            if let Some(value) = value {
+                request = request.header(name, value);
+            }
+        }
+
+        let response = request.body(Body::from(message_body(data))).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("rspamd responded with {status}: {}", body.trim());
+        }
+        let body = response.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+impl UserData for RspamdClient {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method(
+            "scan",
+            |lua, this, (msg, options): (Message, Option<Value>)| async move {
+                let options = match options {
+                    Some(options) => from_lua_value(lua, options)?,
+                    None => ScanOptions::default(),
+                };
+                let result = this.scan(&msg, options).await.map_err(any_err)?;
+                lua.to_value_with(&result, config::serialize_options())
+            },
+        );
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let rspamd_mod = get_or_create_sub_module(lua, "rspamd")?;
+
+    rspamd_mod.set(
+        "open",
+        lua.create_function(move |lua, params: Value| {
+            let params: RspamdParams = from_lua_value(lua, params)?;
+            params.open().map_err(any_err)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_result() {
+        let result: RspamdResult = serde_json::from_str(
+            r#"{
+                "is_skipped": false,
+                "score": 6.5,
+                "required_score": 15.0,
+                "action": "add header",
+                "symbols": {
+                    "R_SPF_FAIL": {
+                        "name": "R_SPF_FAIL",
+                        "score": 1.0,
+                        "metric_score": 1.0,
+                        "description": "SPF verification failed",
+                        "options": ["-all"]
+                    },
+                    "MIME_GOOD": {"name": "MIME_GOOD", "score": -0.1}
+                },
+                "milter": {"add_headers": {"X-Spam": "Yes"}},
+                "message-id": "<id@example.com>",
+                "time_real": 0.1
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(result.action, "add header");
+        assert_eq!(result.score, 6.5);
+        assert_eq!(
+            result.symbols["R_SPF_FAIL"],
+            RspamdSymbol {
+                score: 1.0,
+                description: Some("SPF verification failed".to_string()),
+                options: vec!["-all".to_string()],
+            }
+        );
+        assert_eq!(result.symbols["MIME_GOOD"].score, -0.1);
+        assert!(result.milter.is_some());
+    }
+
+    #[test]
+    fn body_shares_message_data() {
+        let data: Arc<Box<[u8]>> = Arc::new(b"Subject: hello\r\n\r\nhi\r\n".to_vec().into());
+        let body = message_body(data.clone());
+        assert_eq!(&body[..], &data[..]);
+        assert_eq!(body.as_ptr(), data.as_ptr());
+    }
+
+    #[test]
+    fn received_from_ip() {
+        assert_eq!(peer_ip("10.0.0.1:25").as_deref(), Some("10.0.0.1"));
+        assert_eq!(peer_ip("[::1]:2025").as_deref(), Some("::1"));
+        assert_eq!(peer_ip("garbage"), None);
+    }
+}
@@ -0,0 +1,293 @@
+//! Periodically injects messages to seed lists, so that inbox placement
+//! monitoring services can observe how the normal traffic of a tenant
+//! is treated by the various mailbox providers.
+//!
+//! Seed messages pass through the normal queueing and delivery pipeline,
+//! but are tagged with the `seed` meta value so that they are excluded
+//! from accounting and, depending on the logger configuration, from logs.
+use crate::logging::disposition::{log_disposition, LogDisposition, RecordType};
+use crate::message_tracing::save_to_spool;
+use crate::queue::QueueManager;
+use anyhow::Context;
+use chrono::Utc;
+use config::{any_err, from_lua_value, get_or_create_sub_module, load_config, CallbackSignature};
+use kumo_server_lifecycle::ShutdownSubcription;
+use kumo_server_runtime::spawn;
+use message::{EnvelopeAddress, Message};
+use minijinja::Environment;
+use mlua::{Lua, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use rfc5321::Response;
+use serde::Deserialize;
+use spool::SpoolId;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+static SEED_LISTS: Lazy<Mutex<HashMap<String, JoinHandle<()>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub static SEED_MESSAGE_GENERATED_SIG: Lazy<CallbackSignature<Message, ()>> =
+    Lazy::new(|| CallbackSignature::new("seed_message_generated"));
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SeedListParams {
+    /// The unique name of this seed list
+    pub name: String,
+
+    /// The envelope sender for the seed messages
+    pub sender: String,
+
+    /// The seed addresses to which a message is sent
+    /// each time the interval elapses
+    pub recipients: Vec<String>,
+
+    /// The message content, as a template
+    pub content: String,
+
+    /// How often to send to the seed list
+    #[serde(with = "duration_serde")]
+    pub interval: Duration,
+
+    #[serde(default)]
+    pub tenant: Option<String>,
+
+    #[serde(default)]
+    pub campaign: Option<String>,
+
+    /// The provider that this seed list is intended to monitor
+    #[serde(default)]
+    pub provider: Option<String>,
+}
+
+/// Returns true if msg was injected from a seed list
+pub fn is_seed(msg: &Message) -> bool {
+    matches!(msg.get_meta_string("seed"), Ok(Some(_)))
+}
+
+struct SeedList {
+    params: SeedListParams,
+    sender: EnvelopeAddress,
+    recipients: Vec<EnvelopeAddress>,
+    env: Environment<'static>,
+}
+
+impl SeedList {
+    fn new(params: SeedListParams) -> anyhow::Result<Self> {
+        let sender = EnvelopeAddress::parse(&params.sender)
+            .with_context(|| format!("seed list {} sender {}", params.name, params.sender))?;
+        let recipients = params
+            .recipients
+            .iter()
+            .map(|recip| {
+                EnvelopeAddress::parse(recip)
+                    .with_context(|| format!("seed list {} recipient {recip}", params.name))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut env = mod_template::new_environment();
+        env.add_template_owned("content", params.content.clone())
+            .with_context(|| format!("compiling content template for seed list {}", params.name))?;
+
+        Ok(Self {
+            params,
+            sender,
+            recipients,
+            env,
+        })
+    }
+
+    fn build_message(&self, recipient: &EnvelopeAddress) -> anyhow::Result<Message> {
+        let id = SpoolId::new();
+        let content = self
+            .env
+            .get_template("content")?
+            .render(minijinja::context! {
+                name => self.params.name,
+                id => id.to_string(),
+                recipient => recipient.to_string(),
+                date => Utc::now().to_rfc2822(),
+            })?;
+        let normalized = mailparsing::normalize_crlf(content.as_bytes());
+
+        let message = Message::new_dirty(
+            id,
+            self.sender.clone(),
+            recipient.clone(),
+            serde_json::json!({}),
+            Arc::new(normalized.into_boxed_slice()),
+        )?;
+
+        message.set_meta("reception_protocol", "Seed")?;
+        message.set_meta("seed", self.params.name.clone())?;
+        if let Some(tenant) = &self.params.tenant {
+            message.set_meta("tenant", tenant.clone())?;
+        }
+        if let Some(campaign) = &self.params.campaign {
+            message.set_meta("campaign", campaign.clone())?;
+        }
+        if let Some(provider) = &self.params.provider {
+            message.set_meta("seed_provider", provider.clone())?;
+        }
+        message.set_content_meta(true)?;
+
+        Ok(message)
+    }
+
+    async fn inject(&self, recipient: &EnvelopeAddress) -> anyhow::Result<()> {
+        let message = self.build_message(recipient)?;
+
+        let mut config = load_config().await?;
+        config
+            .async_call_callback(&SEED_MESSAGE_GENERATED_SIG, message.clone())
+            .await?;
+
+        let queue_name = message.get_queue_name()?;
+        if queue_name == "null" {
+            return Ok(());
+        }
+
+        if !QueueManager::is_deferred_spool(&queue_name).await? {
+            save_to_spool(&message).await?;
+        }
+        log_disposition(LogDisposition {
+            kind: RecordType::Reception,
+            msg: message.clone(),
+            site: "",
+            peer_address: None,
+            response: Response {
+                code: 250,
+                enhanced_code: None,
+                command: None,
+                content: "".to_string(),
+            },
+            egress_source: None,
+            egress_pool: None,
+            relay_disposition: None,
+            delivery_protocol: None,
+            tls_info: None,
+            source_address: None,
+            provider: None,
+        })
+        .await;
+        QueueManager::insert(&queue_name, message).await?;
+        Ok(())
+    }
+
+    async fn run(self) {
+        let mut shutdown = ShutdownSubcription::get();
+        loop {
+            tokio::select! {
+                _ = shutdown.shutting_down() => break,
+                _ = tokio::time::sleep(self.params.interval) => {}
+            };
+
+            for recipient in &self.recipients {
+                if let Err(err) = self.inject(recipient).await {
+                    tracing::error!(
+                        "seed list {}: failed to inject message to {}: {err:#}",
+                        self.params.name,
+                        recipient.to_string()
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn define(params: SeedListParams) -> anyhow::Result<()> {
+    let list = SeedList::new(params)?;
+    if config::is_validating() {
+        return Ok(());
+    }
+
+    let name = list.params.name.clone();
+    let handle = spawn(format!("seed list {name}"), list.run())?;
+    if let Some(prior) = SEED_LISTS.lock().insert(name, handle) {
+        prior.abort();
+    }
+    Ok(())
+}
+
+fn remove(name: &str) -> bool {
+    match SEED_LISTS.lock().remove(name) {
+        Some(handle) => {
+            handle.abort();
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    SEED_MESSAGE_GENERATED_SIG.register();
+
+    let module = get_or_create_sub_module(lua, "seeds")?;
+
+    module.set(
+        "define",
+        lua.create_function(|lua, params: Value| {
+            let params: SeedListParams = from_lua_value(lua, params)?;
+            define(params).map_err(any_err)
+        })?,
+    )?;
+
+    module.set(
+        "remove",
+        lua.create_function(|_, name: String| Ok(remove(&name)))?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params() -> SeedListParams {
+        SeedListParams {
+            name: "inbox-monitor".to_string(),
+            sender: "seeds@example.com".to_string(),
+            recipients: vec!["seed@example.net".to_string()],
+            content: "Subject: seed {{ id }}\r\nTo: {{ recipient }}\r\n\r\nhello\r\n".to_string(),
+            interval: Duration::from_secs(3600),
+            tenant: Some("mytenant".to_string()),
+            campaign: None,
+            provider: Some("example".to_string()),
+        }
+    }
+
+    #[test]
+    fn build_message() {
+        let list = SeedList::new(params()).unwrap();
+        let msg = list.build_message(&list.recipients[0]).unwrap();
+        assert!(is_seed(&msg));
+        assert_eq!(
+            msg.get_meta_string("tenant").unwrap().as_deref(),
+            Some("mytenant")
+        );
+        assert_eq!(
+            msg.get_meta_string("seed_provider").unwrap().as_deref(),
+            Some("example")
+        );
+        assert_eq!(
+            msg.get_first_named_header_value("To").unwrap().as_deref(),
+            Some("seed@example.net")
+        );
+        let subject = msg
+            .get_first_named_header_value("Subject")
+            .unwrap()
+            .unwrap();
+        assert_eq!(subject, format!("seed {}", msg.id()));
+    }
+
+    #[test]
+    fn invalid_recipient() {
+        let mut params = params();
+        params.recipients.push("not an address".to_string());
+        assert!(SeedList::new(params).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! A pool of idle SMTP client connections.
+//!
+//! When `connection_pool_idle_timeout` is set for an egress path,
+//! an SmtpDispatcher that has run out of work parks its connection
+//! here rather than closing it.  A subsequent dispatcher for the
+//! same ready queue (which corresponds to a site and egress source
+//! pair) can then pick it up and avoid the cost of establishing a
+//! new connection and TLS session.
+//!
+//! A pooled connection keeps the connection limit leases of the
+//! dispatcher that established it, so that idle connections count
+//! against the connection limits of the path.  The leases are
+//! extended while the connection is idle, so that they remain valid
+//! for as long as the connection is in the pool.  When the ready
+//! queue maintainer wants to start a new dispatcher, it hands it a
+//! pooled connection along with its leases in preference to
+//! acquiring new leases, so that pooled connections continue to be
+//! reused when the connection limits are fully subscribed.
+use crate::delivery_metrics::MetricsWrappedConnection;
+use crate::ready_queue::READYQ_RUNTIME;
+use kumo_log_types::{MaybeProxiedSourceAddress, ResolvedAddress};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::IntGauge;
+use rfc5321::{Command, SmtpClient, TlsInformation};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use throttle::limit::LimitLease;
+
+static POOL: Lazy<Mutex<HashMap<String, Vec<PooledConnection>>>> = Lazy::new(Default::default);
+
+static POOLED_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    prometheus::register_int_gauge!(
+        "smtp_client_pooled_connections",
+        "number of idle smtp client connections held in the connection pool"
+    )
+    .unwrap()
+});
+
+#[derive(Debug)]
+pub struct PooledConnection {
+    pub client: MetricsWrappedConnection<SmtpClient>,
+    pub address: ResolvedAddress,
+    pub source_address: Option<MaybeProxiedSourceAddress>,
+    pub tls_info: Option<TlsInformation>,
+    /// When the connection was originally established
+    pub established: Instant,
+    /// How many messages have been delivered over this connection
+    pub deliveries: usize,
+    /// The connection limit leases held on behalf of this connection.
+    /// These are shared with the task that extends them while the
+    /// connection is idle in the pool.
+    leases: Arc<tokio::sync::Mutex<Vec<LimitLease>>>,
+    idle_since: Instant,
+}
+
+impl PooledConnection {
+    pub fn new(
+        client: MetricsWrappedConnection<SmtpClient>,
+        address: ResolvedAddress,
+        source_address: Option<MaybeProxiedSourceAddress>,
+        tls_info: Option<TlsInformation>,
+        established: Instant,
+        deliveries: usize,
+        leases: Vec<LimitLease>,
+    ) -> Self {
+        Self {
+            client,
+            address,
+            source_address,
+            tls_info,
+            established,
+            deliveries,
+            leases: Arc::new(tokio::sync::Mutex::new(leases)),
+            idle_since: Instant::now(),
+        }
+    }
+
+    /// Take over the connection limit leases held by this connection
+    pub async fn take_leases(&mut self) -> Vec<LimitLease> {
+        std::mem::take(&mut *self.leases.lock().await)
+    }
+
+    pub async fn release_leases(&mut self) {
+        for mut lease in self.take_leases().await {
+            lease.release().await;
+        }
+    }
+
+    fn is_expired(&self, now: Instant, max_idle: Duration, max_age: Option<Duration>) -> bool {
+        if now.duration_since(self.idle_since) >= max_idle {
+            return true;
+        }
+        match max_age {
+            Some(max_age) => now.duration_since(self.established) >= max_age,
+            None => false,
+        }
+    }
+
+    fn close(self) {
+        READYQ_RUNTIME
+            .spawn_non_blocking("close pooled smtp connection".to_string(), move || {
+                let mut conn = self;
+                Ok(async move {
+                    conn.client.send_command(&Command::Quit).await.ok();
+                    conn.release_leases().await;
+                })
+            })
+            .ok();
+    }
+}
+
+/// Place a connection into the pool for the ready queue `name`.
+/// If the pool already holds `max_pooled` connections for that
+/// ready queue, the connection is closed instead.
+pub fn check_in(
+    name: &str,
+    conn: PooledConnection,
+    max_idle: Duration,
+    max_age: Option<Duration>,
+    max_pooled: usize,
+) {
+    let leases = Arc::downgrade(&conn.leases);
+    let rejected = {
+        let mut pool = POOL.lock();
+        let entries = pool.entry(name.to_string()).or_default();
+        if entries.len() >= max_pooled {
+            Some(conn)
+        } else {
+            entries.push(conn);
+            POOLED_CONNECTIONS.inc();
+            None
+        }
+    };
+
+    if let Some(conn) = rejected {
+        conn.close();
+        return;
+    }
+
+    // Schedule a reap for when this connection would idle out
+    let name = name.to_string();
+    READYQ_RUNTIME
+        .spawn_non_blocking("reap pooled smtp connections".to_string(), move || {
+            Ok(async move {
+                tokio::time::sleep(max_idle).await;
+                reap(&name, max_idle, max_age);
+            })
+        })
+        .ok();
+
+    // The leases were extended by max_idle when the connection was
+    // checked in.  Keep them at least half that far ahead of expiring
+    // until the connection leaves the pool, either by being checked
+    // out, at which point its leases are taken, or by being closed.
+    READYQ_RUNTIME
+        .spawn_non_blocking(
+            "extend pooled smtp connection leases".to_string(),
+            move || {
+                Ok(async move {
+                    loop {
+                        tokio::time::sleep(max_idle / 2).await;
+                        let Some(shared) = leases.upgrade() else {
+                            return;
+                        };
+                        let held = shared.lock().await;
+                        if held.is_empty() {
+                            return;
+                        }
+                        for lease in held.iter() {
+                            if let Err(err) = lease.extend(max_idle).await {
+                                tracing::error!(
+                                    "failed to extend pooled connection lease: {err:#}"
+                                );
+                            }
+                        }
+                    }
+                })
+            },
+        )
+        .ok();
+}
+
+/// Take a connection for the ready queue `name` out of the pool,
+/// if one is available that is within the idle and age limits.
+/// The most recently used connection is preferred, as it is the
+/// least likely to have been timed out by the peer.
+pub fn check_out(
+    name: &str,
+    max_idle: Duration,
+    max_age: Option<Duration>,
+) -> Option<PooledConnection> {
+    reap(name, max_idle, max_age);
+
+    let mut pool = POOL.lock();
+    let entries = pool.get_mut(name)?;
+    let conn = entries.pop();
+    if entries.is_empty() {
+        pool.remove(name);
+    }
+    if conn.is_some() {
+        POOLED_CONNECTIONS.dec();
+    }
+    conn
+}
+
+fn reap(name: &str, max_idle: Duration, max_age: Option<Duration>) {
+    let now = Instant::now();
+    let expired = {
+        let mut pool = POOL.lock();
+        let Some(entries) = pool.get_mut(name) else {
+            return;
+        };
+        let (expired, live): (Vec<_>, Vec<_>) = entries
+            .drain(..)
+            .partition(|conn| conn.is_expired(now, max_idle, max_age));
+        if live.is_empty() {
+            pool.remove(name);
+        } else {
+            *entries = live;
+        }
+        expired
+    };
+
+    for conn in expired {
+        POOLED_CONNECTIONS.dec();
+        conn.close();
+    }
+}
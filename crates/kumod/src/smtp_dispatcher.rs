@@ -3,33 +3,207 @@ use crate::http_server::admin_trace_smtp_client_v1::{
     SmtpClientTraceEventPayload, SmtpClientTracerImpl,
 };
 use crate::logging::disposition::{log_disposition, LogDisposition, RecordType};
-use crate::ready_queue::{Dispatcher, QueueDispatcher};
+use crate::ready_queue::{BatchAdmission, Dispatcher, QueueDispatcher};
+use crate::smtp_connection_pool::{self, PooledConnection};
 use crate::spool::SpoolManager;
+use crate::traffic_shaping;
 use anyhow::Context;
 use async_trait::async_trait;
 use config::{load_config, CallbackSignature};
+use data_loader::KeySource;
 use dns_resolver::{resolve_a_or_aaaa, ResolvedMxAddresses};
-use kumo_api_types::egress_path::Tls;
+use kumo_api_types::egress_path::{Tls, TlsPinMismatch};
 use kumo_log_types::{MaybeProxiedSourceAddress, ResolvedAddress};
 use kumo_server_lifecycle::ShutdownSubcription;
 use kumo_server_runtime::spawn_local;
+use lruttl::LruCacheWithTtl;
 use message::message::QueueNameComponents;
-use message::Message;
-use mta_sts::policy::PolicyMode;
+use message::{EnvelopeAddress, Message};
+use mta_sts::policy::{MtaStsPolicy, PolicyMode};
+use once_cell::sync::Lazy;
 use rfc5321::{
-    ClientError, EnhancedStatusCode, ForwardPath, Response, ReversePath, SmtpClient,
-    TlsInformation, TlsOptions, TlsStatus,
+    ClientError, DsnMailParameters, EnhancedStatusCode, EsmtpParameter, ForwardPath, Response,
+    ReversePath, SmtpClient, TlsInformation, TlsOptions, TlsStatus,
 };
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::borrow::Cow;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use throttle::limit::LimitLease;
+use tokio::net::UnixStream;
 use tracing::Level;
 use uuid::Uuid;
 
+/// Tracks the next relay host to use for each ready queue
+/// whose relay_strategy is RoundRobin
+static RELAY_ROTATION: Lazy<LruCacheWithTtl<String, Arc<AtomicUsize>>> =
+    Lazy::new(|| LruCacheWithTtl::new(1024));
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct SmtpProtocol {
     #[serde(default)]
     pub mx_list: Vec<MxListEntry>,
+    /// An explicit list of hosts to relay through, used instead of
+    /// resolving the MX records of the routing domain
+    #[serde(default)]
+    pub relay_hosts: Vec<RelayHost>,
+    #[serde(default)]
+    pub relay_strategy: RelayStrategy,
+}
+
+impl SmtpProtocol {
+    /// If the destination is explicitly configured, rather than being
+    /// resolved from MX records, returns the site name that identifies it
+    pub fn explicit_site_name(&self) -> Option<String> {
+        if !self.relay_hosts.is_empty() {
+            let hosts: Vec<String> = self
+                .relay_hosts
+                .iter()
+                .map(|relay| match relay.port {
+                    Some(port) => format!("{}:{port}", relay.host),
+                    None => relay.host.to_string(),
+                })
+                .collect();
+            return Some(format!("relay_hosts:{}", hosts.join(",")));
+        }
+        if !self.mx_list.is_empty() {
+            let mx_list: Vec<String> = self
+                .mx_list
+                .iter()
+                .map(|a| match a {
+                    MxListEntry::Name(a) => a.clone(),
+                    MxListEntry::Resolved(addr) => addr.addr.to_string(),
+                })
+                .collect();
+            return Some(format!("mx_list:{}", mx_list.join(",")));
+        }
+        None
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RelayStrategy {
+    /// Try the relay hosts in the order listed, only moving on to
+    /// the next host when connecting to the previous one fails
+    #[default]
+    Failover,
+    /// Start each new connection with the host after the one used
+    /// by the previous connection, so that connections are spread
+    /// across all of the relay hosts. The remaining hosts are tried
+    /// in order if that fails.
+    RoundRobin,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RelayHost {
+    /// A name that needs to be resolved to its A or AAAA record in DNS,
+    /// an IP domain literal enclosed in square brackets like `[10.0.0.1]`,
+    /// or the absolute path to a unix domain socket
+    pub host: String,
+    /// Overrides the smtp_port of the egress path
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Overrides the enable_tls setting of the egress path
+    #[serde(default)]
+    pub enable_tls: Option<Tls>,
+    /// Overrides the smtp_auth_plain_username and smtp_auth_plain_password
+    /// settings of the egress path
+    #[serde(default)]
+    pub smtp_auth_plain_username: Option<String>,
+    #[serde(default)]
+    pub smtp_auth_plain_password: Option<KeySource>,
+}
+
+impl RelayHost {
+    fn unix_socket(&self) -> Option<&Path> {
+        self.host.starts_with('/').then(|| Path::new(&self.host))
+    }
+}
+
+/// A candidate peer for a connection
+#[derive(Debug, Clone)]
+struct ConnectTarget {
+    /// For a unix domain socket, the name is the path to the
+    /// socket and the address is unspecified
+    address: ResolvedAddress,
+    /// Set when connecting to one of the relay_hosts, whose settings
+    /// take precedence over those of the egress path
+    relay: Option<Arc<RelayHost>>,
+}
+
+impl ConnectTarget {
+    fn unix_socket(&self) -> Option<&Path> {
+        self.relay.as_ref().and_then(|relay| relay.unix_socket())
+    }
+}
+
+/// Orders hosts for a connection attempt, starting from the
+/// host at index start and wrapping around
+fn relay_host_order<T>(mut hosts: Vec<T>, start: usize) -> Vec<T> {
+    if !hosts.is_empty() {
+        let start = start % hosts.len();
+        hosts.rotate_left(start);
+    }
+    hosts
+}
+
+/// Resolves the relay_hosts into connection candidates, ordered so that
+/// the preferred candidate is at the end, as we pop candidates off the end
+/// until we have exhausted the connection plan
+async fn resolve_relay_hosts(name: &str, proto_config: &SmtpProtocol) -> Vec<ConnectTarget> {
+    let start = match proto_config.relay_strategy {
+        RelayStrategy::Failover => 0,
+        RelayStrategy::RoundRobin => RELAY_ROTATION
+            .get_or_insert(name.to_string(), Duration::from_secs(3600), || {
+                Arc::new(AtomicUsize::new(0))
+            })
+            .fetch_add(1, Ordering::Relaxed),
+    };
+    let hosts = relay_host_order(
+        proto_config
+            .relay_hosts
+            .iter()
+            .cloned()
+            .map(Arc::new)
+            .collect(),
+        start,
+    );
+
+    let mut targets = vec![];
+    for relay in hosts {
+        if let Some(path) = relay.unix_socket() {
+            targets.push(ConnectTarget {
+                address: ResolvedAddress {
+                    name: path.display().to_string(),
+                    addr: Ipv4Addr::UNSPECIFIED.into(),
+                },
+                relay: Some(relay),
+            });
+            continue;
+        }
+        match resolve_a_or_aaaa(&relay.host).await {
+            Ok(addresses) => {
+                targets.extend(addresses.into_iter().map(|address| ConnectTarget {
+                    address,
+                    relay: Some(relay.clone()),
+                }));
+            }
+            Err(err) => {
+                // Move on to the next host, as we would for
+                // a host that we could not connect to
+                tracing::error!(
+                    "{name}: failed to resolve relay host {}: {err:#}",
+                    relay.host
+                );
+            }
+        }
+    }
+    targets.reverse();
+    targets
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -44,13 +218,22 @@ pub enum MxListEntry {
 
 #[derive(Debug)]
 pub struct SmtpDispatcher {
-    addresses: Vec<ResolvedAddress>,
+    targets: Vec<ConnectTarget>,
+    /// Deliver using RFC 2033 LMTP rather than SMTP
+    lmtp: bool,
     client: Option<MetricsWrappedConnection<SmtpClient>>,
     client_address: Option<ResolvedAddress>,
+    /// The target that we connected to, if the current connection was
+    /// established by us rather than adopted from the connection pool
+    client_target: Option<ConnectTarget>,
     source_address: Option<MaybeProxiedSourceAddress>,
     ehlo_name: String,
     tls_info: Option<TlsInformation>,
     tracer: Arc<SmtpClientTracerImpl>,
+    /// Set when a peer rejected BDAT despite advertising CHUNKING,
+    /// so that DATA is used when we reconnect to retry the message,
+    /// even if the ehlo capability cache is disabled
+    bdat_rejected: bool,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -80,7 +263,12 @@ impl SmtpDispatcher {
     pub async fn init(
         dispatcher: &mut Dispatcher,
         proto_config: &SmtpProtocol,
+        lmtp: bool,
     ) -> anyhow::Result<Option<Self>> {
+        if lmtp && proto_config.explicit_site_name().is_none() {
+            anyhow::bail!("the lmtp protocol requires either relay_hosts or mx_list");
+        }
+
         let path_config = dispatcher.path_config.borrow().clone();
         let ehlo_name = match &path_config.ehlo_domain {
             Some(n) => n.to_string(),
@@ -95,30 +283,68 @@ impl SmtpDispatcher {
 
         let trace_id = Uuid::new_v4().to_string();
 
-        let addresses = if proto_config.mx_list.is_empty() {
-            dispatcher
-                .mx
-                .as_ref()
-                .expect("to have mx when doing smtp")
-                .resolve_addresses()
-                .await
-        } else {
-            let mut addresses = vec![];
-            for a in proto_config.mx_list.iter() {
-                match a {
-                    MxListEntry::Name(a) => {
-                        addresses.append(
-                            &mut resolve_a_or_aaaa(a)
-                                .await
-                                .with_context(|| format!("resolving mx_list entry {a}"))?,
-                        );
-                    }
-                    MxListEntry::Resolved(addr) => {
-                        addresses.append(&mut vec![addr.clone()]);
+        let (mx_plan, mut targets) = if proto_config.relay_hosts.is_empty() {
+            let addresses = if proto_config.mx_list.is_empty() {
+                dispatcher
+                    .mx
+                    .as_ref()
+                    .expect("to have mx when doing smtp")
+                    .resolve_addresses()
+                    .await
+            } else {
+                let mut addresses = vec![];
+                for a in proto_config.mx_list.iter() {
+                    match a {
+                        MxListEntry::Name(a) => {
+                            addresses.append(
+                                &mut resolve_a_or_aaaa(a)
+                                    .await
+                                    .with_context(|| format!("resolving mx_list entry {a}"))?,
+                            );
+                        }
+                        MxListEntry::Resolved(addr) => {
+                            addresses.append(&mut vec![addr.clone()]);
+                        }
                     }
                 }
+                ResolvedMxAddresses::Addresses(addresses)
+            };
+
+            tracing::trace!("mx resolved to {addresses:?}");
+            let mx_plan = serde_json::to_value(&addresses)?;
+
+            match addresses {
+                ResolvedMxAddresses::NullMx => {
+                    dispatcher
+                        .bulk_ready_queue_operation(Response {
+                            code: 556,
+                            enhanced_code: Some(EnhancedStatusCode {
+                                class: 5,
+                                subject: 1,
+                                detail: 10,
+                            }),
+                            content: "Recipient address has a null MX".to_string(),
+                            command: None,
+                        })
+                        .await;
+                    return Ok(None);
+                }
+                ResolvedMxAddresses::Addresses(a) => (
+                    mx_plan,
+                    a.into_iter()
+                        .map(|address| ConnectTarget {
+                            address,
+                            relay: None,
+                        })
+                        .collect::<Vec<_>>(),
+                ),
             }
-            ResolvedMxAddresses::Addresses(addresses)
+        } else {
+            let targets = resolve_relay_hosts(&dispatcher.name, proto_config).await;
+            tracing::trace!("relay_hosts resolved to {targets:?}");
+            let addresses: Vec<&ResolvedAddress> =
+                targets.iter().map(|target| &target.address).collect();
+            (serde_json::json!({ "RelayHosts": addresses }), targets)
         };
 
         let tracer = Arc::new(SmtpClientTracerImpl::new(serde_json::json!({
@@ -126,31 +352,10 @@ impl SmtpDispatcher {
             "egress_source": dispatcher.egress_source.name.to_string(),
             "id": trace_id,
             "ready_queue_name": dispatcher.name.to_string(),
-            "mx_plan": addresses.clone(),
+            "mx_plan": mx_plan,
         })));
 
-        tracing::trace!("mx resolved to {addresses:?}");
-
-        let mut addresses = match addresses {
-            ResolvedMxAddresses::NullMx => {
-                dispatcher
-                    .bulk_ready_queue_operation(Response {
-                        code: 556,
-                        enhanced_code: Some(EnhancedStatusCode {
-                            class: 5,
-                            subject: 1,
-                            detail: 10,
-                        }),
-                        content: "Recipient address has a null MX".to_string(),
-                        command: None,
-                    })
-                    .await;
-                return Ok(None);
-            }
-            ResolvedMxAddresses::Addresses(a) => a,
-        };
-
-        if addresses.is_empty() {
+        if targets.is_empty() {
             dispatcher
                 .bulk_ready_queue_operation(Response {
                     code: 451,
@@ -166,7 +371,10 @@ impl SmtpDispatcher {
             return Ok(None);
         }
 
-        for addr in &addresses {
+        // The prohibited_hosts and skip_hosts lists don't
+        // apply to unix domain sockets, as they have no address
+        for target in targets.iter().filter(|t| t.unix_socket().is_none()) {
+            let addr = &target.address;
             if path_config.prohibited_hosts.contains(addr.addr) {
                 dispatcher
                     .bulk_ready_queue_operation(Response {
@@ -187,9 +395,11 @@ impl SmtpDispatcher {
             }
         }
 
-        addresses.retain(|addr| !path_config.skip_hosts.contains(addr.addr));
+        targets.retain(|target| {
+            target.unix_socket().is_some() || !path_config.skip_hosts.contains(target.address.addr)
+        });
 
-        if addresses.is_empty() {
+        if targets.is_empty() {
             dispatcher
                 .bulk_ready_queue_operation(Response {
                     code: 550,
@@ -205,15 +415,99 @@ impl SmtpDispatcher {
             return Ok(None);
         }
 
-        Ok(Some(Self {
-            addresses,
+        let mut smtp_dispatcher = Self {
+            targets,
+            lmtp,
             client: None,
             client_address: None,
+            client_target: None,
             ehlo_name,
             tls_info: None,
             source_address: None,
             tracer,
-        }))
+            bdat_rejected: false,
+        };
+
+        // Adopt a pooled connection that was handed to us right away,
+        // so that it is returned to the pool if we idle out without
+        // having used it
+        if dispatcher.pooled_connection.is_some() {
+            smtp_dispatcher.adopt_pooled_connection(dispatcher).await;
+        }
+
+        Ok(Some(smtp_dispatcher))
+    }
+
+    /// Try to adopt an idle connection for this ready queue, either
+    /// the one that was handed to this dispatcher when it was started,
+    /// or one from the connection pool.  Returns true if a connection
+    /// was adopted.
+    async fn adopt_pooled_connection(&mut self, dispatcher: &mut Dispatcher) -> bool {
+        let pool_limits = {
+            let path_config = dispatcher.path_config.borrow();
+            path_config
+                .connection_pool_idle_timeout
+                .map(|max_idle| (max_idle, path_config.max_connection_age))
+        };
+
+        loop {
+            let mut conn = match dispatcher.pooled_connection.take() {
+                Some(conn) => conn,
+                None => {
+                    let Some((max_idle, max_age)) = pool_limits else {
+                        return false;
+                    };
+                    match smtp_connection_pool::check_out(&dispatcher.name, max_idle, max_age) {
+                        Some(conn) => conn,
+                        None => return false,
+                    }
+                }
+            };
+            conn.client.set_tracer(self.tracer.clone());
+
+            // The peer may have timed out the connection while it
+            // was idle in the pool, so verify that it is still usable
+            match conn
+                .client
+                .send_command(&rfc5321::Command::Noop(None))
+                .await
+            {
+                Ok(response) if response.code == 250 => {}
+                _ => {
+                    conn.release_leases().await;
+                    continue;
+                }
+            }
+            // We already hold our own leases for this connection; a
+            // connection that was handed to us has already given its
+            // leases to us, so this releases nothing in that case
+            conn.release_leases().await;
+
+            self.tracer.diagnostic(Level::INFO, || {
+                format!(
+                    "Reusing pooled connection to {:?} after {} deliveries",
+                    conn.address, conn.deliveries
+                )
+            });
+            if let Some(source_address) = &conn.source_address {
+                self.tracer
+                    .set_meta("source_address", source_address.address.to_string());
+            }
+            self.tracer
+                .set_meta("mx_host", conn.address.name.to_string());
+            self.tracer
+                .set_meta("mx_address", conn.address.addr.to_string());
+
+            dispatcher.peer_mail_max = conn.client.limits().mail_max;
+            self.client.replace(conn.client);
+            self.client_address.replace(conn.address);
+            self.client_target.take();
+            self.source_address = conn.source_address;
+            self.tls_info = conn.tls_info;
+            dispatcher.delivered_this_connection = conn.deliveries;
+            dispatcher.connection_established.replace(conn.established);
+            return true;
+        }
     }
 
     async fn attempt_connection_impl(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<()> {
@@ -221,6 +515,10 @@ impl SmtpDispatcher {
             return Ok(());
         }
 
+        if self.adopt_pooled_connection(dispatcher).await {
+            return Ok(());
+        }
+
         let mut shutdown = ShutdownSubcription::get();
 
         let path_config = dispatcher.path_config.borrow();
@@ -262,23 +560,37 @@ impl SmtpDispatcher {
 
         let connection_wrapper = dispatcher.metrics.wrap_connection(());
 
-        let address = self
-            .addresses
+        let target = self
+            .targets
             .pop()
             .ok_or_else(|| anyhow::anyhow!("no more addresses to try!"))?;
+        let unix_socket = target.unix_socket().map(Path::to_path_buf);
+        let client_target = target.clone();
+        let ConnectTarget { address, relay } = target;
 
         let ehlo_name = self.ehlo_name.to_string();
+        let lmtp = self.lmtp;
         let mx_host = address.name.to_string();
-        let mut enable_tls = path_config.enable_tls;
-        let port = dispatcher
-            .egress_source
-            .remote_port
+        let mut enable_tls = match relay.as_ref().and_then(|relay| relay.enable_tls) {
+            Some(enable_tls) => enable_tls,
+            // There is no host name to verify a certificate
+            // against when connecting to a unix domain socket
+            None if unix_socket.is_some() => Tls::Disabled,
+            None => path_config.enable_tls,
+        };
+        let port = relay
+            .as_ref()
+            .and_then(|relay| relay.port)
+            .or(dispatcher.egress_source.remote_port)
             .unwrap_or(path_config.smtp_port);
-        let connect_context = format!("connect to {address:?} port {port} and read initial banner");
+        let peer = match &unix_socket {
+            Some(path) => format!("unix socket {}", path.display()),
+            None => format!("{address:?} port {port}"),
+        };
+        let connect_context = format!("connect to {peer} and read initial banner");
 
-        self.tracer.diagnostic(Level::INFO, || {
-            format!("Attempting connection to {address:?} port {port}")
-        });
+        self.tracer
+            .diagnostic(Level::INFO, || format!("Attempting connection to {peer}"));
 
         let make_connection = {
             let address = address.clone();
@@ -291,20 +603,39 @@ impl SmtpDispatcher {
             // awaiting the shutdown subscription, causing us to uselessly wait
             // for the full connect timeout during shutdown.
             tokio::spawn(async move {
-                let (stream, source_address) = tokio::time::timeout(
-                    timeouts.connect_timeout,
-                    egress_source.connect_to(SocketAddr::new(address.addr, port)),
-                )
-                .await??;
+                let (mut client, source_address) = match unix_socket {
+                    Some(path) => {
+                        let stream = tokio::time::timeout(
+                            timeouts.connect_timeout,
+                            UnixStream::connect(&path),
+                        )
+                        .await??;
 
-                tracing::debug!(
-                    "connected to {address:?} port {port} via source address {source_address:?}"
-                );
+                        tracing::debug!("connected to unix socket {}", path.display());
 
-                let mut client = SmtpClient::with_stream(stream, &mx_host, timeouts);
-                tracer.set_meta("source_address", source_address.address.to_string());
+                        // The egress source doesn't apply to a local socket
+                        (SmtpClient::with_stream(stream, &mx_host, timeouts), None)
+                    }
+                    None => {
+                        let (stream, source_address) = tokio::time::timeout(
+                            timeouts.connect_timeout,
+                            egress_source.connect_to(SocketAddr::new(address.addr, port)),
+                        )
+                        .await??;
+
+                        tracing::debug!(
+                            "connected to {address:?} port {port} via source address {source_address:?}"
+                        );
+
+                        tracer.set_meta("source_address", source_address.address.to_string());
+                        tracer.set_meta("mx_address", address.addr.to_string());
+                        (
+                            SmtpClient::with_stream(stream, &mx_host, timeouts),
+                            Some(source_address),
+                        )
+                    }
+                };
                 tracer.set_meta("mx_host", mx_host.to_string());
-                tracer.set_meta("mx_address", address.addr.to_string());
                 tracer.submit(|| SmtpClientTraceEventPayload::Connected);
 
                 client.set_tracer(tracer);
@@ -315,7 +646,7 @@ impl SmtpDispatcher {
                     .await
                     .context("reading banner")?;
                 if banner.code != 220 {
-                    return anyhow::Result::<(SmtpClient, MaybeProxiedSourceAddress)>::Err(
+                    return anyhow::Result::<(SmtpClient, Option<MaybeProxiedSourceAddress>)>::Err(
                         ClientError::Rejected(banner).into(),
                     );
                 }
@@ -332,16 +663,15 @@ impl SmtpDispatcher {
             result = make_connection => { result? },
         }
         .with_context(|| connect_context.clone())?;
-        self.source_address.replace(source_address);
+        self.source_address = source_address;
 
         // Say EHLO
-        let pretls_caps = client
-            .ehlo(&ehlo_name)
+        greet(&mut client, lmtp, &ehlo_name)
             .await
-            .with_context(|| format!("{address:?}:{port}: EHLO after banner"))?;
+            .with_context(|| format!("{peer}: EHLO after banner"))?;
 
         // Use STARTTLS if available.
-        let has_tls = pretls_caps.contains_key("STARTTLS");
+        let has_tls = client.capabilities().contains_key("STARTTLS");
 
         let mut dane_tlsa = vec![];
         let mut mta_sts_eligible = true;
@@ -390,37 +720,64 @@ impl SmtpDispatcher {
         // Figure out MTA-STS policy.
         if mta_sts_eligible && path_config.enable_mta_sts {
             if let Some(mx) = &dispatcher.mx {
-                match mta_sts::get_policy_for_domain(&mx.domain_name).await {
+                let policy = match mta_sts::get_policy_for_domain(&mx.domain_name).await {
                     Ok(policy) => {
                         self.tracer.diagnostic(Level::INFO, || {
                             format!("MTA-STS policy for {} is {:?}", mx.domain_name, policy.mode)
                         });
+                        Some(policy)
+                    }
+                    Err(err) => {
+                        self.tracer.diagnostic(Level::INFO, || {
+                            format!("MTA-STS resolve error for {}: {err:#}", mx.domain_name)
+                        });
+                        None
+                    }
+                };
+
+                let mode = match get_mta_sts_mode_override(&mx.domain_name, policy.as_deref()).await
+                {
+                    Ok(Some(mode)) => {
+                        self.tracer.diagnostic(Level::INFO, || {
+                            format!(
+                                "MTA-STS mode for {} was overridden to {mode:?} \
+                                 by smtp_client_mta_sts_policy",
+                                mx.domain_name
+                            )
+                        });
+                        Some(mode)
+                    }
+                    Ok(None) => policy.as_ref().map(|p| p.mode),
+                    Err(err) => {
+                        tracing::error!(
+                            "smtp_client_mta_sts_policy event failed for {}: {err:#}. \
+                             Using the published policy",
+                            mx.domain_name
+                        );
+                        policy.as_ref().map(|p| p.mode)
+                    }
+                };
 
-                        match policy.mode {
-                            PolicyMode::Enforce => {
-                                enable_tls = Tls::Required;
-                                if !policy.mx_name_matches(&address.name) {
-                                    anyhow::bail!(
-                                        "MTA-STS policy for {domain} is set to \
+                match mode {
+                    Some(PolicyMode::Enforce) => {
+                        enable_tls = Tls::Required;
+                        if let Some(policy) = &policy {
+                            if !policy.mx_name_matches(&address.name) {
+                                anyhow::bail!(
+                                    "MTA-STS policy for {domain} is set to \
                                      enforce but the current MX candidate \
                                      {mx_host} does not match the list of allowed \
                                      hosts. {policy:?}",
-                                        domain = mx.domain_name,
-                                        mx_host = address.name
-                                    );
-                                }
-                            }
-                            PolicyMode::Testing => {
-                                enable_tls = Tls::OpportunisticInsecure;
+                                    domain = mx.domain_name,
+                                    mx_host = address.name
+                                );
                             }
-                            PolicyMode::None => {}
                         }
                     }
-                    Err(err) => {
-                        self.tracer.diagnostic(Level::INFO, || {
-                            format!("MTA-STS resolve error for {}: {err:#}", mx.domain_name)
-                        });
+                    Some(PolicyMode::Testing) => {
+                        enable_tls = Tls::OpportunisticInsecure;
                     }
+                    Some(PolicyMode::None) | None => {}
                 }
             } else {
                 self.tracer.diagnostic(Level::INFO, || {
@@ -479,13 +836,13 @@ impl SmtpDispatcher {
                 // incorrectly roll over failed TLS into the following command,
                 // and we want to consider those as connection errors rather than
                 // having them show up per-message in MAIL FROM
-                client.ehlo(&ehlo_name).await.map_err(|error| {
-                    OpportunisticInsecureTlsHandshakeError {
+                greet(&mut client, lmtp, &ehlo_name)
+                    .await
+                    .map_err(|error| OpportunisticInsecureTlsHandshakeError {
                         error,
                         address: format!("{address:?}:{port}"),
                         label,
-                    }
-                })?;
+                    })?;
                 enabled
             }
             (Tls::Opportunistic | Tls::Required | Tls::RequiredInsecure, true) => {
@@ -522,22 +879,77 @@ impl SmtpDispatcher {
                         self.tls_info.replace(info);
                     }
                 }
-                client
-                    .ehlo(&ehlo_name)
+                greet(&mut client, lmtp, &ehlo_name)
                     .await
-                    .with_context(|| format!("{address:?}:{port}: EHLO after STARTTLS"))?;
+                    .with_context(|| format!("{peer}: EHLO after STARTTLS"))?;
                 true
             }
         };
 
-        if let Some(username) = &path_config.smtp_auth_plain_username {
-            if !tls_enabled && !path_config.allow_smtp_auth_plain_without_tls {
-                anyhow::bail!(
-                    "TLS is not enabled and AUTH PLAIN is required. Skipping ({address:?}:{port})"
+        crate::ehlo_cache::record(
+            &address.name,
+            address.addr,
+            port,
+            has_tls,
+            tls_enabled,
+            client.capabilities(),
+            path_config.ehlo_capability_cache_ttl,
+        );
+        if client.capabilities().contains_key("CHUNKING")
+            && (self.bdat_rejected
+                || crate::ehlo_cache::bdat_rejected(&address.name, address.addr, port))
+        {
+            self.tracer.diagnostic(Level::INFO, || {
+                format!("{address:?} port {port} previously rejected BDAT, using DATA instead")
+            });
+            client.disable_capability("CHUNKING");
+        }
+
+        if !path_config.tls_pinned_spki_sha256.is_empty() {
+            let peer_spki_sha256 = match (tls_enabled, &self.tls_info) {
+                (true, Some(info)) => info.peer_spki_sha256.as_slice(),
+                _ => &[],
+            };
+            if !path_config.tls_pins_match(peer_spki_sha256) {
+                let message = format!(
+                    "certificate presented by {address:?}:{port} does not match \
+                     tls_pinned_spki_sha256. Presented: {peer_spki_sha256:?}"
                 );
+                match path_config.tls_pin_mismatch {
+                    TlsPinMismatch::TempFail => {
+                        client.send_command(&rfc5321::Command::Quit).await.ok();
+                        anyhow::bail!("{message}");
+                    }
+                    TlsPinMismatch::Alert => {
+                        tracing::warn!("{message}");
+                        self.tracer.diagnostic(Level::WARN, || message.clone());
+                    }
+                }
             }
+        }
 
-            let password = if let Some(pw) = &path_config.smtp_auth_plain_password {
+        // Credentials configured for the relay host take
+        // precedence over those of the egress path
+        let (auth_username, auth_password) = match relay
+            .as_ref()
+            .filter(|relay| relay.smtp_auth_plain_username.is_some())
+        {
+            Some(relay) => (
+                &relay.smtp_auth_plain_username,
+                &relay.smtp_auth_plain_password,
+            ),
+            None => (
+                &path_config.smtp_auth_plain_username,
+                &path_config.smtp_auth_plain_password,
+            ),
+        };
+
+        if let Some(username) = auth_username {
+            if !tls_enabled && !path_config.allow_smtp_auth_plain_without_tls {
+                anyhow::bail!("TLS is not enabled and AUTH PLAIN is required. Skipping ({peer})");
+            }
+
+            let password = if let Some(pw) = auth_password {
                 Some(
                     String::from_utf8(
                         pw.get()
@@ -554,24 +966,455 @@ impl SmtpDispatcher {
                 .auth_plain(username, password.as_deref())
                 .await
                 .with_context(|| {
-                    format!(
-                        "authenticating as {username} via SMTP AUTH PLAIN to {address:?}:{port}"
-                    )
+                    format!("authenticating as {username} via SMTP AUTH PLAIN to {peer}")
                 })?;
         }
 
+        dispatcher.peer_mail_max = client.limits().mail_max;
+        if let Some(limit) = dispatcher.peer_mail_max {
+            self.tracer.diagnostic(Level::INFO, || {
+                format!("Peer advertised LIMITS MAILMAX={limit}")
+            });
+        }
+
         self.client
             .replace(connection_wrapper.map_connection(client));
         self.client_address.replace(address);
+        self.client_target.replace(client_target);
         dispatcher.delivered_this_connection = 0;
+        dispatcher.connection_established.replace(Instant::now());
+        Ok(())
+    }
+
+    /// Pop messages from the ready queue that can share a transaction
+    /// with msg, up to the max_recipients_per_batch limit of the path,
+    /// or the RCPTMAX limit advertised by the peer, if that is smaller.
+    /// Messages that cannot be batched are returned to the ready queue.
+    /// Each member of the batch is subject to the same checks as any
+    /// other message that is dispatched; the traffic shaping leases
+    /// that are returned must be held until the transaction completes.
+    async fn take_batch(
+        &self,
+        msg: &Message,
+        dispatcher: &mut Dispatcher,
+    ) -> anyhow::Result<(Vec<(Message, ForwardPath)>, Vec<LimitLease>)> {
+        let configured = dispatcher.path_config.borrow().max_recipients_per_batch;
+        let limit = self
+            .client
+            .as_ref()
+            .and_then(|client| client.limits().rcpt_max)
+            .map_or(configured, |rcpt_max| rcpt_max.min(configured));
+
+        let mut batch = vec![];
+        let mut leases = vec![];
+        if limit <= 1 {
+            return Ok((batch, leases));
+        }
+
+        let sender = msg.sender()?;
+        let data = msg.get_data();
+        let content = shared_content(&data);
+        let dsn = msg.get_dsn_mail_parameters()?;
+        let mut unsuitable = vec![];
+
+        // Only consider as many candidates as could fit into the
+        // batch, so that a ready queue holding mostly unrelated
+        // messages doesn't get churned through for every transaction
+        for _ in 1..limit {
+            let Some(candidate) = dispatcher.ready.pop() else {
+                break;
+            };
+            let loaded: anyhow::Result<()> = async {
+                candidate.load_meta_if_needed().await?;
+                candidate.load_data_if_needed().await
+            }
+            .await;
+            if let Err(err) = loaded {
+                tracing::error!(
+                    "failed to load {} for {}: {err:#}",
+                    candidate.id(),
+                    dispatcher.name
+                );
+                spawn_local(
+                    "requeue message".to_string(),
+                    Dispatcher::requeue_message(candidate, false, None),
+                )?;
+                continue;
+            }
+            let Some(recipient) = batch_recipient(&candidate, &sender, content, &dsn) else {
+                unsuitable.push(candidate);
+                continue;
+            };
+            match dispatcher.gate_batch_message(candidate).await {
+                Ok(BatchAdmission::Admit(candidate, candidate_leases)) => {
+                    batch.push((candidate, recipient));
+                    leases.extend(candidate_leases);
+                }
+                Ok(BatchAdmission::Skip) => {}
+                Ok(BatchAdmission::Stop) => break,
+                Err(err) => {
+                    tracing::error!("failed to extend batch for {}: {err:#}", dispatcher.name);
+                    break;
+                }
+            }
+        }
+
+        for candidate in unsuitable {
+            dispatcher.return_to_ready(candidate)?;
+        }
+        dispatcher.delivered_this_connection += batch.len();
+
+        Ok((batch, leases))
+    }
+
+    async fn record_disposition(
+        &self,
+        kind: RecordType,
+        msg: &Message,
+        response: Response,
+        dispatcher: &Dispatcher,
+    ) {
+        log_disposition(LogDisposition {
+            kind,
+            msg: msg.clone(),
+            site: &dispatcher.name,
+            peer_address: self.client_address.as_ref(),
+            response,
+            egress_pool: Some(&dispatcher.egress_pool),
+            egress_source: Some(&dispatcher.egress_source.name),
+            relay_disposition: None,
+            delivery_protocol: Some(&dispatcher.delivery_protocol),
+            tls_info: self.tls_info.as_ref(),
+            source_address: self.source_address.clone(),
+            provider: dispatcher.path_config.borrow().provider_name.as_deref(),
+        })
+        .await;
+    }
+
+    async fn log_delivered(
+        &self,
+        msg: Message,
+        response: Response,
+        dispatcher: &Dispatcher,
+    ) -> anyhow::Result<()> {
+        self.record_disposition(RecordType::Delivery, &msg, response, dispatcher)
+            .await;
+        SpoolManager::remove_from_spool(*msg.id()).await?;
+        dispatcher.metrics.inc_delivered();
+        Ok(())
+    }
+
+    async fn log_transient_failure(
+        &self,
+        msg: Message,
+        response: Response,
+        dispatcher: &Dispatcher,
+    ) -> anyhow::Result<()> {
+        self.record_disposition(RecordType::TransientFailure, &msg, response, dispatcher)
+            .await;
+        spawn_local(
+            "requeue message".to_string(),
+            Dispatcher::requeue_message(msg, true, None),
+        )?;
+        dispatcher.metrics.inc_transfail();
         Ok(())
     }
+
+    /// Classify a non-250 response to msg, giving the policy the
+    /// opportunity to rewrite its status code first
+    async fn handle_rejection(
+        &self,
+        msg: Message,
+        mut response: Response,
+        dispatcher: &Dispatcher,
+    ) -> anyhow::Result<()> {
+        let queue_name = msg.get_queue_name()?;
+        let components = QueueNameComponents::parse(&queue_name);
+        let mut config = load_config().await.context("load_config")?;
+
+        let sig =
+            CallbackSignature::<(String, &str, Option<&str>, Option<&str>, &str), Option<u16>>::new(
+                "smtp_client_rewrite_delivery_status",
+            );
+
+        let rewritten_code: anyhow::Result<Option<u16>> = config
+            .async_call_callback(
+                &sig,
+                (
+                    response.to_single_line(),
+                    components.domain,
+                    components.tenant,
+                    components.campaign,
+                    components
+                        .routing_domain
+                        .as_deref()
+                        .unwrap_or(&components.domain),
+                ),
+            )
+            .await;
+
+        match rewritten_code {
+            Ok(Some(code)) if code != response.code => {
+                response.content = format!(
+                    "{} (kumomta: status was rewritten from {} -> {code})",
+                    response.content, response.code
+                );
+                response.code = code;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::error!("smtp_client_rewrite_delivery_status event failed: {err:#}. Preserving original DSN");
+            }
+        }
+
+        if response.code >= 400 && response.code < 500 {
+            // Transient failure
+            tracing::debug!(
+                "failed to send message to {} {:?}: {response:?}",
+                dispatcher.name,
+                self.client_address
+            );
+            traffic_shaping::transient_failure(&dispatcher.site_name, &msg, &response).await;
+            self.log_transient_failure(msg, response, dispatcher).await
+        } else if response.code >= 200 && response.code < 300 {
+            tracing::debug!("Delivered OK! {response:?}");
+            self.log_delivered(msg, response, dispatcher).await
+        } else {
+            dispatcher.metrics.inc_fail();
+            tracing::debug!(
+                "failed to send message to {} {:?}: {response:?}",
+                dispatcher.name,
+                self.client_address
+            );
+            self.record_disposition(RecordType::Bounce, &msg, response, dispatcher)
+                .await;
+            SpoolManager::remove_from_spool(*msg.id()).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Returns true if field is a supplemental trace header, as added at
+/// reception by the smtp server.  The name of that header is configured
+/// per listener, so it is identified by the marker in its encoded
+/// value, in the same way as when parsing ARF reports.
+fn is_supplemental_trace_field(field: &[u8]) -> bool {
+    let Some(colon) = field.iter().position(|&b| b == b':') else {
+        return false;
+    };
+    let value: Vec<u8> = field[colon + 1..]
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    let Ok(decoded) = data_encoding::BASE64.decode(&value) else {
+        return false;
+    };
+    serde_json::from_slice::<serde_json::Value>(&decoded)
+        .ok()
+        .and_then(|obj| {
+            obj.get("_@_")
+                .and_then(|marker| marker.as_str())
+                .map(|marker| marker == "\\_/")
+        })
+        .unwrap_or(false)
+}
+
+/// Returns the trace header fields at the start of data that were
+/// added to each copy of a message at reception, and that therefore
+/// differ between the copies because they name the recipient.
+/// These are the `Received` header and the supplemental header.
+fn leading_trace_fields(data: &[u8]) -> Vec<&[u8]> {
+    let mut fields = vec![];
+    let mut rest = data;
+    loop {
+        let Some(colon) = rest.iter().position(|&b| b == b':' || b == b'\n') else {
+            break;
+        };
+        if rest[colon] != b':' {
+            break;
+        }
+
+        // The field ends at the first line break that is not
+        // followed by folding whitespace
+        let mut end = colon;
+        loop {
+            let Some(newline) = rest[end..].iter().position(|&b| b == b'\n') else {
+                return fields;
+            };
+            end += newline + 1;
+            if !matches!(rest.get(end), Some(b' ' | b'\t')) {
+                break;
+            }
+        }
+
+        let field = &rest[..end];
+        if !rest[..colon].eq_ignore_ascii_case(b"Received") && !is_supplemental_trace_field(field) {
+            break;
+        }
+        fields.push(field);
+        rest = &rest[end..];
+    }
+    fields
+}
+
+/// Returns the content of data that follows its leading trace header
+/// fields, which is identical for each copy of a message that was
+/// received for multiple recipients
+fn shared_content(data: &[u8]) -> &[u8] {
+    let trace_len: usize = leading_trace_fields(data).iter().map(|f| f.len()).sum();
+    &data[trace_len..]
+}
+
+/// Removes the `for <recipient>` clause from a Received field, if present
+fn strip_received_for<'a>(field: &'a [u8], recipient: &str) -> Cow<'a, [u8]> {
+    let clause = format!(" for <{recipient}>");
+    match field
+        .windows(clause.len())
+        .position(|w| w.eq_ignore_ascii_case(clause.as_bytes()))
+    {
+        Some(idx) => {
+            let mut stripped = field[..idx].to_vec();
+            stripped.extend_from_slice(&field[idx + clause.len()..]);
+            Cow::Owned(stripped)
+        }
+        None => Cow::Borrowed(field),
+    }
+}
+
+/// Produces the data to transmit for a batch from the data of its
+/// first message.  The trace headers of that message identify its
+/// recipient, which must not be revealed to the other recipients in
+/// the batch, so its supplemental trace header is removed, and the
+/// `for` clause naming its recipient is removed from its Received
+/// headers.
+fn batch_data(data: &[u8], recipient: &str) -> Vec<u8> {
+    let mut batch_data = Vec::with_capacity(data.len());
+    for field in leading_trace_fields(data) {
+        if !is_supplemental_trace_field(field) {
+            batch_data.extend_from_slice(&strip_received_for(field, recipient));
+        }
+    }
+    batch_data.extend_from_slice(shared_content(data));
+    batch_data
+}
+
+/// Returns the recipient of candidate if it can share a transaction
+/// with a message from sender with the specified content and DSN
+/// parameters: it must have the same sender, identical content other
+/// than its trace headers, the same DSN parameters, and must not
+/// require any per-message parameters
+fn batch_recipient(
+    candidate: &Message,
+    sender: &EnvelopeAddress,
+    content: &[u8],
+    dsn: &DsnMailParameters,
+) -> Option<ForwardPath> {
+    if candidate.sender().ok()? != *sender || shared_content(&candidate.get_data()) != content {
+        return None;
+    }
+    if candidate.get_dsn_mail_parameters().ok()? != *dsn {
+        return None;
+    }
+    if candidate.get_deliver_by(chrono::Utc::now()).ok()?.is_some() {
+        return None;
+    }
+    let recipient: ForwardPath = candidate.recipient().ok()?.try_into().ok()?;
+    // The SMTPUTF8 requirement is determined by the first message
+    // in the batch, so only ASCII recipients can be added to it
+    if !recipient.to_string().is_ascii() {
+        return None;
+    }
+    Some(recipient)
+}
+
+/// Greet the peer with LHLO if it speaks LMTP, or EHLO otherwise,
+/// updating the capabilities of the client
+async fn greet(client: &mut SmtpClient, lmtp: bool, name: &str) -> Result<(), ClientError> {
+    if lmtp {
+        client.lhlo(name).await?;
+    } else {
+        client.ehlo(name).await?;
+    }
+    Ok(())
+}
+
+/// Give the policy an opportunity to override the effective MTA-STS
+/// mode for a destination domain.  `policy` is None if no policy
+/// could be obtained for the domain.
+async fn get_mta_sts_mode_override(
+    domain: &str,
+    policy: Option<&MtaStsPolicy>,
+) -> anyhow::Result<Option<PolicyMode>> {
+    let mut config = load_config().await.context("load_config")?;
+
+    let sig = CallbackSignature::<(String, Option<String>, Vec<String>), Option<String>>::new(
+        "smtp_client_mta_sts_policy",
+    );
+
+    let mode: Option<String> = config
+        .async_call_callback_non_default_opt(
+            &sig,
+            (
+                domain.to_string(),
+                policy.map(|p| p.mode.as_str().to_string()),
+                policy.map(|p| p.mx.clone()).unwrap_or_default(),
+            ),
+        )
+        .await?;
+
+    mode.map(|mode| mode.parse()).transpose()
 }
 
 #[async_trait(?Send)]
 impl QueueDispatcher for SmtpDispatcher {
-    async fn close_connection(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
+    async fn close_connection(&mut self, dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
         if let Some(mut client) = self.client.take() {
+            if let (Some(address), Some(established)) = (
+                self.client_address.clone(),
+                dispatcher.connection_established.take(),
+            ) {
+                let (pool_idle_timeout, max_connection_age, connection_limit) = {
+                    let path_config = dispatcher.path_config.borrow();
+                    (
+                        path_config.connection_pool_idle_timeout,
+                        path_config.max_connection_age,
+                        path_config.connection_limit,
+                    )
+                };
+                let age_ok = max_connection_age
+                    .map(|max_age| established.elapsed() < max_age)
+                    .unwrap_or(true);
+                if let Some(max_idle) = pool_idle_timeout {
+                    if age_ok
+                        && !dispatcher.activity.is_shutting_down()
+                        && dispatcher.delivered_this_connection
+                            < dispatcher.max_deliveries_per_connection()
+                    {
+                        // The pooled connection continues to count
+                        // against the connection limits
+                        if let Some(leases) = dispatcher.transfer_leases(max_idle).await {
+                            smtp_connection_pool::check_in(
+                                &dispatcher.name,
+                                PooledConnection::new(
+                                    client,
+                                    address,
+                                    self.source_address.clone(),
+                                    self.tls_info.clone(),
+                                    established,
+                                    dispatcher.delivered_this_connection,
+                                    leases,
+                                ),
+                                max_idle,
+                                max_connection_age,
+                                connection_limit,
+                            );
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+
             client.send_command(&rfc5321::Command::Quit).await.ok();
             // Close out this dispatcher and let the maintainer spawn
             // a new connection
@@ -591,7 +1434,7 @@ impl QueueDispatcher for SmtpDispatcher {
     }
 
     async fn have_more_connection_candidates(&mut self, _dispatcher: &mut Dispatcher) -> bool {
-        !self.addresses.is_empty()
+        !self.targets.is_empty()
     }
 
     async fn deliver_message(
@@ -603,8 +1446,9 @@ impl QueueDispatcher for SmtpDispatcher {
         msg.load_data_if_needed().await.context("loading data")?;
 
         let data = msg.get_data();
-        let sender: ReversePath = msg
-            .sender()?
+        let sender = msg.sender()?;
+        let sender: ReversePath = crate::bounce_alias::to_external(&sender)
+            .unwrap_or(sender)
             .try_into()
             .map_err(|err| anyhow::anyhow!("{err}"))?;
         let recipient: ForwardPath = msg
@@ -640,130 +1484,169 @@ impl QueueDispatcher for SmtpDispatcher {
         self.tracer
             .submit(|| SmtpClientTraceEventPayload::MessageObtained);
 
-        match self
-            .client
-            .as_mut()
-            .unwrap()
-            .send_mail(sender, recipient, &*data)
-            .await
-        {
-            Err(ClientError::Rejected(mut response)) => {
-                let queue_name = msg.get_queue_name()?;
-                let components = QueueNameComponents::parse(&queue_name);
-                let mut config = load_config().await.context("load_config")?;
-
-                let sig = CallbackSignature::<
-                    (String, &str, Option<&str>, Option<&str>, &str),
-                    Option<u16>,
-                >::new("smtp_client_rewrite_delivery_status");
-
-                let rewritten_code: anyhow::Result<Option<u16>> = config
-                    .async_call_callback(
-                        &sig,
-                        (
-                            response.to_single_line(),
-                            components.domain,
-                            components.tenant,
-                            components.campaign,
-                            components
-                                .routing_domain
-                                .as_deref()
-                                .unwrap_or(&components.domain),
-                        ),
-                    )
-                    .await;
+        let peer_supports = |extension: &str| {
+            self.client
+                .as_ref()
+                .map(|client| client.capabilities().contains_key(extension))
+                .unwrap_or(false)
+        };
 
-                match rewritten_code {
-                    Ok(Some(code)) if code != response.code => {
-                        response.content = format!(
-                            "{} (kumomta: status was rewritten from {} -> {code})",
-                            response.content, response.code
-                        );
-                        response.code = code;
-                    }
-                    Ok(_) => {}
-                    Err(err) => {
-                        tracing::error!("smtp_client_rewrite_delivery_status event failed: {err:#}. Preserving original DSN");
-                    }
-                }
+        let mut sender_parameters = vec![];
+        if peer_supports("DELIVERBY") {
+            // Propagate the remaining time of the DELIVERBY request
+            // <https://datatracker.ietf.org/doc/html/rfc2852#section-4.1>
+            if let Some((_deadline, by)) = msg.get_deliver_by(chrono::Utc::now())? {
+                sender_parameters.push(by.to_parameter());
+            }
+        }
 
-                if response.code >= 400 && response.code < 500 {
-                    // Transient failure
-                    tracing::debug!(
-                        "failed to send message to {} {:?}: {response:?}",
-                        dispatcher.name,
-                        self.client_address
-                    );
-                    if let Some(msg) = dispatcher.msg.take() {
-                        log_disposition(LogDisposition {
-                            kind: RecordType::TransientFailure,
-                            msg: msg.clone(),
-                            site: &dispatcher.name,
-                            peer_address: self.client_address.as_ref(),
-                            response,
-                            egress_pool: Some(&dispatcher.egress_pool),
-                            egress_source: Some(&dispatcher.egress_source.name),
-                            relay_disposition: None,
-                            delivery_protocol: Some(&dispatcher.delivery_protocol),
-                            tls_info: self.tls_info.as_ref(),
-                            source_address: self.source_address.clone(),
-                            provider: dispatcher.path_config.borrow().provider_name.as_deref(),
-                        })
-                        .await;
-                        spawn_local(
-                            "requeue message".to_string(),
-                            Dispatcher::requeue_message(msg, true, None),
-                        )?;
-                    }
-                    dispatcher.metrics.inc_transfail();
-                } else if response.code >= 200 && response.code < 300 {
-                    tracing::debug!("Delivered OK! {response:?}");
-                    if let Some(msg) = dispatcher.msg.take() {
-                        log_disposition(LogDisposition {
-                            kind: RecordType::Delivery,
-                            msg: msg.clone(),
-                            site: &dispatcher.name,
-                            peer_address: self.client_address.as_ref(),
-                            response,
-                            egress_pool: Some(&dispatcher.egress_pool),
-                            egress_source: Some(&dispatcher.egress_source.name),
-                            relay_disposition: None,
-                            delivery_protocol: Some(&dispatcher.delivery_protocol),
-                            tls_info: self.tls_info.as_ref(),
-                            source_address: self.source_address.clone(),
-                            provider: dispatcher.path_config.borrow().provider_name.as_deref(),
-                        })
-                        .await;
-                        SpoolManager::remove_from_spool(*msg.id()).await?;
-                    }
-                    dispatcher.metrics.inc_delivered();
-                } else {
-                    dispatcher.metrics.inc_fail();
-                    tracing::debug!(
-                        "failed to send message to {} {:?}: {response:?}",
-                        dispatcher.name,
-                        self.client_address
+        // The DELIVERBY parameter is specific to this message,
+        // so it cannot share its transaction with others
+        let (batch, _shaping_leases) = if sender_parameters.is_empty() {
+            self.take_batch(&msg, dispatcher).await?
+        } else {
+            (vec![], vec![])
+        };
+
+        // The copies in a batch only differ in their trace headers
+        let batch_data_buf;
+        let data: &[u8] = if batch.is_empty() {
+            &data
+        } else {
+            batch_data_buf = batch_data(&data, &msg.recipient()?.to_string());
+            &batch_data_buf
+        };
+
+        // Messages only share a transaction when they have identical
+        // content and DSN parameters, so these parameters apply to
+        // the batch as a whole
+        if peer_supports("SIZE") {
+            // <https://datatracker.ietf.org/doc/html/rfc1870#section-6>
+            sender_parameters.push(EsmtpParameter {
+                name: "SIZE".to_string(),
+                value: Some(data.len().to_string()),
+            });
+        }
+        // <https://datatracker.ietf.org/doc/html/rfc3461#section-6.2>
+        let relay_dsn = peer_supports("DSN");
+        if relay_dsn {
+            sender_parameters.extend(msg.get_dsn_mail_parameters()?.to_parameters());
+        }
+        // The batch has already been taken from the ready queue, so a
+        // bad parameter is dropped rather than failing the transaction
+        let rcpt_parameters = |msg: &Message| -> Vec<EsmtpParameter> {
+            if !relay_dsn {
+                return vec![];
+            }
+            match msg.get_dsn_rcpt_parameters() {
+                Ok(dsn) => dsn.to_parameters(),
+                Err(err) => {
+                    tracing::error!(
+                        "{}: not relaying invalid DSN recipient parameters: {err:#}",
+                        msg.id()
                     );
-                    if let Some(msg) = dispatcher.msg.take() {
-                        log_disposition(LogDisposition {
-                            kind: RecordType::Bounce,
-                            msg: msg.clone(),
-                            site: &dispatcher.name,
-                            peer_address: self.client_address.as_ref(),
-                            response,
-                            egress_pool: Some(&dispatcher.egress_pool),
-                            egress_source: Some(&dispatcher.egress_source.name),
-                            relay_disposition: None,
-                            delivery_protocol: Some(&dispatcher.delivery_protocol),
-                            tls_info: self.tls_info.as_ref(),
-                            source_address: self.source_address.clone(),
-                            provider: dispatcher.path_config.borrow().provider_name.as_deref(),
-                        })
-                        .await;
-                        SpoolManager::remove_from_spool(*msg.id()).await?;
+                    vec![]
+                }
+            }
+        };
+
+        let client = self.client.as_mut().unwrap();
+        let had_chunking = client.capabilities().contains_key("CHUNKING");
+
+        let needs_smtputf8 = !sender.to_string().is_ascii() || !recipient.to_string().is_ascii();
+        let result = if needs_smtputf8 && !client.capabilities().contains_key("SMTPUTF8") {
+            // We cannot downgrade an internationalized address,
+            // so the message cannot be delivered to this peer.
+            // <https://datatracker.ietf.org/doc/html/rfc6531#section-3.2>
+            Err(ClientError::Rejected(Response {
+                code: 553,
+                enhanced_code: Some(EnhancedStatusCode {
+                    class: 5,
+                    subject: 6,
+                    detail: 7,
+                }),
+                content: "KumoMTA internal: address requires SMTPUTF8 \
+                          but the peer does not support it"
+                    .to_string(),
+                command: None,
+            }))
+        } else {
+            if needs_smtputf8 {
+                sender_parameters.push(EsmtpParameter {
+                    name: "SMTPUTF8".to_string(),
+                    value: None,
+                });
+            }
+            let mut recipients = vec![(recipient, rcpt_parameters(&msg))];
+            for (batch_msg, recipient) in &batch {
+                recipients.push((recipient.clone(), rcpt_parameters(batch_msg)));
+            }
+            client
+                .send_mail_multi_recip(sender, sender_parameters, recipients, data)
+                .await
+        };
+
+        // The client disables CHUNKING when BDAT is rejected; remember
+        // that so that future connections don't have to rediscover it
+        if had_chunking && !client.capabilities().contains_key("CHUNKING") {
+            if let Some(address) = &self.client_address {
+                crate::ehlo_cache::record_bdat_rejected(&address.name, address.addr);
+            }
+        }
+
+        let mut messages = vec![msg];
+        messages.extend(batch.into_iter().map(|(msg, _)| msg));
+
+        match result {
+            Ok(success) => {
+                tracing::debug!("Delivered OK! {:?}", success.response);
+                dispatcher.msg.take();
+                // Each recipient may have its own outcome, either because
+                // its RCPT TO was rejected, or because the peer is an
+                // LMTP server that responded to the data for each of them
+                for (idx, msg) in messages.into_iter().enumerate() {
+                    let response = success.recipient_response(idx).clone();
+                    if response.code == 250 {
+                        self.log_delivered(msg, response, dispatcher).await?;
+                    } else {
+                        self.handle_rejection(msg, response, dispatcher).await?;
                     }
                 }
             }
+            Err(ClientError::RejectedBatch(responses)) => {
+                dispatcher.msg.take();
+                for (msg, response) in messages.into_iter().zip(responses) {
+                    self.handle_rejection(msg, response, dispatcher).await?;
+                }
+            }
+            Err(ClientError::Rejected(response)) => {
+                dispatcher.msg.take();
+                for msg in messages {
+                    self.handle_rejection(msg, response.clone(), dispatcher)
+                        .await?;
+                }
+            }
+            Err(ClientError::BdatRejected(response)) => {
+                // The peer doesn't really support CHUNKING, and the
+                // client has closed the connection. Reconnect to the
+                // same host and retry using DATA. The dispatcher still
+                // holds the first message, but the rest of the batch
+                // must be returned to the ready queue
+                self.tracer.diagnostic(Level::INFO, || {
+                    format!(
+                        "BDAT was rejected with {}, reconnecting to use DATA instead",
+                        response.to_single_line()
+                    )
+                });
+                self.bdat_rejected = true;
+                self.client.take();
+                if let Some(target) = self.client_target.take() {
+                    self.targets.push(target);
+                }
+                for msg in messages.into_iter().skip(1) {
+                    dispatcher.return_to_ready(msg)?;
+                }
+            }
             Err(ClientError::TimeOutRequest { command, duration }) => {
                 // Transient failure
                 let reason = format!(
@@ -772,37 +1655,21 @@ impl QueueDispatcher for SmtpDispatcher {
                     dispatcher.name, self.client_address
                 );
                 tracing::debug!("{reason}");
-                if let Some(msg) = dispatcher.msg.take() {
-                    log_disposition(LogDisposition {
-                        kind: RecordType::TransientFailure,
-                        msg: msg.clone(),
-                        site: &dispatcher.name,
-                        peer_address: self.client_address.as_ref(),
-                        response: Response {
-                            code: 421,
-                            enhanced_code: Some(EnhancedStatusCode {
-                                class: 4,
-                                subject: 4,
-                                detail: 2,
-                            }),
-                            content: reason.clone(),
-                            command: Some(command.encode()),
-                        },
-                        egress_pool: Some(&dispatcher.egress_pool),
-                        egress_source: Some(&dispatcher.egress_source.name),
-                        relay_disposition: None,
-                        delivery_protocol: Some(&dispatcher.delivery_protocol),
-                        tls_info: self.tls_info.as_ref(),
-                        source_address: self.source_address.clone(),
-                        provider: dispatcher.path_config.borrow().provider_name.as_deref(),
-                    })
-                    .await;
-                    spawn_local(
-                        "requeue message".to_string(),
-                        Dispatcher::requeue_message(msg, true, None),
-                    )?;
+                let response = Response {
+                    code: 421,
+                    enhanced_code: Some(EnhancedStatusCode {
+                        class: 4,
+                        subject: 4,
+                        detail: 2,
+                    }),
+                    content: reason.clone(),
+                    command: Some(command.encode()),
+                };
+                dispatcher.msg.take();
+                for msg in messages {
+                    self.log_transient_failure(msg, response.clone(), dispatcher)
+                        .await?;
                 }
-                dispatcher.metrics.inc_transfail();
                 // Move on to the next host
                 anyhow::bail!("{reason}");
             }
@@ -813,75 +1680,152 @@ impl QueueDispatcher for SmtpDispatcher {
                     Timed Out waiting {duration:?} for response to {command:?}",
                     dispatcher.name, self.client_address
                 );
-
                 tracing::debug!("{reason}");
-                if let Some(msg) = dispatcher.msg.take() {
-                    log_disposition(LogDisposition {
-                        kind: RecordType::TransientFailure,
-                        msg: msg.clone(),
-                        site: &dispatcher.name,
-                        peer_address: self.client_address.as_ref(),
-                        response: Response {
-                            code: 421,
-                            enhanced_code: Some(EnhancedStatusCode {
-                                class: 4,
-                                subject: 4,
-                                detail: 2,
-                            }),
-                            content: reason.clone(),
-                            command: command.map(|c| c.encode()),
-                        },
-                        egress_pool: Some(&dispatcher.egress_pool),
-                        egress_source: Some(&dispatcher.egress_source.name),
-                        relay_disposition: None,
-                        delivery_protocol: Some(&dispatcher.delivery_protocol),
-                        tls_info: self.tls_info.as_ref(),
-                        source_address: self.source_address.clone(),
-                        provider: dispatcher.path_config.borrow().provider_name.as_deref(),
-                    })
-                    .await;
-                    spawn_local(
-                        "requeue message".to_string(),
-                        Dispatcher::requeue_message(msg, true, None),
-                    )?;
+                let response = Response {
+                    code: 421,
+                    enhanced_code: Some(EnhancedStatusCode {
+                        class: 4,
+                        subject: 4,
+                        detail: 2,
+                    }),
+                    content: reason.clone(),
+                    command: command.map(|c| c.encode()),
+                };
+                dispatcher.msg.take();
+                for msg in messages {
+                    self.log_transient_failure(msg, response.clone(), dispatcher)
+                        .await?;
                 }
-                dispatcher.metrics.inc_transfail();
                 // Move on to the next host
                 anyhow::bail!("{reason}");
             }
             Err(err) => {
-                // Transient failure; continue with another host
+                // Transient failure; continue with another host.
+                // The dispatcher still holds the first message and will
+                // retry it, but the rest of the batch must be returned
+                // to the ready queue
                 tracing::debug!(
                     "failed to send message to {} {:?}: {err:#}",
                     dispatcher.name,
                     self.client_address
                 );
-                return Err(err.into());
-            }
-            Ok(response) => {
-                tracing::debug!("Delivered OK! {response:?}");
-                if let Some(msg) = dispatcher.msg.take() {
-                    log_disposition(LogDisposition {
-                        kind: RecordType::Delivery,
-                        msg: msg.clone(),
-                        site: &dispatcher.name,
-                        peer_address: self.client_address.as_ref(),
-                        response,
-                        egress_pool: Some(&dispatcher.egress_pool),
-                        egress_source: Some(&dispatcher.egress_source.name),
-                        relay_disposition: None,
-                        delivery_protocol: Some(&dispatcher.delivery_protocol),
-                        tls_info: self.tls_info.as_ref(),
-                        source_address: self.source_address.clone(),
-                        provider: dispatcher.path_config.borrow().provider_name.as_deref(),
-                    })
-                    .await;
-                    SpoolManager::remove_from_spool(*msg.id()).await?;
+                for msg in messages.into_iter().skip(1) {
+                    dispatcher.return_to_ready(msg)?;
                 }
-                dispatcher.metrics.inc_delivered();
+                return Err(err.into());
             }
         };
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spool::SpoolId;
+
+    fn copy_for(recipient: &str, data: &str) -> Message {
+        Message::new_dirty(
+            SpoolId::new(),
+            EnvelopeAddress::parse("sender@example.com").unwrap(),
+            EnvelopeAddress::parse(recipient).unwrap(),
+            serde_json::json!({}),
+            Arc::new(data.as_bytes().to_vec().into_boxed_slice()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn batch_forms_from_copies() {
+        let content = "Subject: hello\r\n\r\nHello\r\n";
+        // As produced for each recipient by the smtp server, with a
+        // supplemental header that was renamed by the listener
+        let copy = |recipient: &str, id: &str| {
+            let supplemental = data_encoding::BASE64.encode(
+                serde_json::json!({"_@_": "\\_/", "recipient": recipient})
+                    .to_string()
+                    .as_bytes(),
+            );
+            copy_for(
+                recipient,
+                &format!(
+                    "X-Trace: {supplemental}\r\n\
+                     Received: from client (10.0.0.1)\r\n  \
+                     by mx (KumoMTA 10.0.0.2) \r\n  \
+                     with ESMTP id {id} for <{recipient}>;\r\n  \
+                     Thu, 1 Jan 2026 00:00:00 +0000\r\n\
+                     {content}"
+                ),
+            )
+        };
+        let first = copy("a@example.com", "one");
+        let second = copy("b@example.com", "two");
+
+        let first_data = first.get_data();
+        assert_eq!(shared_content(&first_data), content.as_bytes());
+
+        let sender = first.sender().unwrap();
+        let dsn = first.get_dsn_mail_parameters().unwrap();
+        let recipient = batch_recipient(&second, &sender, shared_content(&first_data), &dsn);
+        assert_eq!(recipient.unwrap().to_string(), "b@example.com");
+
+        // The trace headers of the first copy must not reveal its
+        // recipient to the rest of the batch
+        let data = String::from_utf8(batch_data(&first_data, "a@example.com")).unwrap();
+        assert_eq!(
+            data,
+            format!(
+                "Received: from client (10.0.0.1)\r\n  \
+                 by mx (KumoMTA 10.0.0.2) \r\n  \
+                 with ESMTP id one;\r\n  \
+                 Thu, 1 Jan 2026 00:00:00 +0000\r\n\
+                 {content}"
+            )
+        );
+
+        // Different content cannot share the transaction
+        let other = copy_for("c@example.com", "Subject: other\r\n\r\nHello\r\n");
+        assert!(batch_recipient(&other, &sender, shared_content(&first_data), &dsn).is_none());
+    }
+
+    #[test]
+    fn supplemental_trace_fields() {
+        assert!(!is_supplemental_trace_field(b"X-Trace: not base64\r\n"));
+        let unmarked = data_encoding::BASE64.encode(b"{\"recipient\":\"a@example.com\"}");
+        assert!(!is_supplemental_trace_field(
+            format!("X-Trace: {unmarked}\r\n").as_bytes()
+        ));
+        let marked = data_encoding::BASE64.encode(b"{\"_@_\":\"\\\\_/\"}");
+        assert!(is_supplemental_trace_field(
+            format!("X-Trace: {marked}\r\n").as_bytes()
+        ));
+    }
+
+    #[test]
+    fn relay_hosts() {
+        let proto: SmtpProtocol = serde_json::from_value(serde_json::json!({
+            "relay_hosts": [
+                {"host": "relay1.example.com"},
+                {"host": "[10.0.0.1]", "port": 2525, "enable_tls": "Required"},
+                {"host": "/run/dovecot/lmtp"},
+            ],
+            "relay_strategy": "RoundRobin",
+        }))
+        .unwrap();
+        assert_eq!(proto.relay_strategy, RelayStrategy::RoundRobin);
+        assert_eq!(
+            proto.explicit_site_name().unwrap(),
+            "relay_hosts:relay1.example.com,[10.0.0.1]:2525,/run/dovecot/lmtp"
+        );
+        assert!(proto.relay_hosts[0].unix_socket().is_none());
+        assert!(proto.relay_hosts[2].unix_socket().is_some());
+
+        assert!(SmtpProtocol::default().explicit_site_name().is_none());
+
+        assert_eq!(relay_host_order(vec![1, 2, 3], 0), vec![1, 2, 3]);
+        assert_eq!(relay_host_order(vec![1, 2, 3], 1), vec![2, 3, 1]);
+        assert_eq!(relay_host_order(vec![1, 2, 3], 5), vec![3, 1, 2]);
+        assert!(relay_host_order(Vec::<u8>::new(), 1).is_empty());
+    }
+}
@@ -1,10 +1,13 @@
+use crate::helo_validation::{HeloValidation, HeloValidationLevel};
 use crate::http_server::admin_trace_smtp_server_v1::{
     SmtpServerTraceEvent, SmtpServerTraceEventPayload, SmtpServerTraceManager,
 };
 use crate::logging::disposition::{log_disposition, LogDisposition, RecordType};
 use crate::logging::rejection::{log_rejection, LogRejection};
+use crate::message_tracing::{save_to_spool, StageSpan};
 use crate::queue::QueueManager;
 use crate::spool::SpoolManager;
+use crate::suppression::SuppressionAction;
 use anyhow::{anyhow, Context};
 use chrono::Utc;
 use cidr_map::CidrSet;
@@ -13,6 +16,9 @@ use data_encoding::BASE64;
 use data_loader::KeySource;
 use kumo_log_types::ResolvedAddress;
 use kumo_prometheus::AtomicCounter;
+use kumo_server_common::tls_helpers::{
+    default_tls_reload_interval, make_server_config, TlsCertificateParams, TlsServerParams,
+};
 use kumo_server_lifecycle::{Activity, ShutdownSubcription};
 use kumo_server_runtime::Runtime;
 use lruttl::LruCacheWithTtl;
@@ -23,22 +29,27 @@ use mlua::prelude::LuaUserData;
 use mlua::{FromLuaMulti, IntoLuaMulti, LuaSerdeExt, UserData, UserDataMethods};
 use once_cell::sync::{Lazy, OnceCell};
 use parking_lot::FairMutex as Mutex;
-use prometheus::{Histogram, HistogramTimer};
-use rfc5321::{AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, Response};
+use prometheus::{Histogram, HistogramTimer, HistogramVec, IntCounter, IntCounterVec};
+use rfc5321::{
+    size_from_parameters, AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, DeliverBy, Domain,
+    DsnMailParameters, DsnRcptParameters, Response,
+};
 use rustls::ServerConfig;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use spool::SpoolId;
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::TlsAcceptor;
 use tracing::{error, instrument, Level};
+use utoipa::ToSchema;
 
 static CRLF: Lazy<Finder> = Lazy::new(|| Finder::new("\r\n"));
 static TXN_LATENCY: Lazy<Histogram> = Lazy::new(|| {
@@ -62,6 +73,79 @@ static PROCESS_DATA_LATENCY: Lazy<Histogram> = Lazy::new(|| {
     )
     .unwrap()
 });
+static TOTAL_COMMANDS: Lazy<IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec!(
+        "smtpsrv_total_commands",
+        "total number of SMTP commands received, by verb",
+        &["service", "command"]
+    )
+    .unwrap()
+});
+static COMMAND_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    prometheus::register_histogram_vec!(
+        "smtpsrv_command_duration",
+        "how long it takes to process an SMTP command and respond to it",
+        &["service", "command"]
+    )
+    .unwrap()
+});
+static COMMAND_POLICY_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    prometheus::register_histogram_vec!(
+        "smtpsrv_command_policy_duration",
+        "how much of the time taken to process an SMTP command was spent in policy callbacks",
+        &["service", "command"]
+    )
+    .unwrap()
+});
+static COMMAND_IO_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    prometheus::register_histogram_vec!(
+        "smtpsrv_command_io_duration",
+        "how much of the time taken to process an SMTP command was spent outside of \
+         policy callbacks, such as reading data from and writing responses to the client",
+        &["service", "command"]
+    )
+    .unwrap()
+});
+
+/// The command metric handles of a listener, keyed by the local address
+/// of the connection and the command verb, so that the labelled metrics
+/// are resolved once rather than for every command
+#[derive(Default)]
+struct CommandMetrics {
+    handles: Mutex<HashMap<(SocketAddr, &'static str), Arc<CommandMetricHandles>>>,
+}
+
+impl Debug for CommandMetrics {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("CommandMetrics").finish()
+    }
+}
+
+struct CommandMetricHandles {
+    total: IntCounter,
+    latency: Histogram,
+    policy_latency: Histogram,
+    io_latency: Histogram,
+}
+
+impl CommandMetrics {
+    fn get(&self, my_address: SocketAddr, command: &'static str) -> Arc<CommandMetricHandles> {
+        self.handles
+            .lock()
+            .entry((my_address, command))
+            .or_insert_with(|| {
+                let service = format!("esmtp_listener:{my_address}");
+                let labels = [service.as_str(), command];
+                Arc::new(CommandMetricHandles {
+                    total: TOTAL_COMMANDS.with_label_values(&labels),
+                    latency: COMMAND_LATENCY.with_label_values(&labels),
+                    policy_latency: COMMAND_POLICY_LATENCY.with_label_values(&labels),
+                    io_latency: COMMAND_IO_LATENCY.with_label_values(&labels),
+                })
+            })
+            .clone()
+    }
+}
 
 #[derive(Debug, Hash, PartialEq, Eq)]
 struct DomainAndListener {
@@ -103,7 +187,7 @@ fn default_ttl() -> Duration {
     Duration::from_secs(60)
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct TraceHeaders {
     /// Whether to add a Received: header
@@ -150,7 +234,110 @@ fn default_true() -> bool {
     true
 }
 
-#[derive(Deserialize, Clone, Debug)]
+/// A class of peers that is allocated its own pool of connection
+/// slots, so that connections from other peers cannot exhaust them.
+#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionClass {
+    /// Used to identify the class in diagnostics
+    pub name: String,
+    /// The peers that belong to this class
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    pub hosts: CidrSet,
+    /// Peers that successfully authenticate as one of these
+    /// identities are moved into this class
+    #[serde(default)]
+    pub authn_ids: Vec<String>,
+    /// The maximum number of concurrent connections for this class
+    pub max_connections: usize,
+}
+
+/// Hands out connection slots, either from the listener-wide limit,
+/// or from the limit of the connection class of the peer
+struct ConnectionLimiter {
+    general: Arc<tokio::sync::Semaphore>,
+    classes: Vec<(ConnectionClass, Arc<tokio::sync::Semaphore>)>,
+}
+
+impl ConnectionLimiter {
+    fn new(max_connections: usize, classes: &[ConnectionClass]) -> Arc<Self> {
+        Arc::new(Self {
+            general: Arc::new(tokio::sync::Semaphore::new(max_connections)),
+            classes: classes
+                .iter()
+                .map(|class| {
+                    (
+                        class.clone(),
+                        Arc::new(tokio::sync::Semaphore::new(class.max_connections)),
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    /// Returns the index of the first class that includes the address
+    fn class_for_address(&self, ip: IpAddr) -> Option<usize> {
+        self.classes
+            .iter()
+            .position(|(class, _)| class.hosts.contains(ip))
+    }
+
+    /// Returns the index of the first class that includes the
+    /// authentication identity
+    fn class_for_authn_id(&self, authn_id: &str) -> Option<usize> {
+        self.classes
+            .iter()
+            .position(|(class, _)| class.authn_ids.iter().any(|id| id == authn_id))
+    }
+
+    /// Takes a slot from the specified class, or from the listener-wide
+    /// limit if `class` is `None`.  Returns `None` if there are no free slots.
+    fn try_acquire(self: &Arc<Self>, class: Option<usize>) -> Option<ConnectionPermit> {
+        let semaphore = match class {
+            Some(idx) => &self.classes[idx].1,
+            None => &self.general,
+        };
+        let permit = semaphore.clone().try_acquire_owned().ok()?;
+        Some(ConnectionPermit {
+            limiter: Arc::clone(self),
+            class,
+            _permit: permit,
+        })
+    }
+}
+
+/// A connection slot, which is returned to its limiter when dropped
+struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    class: Option<usize>,
+    _permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+impl ConnectionPermit {
+    fn class_name(&self) -> Option<&str> {
+        self.class
+            .map(|idx| self.limiter.classes[idx].0.name.as_str())
+    }
+
+    /// Moves the connection into the class for the identity that the
+    /// peer authenticated as, releasing its current slot.  If that class
+    /// has no free slots, the connection keeps its current slot.
+    fn reclassify_for_authn_id(&mut self, authn_id: &str) {
+        let Some(class) = self.limiter.class_for_authn_id(authn_id) else {
+            return;
+        };
+        if self.class == Some(class) {
+            return;
+        }
+        if let Some(permit) = self.limiter.try_acquire(Some(class)) {
+            *self = permit;
+        }
+    }
+}
+
+/// The parameters accepted by `kumo.start_esmtp_listener`
+#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub struct EsmtpListenerParams {
     #[serde(default = "EsmtpListenerParams::default_listen")]
@@ -158,14 +345,31 @@ pub struct EsmtpListenerParams {
     #[serde(default = "EsmtpListenerParams::default_hostname")]
     pub hostname: String,
     #[serde(default = "CidrSet::default_trusted_hosts")]
+    #[schema(value_type = Vec<String>)]
     pub relay_hosts: CidrSet,
+    /// Connections from these hosts must begin with a PROXY
+    /// protocol header describing the original client address
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    pub trusted_proxies: CidrSet,
     #[serde(default = "EsmtpListenerParams::default_banner")]
     pub banner: String,
 
     #[serde(default)]
+    #[schema(value_type = Option<Object>)]
     pub tls_certificate: Option<KeySource>,
     #[serde(default)]
+    #[schema(value_type = Option<Object>)]
     pub tls_private_key: Option<KeySource>,
+    /// Certificates to present to clients that request a
+    /// particular host name via SNI
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub tls_sni_certificates: HashMap<String, TlsCertificateParams>,
+    /// How often to check the certificate sources for changes
+    #[serde(default = "default_tls_reload_interval", with = "duration_serde")]
+    #[schema(value_type = String)]
+    pub tls_reload_interval: Duration,
 
     #[serde(default)]
     pub deferred_spool: bool,
@@ -177,8 +381,29 @@ pub struct EsmtpListenerParams {
         default = "EsmtpListenerParams::default_client_timeout",
         with = "duration_serde"
     )]
+    #[schema(value_type = String)]
     pub client_timeout: Duration,
 
+    /// How strictly to validate the domain given in HELO/EHLO
+    #[serde(default)]
+    pub helo_validation: HeloValidationLevel,
+
+    /// If false, the outcome of helo_validation is only recorded
+    /// in the connection meta for use by policy
+    #[serde(default = "default_true")]
+    pub reject_invalid_helo: bool,
+
+    /// Whether to parse received messages in order to set the
+    /// `has_attachments` meta value
+    #[serde(default)]
+    pub detect_attachments: bool,
+
+    /// Whether to advertise the RFC 3461 DSN extension.  kumod doesn't
+    /// generate delivery status notifications itself, so this is only
+    /// appropriate when policy takes care of that.
+    #[serde(default)]
+    pub advertise_dsn: bool,
+
     #[serde(skip)]
     tls_config: OnceCell<Arc<ServerConfig>>,
 
@@ -188,6 +413,9 @@ pub struct EsmtpListenerParams {
     #[serde(skip)]
     connection_denied_counter: OnceCell<AtomicCounter>,
 
+    #[serde(skip)]
+    command_metrics: OnceCell<Arc<CommandMetrics>>,
+
     #[serde(default = "EsmtpListenerParams::default_max_messages_per_connection")]
     max_messages_per_connection: usize,
     #[serde(default = "EsmtpListenerParams::default_max_recipients_per_message")]
@@ -199,10 +427,14 @@ pub struct EsmtpListenerParams {
     #[serde(default = "EsmtpListenerParams::default_max_connections")]
     max_connections: usize,
 
+    #[serde(default)]
+    connection_classes: Vec<ConnectionClass>,
+
     #[serde(default = "EsmtpListenerParams::default_data_buffer_size")]
     data_buffer_size: usize,
 
     #[serde(default)]
+    #[schema(value_type = String)]
     invalid_line_endings: ConformanceDisposition,
 
     #[serde(default = "EsmtpListenerParams::default_line_length_hard_limit")]
@@ -258,11 +490,13 @@ impl EsmtpListenerParams {
             return Ok(TlsAcceptor::from(config.clone()));
         }
 
-        let config = kumo_server_common::tls_helpers::make_server_config(
-            &self.hostname,
-            &self.tls_private_key,
-            &self.tls_certificate,
-        )
+        let config = make_server_config(TlsServerParams {
+            hostname: &self.hostname,
+            tls_private_key: &self.tls_private_key,
+            tls_certificate: &self.tls_certificate,
+            tls_sni_certificates: &self.tls_sni_certificates,
+            tls_reload_interval: self.tls_reload_interval,
+        })
         .await?;
 
         // If we race to create, take the winner's version
@@ -281,12 +515,41 @@ impl EsmtpListenerParams {
             .get_or_init(|| crate::metrics_helper::connection_denied_for_service("esmtp_listener"))
     }
 
+    fn command_metrics(&self) -> &Arc<CommandMetrics> {
+        self.command_metrics.get_or_init(Default::default)
+    }
+
+    /// If `peer_address` is a trusted proxy, read the PROXY protocol
+    /// header from `socket` and return the original client address
+    /// that it conveys. Otherwise, returns `peer_address`.
+    async fn resolve_proxied_peer(
+        &self,
+        socket: &mut TcpStream,
+        peer_address: SocketAddr,
+    ) -> anyhow::Result<SocketAddr> {
+        if !self.trusted_proxies.contains(peer_address.ip()) {
+            return Ok(peer_address);
+        }
+        let client_address = tokio::time::timeout(
+            self.client_timeout,
+            kumo_server_common::proxy_protocol::read_header(socket),
+        )
+        .await
+        .with_context(|| format!("timed out reading PROXY header from {peer_address}"))?
+        .with_context(|| format!("reading PROXY header from {peer_address}"))?;
+        Ok(client_address.unwrap_or(peer_address))
+    }
+
     pub async fn run(self) -> anyhow::Result<()> {
         // Pre-create the acceptor so that we can share it across
         // the various listeners
         self.build_tls_acceptor().await?;
         self.connection_gauge();
+        // Initialize this before the params are cloned for each
+        // connection, so that the connections share the handles
+        self.command_metrics();
         let denied = self.connection_denied_counter();
+        let filtered = crate::metrics_helper::connection_filtered_for_service("esmtp_listener");
 
         let listener = TcpListener::bind(&self.listen)
             .await
@@ -295,7 +558,8 @@ impl EsmtpListenerParams {
         let addr = listener.local_addr()?;
         tracing::info!("smtp listener on {addr:?}");
         let mut shutting_down = ShutdownSubcription::get();
-        let connection_limiter = Arc::new(tokio::sync::Semaphore::new(self.max_connections));
+        let connection_limiter =
+            ConnectionLimiter::new(self.max_connections, &self.connection_classes);
 
         loop {
             tokio::select! {
@@ -305,7 +569,18 @@ impl EsmtpListenerParams {
                 }
                 result = listener.accept() => {
                     let (mut socket, peer_address) = result?;
-                    let Ok(permit) = connection_limiter.clone().try_acquire_owned() else {
+                    if crate::connection_filter::is_denied(peer_address.ip()) {
+                        // Drop it without a response; the point of the
+                        // filter is to spend as little as possible on
+                        // these peers
+                        filtered.inc();
+                        drop(socket);
+                        continue;
+                    }
+                    // Peers that match a connection class draw from the limit
+                    // of the first matching class, rather than the general limit
+                    let class = connection_limiter.class_for_address(peer_address.ip());
+                    let Some(permit) = connection_limiter.try_acquire(class) else {
                         // We're over the limit. We make a "best effort" to respond;
                         // don't strain too hard here, as the purpose of the limit is
                         // to constrain resource utilization, so no sense going too
@@ -341,15 +616,33 @@ impl EsmtpListenerParams {
                     socket.set_nodelay(true)?;
                     let my_address = socket.local_addr()?;
                     let params = self.clone();
+                    let filtered = filtered.clone();
                     SMTPSRV.spawn(
                         format!("SmtpServer {peer_address:?}"),
                         move || Ok(async move {
+                            let peer_address = match params
+                                .resolve_proxied_peer(&mut socket, peer_address)
+                                .await
+                            {
+                                Ok(addr) => addr,
+                                Err(err) => {
+                                    tracing::error!("{err:#}");
+                                    drop(permit);
+                                    return;
+                                }
+                            };
+                            // The client behind a trusted proxy is only
+                            // known once the PROXY header has been read
+                            if crate::connection_filter::is_denied(peer_address.ip()) {
+                                filtered.inc();
+                                drop(permit);
+                                return;
+                            }
                             if let Err(err) =
-                                SmtpServer::run(socket, my_address, peer_address, params).await
+                                SmtpServer::run(socket, my_address, peer_address, params, permit).await
                                 {
                                     tracing::error!("SmtpServer::run: {err:#}");
                             }
-                            drop(permit);
                     })).await?;
                 }
             };
@@ -441,12 +734,54 @@ pub struct SmtpServer {
     meta: ConnectionMetaData,
     global_reception_count: AtomicCounter,
     reception_count: AtomicCounter,
+    command_timing: Option<CommandTiming>,
+    connection_permit: ConnectionPermit,
+}
+
+/// Tracks the time taken to process the current command, so that
+/// the time spent in policy can be reported separately from the rest
+struct CommandTiming {
+    command: &'static str,
+    start: Instant,
+    policy: Duration,
+}
+
+/// Returns the verb used to label the command metrics
+fn command_verb(command: &Result<Command, String>) -> &'static str {
+    match command {
+        Ok(Command::Ehlo(_)) => "EHLO",
+        Ok(Command::Helo(_)) => "HELO",
+        Ok(Command::Lhlo(_)) => "LHLO",
+        Ok(Command::MailFrom { .. }) => "MAIL",
+        Ok(Command::RcptTo { .. }) => "RCPT",
+        Ok(Command::Data | Command::DataDot) => "DATA",
+        Ok(Command::Bdat { .. }) => "BDAT",
+        Ok(Command::Rset) => "RSET",
+        Ok(Command::Quit) => "QUIT",
+        Ok(Command::Vrfy(_)) => "VRFY",
+        Ok(Command::Expn(_)) => "EXPN",
+        Ok(Command::Help(_)) => "HELP",
+        Ok(Command::Noop(_)) => "NOOP",
+        Ok(Command::StartTls) => "STARTTLS",
+        Ok(Command::Auth { .. }) => "AUTH",
+        Err(_) => "invalid",
+    }
 }
 
 #[derive(Debug)]
 struct TransactionState {
     sender: EnvelopeAddress,
-    recipients: Vec<EnvelopeAddress>,
+    /// The recipients, along with their RFC 3461 DSN parameters
+    recipients: Vec<(EnvelopeAddress, DsnRcptParameters)>,
+    deliver_by: Option<DeliverBy>,
+    dsn: DsnMailParameters,
+    /// The SMTPUTF8 parameter was specified in MAIL FROM
+    smtputf8: bool,
+    /// The PRDR parameter was specified in MAIL FROM, so the
+    /// response to the message data is given per-recipient
+    prdr: bool,
+    /// The accumulated data from a sequence of BDAT commands
+    bdat_data: Option<Vec<u8>>,
     _timer: HistogramTimer,
 }
 
@@ -466,12 +801,13 @@ impl RelayDisposition {
 }
 
 impl SmtpServer {
-    #[instrument(skip(params, my_address, peer_address))]
-    pub async fn run<T>(
+    #[instrument(skip(params, my_address, peer_address, connection_permit))]
+    async fn run<T>(
         socket: T,
         my_address: SocketAddr,
         peer_address: SocketAddr,
         params: EsmtpListenerParams,
+        connection_permit: ConnectionPermit,
     ) -> anyhow::Result<()>
     where
         T: AsyncReadAndWrite + Debug + Send + 'static,
@@ -483,6 +819,9 @@ impl SmtpServer {
         meta.set_meta("received_via", my_address.to_string());
         meta.set_meta("received_from", peer_address.to_string());
         meta.set_meta("hostname", params.hostname.to_string());
+        if let Some(class) = connection_permit.class_name() {
+            meta.set_meta("connection_class", class);
+        }
 
         let service = format!("esmtp_listener:{my_address}");
 
@@ -504,6 +843,8 @@ impl SmtpServer {
             global_reception_count: crate::metrics_helper::total_msgs_received_for_service(
                 "esmtp_listener",
             ),
+            command_timing: None,
+            connection_permit,
         };
 
         server.params.connection_gauge().inc();
@@ -527,6 +868,7 @@ impl SmtpServer {
                     .ok();
             }
         }
+        server.finish_command();
         server.params.connection_gauge().dec();
 
         SmtpServerTraceManager::submit(|| SmtpServerTraceEvent {
@@ -538,6 +880,40 @@ impl SmtpServer {
         Ok(())
     }
 
+    fn start_command(&mut self, command: &'static str) {
+        self.finish_command();
+        self.command_timing.replace(CommandTiming {
+            command,
+            start: Instant::now(),
+            policy: Duration::ZERO,
+        });
+    }
+
+    fn finish_command(&mut self) {
+        let Some(timing) = self.command_timing.take() else {
+            return;
+        };
+        let handles = self
+            .params
+            .command_metrics()
+            .get(self.my_address, timing.command);
+        let elapsed = timing.start.elapsed();
+
+        handles.total.inc();
+        handles.latency.observe(elapsed.as_secs_f64());
+        handles.policy_latency.observe(timing.policy.as_secs_f64());
+        handles
+            .io_latency
+            .observe(elapsed.saturating_sub(timing.policy).as_secs_f64());
+    }
+
+    /// Attribute time spent in a policy callback to the current command
+    fn record_policy_time(&mut self, elapsed: Duration) {
+        if let Some(timing) = &mut self.command_timing {
+            timing.policy += elapsed;
+        }
+    }
+
     fn peer_in_cidr_list(&self, cidr: &CidrSet) -> bool {
         cidr.contains(self.peer_address.ip())
     }
@@ -545,17 +921,21 @@ impl SmtpServer {
     async fn lookup_listener_domain(
         &mut self,
         domain_name: &str,
-    ) -> anyhow::Result<Option<EsmtpDomain>> {
+    ) -> anyhow::Result<Result<Option<EsmtpDomain>, RejectError>> {
         let key = DomainAndListener {
             domain: domain_name.to_string(),
             listener: self.my_address.to_string(),
         };
 
         if let Some(opt_dom) = DOMAINS.lock().get(&key) {
-            return Ok(opt_dom);
+            return Ok(Ok(opt_dom));
         }
 
-        let mut config = load_config().await?;
+        let policy_start = Instant::now();
+        let mut config = match load_config().await {
+            Ok(config) => config,
+            Err(err) => return Ok(Err(self.limit_rejection(err)?)),
+        };
 
         let sig =
             CallbackSignature::<(String, String, ConnectionMetaData), Option<EsmtpDomain>>::new(
@@ -567,6 +947,7 @@ impl SmtpServer {
                 (key.domain.clone(), key.listener.clone(), self.meta.clone()),
             )
             .await;
+        self.record_policy_time(policy_start.elapsed());
 
         let value = match value {
             Ok(v) => {
@@ -591,7 +972,7 @@ impl SmtpServer {
                     },
                     when: Utc::now(),
                 });
-                return Err(err);
+                return Ok(Err(self.limit_rejection(err)?));
             }
         };
 
@@ -601,21 +982,50 @@ impl SmtpServer {
             Instant::now() + value.as_ref().map(|v| v.ttl).unwrap_or_else(default_ttl),
         );
 
-        Ok(value)
+        Ok(Ok(value))
+    }
+
+    /// Validates the HELO/EHLO domain according to the helo_validation
+    /// listener option, recording the outcome in the connection meta.
+    /// Returns the response with which to reject the command if it
+    /// should be rejected.
+    /// Clients in relay_hosts are not validated, as internal injectors
+    /// commonly identify themselves with unqualified names.
+    async fn validate_helo(&mut self, domain: &Domain) -> Option<(u16, String)> {
+        if self.params.helo_validation == HeloValidationLevel::None
+            || self.peer_in_cidr_list(&self.params.relay_hosts)
+        {
+            return None;
+        }
+
+        let result =
+            HeloValidation::evaluate(self.params.helo_validation, domain, self.peer_address.ip())
+                .await;
+        if let Ok(value) = serde_json::to_value(&result) {
+            self.meta.set_meta("helo_validation", value);
+        }
+
+        if !result.valid && self.params.reject_invalid_helo {
+            Some(result.rejection())
+        } else {
+            None
+        }
     }
 
     async fn check_relaying(
         &mut self,
         sender: &EnvelopeAddress,
         recipient: &EnvelopeAddress,
-    ) -> anyhow::Result<RelayDisposition> {
+    ) -> anyhow::Result<Result<RelayDisposition, RejectError>> {
         let relay_hosts_allowed = self.peer_in_cidr_list(&self.params.relay_hosts);
 
         let sender_domain = sender.domain();
         let mut relay_from_allowed = false;
 
-        if let Some(dom) = self.lookup_listener_domain(&sender_domain).await? {
-            relay_from_allowed = self.peer_in_cidr_list(&dom.relay_from);
+        match self.lookup_listener_domain(&sender_domain).await? {
+            Ok(Some(dom)) => relay_from_allowed = self.peer_in_cidr_list(&dom.relay_from),
+            Ok(None) => {}
+            Err(rej) => return Ok(Err(rej)),
         }
 
         let recipient_domain = recipient.domain();
@@ -623,10 +1033,14 @@ impl SmtpServer {
         let mut log_arf = false;
         let mut log_oob = false;
 
-        if let Some(dom) = self.lookup_listener_domain(&recipient_domain).await? {
-            relay_to_allowed.replace(dom.relay_to);
-            log_arf = dom.log_arf;
-            log_oob = dom.log_oob;
+        match self.lookup_listener_domain(&recipient_domain).await? {
+            Ok(Some(dom)) => {
+                relay_to_allowed.replace(dom.relay_to);
+                log_arf = dom.log_arf;
+                log_oob = dom.log_oob;
+            }
+            Ok(None) => {}
+            Err(rej) => return Ok(Err(rej)),
         }
 
         // Check the rules for relaying-from first; that allows
@@ -648,11 +1062,11 @@ impl SmtpServer {
              -> log_arf={log_arf} log_oob={log_oob} relay={relay}"
         );
 
-        Ok(RelayDisposition {
+        Ok(Ok(RelayDisposition {
             relay,
             log_arf,
             log_oob,
-        })
+        }))
     }
 
     #[instrument(skip(self))]
@@ -679,7 +1093,7 @@ impl SmtpServer {
                 let mut recipient = None;
                 if let Some(state) = &self.state {
                     sender.replace(state.sender.to_string());
-                    recipient = state.recipients.last().map(|r| r.to_string());
+                    recipient = state.recipients.last().map(|(r, _)| r.to_string());
                 }
 
                 log_rejection(LogRejection {
@@ -836,6 +1250,77 @@ impl SmtpServer {
         }
     }
 
+    /// Read exactly `size` octets of BDAT chunk data.
+    /// When `discard` is true the data is consumed from the
+    /// connection but not retained.
+    #[instrument(skip(self))]
+    async fn read_chunk(&mut self, size: usize, discard: bool) -> anyhow::Result<ReadData> {
+        tracing::trace!("reading chunk");
+
+        let mut chunk = vec![];
+        let mut remaining = size;
+        let mut data = DebugabbleReadBuffer(vec![0u8; self.params.data_buffer_size]);
+
+        loop {
+            let available = remaining.min(self.read_buffer.len());
+            if discard {
+                self.read_buffer.drain(0..available);
+            } else {
+                chunk.extend(self.read_buffer.drain(0..available));
+            }
+            remaining -= available;
+
+            if remaining == 0 {
+                tracing::trace!("returning ReadData::Data {:?}", DebugPrintBuffer(&chunk));
+                return Ok(ReadData::Data(chunk));
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(self.params.client_timeout) => {
+                    return Ok(ReadData::TimedOut);
+                }
+                size = self.socket.as_mut().unwrap().read(&mut data) => {
+                    match size {
+                        Err(err) => {
+                            tracing::trace!("error reading: {err:#}");
+                            SmtpServerTraceManager::submit(|| SmtpServerTraceEvent {
+                                conn_meta: self.meta.clone_inner(),
+                                payload: SmtpServerTraceEventPayload::Diagnostic {
+                                    level: Level::ERROR,
+                                    message: format!("error reading: {err:#}"),
+                                },
+                                when: Utc::now(),
+                            });
+                            return Ok(ReadData::Disconnected);
+                        }
+                        Ok(size) if size == 0 => {
+                            SmtpServerTraceManager::submit(|| SmtpServerTraceEvent {
+                                conn_meta: self.meta.clone_inner(),
+                                payload: SmtpServerTraceEventPayload::Diagnostic {
+                                    level: Level::ERROR,
+                                    message: "Peer Disconnected".to_string(),
+                                },
+                                when: Utc::now(),
+                            });
+                            return Ok(ReadData::Disconnected);
+                        }
+                        Ok(size) => {
+                            SmtpServerTraceManager::submit(|| SmtpServerTraceEvent {
+                                conn_meta: self.meta.clone_inner(),
+                                payload: SmtpServerTraceEventPayload::Read(data[0..size].to_vec()),
+                                when: Utc::now(),
+                            });
+                            self.read_buffer.extend_from_slice(&data[0..size]);
+                        }
+                    }
+                }
+                _ = self.shutdown.shutting_down() => {
+                    return Ok(ReadData::ShuttingDown);
+                }
+            };
+        }
+    }
+
     #[instrument(skip(self))]
     async fn read_line(&mut self, override_limit: Option<usize>) -> anyhow::Result<ReadLine> {
         if self.socket.is_none() {
@@ -932,9 +1417,15 @@ impl SmtpServer {
         args: A,
     ) -> anyhow::Result<Result<R, RejectError>> {
         let name = name.into();
-        let mut config = load_config().await?;
+        let policy_start = Instant::now();
+        let mut config = match load_config().await {
+            Ok(config) => config,
+            Err(err) => return Ok(Err(self.limit_rejection(err)?)),
+        };
         let sig = CallbackSignature::<A, R>::new(name.clone());
-        match config.async_call_callback(&sig, args).await {
+        let result = config.async_call_callback(&sig, args).await;
+        self.record_policy_time(policy_start.elapsed());
+        match result {
             Ok(r) => {
                 SmtpServerTraceManager::submit(|| SmtpServerTraceEvent {
                     conn_meta: self.meta.clone_inner(),
@@ -958,15 +1449,33 @@ impl SmtpServer {
                     },
                     when: Utc::now(),
                 });
-                if let Some(rej) = RejectError::from_anyhow(&err) {
-                    Ok(Err(rej))
-                } else {
-                    Err(err)
+                match self.limit_rejection(err) {
+                    Ok(rej) => Ok(Err(rej)),
+                    Err(err) => match RejectError::from_anyhow(&err) {
+                        Some(rej) => Ok(Err(rej)),
+                        None => Err(err),
+                    },
                 }
             }
         }
     }
 
+    /// If err was caused by an event handler being aborted for exceeding
+    /// its limits, returns the transient rejection to send to the client.
+    /// The handler has already been logged; letting the client try again
+    /// later is preferable to dropping the connection.
+    /// Any other error is returned as-is.
+    fn limit_rejection(&self, err: anyhow::Error) -> anyhow::Result<RejectError> {
+        if config::EventLimitExceeded::from_anyhow(&err).is_some() {
+            Ok(RejectError {
+                code: 451,
+                message: format!("4.3.0 {} policy error. Try later", self.params.hostname),
+            })
+        } else {
+            Err(err)
+        }
+    }
+
     #[instrument(skip(self))]
     async fn process(&mut self) -> anyhow::Result<()> {
         let _activity = match Activity::get_opt(format!(
@@ -1017,6 +1526,18 @@ impl SmtpServer {
             return Ok(());
         }
 
+        if crate::drain::is_draining() {
+            // We don't bump the connection_denied_counter here, because
+            // draining is an operator initiated condition.
+            self.write_response(
+                421,
+                format!("4.3.2 {} draining. Try later", self.params.hostname),
+                None,
+            )
+            .await?;
+            return Ok(());
+        }
+
         if !SpoolManager::get().spool_started() {
             // We don't bump the connection_denied_counter here, because
             // startup is a normal condition and doesn't require an operator
@@ -1043,6 +1564,8 @@ impl SmtpServer {
         )
         .await?;
         loop {
+            self.finish_command();
+
             if self.check_shutdown() {
                 self.write_response(
                     421,
@@ -1081,7 +1604,10 @@ impl SmtpServer {
                 }
             };
 
-            match Command::parse(&line) {
+            let command = Command::parse(&line);
+            self.start_command(command_verb(&command));
+
+            match command {
                 Err(err) => {
                     self.write_response(
                         501,
@@ -1267,6 +1793,10 @@ impl SmtpServer {
                                     self.authentication_id.replace(authc.to_string());
                                     self.meta.set_meta("authz_id", authz);
                                     self.meta.set_meta("authn_id", authc);
+                                    self.connection_permit.reclassify_for_authn_id(authc);
+                                    if let Some(class) = self.connection_permit.class_name() {
+                                        self.meta.set_meta("connection_class", class);
+                                    }
 
                                     self.write_response(235, "2.7.0 AUTH OK!", None).await?;
                                 }
@@ -1284,6 +1814,10 @@ impl SmtpServer {
                     }
                 }
                 Ok(Command::Ehlo(domain)) => {
+                    if let Some((code, message)) = self.validate_helo(&domain).await {
+                        self.write_response(code, message, Some(line)).await?;
+                        continue;
+                    }
                     let domain = domain.to_string();
 
                     if let Err(rej) = self
@@ -1298,7 +1832,26 @@ impl SmtpServer {
                         continue;
                     }
 
-                    let mut extensions = vec!["PIPELINING", "ENHANCEDSTATUSCODES"];
+                    let limits = format!(
+                        "LIMITS MAILMAX={} RCPTMAX={}",
+                        self.params.max_messages_per_connection,
+                        self.params.max_recipients_per_message
+                    );
+                    let size = format!("SIZE {}", self.params.max_message_size);
+                    let mut extensions = vec![
+                        "PIPELINING",
+                        "ENHANCEDSTATUSCODES",
+                        "DELIVERBY",
+                        &size,
+                        "8BITMIME",
+                        "CHUNKING",
+                        "SMTPUTF8",
+                        "PRDR",
+                        &limits,
+                    ];
+                    if self.params.advertise_dsn {
+                        extensions.push("DSN");
+                    }
                     if !self.tls_active {
                         extensions.push("STARTTLS");
                     } else {
@@ -1315,9 +1868,14 @@ impl SmtpServer {
                         None,
                     )
                     .await?;
+                    self.meta.set_meta("ehlo_domain", domain.clone());
                     self.said_hello.replace(domain);
                 }
                 Ok(Command::Helo(domain)) => {
+                    if let Some((code, message)) = self.validate_helo(&domain).await {
+                        self.write_response(code, message, Some(line)).await?;
+                        continue;
+                    }
                     let domain = domain.to_string();
 
                     if let Err(rej) = self
@@ -1333,11 +1891,12 @@ impl SmtpServer {
                     }
                     self.write_response(250, format!("Hello {domain}!"), None)
                         .await?;
+                    self.meta.set_meta("ehlo_domain", domain.clone());
                     self.said_hello.replace(domain);
                 }
                 Ok(Command::MailFrom {
                     address,
-                    parameters: _,
+                    parameters,
                 }) => {
                     if self.state.is_some() {
                         self.write_response(
@@ -1348,8 +1907,76 @@ impl SmtpServer {
                         .await?;
                         continue;
                     }
+                    if crate::drain::is_draining() {
+                        // Don't start new transactions on sessions that
+                        // were established before the drain started
+                        self.write_response(
+                            421,
+                            format!("4.3.2 {} draining. Try later", self.params.hostname),
+                            Some(line),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+
+                    let deliver_by = match DeliverBy::from_parameters(&parameters) {
+                        Ok(by) => by,
+                        Err(err) => {
+                            self.write_response(501, format!("5.5.4 {err}"), Some(line))
+                                .await?;
+                            continue;
+                        }
+                    };
+                    let dsn = match DsnMailParameters::from_parameters(&parameters) {
+                        Ok(dsn) => dsn,
+                        Err(err) => {
+                            self.write_response(501, format!("5.5.4 {err}"), Some(line))
+                                .await?;
+                            continue;
+                        }
+                    };
+
+                    // <https://datatracker.ietf.org/doc/html/rfc1870#section-6.1>
+                    match size_from_parameters(&parameters) {
+                        Ok(Some(size)) if size > self.params.max_message_size => {
+                            self.write_response(
+                                552,
+                                format!(
+                                    "5.3.4 message size {size} exceeds fixed maximum \
+                                     message size {}",
+                                    self.params.max_message_size
+                                ),
+                                Some(line),
+                            )
+                            .await?;
+                            continue;
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            self.write_response(501, format!("5.5.4 {err}"), Some(line))
+                                .await?;
+                            continue;
+                        }
+                    }
 
-                    let address = EnvelopeAddress::parse(&address.to_string())?;
+                    let smtputf8 = parameters
+                        .iter()
+                        .any(|p| p.name.eq_ignore_ascii_case("SMTPUTF8"));
+                    let prdr = parameters
+                        .iter()
+                        .any(|p| p.name.eq_ignore_ascii_case("PRDR"));
+                    let address = address.to_string();
+                    if !smtputf8 && !address.is_ascii() {
+                        self.write_response(
+                            553,
+                            "5.6.7 SMTPUTF8 is required for non-ASCII addresses",
+                            Some(line),
+                        )
+                        .await?;
+                        continue;
+                    }
+
+                    let address = EnvelopeAddress::parse(&address)?;
                     if let Err(rej) = self
                         .call_callback::<(), _, _>(
                             "smtp_server_mail_from",
@@ -1365,6 +1992,11 @@ impl SmtpServer {
                     self.state.replace(TransactionState {
                         sender: address.clone(),
                         recipients: vec![],
+                        deliver_by,
+                        dsn,
+                        smtputf8,
+                        prdr,
+                        bdat_data: None,
                         _timer: TXN_LATENCY.start_timer(),
                     });
                     self.write_response(250, format!("OK {address:?}"), None)
@@ -1372,7 +2004,7 @@ impl SmtpServer {
                 }
                 Ok(Command::RcptTo {
                     address,
-                    parameters: _,
+                    parameters,
                 }) => {
                     if self.state.is_none() {
                         self.write_response(
@@ -1383,10 +2015,39 @@ impl SmtpServer {
                         .await?;
                         continue;
                     }
-                    let address = EnvelopeAddress::parse(&address.to_string())?;
+                    let address = address.to_string();
+                    let smtputf8 = self.state.as_ref().map(|s| s.smtputf8).unwrap_or(false);
+                    if !smtputf8 && !address.is_ascii() {
+                        self.write_response(
+                            553,
+                            "5.6.7 SMTPUTF8 is required for non-ASCII addresses",
+                            Some(line),
+                        )
+                        .await?;
+                        continue;
+                    }
+                    let rcpt_dsn = match DsnRcptParameters::from_parameters(&parameters) {
+                        Ok(dsn) => dsn,
+                        Err(err) => {
+                            self.write_response(501, format!("5.5.4 {err}"), Some(line))
+                                .await?;
+                            continue;
+                        }
+                    };
+                    let address = EnvelopeAddress::parse(&address)?;
+                    // Mail addressed to an external bounce domain, such as
+                    // an OOB DSN, is processed as the internal return path
+                    let address = crate::bounce_alias::to_internal(&address).unwrap_or(address);
 
                     let sender = self.state.as_ref().unwrap().sender.clone();
-                    let relay_disposition = self.check_relaying(&sender, &address).await?;
+                    let relay_disposition = match self.check_relaying(&sender, &address).await? {
+                        Ok(disposition) => disposition,
+                        Err(rej) => {
+                            self.write_response(rej.code, rej.message, Some(line))
+                                .await?;
+                            continue;
+                        }
+                    };
 
                     if !relay_disposition.accept_rcpt_to() {
                         self.write_response(
@@ -1398,6 +2059,34 @@ impl SmtpServer {
                         continue;
                     }
 
+                    if relay_disposition.relay {
+                        match crate::suppression::check_recipient(&address.to_string()).await {
+                            Ok(Some(SuppressionAction::Reject)) => {
+                                self.write_response(
+                                    550,
+                                    format!("5.1.1 recipient {address} is on the suppression list"),
+                                    Some(line),
+                                )
+                                .await?;
+                                continue;
+                            }
+                            Ok(_) => {}
+                            Err(err) => {
+                                tracing::error!(
+                                    "failed to check suppression list for {address}: {err:#}"
+                                );
+                                self.write_response(
+                                    451,
+                                    "4.3.0 unable to check the suppression list, \
+                                     try again later",
+                                    Some(line),
+                                )
+                                .await?;
+                                continue;
+                            }
+                        }
+                    }
+
                     if let Some(state) = &self.state {
                         if state.recipients.len() == self.params.max_recipients_per_message {
                             self.write_response(451, "4.5.3 too many recipients", Some(line))
@@ -1454,7 +2143,7 @@ impl SmtpServer {
                         .as_mut()
                         .expect("checked state above")
                         .recipients
-                        .push(address);
+                        .push((address, rcpt_dsn));
                 }
                 Ok(Command::Data) => {
                     if self.state.is_none() {
@@ -1476,6 +2165,20 @@ impl SmtpServer {
                             .await?;
                         continue;
                     }
+                    if self
+                        .state
+                        .as_ref()
+                        .map(|s| s.bdat_data.is_some())
+                        .unwrap_or(false)
+                    {
+                        self.write_response(
+                            503,
+                            "5.5.1 DATA cannot be used after BDAT",
+                            Some(line),
+                        )
+                        .await?;
+                        continue;
+                    }
 
                     self.write_response(354, "Send body; end with CRLF.CRLF", None)
                         .await?;
@@ -1518,6 +2221,109 @@ impl SmtpServer {
                     let _process_data_timer = PROCESS_DATA_LATENCY.start_timer();
                     self.process_data(data).await?;
                 }
+                Ok(Command::Bdat { chunk_size, last }) => {
+                    // The chunk data always follows the command, so we must
+                    // consume it even if we are going to reject the command
+                    // <https://datatracker.ietf.org/doc/html/rfc3030#section-2>
+                    let no_recipients = self
+                        .state
+                        .as_ref()
+                        .map(|s| s.recipients.is_empty())
+                        .unwrap_or(true);
+                    let received = self
+                        .state
+                        .as_ref()
+                        .and_then(|s| s.bdat_data.as_ref())
+                        .map(|data| data.len())
+                        .unwrap_or(0);
+                    let too_big =
+                        received.saturating_add(chunk_size) > self.params.max_message_size;
+
+                    let read_data_timer = READ_DATA_LATENCY.start_timer();
+                    let chunk = match self
+                        .read_chunk(chunk_size, no_recipients || too_big)
+                        .await?
+                    {
+                        ReadData::Disconnected => return Ok(()),
+                        ReadData::Data(chunk) => chunk,
+                        ReadData::TooBig | ReadData::TooLong => {
+                            unreachable!("read_chunk does not check limits")
+                        }
+                        ReadData::TimedOut => {
+                            self.write_response(
+                                421,
+                                format!("4.3.2 {} idle too long", self.params.hostname),
+                                Some(line),
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                        ReadData::ShuttingDown => {
+                            self.write_response(
+                                421,
+                                format!("4.3.2 {} shutting down", self.params.hostname),
+                                Some(line),
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                    };
+                    read_data_timer.stop_and_record();
+
+                    if self.state.is_none() {
+                        self.write_response(
+                            503,
+                            "5.5.0 MAIL FROM must be issued first",
+                            Some(line),
+                        )
+                        .await?;
+                        continue;
+                    }
+                    if no_recipients {
+                        self.write_response(503, "5.5.0 RCPT TO must be issued first", Some(line))
+                            .await?;
+                        continue;
+                    }
+                    if too_big {
+                        self.state.take();
+                        self.write_response(552, "5.3.4 message too big", Some(line))
+                            .await?;
+                        continue;
+                    }
+
+                    let data = {
+                        let state = self.state.as_mut().expect("checked state above");
+                        state
+                            .bdat_data
+                            .get_or_insert_with(Vec::new)
+                            .extend_from_slice(&chunk);
+                        if last {
+                            state.bdat_data.take()
+                        } else {
+                            None
+                        }
+                    };
+
+                    let Some(data) = data else {
+                        self.write_response(
+                            250,
+                            format!("2.0.0 {chunk_size} octets received"),
+                            None,
+                        )
+                        .await?;
+                        continue;
+                    };
+
+                    if !check_line_lengths(&data, self.params.line_length_hard_limit) {
+                        self.state.take();
+                        self.write_response(500, "5.2.3 line too long", Some(line))
+                            .await?;
+                        continue;
+                    }
+
+                    let _process_data_timer = PROCESS_DATA_LATENCY.start_timer();
+                    self.process_data(data).await?;
+                }
                 Ok(Command::Rset) => {
                     self.state.take();
                     self.write_response(250, "Reset state", None).await?;
@@ -1526,7 +2332,7 @@ impl SmtpServer {
                     self.write_response(250, "the goggles do nothing", None)
                         .await?;
                 }
-                Ok(Command::Vrfy(_) | Command::Expn(_) | Command::Help(_)) => {
+                Ok(Command::Vrfy(_) | Command::Expn(_) | Command::Help(_) | Command::Lhlo(_)) => {
                     self.write_response(502, format!("5.5.1 Command unimplemented"), Some(line))
                         .await?;
                 }
@@ -1583,19 +2389,40 @@ impl SmtpServer {
         }
 
         let mut ids = vec![];
+        // One per message, covering its reception through to its
+        // insertion into its scheduled queue
+        let mut reception_spans = vec![];
 
         // If anything decides to reject at this phase, it needs to apply to
         // the entire batch, so we make a first pass to accumulate the messages
         // here. If anything rejects, we return before we've committed to doing
         // any real work
         let mut accepted_messages = vec![];
+        // When PRDR is in use, rejections apply only to the individual
+        // recipient. This holds the rejection, if any, for each
+        // recipient in the order that they were specified.
+        let mut prdr_rejections = vec![];
 
-        let datestamp = Utc::now().to_rfc2822();
+        let now = Utc::now();
+        let datestamp = now.to_rfc2822();
 
-        for recip in state.recipients {
+        for (recip, rcpt_dsn) in state.recipients {
             let id = SpoolId::new();
-            let protocol = "ESMTP"; // FIXME: update SmtpServer ctor if we change this.
-                                    // OR: just read this from self.meta?
+            // FIXME: update SmtpServer ctor if we change this.
+            // OR: just read this from self.meta?
+            let protocol = if state.smtputf8 {
+                // <https://datatracker.ietf.org/doc/html/rfc6531#section-4.3>
+                "UTF8SMTP"
+            } else {
+                "ESMTP"
+            };
+
+            let mut span = StageSpan::start(&id, "reception");
+            if span.is_recording() {
+                span.set_attribute("protocol", protocol);
+                span.set_attribute("peer_address", self.peer_address.ip().to_string());
+            }
+            reception_spans.push(span);
 
             let mut body = if self.params.trace_headers.received_header {
                 let received = {
@@ -1628,6 +2455,28 @@ impl SmtpServer {
                 self.meta.clone_inner(),
                 Arc::new(body.into_boxed_slice()),
             )?;
+            message.set_content_meta(self.params.detect_attachments)?;
+
+            if let Some(by) = &state.deliver_by {
+                // Record the deadline as an absolute time; the by-time in
+                // the request is relative to the time of reception.
+                // <https://datatracker.ietf.org/doc/html/rfc2852#section-4>
+                let deadline = now + chrono::Duration::seconds(by.by_time);
+                message.set_meta("deliver_by", deadline.to_rfc3339())?;
+                message.set_meta("deliver_by_mode", by.mode_string())?;
+            }
+            if let Some(ret) = state.dsn.ret {
+                message.set_meta("dsn_ret", ret.as_str())?;
+            }
+            if let Some(envid) = &state.dsn.envid {
+                message.set_meta("dsn_envid", envid.as_str())?;
+            }
+            if let Some(notify) = rcpt_dsn.notify {
+                message.set_meta("dsn_notify", notify.to_string())?;
+            }
+            if let Some(orcpt) = &rcpt_dsn.orcpt {
+                message.set_meta("dsn_orcpt", orcpt.as_str())?;
+            }
 
             if let Err(rej) = self
                 .call_callback::<(), _, _>(
@@ -1636,6 +2485,10 @@ impl SmtpServer {
                 )
                 .await?
             {
+                if state.prdr {
+                    prdr_rejections.push(Some(rej));
+                    continue;
+                }
                 // Rejecting any one message from a batch in
                 // smtp_server_message_received will reject the
                 // entire batch
@@ -1643,7 +2496,59 @@ impl SmtpServer {
                     .await?;
                 return Ok(());
             }
-            accepted_messages.push(message);
+            // The reservation is given back if the message is not
+            // taken on after all
+            let reservation = match crate::campaign::check_message(&message).await {
+                Ok(reservation) => reservation,
+                Err(err) => {
+                    let Some(rej) = RejectError::from_anyhow(&err) else {
+                        return Err(err);
+                    };
+                    if state.prdr {
+                        prdr_rejections.push(Some(rej));
+                        continue;
+                    }
+                    self.write_response(rej.code, rej.message, Some("DATA".into()))
+                        .await?;
+                    return Ok(());
+                }
+            };
+            // Suppressed recipients were already rejected at RCPT TO
+            // unless the action is to silently discard their messages.
+            // The recipient was accepted, so a failure to check the
+            // list doesn't prevent delivery.
+            let recipient = message.recipient()?.to_string();
+            match crate::suppression::check_recipient(&recipient).await {
+                Ok(Some(SuppressionAction::Drop)) => {
+                    message.set_meta("queue", "null")?;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::error!(
+                        "failed to check suppression list for {recipient}, \
+                         accepting the message: {err:#}"
+                    );
+                }
+            }
+            // This is checked before any message is taken on, so that
+            // a policy failure can still be reported for each recipient
+            let relay_disposition = match self
+                .check_relaying(&message.sender()?, &message.recipient()?)
+                .await?
+            {
+                Ok(disposition) => disposition,
+                Err(rej) => {
+                    if state.prdr {
+                        prdr_rejections.push(Some(rej));
+                        continue;
+                    }
+                    self.write_response(rej.code, rej.message, Some("DATA".into()))
+                        .await?;
+                    return Ok(());
+                }
+            };
+            prdr_rejections.push(None);
+            accepted_messages.push((message, relay_disposition, reservation));
         }
 
         // At this point we've nominally accepted the batch; let's
@@ -1652,8 +2557,11 @@ impl SmtpServer {
         let mut messages = vec![];
         let mut was_arf_or_oob = false;
         let mut black_holed = false;
+        // Whether each of the accepted_messages was actually taken
+        // on, in the same order, for producing PRDR responses
+        let mut prdr_accepted = vec![];
 
-        for message in accepted_messages {
+        for (message, relay_disposition, reservation) in accepted_messages {
             if self.params.trace_headers.supplemental_header {
                 let mut object = json!({
                     // Marker to identify encoded supplemental header
@@ -1678,10 +2586,6 @@ impl SmtpServer {
 
             let queue_name = message.get_queue_name()?;
 
-            let relay_disposition = self
-                .check_relaying(&message.sender()?, &message.recipient()?)
-                .await?;
-
             SmtpServerTraceManager::submit(|| SmtpServerTraceEvent {
                 conn_meta: self.meta.clone_inner(),
                 payload: SmtpServerTraceEventPayload::MessageDisposition {
@@ -1704,16 +2608,25 @@ impl SmtpServer {
             });
 
             if queue_name != "null" {
-                if relay_disposition.relay && !self.params.deferred_spool {
-                    message.save().await?;
+                if relay_disposition.relay
+                    && !self.params.deferred_spool
+                    && !QueueManager::is_deferred_spool(&queue_name).await?
+                {
+                    save_to_spool(&message).await?;
+                }
+                if relay_disposition.relay {
+                    reservation.commit();
                 }
             }
 
-            if relay_disposition.log_arf && matches!(message.parse_rfc5965(), Ok(Some(_))) {
-                was_arf_or_oob = true;
-            } else if relay_disposition.log_oob && matches!(message.parse_rfc3464(), Ok(Some(_))) {
-                was_arf_or_oob = true;
-            }
+            let is_arf_or_oob = (relay_disposition.log_arf
+                && matches!(message.parse_rfc5965(), Ok(Some(_))))
+                || (relay_disposition.log_oob && matches!(message.parse_rfc3464(), Ok(Some(_))));
+            was_arf_or_oob |= is_arf_or_oob;
+            prdr_accepted.push((
+                message.id().to_string(),
+                queue_name == "null" || relay_disposition.relay || is_arf_or_oob,
+            ));
 
             log_disposition(LogDisposition {
                 kind: RecordType::Reception,
@@ -1752,8 +2665,17 @@ impl SmtpServer {
         for (queue_name, msg) in messages {
             QueueManager::insert(&queue_name, msg).await?;
         }
+        drop(reception_spans);
+
+        let accepted = black_holed || relayed_any || was_arf_or_oob;
+
+        if state.prdr {
+            return self
+                .write_prdr_responses(prdr_rejections, prdr_accepted)
+                .await;
+        }
 
-        if !black_holed && !relayed_any && !was_arf_or_oob {
+        if !accepted {
             self.write_response(550, "5.7.1 relaying not permitted", Some("DATA".into()))
                 .await?;
         } else {
@@ -1764,10 +2686,77 @@ impl SmtpServer {
 
         Ok(())
     }
+
+    /// Produce the PRDR response to the message data: an intermediate
+    /// 353 response, followed by a response for each recipient in the
+    /// order that they were specified, followed by the response for
+    /// the message as a whole.
+    /// <https://datatracker.ietf.org/doc/html/draft-hall-prdr-00>
+    async fn write_prdr_responses(
+        &mut self,
+        rejections: Vec<Option<RejectError>>,
+        accepted: Vec<(String, bool)>,
+    ) -> anyhow::Result<()> {
+        self.write_response(353, "PRDR content analysis beginning", None)
+            .await?;
+
+        for (code, message) in prdr_responses(rejections, accepted) {
+            let command = if code == 250 {
+                None
+            } else {
+                Some("DATA".into())
+            };
+            self.write_response(code, message, command).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Computes the per-recipient PRDR responses, followed by the response
+/// for the message as a whole.
+/// `rejections` holds the rejection, if any, for each recipient in the
+/// order that they were specified, and `accepted` holds the id of each
+/// message that was not rejected, along with whether it was actually
+/// taken on, in the same order.
+/// A recipient is only told that its message was accepted if it was
+/// actually taken on; the others get the same response that they would
+/// have received without PRDR.
+fn prdr_responses(
+    rejections: Vec<Option<RejectError>>,
+    accepted: Vec<(String, bool)>,
+) -> Vec<(u16, String)> {
+    let mut responses = vec![];
+    let mut accepted = accepted.into_iter();
+    let mut accepted_ids = vec![];
+
+    for rejection in rejections {
+        match rejection {
+            Some(rej) => responses.push((rej.code, rej.message)),
+            None => match accepted.next() {
+                Some((id, true)) => {
+                    responses.push((250, format!("OK id={id}")));
+                    accepted_ids.push(id);
+                }
+                Some((_, false)) | None => {
+                    responses.push((550, "5.7.1 relaying not permitted".to_string()));
+                }
+            },
+        }
+    }
+
+    if accepted_ids.is_empty() {
+        responses.push((550, "5.7.1 no recipients were accepted".to_string()));
+    } else {
+        let ids = accepted_ids.join(" ");
+        responses.push((250, format!("OK ids={ids}")));
+    }
+
+    responses
 }
 
 #[derive(Clone)]
-struct ConnectionMetaData {
+pub struct ConnectionMetaData {
     map: Arc<Mutex<serde_json::Value>>,
 }
 
@@ -1873,6 +2862,56 @@ fn check_line_lengths(data: &[u8], limit: usize) -> bool {
 mod test {
     use super::*;
 
+    #[test]
+    fn prdr_mixed_outcomes() {
+        let rej = |code, message: &str| {
+            Some(RejectError {
+                code,
+                message: message.to_string(),
+            })
+        };
+
+        let responses = prdr_responses(
+            vec![
+                None,
+                rej(451, "4.7.1 try later"),
+                None,
+                rej(550, "5.7.1 no thanks"),
+                None,
+            ],
+            vec![
+                ("a".to_string(), true),
+                ("b".to_string(), false),
+                ("c".to_string(), true),
+            ],
+        );
+        assert_eq!(
+            responses,
+            vec![
+                (250, "OK id=a".to_string()),
+                (451, "4.7.1 try later".to_string()),
+                (550, "5.7.1 relaying not permitted".to_string()),
+                (550, "5.7.1 no thanks".to_string()),
+                (250, "OK id=c".to_string()),
+                (250, "OK ids=a c".to_string()),
+            ]
+        );
+
+        // Nothing was relayed, so the message as a whole is refused
+        let responses = prdr_responses(
+            vec![rej(550, "5.7.1 no thanks"), None],
+            vec![("b".to_string(), false)],
+        );
+        assert_eq!(
+            responses,
+            vec![
+                (550, "5.7.1 no thanks".to_string()),
+                (550, "5.7.1 relaying not permitted".to_string()),
+                (550, "5.7.1 no recipients were accepted".to_string()),
+            ]
+        );
+    }
+
     #[test]
     fn unstuffer() {
         let stuffed = b"hello\r\n..dot\r\nthere\r\n..more dot".to_vec();
@@ -1900,4 +2939,105 @@ mod test {
             12
         ));
     }
+
+    fn connection_class(name: &str, hosts: Vec<&str>, authn_ids: &[&str]) -> ConnectionClass {
+        ConnectionClass {
+            name: name.to_string(),
+            hosts: hosts.try_into().unwrap(),
+            authn_ids: authn_ids.iter().map(|id| id.to_string()).collect(),
+            max_connections: 1,
+        }
+    }
+
+    #[test]
+    fn connection_class_selection() {
+        let limiter = ConnectionLimiter::new(
+            1,
+            &[
+                connection_class("injectors", vec!["10.0.1.0/24"], &[]),
+                connection_class("relays", vec!["10.0.0.0/16"], &["relay-user"]),
+                connection_class("partners", vec![], &["partner-user"]),
+            ],
+        );
+
+        // The first matching class wins
+        assert_eq!(
+            limiter.class_for_address("10.0.1.5".parse().unwrap()),
+            Some(0)
+        );
+        assert_eq!(
+            limiter.class_for_address("10.0.2.5".parse().unwrap()),
+            Some(1)
+        );
+        assert_eq!(
+            limiter.class_for_address("192.0.2.1".parse().unwrap()),
+            None
+        );
+
+        assert_eq!(limiter.class_for_authn_id("relay-user"), Some(1));
+        assert_eq!(limiter.class_for_authn_id("partner-user"), Some(2));
+        assert_eq!(limiter.class_for_authn_id("someone-else"), None);
+    }
+
+    #[test]
+    fn connection_class_limits() {
+        let limiter = ConnectionLimiter::new(
+            1,
+            &[connection_class("injectors", vec!["10.0.1.0/24"], &[])],
+        );
+
+        let general = limiter.try_acquire(None).unwrap();
+        assert_eq!(general.class_name(), None);
+        // The general limit is exhausted, but the class still has room
+        assert!(limiter.try_acquire(None).is_none());
+        let injector = limiter.try_acquire(Some(0)).unwrap();
+        assert_eq!(injector.class_name(), Some("injectors"));
+        assert!(limiter.try_acquire(Some(0)).is_none());
+
+        // Slots are returned when the permit is dropped
+        drop(general);
+        assert!(limiter.try_acquire(None).is_some());
+        drop(injector);
+        assert!(limiter.try_acquire(Some(0)).is_some());
+    }
+
+    #[test]
+    fn connection_class_reclassify_on_auth() {
+        let limiter = ConnectionLimiter::new(
+            1,
+            &[connection_class("partners", vec![], &["partner-user"])],
+        );
+
+        // Unknown identities leave the connection in the general class
+        let mut permit = limiter.try_acquire(None).unwrap();
+        permit.reclassify_for_authn_id("someone-else");
+        assert_eq!(permit.class_name(), None);
+        assert!(limiter.try_acquire(None).is_none());
+
+        // Authenticating moves the connection into the class, freeing
+        // up its slot in the general limit
+        permit.reclassify_for_authn_id("partner-user");
+        assert_eq!(permit.class_name(), Some("partners"));
+        let mut other = limiter.try_acquire(None).unwrap();
+
+        // When the class is full, the connection keeps its slot
+        other.reclassify_for_authn_id("partner-user");
+        assert_eq!(other.class_name(), None);
+        assert!(limiter.try_acquire(None).is_none());
+    }
+
+    #[test]
+    fn command_verbs() {
+        assert_eq!(
+            command_verb(&Command::parse("MAIL FROM:<user@example.com>")),
+            "MAIL"
+        );
+        assert_eq!(
+            command_verb(&Command::parse("RCPT TO:<user@example.com>")),
+            "RCPT"
+        );
+        assert_eq!(command_verb(&Command::parse("STARTTLS")), "STARTTLS");
+        assert_eq!(command_verb(&Command::parse("AUTH PLAIN")), "AUTH");
+        assert_eq!(command_verb(&Command::parse("BOGUS")), "invalid");
+    }
 }
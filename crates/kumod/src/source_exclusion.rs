@@ -0,0 +1,224 @@
+//! Allows policy, traffic shaping automation or an operator to take
+//! an egress source out of rotation for a particular destination for
+//! a while, for example after the destination responds with a
+//! blocklist message that names the source IP.
+//!
+//! While a source is excluded it is skipped when selecting a source
+//! from an egress pool for that destination, and the messages in its
+//! ready queue for that destination are returned to their scheduled
+//! queues so that they can be picked up by the remaining sources.
+//! The source is restored to rotation automatically once the
+//! exclusion expires.
+use chrono::{DateTime, Utc};
+use config::{from_lua_value, get_or_create_sub_module};
+use kumo_api_types::source_exclusion::{
+    SourceExclusionV1CancelRequest, SourceExclusionV1Entry, SourceExclusionV1ListRequest,
+    SourceExclusionV1Request,
+};
+use mlua::{Lua, LuaSerdeExt, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use std::collections::HashMap;
+
+static EXCLUSIONS: Lazy<Mutex<HashMap<ExclusionKey, Exclusion>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ExclusionKey {
+    source: String,
+    destination: String,
+}
+
+#[derive(Clone, Debug)]
+struct Exclusion {
+    reason: String,
+    created: DateTime<Utc>,
+    expires: DateTime<Utc>,
+}
+
+impl Exclusion {
+    fn entry(&self, key: &ExclusionKey) -> SourceExclusionV1Entry {
+        SourceExclusionV1Entry {
+            source: key.source.clone(),
+            destination: key.destination.clone(),
+            reason: self.reason.clone(),
+            created: self.created,
+            expires: self.expires,
+        }
+    }
+}
+
+/// Exclude a source from a destination, replacing any existing
+/// exclusion of that source from that destination
+pub fn exclude(request: SourceExclusionV1Request) -> SourceExclusionV1Entry {
+    let now = Utc::now();
+    let key = ExclusionKey {
+        source: request.source.clone(),
+        destination: request.destination.clone(),
+    };
+    let exclusion = Exclusion {
+        expires: request.expires(now),
+        reason: request.reason,
+        created: now,
+    };
+    tracing::info!(
+        "excluding source {} from {} until {}: {}",
+        key.source,
+        key.destination,
+        exclusion.expires.to_rfc3339(),
+        exclusion.reason
+    );
+    let entry = exclusion.entry(&key);
+    EXCLUSIONS.lock().insert(key, exclusion);
+    entry
+}
+
+/// Restore a source to rotation for a destination before its
+/// exclusion expires. Returns true if it was excluded.
+pub fn restore(request: SourceExclusionV1CancelRequest) -> bool {
+    let key = ExclusionKey {
+        source: request.source,
+        destination: request.destination,
+    };
+    let removed = EXCLUSIONS.lock().remove(&key).is_some();
+    if removed {
+        tracing::info!("restored source {} to {}", key.source, key.destination);
+    }
+    removed
+}
+
+/// If the source is currently excluded from the site, or from the
+/// provider, returns the time remaining until it is restored
+pub fn excluded_remaining(
+    source: &str,
+    site_name: &str,
+    provider: Option<&str>,
+) -> Option<chrono::Duration> {
+    let mut exclusions = EXCLUSIONS.lock();
+    if exclusions.is_empty() {
+        return None;
+    }
+
+    let now = Utc::now();
+    let mut remaining = None;
+    for destination in std::iter::once(site_name).chain(provider) {
+        let key = ExclusionKey {
+            source: source.to_string(),
+            destination: destination.to_string(),
+        };
+        let Some(exclusion) = exclusions.get(&key) else {
+            continue;
+        };
+        if exclusion.expires <= now {
+            tracing::info!("restored source {source} to {destination}: exclusion expired");
+            exclusions.remove(&key);
+            continue;
+        }
+        let this_remaining = exclusion.expires - now;
+        remaining.replace(remaining.unwrap_or(this_remaining).max(this_remaining));
+    }
+    remaining
+}
+
+pub fn list(request: SourceExclusionV1ListRequest) -> Vec<SourceExclusionV1Entry> {
+    let now = Utc::now();
+    let mut exclusions = EXCLUSIONS.lock();
+    exclusions.retain(|_, exclusion| exclusion.expires > now);
+    let mut entries: Vec<_> = exclusions
+        .iter()
+        .filter(|(key, _)| {
+            request
+                .source
+                .as_ref()
+                .map_or(true, |source| *source == key.source)
+                && request
+                    .destination
+                    .as_ref()
+                    .map_or(true, |destination| *destination == key.destination)
+        })
+        .map(|(key, exclusion)| exclusion.entry(key))
+        .collect();
+    entries.sort_by(|a, b| (&a.destination, &a.source).cmp(&(&b.destination, &b.source)));
+    entries
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "source_exclusion")?;
+
+    module.set(
+        "exclude",
+        lua.create_function(|lua, params: Value| {
+            let request: SourceExclusionV1Request = from_lua_value(lua, params)?;
+            lua.to_value(&exclude(request))
+        })?,
+    )?;
+
+    module.set(
+        "restore",
+        lua.create_function(|lua, params: Value| {
+            let request: SourceExclusionV1CancelRequest = from_lua_value(lua, params)?;
+            Ok(restore(request))
+        })?,
+    )?;
+
+    module.set(
+        "list",
+        lua.create_function(|lua, params: Option<Value>| {
+            let request: SourceExclusionV1ListRequest = match params {
+                Some(params) => from_lua_value(lua, params)?,
+                None => SourceExclusionV1ListRequest::default(),
+            };
+            lua.to_value(&list(request))
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exclude_and_restore() {
+        let request = SourceExclusionV1Request {
+            source: "ip-1".to_string(),
+            destination: "test-exclude-provider".to_string(),
+            reason: "blocklisted".to_string(),
+            duration: Some(std::time::Duration::from_secs(60)),
+            expires: None,
+        };
+        exclude(request.clone());
+
+        assert!(excluded_remaining("ip-1", "mx.example.com", None).is_none());
+        assert!(
+            excluded_remaining("ip-1", "mx.example.com", Some("test-exclude-provider")).is_some()
+        );
+        assert!(
+            excluded_remaining("ip-2", "mx.example.com", Some("test-exclude-provider")).is_none()
+        );
+
+        let listed = list(SourceExclusionV1ListRequest {
+            source: None,
+            destination: Some("test-exclude-provider".to_string()),
+        });
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].reason, "blocklisted");
+
+        assert!(restore(SourceExclusionV1CancelRequest {
+            source: "ip-1".to_string(),
+            destination: "test-exclude-provider".to_string(),
+        }));
+        assert!(
+            excluded_remaining("ip-1", "mx.example.com", Some("test-exclude-provider")).is_none()
+        );
+
+        // An exclusion that has already expired is not in effect
+        exclude(SourceExclusionV1Request {
+            destination: "test-exclude-site".to_string(),
+            expires: Some(Utc::now() - chrono::Duration::seconds(1)),
+            ..request
+        });
+        assert!(excluded_remaining("ip-1", "test-exclude-site", None).is_none());
+    }
+}
@@ -0,0 +1,652 @@
+//! Tracks the health of each egress source for each destination, based
+//! on the outcomes of the delivery attempts that pass through
+//! `log_disposition`. The destination is the provider, if the site
+//! belongs to one, or the site otherwise, so that a problem with one
+//! destination doesn't take the source out of service for the others.
+//!
+//! When the rate of connection failures or permanent failures for a
+//! source exceeds the configured thresholds, or the source is found to
+//! be on a blocklist, the source is marked unhealthy for that destination
+//! for a while. Unhealthy sources are skipped when selecting a source from
+//! an egress pool for that destination, and the messages in their ready
+//! queues for it are returned to their scheduled queues so that they can
+//! be picked up by the remaining sources.
+use crate::ready_queue::ReadyQueueManager;
+use bounce_classify::{BounceClass, PreDefinedBounceClass};
+use chrono::{DateTime, Utc};
+use config::{from_lua_value, get_or_create_sub_module, load_config, CallbackSignature};
+use kumo_log_types::RecordType;
+use kumo_server_runtime::spawn;
+use mlua::{IntoLua, Lua, LuaSerdeExt, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use rfc5321::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// The window is divided into this many buckets; older buckets
+/// are discarded as time passes.
+const NUM_BUCKETS: u64 = 20;
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+static CONFIG: Lazy<Mutex<SourceHealthConfig>> =
+    Lazy::new(|| Mutex::new(SourceHealthConfig::default()));
+static TRACKERS: Lazy<Mutex<HashMap<TrackerKey, Tracker>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub static SOURCE_HEALTH_CHANGED_SIG: Lazy<
+    CallbackSignature<(String, String, SourceHealthStatus), ()>,
+> = Lazy::new(|| CallbackSignature::new_with_multiple("egress_source_health_changed"));
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct TrackerKey {
+    source: String,
+    destination: String,
+}
+
+impl TrackerKey {
+    fn new(source: &str, destination: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            destination: destination.to_string(),
+        }
+    }
+}
+
+/// Returns the destination for which health is tracked: the provider,
+/// if the site belongs to one, or the site otherwise
+pub fn destination<'a>(site_name: &'a str, provider: Option<&'a str>) -> &'a str {
+    provider.unwrap_or(site_name)
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SourceHealthConfig {
+    /// Whether health checking is enabled
+    #[serde(default)]
+    pub enable: bool,
+
+    /// The rates are computed over this rolling window
+    #[serde(
+        default = "SourceHealthConfig::default_window",
+        with = "duration_serde"
+    )]
+    pub window: Duration,
+
+    /// The rate thresholds are only considered once a source has
+    /// at least this many outcomes within the window
+    #[serde(default = "SourceHealthConfig::default_min_volume")]
+    pub min_volume: usize,
+
+    #[serde(default)]
+    pub connection_failure_rate_threshold: Option<f64>,
+
+    #[serde(default)]
+    pub permanent_failure_rate_threshold: Option<f64>,
+
+    /// Responses with these classifications indicate that the
+    /// source is on a blocklist
+    #[serde(default = "SourceHealthConfig::default_blocklist_classes")]
+    pub blocklist_classes: Vec<BounceClass>,
+
+    /// How many blocklist responses within the window will
+    /// mark the source as unhealthy
+    #[serde(default = "SourceHealthConfig::default_blocklist_threshold")]
+    pub blocklist_threshold: usize,
+
+    /// How long an unhealthy source is taken out of service
+    /// before it is tried again
+    #[serde(
+        default = "SourceHealthConfig::default_quarantine",
+        with = "duration_serde"
+    )]
+    pub quarantine: Duration,
+}
+
+impl Default for SourceHealthConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            window: Self::default_window(),
+            min_volume: Self::default_min_volume(),
+            connection_failure_rate_threshold: None,
+            permanent_failure_rate_threshold: None,
+            blocklist_classes: Self::default_blocklist_classes(),
+            blocklist_threshold: Self::default_blocklist_threshold(),
+            quarantine: Self::default_quarantine(),
+        }
+    }
+}
+
+impl SourceHealthConfig {
+    fn default_window() -> Duration {
+        Duration::from_secs(600)
+    }
+
+    fn default_min_volume() -> usize {
+        20
+    }
+
+    fn default_blocklist_classes() -> Vec<BounceClass> {
+        vec![PreDefinedBounceClass::SpamBlock.into()]
+    }
+
+    fn default_blocklist_threshold() -> usize {
+        3
+    }
+
+    fn default_quarantine() -> Duration {
+        Duration::from_secs(1800)
+    }
+
+    fn bucket_index(&self) -> u64 {
+        let bucket_secs = (self.window.as_secs() / NUM_BUCKETS).max(1);
+        START.elapsed().as_secs() / bucket_secs
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Success,
+    TransientFailure,
+    ConnectionFailure,
+    PermanentFailure,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+struct Counts {
+    total: usize,
+    connection_failures: usize,
+    permanent_failures: usize,
+    blocklisted: usize,
+}
+
+impl Counts {
+    fn add(&mut self, outcome: Outcome, blocklisted: bool) {
+        self.total += 1;
+        match outcome {
+            Outcome::ConnectionFailure => self.connection_failures += 1,
+            Outcome::PermanentFailure => self.permanent_failures += 1,
+            Outcome::Success | Outcome::TransientFailure => {}
+        }
+        if blocklisted {
+            self.blocklisted += 1;
+        }
+    }
+
+    fn accumulate(&mut self, other: &Counts) {
+        self.total += other.total;
+        self.connection_failures += other.connection_failures;
+        self.permanent_failures += other.permanent_failures;
+        self.blocklisted += other.blocklisted;
+    }
+}
+
+/// The health of an egress source
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SourceHealthStatus {
+    pub healthy: bool,
+    /// Why the source was marked unhealthy
+    pub reason: Option<String>,
+    /// When the source will be returned to service
+    pub unhealthy_until: Option<DateTime<Utc>>,
+    pub total: usize,
+    pub connection_failures: usize,
+    pub permanent_failures: usize,
+    pub blocklisted: usize,
+}
+
+impl<'lua> IntoLua<'lua> for SourceHealthStatus {
+    fn into_lua(self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+        lua.to_value(&self)
+    }
+}
+
+#[derive(Default, Debug)]
+struct Tracker {
+    buckets: VecDeque<(u64, Counts)>,
+    unhealthy: Option<(DateTime<Utc>, String)>,
+}
+
+impl Tracker {
+    fn trim(&mut self, index: u64) {
+        while let Some((bucket, _)) = self.buckets.front() {
+            if bucket + NUM_BUCKETS > index {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    fn record(&mut self, index: u64, outcome: Outcome, blocklisted: bool) {
+        self.trim(index);
+        match self.buckets.back_mut() {
+            Some((bucket, counts)) if *bucket == index => counts.add(outcome, blocklisted),
+            _ => {
+                let mut counts = Counts::default();
+                counts.add(outcome, blocklisted);
+                self.buckets.push_back((index, counts));
+            }
+        }
+    }
+
+    fn totals(&self) -> Counts {
+        let mut totals = Counts::default();
+        for (_, counts) in &self.buckets {
+            totals.accumulate(counts);
+        }
+        totals
+    }
+
+    fn status(&self) -> SourceHealthStatus {
+        let totals = self.totals();
+        SourceHealthStatus {
+            healthy: self.unhealthy.is_none(),
+            reason: self.unhealthy.as_ref().map(|(_, reason)| reason.clone()),
+            unhealthy_until: self.unhealthy.as_ref().map(|(until, _)| *until),
+            total: totals.total,
+            connection_failures: totals.connection_failures,
+            permanent_failures: totals.permanent_failures,
+            blocklisted: totals.blocklisted,
+        }
+    }
+
+    /// Returns the reason that the source should be considered
+    /// unhealthy, if any
+    fn check_thresholds(&self, config: &SourceHealthConfig) -> Option<String> {
+        let totals = self.totals();
+        if totals.blocklisted >= config.blocklist_threshold.max(1) {
+            return Some(format!(
+                "{} blocklist responses within {:?}",
+                totals.blocklisted, config.window
+            ));
+        }
+        if totals.total < config.min_volume {
+            return None;
+        }
+        let rate = |n: usize| n as f64 / totals.total as f64;
+        if let Some(threshold) = config.connection_failure_rate_threshold {
+            let rate = rate(totals.connection_failures);
+            if rate > threshold {
+                return Some(format!(
+                    "connection failure rate {rate:.2} exceeds {threshold:.2}"
+                ));
+            }
+        }
+        if let Some(threshold) = config.permanent_failure_rate_threshold {
+            let rate = rate(totals.permanent_failures);
+            if rate > threshold {
+                return Some(format!(
+                    "permanent failure rate {rate:.2} exceeds {threshold:.2}"
+                ));
+            }
+        }
+        None
+    }
+
+    /// Returns true if the quarantine has just expired
+    fn expire_quarantine(&mut self, now: DateTime<Utc>) -> bool {
+        match &self.unhealthy {
+            Some((until, _)) if *until <= now => {
+                self.unhealthy.take();
+                // Start over, so that the failures that caused the
+                // quarantine don't immediately cause another one
+                self.buckets.clear();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn classify_outcome(kind: RecordType, response: &Response) -> Option<Outcome> {
+    match kind {
+        RecordType::Delivery => Some(Outcome::Success),
+        RecordType::Bounce => Some(Outcome::PermanentFailure),
+        RecordType::TransientFailure => {
+            if response
+                .content
+                .starts_with("KumoMTA internal: failed to connect")
+            {
+                Some(Outcome::ConnectionFailure)
+            } else if response.content.starts_with("KumoMTA internal") {
+                // Not a verdict from the destination about this source
+                None
+            } else {
+                Some(Outcome::TransientFailure)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn notify(key: TrackerKey, status: SourceHealthStatus) {
+    let TrackerKey {
+        source,
+        destination,
+    } = key;
+    if status.healthy {
+        tracing::info!("egress source {source} has been returned to service for {destination}");
+    } else {
+        tracing::warn!(
+            "egress source {source} is unhealthy for {destination}: {}",
+            status.reason.as_deref().unwrap_or("")
+        );
+    }
+    let result = spawn(
+        format!("egress_source_health_changed {source} {destination}"),
+        async move {
+            match load_config().await {
+                Ok(mut lua_config) => {
+                    if let Err(err) = lua_config
+                        .async_call_callback(
+                            &SOURCE_HEALTH_CHANGED_SIG,
+                            (source, destination, status),
+                        )
+                        .await
+                    {
+                        tracing::error!(
+                            "error while calling egress_source_health_changed: {err:#}"
+                        );
+                    }
+                }
+                Err(err) => {
+                    tracing::error!(
+                        "failed to load lua config while attempting to \
+                     call egress_source_health_changed: {err:#}"
+                    );
+                }
+            }
+        },
+    );
+    if let Err(err) = result {
+        tracing::error!("failed to spawn egress_source_health_changed: {err:#}");
+    }
+}
+
+/// Update the health of the source for the disposition described by
+/// the parameters. This is called from `log_disposition`, where `site`
+/// is the name of the ready queue through which the attempt was made.
+pub async fn record_disposition(
+    kind: RecordType,
+    egress_source: Option<&str>,
+    site: &str,
+    provider: Option<&str>,
+    response: &Response,
+) {
+    let Some(source) = egress_source else {
+        return;
+    };
+    let config = CONFIG.lock().clone();
+    if !config.enable {
+        return;
+    }
+    let Some(outcome) = classify_outcome(kind, response) else {
+        return;
+    };
+    let blocklisted = match crate::logging::classify::classify_response(response).await {
+        Some(class) => config.blocklist_classes.contains(&class),
+        None => false,
+    };
+
+    // Prefer the ready queue's own view of its destination, so that
+    // we agree with the checks made by the ready queue and source
+    // selection
+    let health_destination = ReadyQueueManager::get_by_name(site)
+        .map(|queue| queue.destination())
+        .unwrap_or_else(|| destination(site, provider).to_string());
+    let key = TrackerKey::new(source, &health_destination);
+
+    let index = config.bucket_index();
+    let transition = {
+        let mut trackers = TRACKERS.lock();
+        let tracker = trackers.entry(key.clone()).or_default();
+        if tracker.unhealthy.is_some() {
+            // Already out of service; outcomes of attempts that were
+            // in flight are not interesting
+            return;
+        }
+        tracker.record(index, outcome, blocklisted);
+        match tracker.check_thresholds(&config) {
+            Some(reason) => {
+                let until = Utc::now()
+                    + chrono::Duration::from_std(config.quarantine)
+                        .unwrap_or(kumo_chrono_helper::MINUTE);
+                tracker.unhealthy.replace((until, reason));
+                Some(tracker.status())
+            }
+            None => None,
+        }
+    };
+
+    if let Some(status) = transition {
+        notify(key, status);
+    }
+}
+
+/// Returns true if the source is currently out of service
+/// for the destination
+pub fn is_unhealthy(source: &str, destination: &str) -> bool {
+    let mut trackers = TRACKERS.lock();
+    if trackers.is_empty() {
+        return false;
+    }
+    let key = TrackerKey::new(source, destination);
+    let Some(tracker) = trackers.get_mut(&key) else {
+        return false;
+    };
+    if tracker.unhealthy.is_none() {
+        return false;
+    }
+    if tracker.expire_quarantine(Utc::now()) {
+        let status = tracker.status();
+        drop(trackers);
+        notify(key, status);
+        return false;
+    }
+    true
+}
+
+/// Returns the time until the quarantine of the source for the
+/// destination ends, if it is unhealthy
+pub fn quarantine_remaining(source: &str, destination: &str) -> Option<chrono::Duration> {
+    let trackers = TRACKERS.lock();
+    let key = TrackerKey::new(source, destination);
+    let (until, _) = trackers.get(&key)?.unhealthy.as_ref()?;
+    Some(*until - Utc::now())
+}
+
+fn get_status(source: &str, destination: &str) -> SourceHealthStatus {
+    is_unhealthy(source, destination);
+    let index = CONFIG.lock().bucket_index();
+    let mut trackers = TRACKERS.lock();
+    match trackers.get_mut(&TrackerKey::new(source, destination)) {
+        Some(tracker) => {
+            tracker.trim(index);
+            tracker.status()
+        }
+        None => Tracker::default().status(),
+    }
+}
+
+/// Returns the source to service for the destination immediately
+fn reset(source: &str, destination: &str) -> bool {
+    let key = TrackerKey::new(source, destination);
+    let mut trackers = TRACKERS.lock();
+    let Some(tracker) = trackers.get_mut(&key) else {
+        return false;
+    };
+    let was_unhealthy = tracker.unhealthy.is_some();
+    if was_unhealthy {
+        tracker.expire_quarantine(DateTime::<Utc>::MAX_UTC);
+        let status = tracker.status();
+        drop(trackers);
+        notify(key, status);
+    }
+    was_unhealthy
+}
+
+fn configure(config: SourceHealthConfig) {
+    let mut current = CONFIG.lock();
+    if current.window != config.window {
+        // The bucket indices are not comparable across
+        // different window sizes, so start over
+        for tracker in TRACKERS.lock().values_mut() {
+            tracker.buckets.clear();
+        }
+    }
+    *current = config;
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    SOURCE_HEALTH_CHANGED_SIG.register();
+
+    let module = get_or_create_sub_module(lua, "source_health")?;
+
+    module.set(
+        "configure",
+        lua.create_function(|lua, params: Value| {
+            let config: SourceHealthConfig = from_lua_value(lua, params)?;
+            configure(config);
+            Ok(())
+        })?,
+    )?;
+
+    module.set(
+        "get",
+        lua.create_function(|lua, (source, destination): (String, String)| {
+            lua.to_value(&get_status(&source, &destination))
+        })?,
+    )?;
+
+    module.set(
+        "reset",
+        lua.create_function(|_, (source, destination): (String, String)| {
+            Ok(reset(&source, &destination))
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(code: u16, content: &str) -> Response {
+        Response {
+            code,
+            enhanced_code: None,
+            command: None,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn outcomes() {
+        assert_eq!(
+            classify_outcome(RecordType::Delivery, &response(250, "ok")),
+            Some(Outcome::Success)
+        );
+        assert_eq!(
+            classify_outcome(
+                RecordType::TransientFailure,
+                &response(
+                    400,
+                    "KumoMTA internal: failed to connect to any candidate hosts: refused"
+                )
+            ),
+            Some(Outcome::ConnectionFailure)
+        );
+        assert_eq!(
+            classify_outcome(
+                RecordType::TransientFailure,
+                &response(451, "KumoMTA internal: all possible sources are suspended")
+            ),
+            None
+        );
+        assert_eq!(
+            classify_outcome(RecordType::Bounce, &response(550, "no such user")),
+            Some(Outcome::PermanentFailure)
+        );
+        assert_eq!(
+            classify_outcome(RecordType::Reception, &response(250, "")),
+            None
+        );
+    }
+
+    #[test]
+    fn thresholds() {
+        let config = SourceHealthConfig {
+            enable: true,
+            min_volume: 10,
+            connection_failure_rate_threshold: Some(0.5),
+            ..SourceHealthConfig::default()
+        };
+        let mut tracker = Tracker::default();
+        for _ in 0..6 {
+            tracker.record(0, Outcome::ConnectionFailure, false);
+        }
+        // Not enough volume yet
+        assert_eq!(tracker.check_thresholds(&config), None);
+
+        for _ in 0..4 {
+            tracker.record(0, Outcome::Success, false);
+        }
+        assert_eq!(
+            tracker.check_thresholds(&config).as_deref(),
+            Some("connection failure rate 0.60 exceeds 0.50")
+        );
+
+        // The failures fall out of the window
+        tracker.trim(NUM_BUCKETS);
+        assert_eq!(tracker.check_thresholds(&config), None);
+
+        // Blocklisting doesn't require a minimum volume
+        tracker.record(NUM_BUCKETS, Outcome::TransientFailure, true);
+        tracker.record(NUM_BUCKETS, Outcome::TransientFailure, true);
+        assert_eq!(tracker.check_thresholds(&config), None);
+        tracker.record(NUM_BUCKETS, Outcome::PermanentFailure, true);
+        assert!(tracker.check_thresholds(&config).is_some());
+    }
+
+    #[test]
+    fn per_destination() {
+        let mut tracker = Tracker::default();
+        tracker.unhealthy = Some((
+            Utc::now() + chrono::Duration::seconds(60),
+            "testing".to_string(),
+        ));
+        TRACKERS
+            .lock()
+            .insert(TrackerKey::new("ip-1", "provider-a"), tracker);
+
+        assert!(is_unhealthy("ip-1", "provider-a"));
+        assert!(quarantine_remaining("ip-1", "provider-a").is_some());
+        // An outage at one destination doesn't affect the others
+        assert!(!is_unhealthy("ip-1", "provider-b"));
+        assert!(!is_unhealthy("ip-2", "provider-a"));
+
+        assert_eq!(
+            destination("mx.example.com", Some("provider-a")),
+            "provider-a"
+        );
+        assert_eq!(destination("mx.example.com", None), "mx.example.com");
+    }
+
+    #[test]
+    fn quarantine() {
+        let now = Utc::now();
+        let mut tracker = Tracker::default();
+        tracker.record(0, Outcome::ConnectionFailure, false);
+        tracker.unhealthy = Some((now, "testing".to_string()));
+        assert!(!tracker.status().healthy);
+
+        assert!(!tracker.expire_quarantine(now - chrono::Duration::seconds(1)));
+        assert!(tracker.expire_quarantine(now));
+        let status = tracker.status();
+        assert!(status.healthy);
+        assert_eq!(status.total, 0);
+    }
+}
@@ -232,6 +232,24 @@ impl SpoolManager {
         Ok(())
     }
 
+    /// Move the meta and data for id into the quarantine area of their
+    /// respective spools, so that a corrupt entry can be inspected and
+    /// recovered offline using spool-util rather than being discarded.
+    pub async fn quarantine_impl(&self, id: SpoolId) -> anyhow::Result<()> {
+        let (meta_spool, data_spool) = Self::get_data_meta();
+        for (label, spool) in [("meta", meta_spool), ("data", data_spool)] {
+            if let Err(err) = spool.quarantine(id).await {
+                tracing::error!(
+                    "Error quarantining {label} for {id}, removing it instead: {err:#}"
+                );
+                if let Err(err) = spool.remove(id).await {
+                    tracing::debug!("Error removing {label} for {id}: {err:#}");
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn spool_in_thread(
         &self,
         rx: flume::Receiver<SpoolEntry>,
@@ -359,13 +377,12 @@ impl SpoolManager {
                     }
                     Err(err) => {
                         tracing::error!("Failed to parse metadata for {id}: {err:#}");
-                        self.remove_from_spool_impl(id).await?;
+                        self.quarantine_impl(id).await?;
                     }
                 },
                 SpoolEntry::Corrupt { id, error } => {
                     tracing::error!("Failed to load {id}: {error}");
-                    // TODO: log this better
-                    self.remove_from_spool_impl(id).await?;
+                    self.quarantine_impl(id).await?;
                 }
             }
         }
@@ -0,0 +1,537 @@
+//! A persistent list of recipients that should no longer be sent
+//! any mail, because delivery to them has permanently failed or
+//! because they have complained about receiving it.
+//!
+//! The list is fed automatically from the dispositions that pass
+//! through `log_disposition`: bounces are classified using the bounce
+//! classifier and recorded if their class is one of the configured
+//! `bounce_classes`, and complaints are recorded from incoming ARF
+//! reports. Recipients on the list are rejected at RCPT TO or by
+//! the HTTP injection API, or silently dropped, depending on the
+//! configured `action`.
+use crate::logging::classify::classify_response;
+use crate::logging::disposition::oob_response;
+use crate::smtp_server::RelayDisposition;
+use anyhow::Context;
+use bounce_classify::{BounceClass, PreDefinedBounceClass};
+use chrono::{DateTime, Utc};
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use kumo_api_types::suppression::{
+    SuppressionReason, SuppressionV1AddEntry, SuppressionV1Entry, SuppressionV1ListRequest,
+};
+use kumo_log_types::rfc3464::ReportAction;
+use kumo_log_types::rfc5965::ARFReport;
+use kumo_log_types::RecordType;
+use message::Message;
+use mlua::{Lua, LuaSerdeExt, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use rfc5321::Response;
+use serde::Deserialize;
+use sqlite::Connection;
+use std::sync::Arc;
+
+static STORE: Lazy<Mutex<Option<Arc<SuppressionStore>>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SuppressionAction {
+    /// Reject the recipient at RCPT TO, or fail it in the
+    /// response to the HTTP injection request
+    #[default]
+    Reject,
+    /// Accept the message, but assign it to the `null` queue
+    /// so that it is discarded
+    Drop,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SuppressionConfig {
+    #[serde(default = "SuppressionConfig::default_path")]
+    pub path: String,
+
+    #[serde(default)]
+    pub action: SuppressionAction,
+
+    /// Bounces whose classification is one of these
+    /// cause the recipient to be suppressed
+    #[serde(default = "SuppressionConfig::default_bounce_classes")]
+    pub bounce_classes: Vec<BounceClass>,
+
+    /// Whether complaints cause the recipient to be suppressed
+    #[serde(default = "SuppressionConfig::default_suppress_complaints")]
+    pub suppress_complaints: bool,
+}
+
+impl SuppressionConfig {
+    fn default_path() -> String {
+        "/var/spool/kumomta/suppression.db".to_string()
+    }
+
+    fn default_bounce_classes() -> Vec<BounceClass> {
+        vec![
+            PreDefinedBounceClass::InvalidRecipient.into(),
+            PreDefinedBounceClass::BadDomain.into(),
+            PreDefinedBounceClass::InactiveMailbox.into(),
+        ]
+    }
+
+    fn default_suppress_complaints() -> bool {
+        true
+    }
+}
+
+struct SuppressionStore {
+    config: SuppressionConfig,
+    /// Used to make changes to the list
+    db: Mutex<Connection>,
+    /// Idle connections used for lookups, so that lookups
+    /// don't have to wait for each other, or for changes
+    readers: Mutex<Vec<Connection>>,
+}
+
+/// Produce the key under which address is recorded.
+/// The local part is case sensitive in theory, but not in practice.
+fn normalize(address: &str) -> String {
+    address
+        .trim()
+        .trim_matches(&['<', '>'][..])
+        .to_ascii_lowercase()
+}
+
+fn domain_of(recipient: &str) -> &str {
+    recipient
+        .rsplit_once('@')
+        .map(|(_, domain)| domain)
+        .unwrap_or("")
+}
+
+impl SuppressionStore {
+    fn connect(config: &SuppressionConfig) -> anyhow::Result<Connection> {
+        let mut db = Connection::open(&config.path)
+            .with_context(|| format!("opening suppression database {}", config.path))?;
+        db.set_busy_timeout(500)?;
+        Ok(db)
+    }
+
+    fn open(config: SuppressionConfig) -> anyhow::Result<Self> {
+        let db = Self::connect(&config)?;
+        let query = r#"
+PRAGMA journal_mode = WAL;
+
+CREATE TABLE IF NOT EXISTS suppression (
+    recipient text NOT NULL PRIMARY KEY,
+    domain text NOT NULL,
+    reason text NOT NULL,
+    description text,
+    created DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS suppression_domain ON suppression (domain);
+    "#;
+
+        db.execute(query)?;
+
+        Ok(Self {
+            config,
+            db: Mutex::new(db),
+            readers: Mutex::new(vec![]),
+        })
+    }
+
+    /// Runs func with a connection that is only used for reading
+    fn with_reader<T>(
+        &self,
+        func: impl FnOnce(&Connection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let db = match self.readers.lock().pop() {
+            Some(db) => db,
+            None => Self::connect(&self.config)?,
+        };
+        let result = func(&db);
+        if result.is_ok() {
+            self.readers.lock().push(db);
+        }
+        result
+    }
+
+    fn add(&self, entries: &[SuppressionV1AddEntry]) -> anyhow::Result<()> {
+        let db = self.db.lock();
+        db.execute("BEGIN")?;
+        let result = (|| {
+            let mut upsert = db.prepare(
+                "INSERT INTO suppression
+                    (recipient, domain, reason, description, created)
+                    VALUES ($recipient, $domain, $reason, $description, $created)
+                    ON CONFLICT (recipient)
+                    DO UPDATE SET reason=$reason, description=$description, created=$created",
+            )?;
+            let created = Utc::now().to_rfc3339();
+            for entry in entries {
+                let recipient = normalize(&entry.recipient);
+                if domain_of(&recipient).is_empty() {
+                    anyhow::bail!("invalid recipient address '{}'", entry.recipient);
+                }
+                upsert.reset()?;
+                upsert.bind(("$recipient", recipient.as_str()))?;
+                upsert.bind(("$domain", domain_of(&recipient)))?;
+                upsert.bind(("$reason", entry.reason.as_str()))?;
+                upsert.bind(("$description", entry.description.as_deref()))?;
+                upsert.bind(("$created", created.as_str()))?;
+                upsert.next()?;
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => {
+                db.execute("COMMIT")?;
+                Ok(())
+            }
+            Err(err) => {
+                db.execute("ROLLBACK").ok();
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns the number of entries that were removed
+    fn remove(&self, recipients: &[String]) -> anyhow::Result<usize> {
+        let db = self.db.lock();
+        let mut delete = db.prepare("DELETE FROM suppression WHERE recipient=$recipient")?;
+        let mut removed = 0;
+        for recipient in recipients {
+            delete.reset()?;
+            delete.bind(("$recipient", normalize(recipient).as_str()))?;
+            delete.next()?;
+            removed += db.change_count();
+        }
+        Ok(removed)
+    }
+
+    fn list(&self, request: &SuppressionV1ListRequest) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+        self.with_reader(|db| Self::list_with(db, request))
+    }
+
+    fn list_with(
+        db: &Connection,
+        request: &SuppressionV1ListRequest,
+    ) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+        let mut stmt = db.prepare(
+            "SELECT * FROM suppression WHERE
+                ($recipient IS NULL OR recipient=$recipient)
+                AND ($domain IS NULL OR domain=$domain)
+                ORDER BY recipient LIMIT $limit",
+        )?;
+        let recipient = request.recipient.as_deref().map(normalize);
+        let domain = request.domain.as_deref().map(|d| d.to_ascii_lowercase());
+        stmt.bind(("$recipient", recipient.as_deref()))?;
+        stmt.bind(("$domain", domain.as_deref()))?;
+        stmt.bind(("$limit", request.limit as i64))?;
+
+        let mut entries = vec![];
+        while let sqlite::State::Row = stmt.next()? {
+            let reason: String = stmt.read("reason")?;
+            let created: String = stmt.read("created")?;
+            entries.push(SuppressionV1Entry {
+                recipient: stmt.read("recipient")?,
+                reason: reason.parse().map_err(|err: String| anyhow::anyhow!(err))?,
+                description: stmt.read("description")?,
+                created: DateTime::parse_from_rfc3339(&created)?.to_utc(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn lookup(&self, recipient: &str) -> anyhow::Result<Option<SuppressionV1Entry>> {
+        let mut entries = self.list(&SuppressionV1ListRequest {
+            recipient: Some(recipient.to_string()),
+            domain: None,
+            limit: 1,
+        })?;
+        Ok(entries.pop())
+    }
+}
+
+fn get_store() -> Option<Arc<SuppressionStore>> {
+    STORE.lock().clone()
+}
+
+/// Returns true if the suppression list is configured to
+/// suppress the recipients of complaints
+pub fn suppresses_complaints() -> bool {
+    get_store()
+        .map(|store| store.config.suppress_complaints)
+        .unwrap_or(false)
+}
+
+/// Runs func against the store on a blocking thread.
+/// Returns None if the suppression list is not configured.
+async fn with_store<T, F>(func: F) -> anyhow::Result<Option<T>>
+where
+    T: Send + 'static,
+    F: FnOnce(&SuppressionStore) -> anyhow::Result<T> + Send + 'static,
+{
+    let Some(store) = get_store() else {
+        return Ok(None);
+    };
+    tokio::task::spawn_blocking(move || func(&store).map(Some)).await?
+}
+
+async fn require_store<T, F>(func: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&SuppressionStore) -> anyhow::Result<T> + Send + 'static,
+{
+    with_store(func)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("the suppression list has not been configured"))
+}
+
+pub async fn add_entries(entries: Vec<SuppressionV1AddEntry>) -> anyhow::Result<()> {
+    require_store(move |store| store.add(&entries)).await
+}
+
+/// Removes the entries for the specified recipients, returning
+/// the number of entries that were removed
+pub async fn remove_entries(recipients: Vec<String>) -> anyhow::Result<usize> {
+    require_store(move |store| store.remove(&recipients)).await
+}
+
+pub async fn list_entries(
+    request: SuppressionV1ListRequest,
+) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+    require_store(move |store| store.list(&request)).await
+}
+
+/// Returns the configured action if recipient is suppressed
+pub async fn check_recipient(recipient: &str) -> anyhow::Result<Option<SuppressionAction>> {
+    let recipient = recipient.to_string();
+    Ok(
+        with_store(move |store| Ok(store.lookup(&recipient)?.map(|_| store.config.action)))
+            .await?
+            .flatten(),
+    )
+}
+
+/// Applies the configured action to msg if its recipient is suppressed:
+/// returns an error if the action is Reject, or assigns it to the
+/// `null` queue if the action is Drop.
+pub async fn check_message(msg: &Message) -> anyhow::Result<()> {
+    let recipient = msg.recipient()?.to_string();
+    match check_recipient(&recipient).await? {
+        None => Ok(()),
+        Some(SuppressionAction::Reject) => {
+            anyhow::bail!("recipient {recipient} is on the suppression list")
+        }
+        Some(SuppressionAction::Drop) => msg.set_meta("queue", "null"),
+    }
+}
+
+/// Add the recipients of any hard bounce or complaint described by
+/// the parameters to the suppression list.
+/// This is called from `log_disposition`.
+pub async fn record_disposition(
+    kind: RecordType,
+    msg: &Message,
+    response: &Response,
+    relay_disposition: Option<RelayDisposition>,
+    feedback_report: Option<&ARFReport>,
+) {
+    let Some(store) = get_store() else {
+        return;
+    };
+
+    let bounce_entry = |recipient: String, response: &Response, class: BounceClass| {
+        store
+            .config
+            .bounce_classes
+            .contains(&class)
+            .then(|| SuppressionV1AddEntry {
+                recipient,
+                reason: SuppressionReason::Bounce,
+                description: Some(response.to_single_line()),
+            })
+    };
+
+    let mut entries = vec![];
+
+    match kind {
+        RecordType::Bounce => {
+            if let (Ok(recipient), Some(class)) =
+                (msg.recipient(), classify_response(response).await)
+            {
+                entries.extend(bounce_entry(recipient.to_string(), response, class));
+            }
+        }
+        RecordType::Feedback if store.config.suppress_complaints => {
+            if let Some(report) = feedback_report {
+                for recipient in &report.original_rcpto_to {
+                    entries.push(SuppressionV1AddEntry {
+                        recipient: recipient.to_string(),
+                        reason: SuppressionReason::Complaint,
+                        description: Some(format!("feedback type {}", report.feedback_type)),
+                    });
+                }
+            }
+        }
+        RecordType::Reception => {
+            if let Some(RelayDisposition { log_oob: true, .. }) = relay_disposition {
+                if let Ok(Some(report)) = msg.parse_rfc3464() {
+                    for recip in &report.per_recipient {
+                        if recip.action != ReportAction::Failed {
+                            continue;
+                        }
+                        let response = oob_response(recip);
+                        if let Some(class) = classify_response(&response).await {
+                            let recipient = recip
+                                .original_recipient
+                                .as_ref()
+                                .unwrap_or(&recip.final_recipient)
+                                .recipient
+                                .to_string();
+                            entries.extend(bounce_entry(recipient, &response, class));
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+
+    if entries.is_empty() {
+        return;
+    }
+
+    let result = tokio::task::spawn_blocking(move || store.add(&entries)).await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::error!("failed to update suppression list: {err:#}"),
+        Err(err) => tracing::error!("failed to update suppression list: {err:#}"),
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "suppression")?;
+
+    module.set(
+        "configure",
+        lua.create_function(|lua, params: Value| {
+            let config: SuppressionConfig = from_lua_value(lua, params)?;
+            if config::is_validating() {
+                return Ok(());
+            }
+            let store = SuppressionStore::open(config).map_err(any_err)?;
+            STORE.lock().replace(Arc::new(store));
+            Ok(())
+        })?,
+    )?;
+
+    module.set(
+        "lookup",
+        lua.create_async_function(|lua, recipient: String| async move {
+            let entry = require_store(move |store| store.lookup(&recipient))
+                .await
+                .map_err(any_err)?;
+            lua.to_value(&entry)
+        })?,
+    )?;
+
+    module.set(
+        "add",
+        lua.create_async_function(|lua, params: Value| async move {
+            let entry: SuppressionV1AddEntry = from_lua_value(lua, params)?;
+            add_entries(vec![entry]).await.map_err(any_err)
+        })?,
+    )?;
+
+    module.set(
+        "remove",
+        lua.create_async_function(|_lua, recipient: String| async move {
+            let removed = remove_entries(vec![recipient]).await.map_err(any_err)?;
+            Ok(removed > 0)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_store(name: &str) -> SuppressionStore {
+        let path = std::env::temp_dir().join(format!(
+            "kumod-suppression-{name}-{}.db",
+            std::process::id()
+        ));
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{suffix}", path.display())).ok();
+        }
+        let config: SuppressionConfig =
+            serde_json::from_value(serde_json::json!({"path": path})).unwrap();
+        SuppressionStore::open(config).unwrap()
+    }
+
+    fn entry(recipient: &str, reason: SuppressionReason) -> SuppressionV1AddEntry {
+        SuppressionV1AddEntry {
+            recipient: recipient.to_string(),
+            reason,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn add_lookup_remove() {
+        let store = new_store("add_lookup_remove");
+        store
+            .add(&[
+                entry("<User@Example.com>", SuppressionReason::Bounce),
+                entry("other@example.com", SuppressionReason::Complaint),
+                entry("user@example.org", SuppressionReason::Manual),
+            ])
+            .unwrap();
+
+        let found = store.lookup("user@EXAMPLE.com").unwrap().unwrap();
+        assert_eq!(found.recipient, "user@example.com");
+        assert_eq!(found.reason, SuppressionReason::Bounce);
+        assert!(store.lookup("nobody@example.com").unwrap().is_none());
+
+        let by_domain = store
+            .list(&SuppressionV1ListRequest {
+                recipient: None,
+                domain: Some("example.com".to_string()),
+                limit: 10,
+            })
+            .unwrap();
+        assert_eq!(
+            by_domain
+                .iter()
+                .map(|e| e.recipient.as_str())
+                .collect::<Vec<_>>(),
+            vec!["other@example.com", "user@example.com"]
+        );
+
+        assert_eq!(
+            store
+                .remove(&[
+                    "USER@example.com".to_string(),
+                    "nobody@example.com".to_string()
+                ])
+                .unwrap(),
+            1
+        );
+        assert!(store.lookup("user@example.com").unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_entries_are_not_applied() {
+        let store = new_store("invalid_entries");
+        assert!(store
+            .add(&[
+                entry("user@example.com", SuppressionReason::Manual),
+                entry("not-an-address", SuppressionReason::Manual),
+            ])
+            .is_err());
+        assert!(store.lookup("user@example.com").unwrap().is_none());
+    }
+}
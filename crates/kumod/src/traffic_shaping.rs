@@ -0,0 +1,401 @@
+//! Runtime adjustable traffic shaping limits that can be scoped to a
+//! tenant and/or campaign at a given destination site.
+//!
+//! The limits in the egress path configuration apply to a ready queue
+//! as a whole, and thus to all tenants and campaigns that share it.
+//! When a provider starts to push back on a specific sender, policy
+//! can use `kumo.shaping.set_override` (typically from the
+//! `smtp_client_transient_failure` event) to reduce the limits for
+//! just that tenant or campaign for a period of time.
+use config::{
+    from_lua_value, get_or_create_sub_module, load_config, serialize_options, CallbackSignature,
+};
+use message::Message;
+use mlua::{Lua, LuaSerdeExt, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use rfc5321::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use throttle::limit::{LimitLease, LimitSpec};
+use throttle::ThrottleSpec;
+
+static OVERRIDES: Lazy<Mutex<HashMap<ShapingScope, ActiveOverride>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub static TRANSIENT_FAILURE_SIG: Lazy<
+    CallbackSignature<(String, String, Option<String>, Option<String>), ()>,
+> = Lazy::new(|| CallbackSignature::new_with_multiple("smtp_client_transient_failure"));
+
+/// Identifies the traffic to which an override applies.
+/// A tenant or campaign of None matches any tenant or campaign.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct ShapingScope {
+    pub site_name: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub campaign: Option<String>,
+}
+
+impl ShapingScope {
+    fn matches(&self, site_name: &str, tenant: Option<&str>, campaign: Option<&str>) -> bool {
+        self.site_name == site_name
+            && self.tenant.as_deref().map_or(true, |t| Some(t) == tenant)
+            && self
+                .campaign
+                .as_deref()
+                .map_or(true, |c| Some(c) == campaign)
+    }
+
+    /// Returns true if this scope applies to the site as a whole
+    fn is_site_wide(&self) -> bool {
+        self.tenant.is_none() && self.campaign.is_none()
+    }
+
+    fn key(&self, kind: &str) -> String {
+        format!(
+            "kumomta.shaping.{kind}.{}.{}.{}",
+            self.site_name,
+            self.tenant.as_deref().unwrap_or("*"),
+            self.campaign.as_deref().unwrap_or("*")
+        )
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ShapingOverride {
+    pub site_name: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub campaign: Option<String>,
+
+    /// For a site-wide override, reduces the connection_limit of
+    /// the ready queues for the site. For a tenant or campaign,
+    /// limits the number of connections that are concurrently
+    /// delivering messages for that tenant or campaign.
+    #[serde(default)]
+    pub connection_limit: Option<usize>,
+
+    #[serde(default)]
+    pub max_message_rate: Option<ThrottleSpec>,
+
+    /// Only honored for site-wide overrides
+    #[serde(default)]
+    pub max_deliveries_per_connection: Option<usize>,
+
+    /// How long the override remains in effect
+    #[serde(with = "duration_serde")]
+    pub duration: Duration,
+
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl ShapingOverride {
+    fn scope(&self) -> ShapingScope {
+        ShapingScope {
+            site_name: self.site_name.clone(),
+            tenant: self.tenant.clone(),
+            campaign: self.campaign.clone(),
+        }
+    }
+}
+
+/// The representation of an active override returned by
+/// `kumo.shaping.list_overrides`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ShapingOverrideEntry {
+    pub site_name: String,
+    pub tenant: Option<String>,
+    pub campaign: Option<String>,
+    pub connection_limit: Option<usize>,
+    pub max_message_rate: Option<String>,
+    pub max_deliveries_per_connection: Option<usize>,
+    pub reason: Option<String>,
+    #[serde(with = "duration_serde")]
+    pub remaining: Duration,
+}
+
+struct ActiveOverride {
+    params: ShapingOverride,
+    expires: Instant,
+}
+
+impl ActiveOverride {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires <= now
+    }
+}
+
+/// Install an override, replacing any existing override with the same scope
+pub fn set_override(params: ShapingOverride) {
+    let expires = Instant::now() + params.duration;
+    OVERRIDES
+        .lock()
+        .insert(params.scope(), ActiveOverride { params, expires });
+}
+
+/// Remove the override with the specified scope.
+/// Returns true if there was an override to remove.
+pub fn clear_override(scope: &ShapingScope) -> bool {
+    OVERRIDES.lock().remove(scope).is_some()
+}
+
+pub fn list_overrides() -> Vec<ShapingOverrideEntry> {
+    let now = Instant::now();
+    let mut overrides = OVERRIDES.lock();
+    overrides.retain(|_, entry| !entry.is_expired(now));
+    overrides
+        .values()
+        .map(|entry| {
+            let params = &entry.params;
+            ShapingOverrideEntry {
+                site_name: params.site_name.clone(),
+                tenant: params.tenant.clone(),
+                campaign: params.campaign.clone(),
+                connection_limit: params.connection_limit,
+                max_message_rate: params.max_message_rate.map(|spec| spec.to_string()),
+                max_deliveries_per_connection: params.max_deliveries_per_connection,
+                reason: params.reason.clone(),
+                remaining: entry.expires - now,
+            }
+        })
+        .collect()
+}
+
+/// Returns the active overrides that match the supplied traffic
+fn matching_overrides(
+    site_name: &str,
+    tenant: Option<&str>,
+    campaign: Option<&str>,
+) -> Vec<ShapingOverride> {
+    let now = Instant::now();
+    let mut overrides = OVERRIDES.lock();
+    if overrides.is_empty() {
+        return vec![];
+    }
+    overrides.retain(|_, entry| !entry.is_expired(now));
+    overrides
+        .iter()
+        .filter(|(scope, _)| scope.matches(site_name, tenant, campaign))
+        .map(|(_, entry)| entry.params.clone())
+        .collect()
+}
+
+/// Returns the smallest site-wide value produced by `get` from the
+/// active overrides for site_name
+fn site_wide_limit<F: Fn(&ShapingOverride) -> Option<usize>>(
+    site_name: &str,
+    get: F,
+) -> Option<usize> {
+    let now = Instant::now();
+    OVERRIDES
+        .lock()
+        .iter()
+        .filter(|(scope, entry)| {
+            scope.site_name == site_name && scope.is_site_wide() && !entry.is_expired(now)
+        })
+        .filter_map(|(_, entry)| get(&entry.params))
+        .min()
+}
+
+pub fn site_connection_limit(site_name: &str) -> Option<usize> {
+    site_wide_limit(site_name, |params| params.connection_limit)
+}
+
+pub fn site_max_deliveries_per_connection(site_name: &str) -> Option<usize> {
+    site_wide_limit(site_name, |params| params.max_deliveries_per_connection)
+}
+
+/// The outcome of checking a message against the active overrides
+pub enum ShapingResult {
+    /// The message may be delivered. The leases must be held
+    /// until the delivery attempt has completed.
+    Proceed(Vec<LimitLease>),
+    /// The message must not be delivered for at least this long
+    Delay(Duration),
+}
+
+/// Applies the rate and connection limits of the active overrides that
+/// match msg. The message metadata must already be loaded.
+pub async fn check_message(
+    site_name: &str,
+    msg: &Message,
+    lease_duration: Duration,
+) -> anyhow::Result<ShapingResult> {
+    let tenant = msg.get_meta_string("tenant")?;
+    let campaign = msg.get_meta_string("campaign")?;
+    let overrides = matching_overrides(site_name, tenant.as_deref(), campaign.as_deref());
+
+    let mut leases = vec![];
+    for params in overrides {
+        let scope = params.scope();
+        if let Some(spec) = &params.max_message_rate {
+            let result = spec.throttle(scope.key("message_rate")).await?;
+            if let Some(delay) = result.retry_after {
+                return Ok(ShapingResult::Delay(delay));
+            }
+        }
+
+        // Site-wide connection limits are applied by the ready queue
+        if let (Some(limit), false) = (params.connection_limit, scope.is_site_wide()) {
+            let spec = LimitSpec {
+                limit,
+                duration: lease_duration,
+            };
+            match spec.acquire_lease(scope.key("connection_limit")).await {
+                Ok(lease) => leases.push(lease),
+                Err(throttle::Error::TooManyLeases(delay)) => {
+                    return Ok(ShapingResult::Delay(delay));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    Ok(ShapingResult::Proceed(leases))
+}
+
+/// Trigger the smtp_client_transient_failure event so that policy
+/// can adjust the shaping for the traffic associated with msg
+pub async fn transient_failure(site_name: &str, msg: &Message, response: &Response) {
+    let result: anyhow::Result<()> = async {
+        let tenant = msg.get_meta_string("tenant")?;
+        let campaign = msg.get_meta_string("campaign")?;
+        let mut config = load_config().await?;
+        config
+            .async_call_callback(
+                &TRANSIENT_FAILURE_SIG,
+                (
+                    response.to_single_line(),
+                    site_name.to_string(),
+                    tenant,
+                    campaign,
+                ),
+            )
+            .await
+    }
+    .await;
+
+    if let Err(err) = result {
+        tracing::error!("smtp_client_transient_failure event failed: {err:#}");
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    TRANSIENT_FAILURE_SIG.register();
+
+    let module = get_or_create_sub_module(lua, "shaping")?;
+
+    module.set(
+        "set_override",
+        lua.create_function(|lua, params: Value| {
+            let params: ShapingOverride = from_lua_value(lua, params)?;
+            set_override(params);
+            Ok(())
+        })?,
+    )?;
+
+    module.set(
+        "clear_override",
+        lua.create_function(|lua, scope: Value| {
+            let scope: ShapingScope = from_lua_value(lua, scope)?;
+            Ok(clear_override(&scope))
+        })?,
+    )?;
+
+    module.set(
+        "list_overrides",
+        lua.create_function(|lua, ()| lua.to_value_with(&list_overrides(), serialize_options()))?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scope_matching() {
+        let site = ShapingScope {
+            site_name: "(alt1|alt2)?.example.com".to_string(),
+            tenant: None,
+            campaign: None,
+        };
+        let tenant = ShapingScope {
+            tenant: Some("mytenant".to_string()),
+            ..site.clone()
+        };
+        let campaign = ShapingScope {
+            campaign: Some("newsletter".to_string()),
+            ..tenant.clone()
+        };
+
+        assert!(site.is_site_wide());
+        assert!(!tenant.is_site_wide());
+        assert!(site.matches("(alt1|alt2)?.example.com", None, None));
+        assert!(site.matches("(alt1|alt2)?.example.com", Some("other"), None));
+        assert!(!site.matches("other.site", None, None));
+
+        assert!(tenant.matches("(alt1|alt2)?.example.com", Some("mytenant"), None));
+        assert!(tenant.matches(
+            "(alt1|alt2)?.example.com",
+            Some("mytenant"),
+            Some("newsletter")
+        ));
+        assert!(!tenant.matches("(alt1|alt2)?.example.com", Some("other"), None));
+        assert!(!tenant.matches("(alt1|alt2)?.example.com", None, None));
+
+        assert!(campaign.matches(
+            "(alt1|alt2)?.example.com",
+            Some("mytenant"),
+            Some("newsletter")
+        ));
+        assert!(!campaign.matches("(alt1|alt2)?.example.com", Some("mytenant"), None));
+
+        assert_eq!(
+            campaign.key("message_rate"),
+            "kumomta.shaping.message_rate.(alt1|alt2)?.example.com.mytenant.newsletter"
+        );
+        assert_eq!(
+            site.key("message_rate"),
+            "kumomta.shaping.message_rate.(alt1|alt2)?.example.com.*.*"
+        );
+    }
+
+    #[test]
+    fn site_wide_limits() {
+        let site_name = "site_wide_limits.example.com";
+        let make = |tenant: Option<&str>, connection_limit| ShapingOverride {
+            site_name: site_name.to_string(),
+            tenant: tenant.map(|t| t.to_string()),
+            campaign: None,
+            connection_limit: Some(connection_limit),
+            max_message_rate: None,
+            max_deliveries_per_connection: None,
+            duration: Duration::from_secs(60),
+            reason: None,
+        };
+
+        assert_eq!(site_connection_limit(site_name), None);
+        set_override(make(None, 10));
+        set_override(make(Some("mytenant"), 2));
+        assert_eq!(site_connection_limit(site_name), Some(10));
+        assert_eq!(site_max_deliveries_per_connection(site_name), None);
+
+        // Replaces the earlier site-wide override
+        set_override(make(None, 5));
+        assert_eq!(site_connection_limit(site_name), Some(5));
+
+        assert!(clear_override(&make(None, 0).scope()));
+        assert_eq!(site_connection_limit(site_name), None);
+        assert!(clear_override(&make(Some("mytenant"), 0).scope()));
+        assert!(!clear_override(&make(Some("mytenant"), 0).scope()));
+    }
+}
@@ -0,0 +1,558 @@
+//! Implements IP warm-up: the volume that an egress source may send
+//! to each destination provider is limited per day, according to a
+//! configurable curve that ramps up as the source matures.
+//!
+//! The daily usage is held in memory and periodically written to an
+//! sqlite database, so that it survives a restart.
+use anyhow::Context;
+use chrono::{Days, NaiveDate, Utc};
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use kumo_log_types::RecordType;
+use kumo_server_lifecycle::ShutdownSubcription;
+use kumo_server_runtime::get_main_runtime;
+use mlua::{Lua, LuaSerdeExt, Value};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use serde::{Deserialize, Serialize};
+use spool::SpoolId;
+use sqlite::Connection;
+use std::collections::HashMap;
+use tokio::task::JoinHandle;
+
+static STATE: Lazy<Mutex<WarmupState>> = Lazy::new(|| Mutex::new(WarmupState::default()));
+static FLUSHER: Lazy<JoinHandle<()>> = Lazy::new(|| tokio::task::spawn(flusher()));
+
+/// What to do with messages that exceed the daily limit of a source
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WarmupOverflow {
+    /// Assign them to the other sources in the pool
+    #[default]
+    Spill,
+    /// Delay them until the limit resets the next day
+    Defer,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WarmupSchedule {
+    /// The first day of the warm-up
+    pub start: NaiveDate,
+    /// The limit for each day of the warm-up, per destination.
+    /// Once the warm-up has run for more days than there are
+    /// entries, the source is no longer limited.
+    pub daily_limits: Vec<usize>,
+    #[serde(default)]
+    pub overflow: WarmupOverflow,
+}
+
+impl WarmupSchedule {
+    /// Returns the limit that applies on day, or None if the
+    /// warm-up has completed
+    fn limit_for(&self, day: NaiveDate) -> Option<usize> {
+        let elapsed = (day - self.start).num_days().max(0) as usize;
+        self.daily_limits.get(elapsed).copied()
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WarmupConfig {
+    #[serde(default = "WarmupConfig::default_path")]
+    pub path: String,
+
+    /// The warm-up schedule for each egress source, keyed by
+    /// the name of the source
+    #[serde(default)]
+    pub sources: HashMap<String, WarmupSchedule>,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        Self {
+            path: Self::default_path(),
+            sources: HashMap::new(),
+        }
+    }
+}
+
+impl WarmupConfig {
+    fn default_path() -> String {
+        "/var/spool/kumomta/warmup.db".to_string()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Usage {
+    day: NaiveDate,
+    count: usize,
+}
+
+/// The allowance taken by a message that is being delivered,
+/// which is returned if the attempt fails transiently
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Reservation {
+    source: String,
+    destination: String,
+    day: NaiveDate,
+}
+
+#[derive(Default)]
+struct WarmupState {
+    config: WarmupConfig,
+    /// Keyed by (source, destination)
+    usage: HashMap<(String, String), Usage>,
+    /// Keyed by the id of the message being delivered
+    reservations: HashMap<SpoolId, Reservation>,
+    /// The path from which usage was most recently loaded
+    loaded_path: Option<String>,
+}
+
+/// Whether a source may be used for a destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WarmupStatus {
+    Available,
+    Exhausted(WarmupOverflow),
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct WarmupUsage {
+    pub source: String,
+    pub destination: String,
+    pub day: NaiveDate,
+    pub count: usize,
+    pub limit: Option<usize>,
+}
+
+impl WarmupState {
+    fn count_for(&self, key: &(String, String), day: NaiveDate) -> usize {
+        match self.usage.get(key) {
+            Some(usage) if usage.day == day => usage.count,
+            _ => 0,
+        }
+    }
+
+    fn status(&self, source: &str, destination: &str, day: NaiveDate) -> WarmupStatus {
+        let Some(schedule) = self.config.sources.get(source) else {
+            return WarmupStatus::Available;
+        };
+        let Some(limit) = schedule.limit_for(day) else {
+            return WarmupStatus::Available;
+        };
+        let key = (source.to_string(), destination.to_string());
+        if self.count_for(&key, day) < limit {
+            WarmupStatus::Available
+        } else {
+            WarmupStatus::Exhausted(schedule.overflow)
+        }
+    }
+
+    /// Returns true if source has a warm-up limit on day
+    fn is_warming(&self, source: &str, day: NaiveDate) -> bool {
+        self.config
+            .sources
+            .get(source)
+            .and_then(|schedule| schedule.limit_for(day))
+            .is_some()
+    }
+
+    /// Checks the status of source for destination and, if it
+    /// is available, counts id against its allowance for day
+    fn try_consume(
+        &mut self,
+        id: SpoolId,
+        source: &str,
+        destination: &str,
+        day: NaiveDate,
+    ) -> WarmupStatus {
+        let status = self.status(source, destination, day);
+        if status != WarmupStatus::Available || !self.is_warming(source, day) {
+            return status;
+        }
+        let usage = self
+            .usage
+            .entry((source.to_string(), destination.to_string()))
+            .or_insert(Usage { day, count: 0 });
+        if usage.day != day {
+            *usage = Usage { day, count: 0 };
+        }
+        usage.count += 1;
+        self.reservations.insert(
+            id,
+            Reservation {
+                source: source.to_string(),
+                destination: destination.to_string(),
+                day,
+            },
+        );
+        status
+    }
+
+    /// Settles the reservation of id: a transient failure returns the
+    /// allowance so that the next attempt can use it, while any other
+    /// outcome means that the message was sent to the destination
+    fn record_disposition(&mut self, kind: RecordType, id: SpoolId) {
+        let Some(reservation) = self.reservations.remove(&id) else {
+            return;
+        };
+        if kind != RecordType::TransientFailure {
+            return;
+        }
+        if let Some(usage) = self
+            .usage
+            .get_mut(&(reservation.source, reservation.destination))
+        {
+            if usage.day == reservation.day {
+                usage.count = usage.count.saturating_sub(1);
+            }
+        }
+    }
+
+    fn list(&self, day: NaiveDate) -> Vec<WarmupUsage> {
+        let mut result: Vec<_> = self
+            .usage
+            .iter()
+            .filter(|(_, usage)| usage.day == day)
+            .map(|((source, destination), usage)| WarmupUsage {
+                source: source.to_string(),
+                destination: destination.to_string(),
+                day: usage.day,
+                count: usage.count,
+                limit: self
+                    .config
+                    .sources
+                    .get(source)
+                    .and_then(|schedule| schedule.limit_for(day)),
+            })
+            .collect();
+        result.sort_by(|a, b| (&a.source, &a.destination).cmp(&(&b.source, &b.destination)));
+        result
+    }
+}
+
+fn today() -> NaiveDate {
+    Utc::now().date_naive()
+}
+
+/// Returns the status of source for destination today
+pub fn status(source: &str, destination: &str) -> WarmupStatus {
+    let state = STATE.lock();
+    if state.config.sources.is_empty() {
+        return WarmupStatus::Available;
+    }
+    state.status(source, destination, today())
+}
+
+/// Returns true if source is subject to a warm-up limit today,
+/// and so is not a suitable recipient of the overflow of others
+pub fn is_warming(source: &str) -> bool {
+    let state = STATE.lock();
+    if state.config.sources.is_empty() {
+        return false;
+    }
+    state.is_warming(source, today())
+}
+
+/// Atomically checks whether the message id may be sent from source
+/// to destination and, if so, counts it against today's allowance.
+/// The allowance is returned if the attempt fails transiently.
+pub fn try_consume(id: SpoolId, source: &str, destination: &str) -> WarmupStatus {
+    let mut state = STATE.lock();
+    if state.config.sources.is_empty() {
+        return WarmupStatus::Available;
+    }
+    let status = state.try_consume(id, source, destination, today());
+    drop(state);
+    // Ensure that the usage will be persisted
+    Lazy::force(&FLUSHER);
+    status
+}
+
+/// Settles the allowance taken by try_consume for the message id
+pub fn record_disposition(kind: RecordType, id: SpoolId) {
+    let mut state = STATE.lock();
+    if state.reservations.is_empty() {
+        return;
+    }
+    state.record_disposition(kind, id);
+}
+
+/// Returns the duration until the daily limits reset
+pub fn time_until_reset() -> chrono::Duration {
+    let now = Utc::now();
+    let tomorrow = now
+        .date_naive()
+        .checked_add_days(Days::new(1))
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|midnight| midnight.and_utc());
+    match tomorrow {
+        Some(tomorrow) => tomorrow - now,
+        None => chrono::Duration::hours(1),
+    }
+}
+
+fn open_db(path: &str) -> anyhow::Result<Connection> {
+    let db = Connection::open(path).with_context(|| format!("opening warm-up database {path}"))?;
+    init_db(&db)?;
+    Ok(db)
+}
+
+fn init_db(db: &Connection) -> anyhow::Result<()> {
+    let query = r#"
+CREATE TABLE IF NOT EXISTS warmup_usage (
+    source text NOT NULL,
+    destination text NOT NULL,
+    day text NOT NULL,
+    count int NOT NULL,
+    PRIMARY KEY (source, destination)
+);
+    "#;
+    db.execute(query)?;
+    Ok(())
+}
+
+fn load_usage(db: &Connection, day: NaiveDate) -> anyhow::Result<HashMap<(String, String), Usage>> {
+    let mut stmt = db.prepare("SELECT * FROM warmup_usage WHERE day=$day")?;
+    stmt.bind(("$day", day.to_string().as_str()))?;
+    let mut usage = HashMap::new();
+    while let sqlite::State::Row = stmt.next()? {
+        let source: String = stmt.read("source")?;
+        let destination: String = stmt.read("destination")?;
+        let count: i64 = stmt.read("count")?;
+        usage.insert(
+            (source, destination),
+            Usage {
+                day,
+                count: count as usize,
+            },
+        );
+    }
+    Ok(usage)
+}
+
+fn store_usage(db: &Connection, usage: &HashMap<(String, String), Usage>) -> anyhow::Result<()> {
+    db.execute("BEGIN")?;
+    let result = (|| {
+        let mut upsert = db.prepare(
+            "INSERT INTO warmup_usage (source, destination, day, count)
+                VALUES ($source, $destination, $day, $count)
+                ON CONFLICT (source, destination)
+                DO UPDATE SET day=$day, count=$count",
+        )?;
+        for ((source, destination), usage) in usage {
+            upsert.reset()?;
+            upsert.bind(("$source", source.as_str()))?;
+            upsert.bind(("$destination", destination.as_str()))?;
+            upsert.bind(("$day", usage.day.to_string().as_str()))?;
+            upsert.bind(("$count", usage.count as i64))?;
+            upsert.next()?;
+        }
+        Ok(())
+    })();
+    match result {
+        Ok(()) => {
+            db.execute("COMMIT")?;
+            Ok(())
+        }
+        Err(err) => {
+            db.execute("ROLLBACK").ok();
+            Err(err)
+        }
+    }
+}
+
+fn flush() -> anyhow::Result<()> {
+    let (path, usage) = {
+        let mut state = STATE.lock();
+        let day = today();
+        // Messages that were never dispositioned, eg: because they
+        // were expired or removed, must not hold on to their
+        // reservations forever
+        state
+            .reservations
+            .retain(|_, reservation| reservation.day == day);
+        let usage: HashMap<_, _> = state
+            .usage
+            .iter()
+            .filter(|(_, usage)| usage.day == day)
+            .map(|(key, usage)| (key.clone(), *usage))
+            .collect();
+        (state.config.path.clone(), usage)
+    };
+    if usage.is_empty() {
+        return Ok(());
+    }
+    let db = open_db(&path)?;
+    store_usage(&db, &usage)
+}
+
+async fn flusher() {
+    let mut shutdown = ShutdownSubcription::get();
+    loop {
+        let shutting_down = tokio::select! {
+            _ = shutdown.shutting_down() => true,
+            _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => false,
+        };
+
+        match get_main_runtime().spawn_blocking(flush).await {
+            Ok(Err(err)) => tracing::error!("Error flushing warm-up usage: {err:#}"),
+            Err(err) => tracing::error!("Error flushing warm-up usage: {err:#}"),
+            Ok(Ok(())) => {}
+        }
+
+        if shutting_down {
+            break;
+        }
+    }
+}
+
+fn configure(config: WarmupConfig) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+
+    let mut state = STATE.lock();
+    if state.loaded_path.as_deref() != Some(config.path.as_str()) {
+        let db = open_db(&config.path)?;
+        state.usage = load_usage(&db, today())?;
+        state.loaded_path.replace(config.path.clone());
+    }
+    state.config = config;
+    Ok(())
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "warmup")?;
+
+    module.set(
+        "configure",
+        lua.create_function(|lua, params: Value| {
+            let config: WarmupConfig = from_lua_value(lua, params)?;
+            configure(config).map_err(any_err)
+        })?,
+    )?;
+
+    module.set(
+        "list",
+        lua.create_function(|lua, ()| {
+            let usage = STATE.lock().list(today());
+            lua.to_value(&usage)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn day(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn state(overflow: WarmupOverflow) -> WarmupState {
+        let mut state = WarmupState::default();
+        state.config.sources.insert(
+            "ip-1".to_string(),
+            WarmupSchedule {
+                start: day("2024-06-01"),
+                daily_limits: vec![2, 4],
+                overflow,
+            },
+        );
+        state
+    }
+
+    fn consume(state: &mut WarmupState, source: &str, destination: &str, day: NaiveDate) {
+        state.try_consume(SpoolId::new(), source, destination, day);
+    }
+
+    #[test]
+    fn limits_follow_curve() {
+        let mut state = state(WarmupOverflow::Defer);
+        let d1 = day("2024-06-01");
+
+        assert_eq!(state.status("ip-1", "gmail", d1), WarmupStatus::Available);
+        consume(&mut state, "ip-1", "gmail", d1);
+        consume(&mut state, "ip-1", "gmail", d1);
+        assert_eq!(
+            state.try_consume(SpoolId::new(), "ip-1", "gmail", d1),
+            WarmupStatus::Exhausted(WarmupOverflow::Defer)
+        );
+        // Each destination has its own allowance
+        assert_eq!(state.status("ip-1", "yahoo", d1), WarmupStatus::Available);
+        // Unscheduled sources are never limited
+        assert_eq!(state.status("ip-2", "gmail", d1), WarmupStatus::Available);
+
+        // The usage resets the next day, with a higher limit
+        let d2 = day("2024-06-02");
+        for _ in 0..4 {
+            assert_eq!(
+                state.try_consume(SpoolId::new(), "ip-1", "gmail", d2),
+                WarmupStatus::Available
+            );
+        }
+        assert_eq!(
+            state.status("ip-1", "gmail", d2),
+            WarmupStatus::Exhausted(WarmupOverflow::Defer)
+        );
+
+        // The warm-up has completed
+        let d3 = day("2024-06-03");
+        assert!(!state.is_warming("ip-1", d3));
+        for _ in 0..3 {
+            consume(&mut state, "ip-1", "gmail", d3);
+        }
+        assert_eq!(state.status("ip-1", "gmail", d3), WarmupStatus::Available);
+        // and its usage is no longer counted
+        assert!(state.list(d3).is_empty());
+        assert!(state.reservations.is_empty());
+    }
+
+    #[test]
+    fn transient_failures_return_allowance() {
+        let mut state = state(WarmupOverflow::Spill);
+        let d1 = day("2024-06-01");
+        let delivered = SpoolId::new();
+        let deferred = SpoolId::new();
+
+        assert_eq!(
+            state.try_consume(delivered, "ip-1", "gmail", d1),
+            WarmupStatus::Available
+        );
+        assert_eq!(
+            state.try_consume(deferred, "ip-1", "gmail", d1),
+            WarmupStatus::Available
+        );
+        assert_eq!(
+            state.status("ip-1", "gmail", d1),
+            WarmupStatus::Exhausted(WarmupOverflow::Spill)
+        );
+
+        state.record_disposition(RecordType::Delivery, delivered);
+        state.record_disposition(RecordType::TransientFailure, deferred);
+        assert_eq!(state.status("ip-1", "gmail", d1), WarmupStatus::Available);
+        assert_eq!(state.list(d1)[0].count, 1);
+        assert!(state.reservations.is_empty());
+
+        // Subsequent dispositions of the same message are not counted again
+        state.record_disposition(RecordType::TransientFailure, delivered);
+        assert_eq!(state.list(d1)[0].count, 1);
+    }
+
+    #[test]
+    fn persistence() {
+        let db = Connection::open(":memory:").unwrap();
+        init_db(&db).unwrap();
+
+        let mut state = state(WarmupOverflow::Spill);
+        let d1 = day("2024-06-01");
+        consume(&mut state, "ip-1", "gmail", d1);
+        consume(&mut state, "ip-1", "gmail", d1);
+        store_usage(&db, &state.usage).unwrap();
+
+        let loaded = load_usage(&db, d1).unwrap();
+        assert_eq!(loaded, state.usage);
+        assert!(load_usage(&db, day("2024-06-02")).unwrap().is_empty());
+    }
+}
@@ -73,6 +73,44 @@ impl<K: Hash + Eq, V: Clone> LruCacheWithTtl<K, V> {
         item
     }
 
+    /// Removes the item, returning it if it had not expired
+    pub fn remove<Q: ?Sized>(&self, name: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let entry = self.cache.lock().remove(name)?;
+        if Instant::now() < entry.expiration {
+            Some(entry.item)
+        } else {
+            None
+        }
+    }
+
+    /// Atomically replaces the item with the value returned by `func`.
+    /// `func` is passed the existing item and its expiration, if it has
+    /// not expired, and returns the new item and its expiration.
+    /// If `func` returns an error, the cache is not modified.
+    pub fn update<E, F>(&self, name: K, func: F) -> Result<V, E>
+    where
+        F: FnOnce(Option<(&V, Instant)>) -> Result<(V, Instant), E>,
+    {
+        let mut cache = self.cache.lock();
+        let existing = cache
+            .get_mut(&name)
+            .filter(|entry| Instant::now() < entry.expiration)
+            .map(|entry| (&entry.item, entry.expiration));
+        let (item, expiration) = func(existing)?;
+        cache.insert(
+            name,
+            Item {
+                item: item.clone(),
+                expiration,
+            },
+        );
+        Ok(item)
+    }
+
     /// Get an existing item, but if that item doesn't already exist,
     /// call `func` to provide a value that will be inserted and then
     /// returned.  This is done atomically wrt. other callers.
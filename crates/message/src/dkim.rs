@@ -1,4 +1,5 @@
 use anyhow::Context;
+use config::epoch::{get_current_epoch, ConfigEpoch};
 use config::{from_lua_value, get_or_create_sub_module};
 use data_loader::KeySource;
 use kumo_dkim::DkimPrivateKey;
@@ -11,7 +12,9 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 lazy_static::lazy_static! {
-    static ref SIGNER_CACHE: LruCacheWithTtl<SignerConfig, Arc<CFSigner>> = LruCacheWithTtl::new(1024);
+    /// Keyed by the configuration epoch as well as the signer config,
+    /// so that bumping the epoch causes keys to be loaded again
+    static ref SIGNER_CACHE: LruCacheWithTtl<(ConfigEpoch, SignerConfig), Arc<CFSigner>> = LruCacheWithTtl::new(1024);
     static ref SIGNER_KEY_FETCH: Histogram = prometheus::register_histogram!(
         "dkim_signer_key_fetch",
         "how long it takes to obtain a dkim key").unwrap();
@@ -139,6 +142,25 @@ impl Signer {
     pub fn sign(&self, message: &[u8]) -> anyhow::Result<String> {
         self.0.sign(message)
     }
+
+    /// Sign message with each of signers, parsing the message and
+    /// computing each distinct body hash only once
+    pub fn sign_multiple(signers: &[Signer], message: &[u8]) -> anyhow::Result<Vec<String>> {
+        let parse_timer = SIGNER_PARSE.start_timer();
+        let message_str =
+            std::str::from_utf8(message).context("DKIM signer: message is not ASCII or UTF-8")?;
+        let mail = kumo_dkim::ParsedEmail::parse(message_str)
+            .context("failed to parse message to pass to dkim signer")?;
+        parse_timer.stop_and_record();
+
+        let sign_timer = SIGNER_SIGN.start_timer();
+        let signers: Vec<&kumo_dkim::Signer> =
+            signers.iter().map(|signer| &signer.0.signer).collect();
+        let headers = kumo_dkim::Signer::sign_multiple(&signers, &mail)?;
+        sign_timer.stop_and_record();
+
+        Ok(headers)
+    }
 }
 
 impl LuaUserData for Signer {}
@@ -149,9 +171,11 @@ pub fn register<'lua>(lua: &'lua Lua) -> anyhow::Result<()> {
         "rsa_sha256_signer",
         lua.create_async_function(|lua, params: Value| async move {
             let params: SignerConfig = from_lua_value(lua, params)?;
+            let cache_key = (get_current_epoch(), params);
+            let params = &cache_key.1;
 
             SIGNER_CACHE_LOOKUP.inc();
-            if let Some(inner) = SIGNER_CACHE.get(&params) {
+            if let Some(inner) = SIGNER_CACHE.get(&cache_key) {
                 SIGNER_CACHE_HIT.inc();
                 return Ok(Signer(inner));
             }
@@ -176,7 +200,7 @@ pub fn register<'lua>(lua: &'lua Lua) -> anyhow::Result<()> {
             let inner = Arc::new(CFSigner { signer });
 
             let expiration = Instant::now() + Duration::from_secs(params.ttl);
-            SIGNER_CACHE.insert(params, Arc::clone(&inner), expiration);
+            SIGNER_CACHE.insert(cache_key, Arc::clone(&inner), expiration);
 
             signer_creation_timer.stop_and_record();
             Ok(Signer(inner))
@@ -187,8 +211,10 @@ pub fn register<'lua>(lua: &'lua Lua) -> anyhow::Result<()> {
         "ed25519_signer",
         lua.create_async_function(|lua, params: Value| async move {
             let params: SignerConfig = from_lua_value(lua, params)?;
+            let cache_key = (get_current_epoch(), params);
+            let params = &cache_key.1;
 
-            if let Some(inner) = SIGNER_CACHE.get(&params) {
+            if let Some(inner) = SIGNER_CACHE.get(&cache_key) {
                 return Ok(Signer(inner));
             }
 
@@ -211,7 +237,7 @@ pub fn register<'lua>(lua: &'lua Lua) -> anyhow::Result<()> {
             let inner = Arc::new(CFSigner { signer });
 
             let expiration = Instant::now() + Duration::from_secs(params.ttl);
-            SIGNER_CACHE.insert(params, Arc::clone(&inner), expiration);
+            SIGNER_CACHE.insert(cache_key, Arc::clone(&inner), expiration);
 
             signer_creation_timer.stop_and_record();
             Ok(Signer(inner))
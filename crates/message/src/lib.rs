@@ -2,8 +2,11 @@ pub mod address;
 #[cfg(feature = "impl")]
 pub mod dkim;
 pub mod message;
+pub mod mime;
 pub mod queue_name;
 pub mod scheduling;
+#[cfg(feature = "impl")]
+pub mod tracking;
 
 pub use crate::address::EnvelopeAddress;
 pub use crate::message::Message;
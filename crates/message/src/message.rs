@@ -1,8 +1,11 @@
 use crate::address::HeaderAddressList;
 #[cfg(feature = "impl")]
 use crate::dkim::Signer;
+use crate::mime::{self, MimePartInfo, NewMimePart};
 pub use crate::queue_name::QueueNameComponents;
 use crate::scheduling::Scheduling;
+#[cfg(feature = "impl")]
+use crate::tracking::{Tracker, TrackingOptions};
 use crate::EnvelopeAddress;
 use anyhow::Context;
 use chrono::{DateTime, Utc};
@@ -20,8 +23,9 @@ use kumo_log_types::rfc5965::ARFReport;
 use mailparsing::{AuthenticationResult, AuthenticationResults, EncodeHeaderValue};
 use mailparsing::{DecodedBody, Header, HeaderParseResult, MessageConformance, MimePart};
 #[cfg(feature = "impl")]
-use mlua::{LuaSerdeExt, UserData, UserDataMethods};
+use mlua::{FromLua, LuaSerdeExt, UserData, UserDataMethods};
 use prometheus::{Histogram, IntGauge};
+use rfc5321::{DeliverBy, DsnMailParameters, DsnNotify, DsnRcptParameters, DsnReturn};
 use serde::{Deserialize, Serialize};
 use spool::{get_data_spool, get_meta_spool, Spool, SpoolId};
 use std::hash::Hash;
@@ -43,6 +47,10 @@ bitflags::bitflags! {
     }
 }
 
+/// The metadata key in which dkim_sign records the names of
+/// the headers that are covered by the signatures it has added
+const DKIM_SIGNED_HEADERS_META: &str = "dkim_signed_headers";
+
 lazy_static::lazy_static! {
     static ref MESSAGE_COUNT: IntGauge = prometheus::register_int_gauge!(
         "message_count",
@@ -77,6 +85,9 @@ struct MessageInner {
     flags: MessageFlags,
     num_attempts: u16,
     due: Option<DateTime<Utc>>,
+    /// A copy of the priority from the metadata, so that it remains
+    /// available after the metadata has been shrunk away
+    priority: i32,
 }
 
 #[derive(Debug)]
@@ -127,6 +138,8 @@ struct MetaData {
     meta: serde_json::Value,
     #[serde(default)]
     schedule: Option<Scheduling>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<i32>,
 }
 
 impl Drop for MessageInner {
@@ -164,11 +177,13 @@ impl Message {
                         recipient,
                         meta,
                         schedule: None,
+                        priority: None,
                     })),
                     data,
                     flags: MessageFlags::META_DIRTY | MessageFlags::DATA_DIRTY,
                     num_attempts: 0,
                     due: None,
+                    priority: 0,
                 }),
             }),
         })
@@ -193,6 +208,7 @@ impl Message {
         } else {
             MessageFlags::empty()
         };
+        let priority = metadata.priority.unwrap_or(0);
 
         Ok(Self {
             msg_and_id: Arc::new(MessageWithId {
@@ -203,6 +219,7 @@ impl Message {
                     flags,
                     num_attempts: 0,
                     due: None,
+                    priority,
                 }),
             }),
         })
@@ -230,6 +247,18 @@ impl Message {
     }
 
     pub fn set_scheduling(&self, scheduling: Option<Scheduling>) -> anyhow::Result<()> {
+        if let Some(Scheduling {
+            first_attempt: Some(first_attempt),
+            expires: Some(expires),
+            ..
+        }) = &scheduling
+        {
+            anyhow::ensure!(
+                expires > first_attempt,
+                "expires {expires} must be later than first_attempt {first_attempt}"
+            );
+        }
+
         let mut inner = self.msg_and_id.inner.lock().unwrap();
         match &mut inner.metadata {
             None => anyhow::bail!("metadata must be loaded first"),
@@ -247,6 +276,37 @@ impl Message {
         }
     }
 
+    pub fn get_scheduling(&self) -> anyhow::Result<Option<Scheduling>> {
+        let inner = self.msg_and_id.inner.lock().unwrap();
+        match &inner.metadata {
+            None => anyhow::bail!("metadata must be loaded first"),
+            Some(meta) => Ok(meta.schedule),
+        }
+    }
+
+    /// Set the priority of the message. Within a ready queue,
+    /// messages with a higher priority are dispatched ahead of
+    /// those with a lower priority. The default priority is 0.
+    pub fn set_priority(&self, priority: i32) -> anyhow::Result<()> {
+        let mut inner = self.msg_and_id.inner.lock().unwrap();
+        match &mut inner.metadata {
+            None => anyhow::bail!("metadata must be loaded first"),
+            Some(meta) => {
+                meta.priority = (priority != 0).then_some(priority);
+                inner.priority = priority;
+                inner.flags.set(MessageFlags::META_DIRTY, true);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the priority of the message. This is available
+    /// even when the metadata is not loaded.
+    pub fn get_priority(&self) -> i32 {
+        let inner = self.msg_and_id.inner.lock().unwrap();
+        inner.priority
+    }
+
     pub fn get_due(&self) -> Option<DateTime<Utc>> {
         let inner = self.msg_and_id.inner.lock().unwrap();
         inner.due
@@ -428,6 +488,22 @@ impl Message {
         Ok(did_shrink)
     }
 
+    /// Release the in-memory copy of the message data, retaining the
+    /// metadata.  The data can be loaded again from the spool on demand.
+    /// Returns true if the data was released.
+    pub fn unload_data(&self) -> anyhow::Result<bool> {
+        let mut inner = self.msg_and_id.inner.lock().unwrap();
+        if inner.flags.contains(MessageFlags::DATA_DIRTY) {
+            anyhow::bail!("Cannot unload data: DATA_DIRTY");
+        }
+        if inner.data.is_empty() {
+            return Ok(false);
+        }
+        DATA_COUNT.dec();
+        inner.data = NO_DATA.clone();
+        Ok(true)
+    }
+
     pub fn sender(&self) -> anyhow::Result<EnvelopeAddress> {
         let inner = self.msg_and_id.inner.lock().unwrap();
         match &inner.metadata {
@@ -610,6 +686,66 @@ impl Message {
         self.msg_and_id.id.age(now)
     }
 
+    /// If the message was received with an RFC 2852 DELIVERBY request,
+    /// returns the absolute deadline together with the request, where
+    /// the by-time has been adjusted to reflect the time remaining
+    /// relative to `now`.
+    pub fn get_deliver_by(
+        &self,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Option<(DateTime<Utc>, DeliverBy)>> {
+        let Some(deadline) = self.get_meta_string("deliver_by")? else {
+            return Ok(None);
+        };
+        let deadline = DateTime::parse_from_rfc3339(&deadline)
+            .with_context(|| format!("parsing deliver_by {deadline}"))?
+            .with_timezone(&Utc);
+        let mode = self
+            .get_meta_string("deliver_by_mode")?
+            .unwrap_or_else(|| "N".to_string());
+        let (mode, trace) = DeliverBy::parse_mode(&mode)
+            .ok_or_else(|| anyhow::anyhow!("invalid deliver_by_mode {mode}"))?;
+
+        let by = DeliverBy {
+            by_time: 0,
+            mode,
+            trace,
+        }
+        .with_remaining((deadline - now).num_seconds());
+
+        Ok(Some((deadline, by)))
+    }
+
+    /// Returns the RFC 3461 DSN parameters that were specified in the
+    /// MAIL FROM command with which the message was received, as
+    /// recorded in the `dsn_ret` and `dsn_envid` meta values.
+    pub fn get_dsn_mail_parameters(&self) -> anyhow::Result<DsnMailParameters> {
+        let ret = match self.get_meta_string("dsn_ret")? {
+            Some(ret) => Some(
+                DsnReturn::parse(&ret).ok_or_else(|| anyhow::anyhow!("invalid dsn_ret {ret}"))?,
+            ),
+            None => None,
+        };
+        Ok(DsnMailParameters {
+            ret,
+            envid: self.get_meta_string("dsn_envid")?,
+        })
+    }
+
+    /// Returns the RFC 3461 DSN parameters that were specified in the
+    /// RCPT TO command for the recipient of the message, as recorded
+    /// in the `dsn_notify` and `dsn_orcpt` meta values.
+    pub fn get_dsn_rcpt_parameters(&self) -> anyhow::Result<DsnRcptParameters> {
+        let notify = match self.get_meta_string("dsn_notify")? {
+            Some(notify) => Some(DsnNotify::parse(&notify).map_err(|err| anyhow::anyhow!(err))?),
+            None => None,
+        };
+        Ok(DsnRcptParameters {
+            notify,
+            orcpt: self.get_meta_string("dsn_orcpt")?,
+        })
+    }
+
     pub fn get_queue_name(&self) -> anyhow::Result<String> {
         Ok(match self.get_meta_string("queue")? {
             Some(name) => name,
@@ -722,6 +858,64 @@ impl Message {
         }
     }
 
+    /// Replaces the first header named name with `name: value`,
+    /// keeping its position, and removes any other headers with that
+    /// name. If there is no such header, it is appended.
+    pub fn set_header(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        let data = self.get_data();
+        let HeaderParseResult {
+            headers,
+            body_offset,
+            ..
+        } = Header::parse_headers(data.as_ref().as_ref())?;
+
+        let mut new_data = Vec::with_capacity(size_header(Some(name), value) + 2 + data.len());
+        let mut replaced = false;
+        for hdr in headers.iter() {
+            if hdr.get_name().eq_ignore_ascii_case(name) {
+                if !replaced {
+                    emit_header(&mut new_data, Some(name), value);
+                    replaced = true;
+                }
+                continue;
+            }
+            hdr.write_header(&mut new_data)?;
+        }
+        if !replaced {
+            emit_header(&mut new_data, Some(name), value);
+        }
+        new_data.extend_from_slice(b"\r\n");
+        new_data.extend_from_slice(&data[body_offset..]);
+        self.assign_data(new_data);
+        Ok(())
+    }
+
+    /// Returns an error if a header for which would_modify returns
+    /// true is covered by a DKIM signature that dkim_sign added to
+    /// this message, as modifying that header would invalidate our
+    /// signature. Signatures that were already present when the
+    /// message was received are not considered.
+    /// The metadata must be loaded.
+    pub fn check_headers_not_signed<F: Fn(&str) -> bool>(
+        &self,
+        would_modify: F,
+    ) -> anyhow::Result<()> {
+        let signed: Vec<String> = match self.get_meta(DKIM_SIGNED_HEADERS_META)? {
+            serde_json::Value::Null => return Ok(()),
+            value => serde_json::from_value(value)?,
+        };
+        for name in signed {
+            if would_modify(&name) {
+                anyhow::bail!(
+                    "cannot modify the {name} header as it is covered \
+                     by a DKIM signature; modify headers before calling \
+                     msg:dkim_sign"
+                );
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_address_header(
         &self,
         header_name: &str,
@@ -843,7 +1037,43 @@ impl Message {
         let data = self.get_data();
         let header = signer.sign(&data)?;
         self.prepend_header(None, &header);
-        Ok(())
+        self.set_dkim_signed(&[header])
+    }
+
+    /// Sign the message with each of signers.  The resulting
+    /// DKIM-Signature headers appear in the same order as signers.
+    /// Hashing a large body is CPU intensive, so the signatures are
+    /// computed on a blocking thread rather than the async executor.
+    #[cfg(feature = "impl")]
+    pub async fn dkim_sign_multiple(&self, signers: Vec<Signer>) -> anyhow::Result<()> {
+        let data = self.get_data();
+        let headers =
+            tokio::task::spawn_blocking(move || Signer::sign_multiple(&signers, &data)).await??;
+        for header in headers.iter().rev() {
+            self.prepend_header(None, header);
+        }
+        self.set_dkim_signed(&headers)
+    }
+
+    /// Record the names of the headers covered by the DKIM-Signature
+    /// headers that we have just added in the metadata, so that
+    /// check_headers_not_signed continues to protect them after the
+    /// message is reloaded from spool
+    #[cfg(feature = "impl")]
+    fn set_dkim_signed(&self, signatures: &[String]) -> anyhow::Result<()> {
+        let mut signed: Vec<String> = match self.get_meta(DKIM_SIGNED_HEADERS_META)? {
+            serde_json::Value::Null => vec![],
+            value => serde_json::from_value(value)?,
+        };
+        for name in signatures
+            .iter()
+            .flat_map(|sig| dkim_signed_header_names(sig))
+        {
+            if !is_header_in_names_list(&name, &signed) {
+                signed.push(name);
+            }
+        }
+        self.set_meta(DKIM_SIGNED_HEADERS_META, signed)
     }
 
     pub fn import_scheduling_header(&self, header_name: &str, remove: bool) -> anyhow::Result<()> {
@@ -922,6 +1152,106 @@ impl Message {
         }
     }
 
+    /// Rewrites the links in, and/or adds a tracking pixel to, the html
+    /// part of the message.  Since this changes the content, any existing
+    /// DKIM signatures are removed; the message should be signed after
+    /// calling this.  Returns false if there is no html part.
+    #[cfg(feature = "impl")]
+    pub fn add_tracking(&self, options: &TrackingOptions, key: &[u8]) -> anyhow::Result<bool> {
+        let data = self.get_data();
+        let mut msg = MimePart::parse(data.as_ref().as_ref())?;
+        let parts = msg.simplified_structure_pointers()?;
+        let Some(p) = parts.html_part.and_then(|p| msg.resolve_ptr_mut(p)) else {
+            return Ok(false);
+        };
+        let html = match p.body()? {
+            DecodedBody::Text(text) => text.as_str().to_string(),
+            DecodedBody::Binary(_) => {
+                anyhow::bail!("expected text/html part to be text, but it is binary");
+            }
+        };
+
+        let id = self.id().to_string();
+        let recipient = self.recipient()?.to_string();
+        let html = Tracker::new(key, &id, &recipient, options).apply(&html)?;
+        p.replace_text_body("text/html", &html);
+
+        let new_data = msg.to_message_string();
+        self.assign_data(new_data.into_bytes());
+        self.remove_all_named_headers("DKIM-Signature")?;
+        Ok(true)
+    }
+
+    pub fn get_mime_structure(&self) -> anyhow::Result<MimePartInfo> {
+        let data = self.get_data();
+        let msg = MimePart::parse(data.as_ref().as_ref())?;
+        MimePartInfo::new(&msg, String::new())
+    }
+
+    pub fn get_mime_part_body(&self, section: &str) -> anyhow::Result<Vec<u8>> {
+        let data = self.get_data();
+        let msg = MimePart::parse(data.as_ref().as_ref())?;
+        mime::get_part_body(&msg, section)
+    }
+
+    /// Parses the message, allows func to modify the MIME tree,
+    /// and then assigns the resulting content to the message
+    fn edit_mime<F: FnOnce(&mut MimePart) -> anyhow::Result<()>>(
+        &self,
+        func: F,
+    ) -> anyhow::Result<()> {
+        let data = self.get_data();
+        let mut msg = MimePart::parse(data.as_ref().as_ref())?;
+        func(&mut msg)?;
+        let new_data = msg.to_message_string();
+        self.assign_data(new_data.into_bytes());
+        Ok(())
+    }
+
+    pub fn add_mime_part(&self, part: &NewMimePart, section: Option<&str>) -> anyhow::Result<()> {
+        let part = part.build()?;
+        self.edit_mime(|msg| mime::add_part(msg, part, section))
+    }
+
+    pub fn replace_mime_part(&self, section: &str, part: &NewMimePart) -> anyhow::Result<()> {
+        let part = part.build()?;
+        self.edit_mime(|msg| mime::replace_part(msg, section, part))
+    }
+
+    pub fn remove_mime_part(&self, section: &str) -> anyhow::Result<()> {
+        self.edit_mime(|msg| mime::remove_part(msg, section))
+    }
+
+    /// Re-encodes each part of the message using an appropriate
+    /// transfer encoding, and regenerates the multipart boundaries
+    pub fn rebuild_mime(&self) -> anyhow::Result<()> {
+        let data = self.get_data();
+        let msg = MimePart::parse(data.as_ref().as_ref())?;
+        let new_data = msg.rebuild()?.to_message_string();
+        self.assign_data(new_data.into_bytes());
+        Ok(())
+    }
+
+    /// Records the size of the message in the `message_size` meta
+    /// value and, if `detect_attachments` is true, whether it has any
+    /// attachments in the `has_attachments` meta value, so that policy
+    /// can route on them without having to parse the message itself.
+    /// Detecting attachments requires parsing the message, and
+    /// `has_attachments` is not set if the message cannot be parsed.
+    pub fn set_content_meta(&self, detect_attachments: bool) -> anyhow::Result<()> {
+        let data = self.get_data();
+        self.set_meta("message_size", data.len())?;
+        if !detect_attachments {
+            return Ok(());
+        }
+        if let Ok(structure) = MimePart::parse(data.as_ref().as_ref())
+            .and_then(|msg| msg.simplified_structure_pointers())
+        {
+            self.set_meta("has_attachments", !structure.attachments.is_empty())?;
+        }
+        Ok(())
+    }
+
     pub fn check_fix_conformance(
         &self,
         check: MessageConformance,
@@ -982,6 +1312,22 @@ impl Message {
     }
 }
 
+/// Returns the names listed in the h= tag of a DKIM-Signature
+#[cfg(feature = "impl")]
+fn dkim_signed_header_names(signature: &str) -> Vec<String> {
+    signature
+        .split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .find(|(name, _)| name.trim() == "h")
+        .map(|(_, list)| {
+            list.split(':')
+                .map(|name| name.chars().filter(|c| !c.is_whitespace()).collect())
+                .filter(|name: &String| !name.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 fn is_header_in_names_list(hdr_name: &str, names: &[String]) -> bool {
     for name in names {
         if hdr_name.eq_ignore_ascii_case(name) {
@@ -1019,8 +1365,32 @@ fn emit_header(dest: &mut Vec<u8>, name: Option<&str>, value: &str) {
     }
 }
 
+#[cfg(feature = "impl")]
+fn check_header_not_signed(msg: &Message, name: &str) -> mlua::Result<()> {
+    msg.check_headers_not_signed(|n| n.eq_ignore_ascii_case(name))
+        .map_err(any_err)
+}
+
+/// When encode is true, folds value and applies RFC 2047 encoding
+/// to any non-ASCII words, so that it is suitable for use as the
+/// value of the header name
+#[cfg(feature = "impl")]
+fn maybe_encode_header_value(name: &str, value: String, encode: Option<bool>) -> String {
+    if encode.unwrap_or(false) {
+        Header::new_unstructured(name, value)
+            .get_raw_value()
+            .to_string()
+    } else {
+        value
+    }
+}
+
 #[cfg(feature = "impl")]
 impl UserData for Message {
+    /// Methods that examine or modify the message content load the data
+    /// from the spool on demand.  Methods that only use the metadata do
+    /// not, so that policy which doesn't examine the content never forces
+    /// it into memory.
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method(
             "set_meta",
@@ -1034,23 +1404,92 @@ impl UserData for Message {
             let value = this.get_meta(name).map_err(any_err)?;
             Ok(Some(lua.to_value_with(&value, serialize_options())?))
         });
-        methods.add_method("get_data", move |lua, this, _: ()| {
+        methods.add_async_method("get_data", |lua, this, _: ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             let data = this.get_data();
             lua.create_string(&*data)
         });
+        methods.add_method("is_data_loaded", move |_, this, _: ()| {
+            Ok(this.is_data_loaded())
+        });
+        methods.add_async_method("load_data", |_, this, _: ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)
+        });
+        methods.add_method("unload_data", move |_, this, _: ()| {
+            Ok(this.unload_data().map_err(any_err)?)
+        });
         methods.add_method("set_data", move |_lua, this, data: mlua::String| {
             this.assign_data(data.as_bytes().to_vec());
             Ok(())
         });
 
-        methods.add_method("append_text_plain", move |_lua, this, data: String| {
+        methods.add_async_method("append_text_plain", |_lua, this, data: String| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             this.append_text_plain(&data).map_err(any_err)
         });
 
-        methods.add_method("append_text_html", move |_lua, this, data: String| {
+        methods.add_async_method("append_text_html", |_lua, this, data: String| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             this.append_text_html(&data).map_err(any_err)
         });
 
+        methods.add_async_method(
+            "add_tracking",
+            |lua, this, params: mlua::Value| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
+                let options: TrackingOptions = from_lua_value(lua, params)?;
+                let key = options.key.get().await.map_err(any_err)?;
+                this.add_tracking(&options, &key).map_err(any_err)
+            },
+        );
+
+        methods.add_async_method("get_mime_structure", |lua, this, _: ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
+            let structure = this.get_mime_structure().map_err(any_err)?;
+            lua.to_value_with(&structure, serialize_options())
+        });
+
+        methods.add_async_method(
+            "get_mime_part_body",
+            |lua, this, section: String| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
+                let body = this.get_mime_part_body(&section).map_err(any_err)?;
+                lua.create_string(&body)
+            },
+        );
+
+        methods.add_async_method(
+            "add_mime_part",
+            |lua, this, (part, section): (mlua::Value, Option<String>)| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
+                let part: NewMimePart = from_lua_value(lua, part)?;
+                this.add_mime_part(&part, section.as_deref())
+                    .map_err(any_err)
+            },
+        );
+
+        methods.add_async_method(
+            "replace_mime_part",
+            |lua, this, (section, part): (String, mlua::Value)| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
+                let part: NewMimePart = from_lua_value(lua, part)?;
+                this.replace_mime_part(&section, &part).map_err(any_err)
+            },
+        );
+
+        methods.add_async_method(
+            "remove_mime_part",
+            |_lua, this, section: String| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
+                this.remove_mime_part(&section).map_err(any_err)
+            },
+        );
+
+        methods.add_async_method("rebuild_mime", |_lua, this, _: ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
+            this.rebuild_mime().map_err(any_err)
+        });
+
         methods.add_method("id", move |_, this, _: ()| Ok(this.id().to_string()));
         methods.add_method("sender", move |_, this, _: ()| {
             Ok(this.sender().map_err(any_err)?)
@@ -1096,13 +1535,27 @@ impl UserData for Message {
         });
 
         #[cfg(feature = "impl")]
-        methods.add_method("dkim_sign", move |_, this, signer: Signer| {
-            Ok(this.dkim_sign(&signer).map_err(any_err)?)
+        methods.add_async_method("dkim_sign", |lua, this, signer: mlua::Value| async move {
+            this.load_meta_if_needed().await.map_err(any_err)?;
+            this.load_data_if_needed().await.map_err(any_err)?;
+            match signer {
+                mlua::Value::Table(signers) => {
+                    let signers: Vec<Signer> = signers
+                        .sequence_values::<Signer>()
+                        .collect::<mlua::Result<_>>()?;
+                    Ok(this.dkim_sign_multiple(signers).await.map_err(any_err)?)
+                }
+                signer => {
+                    let signer = Signer::from_lua(signer, lua)?;
+                    Ok(this.dkim_sign(&signer).map_err(any_err)?)
+                }
+            }
         });
 
-        methods.add_method(
+        methods.add_async_method(
             "add_authentication_results",
-            move |lua, this, (serv_id, results): (String, mlua::Value)| {
+            |lua, this, (serv_id, results): (String, mlua::Value)| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
                 let results: Vec<AuthenticationResult> = lua.from_value(results)?;
                 let results = AuthenticationResults {
                     serv_id,
@@ -1118,45 +1571,70 @@ impl UserData for Message {
 
         #[cfg(feature = "impl")]
         methods.add_async_method("dkim_verify", |lua, this, ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             let results = this.dkim_verify().await.map_err(any_err)?;
             lua.to_value_with(&results, serialize_options())
         });
 
-        methods.add_method(
+        methods.add_async_method(
             "prepend_header",
-            move |_, this, (name, value): (String, String)| {
+            |_, this, (name, value, encode): (String, String, Option<bool>)| async move {
+                // Prepending doesn't alter the headers that a verifier
+                // selects, so it cannot invalidate a signature
+                this.load_data_if_needed().await.map_err(any_err)?;
+                let value = maybe_encode_header_value(&name, value, encode);
                 Ok(this.prepend_header(Some(&name), &value))
             },
         );
-        methods.add_method(
+        methods.add_async_method(
             "append_header",
-            move |_, this, (name, value): (String, String)| {
+            |_, this, (name, value, encode): (String, String, Option<bool>)| async move {
+                this.load_meta_if_needed().await.map_err(any_err)?;
+                this.load_data_if_needed().await.map_err(any_err)?;
+                check_header_not_signed(&this, &name)?;
+                let value = maybe_encode_header_value(&name, value, encode);
                 Ok(this.append_header(Some(&name), &value))
             },
         );
-        methods.add_method("get_address_header", move |_, this, name: String| {
+        methods.add_async_method(
+            "set_header",
+            |_, this, (name, value, encode): (String, String, Option<bool>)| async move {
+                this.load_meta_if_needed().await.map_err(any_err)?;
+                this.load_data_if_needed().await.map_err(any_err)?;
+                check_header_not_signed(&this, &name)?;
+                let value = maybe_encode_header_value(&name, value, encode);
+                this.set_header(&name, &value).map_err(any_err)
+            },
+        );
+        methods.add_async_method("get_address_header", |_, this, name: String| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             Ok(this.get_address_header(&name).map_err(any_err)?)
         });
-        methods.add_method("from_header", move |_, this, ()| {
+        methods.add_async_method("from_header", |_, this, ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             Ok(this.get_address_header("From").map_err(any_err)?)
         });
-        methods.add_method("to_header", move |_, this, ()| {
+        methods.add_async_method("to_header", |_, this, ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             Ok(this.get_address_header("To").map_err(any_err)?)
         });
 
-        methods.add_method(
+        methods.add_async_method(
             "get_first_named_header_value",
-            move |_, this, name: String| {
+            |_, this, name: String| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
                 Ok(this.get_first_named_header_value(&name).map_err(any_err)?)
             },
         );
-        methods.add_method(
+        methods.add_async_method(
             "get_all_named_header_values",
-            move |_, this, name: String| {
+            |_, this, name: String| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
                 Ok(this.get_all_named_header_values(&name).map_err(any_err)?)
             },
         );
-        methods.add_method("get_all_headers", move |_, this, _: ()| {
+        methods.add_async_method("get_all_headers", |_, this, _: ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             Ok(this
                 .get_all_headers()
                 .map_err(any_err)?
@@ -1164,7 +1642,8 @@ impl UserData for Message {
                 .map(|(name, value)| vec![name, value])
                 .collect::<Vec<Vec<String>>>())
         });
-        methods.add_method("get_all_headers", move |_, this, _: ()| {
+        methods.add_async_method("get_all_headers", |_, this, _: ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             Ok(this
                 .get_all_headers()
                 .map_err(any_err)?
@@ -1172,30 +1651,47 @@ impl UserData for Message {
                 .map(|(name, value)| vec![name, value])
                 .collect::<Vec<Vec<String>>>())
         });
-        methods.add_method(
+        methods.add_async_method(
             "import_x_headers",
-            move |_, this, names: Option<Vec<String>>| {
+            |_, this, names: Option<Vec<String>>| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
                 Ok(this
                     .import_x_headers(names.unwrap_or_else(|| vec![]))
                     .map_err(any_err)?)
             },
         );
 
-        methods.add_method(
+        methods.add_async_method(
             "remove_x_headers",
-            move |_, this, names: Option<Vec<String>>| {
-                Ok(this
-                    .remove_x_headers(names.unwrap_or_else(|| vec![]))
-                    .map_err(any_err)?)
+            |_, this, names: Option<Vec<String>>| async move {
+                this.load_meta_if_needed().await.map_err(any_err)?;
+                this.load_data_if_needed().await.map_err(any_err)?;
+                let names = names.unwrap_or_else(|| vec![]);
+                this.check_headers_not_signed(|name| {
+                    if names.is_empty() {
+                        is_x_header(name)
+                    } else {
+                        is_header_in_names_list(name, &names)
+                    }
+                })
+                .map_err(any_err)?;
+                Ok(this.remove_x_headers(names).map_err(any_err)?)
+            },
+        );
+        methods.add_async_method(
+            "remove_all_named_headers",
+            |_, this, name: String| async move {
+                this.load_meta_if_needed().await.map_err(any_err)?;
+                this.load_data_if_needed().await.map_err(any_err)?;
+                check_header_not_signed(&this, &name)?;
+                Ok(this.remove_all_named_headers(&name).map_err(any_err)?)
             },
         );
-        methods.add_method("remove_all_named_headers", move |_, this, name: String| {
-            Ok(this.remove_all_named_headers(&name).map_err(any_err)?)
-        });
 
-        methods.add_method(
+        methods.add_async_method(
             "import_scheduling_header",
-            move |_, this, (header_name, remove): (String, bool)| {
+            |_, this, (header_name, remove): (String, bool)| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
                 Ok(this
                     .import_scheduling_header(&header_name, remove)
                     .map_err(any_err)?)
@@ -1207,7 +1703,8 @@ impl UserData for Message {
             Ok(this.set_scheduling(sched).map_err(any_err)?)
         });
 
-        methods.add_method("parse_rfc3464", move |lua, this, _: ()| {
+        methods.add_async_method("parse_rfc3464", |lua, this, _: ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             let report = this.parse_rfc3464().map_err(any_err)?;
             match report {
                 Some(report) => lua.to_value_with(&report, serialize_options()),
@@ -1215,7 +1712,8 @@ impl UserData for Message {
             }
         });
 
-        methods.add_method("parse_rfc5965", move |lua, this, _: ()| {
+        methods.add_async_method("parse_rfc5965", |lua, this, _: ()| async move {
+            this.load_data_if_needed().await.map_err(any_err)?;
             let report = this.parse_rfc5965().map_err(any_err)?;
             match report {
                 Some(report) => lua.to_value_with(&report, serialize_options()),
@@ -1235,6 +1733,7 @@ impl UserData for Message {
         methods.add_async_method(
             "check_fix_conformance",
             |_, this, (check, fix): (String, String)| async move {
+                this.load_data_if_needed().await.map_err(any_err)?;
                 use std::str::FromStr;
                 let check = MessageConformance::from_str(&check).map_err(any_err)?;
                 let fix = MessageConformance::from_str(&fix).map_err(any_err)?;
@@ -1329,6 +1828,17 @@ mod test {
             .unwrap();
     }
 
+    #[test]
+    fn unload_dirty_data() {
+        let msg = new_msg_body(X_HDR_CONTENT);
+        assert!(msg.unload_data().is_err());
+        assert!(msg.is_data_loaded());
+
+        let lua = mlua::Lua::new();
+        lua.globals().set("msg", msg).unwrap();
+        lua.load("assert(msg:is_data_loaded())").exec().unwrap();
+    }
+
     #[test]
     fn import_some_x_headers() {
         let msg = new_msg_body(X_HDR_CONTENT);
@@ -1440,6 +1950,46 @@ mod test {
         );
     }
 
+    #[test]
+    fn set_header() {
+        let msg = new_msg_body(MULTI_HEADER_CONTENT);
+        msg.set_header("X-header", "new value").unwrap();
+        msg.set_header("X-header", "new value").unwrap();
+        msg.set_header("To", "someone@example.com").unwrap();
+        k9::assert_equal!(
+            data_as_string(&msg),
+            "X-Hello: there\r\nX-header: new value\r\nSubject: Hello\r\n\
+             From :Someone@somewhere\r\nTo: someone@example.com\r\n\r\nBody"
+        );
+    }
+
+    #[test]
+    fn check_headers_not_signed() {
+        let upstream = "DKIM-Signature: v=1; d=example.net; s=sel; h=To; bh=abc; b=def\r\n";
+        let ours = "DKIM-Signature: v=1; d=example.com; s=sel;\r\n\
+                    \th=From:Sub\r\n\tject; bh=abc; b=def\r\n";
+        let msg = new_msg_body(format!("{upstream}{MULTI_HEADER_CONTENT}"));
+        let is_subject = |name: &str| name.eq_ignore_ascii_case("subject");
+        let is_to = |name: &str| name.eq_ignore_ascii_case("to");
+
+        // Signatures from elsewhere don't prevent modification
+        msg.check_headers_not_signed(is_subject).unwrap();
+        msg.check_headers_not_signed(is_to).unwrap();
+
+        msg.prepend_header(None, ours);
+        msg.set_dkim_signed(&[ours.to_string()]).unwrap();
+        assert!(msg.check_headers_not_signed(is_subject).is_err());
+        msg.check_headers_not_signed(is_to).unwrap();
+        msg.check_headers_not_signed(is_x_header).unwrap();
+
+        // The protection is recorded in the metadata, so that it
+        // survives the message being reloaded from spool
+        k9::assert_equal!(
+            msg.get_meta(DKIM_SIGNED_HEADERS_META).unwrap(),
+            serde_json::json!(["From", "Subject"])
+        );
+    }
+
     #[test]
     fn append_text_plain() {
         let msg = new_msg_body(MULTI_HEADER_CONTENT);
@@ -1509,6 +2059,86 @@ AAECAw==\r\n\
 --my-boundary--\r\n\
 \r\n";
 
+    #[test]
+    fn add_tracking() {
+        let msg = new_msg_body(format!(
+            "DKIM-Signature: v=1; d=example.com\r\n{MIXED_CONTENT}"
+        ));
+        let options: TrackingOptions = serde_json::from_value(serde_json::json!({
+            "key": {"key_data": "secret"},
+            "open_url": "https://t.example.com/o/",
+        }))
+        .unwrap();
+        assert!(msg.add_tracking(&options, b"secret").unwrap());
+
+        let data = data_as_string(&msg);
+        assert!(!data.contains("DKIM-Signature"));
+        let parsed = MimePart::parse(data.as_str()).unwrap();
+        let html = parsed.simplified_structure().unwrap().html.unwrap();
+        assert!(html.contains("<img src=\"https://t.example.com/o/"));
+
+        let plain = new_msg_body(MULTI_HEADER_CONTENT);
+        assert!(!plain.add_tracking(&options, b"secret").unwrap());
+    }
+
+    #[test]
+    fn edit_mime() {
+        let msg = new_msg_body(MIXED_CONTENT);
+        let structure = msg.get_mime_structure().unwrap();
+        assert_eq!(structure.parts.len(), 3);
+        assert_eq!(structure.parts[2].section, "3");
+        assert_eq!(structure.parts[2].file_name.as_deref(), Some("woot.bin"));
+        assert_eq!(structure.parts[2].size, 4);
+        assert_eq!(msg.get_mime_part_body("3").unwrap(), vec![0, 1, 2, 3]);
+
+        msg.remove_mime_part("3").unwrap();
+        let footer: NewMimePart = serde_json::from_value(serde_json::json!({
+            "content_type": "text/plain",
+            "content": "footer",
+        }))
+        .unwrap();
+        msg.replace_mime_part("1", &footer).unwrap();
+        let attachment: NewMimePart = serde_json::from_value(serde_json::json!({
+            "content_type": "text/csv",
+            "content": "a,b\r\n",
+            "file_name": "data.csv",
+        }))
+        .unwrap();
+        msg.add_mime_part(&attachment, None).unwrap();
+
+        k9::snapshot!(
+            data_as_string(&msg),
+            r#"
+Content-Type: multipart/mixed;\r
+\tboundary="my-boundary"\r
+\r
+--my-boundary\r
+Content-Type: text/plain;\r
+\tcharset="us-ascii"\r
+\r
+footer\r
+--my-boundary\r
+Content-Type: text/html;\r
+\tcharset="us-ascii"\r
+\r
+<b>rich</b> text\r
+--my-boundary\r
+Content-Type: text/csv;\r
+\tcharset="us-ascii"\r
+Content-Disposition: attachment;\r
+\tfilename="data.csv"\r
+\r
+a,b\r
+--my-boundary--\r
+\r
+
+"#
+        );
+
+        assert!(msg.remove_mime_part("").is_err());
+        assert!(msg.remove_mime_part("7").is_err());
+    }
+
     #[test]
     fn append_text_html() {
         let msg = new_msg_body(MIXED_CONTENT);
@@ -1756,14 +2386,72 @@ Body\r
         msg.set_scheduling(Some(Scheduling {
             restriction: None,
             first_attempt: Some((now + one_day).into()),
+            expires: None,
         }))?;
 
         let due = msg.get_due().expect("due to now be set");
         assert!(due - now >= one_day, "due time is at least 1 day away");
 
+        assert!(msg
+            .set_scheduling(Some(Scheduling {
+                restriction: None,
+                first_attempt: Some((now + one_day).into()),
+                expires: Some(now.into()),
+            }))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn priority_survives_shrink() -> anyhow::Result<()> {
+        let msg = new_msg_body(MULTI_HEADER_CONTENT);
+        assert_eq!(msg.get_priority(), 0);
+        msg.set_priority(5)?;
+
+        let meta = {
+            let inner = msg.msg_and_id.inner.lock().unwrap();
+            serde_json::to_vec(inner.metadata.as_ref().unwrap())?
+        };
+        let restored = Message::new_from_spool(*msg.id(), meta)?;
+        assert_eq!(restored.get_priority(), 5);
+
+        restored.shrink()?;
+        assert_eq!(restored.get_priority(), 5);
         Ok(())
     }
 
+    #[test]
+    fn set_content_meta() {
+        let msg = new_msg_body(MIXED_CONTENT);
+        msg.set_content_meta(false).unwrap();
+        assert_eq!(
+            msg.get_meta("message_size").unwrap(),
+            serde_json::json!(MIXED_CONTENT.len())
+        );
+        assert_eq!(
+            msg.get_meta("has_attachments").unwrap(),
+            serde_json::Value::Null
+        );
+
+        msg.set_content_meta(true).unwrap();
+        assert_eq!(
+            msg.get_meta("message_size").unwrap(),
+            serde_json::json!(MIXED_CONTENT.len())
+        );
+        assert_eq!(
+            msg.get_meta("has_attachments").unwrap(),
+            serde_json::json!(true)
+        );
+
+        let msg = new_msg_body(MULTI_HEADER_CONTENT);
+        msg.set_content_meta(true).unwrap();
+        assert_eq!(
+            msg.get_meta("has_attachments").unwrap(),
+            serde_json::json!(false)
+        );
+    }
+
     #[cfg(all(test, target_pointer_width = "64"))]
     #[test]
     fn sizes() {
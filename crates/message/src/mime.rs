@@ -0,0 +1,332 @@
+//! Structured editing of the MIME tree of a message.
+//!
+//! Parts are addressed by section number, in the same style as IMAP:
+//! the children of the root part are numbered `1`, `2` and so on, and
+//! their children are `1.1`, `1.2` and so on.  The empty string
+//! addresses the root part.
+use mailparsing::{
+    AttachmentOptions, DecodedBody, HeaderMap, MessageID, MimeParameters, MimePart, PartPointer,
+};
+use serde::de::{Deserializer, Error, Visitor};
+use serde::{Deserialize, Serialize};
+
+/// Describes a part of the MIME tree, as returned by
+/// `msg:get_mime_structure`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MimePartInfo {
+    pub section: String,
+    pub content_type: Option<String>,
+    pub charset: Option<String>,
+    pub transfer_encoding: Option<String>,
+    pub disposition: Option<String>,
+    pub file_name: Option<String>,
+    pub content_id: Option<String>,
+    /// The decoded size of the content, in bytes. For a multipart
+    /// part, this is the sum of the sizes of its children.
+    pub size: usize,
+    pub parts: Vec<MimePartInfo>,
+}
+
+impl MimePartInfo {
+    pub fn new(part: &MimePart, section: String) -> anyhow::Result<Self> {
+        let headers = part.headers();
+        let content_type = headers.content_type()?;
+        let disposition = headers.content_disposition()?;
+
+        let mut parts = vec![];
+        for (idx, child) in part.child_parts().iter().enumerate() {
+            let child_section = if section.is_empty() {
+                format!("{}", idx + 1)
+            } else {
+                format!("{section}.{}", idx + 1)
+            };
+            parts.push(Self::new(child, child_section)?);
+        }
+
+        let size = if parts.is_empty() {
+            match part.body()? {
+                DecodedBody::Text(text) => text.len(),
+                DecodedBody::Binary(data) => data.len(),
+            }
+        } else {
+            parts.iter().map(|p| p.size).sum()
+        };
+
+        Ok(Self {
+            section,
+            charset: content_type.as_ref().and_then(|ct| ct.get("charset")),
+            content_type: content_type.map(|ct| ct.value),
+            transfer_encoding: headers.content_transfer_encoding()?.map(|cte| cte.value),
+            file_name: disposition.as_ref().and_then(|cd| cd.get("filename")),
+            disposition: disposition.map(|cd| cd.value),
+            content_id: headers.content_id()?.map(|id| id.0),
+            size,
+            parts,
+        })
+    }
+}
+
+/// The parameters accepted by `msg:add_mime_part` and
+/// `msg:replace_mime_part`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NewMimePart {
+    pub content_type: String,
+    pub content: PartContent,
+    #[serde(default)]
+    pub file_name: Option<String>,
+    #[serde(default)]
+    pub inline: bool,
+    #[serde(default)]
+    pub content_id: Option<String>,
+}
+
+impl NewMimePart {
+    /// Builds the part. Textual content is encoded as quoted-printable
+    /// or base64, whichever is smaller; anything else as base64.
+    pub fn build(&self) -> anyhow::Result<MimePart<'static>> {
+        let attachment = if self.file_name.is_some() || self.inline {
+            Some(AttachmentOptions {
+                file_name: self.file_name.clone(),
+                inline: self.inline,
+                content_id: self.content_id.clone(),
+            })
+        } else {
+            None
+        };
+
+        if !self.content_type.to_ascii_lowercase().starts_with("text/") {
+            let mut part =
+                MimePart::new_binary(&self.content_type, &self.content.0, attachment.as_ref());
+            if attachment.is_none() {
+                if let Some(id) = &self.content_id {
+                    part.headers_mut().set_content_id(MessageID(id.to_string()));
+                }
+            }
+            return Ok(part);
+        }
+
+        let text = std::str::from_utf8(&self.content.0).map_err(|_| {
+            anyhow::anyhow!("content for {} part must be UTF-8 text", self.content_type)
+        })?;
+        let mut part = MimePart::new_text(&self.content_type, text);
+        if let Some(opts) = &attachment {
+            let mut cd = MimeParameters::new(if opts.inline { "inline" } else { "attachment" });
+            if let Some(name) = &opts.file_name {
+                cd.set("filename", name);
+            }
+            part.headers_mut().set_content_disposition(cd);
+        }
+        if let Some(id) = &self.content_id {
+            part.headers_mut().set_content_id(MessageID(id.to_string()));
+        }
+        Ok(part)
+    }
+}
+
+/// The content of a new part. Lua strings may hold binary data,
+/// so this accepts bytes as well as strings.
+#[derive(Debug, Clone)]
+pub struct PartContent(pub Vec<u8>);
+
+impl<'de> Deserialize<'de> for PartContent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ContentVisitor;
+
+        impl<'de> Visitor<'de> for ContentVisitor {
+            type Value = PartContent;
+
+            fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                fmt.write_str("a string")
+            }
+
+            fn visit_str<E: Error>(self, s: &str) -> Result<PartContent, E> {
+                Ok(PartContent(s.as_bytes().to_vec()))
+            }
+
+            fn visit_bytes<E: Error>(self, b: &[u8]) -> Result<PartContent, E> {
+                Ok(PartContent(b.to_vec()))
+            }
+        }
+
+        deserializer.deserialize_any(ContentVisitor)
+    }
+}
+
+/// Parses a section number into the 0-based indices of
+/// the parts that lead to it from the root
+pub fn parse_section(section: &str) -> anyhow::Result<Vec<u8>> {
+    if section.is_empty() {
+        return Ok(vec![]);
+    }
+    section
+        .split('.')
+        .map(|n| match n.parse::<u8>() {
+            Ok(n) if n > 0 => Ok(n - 1),
+            _ => anyhow::bail!("invalid MIME section number '{section}'"),
+        })
+        .collect()
+}
+
+fn pointer(indices: &[u8]) -> PartPointer {
+    indices.iter().fold(PartPointer::root(), |ptr, &n| {
+        ptr.append(PartPointer::nth(n))
+    })
+}
+
+fn is_content_header(name: &str) -> bool {
+    name.len() > 8 && name[..8].eq_ignore_ascii_case("content-")
+}
+
+/// Replaces the content of part with new_part, but keeps any
+/// headers of part that don't describe its content, such as
+/// the Subject of the root part.
+fn replace_content<'a>(part: &mut MimePart<'a>, mut new_part: MimePart<'a>) {
+    let mut headers: Vec<_> = part
+        .headers_mut()
+        .iter()
+        .filter(|hdr| !is_content_header(hdr.get_name()))
+        .cloned()
+        .collect();
+    headers.append(new_part.headers_mut());
+    *new_part.headers_mut() = HeaderMap::new(headers);
+    *part = new_part;
+}
+
+fn resolve_mut<'a, 'b>(
+    root: &'b mut MimePart<'a>,
+    section: &str,
+) -> anyhow::Result<&'b mut MimePart<'a>> {
+    root.resolve_ptr_mut(pointer(&parse_section(section)?))
+        .ok_or_else(|| anyhow::anyhow!("MIME section '{section}' does not exist"))
+}
+
+pub fn get_part_body(root: &MimePart, section: &str) -> anyhow::Result<Vec<u8>> {
+    let part = root
+        .resolve_ptr(pointer(&parse_section(section)?))
+        .ok_or_else(|| anyhow::anyhow!("MIME section '{section}' does not exist"))?;
+    if !part.child_parts().is_empty() {
+        anyhow::bail!("MIME section '{section}' is a multipart and has no body of its own");
+    }
+    Ok(match part.body()? {
+        DecodedBody::Text(text) => text.as_bytes().to_vec(),
+        DecodedBody::Binary(data) => data,
+    })
+}
+
+/// Adds new_part as the last child of the part at section.
+/// When no section is specified, the part is added to the root
+/// part if it is multipart/mixed, otherwise the content of the
+/// root is wrapped in a new multipart/mixed part with new_part.
+pub fn add_part<'a>(
+    root: &mut MimePart<'a>,
+    new_part: MimePart<'a>,
+    section: Option<&str>,
+) -> anyhow::Result<()> {
+    let parent = match section {
+        Some(section) => resolve_mut(root, section)?,
+        None => {
+            let is_mixed = root
+                .headers()
+                .content_type()?
+                .map(|ct| ct.value.eq_ignore_ascii_case("multipart/mixed"))
+                .unwrap_or(false);
+            if !is_mixed {
+                let mut content = root.clone();
+                content
+                    .headers_mut()
+                    .retain(|hdr| is_content_header(hdr.get_name()));
+                let mixed =
+                    MimePart::new_multipart("multipart/mixed", vec![content, new_part], None);
+                replace_content(root, mixed);
+                return Ok(());
+            }
+            root
+        }
+    };
+
+    let is_multipart = parent
+        .headers()
+        .content_type()?
+        .map(|ct| ct.value.to_ascii_lowercase().starts_with("multipart/"))
+        .unwrap_or(false);
+    if !is_multipart {
+        anyhow::bail!("cannot add a part to a part that is not multipart");
+    }
+    parent.child_parts_mut().push(new_part);
+    Ok(())
+}
+
+pub fn replace_part<'a>(
+    root: &mut MimePart<'a>,
+    section: &str,
+    new_part: MimePart<'a>,
+) -> anyhow::Result<()> {
+    let part = resolve_mut(root, section)?;
+    replace_content(part, new_part);
+    Ok(())
+}
+
+pub fn remove_part(root: &mut MimePart, section: &str) -> anyhow::Result<()> {
+    let mut indices = parse_section(section)?;
+    let idx = indices
+        .pop()
+        .ok_or_else(|| anyhow::anyhow!("cannot remove the root MIME part"))?;
+    let parent = root
+        .resolve_ptr_mut(pointer(&indices))
+        .ok_or_else(|| anyhow::anyhow!("MIME section '{section}' does not exist"))?;
+    let parts = parent.child_parts_mut();
+    if idx as usize >= parts.len() {
+        anyhow::bail!("MIME section '{section}' does not exist");
+    }
+    if parts.len() == 1 {
+        // An empty multipart would be written out using its
+        // original body, resurrecting the part
+        anyhow::bail!("cannot remove MIME section '{section}' as it is the only child part");
+    }
+    parts.remove(idx as usize);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sections() {
+        assert_eq!(parse_section("").unwrap(), Vec::<u8>::new());
+        assert_eq!(parse_section("1").unwrap(), vec![0]);
+        assert_eq!(parse_section("2.3").unwrap(), vec![1, 2]);
+        assert!(parse_section("0").is_err());
+        assert!(parse_section("1.").is_err());
+        assert!(parse_section("a").is_err());
+    }
+
+    #[test]
+    fn add_to_single_part() {
+        let mut root =
+            MimePart::parse("Subject: hello\r\nContent-Type: text/plain\r\n\r\nbody\r\n").unwrap();
+        let new_part = NewMimePart {
+            content_type: "application/octet-stream".to_string(),
+            content: PartContent(vec![0, 1, 2, 3]),
+            file_name: Some("woot.bin".to_string()),
+            inline: false,
+            content_id: None,
+        }
+        .build()
+        .unwrap();
+        add_part(&mut root, new_part, None).unwrap();
+
+        let info = MimePartInfo::new(&root, String::new()).unwrap();
+        assert_eq!(info.content_type.as_deref(), Some("multipart/mixed"));
+        assert_eq!(info.parts.len(), 2);
+        assert_eq!(info.parts[0].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(info.parts[1].section, "2");
+        assert_eq!(info.parts[1].file_name.as_deref(), Some("woot.bin"));
+        assert_eq!(info.parts[1].size, 4);
+
+        // The Subject stays with the message rather than the content
+        assert!(root.headers().subject().unwrap().is_some());
+        assert!(root.child_parts()[0].headers().subject().unwrap().is_none());
+    }
+}
@@ -83,6 +83,10 @@ pub struct Scheduling {
     pub restriction: Option<ScheduleRestriction>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub first_attempt: Option<DateTime<FixedOffset>>,
+    /// If the message has not been delivered by this time,
+    /// it will be expired rather than retried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<FixedOffset>>,
 }
 
 impl Scheduling {
@@ -277,6 +281,7 @@ mod test {
                 end: NaiveTime::from_hms_opt(17, 0, 0).unwrap(),
             }),
             first_attempt: None,
+            expires: None,
         };
 
         let serialized = serde_json::to_string(&sched).unwrap();
@@ -299,6 +304,7 @@ mod test {
                 end: NaiveTime::from_hms_opt(17, 0, 0).unwrap(),
             }),
             first_attempt: DateTime::parse_from_rfc3339("1996-12-19T16:39:57-08:00").ok(),
+            expires: None,
         };
 
         let serialized = serde_json::to_string(&sched).unwrap();
@@ -316,6 +322,7 @@ mod test {
         let sched = Scheduling {
             restriction: None,
             first_attempt: DateTime::parse_from_rfc3339("1996-12-19T16:39:57-08:00").ok(),
+            expires: None,
         };
 
         let serialized = serde_json::to_string(&sched).unwrap();
@@ -328,11 +335,30 @@ mod test {
         k9::assert_equal!(sched, round_trip);
     }
 
+    #[test]
+    fn schedule_parse_start_and_expires() {
+        let sched = Scheduling {
+            restriction: None,
+            first_attempt: DateTime::parse_from_rfc3339("1996-12-19T16:39:57-08:00").ok(),
+            expires: DateTime::parse_from_rfc3339("1996-12-20T16:39:57-08:00").ok(),
+        };
+
+        let serialized = serde_json::to_string(&sched).unwrap();
+        k9::snapshot!(
+            &serialized,
+            r#"{"first_attempt":"1996-12-19T16:39:57-08:00","expires":"1996-12-20T16:39:57-08:00"}"#
+        );
+
+        let round_trip: Scheduling = serde_json::from_str(&serialized).unwrap();
+        k9::assert_equal!(sched, round_trip);
+    }
+
     #[test]
     fn schedule_adjust_start() {
         let sched = Scheduling {
             restriction: None,
             first_attempt: DateTime::parse_from_rfc3339("2023-03-20T16:39:57-08:00").ok(),
+            expires: None,
         };
 
         let now: DateTime<Utc> = DateTime::parse_from_rfc3339("2023-03-20T08:00:00-08:00")
@@ -352,6 +378,7 @@ mod test {
                 end: NaiveTime::from_hms_opt(17, 0, 0).unwrap(),
             }),
             first_attempt: None,
+            expires: None,
         };
 
         // This is a Tuesday
@@ -375,6 +402,7 @@ mod test {
                 end: NaiveTime::from_hms_opt(17, 0, 0).unwrap(),
             }),
             first_attempt: None,
+            expires: None,
         };
 
         // This is a Monday, but after hours
@@ -0,0 +1,277 @@
+//! Open and click tracking for the html part of a message.
+//!
+//! Links are rewritten to pass through a click tracking service, and
+//! a tracking pixel is added to the end of the body. Both carry a token
+//! that identifies the message and its recipient (and, for links, the
+//! original url). The token is `PAYLOAD.SIGNATURE`, where `PAYLOAD` is
+//! the unpadded base64url encoding of a JSON object and `SIGNATURE` is
+//! the unpadded base64url encoding of the HMAC-SHA256 of `PAYLOAD`, so
+//! that the tracking service can verify that the token was issued by us.
+use data_encoding::BASE64URL_NOPAD;
+use data_loader::KeySource;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// The parameters accepted by `msg:add_tracking`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TrackingOptions {
+    /// The key used to sign the tokens
+    pub key: KeySource,
+
+    /// If set, http and https links are rewritten to this url
+    /// with the token appended
+    #[serde(default)]
+    pub click_url: Option<String>,
+
+    /// If set, a tracking pixel that loads this url with the token
+    /// appended is added to the end of the html
+    #[serde(default)]
+    pub open_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct TokenPayload {
+    /// The message id
+    pub id: String,
+    /// The envelope recipient
+    pub rcpt: String,
+    /// The original url of a rewritten link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+pub struct Tracker<'a> {
+    key: &'a [u8],
+    id: &'a str,
+    rcpt: &'a str,
+    click_url: Option<&'a str>,
+    open_url: Option<&'a str>,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn new(key: &'a [u8], id: &'a str, rcpt: &'a str, options: &'a TrackingOptions) -> Self {
+        Self {
+            key,
+            id,
+            rcpt,
+            click_url: options.click_url.as_deref(),
+            open_url: options.open_url.as_deref(),
+        }
+    }
+
+    pub fn token(&self, url: Option<&str>) -> anyhow::Result<String> {
+        let payload = serde_json::to_string(&TokenPayload {
+            id: self.id.to_string(),
+            rcpt: self.rcpt.to_string(),
+            url: url.map(|u| u.to_string()),
+        })?;
+        let payload = BASE64URL_NOPAD.encode(payload.as_bytes());
+
+        let mut mac = new_mac(self.key)?;
+        mac.update(payload.as_bytes());
+        let signature = BASE64URL_NOPAD.encode(&mac.finalize().into_bytes());
+
+        Ok(format!("{payload}.{signature}"))
+    }
+
+    /// Returns the payload of token if its signature is valid
+    pub fn verify(key: &[u8], token: &str) -> anyhow::Result<TokenPayload> {
+        let (payload, signature) = token
+            .split_once('.')
+            .ok_or_else(|| anyhow::anyhow!("malformed token"))?;
+        let signature = BASE64URL_NOPAD.decode(signature.as_bytes())?;
+
+        let mut mac = new_mac(key)?;
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| anyhow::anyhow!("token signature is invalid"))?;
+
+        let payload = BASE64URL_NOPAD.decode(payload.as_bytes())?;
+        Ok(serde_json::from_slice(&payload)?)
+    }
+
+    /// Applies the configured tracking to html
+    pub fn apply(&self, html: &str) -> anyhow::Result<String> {
+        let mut html = match self.click_url {
+            Some(click_url) => self.rewrite_links(html, click_url)?,
+            None => html.to_string(),
+        };
+
+        if let Some(open_url) = self.open_url {
+            let pixel = format!(
+                "<img src=\"{open_url}{}\" width=\"1\" height=\"1\" alt=\"\" \
+                 style=\"display:block;border:0;width:1px;height:1px\" />",
+                self.token(None)?
+            );
+            match rfind_ascii_case_insensitive(&html, "</body>") {
+                Some(idx) => html.insert_str(idx, &pixel),
+                None => html.push_str(&pixel),
+            }
+        }
+
+        Ok(html)
+    }
+
+    fn rewrite_links(&self, html: &str, click_url: &str) -> anyhow::Result<String> {
+        let mut result = String::with_capacity(html.len());
+        let mut rest = html;
+
+        while let Some(pos) = find_ascii_case_insensitive(rest, "href") {
+            let (before, after) = rest.split_at(pos + 4);
+            result.push_str(before);
+            rest = after;
+
+            // Must be an attribute of an anchor tag, rather than
+            // eg: `data-href` or some text that mentions href
+            if !result[..result.len() - 4].ends_with(|c: char| c.is_ascii_whitespace())
+                || !in_anchor_tag(&result)
+            {
+                continue;
+            }
+
+            let Some(value) = rest.trim_start().strip_prefix('=') else {
+                continue;
+            };
+            let value = value.trim_start();
+            let quote = match value.chars().next() {
+                Some(q @ ('"' | '\'')) => q,
+                _ => continue,
+            };
+            let value = &value[1..];
+            let Some(end) = value.find(quote) else {
+                continue;
+            };
+
+            // Copy the `="` portion through verbatim
+            result.push_str(&rest[..rest.len() - value.len()]);
+            let url = &value[..end];
+            match self.tracked_link(url, click_url)? {
+                Some(link) => result.push_str(&link),
+                None => result.push_str(url),
+            }
+            rest = &value[end..];
+        }
+
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    fn tracked_link(&self, url: &str, click_url: &str) -> anyhow::Result<Option<String>> {
+        let url = url.trim();
+        let lower = url.to_ascii_lowercase();
+        if !(lower.starts_with("http://") || lower.starts_with("https://"))
+            || url.starts_with(click_url)
+        {
+            return Ok(None);
+        }
+        // The attribute value is html; the token should
+        // hold the url that the browser would visit
+        let url = url.replace("&amp;", "&");
+        Ok(Some(format!("{click_url}{}", self.token(Some(&url))?)))
+    }
+}
+
+fn new_mac(key: &[u8]) -> anyhow::Result<Hmac<Sha256>> {
+    Hmac::<Sha256>::new_from_slice(key)
+        .map_err(|err| anyhow::anyhow!("invalid tracking key: {err}"))
+}
+
+fn find_ascii_case_insensitive(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+fn rfind_ascii_case_insensitive(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .rposition(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// Returns true if the end of html is inside an `<a` tag
+fn in_anchor_tag(html: &str) -> bool {
+    let Some(start) = html.rfind('<') else {
+        return false;
+    };
+    let tag = &html[start + 1..];
+    if tag.contains('>') {
+        return false;
+    }
+    let mut chars = tag.chars();
+    matches!(chars.next(), Some('a' | 'A'))
+        && matches!(chars.next(), Some(c) if c.is_ascii_whitespace())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(click_url: Option<&str>, open_url: Option<&str>) -> TrackingOptions {
+        TrackingOptions {
+            key: KeySource::Data {
+                key_data: "secret".to_string(),
+            },
+            click_url: click_url.map(|s| s.to_string()),
+            open_url: open_url.map(|s| s.to_string()),
+        }
+    }
+
+    #[test]
+    fn rewrite_links() {
+        let opts = options(Some("https://t.example.com/c/"), None);
+        let tracker = Tracker::new(b"secret", "ID", "user@example.com", &opts);
+
+        let html = "<p data-href=\"https://x\">href=\"https://y\"</p>\
+            <A class=x HREF = 'https://example.com/?a=1&amp;b=2'>one</A>\
+            <a href=\"mailto:someone@example.com\">two</a>\
+            <link href=\"https://example.com/style.css\">\
+            <a href=\"https://t.example.com/c/already\">three</a>";
+        let result = tracker.apply(html).unwrap();
+
+        let token = tracker.token(Some("https://example.com/?a=1&b=2")).unwrap();
+        assert_eq!(
+            result,
+            format!(
+                "<p data-href=\"https://x\">href=\"https://y\"</p>\
+                <A class=x HREF = 'https://t.example.com/c/{token}'>one</A>\
+                <a href=\"mailto:someone@example.com\">two</a>\
+                <link href=\"https://example.com/style.css\">\
+                <a href=\"https://t.example.com/c/already\">three</a>"
+            )
+        );
+
+        let payload = Tracker::verify(b"secret", &token).unwrap();
+        assert_eq!(
+            payload,
+            TokenPayload {
+                id: "ID".to_string(),
+                rcpt: "user@example.com".to_string(),
+                url: Some("https://example.com/?a=1&b=2".to_string()),
+            }
+        );
+        assert!(Tracker::verify(b"wrong", &token).is_err());
+    }
+
+    #[test]
+    fn open_pixel() {
+        let opts = options(None, Some("https://t.example.com/o/"));
+        let tracker = Tracker::new(b"secret", "ID", "user@example.com", &opts);
+        let token = tracker.token(None).unwrap();
+
+        let result = tracker
+            .apply("<html><BODY><a href=\"https://example.com\">x</a></BODY></html>")
+            .unwrap();
+        assert_eq!(
+            result,
+            format!(
+                "<html><BODY><a href=\"https://example.com\">x</a>\
+                <img src=\"https://t.example.com/o/{token}\" width=\"1\" height=\"1\" alt=\"\" \
+                style=\"display:block;border:0;width:1px;height:1px\" /></BODY></html>"
+            )
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use config::{any_err, from_lua_value};
-use lapin::options::BasicPublishOptions;
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
 use lapin::publisher_confirm::{Confirmation, PublisherConfirm};
 use lapin::{BasicProperties, Channel, Connection, ConnectionProperties};
 use mlua::prelude::LuaUserData;
@@ -96,13 +96,57 @@ impl LuaUserData for AMQPClient {
     }
 }
 
+impl AMQPClient {
+    /// Put the channel into confirm mode, so that the broker
+    /// acknowledges each message published via publish_confirmed
+    pub async fn confirm_select(&self) -> anyhow::Result<()> {
+        self.holder
+            .channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    /// Publish payload as a persistent message and wait for the
+    /// broker to confirm it
+    pub async fn publish_confirmed(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        content_type: &str,
+    ) -> anyhow::Result<ConfirmResult> {
+        let properties = BasicProperties::default()
+            .with_content_type(content_type.into())
+            .with_delivery_mode(2);
+        let confirm = self
+            .holder
+            .channel
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions::default(),
+                payload,
+                properties,
+            )
+            .await?;
+        confirm_result(confirm).await
+    }
+
+    pub async fn close(&self) -> anyhow::Result<()> {
+        self.holder.channel.close(200, "").await?;
+        self.holder.connection.close(200, "").await?;
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 struct Confirm {
     confirm: Arc<Mutex<Option<PublisherConfirm>>>,
 }
 
-#[derive(Serialize, Debug)]
-enum ConfirmStatus {
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfirmStatus {
     Ack,
     Nack,
     NotRequested,
@@ -121,17 +165,14 @@ impl ConfirmStatus {
 }
 
 #[derive(Serialize, Debug)]
-struct ConfirmResult {
-    status: ConfirmStatus,
-    reply_code: Option<u64>,
-    reply_text: Option<String>,
+pub struct ConfirmResult {
+    pub status: ConfirmStatus,
+    pub reply_code: Option<u64>,
+    pub reply_text: Option<String>,
 }
 
-async fn wait_confirmation<'lua>(
-    lua: &'lua Lua,
-    confirm: PublisherConfirm,
-) -> mlua::Result<Value<'lua>> {
-    let confirmation = confirm.await.map_err(any_err)?;
+async fn confirm_result(confirm: PublisherConfirm) -> anyhow::Result<ConfirmResult> {
+    let confirmation = confirm.await?;
     let status = ConfirmStatus::from_confirmation(&confirmation);
     let (reply_code, reply_text) = if let Some(msg) = confirmation.take_message() {
         (
@@ -142,11 +183,18 @@ async fn wait_confirmation<'lua>(
         (None, None)
     };
 
-    let confirmation = ConfirmResult {
+    Ok(ConfirmResult {
         status,
         reply_code,
         reply_text,
-    };
+    })
+}
+
+async fn wait_confirmation<'lua>(
+    lua: &'lua Lua,
+    confirm: PublisherConfirm,
+) -> mlua::Result<Value<'lua>> {
+    let confirmation = confirm_result(confirm).await.map_err(any_err)?;
 
     let result = lua.to_value_with(&confirmation, config::serialize_options())?;
     Ok(result)
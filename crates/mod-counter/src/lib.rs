@@ -0,0 +1,172 @@
+use config::{any_err, get_or_create_sub_module};
+use kumo_prometheus::{label_key, AtomicCounter, CounterRegistry};
+use mlua::{Lua, MetaMethod, UserData, UserDataMethods};
+use once_cell::sync::Lazy;
+
+label_key! {
+    pub struct CounterNameKey {
+        pub name: String,
+    }
+}
+
+static COUNTERS: Lazy<CounterRegistry<CounterNameKey>> = Lazy::new(|| {
+    CounterRegistry::register(
+        "lua_counter",
+        "user defined counters created via kumo.counter.counter",
+    )
+});
+
+static GAUGES: Lazy<CounterRegistry<CounterNameKey>> = Lazy::new(|| {
+    CounterRegistry::register_gauge(
+        "lua_gauge",
+        "user defined gauges created via kumo.counter.gauge",
+    )
+});
+
+/// A handle to a named, process-wide counter.
+/// All operations are atomic and never yield, so they are safe
+/// to use from concurrently executing lua coroutines and from
+/// multiple lua contexts.
+struct Counter {
+    name: String,
+    counter: AtomicCounter,
+}
+
+impl UserData for Counter {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("inc", |_, this, amount: Option<usize>| {
+            Ok(this.counter.add_and_get(amount.unwrap_or(1)))
+        });
+        methods.add_method("get", |_, this, _: ()| Ok(this.counter.get()));
+        methods.add_meta_method(MetaMethod::ToString, |_, this, _: ()| {
+            Ok(format!("Counter({}={})", this.name, this.counter.get()))
+        });
+    }
+}
+
+/// A handle to a named, process-wide gauge.
+/// Like Counter, but the value may also decrease or be set
+/// to an arbitrary value. The value saturates at zero.
+struct Gauge {
+    name: String,
+    counter: AtomicCounter,
+}
+
+impl UserData for Gauge {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("inc", |_, this, amount: Option<usize>| {
+            Ok(this.counter.add_and_get(amount.unwrap_or(1)))
+        });
+        methods.add_method("dec", |_, this, amount: Option<usize>| {
+            Ok(this.counter.saturating_sub_and_get(amount.unwrap_or(1)))
+        });
+        methods.add_method("set", |_, this, value: usize| {
+            this.counter.set(value);
+            Ok(())
+        });
+        methods.add_method("get", |_, this, _: ()| Ok(this.counter.get()));
+        methods.add_meta_method(MetaMethod::ToString, |_, this, _: ()| {
+            Ok(format!("Gauge({}={})", this.name, this.counter.get()))
+        });
+    }
+}
+
+/// The name is emitted verbatim as a label value in the metrics
+/// output, so we constrain it to a conservative set of characters
+/// that never require escaping.
+fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        anyhow::bail!("counter name must not be empty");
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':' | '@')))
+    {
+        anyhow::bail!("counter name {name:?} contains invalid character {c:?}");
+    }
+    Ok(())
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let counter_mod = get_or_create_sub_module(lua, "counter")?;
+
+    counter_mod.set(
+        "counter",
+        lua.create_function(|_, name: String| {
+            validate_name(&name).map_err(any_err)?;
+            let key = BorrowedCounterNameKey { name: &name };
+            let counter = COUNTERS.get_or_create(&key as &dyn CounterNameKeyTrait);
+            Ok(Counter { name, counter })
+        })?,
+    )?;
+
+    counter_mod.set(
+        "gauge",
+        lua.create_function(|_, name: String| {
+            validate_name(&name).map_err(any_err)?;
+            let key = BorrowedCounterNameKey { name: &name };
+            let counter = GAUGES.get_or_create(&key as &dyn CounterNameKeyTrait);
+            Ok(Gauge { name, counter })
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters_are_shared() {
+        let lua = Lua::new();
+        register(&lua).unwrap();
+
+        let result: (usize, usize, usize) = lua
+            .load(
+                r#"
+            local kumo = require 'kumo'
+            local a = kumo.counter.counter 'test_counters_are_shared'
+            local b = kumo.counter.counter 'test_counters_are_shared'
+            a:inc()
+            local after_b = b:inc(5)
+            return a:get(), after_b, kumo.counter.counter('test_counters_unrelated'):get()
+        "#,
+            )
+            .eval()
+            .unwrap();
+
+        assert_eq!(result, (6, 6, 0));
+    }
+
+    #[test]
+    fn gauge_saturates() {
+        let lua = Lua::new();
+        register(&lua).unwrap();
+
+        let result: (usize, usize, usize) = lua
+            .load(
+                r#"
+            local kumo = require 'kumo'
+            local g = kumo.counter.gauge 'test_gauge_saturates'
+            g:set(3)
+            local after_inc = g:inc()
+            local after_dec = g:dec(10)
+            g:set(2)
+            return after_inc, after_dec, g:get()
+        "#,
+            )
+            .eval()
+            .unwrap();
+
+        assert_eq!(result, (4, 0, 2));
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert!(validate_name("customer.acme:sends").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("has\"quote").is_err());
+        assert!(validate_name("has space").is_err());
+    }
+}
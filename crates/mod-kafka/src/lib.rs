@@ -1,7 +1,9 @@
-use config::{any_err, get_or_create_sub_module};
+use config::{any_err, get_or_create_sub_module, load_config, CallbackSignature};
+use kumo_server_lifecycle::{Activity, ShutdownSubcription};
 use mlua::prelude::LuaUserData;
-use mlua::{Lua, LuaSerdeExt, UserDataMethods, Value};
-use rdkafka::message::{Header, OwnedHeaders};
+use mlua::{IntoLua, Lua, LuaSerdeExt, UserDataMethods, Value};
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::message::{BorrowedMessage, Header, Headers, Message, OwnedHeaders};
 use rdkafka::producer::{FutureProducer, FutureRecord};
 use rdkafka::util::Timeout;
 use rdkafka::ClientConfig;
@@ -9,6 +11,7 @@ use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::task::LocalSet;
 
 #[derive(Clone)]
 struct Producer {
@@ -101,6 +104,198 @@ impl LuaUserData for Producer {
     }
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConsumerParams {
+    /// The librdkafka client configuration. Must include `group.id`
+    config: HashMap<String, String>,
+    /// The topics to subscribe to
+    topics: Vec<String>,
+    /// The event whose handler will be called for each record
+    event_name: String,
+    /// How long to wait before calling the handler again for
+    /// a record for which it raised an error
+    #[serde(
+        default = "ConsumerParams::default_retry_interval",
+        with = "duration_serde"
+    )]
+    retry_interval: Duration,
+}
+
+impl ConsumerParams {
+    fn default_retry_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn build_consumer(&self) -> anyhow::Result<StreamConsumer> {
+        if !self.config.contains_key("group.id") {
+            anyhow::bail!("kafka consumer config must include group.id");
+        }
+
+        let mut builder = ClientConfig::new();
+        for (k, v) in &self.config {
+            builder.set(k, v);
+        }
+        // We store the offset of each record only once the handler
+        // has successfully processed it, so that records are not lost
+        // if we are restarted while they are being processed
+        builder.set("enable.auto.offset.store", "false");
+
+        let consumer: StreamConsumer = builder.create()?;
+        let topics: Vec<&str> = self.topics.iter().map(|t| t.as_str()).collect();
+        consumer.subscribe(&topics)?;
+        Ok(consumer)
+    }
+
+    fn next_recv_backoff(&self, prior: Option<Duration>) -> Duration {
+        const MIN_RECV_BACKOFF: Duration = Duration::from_millis(100);
+        let max = self.retry_interval.max(MIN_RECV_BACKOFF);
+        match prior {
+            None => MIN_RECV_BACKOFF,
+            Some(prior) => (prior * 2).min(max),
+        }
+    }
+
+    /// Dispatches records to the handler one at a time, so that
+    /// we only fetch more records from the broker as quickly as
+    /// the handler can process them
+    async fn run(&self, consumer: StreamConsumer) {
+        let mut shutdown = ShutdownSubcription::get();
+        let mut recv_backoff = None;
+
+        'next_record: loop {
+            let msg = tokio::select! {
+                _ = shutdown.shutting_down() => break,
+                msg = consumer.recv() => msg,
+            };
+            let msg = match msg {
+                Ok(msg) => {
+                    recv_backoff = None;
+                    msg
+                }
+                Err(err) => {
+                    // Back off exponentially, up to the retry interval, so
+                    // that a broker that remains unavailable doesn't cause
+                    // us to spin and flood the logs
+                    let delay = self.next_recv_backoff(recv_backoff);
+                    recv_backoff.replace(delay);
+                    tracing::error!(
+                        "kafka consumer for {}: {err:#}. Will retry in {delay:?}",
+                        self.event_name
+                    );
+                    tokio::select! {
+                        _ = shutdown.shutting_down() => break,
+                        _ = tokio::time::sleep(delay) => {}
+                    };
+                    continue;
+                }
+            };
+
+            let record = ConsumerRecord::new(&msg);
+            loop {
+                let Ok(_activity) = Activity::get(format!("kafka consumer {}", self.event_name))
+                else {
+                    break 'next_record;
+                };
+                match self.dispatch(record.clone()).await {
+                    Ok(()) => break,
+                    Err(err) => {
+                        tracing::error!(
+                            "Error while dispatching {} for kafka record \
+                             {}/{}/{}: {err:#}. Will retry in {:?}",
+                            self.event_name,
+                            record.topic,
+                            record.partition,
+                            record.offset,
+                            self.retry_interval
+                        );
+                    }
+                }
+                tokio::select! {
+                    _ = shutdown.shutting_down() => break 'next_record,
+                    _ = tokio::time::sleep(self.retry_interval) => {}
+                };
+            }
+
+            if let Err(err) = consumer.store_offset_from_message(&msg) {
+                tracing::error!(
+                    "kafka consumer for {}: failed to store offset: {err:#}",
+                    self.event_name
+                );
+            }
+        }
+
+        if let Err(err) = consumer.commit_consumer_state(CommitMode::Sync) {
+            tracing::debug!(
+                "kafka consumer for {}: commit during shutdown: {err:#}",
+                self.event_name
+            );
+        }
+    }
+
+    async fn dispatch(&self, record: ConsumerRecord) -> anyhow::Result<()> {
+        let mut config = load_config().await?;
+        let sig = CallbackSignature::<ConsumerRecord, ()>::new(self.event_name.to_string());
+        config.async_call_callback_non_default(&sig, record).await
+    }
+}
+
+/// A record received by a consumer, as passed to the lua handler
+#[derive(Clone, Debug)]
+struct ConsumerRecord {
+    topic: String,
+    partition: i32,
+    offset: i64,
+    key: Option<Vec<u8>>,
+    payload: Option<Vec<u8>>,
+    headers: Vec<(String, Vec<u8>)>,
+    timestamp: Option<i64>,
+}
+
+impl ConsumerRecord {
+    fn new(msg: &BorrowedMessage) -> Self {
+        Self {
+            topic: msg.topic().to_string(),
+            partition: msg.partition(),
+            offset: msg.offset(),
+            key: msg.key().map(|k| k.to_vec()),
+            payload: msg.payload().map(|p| p.to_vec()),
+            headers: msg
+                .headers()
+                .map(|headers| {
+                    headers
+                        .iter()
+                        .map(|h| (h.key.to_string(), h.value.unwrap_or_default().to_vec()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            timestamp: msg.timestamp().to_millis(),
+        }
+    }
+}
+
+impl<'lua> IntoLua<'lua> for ConsumerRecord {
+    fn into_lua(self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+        let record = lua.create_table()?;
+        record.set("topic", self.topic)?;
+        record.set("partition", self.partition)?;
+        record.set("offset", self.offset)?;
+        if let Some(key) = self.key {
+            record.set("key", lua.create_string(&key)?)?;
+        }
+        if let Some(payload) = self.payload {
+            record.set("payload", lua.create_string(&payload)?)?;
+        }
+        let headers = lua.create_table()?;
+        for (k, v) in self.headers {
+            headers.set(k, lua.create_string(&v)?)?;
+        }
+        record.set("headers", headers)?;
+        record.set("timestamp", self.timestamp)?;
+        Ok(Value::Table(record))
+    }
+}
+
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
     let kafka_mod = get_or_create_sub_module(lua, "kafka")?;
 
@@ -120,5 +315,44 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kafka_mod.set(
+        "start_consumer",
+        lua.create_function(|lua, params: Value| {
+            let params: ConsumerParams = lua.from_value(params)?;
+
+            if config::is_validating() {
+                return Ok(());
+            }
+
+            // The consumer is created on its own thread, so that it is
+            // associated with that thread's runtime, but we wait for it
+            // here so that configuration errors are reported to the caller
+            let (tx, rx) = std::sync::mpsc::sync_channel(1);
+            std::thread::Builder::new()
+                .name(format!("kafka-consumer-{}", params.event_name))
+                .spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_io()
+                        .enable_time()
+                        .build()
+                        .unwrap();
+                    let local_set = LocalSet::new();
+                    local_set.block_on(&runtime, async move {
+                        match params.build_consumer() {
+                            Ok(consumer) => {
+                                tx.send(Ok(())).ok();
+                                params.run(consumer).await;
+                            }
+                            Err(err) => {
+                                tx.send(Err(err)).ok();
+                            }
+                        }
+                    });
+                })?;
+
+            rx.recv().map_err(any_err)?.map_err(any_err)
+        })?,
+    )?;
+
     Ok(())
 }
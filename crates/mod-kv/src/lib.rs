@@ -0,0 +1,380 @@
+//! Provides `kumo.kv`, a simple key/value store that policy can use
+//! to hold state that needs to outlive an individual lua context,
+//! such as greylisting records or rate counters.
+//!
+//! The store can either be held in the memory of the kumod process,
+//! in which case it is shared by all of its lua contexts, or in redis
+//! or memcached, in which case it can also be shared across nodes.
+use crate::memcache::MemcacheClient;
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use lruttl::LruCacheWithTtl;
+use mlua::{Lua, UserData, UserDataMethods, Value};
+use mod_redis::{cmd, FromRedisValue, RedisConnKey, RedisConnection};
+use once_cell::sync::Lazy;
+use parking_lot::FairMutex as Mutex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod memcache;
+
+static MEMORY_STORES: Lazy<Mutex<HashMap<String, Arc<MemoryStore>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static MEMCACHE_CLIENTS: Lazy<Mutex<HashMap<MemcacheParams, Arc<MemcacheClient>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// memcached interprets expirations beyond 30 days as absolute
+/// unix timestamps, so longer TTLs are capped to this value
+const MAX_MEMCACHE_TTL: u64 = 30 * 86400;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum KvStoreParams {
+    /// Held in the memory of this process
+    Memory {
+        /// Stores with the same name share the same data
+        #[serde(default = "default_store_name")]
+        name: String,
+        /// The maximum number of keys to hold. When the store is
+        /// full, the least recently used entry is evicted.
+        #[serde(default = "default_capacity")]
+        capacity: usize,
+    },
+    Redis(RedisConnKey),
+    Memcached(MemcacheParams),
+}
+
+fn default_store_name() -> String {
+    "default".to_string()
+}
+
+fn default_capacity() -> usize {
+    1024 * 1024
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct MemcacheParams {
+    /// eg: `memcache://127.0.0.1:11211`
+    pub servers: Vec<String>,
+    /// Maximum number of connections managed by the pool,
+    /// per server. Default is 10
+    #[serde(default)]
+    pub pool_size: Option<u32>,
+}
+
+impl KvStoreParams {
+    pub async fn open(&self) -> anyhow::Result<KvStore> {
+        match self {
+            Self::Memory { name, capacity } => {
+                let store = MEMORY_STORES
+                    .lock()
+                    .entry(name.to_string())
+                    .or_insert_with(|| Arc::new(MemoryStore::new(*capacity)))
+                    .clone();
+                Ok(KvStore::Memory(store))
+            }
+            Self::Redis(key) => Ok(KvStore::Redis(key.open()?)),
+            Self::Memcached(params) => {
+                let mut clients = MEMCACHE_CLIENTS.lock();
+                if let Some(client) = clients.get(params) {
+                    return Ok(KvStore::Memcached(client.clone()));
+                }
+                let client = Arc::new(MemcacheClient::new(
+                    &params.servers,
+                    params.pool_size.unwrap_or(10) as usize,
+                )?);
+                clients.insert(params.clone(), client.clone());
+                Ok(KvStore::Memcached(client))
+            }
+        }
+    }
+}
+
+/// Keys without a TTL are held for this long, unless they are evicted
+const NO_TTL: Duration = Duration::from_secs(100 * 365 * 86400);
+
+pub struct MemoryStore {
+    entries: LruCacheWithTtl<String, String>,
+}
+
+impl MemoryStore {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: LruCacheWithTtl::new(capacity),
+        }
+    }
+
+    fn expiration(ttl: Option<Duration>) -> Instant {
+        let now = Instant::now();
+        now.checked_add(ttl.unwrap_or(NO_TTL))
+            .or_else(|| now.checked_add(NO_TTL))
+            .unwrap_or(now)
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.entries.get(key)
+    }
+
+    fn set(&self, key: &str, value: String, ttl: Option<Duration>) {
+        self.entries
+            .insert(key.to_string(), value, Self::expiration(ttl));
+    }
+
+    fn incr(&self, key: &str, amount: u64, ttl: Option<Duration>) -> anyhow::Result<u64> {
+        let value = self
+            .entries
+            .update(key.to_string(), |existing| match existing {
+                Some((value, expiration)) => {
+                    let value: u64 = value.parse().map_err(|_| {
+                        anyhow::anyhow!("value of {key} is not an unsigned integer")
+                    })?;
+                    Ok((value.saturating_add(amount).to_string(), expiration))
+                }
+                None => Ok((amount.to_string(), Self::expiration(ttl))),
+            })?;
+        Ok(value.parse()?)
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+}
+
+#[derive(Clone)]
+pub enum KvStore {
+    Memory(Arc<MemoryStore>),
+    Redis(RedisConnection),
+    Memcached(Arc<MemcacheClient>),
+}
+
+fn memcache_ttl(ttl: Option<Duration>) -> u32 {
+    match ttl {
+        // 0 means that the entry doesn't expire, so round up
+        Some(ttl) => ttl.as_secs().clamp(1, MAX_MEMCACHE_TTL) as u32,
+        None => 0,
+    }
+}
+
+impl KvStore {
+    pub async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        match self {
+            Self::Memory(store) => Ok(store.get(key)),
+            Self::Redis(conn) => {
+                let mut get = cmd("GET");
+                get.arg(key);
+                let value = conn.query(get).await?;
+                Ok(Option::<String>::from_redis_value(&value)?)
+            }
+            Self::Memcached(client) => client.get(key).await,
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: String, ttl: Option<Duration>) -> anyhow::Result<()> {
+        match self {
+            Self::Memory(store) => {
+                store.set(key, value, ttl);
+                Ok(())
+            }
+            Self::Redis(conn) => {
+                let mut set = cmd("SET");
+                set.arg(key).arg(value);
+                if let Some(ttl) = ttl {
+                    set.arg("PX").arg(ttl.as_millis().max(1) as u64);
+                }
+                conn.query(set).await?;
+                Ok(())
+            }
+            Self::Memcached(client) => client.set(key, &value, memcache_ttl(ttl)).await,
+        }
+    }
+
+    /// Increments the counter held by key by amount, returning the
+    /// new value. If key doesn't exist, it is created with a value of
+    /// amount, and will expire after ttl. The ttl of an existing key
+    /// is not changed.
+    pub async fn incr(&self, key: &str, amount: u64, ttl: Option<Duration>) -> anyhow::Result<u64> {
+        match self {
+            Self::Memory(store) => store.incr(key, amount, ttl),
+            Self::Redis(conn) => {
+                if let Some(ttl) = ttl {
+                    // Create the key with its expiration, if it doesn't
+                    // already exist, so that the counter cannot be left
+                    // without an expiration if the increment fails
+                    let mut create = cmd("SET");
+                    create
+                        .arg(key)
+                        .arg(0)
+                        .arg("NX")
+                        .arg("PX")
+                        .arg(ttl.as_millis().max(1) as u64);
+                    conn.query(create).await?;
+                }
+                let mut incr = cmd("INCRBY");
+                incr.arg(key).arg(amount);
+                Ok(u64::from_redis_value(&conn.query(incr).await?)?)
+            }
+            Self::Memcached(client) => {
+                // memcached cannot increment a key that doesn't exist,
+                // so try to create it if incrementing fails. If that
+                // also fails, another client must have created it
+                // in the meantime, so increment it again.
+                if let Some(value) = client.incr(key, amount).await? {
+                    return Ok(value);
+                }
+                if client
+                    .add(key, &amount.to_string(), memcache_ttl(ttl))
+                    .await?
+                {
+                    return Ok(amount);
+                }
+                client
+                    .incr(key, amount)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("{key} was removed while it was being created"))
+            }
+        }
+    }
+
+    /// Removes key, returning true if it was present
+    pub async fn delete(&self, key: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(store) => Ok(store.delete(key)),
+            Self::Redis(conn) => {
+                let mut del = cmd("DEL");
+                del.arg(key);
+                Ok(i64::from_redis_value(&conn.query(del).await?)? > 0)
+            }
+            Self::Memcached(client) => client.delete(key).await,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Ttl(#[serde(with = "duration_serde")] Duration);
+
+fn ttl_from_lua(lua: &Lua, ttl: Option<Value>) -> mlua::Result<Option<Duration>> {
+    match ttl {
+        None | Some(Value::Nil) => Ok(None),
+        Some(ttl) => {
+            let Ttl(ttl) = from_lua_value(lua, ttl)?;
+            Ok(Some(ttl))
+        }
+    }
+}
+
+impl UserData for KvStore {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("get", |_, this, key: String| async move {
+            this.get(&key).await.map_err(any_err)
+        });
+
+        methods.add_async_method(
+            "set",
+            |lua, this, (key, value, ttl): (String, mlua::String, Option<Value>)| async move {
+                let ttl = ttl_from_lua(lua, ttl)?;
+                let value = value.to_str()?.to_string();
+                this.set(&key, value, ttl).await.map_err(any_err)
+            },
+        );
+
+        methods.add_async_method(
+            "incr",
+            |lua, this, (key, amount, ttl): (String, Option<u64>, Option<Value>)| async move {
+                let ttl = ttl_from_lua(lua, ttl)?;
+                this.incr(&key, amount.unwrap_or(1), ttl)
+                    .await
+                    .map_err(any_err)
+            },
+        );
+
+        methods.add_async_method("delete", |_, this, key: String| async move {
+            this.delete(&key).await.map_err(any_err)
+        });
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let kv_mod = get_or_create_sub_module(lua, "kv")?;
+
+    kv_mod.set(
+        "open",
+        lua.create_async_function(|lua, params: Value| async move {
+            let params: KvStoreParams = from_lua_value(lua, params)?;
+            params.open().await.map_err(any_err)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_store() {
+        let store = MemoryStore::new(2);
+        assert_eq!(store.get("a"), None);
+
+        store.set("a", "hello".to_string(), None);
+        assert_eq!(store.get("a").as_deref(), Some("hello"));
+        assert!(store.incr("a", 1, None).is_err());
+
+        assert_eq!(store.incr("b", 2, None).unwrap(), 2);
+        assert_eq!(store.incr("b", 3, None).unwrap(), 5);
+        assert_eq!(store.get("b").as_deref(), Some("5"));
+
+        // An expired entry behaves as though it is not present
+        store.set("b", "5".to_string(), Some(Duration::ZERO));
+        assert_eq!(store.get("b"), None);
+        assert_eq!(store.incr("b", 1, None).unwrap(), 1);
+
+        assert!(store.delete("b"));
+        assert!(!store.delete("b"));
+
+        // At capacity, the least recently used entry is evicted
+        store.set("b", "1".to_string(), Some(Duration::from_secs(60)));
+        assert_eq!(store.get("a").as_deref(), Some("hello"));
+        store.set("c", "1".to_string(), None);
+        assert_eq!(store.get("a").as_deref(), Some("hello"));
+        assert_eq!(store.get("b"), None);
+        assert_eq!(store.get("c").as_deref(), Some("1"));
+
+        // A failed increment leaves the value alone
+        assert!(store.incr("a", 1, None).is_err());
+        assert_eq!(store.get("a").as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn params() {
+        let lua = Lua::new();
+        let params: KvStoreParams = from_lua_value(
+            &lua,
+            lua.load("{backend='memcached', servers={'memcache://127.0.0.1:11211'}}")
+                .eval::<Value>()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            KvStoreParams::Memcached(MemcacheParams {
+                servers: vec!["memcache://127.0.0.1:11211".to_string()],
+                pool_size: None,
+            })
+        );
+
+        let params: KvStoreParams = from_lua_value(
+            &lua,
+            lua.load("{backend='memory'}").eval::<Value>().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            KvStoreParams::Memory {
+                name: "default".to_string(),
+                capacity: default_capacity(),
+            }
+        );
+    }
+}
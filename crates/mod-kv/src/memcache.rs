@@ -0,0 +1,288 @@
+//! A minimal async client for the memcached text protocol, covering
+//! just the commands that are needed to implement the kv store.
+use deadpool::managed::{Manager, Metrics, Object, Pool, RecycleError, RecycleResult};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+
+/// memcached rejects keys that are longer than this
+const MAX_KEY_LEN: usize = 250;
+
+pub struct MemcacheClient {
+    pools: Vec<Pool<ServerManager>>,
+}
+
+pub struct ServerManager {
+    addr: String,
+}
+
+pub struct ServerConn {
+    stream: BufStream<TcpStream>,
+    /// Set while a command is in flight, so that a connection whose
+    /// command was interrupted part way through isn't reused
+    busy: bool,
+}
+
+impl Manager for ServerManager {
+    type Type = ServerConn;
+    type Error = anyhow::Error;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        Ok(ServerConn {
+            stream: BufStream::new(stream),
+            busy: false,
+        })
+    }
+
+    async fn recycle(
+        &self,
+        conn: &mut Self::Type,
+        _metrics: &Metrics,
+    ) -> RecycleResult<anyhow::Error> {
+        if conn.busy {
+            return Err(RecycleError::message("connection is in an unknown state"));
+        }
+        Ok(())
+    }
+}
+
+impl ServerConn {
+    async fn send(&mut self, command: &[u8]) -> anyhow::Result<()> {
+        self.busy = true;
+        self.stream.write_all(command).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            anyhow::bail!("connection closed by memcached");
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line == "ERROR"
+            || line.starts_with("CLIENT_ERROR")
+            || line.starts_with("SERVER_ERROR")
+        {
+            anyhow::bail!("memcached responded with: {line}");
+        }
+        Ok(line)
+    }
+
+    /// Reads the final line of a response, after which the connection
+    /// can be used for another command
+    async fn read_reply(&mut self) -> anyhow::Result<String> {
+        let line = self.read_line().await?;
+        self.busy = false;
+        Ok(line)
+    }
+}
+
+fn check_key(key: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !key.is_empty()
+            && key.len() <= MAX_KEY_LEN
+            && !key.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()),
+        "invalid memcached key {key:?}: keys must be 1-{MAX_KEY_LEN} bytes \
+         with no whitespace or control characters"
+    );
+    Ok(())
+}
+
+fn unexpected(command: &str, line: &str) -> anyhow::Error {
+    anyhow::anyhow!("unexpected response to memcached {command}: {line}")
+}
+
+impl MemcacheClient {
+    pub fn new(servers: &[String], pool_size: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !servers.is_empty(),
+            "at least one memcached server is required"
+        );
+        let mut pools = vec![];
+        for server in servers {
+            let addr = server.strip_prefix("memcache://").unwrap_or(server);
+            let addr = addr.trim_end_matches('/').to_string();
+            // Connections are established on demand
+            let pool = Pool::builder(ServerManager { addr })
+                .runtime(deadpool::Runtime::Tokio1)
+                .max_size(pool_size)
+                .build()?;
+            pools.push(pool);
+        }
+        Ok(Self { pools })
+    }
+
+    /// Selects the server that holds key. This uses FNV-1a rather than
+    /// the std hasher so that every node picks the same server for a key.
+    async fn conn(&self, key: &str) -> anyhow::Result<Object<ServerManager>> {
+        check_key(key)?;
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in key.bytes() {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        let pool = &self.pools[(hash % self.pools.len() as u64) as usize];
+        pool.get().await.map_err(|err| anyhow::anyhow!("{err:#}"))
+    }
+
+    pub async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let mut conn = self.conn(key).await?;
+        conn.send(format!("get {key}\r\n").as_bytes()).await?;
+
+        let line = conn.read_line().await?;
+        if line == "END" {
+            conn.busy = false;
+            return Ok(None);
+        }
+
+        // VALUE <key> <flags> <bytes>
+        let len: usize = line
+            .strip_prefix("VALUE ")
+            .and_then(|rest| rest.split(' ').nth(2))
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| unexpected("get", &line))?;
+        let mut data = vec![0u8; len + 2];
+        conn.stream.read_exact(&mut data).await?;
+        data.truncate(len);
+
+        let line = conn.read_reply().await?;
+        if line != "END" {
+            return Err(unexpected("get", &line));
+        }
+        Ok(Some(String::from_utf8(data)?))
+    }
+
+    /// Issues a storage command, returning true if the value was stored
+    async fn store(&self, verb: &str, key: &str, value: &str, ttl: u32) -> anyhow::Result<bool> {
+        let mut conn = self.conn(key).await?;
+        let mut command = format!("{verb} {key} 0 {ttl} {}\r\n", value.len()).into_bytes();
+        command.extend_from_slice(value.as_bytes());
+        command.extend_from_slice(b"\r\n");
+        conn.send(&command).await?;
+
+        match conn.read_reply().await?.as_str() {
+            "STORED" => Ok(true),
+            "NOT_STORED" => Ok(false),
+            line => Err(unexpected(verb, line)),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str, ttl: u32) -> anyhow::Result<()> {
+        self.store("set", key, value, ttl).await?;
+        Ok(())
+    }
+
+    /// Stores value only if key doesn't already exist, returning
+    /// true if it was stored
+    pub async fn add(&self, key: &str, value: &str, ttl: u32) -> anyhow::Result<bool> {
+        self.store("add", key, value, ttl).await
+    }
+
+    /// Increments key, returning None if it doesn't exist
+    pub async fn incr(&self, key: &str, amount: u64) -> anyhow::Result<Option<u64>> {
+        let mut conn = self.conn(key).await?;
+        conn.send(format!("incr {key} {amount}\r\n").as_bytes())
+            .await?;
+
+        let line = conn.read_reply().await?;
+        if line == "NOT_FOUND" {
+            return Ok(None);
+        }
+        line.parse()
+            .map(Some)
+            .map_err(|_| unexpected("incr", &line))
+    }
+
+    /// Removes key, returning true if it was present
+    pub async fn delete(&self, key: &str) -> anyhow::Result<bool> {
+        let mut conn = self.conn(key).await?;
+        conn.send(format!("delete {key}\r\n").as_bytes()).await?;
+
+        match conn.read_reply().await?.as_str() {
+            "DELETED" => Ok(true),
+            "NOT_FOUND" => Ok(false),
+            line => Err(unexpected("delete", line)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+
+    /// Serves a single connection with just enough of the protocol
+    /// to exercise the client
+    async fn fake_memcached(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufStream::new(stream);
+        let mut data: HashMap<String, String> = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                return;
+            }
+            let words: Vec<&str> = line.trim_end().split(' ').collect();
+            let reply = match words[..] {
+                ["get", key] => match data.get(key) {
+                    Some(value) => format!("VALUE {key} 0 {}\r\n{value}\r\nEND", value.len()),
+                    None => "END".to_string(),
+                },
+                [verb @ ("set" | "add"), key, _flags, _ttl, len] => {
+                    let mut value = vec![0u8; len.parse::<usize>().unwrap() + 2];
+                    stream.read_exact(&mut value).await.unwrap();
+                    value.truncate(value.len() - 2);
+                    if verb == "add" && data.contains_key(key) {
+                        "NOT_STORED".to_string()
+                    } else {
+                        data.insert(key.to_string(), String::from_utf8(value).unwrap());
+                        "STORED".to_string()
+                    }
+                }
+                ["incr", key, amount] => match data.get_mut(key) {
+                    Some(value) => {
+                        let n = value.parse::<u64>().unwrap() + amount.parse::<u64>().unwrap();
+                        *value = n.to_string();
+                        n.to_string()
+                    }
+                    None => "NOT_FOUND".to_string(),
+                },
+                ["delete", key] => match data.remove(key) {
+                    Some(_) => "DELETED".to_string(),
+                    None => "NOT_FOUND".to_string(),
+                },
+                _ => "ERROR".to_string(),
+            };
+            stream.write_all(reply.as_bytes()).await.unwrap();
+            stream.write_all(b"\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(fake_memcached(listener));
+
+        let client = MemcacheClient::new(&[format!("memcache://{addr}")], 1).unwrap();
+        assert_eq!(client.get("a").await.unwrap(), None);
+        client.set("a", "hello\r\nworld", 0).await.unwrap();
+        assert_eq!(
+            client.get("a").await.unwrap().as_deref(),
+            Some("hello\r\nworld")
+        );
+
+        assert_eq!(client.incr("n", 1).await.unwrap(), None);
+        assert!(client.add("n", "1", 60).await.unwrap());
+        assert!(!client.add("n", "1", 60).await.unwrap());
+        assert_eq!(client.incr("n", 2).await.unwrap(), Some(3));
+
+        assert!(client.delete("n").await.unwrap());
+        assert!(!client.delete("n").await.unwrap());
+
+        assert!(client.get("has space").await.is_err());
+    }
+}
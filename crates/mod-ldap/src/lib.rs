@@ -0,0 +1,292 @@
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use deadpool::managed::{Manager, Metrics, Pool, RecycleError, RecycleResult};
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+use mlua::{Lua, UserData, UserDataMethods, Value};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+static POOLS: Lazy<Mutex<HashMap<LdapConnKey, Pool<LdapManager>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The LDAP result code for a bind with invalid credentials
+const INVALID_CREDENTIALS: u32 = 49;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LdapConnKey {
+    /// eg: `ldap://ldap.example.com` or `ldaps://ldap.example.com:636`
+    pub url: String,
+    /// If set, pooled connections bind as this DN before use
+    #[serde(default)]
+    pub bind_dn: Option<String>,
+    #[serde(default)]
+    pub bind_password: Option<String>,
+    /// Upgrade `ldap://` connections to TLS via StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    /// Skip verification of the server certificate
+    #[serde(default)]
+    pub insecure_skip_tls_verify: bool,
+    /// Maximum number of connections managed by the pool.
+    /// Default is 10
+    #[serde(default)]
+    pub pool_size: Option<usize>,
+    #[serde(default, with = "duration_serde")]
+    pub connect_timeout: Option<Duration>,
+    #[serde(default, with = "duration_serde")]
+    pub recycle_timeout: Option<Duration>,
+    #[serde(default, with = "duration_serde")]
+    pub wait_timeout: Option<Duration>,
+    /// Limits how long an individual search may take
+    #[serde(default, with = "duration_serde")]
+    pub response_timeout: Option<Duration>,
+}
+
+impl LdapConnKey {
+    fn settings(&self) -> LdapConnSettings {
+        let mut settings = LdapConnSettings::new()
+            .set_starttls(self.starttls)
+            .set_no_tls_verify(self.insecure_skip_tls_verify);
+        if let Some(timeout) = self.connect_timeout {
+            settings = settings.set_conn_timeout(timeout);
+        }
+        settings
+    }
+
+    /// Establish a new connection, without binding
+    async fn connect(&self) -> anyhow::Result<Ldap> {
+        let (conn, ldap) = LdapConnAsync::with_settings(self.settings(), &self.url).await?;
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            if let Err(err) = conn.drive().await {
+                tracing::error!("ldap connection to {url}: {err:#}");
+            }
+        });
+        Ok(ldap)
+    }
+
+    pub fn get_pool(&self) -> anyhow::Result<Pool<LdapManager>> {
+        let mut pools = POOLS.lock().unwrap();
+        if let Some(pool) = pools.get(self) {
+            return Ok(pool.clone());
+        }
+
+        let mut builder = Pool::builder(LdapManager(self.clone()))
+            .runtime(deadpool::Runtime::Tokio1)
+            .create_timeout(self.connect_timeout)
+            .recycle_timeout(self.recycle_timeout)
+            .wait_timeout(self.wait_timeout);
+
+        if let Some(limit) = self.pool_size {
+            builder = builder.max_size(limit);
+        }
+
+        let pool = builder.build()?;
+
+        pools.insert(self.clone(), pool.clone());
+
+        Ok(pool)
+    }
+
+    pub fn open(&self) -> anyhow::Result<LdapConnection> {
+        anyhow::ensure!(
+            self.bind_dn.is_some() || self.bind_password.is_none(),
+            "bind_password requires bind_dn"
+        );
+        Ok(LdapConnection(Arc::new(self.clone())))
+    }
+}
+
+pub struct LdapManager(LdapConnKey);
+
+impl Manager for LdapManager {
+    type Type = Ldap;
+    type Error = anyhow::Error;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        let mut ldap = self.0.connect().await?;
+        if let Some(dn) = &self.0.bind_dn {
+            let password = self.0.bind_password.as_deref().unwrap_or("");
+            ldap.simple_bind(dn, password).await?.success()?;
+        }
+        Ok(ldap)
+    }
+
+    async fn recycle(
+        &self,
+        ldap: &mut Self::Type,
+        _metrics: &Metrics,
+    ) -> RecycleResult<anyhow::Error> {
+        if ldap.is_closed() {
+            return Err(RecycleError::message("connection was closed"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SearchScope {
+    Base,
+    #[serde(rename = "one")]
+    OneLevel,
+    #[default]
+    Subtree,
+}
+
+impl From<SearchScope> for Scope {
+    fn from(scope: SearchScope) -> Scope {
+        match scope {
+            SearchScope::Base => Scope::Base,
+            SearchScope::OneLevel => Scope::OneLevel,
+            SearchScope::Subtree => Scope::Subtree,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SearchParams {
+    base: String,
+    #[serde(default)]
+    scope: SearchScope,
+    #[serde(default = "SearchParams::default_filter")]
+    filter: String,
+    /// The attributes to return. If empty, all user
+    /// attributes are returned.
+    #[serde(default)]
+    attributes: Vec<String>,
+}
+
+impl SearchParams {
+    fn default_filter() -> String {
+        "(objectClass=*)".to_string()
+    }
+}
+
+#[derive(Clone)]
+pub struct LdapConnection(Arc<LdapConnKey>);
+
+impl LdapConnection {
+    async fn search(&self, params: SearchParams) -> anyhow::Result<Vec<SearchEntry>> {
+        let pool = self.0.get_pool()?;
+        let mut ldap = pool.get().await.map_err(|err| anyhow::anyhow!("{err:#}"))?;
+        if let Some(timeout) = self.0.response_timeout {
+            ldap.with_timeout(timeout);
+        }
+        let (entries, _result) = ldap
+            .search(
+                &params.base,
+                params.scope.into(),
+                &params.filter,
+                params.attributes,
+            )
+            .await?
+            .success()?;
+        Ok(entries.into_iter().map(SearchEntry::construct).collect())
+    }
+
+    /// Verify a set of credentials by binding with them on a dedicated
+    /// connection, so that the identity of the pooled connections is
+    /// unaffected. Returns false if the credentials are invalid.
+    async fn bind(&self, dn: &str, password: &str) -> anyhow::Result<bool> {
+        // An empty password would result in an unauthenticated bind,
+        // which most servers will accept regardless of the dn
+        if password.is_empty() {
+            return Ok(false);
+        }
+        let mut ldap = self.0.connect().await?;
+        if let Some(timeout) = self.0.response_timeout {
+            ldap.with_timeout(timeout);
+        }
+        let result = ldap.simple_bind(dn, password).await?;
+        ldap.unbind().await.ok();
+        match result.success() {
+            Ok(_) => Ok(true),
+            Err(LdapError::LdapResult { result }) if result.rc == INVALID_CREDENTIALS => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn entry_to_lua<'lua>(lua: &'lua Lua, entry: SearchEntry) -> mlua::Result<Value<'lua>> {
+    let attributes = lua.create_table()?;
+    for (name, values) in entry.attrs {
+        attributes.set(name, values)?;
+    }
+    for (name, values) in entry.bin_attrs {
+        let array = lua.create_table()?;
+        for v in values {
+            array.push(lua.create_string(&v)?)?;
+        }
+        attributes.set(name, array)?;
+    }
+
+    let result = lua.create_table()?;
+    result.set("dn", entry.dn)?;
+    result.set("attributes", attributes)?;
+    Ok(Value::Table(result))
+}
+
+impl UserData for LdapConnection {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("search", |lua, this, params: Value| async move {
+            let params: SearchParams = from_lua_value(lua, params)?;
+            let entries = this.search(params).await.map_err(any_err)?;
+            let result = lua.create_table()?;
+            for entry in entries {
+                result.push(entry_to_lua(lua, entry)?)?;
+            }
+            Ok(result)
+        });
+
+        methods.add_async_method(
+            "bind",
+            |_, this, (dn, password): (String, String)| async move {
+                this.bind(&dn, &password).await.map_err(any_err)
+            },
+        );
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let ldap_mod = get_or_create_sub_module(lua, "ldap")?;
+
+    ldap_mod.set(
+        "open",
+        lua.create_function(move |lua, key: Value| {
+            let key: LdapConnKey = from_lua_value(lua, key)?;
+            key.open().map_err(any_err)
+        })?,
+    )?;
+
+    ldap_mod.set(
+        "escape_filter",
+        lua.create_function(move |_, value: String| Ok(ldap3::ldap_escape(value).to_string()))?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn search_params() {
+        let lua = Lua::new();
+        let params: SearchParams = from_lua_value(
+            &lua,
+            lua.load("{base='dc=example,dc=com', scope='one', attributes={'mail'}}")
+                .eval::<Value>()
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(matches!(params.scope, SearchScope::OneLevel));
+        assert_eq!(params.filter, "(objectClass=*)");
+        assert_eq!(params.attributes, vec!["mail".to_string()]);
+    }
+}
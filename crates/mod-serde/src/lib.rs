@@ -1,5 +1,6 @@
 use anyhow::Context;
 use config::{any_err, get_or_create_module, get_or_create_sub_module, serialize_options};
+use data_loader::interpolate_secrets;
 use mlua::{Lua, LuaSerdeExt, Value as LuaValue};
 use serde_json::Value as JValue;
 
@@ -57,9 +58,13 @@ async fn json_load(lua: &Lua, file_name: String) -> mlua::Result<LuaValue> {
 
     let stripped = json_comments::StripComments::new(&*data);
 
-    let obj: serde_json::Value = serde_json::from_reader(stripped)
+    let mut obj: serde_json::Value = serde_json::from_reader(stripped)
         .with_context(|| format!("parsing {file_name} as json"))
         .map_err(any_err)?;
+    interpolate_secrets(&mut obj)
+        .await
+        .with_context(|| format!("resolving secrets in {file_name}"))
+        .map_err(any_err)?;
     lua.to_value_with(&obj, serialize_options())
 }
 
@@ -85,9 +90,13 @@ async fn toml_load(lua: &Lua, file_name: String) -> mlua::Result<LuaValue> {
         .with_context(|| format!("reading file {file_name}"))
         .map_err(any_err)?;
 
-    let obj: toml::Value = toml::from_str(&data)
+    let mut obj: toml::Value = toml::from_str(&data)
         .with_context(|| format!("parsing {file_name} as toml"))
         .map_err(any_err)?;
+    interpolate_secrets(&mut obj)
+        .await
+        .with_context(|| format!("resolving secrets in {file_name}"))
+        .map_err(any_err)?;
     lua.to_value_with(&obj, serialize_options())
 }
 
@@ -112,9 +121,13 @@ async fn yaml_load(lua: &Lua, file_name: String) -> mlua::Result<LuaValue> {
         .with_context(|| format!("reading file {file_name}"))
         .map_err(any_err)?;
 
-    let value: JValue = serde_yaml::from_slice(&data)
+    let mut value: JValue = serde_yaml::from_slice(&data)
         .with_context(|| format!("parsing {file_name} as yaml"))
         .map_err(any_err)?;
+    interpolate_secrets(&mut value)
+        .await
+        .with_context(|| format!("resolving secrets in {file_name}"))
+        .map_err(any_err)?;
     lua.to_value_with(&value, serialize_options())
 }
 
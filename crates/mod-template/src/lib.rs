@@ -0,0 +1,175 @@
+//! A shared minijinja template engine.
+//!
+//! All of the places that render user supplied templates (injection,
+//! log records, policy via `kumo.template.render`) construct their
+//! environment via `new_environment` so that they have the same
+//! set of filters and functions available.
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use mailparsing::Header;
+use minijinja::value::{Value, ValueKind};
+use minijinja::{Environment, Error, ErrorKind};
+use mlua::{Lua, Value as LuaValue};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+
+/// The maximum number of distinct template sources retained by
+/// the cache used by `render`.
+const MAX_CACHED_TEMPLATES: usize = 1024;
+
+static CACHE: Lazy<RwLock<Environment<'static>>> = Lazy::new(|| RwLock::new(new_environment()));
+
+/// Create a new template environment with the standard set of
+/// filters and functions registered.
+pub fn new_environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    minijinja_contrib::add_to_environment(&mut env);
+    env.add_filter("idn", idn_filter);
+    env.add_filter("rfc2047", rfc2047_filter);
+    env.add_filter("csv", csv_filter);
+    env
+}
+
+/// Format a value as a CSV field, or a sequence of values as a
+/// CSV row, quoting any fields that contain a delimiter, quote
+/// or line break as described by RFC 4180.
+fn csv_filter(value: Value) -> Result<String, Error> {
+    fn field(value: &Value) -> String {
+        let text = match value.as_str() {
+            Some(s) => s.to_string(),
+            None if value.is_none() || value.is_undefined() => String::new(),
+            None => value.to_string(),
+        };
+        if text.contains([',', '"', '\r', '\n']) {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        } else {
+            text
+        }
+    }
+
+    if value.kind() == ValueKind::Seq {
+        let fields: Vec<String> = value.try_iter()?.map(|item| field(&item)).collect();
+        Ok(fields.join(","))
+    } else {
+        Ok(field(&value))
+    }
+}
+
+/// Convert an internationalized domain name, or the domain portion
+/// of an email address, to its ASCII (punycode) representation.
+fn idn_filter(value: String) -> Result<String, Error> {
+    let (local_part, domain) = match value.rsplit_once('@') {
+        Some((local_part, domain)) => (Some(local_part), domain),
+        None => (None, value.as_str()),
+    };
+    let domain = idna::domain_to_ascii(domain).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidOperation,
+            format!("idn: {domain} is not a valid domain: {err:?}"),
+        )
+    })?;
+    Ok(match local_part {
+        Some(local_part) => format!("{local_part}@{domain}"),
+        None => domain,
+    })
+}
+
+/// Encode text for use in an unstructured header such as Subject,
+/// using RFC 2047 encoded-words for any non-ASCII portions.
+fn rfc2047_filter(value: String) -> String {
+    Header::new_unstructured("X", value)
+        .get_raw_value()
+        .to_string()
+}
+
+/// Render template `source` with `context`.
+/// The compiled form of the template is cached, so repeatedly rendering
+/// the same template source only incurs the cost of parsing it once.
+pub fn render(source: &str, context: &serde_json::Value) -> anyhow::Result<String> {
+    let context = minijinja::Value::from_serialize(context);
+    {
+        let env = CACHE.read();
+        if let Ok(template) = env.get_template(source) {
+            return Ok(template.render(context)?);
+        }
+    }
+
+    let mut env = CACHE.write();
+    if env.templates().count() >= MAX_CACHED_TEMPLATES {
+        env.clear_templates();
+    }
+    env.add_template_owned(source.to_string(), source.to_string())?;
+    let template = env.get_template(source)?;
+    Ok(template.render(context)?)
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let template_mod = get_or_create_sub_module(lua, "template")?;
+
+    template_mod.set(
+        "render",
+        lua.create_function(|lua, (source, context): (String, Option<LuaValue>)| {
+            let context: serde_json::Value = match context {
+                Some(context) => from_lua_value(lua, context)?,
+                None => serde_json::Value::Null,
+            };
+            render(&source, &context).map_err(any_err)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filters() {
+        assert_eq!(
+            render(
+                "{{ domain | idn }} {{ addr | idn }}",
+                &serde_json::json!({"domain": "bücher.example", "addr": "user@bücher.example"})
+            )
+            .unwrap(),
+            "xn--bcher-kva.example user@xn--bcher-kva.example"
+        );
+        assert_eq!(
+            render(
+                "{{ subject | rfc2047 }}",
+                &serde_json::json!({"subject": "hello André"})
+            )
+            .unwrap(),
+            "hello =?UTF-8?q?Andr=C3=A9?="
+        );
+        assert_eq!(
+            render(
+                "{{ [id, code, text, missing] | csv }}",
+                &serde_json::json!({"id": "abc", "code": 250, "text": "a \"b\", c"})
+            )
+            .unwrap(),
+            r#"abc,250,"a ""b"", c","#
+        );
+        assert_eq!(
+            render("{{ q | urlencode }}", &serde_json::json!({"q": "a b&c"})).unwrap(),
+            "a%20b%26c"
+        );
+    }
+
+    #[test]
+    fn lua_render() {
+        let lua = Lua::new();
+        register(&lua).unwrap();
+
+        let result: String = lua
+            .load(
+                r#"
+            local kumo = require 'kumo'
+            return kumo.template.render('Hello {{ name }}!', { name = 'world' })
+        "#,
+            )
+            .eval()
+            .unwrap();
+
+        assert_eq!(result, "Hello world!");
+    }
+}
@@ -3,15 +3,29 @@ use hickory_resolver::Name;
 use lruttl::LruCacheWithTtl;
 use once_cell::sync::Lazy;
 use policy::MtaStsPolicy;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 static CACHE: Lazy<Mutex<LruCacheWithTtl<Name, CachedPolicy>>> =
     Lazy::new(|| Mutex::new(LruCacheWithTtl::new(64 * 1024)));
+static CACHE_PATH: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
 
 pub mod dns;
+pub mod persist;
 pub mod policy;
 
+/// Configure a directory into which fetched policies will be persisted,
+/// so that they survive a restart of the process.
+/// Pass `None` to disable persistence, which is the default.
+pub fn set_cache_path(path: Option<PathBuf>) {
+    *CACHE_PATH.lock().unwrap() = path;
+}
+
+fn get_cache_path() -> Option<PathBuf> {
+    CACHE_PATH.lock().unwrap().clone()
+}
+
 #[derive(Clone)]
 struct CachedPolicy {
     pub id: String,
@@ -76,8 +90,55 @@ pub async fn get_policy_for_domain(policy_domain: &str) -> anyhow::Result<Arc<Mt
     get_policy_for_domain_impl(policy_domain, &*resolver, &Getter {}).await
 }
 
-fn cache_lookup(name: &Name) -> Option<CachedPolicy> {
-    CACHE.lock().unwrap().get(&name).map(|p| p.clone())
+async fn cache_lookup(name: &Name) -> Option<CachedPolicy> {
+    if let Some(cached) = CACHE.lock().unwrap().get(&name).map(|p| p.clone()) {
+        return Some(cached);
+    }
+
+    // Not in memory; perhaps we persisted it in a prior run
+    let dir = get_cache_path()?;
+    match persist::load_policy(&dir, name).await {
+        Ok(Some(entry)) => {
+            let remaining = entry
+                .expires
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO);
+            let cached = CachedPolicy {
+                id: entry.id,
+                policy: Arc::new(entry.policy),
+            };
+            CACHE
+                .lock()
+                .unwrap()
+                .insert(name.clone(), cached.clone(), Instant::now() + remaining);
+            Some(cached)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            tracing::error!("failed to load persisted MTA-STS policy for {name}: {err:#}");
+            None
+        }
+    }
+}
+
+async fn cache_insert(name: Name, id: String, policy: Arc<MtaStsPolicy>) {
+    let ttl = Duration::from_secs(policy.max_age);
+
+    if let Some(dir) = get_cache_path() {
+        let entry = persist::PersistedPolicy {
+            id: id.clone(),
+            expires: SystemTime::now() + ttl,
+            policy: (*policy).clone(),
+        };
+        if let Err(err) = persist::save_policy(&dir, &name, &entry).await {
+            tracing::error!("failed to persist MTA-STS policy for {name}: {err:#}");
+        }
+    }
+
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(name, CachedPolicy { id, policy }, Instant::now() + ttl);
 }
 
 async fn get_policy_for_domain_impl(
@@ -87,7 +148,9 @@ async fn get_policy_for_domain_impl(
 ) -> anyhow::Result<Arc<MtaStsPolicy>> {
     let name = Name::from_str_relaxed(policy_domain)?.to_lowercase();
 
-    if let Some(cached) = cache_lookup(&name) {
+    let cached = cache_lookup(&name).await;
+
+    if let Some(cached) = &cached {
         // Removal of the DNS record does not invalidate our
         // cached result, only updating it with a different id
         let still_valid = dns::resolve_dns_record(policy_domain, resolver)
@@ -100,20 +163,30 @@ async fn get_policy_for_domain_impl(
         }
     }
 
-    let record = dns::resolve_dns_record(policy_domain, resolver).await?;
-
-    let policy = Arc::new(policy::load_policy_for_domain(policy_domain, getter).await?);
-
-    let expires = Instant::now() + Duration::from_secs(policy.max_age);
+    let fetched = async {
+        let record = dns::resolve_dns_record(policy_domain, resolver).await?;
+        let policy = policy::load_policy_for_domain(policy_domain, getter).await?;
+        anyhow::Result::<_>::Ok((record, policy))
+    }
+    .await;
+
+    let (record, policy) = match (fetched, cached) {
+        (Ok(result), _) => result,
+        // <https://datatracker.ietf.org/doc/html/rfc8461#section-5.1>
+        // If the refresh fails, continue to use the unexpired
+        // cached policy rather than falling back to no policy
+        (Err(err), Some(cached)) => {
+            tracing::debug!(
+                "failed to refresh MTA-STS policy for {policy_domain}: {err:#}. \
+                 Using previously cached policy"
+            );
+            return Ok(cached.policy);
+        }
+        (Err(err), None) => return Err(err),
+    };
 
-    CACHE.lock().unwrap().insert(
-        name,
-        CachedPolicy {
-            id: record.id,
-            policy: Arc::clone(&policy),
-        },
-        expires,
-    );
+    let policy = Arc::new(policy);
+    cache_insert(name, record.id, Arc::clone(&policy)).await;
 
     Ok(policy)
 }
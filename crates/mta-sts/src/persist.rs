@@ -0,0 +1,175 @@
+//! Persistence of MTA-STS policies, so that the cache of previously
+//! fetched policies survives a restart of the process.
+//!
+//! Each policy is stored in its own file named after the policy domain.
+//! The file consists of a small header that records the DNS id and
+//! the expiration time, followed by a blank line and then the policy
+//! text in the same format as it is served via HTTPS.
+use crate::policy::MtaStsPolicy;
+use hickory_resolver::Name;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub struct PersistedPolicy {
+    pub id: String,
+    pub expires: SystemTime,
+    pub policy: MtaStsPolicy,
+}
+
+impl PersistedPolicy {
+    pub fn encode(&self) -> String {
+        let expires = self
+            .expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs();
+        format!(
+            "id: {}\nexpires: {expires}\n\n{}",
+            self.id,
+            self.policy.to_policy_string()
+        )
+    }
+
+    pub fn decode(data: &str) -> anyhow::Result<Self> {
+        let (header, policy) = data
+            .split_once("\n\n")
+            .ok_or_else(|| anyhow::anyhow!("persisted policy is missing header"))?;
+
+        let mut id = None;
+        let mut expires = None;
+        for line in header.lines() {
+            match line.split_once(':') {
+                Some(("id", value)) => {
+                    id.replace(value.trim().to_string());
+                }
+                Some(("expires", value)) => {
+                    let secs: u64 = value.trim().parse()?;
+                    expires.replace(UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                _ => anyhow::bail!("invalid persisted policy header line {line}"),
+            }
+        }
+
+        Ok(Self {
+            id: id.ok_or_else(|| anyhow::anyhow!("persisted policy is missing id"))?,
+            expires: expires
+                .ok_or_else(|| anyhow::anyhow!("persisted policy is missing expires"))?,
+            policy: MtaStsPolicy::parse(policy)?,
+        })
+    }
+}
+
+fn policy_file_name(dir: &Path, name: &Name) -> anyhow::Result<PathBuf> {
+    let name = name.to_ascii().to_lowercase();
+    let name = name.trim_end_matches('.');
+    anyhow::ensure!(
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_')),
+        "refusing to persist MTA-STS policy for unusual domain name {name}"
+    );
+    Ok(dir.join(name))
+}
+
+/// Produce a name for the temporary file used while saving `path`.
+/// The name is unique to this process and this call, so that
+/// concurrent saves never write to the same temporary file.
+/// The leading dot guarantees that it cannot collide with the
+/// name of a policy file.
+fn temp_file_name(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{file_name}.{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+pub async fn save_policy(dir: &Path, name: &Name, entry: &PersistedPolicy) -> anyhow::Result<()> {
+    let path = policy_file_name(dir, name)?;
+    tokio::fs::create_dir_all(dir).await?;
+
+    // Write to a temporary file and rename it into place, so that
+    // a concurrent reader never observes a partially written file
+    let temp = temp_file_name(&path);
+    tokio::fs::write(&temp, entry.encode()).await?;
+    tokio::fs::rename(&temp, &path).await?;
+    Ok(())
+}
+
+/// Load a previously persisted policy.
+/// Returns Ok(None) if there is no persisted policy, or if it has expired.
+pub async fn load_policy(dir: &Path, name: &Name) -> anyhow::Result<Option<PersistedPolicy>> {
+    let path = policy_file_name(dir, name)?;
+    let data = match tokio::fs::read_to_string(&path).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let entry = PersistedPolicy::decode(&data)?;
+    if entry.expires <= SystemTime::now() {
+        tokio::fs::remove_file(&path).await.ok();
+        return Ok(None);
+    }
+
+    Ok(Some(entry))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let entry = PersistedPolicy {
+            id: "20190429T010101".to_string(),
+            expires: UNIX_EPOCH + Duration::from_secs(1700000000),
+            policy: MtaStsPolicy::parse(
+                "version: STSv1\nmode: enforce\nmx: mail.example.com\nmax_age: 604800",
+            )
+            .unwrap(),
+        };
+
+        let encoded = entry.encode();
+        assert_eq!(
+            encoded,
+            "id: 20190429T010101\nexpires: 1700000000\n\n\
+             version: STSv1\nmode: enforce\nmx: mail.example.com\nmax_age: 604800\n"
+        );
+
+        let decoded = PersistedPolicy::decode(&encoded).unwrap();
+        assert_eq!(decoded.id, entry.id);
+        assert_eq!(decoded.expires, entry.expires);
+        assert_eq!(decoded.policy.mx, entry.policy.mx);
+    }
+
+    #[test]
+    fn file_names() {
+        let dir = Path::new("/cache");
+        assert_eq!(
+            policy_file_name(dir, &Name::from_str_relaxed("Example.COM.").unwrap()).unwrap(),
+            PathBuf::from("/cache/example.com")
+        );
+    }
+
+    #[test]
+    fn temp_file_names() {
+        let com = temp_file_name(Path::new("/cache/example.com"));
+        let net = temp_file_name(Path::new("/cache/example.net"));
+        assert_ne!(com, net);
+        assert_ne!(com, temp_file_name(Path::new("/cache/example.com")));
+        assert_eq!(com.parent(), Some(Path::new("/cache")));
+        let name = com.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".example.com."), "{name}");
+        assert!(name.ends_with(".tmp"), "{name}");
+    }
+}
@@ -1,14 +1,36 @@
 use futures::future::BoxFuture;
 use std::collections::BTreeMap;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum PolicyMode {
     Enforce,
     Testing,
     None,
 }
 
-#[derive(Debug)]
+impl PolicyMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Enforce => "enforce",
+            Self::Testing => "testing",
+            Self::None => "none",
+        }
+    }
+}
+
+impl std::str::FromStr for PolicyMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "enforce" => Ok(Self::Enforce),
+            "testing" => Ok(Self::Testing),
+            "none" => Ok(Self::None),
+            _ => anyhow::bail!("invalid MTA-STS policy mode {s}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct MtaStsPolicy {
     pub mode: PolicyMode,
     pub mx: Vec<String>,
@@ -45,12 +67,9 @@ impl MtaStsPolicy {
 
         let mode = match fields.remove("mode") {
             None => anyhow::bail!("STS policy {data} is missing required mode"),
-            Some(mode) if mode.len() == 1 => match mode[0].as_str() {
-                "enforce" => PolicyMode::Enforce,
-                "testing" => PolicyMode::Testing,
-                "none" => PolicyMode::None,
-                _ => anyhow::bail!("STS policy {data} has invalid mode"),
-            },
+            Some(mode) if mode.len() == 1 => mode[0]
+                .parse()
+                .map_err(|_| anyhow::anyhow!("STS policy {data} has invalid mode"))?,
             _ => anyhow::bail!("STS policy {data} has invalid mode"),
         };
 
@@ -82,6 +101,23 @@ impl MtaStsPolicy {
         })
     }
 
+    /// Produce the textual representation of the policy, in the
+    /// same format as the policy file that is served via HTTPS.
+    /// The output can be round-tripped through `MtaStsPolicy::parse`.
+    pub fn to_policy_string(&self) -> String {
+        let mut result = format!("version: STSv1\nmode: {}\n", self.mode.as_str());
+        for mx in &self.mx {
+            result.push_str(&format!("mx: {mx}\n"));
+        }
+        result.push_str(&format!("max_age: {}\n", self.max_age));
+        for (key, values) in &self.fields {
+            for value in values {
+                result.push_str(&format!("{key}: {value}\n"));
+            }
+        }
+        result
+    }
+
     /// Returns true if `name` matches any of the allowed mx
     /// host name patterns.
     /// `name` must be lowercase.
@@ -204,6 +240,21 @@ MtaStsPolicy {
         );
     }
 
+    #[test]
+    fn policy_round_trip() {
+        let policy = MtaStsPolicy::parse(SAMPLE_POLICY).unwrap();
+        let text = policy.to_policy_string();
+        assert_eq!(
+            text,
+            "version: STSv1\nmode: enforce\nmx: mail.example.com\n\
+             mx: *.example.net\nmx: backupmx.example.com\nmax_age: 604800\n"
+        );
+        let parsed = MtaStsPolicy::parse(&text).unwrap();
+        assert_eq!(parsed.mode, policy.mode);
+        assert_eq!(parsed.mx, policy.mx);
+        assert_eq!(parsed.max_age, policy.max_age);
+    }
+
     #[test]
     fn name_matching() {
         assert!(name_match("foo.com", "foo.com"));
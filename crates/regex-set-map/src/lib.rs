@@ -1,10 +1,32 @@
 use config::{any_err, get_or_create_sub_module};
+use lruttl::LruCacheWithTtl;
 use mlua::prelude::LuaUserData;
-use mlua::{FromLua, Lua, MetaMethod, UserDataMethods};
+use mlua::{FromLua, Lua, MetaMethod, UserData, UserDataMethods};
 use mod_memoize::CacheValue;
+use once_cell::sync::Lazy;
 use regex::{RegexSet, RegexSetBuilder};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Compiling a set of many thousands of patterns is expensive, and
+/// policy will often construct the same set on each event, so the
+/// compiled sets are cached, keyed by their list of patterns.
+static COMPILED_SETS: Lazy<LruCacheWithTtl<Vec<String>, RegexSet>> =
+    Lazy::new(|| LruCacheWithTtl::new(128));
+const COMPILED_SET_TTL: Duration = Duration::from_secs(300);
+
+/// Compile patterns into a RegexSet, reusing a previously compiled
+/// set with the same list of patterns if one is cached.
+pub fn compile_regex_set(patterns: Vec<String>) -> Result<RegexSet, String> {
+    if let Some(set) = COMPILED_SETS.get(&patterns) {
+        return Ok(set);
+    }
+    let set = RegexSetBuilder::new(&patterns)
+        .build()
+        .map_err(|err| format!("compiling rules: {err:#}"))?;
+    Ok(COMPILED_SETS.insert(patterns, set, Instant::now() + COMPILED_SET_TTL))
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(try_from = "RegexSetMapBuilder<V>", into = "RegexSetMapBuilder<V>")]
@@ -64,9 +86,7 @@ impl<V: Clone> RegexSetMapBuilder<V> {
         self.patterns.shrink_to_fit();
         self.pattern_to_value.shrink_to_fit();
 
-        let set = RegexSetBuilder::new(self.patterns)
-            .build()
-            .map_err(|err| format!("compiling rules: {err:#}"))?;
+        let set = compile_regex_set(self.patterns)?;
         Ok(RegexSetMap {
             set,
             pattern_to_value: self.pattern_to_value,
@@ -88,7 +108,53 @@ impl LuaUserData for RegexSetMap<CacheValue> {
     }
 }
 
+/// A set of patterns that can be matched against a subject
+/// in a single pass
+#[derive(Clone)]
+struct RegexSetWrap(RegexSet);
+
+impl UserData for RegexSetWrap {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        mod_memoize::Memoized::impl_memoize(methods);
+        methods.add_method("is_match", |_, this, subject: String| {
+            Ok(this.0.is_match(&subject))
+        });
+
+        // Returns the 1-based indices of the patterns that match
+        methods.add_method("matches", |_, this, subject: String| {
+            Ok(this
+                .0
+                .matches(&subject)
+                .into_iter()
+                .map(|idx| idx + 1)
+                .collect::<Vec<_>>())
+        });
+
+        methods.add_method("matching_patterns", |_, this, subject: String| {
+            let patterns = this.0.patterns();
+            Ok(this
+                .0
+                .matches(&subject)
+                .into_iter()
+                .map(|idx| patterns[idx].clone())
+                .collect::<Vec<_>>())
+        });
+
+        methods.add_meta_method(MetaMethod::Len, |_, this, _: ()| Ok(this.0.len()));
+    }
+}
+
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let set_module = get_or_create_sub_module(lua, "regex_set")?;
+
+    set_module.set(
+        "new",
+        lua.create_function(|_, patterns: Vec<String>| {
+            let set = compile_regex_set(patterns).map_err(any_err)?;
+            Ok(RegexSetWrap(set))
+        })?,
+    )?;
+
     let module = get_or_create_sub_module(lua, "regex_set_map")?;
 
     module.set(
@@ -114,6 +180,21 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
 mod test {
     use super::*;
 
+    #[test]
+    fn compiled_sets_are_cached() {
+        let patterns = vec!["^postmaster@".to_string(), "@example\\.com$".to_string()];
+        let set = compile_regex_set(patterns.clone()).unwrap();
+        assert!(COMPILED_SETS.get(&patterns).is_some());
+        assert_eq!(
+            set.matches("postmaster@example.com")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        assert!(compile_regex_set(vec!["(".to_string()]).is_err());
+    }
+
     #[test]
     fn test_basic_mapping() {
         let mut builder = RegexSetMapBuilder::new();
@@ -1,5 +1,8 @@
 use crate::client_types::*;
-use crate::{AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, Domain, ForwardPath, ReversePath};
+use crate::{
+    AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, Domain, EsmtpParameter, ForwardPath,
+    ReversePath,
+};
 use hickory_proto::rr::rdata::tlsa::{CertUsage, Matching, Selector};
 use hickory_proto::rr::rdata::TLSA;
 use memchr::memmem::Finder;
@@ -40,6 +43,14 @@ pub enum ClientError {
     NotConnected,
     #[error("Command rejected {0:?}")]
     Rejected(Response),
+    #[error("All recipients rejected {0:?}")]
+    RejectedBatch(Vec<Response>),
+    /// The peer advertised CHUNKING but did not recognize BDAT.
+    /// CHUNKING has been disabled and the connection closed, as the
+    /// peer may have interpreted the chunk as commands; the transaction
+    /// can be retried using DATA on a new connection.
+    #[error("BDAT rejected {0:?}")]
+    BdatRejected(Response),
     #[error("STARTTLS: {0} is not a valid DNS name")]
     InvalidDnsName(String),
     #[error("Timed Out waiting {duration:?} for response to {command:?}")]
@@ -80,6 +91,45 @@ pub struct EsmtpCapability {
     pub param: Option<String>,
 }
 
+/// The limits advertised by the peer via the LIMITS extension.
+/// <https://datatracker.ietf.org/doc/html/rfc9422>
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of transactions per connection
+    pub mail_max: Option<usize>,
+    /// The maximum number of recipients per transaction
+    pub rcpt_max: Option<usize>,
+    /// The maximum number of distinct recipient domains per transaction
+    pub rcpt_domain_max: Option<usize>,
+}
+
+impl Limits {
+    /// Parse the parameters of the LIMITS capability, such
+    /// as `MAILMAX=1000 RCPTMAX=50`. Unknown or malformed
+    /// limits are ignored, as required by the RFC.
+    pub fn parse(param: &str) -> Self {
+        let mut limits = Self::default();
+        for token in param.split_ascii_whitespace() {
+            let Some((name, value)) = token.split_once('=') else {
+                continue;
+            };
+            let Ok(value) = value.parse::<usize>() else {
+                continue;
+            };
+            if value == 0 {
+                continue;
+            }
+            match name.to_ascii_uppercase().as_str() {
+                "MAILMAX" => limits.mail_max = Some(value),
+                "RCPTMAX" => limits.rcpt_max = Some(value),
+                "RCPTDOMAINMAX" => limits.rcpt_domain_max = Some(value),
+                _ => {}
+            }
+        }
+        limits
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum SmtpClientTraceEvent {
     Closed,
@@ -149,6 +199,8 @@ pub struct SmtpClient {
     read_buffer: Vec<u8>,
     timeouts: SmtpClientTimeouts,
     tracer: Option<Arc<dyn SmtpClientTracer + Send + Sync>>,
+    /// Set when the peer was greeted with LHLO
+    lmtp: bool,
 }
 
 fn extract_hostname(hostname: &str) -> &str {
@@ -192,9 +244,32 @@ impl SmtpClient {
             read_buffer: Vec::with_capacity(1024),
             timeouts,
             tracer: None,
+            lmtp: false,
         }
     }
 
+    /// Returns the capabilities that were advertised by the peer
+    /// in response to the most recent EHLO
+    pub fn capabilities(&self) -> &HashMap<String, EsmtpCapability> {
+        &self.capabilities
+    }
+
+    /// Returns the limits that were advertised by the peer
+    /// in response to the most recent EHLO
+    pub fn limits(&self) -> Limits {
+        self.capabilities
+            .get("LIMITS")
+            .and_then(|cap| cap.param.as_deref())
+            .map(Limits::parse)
+            .unwrap_or_default()
+    }
+
+    /// Behave as though the peer did not advertise the named
+    /// capability in response to the most recent EHLO
+    pub fn disable_capability(&mut self, name: &str) {
+        self.capabilities.remove(name);
+    }
+
     pub fn set_tracer(&mut self, tracer: Arc<dyn SmtpClientTracer + Send + Sync>) {
         self.tracer.replace(tracer);
     }
@@ -416,9 +491,28 @@ impl SmtpClient {
         &mut self,
         ehlo_name: &str,
     ) -> Result<&HashMap<String, EsmtpCapability>, ClientError> {
-        let response = self
-            .send_command(&Command::Ehlo(Domain::Name(ehlo_name.to_string())))
-            .await?;
+        self.lmtp = false;
+        self.hello(Command::Ehlo(Domain::Name(ehlo_name.to_string())))
+            .await
+    }
+
+    /// The RFC 2033 LMTP equivalent of EHLO. Subsequent transactions
+    /// are conducted using LMTP, which provides a separate response to
+    /// the message data for each recipient.
+    pub async fn lhlo(
+        &mut self,
+        lhlo_name: &str,
+    ) -> Result<&HashMap<String, EsmtpCapability>, ClientError> {
+        self.lmtp = true;
+        self.hello(Command::Lhlo(Domain::Name(lhlo_name.to_string())))
+            .await
+    }
+
+    async fn hello(
+        &mut self,
+        command: Command,
+    ) -> Result<&HashMap<String, EsmtpCapability>, ClientError> {
+        let response = self.send_command(&command).await?;
         if response.code != 250 {
             return Err(ClientError::Rejected(response));
         }
@@ -509,6 +603,9 @@ impl SmtpClient {
                 if let Some(cert) = ssl_stream.ssl().peer_certificate() {
                     tls_info.subject_name = subject_name(&cert);
                 }
+                if let Some(chain) = ssl_stream.ssl().peer_cert_chain() {
+                    tls_info.peer_spki_sha256 = chain.iter().filter_map(spki_sha256).collect();
+                }
                 if let Ok(authority) = ssl_stream.ssl().dane_authority() {
                     if let Some(cert) = &authority.cert {
                         tls_info.subject_name = subject_name(cert);
@@ -562,6 +659,11 @@ impl SmtpClient {
                             if let Ok(cert) = X509::from_der(peer_cert.as_ref()) {
                                 tls_info.subject_name = subject_name(&cert);
                             }
+                            tls_info.peer_spki_sha256 = certs
+                                .iter()
+                                .filter_map(|cert| X509::from_der(cert.as_ref()).ok())
+                                .filter_map(|cert| spki_sha256(&cert))
+                                .collect();
                         }
 
                         Box::new(stream)
@@ -590,26 +692,135 @@ impl SmtpClient {
         })
     }
 
+    /// Transmit the message content as a single RFC 3030 BDAT LAST chunk
+    async fn send_bdat(&mut self, data: &[u8]) -> Result<Response, ClientError> {
+        let command = Command::Bdat {
+            chunk_size: data.len(),
+            last: true,
+        };
+        let line = command.encode();
+        tracing::trace!(
+            "send->{}: {} (+{} bytes of data)",
+            self.hostname,
+            line.escape_debug(),
+            data.len()
+        );
+
+        match self.socket.as_mut() {
+            Some(sock) => {
+                if let Some(tracer) = &self.tracer {
+                    WriteTracer::trace(tracer, &line);
+                    BinWriteTracer::trace(tracer, data);
+                }
+
+                let write = async {
+                    sock.write_all(line.as_bytes()).await?;
+                    sock.write_all(data).await
+                };
+
+                match timeout(command.client_timeout_request(&self.timeouts), write).await {
+                    Ok(result) => result.map_err(|_| ClientError::NotConnected)?,
+                    Err(_) => return Err(ClientError::TimeOutData),
+                }
+            }
+            None => return Err(ClientError::NotConnected),
+        }
+
+        let resp = self
+            .read_response(Some(&command), command.client_timeout(&self.timeouts))
+            .await?;
+        if resp.code != 250 {
+            return Err(ClientError::Rejected(resp));
+        }
+
+        Ok(resp)
+    }
+
     pub async fn send_mail<B: AsRef<[u8]>, SENDER: Into<ReversePath>, RECIP: Into<ForwardPath>>(
         &mut self,
         sender: SENDER,
         recipient: RECIP,
         data: B,
     ) -> Result<Response, ClientError> {
-        let mut responses = self
-            .pipeline_commands(vec![
-                Command::Rset,
-                Command::MailFrom {
-                    address: sender.into(),
-                    parameters: vec![],
-                },
-                Command::RcptTo {
-                    address: recipient.into(),
-                    parameters: vec![],
-                },
-                Command::Data,
-            ])
-            .await;
+        self.send_mail_with_parameters(sender, vec![], recipient, vec![], data)
+            .await
+    }
+
+    /// Like send_mail, but allows passing ESMTP parameters to the
+    /// MAIL FROM and RCPT TO commands.  It is the responsibility of
+    /// the caller to only pass parameters for extensions that were
+    /// advertised by the peer; see `capabilities()`.
+    pub async fn send_mail_with_parameters<
+        B: AsRef<[u8]>,
+        SENDER: Into<ReversePath>,
+        RECIP: Into<ForwardPath>,
+    >(
+        &mut self,
+        sender: SENDER,
+        sender_parameters: Vec<EsmtpParameter>,
+        recipient: RECIP,
+        recipient_parameters: Vec<EsmtpParameter>,
+        data: B,
+    ) -> Result<Response, ClientError> {
+        match self
+            .send_mail_multi_recip(
+                sender,
+                sender_parameters,
+                vec![(recipient.into(), recipient_parameters)],
+                data,
+            )
+            .await
+        {
+            Ok(success) => Ok(success.response),
+            Err(ClientError::RejectedBatch(mut responses)) if responses.len() == 1 => {
+                Err(ClientError::Rejected(responses.remove(0)))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Send a single copy of the message data to multiple recipients
+    /// in the same transaction.
+    /// If the peer rejects some, but not all, of the recipients, the
+    /// message is sent to the accepted recipients and the individual
+    /// RCPT TO responses are reported via `BatchSendSuccess`.
+    /// If all of the recipients are rejected, `ClientError::RejectedBatch`
+    /// is returned.  Any other error applies to all of the recipients.
+    pub async fn send_mail_multi_recip<B: AsRef<[u8]>, SENDER: Into<ReversePath>>(
+        &mut self,
+        sender: SENDER,
+        sender_parameters: Vec<EsmtpParameter>,
+        recipients: Vec<(ForwardPath, Vec<EsmtpParameter>)>,
+        data: B,
+    ) -> Result<BatchSendSuccess, ClientError> {
+        // Prefer RFC 3030 BDAT when available, as it avoids the need
+        // to dot-stuff the message and to scan for the terminator.
+        // We don't pipeline BDAT itself, as we'd potentially waste
+        // bandwidth transmitting the whole message only for it to be
+        // discarded because the RCPT was rejected.
+        // LMTP allows BDAT, but we only implement the per-recipient
+        // responses for DATA, so don't use it with LMTP.
+        let use_bdat = !self.lmtp && self.capabilities.contains_key("CHUNKING");
+
+        let num_recipients = recipients.len();
+        let mut commands = vec![
+            Command::Rset,
+            Command::MailFrom {
+                address: sender.into(),
+                parameters: sender_parameters,
+            },
+        ];
+        for (address, parameters) in recipients {
+            commands.push(Command::RcptTo {
+                address,
+                parameters,
+            });
+        }
+        if !use_bdat {
+            commands.push(Command::Data);
+        }
+
+        let mut responses = self.pipeline_commands(commands).await;
 
         if responses.is_empty() {
             // Should be impossible to get here really, but if we do,
@@ -627,9 +838,40 @@ impl SmtpClient {
             return Err(ClientError::Rejected(mail_resp));
         }
 
-        let rcpt_resp = responses.remove(0)?;
-        if rcpt_resp.code != 250 {
-            return Err(ClientError::Rejected(rcpt_resp));
+        let mut rcpt_responses = Vec::with_capacity(num_recipients);
+        for _ in 0..num_recipients {
+            rcpt_responses.push(responses.remove(0)?);
+        }
+        if !rcpt_responses.iter().any(|resp| resp.code == 250) {
+            return Err(ClientError::RejectedBatch(rcpt_responses));
+        }
+
+        let data: &[u8] = data.as_ref();
+
+        if use_bdat {
+            return match self.send_bdat(data).await {
+                Ok(response) => Ok(BatchSendSuccess {
+                    response,
+                    rcpt_responses,
+                    lmtp_responses: vec![],
+                }),
+                Err(ClientError::Rejected(resp)) if matches!(resp.code, 500 | 502 | 504) => {
+                    // Some peers advertise CHUNKING but then fail to
+                    // recognize BDAT, and go on to interpret each line
+                    // of the chunk as a command. We can neither know how
+                    // many responses are still to come, nor what those
+                    // commands did to the session, so close the connection
+                    // and let the caller retry using DATA on a new one.
+                    tracing::debug!(
+                        "{}: BDAT was rejected with {resp:?}, disabling CHUNKING",
+                        self.hostname
+                    );
+                    self.capabilities.remove("CHUNKING");
+                    self.socket.take();
+                    Err(ClientError::BdatRejected(resp))
+                }
+                Err(err) => Err(err),
+            };
         }
 
         let data_resp = responses.remove(0)?;
@@ -637,7 +879,6 @@ impl SmtpClient {
             return Err(ClientError::Rejected(data_resp));
         }
 
-        let data: &[u8] = data.as_ref();
         let stuffed;
 
         let data = match apply_dot_stuffing(data) {
@@ -698,6 +939,10 @@ impl SmtpClient {
             None => return Err(ClientError::NotConnected),
         }
 
+        if self.lmtp {
+            return self.read_lmtp_responses(rcpt_responses).await;
+        }
+
         let data_dot = Command::DataDot;
         let resp = self
             .read_response(Some(&data_dot), data_dot.client_timeout(&self.timeouts))
@@ -706,7 +951,70 @@ impl SmtpClient {
             return Err(ClientError::Rejected(resp));
         }
 
-        Ok(resp)
+        Ok(BatchSendSuccess {
+            response: resp,
+            rcpt_responses,
+            lmtp_responses: vec![],
+        })
+    }
+
+    /// An LMTP server responds to the message data once for each
+    /// recipient that it accepted, in the order that they were given.
+    /// <https://datatracker.ietf.org/doc/html/rfc2033#section-4.2>
+    async fn read_lmtp_responses(
+        &mut self,
+        rcpt_responses: Vec<Response>,
+    ) -> Result<BatchSendSuccess, ClientError> {
+        let data_dot = Command::DataDot;
+        let mut lmtp_responses = Vec::with_capacity(rcpt_responses.len());
+        for rcpt_resp in &rcpt_responses {
+            if rcpt_resp.code == 250 {
+                lmtp_responses.push(
+                    self.read_response(Some(&data_dot), data_dot.client_timeout(&self.timeouts))
+                        .await?,
+                );
+            } else {
+                lmtp_responses.push(rcpt_resp.clone());
+            }
+        }
+
+        match lmtp_responses.iter().find(|resp| resp.code == 250) {
+            Some(response) => Ok(BatchSendSuccess {
+                response: response.clone(),
+                rcpt_responses,
+                lmtp_responses,
+            }),
+            None => Err(ClientError::RejectedBatch(lmtp_responses)),
+        }
+    }
+}
+
+/// The outcome of `SmtpClient::send_mail_multi_recip`
+#[derive(Debug)]
+pub struct BatchSendSuccess {
+    /// The response to the message data
+    pub response: Response,
+    /// The response to each RCPT TO, in the same order as the
+    /// recipients that were passed in
+    pub rcpt_responses: Vec<Response>,
+    /// When using LMTP, the final response for each recipient, in the
+    /// same order as the recipients that were passed in: the response
+    /// to the message data for those that were accepted, or the
+    /// response to the RCPT TO for those that were not.
+    /// Empty when using SMTP.
+    pub lmtp_responses: Vec<Response>,
+}
+
+impl BatchSendSuccess {
+    /// Returns the final response for the recipient at idx
+    pub fn recipient_response(&self, idx: usize) -> &Response {
+        if let Some(resp) = self.lmtp_responses.get(idx) {
+            return resp;
+        }
+        match self.rcpt_responses.get(idx) {
+            Some(resp) if resp.code != 250 => resp,
+            _ => &self.response,
+        }
     }
 }
 
@@ -722,6 +1030,10 @@ pub struct TlsInformation {
     pub protocol_version: String,
     pub subject_name: Vec<String>,
     pub provider_name: String,
+    /// The base64 encoded SHA-256 hash of the SubjectPublicKeyInfo
+    /// of each certificate presented by the peer, leaf first
+    #[serde(default)]
+    pub peer_spki_sha256: Vec<String>,
 }
 
 impl Drop for SmtpClient {
@@ -947,6 +1259,13 @@ fn apply_dot_stuffing(data: &[u8]) -> Option<Vec<u8>> {
     Some(stuffed)
 }
 
+/// Compute the base64 encoded SHA-256 hash of the SubjectPublicKeyInfo
+/// of cert, in the same form used by HPKP `pin-sha256` directives
+fn spki_sha256(cert: &X509Ref) -> Option<String> {
+    let spki = cert.public_key().ok()?.public_key_to_der().ok()?;
+    Some(data_encoding::BASE64.encode(&openssl::sha::sha256(&spki)))
+}
+
 /// Extracts the object=name pairs of the subject name from a cert.
 /// eg:
 /// ```norun
@@ -968,6 +1287,7 @@ fn subject_name(cert: &X509Ref) -> Vec<String> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use tokio::io::AsyncBufReadExt;
 
     #[test]
     fn test_stuffing() {
@@ -980,6 +1300,26 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_limits() {
+        assert_eq!(
+            Limits::parse("MAILMAX=1000 RCPTMAX=50 RCPTDOMAINMAX=1"),
+            Limits {
+                mail_max: Some(1000),
+                rcpt_max: Some(50),
+                rcpt_domain_max: Some(1),
+            }
+        );
+        assert_eq!(
+            Limits::parse("rcptmax=20 FOO=bar MAILMAX=x MAILMAX"),
+            Limits {
+                mail_max: None,
+                rcpt_max: Some(20),
+                rcpt_domain_max: None,
+            }
+        );
+    }
+
     /*
     #[tokio::test]
     async fn test_against_sink() {
@@ -1119,6 +1459,159 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn lmtp_per_recipient_responses() {
+        let (client_sock, mut server_sock) = tokio::net::UnixStream::pair().unwrap();
+        let mut client =
+            SmtpClient::with_stream(client_sock, "localhost", SmtpClientTimeouts::default());
+
+        // The client reads the responses in order, so they can
+        // all be written ahead of the commands that they answer
+        server_sock
+            .write_all(
+                b"250-localhost\r\n250 PIPELINING\r\n\
+                  250 reset\r\n\
+                  250 sender ok\r\n\
+                  250 first ok\r\n\
+                  550 5.1.1 no such user\r\n\
+                  250 third ok\r\n\
+                  354 go ahead\r\n\
+                  250 2.0.0 first delivered\r\n\
+                  452 4.2.2 third is over quota\r\n",
+            )
+            .await
+            .unwrap();
+        let drain = tokio::spawn(async move {
+            let mut buf = vec![];
+            server_sock.read_to_end(&mut buf).await.ok();
+            String::from_utf8(buf).unwrap()
+        });
+
+        client.lhlo("client").await.unwrap();
+        let recipient = |addr: &str| (ForwardPath::try_from(addr).unwrap(), vec![]);
+        let success = client
+            .send_mail_multi_recip(
+                ReversePath::try_from("sender@example.com").unwrap(),
+                vec![],
+                vec![
+                    recipient("first@example.com"),
+                    recipient("second@example.com"),
+                    recipient("third@example.com"),
+                ],
+                "Subject: hello\r\n\r\nwoot\r\n",
+            )
+            .await
+            .unwrap();
+        drop(client);
+
+        assert_eq!(success.recipient_response(0).content, "first delivered");
+        assert_eq!(success.recipient_response(1).code, 550);
+        assert_eq!(success.recipient_response(2).code, 452);
+        assert_eq!(success.response.code, 250);
+
+        let sent = drain.await.unwrap();
+        assert!(sent.starts_with("LHLO client\r\n"), "{sent}");
+    }
+
+    /// A peer that advertises CHUNKING when `chunking` is set, but
+    /// doesn't recognize BDAT, and so responds to each line of a chunk
+    /// as though it were a command. Returns what the client sent.
+    fn broken_chunking_peer(
+        sock: tokio::net::UnixStream,
+        chunking: bool,
+    ) -> tokio::task::JoinHandle<String> {
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(sock);
+            let mut lines = tokio::io::BufReader::new(read).lines();
+            let mut sent = String::new();
+            let mut in_data = false;
+            while let Ok(Some(line)) = lines.next_line().await {
+                sent.push_str(&line);
+                sent.push_str("\r\n");
+                let verb = line.split(' ').next().unwrap_or("").to_ascii_uppercase();
+                let response = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    "250 2.0.0 delivered\r\n"
+                } else {
+                    match verb.as_str() {
+                        "EHLO" if chunking => "250-localhost\r\n250-PIPELINING\r\n250 CHUNKING\r\n",
+                        "EHLO" => "250-localhost\r\n250 PIPELINING\r\n",
+                        "RSET" | "MAIL" | "RCPT" => "250 ok\r\n",
+                        "DATA" => {
+                            in_data = true;
+                            "354 go ahead\r\n"
+                        }
+                        _ => "500 5.5.1 command not recognized\r\n",
+                    }
+                };
+                // The client may already have hung up
+                write.write_all(response.as_bytes()).await.ok();
+            }
+            sent
+        })
+    }
+
+    #[tokio::test]
+    async fn bdat_rejected_closes_connection() {
+        let (client_sock, server_sock) = tokio::net::UnixStream::pair().unwrap();
+        let mut client =
+            SmtpClient::with_stream(client_sock, "localhost", SmtpClientTimeouts::default());
+        let peer = broken_chunking_peer(server_sock, true);
+
+        client.ehlo("client").await.unwrap();
+        let err = client
+            .send_mail_multi_recip(
+                ReversePath::try_from("sender@example.com").unwrap(),
+                vec![],
+                vec![(ForwardPath::try_from("user@example.com").unwrap(), vec![])],
+                "Subject: hello\r\n\r\nwoot\r\n",
+            )
+            .await
+            .unwrap_err();
+        match err {
+            ClientError::BdatRejected(resp) => assert_eq!(resp.code, 500),
+            err => panic!("unexpected error {err:#}"),
+        }
+        assert!(!client.capabilities().contains_key("CHUNKING"));
+
+        // The client hung up, so the peer has finished: it saw the chunk
+        // as commands, and nothing was sent after it
+        let sent = peer.await.unwrap();
+        assert!(
+            sent.ends_with("BDAT 24 LAST\r\nSubject: hello\r\n\r\nwoot\r\n"),
+            "{sent}"
+        );
+
+        // Retrying on a new connection with CHUNKING disabled uses DATA
+        let (client_sock, server_sock) = tokio::net::UnixStream::pair().unwrap();
+        let mut client =
+            SmtpClient::with_stream(client_sock, "localhost", SmtpClientTimeouts::default());
+        let peer = broken_chunking_peer(server_sock, true);
+        client.ehlo("client").await.unwrap();
+        client.disable_capability("CHUNKING");
+        let success = client
+            .send_mail_multi_recip(
+                ReversePath::try_from("sender@example.com").unwrap(),
+                vec![],
+                vec![(ForwardPath::try_from("user@example.com").unwrap(), vec![])],
+                "Subject: hello\r\n\r\nwoot\r\n",
+            )
+            .await
+            .unwrap();
+        assert_eq!(success.response.content, "delivered");
+        drop(client);
+
+        let sent = peer.await.unwrap();
+        assert!(!sent.contains("BDAT"), "{sent}");
+        assert!(
+            sent.ends_with("DATA\r\nSubject: hello\r\n\r\nwoot\r\n.\r\n"),
+            "{sent}"
+        );
+    }
+
     #[test]
     fn test_extract_hostname() {
         assert_eq!(extract_hostname("foo"), "foo");
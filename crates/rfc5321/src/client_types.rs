@@ -1,70 +1,84 @@
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
+/// Timeouts that apply to the various phases of an outgoing
+/// SMTP session
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 pub struct SmtpClientTimeouts {
     #[serde(
         default = "SmtpClientTimeouts::default_connect_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub connect_timeout: Duration,
 
     #[serde(
         default = "SmtpClientTimeouts::default_banner_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub banner_timeout: Duration,
 
     #[serde(
         default = "SmtpClientTimeouts::default_ehlo_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub ehlo_timeout: Duration,
 
     #[serde(
         default = "SmtpClientTimeouts::default_mail_from_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub mail_from_timeout: Duration,
 
     #[serde(
         default = "SmtpClientTimeouts::default_rcpt_to_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub rcpt_to_timeout: Duration,
 
     #[serde(
         default = "SmtpClientTimeouts::default_data_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub data_timeout: Duration,
     #[serde(
         default = "SmtpClientTimeouts::default_data_dot_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub data_dot_timeout: Duration,
     #[serde(
         default = "SmtpClientTimeouts::default_rset_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub rset_timeout: Duration,
 
     #[serde(
         default = "SmtpClientTimeouts::default_idle_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub idle_timeout: Duration,
 
     #[serde(
         default = "SmtpClientTimeouts::default_starttls_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub starttls_timeout: Duration,
 
     #[serde(
         default = "SmtpClientTimeouts::default_auth_timeout",
         with = "duration_serde"
     )]
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))]
     pub auth_timeout: Duration,
 }
 
@@ -0,0 +1,555 @@
+//! Helpers for working with the parameters of ESMTP service extensions
+use crate::EsmtpParameter;
+use std::fmt;
+
+/// The by-mode of an RFC 2852 DELIVERBY request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliverByMode {
+    /// `N`: the sender wants a delay DSN if the message cannot be
+    /// delivered within the by-time, but delivery should continue.
+    Notify,
+    /// `R`: the message should be returned (bounced) if it cannot
+    /// be delivered within the by-time.
+    Return,
+}
+
+/// Represents the value of the `BY` parameter to `MAIL FROM` defined by
+/// <https://datatracker.ietf.org/doc/html/rfc2852>
+///
+/// ```text
+/// by-value = by-time ";" by-mode [ by-trace ]
+/// by-time  = ["-" / "+"] 1*9digit
+/// by-mode  = "N" / "R"
+/// by-trace = "T"
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliverBy {
+    /// Number of seconds, relative to the time that the message
+    /// was received, within which the message should be delivered.
+    /// May be zero or negative when the mode is `Notify`.
+    pub by_time: i64,
+    pub mode: DeliverByMode,
+    /// Whether the `T` trace modifier was specified
+    pub trace: bool,
+}
+
+impl DeliverBy {
+    pub const PARAMETER_NAME: &'static str = "BY";
+    const MAX_BY_TIME: i64 = 999_999_999;
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (by_time, mode) = value
+            .split_once(';')
+            .ok_or_else(|| format!("BY parameter {value:?} is missing ';'"))?;
+
+        let digits = by_time.strip_prefix(['-', '+']).unwrap_or(by_time);
+        if digits.is_empty() || digits.len() > 9 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("BY parameter {value:?} has invalid by-time"));
+        }
+        let by_time: i64 = by_time
+            .parse()
+            .map_err(|_| format!("BY parameter {value:?} has invalid by-time"))?;
+
+        let (mode, trace) = Self::parse_mode(mode)
+            .ok_or_else(|| format!("BY parameter {value:?} has invalid by-mode"))?;
+
+        // <https://datatracker.ietf.org/doc/html/rfc2852#section-4>
+        // When the by-mode is R, the by-time MUST be greater than zero
+        if mode == DeliverByMode::Return && by_time <= 0 {
+            return Err(format!(
+                "BY parameter {value:?} must have a positive by-time when by-mode is R"
+            ));
+        }
+
+        Ok(Self {
+            by_time,
+            mode,
+            trace,
+        })
+    }
+
+    /// Parse the by-mode and optional by-trace portion of the parameter,
+    /// for example `R` or `NT`.
+    pub fn parse_mode(mode: &str) -> Option<(DeliverByMode, bool)> {
+        match mode.to_ascii_uppercase().as_str() {
+            "N" => Some((DeliverByMode::Notify, false)),
+            "NT" => Some((DeliverByMode::Notify, true)),
+            "R" => Some((DeliverByMode::Return, false)),
+            "RT" => Some((DeliverByMode::Return, true)),
+            _ => None,
+        }
+    }
+
+    /// Returns the by-mode and by-trace portion of the parameter,
+    /// in the form accepted by `parse_mode`.
+    pub fn mode_string(&self) -> String {
+        let mode = match self.mode {
+            DeliverByMode::Notify => "N",
+            DeliverByMode::Return => "R",
+        };
+        let trace = if self.trace { "T" } else { "" };
+        format!("{mode}{trace}")
+    }
+
+    /// Returns a copy of self with by_time adjusted to reflect the
+    /// remaining number of seconds, clamped to the valid range for
+    /// the mode.  This is used when relaying the request to the next hop.
+    pub fn with_remaining(&self, remaining_seconds: i64) -> Self {
+        let min = match self.mode {
+            DeliverByMode::Return => 1,
+            DeliverByMode::Notify => -Self::MAX_BY_TIME,
+        };
+        Self {
+            by_time: remaining_seconds.clamp(min, Self::MAX_BY_TIME),
+            ..*self
+        }
+    }
+
+    pub fn to_parameter(&self) -> EsmtpParameter {
+        EsmtpParameter {
+            name: Self::PARAMETER_NAME.to_string(),
+            value: Some(self.to_string()),
+        }
+    }
+
+    /// Find and parse the BY parameter from a list of MAIL FROM parameters
+    pub fn from_parameters(parameters: &[EsmtpParameter]) -> Result<Option<Self>, String> {
+        match parameters
+            .iter()
+            .find(|p| p.name.eq_ignore_ascii_case(Self::PARAMETER_NAME))
+        {
+            Some(EsmtpParameter {
+                value: Some(value), ..
+            }) => Self::parse(value).map(Some),
+            Some(EsmtpParameter { value: None, .. }) => {
+                Err("BY parameter requires a value".to_string())
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for DeliverBy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{};{}", self.by_time, self.mode_string())
+    }
+}
+
+fn find_parameter<'a>(parameters: &'a [EsmtpParameter], name: &str) -> Option<&'a EsmtpParameter> {
+    parameters
+        .iter()
+        .find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+fn parameter_value<'a>(
+    parameters: &'a [EsmtpParameter],
+    name: &str,
+) -> Result<Option<&'a str>, String> {
+    match find_parameter(parameters, name) {
+        Some(EsmtpParameter {
+            value: Some(value), ..
+        }) => Ok(Some(value)),
+        Some(EsmtpParameter { value: None, .. }) => {
+            Err(format!("{name} parameter requires a value"))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Find and parse the `SIZE` parameter to `MAIL FROM` defined by
+/// <https://datatracker.ietf.org/doc/html/rfc1870>
+pub fn size_from_parameters(parameters: &[EsmtpParameter]) -> Result<Option<usize>, String> {
+    match parameter_value(parameters, "SIZE")? {
+        Some(value) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("SIZE parameter {value:?} is too large")),
+        Some(value) => Err(format!("SIZE parameter {value:?} is not a number")),
+        None => Ok(None),
+    }
+}
+
+/// Returns true if value is a valid xtext, as defined by
+/// <https://datatracker.ietf.org/doc/html/rfc3461#section-4>
+///
+/// ```text
+/// xtext = *( xchar / hexchar )
+/// xchar = any ASCII CHAR between "!" (33) and "~" (126) inclusive,
+///         except for "+" and "="
+/// hexchar = ASCII "+" immediately followed by two upper case
+///           hexadecimal digits
+/// ```
+pub fn is_valid_xtext(value: &str) -> bool {
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => {
+                for _ in 0..2 {
+                    match bytes.next() {
+                        Some(b'0'..=b'9' | b'A'..=b'F') => {}
+                        _ => return false,
+                    }
+                }
+            }
+            b'=' => return false,
+            b'!'..=b'~' => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// The value of the `RET` parameter to `MAIL FROM` defined by
+/// <https://datatracker.ietf.org/doc/html/rfc3461#section-4.3>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DsnReturn {
+    /// `FULL`: the full message should be returned in any failure DSN
+    Full,
+    /// `HDRS`: only the headers should be returned
+    Headers,
+}
+
+impl DsnReturn {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_uppercase().as_str() {
+            "FULL" => Some(Self::Full),
+            "HDRS" => Some(Self::Headers),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Full => "FULL",
+            Self::Headers => "HDRS",
+        }
+    }
+}
+
+/// The DSN parameters to `MAIL FROM` defined by
+/// <https://datatracker.ietf.org/doc/html/rfc3461#section-4>
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DsnMailParameters {
+    pub ret: Option<DsnReturn>,
+    /// The envelope identifier, in its xtext encoded form
+    pub envid: Option<String>,
+}
+
+impl DsnMailParameters {
+    /// <https://datatracker.ietf.org/doc/html/rfc3461#section-4.4>
+    const MAX_ENVID_LEN: usize = 100;
+
+    /// Find and parse the RET and ENVID parameters from a list of
+    /// MAIL FROM parameters
+    pub fn from_parameters(parameters: &[EsmtpParameter]) -> Result<Self, String> {
+        let ret = match parameter_value(parameters, "RET")? {
+            Some(value) => Some(
+                DsnReturn::parse(value)
+                    .ok_or_else(|| format!("RET parameter {value:?} must be FULL or HDRS"))?,
+            ),
+            None => None,
+        };
+        let envid = match parameter_value(parameters, "ENVID")? {
+            Some(value) => {
+                if value.len() > Self::MAX_ENVID_LEN || !is_valid_xtext(value) {
+                    return Err(format!("ENVID parameter {value:?} is invalid"));
+                }
+                Some(value.to_string())
+            }
+            None => None,
+        };
+        Ok(Self { ret, envid })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ret.is_none() && self.envid.is_none()
+    }
+
+    pub fn to_parameters(&self) -> Vec<EsmtpParameter> {
+        let mut parameters = vec![];
+        if let Some(ret) = self.ret {
+            parameters.push(EsmtpParameter {
+                name: "RET".to_string(),
+                value: Some(ret.as_str().to_string()),
+            });
+        }
+        if let Some(envid) = &self.envid {
+            parameters.push(EsmtpParameter {
+                name: "ENVID".to_string(),
+                value: Some(envid.clone()),
+            });
+        }
+        parameters
+    }
+}
+
+/// The value of the `NOTIFY` parameter to `RCPT TO` defined by
+/// <https://datatracker.ietf.org/doc/html/rfc3461#section-4.1>.
+/// `NEVER` is represented by all of the fields being false.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DsnNotify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+impl DsnNotify {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Ok(Self::default());
+        }
+        let mut notify = Self::default();
+        for keyword in value.split(',') {
+            let flag = match keyword.to_ascii_uppercase().as_str() {
+                "SUCCESS" => &mut notify.success,
+                "FAILURE" => &mut notify.failure,
+                "DELAY" => &mut notify.delay,
+                _ => return Err(format!("NOTIFY parameter {value:?} is invalid")),
+            };
+            *flag = true;
+        }
+        Ok(notify)
+    }
+}
+
+impl fmt::Display for DsnNotify {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let keywords: Vec<&str> = [
+            (self.success, "SUCCESS"),
+            (self.failure, "FAILURE"),
+            (self.delay, "DELAY"),
+        ]
+        .into_iter()
+        .filter_map(|(set, keyword)| set.then_some(keyword))
+        .collect();
+        if keywords.is_empty() {
+            write!(fmt, "NEVER")
+        } else {
+            write!(fmt, "{}", keywords.join(","))
+        }
+    }
+}
+
+/// The DSN parameters to `RCPT TO` defined by
+/// <https://datatracker.ietf.org/doc/html/rfc3461#section-4>
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DsnRcptParameters {
+    pub notify: Option<DsnNotify>,
+    /// The original recipient, in the form `addr-type;xtext`
+    pub orcpt: Option<String>,
+}
+
+impl DsnRcptParameters {
+    /// <https://datatracker.ietf.org/doc/html/rfc3461#section-4.2>
+    const MAX_ORCPT_LEN: usize = 500;
+
+    /// Find and parse the NOTIFY and ORCPT parameters from a list of
+    /// RCPT TO parameters
+    pub fn from_parameters(parameters: &[EsmtpParameter]) -> Result<Self, String> {
+        let notify = match parameter_value(parameters, "NOTIFY")? {
+            Some(value) => Some(DsnNotify::parse(value)?),
+            None => None,
+        };
+        let orcpt = match parameter_value(parameters, "ORCPT")? {
+            Some(value) => {
+                let valid = value.len() <= Self::MAX_ORCPT_LEN
+                    && match value.split_once(';') {
+                        Some((addr_type, addr)) => {
+                            !addr_type.is_empty()
+                                && addr_type
+                                    .bytes()
+                                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                                && is_valid_xtext(addr)
+                        }
+                        None => false,
+                    };
+                if !valid {
+                    return Err(format!("ORCPT parameter {value:?} is invalid"));
+                }
+                Some(value.to_string())
+            }
+            None => None,
+        };
+        Ok(Self { notify, orcpt })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notify.is_none() && self.orcpt.is_none()
+    }
+
+    pub fn to_parameters(&self) -> Vec<EsmtpParameter> {
+        let mut parameters = vec![];
+        if let Some(notify) = self.notify {
+            parameters.push(EsmtpParameter {
+                name: "NOTIFY".to_string(),
+                value: Some(notify.to_string()),
+            });
+        }
+        if let Some(orcpt) = &self.orcpt {
+            parameters.push(EsmtpParameter {
+                name: "ORCPT".to_string(),
+                value: Some(orcpt.clone()),
+            });
+        }
+        parameters
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_deliver_by() {
+        assert_eq!(
+            DeliverBy::parse("120;R").unwrap(),
+            DeliverBy {
+                by_time: 120,
+                mode: DeliverByMode::Return,
+                trace: false
+            }
+        );
+        assert_eq!(
+            DeliverBy::parse("-10;nt").unwrap(),
+            DeliverBy {
+                by_time: -10,
+                mode: DeliverByMode::Notify,
+                trace: true
+            }
+        );
+        assert_eq!(DeliverBy::parse("+30;RT").unwrap().to_string(), "30;RT");
+
+        assert!(DeliverBy::parse("0;R").is_err());
+        assert!(DeliverBy::parse("-5;R").is_err());
+        assert!(DeliverBy::parse("10").is_err());
+        assert!(DeliverBy::parse("10;X").is_err());
+        assert!(DeliverBy::parse("1234567890;N").is_err());
+        assert!(DeliverBy::parse(";N").is_err());
+    }
+
+    #[test]
+    fn deliver_by_remaining() {
+        let by = DeliverBy::parse("600;R").unwrap();
+        assert_eq!(by.with_remaining(300).to_string(), "300;R");
+        assert_eq!(by.with_remaining(-3).to_string(), "1;R");
+
+        let by = DeliverBy::parse("600;N").unwrap();
+        assert_eq!(by.with_remaining(-3).to_string(), "-3;N");
+    }
+
+    #[test]
+    fn deliver_by_from_parameters() {
+        let params = vec![
+            EsmtpParameter {
+                name: "SIZE".to_string(),
+                value: Some("1000".to_string()),
+            },
+            EsmtpParameter {
+                name: "by".to_string(),
+                value: Some("60;N".to_string()),
+            },
+        ];
+        assert_eq!(
+            DeliverBy::from_parameters(&params).unwrap(),
+            Some(DeliverBy {
+                by_time: 60,
+                mode: DeliverByMode::Notify,
+                trace: false
+            })
+        );
+        assert_eq!(DeliverBy::from_parameters(&params[0..1]).unwrap(), None);
+    }
+
+    fn param(name: &str, value: Option<&str>) -> EsmtpParameter {
+        EsmtpParameter {
+            name: name.to_string(),
+            value: value.map(|v| v.to_string()),
+        }
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(
+            size_from_parameters(&[param("size", Some("1000"))]).unwrap(),
+            Some(1000)
+        );
+        assert_eq!(size_from_parameters(&[]).unwrap(), None);
+        assert!(size_from_parameters(&[param("SIZE", None)]).is_err());
+        assert!(size_from_parameters(&[param("SIZE", Some("-1"))]).is_err());
+        assert!(size_from_parameters(&[param("SIZE", Some("99999999999999999999999"))]).is_err());
+    }
+
+    #[test]
+    fn xtext() {
+        assert!(is_valid_xtext("QQ314159"));
+        assert!(is_valid_xtext("user+2Bextra@example.com"));
+        assert!(!is_valid_xtext("user+2b@example.com"));
+        assert!(!is_valid_xtext("user+2"));
+        assert!(!is_valid_xtext("a=b"));
+        assert!(!is_valid_xtext("a b"));
+    }
+
+    #[test]
+    fn dsn_mail_parameters() {
+        let params = vec![
+            param("SIZE", Some("1000")),
+            param("ret", Some("hdrs")),
+            param("ENVID", Some("QQ314159")),
+        ];
+        let dsn = DsnMailParameters::from_parameters(&params).unwrap();
+        assert_eq!(
+            dsn,
+            DsnMailParameters {
+                ret: Some(DsnReturn::Headers),
+                envid: Some("QQ314159".to_string()),
+            }
+        );
+        assert_eq!(
+            dsn.to_parameters()
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>(),
+            vec!["RET=HDRS", "ENVID=QQ314159"]
+        );
+
+        assert!(DsnMailParameters::from_parameters(&[]).unwrap().is_empty());
+        assert!(DsnMailParameters::from_parameters(&[param("RET", Some("BODY"))]).is_err());
+        assert!(DsnMailParameters::from_parameters(&[param("ENVID", Some("a=b"))]).is_err());
+    }
+
+    #[test]
+    fn dsn_rcpt_parameters() {
+        let dsn = DsnRcptParameters::from_parameters(&[
+            param("NOTIFY", Some("delay,Success")),
+            param("ORCPT", Some("rfc822;user+2Bextra@example.com")),
+        ])
+        .unwrap();
+        assert_eq!(
+            dsn.notify,
+            Some(DsnNotify {
+                success: true,
+                failure: false,
+                delay: true
+            })
+        );
+        assert_eq!(
+            dsn.to_parameters()
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "NOTIFY=SUCCESS,DELAY",
+                "ORCPT=rfc822;user+2Bextra@example.com"
+            ]
+        );
+
+        assert_eq!(DsnNotify::parse("never").unwrap().to_string(), "NEVER");
+        assert!(DsnNotify::parse("NEVER,SUCCESS").is_err());
+        assert!(DsnNotify::parse("").is_err());
+        assert!(
+            DsnRcptParameters::from_parameters(&[param("ORCPT", Some("user@example.com"))])
+                .is_err()
+        );
+        assert!(DsnRcptParameters::from_parameters(&[param("NOTIFY", None)]).is_err());
+    }
+}
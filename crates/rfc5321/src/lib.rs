@@ -1,6 +1,7 @@
 #[cfg(feature = "client")]
 pub mod client;
 pub mod client_types;
+pub mod extensions;
 pub mod parser;
 #[cfg(feature = "client")]
 pub mod traits;
@@ -8,6 +9,7 @@ pub mod traits;
 #[cfg(feature = "client")]
 pub use client::*;
 pub use client_types::*;
+pub use extensions::*;
 pub use parser::*;
 #[cfg(feature = "client")]
 pub use traits::*;
@@ -1,7 +1,7 @@
 use std::fmt::Debug;
 use std::os::fd::{AsRawFd, FromRawFd};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UnixStream};
 use tokio_openssl::SslStream;
 use tokio_rustls::client::TlsStream as TlsClientStream;
 use tokio_rustls::server::TlsStream as TlsServerStream;
@@ -38,6 +38,7 @@ impl AsyncReadAndWrite for TcpStream {
         }
     }
 }
+impl AsyncReadAndWrite for UnixStream {}
 impl AsyncReadAndWrite for SslStream<TcpStream> {}
 impl AsyncReadAndWrite for SslStream<BoxedAsyncReadAndWrite> {}
 
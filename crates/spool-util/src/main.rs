@@ -1,8 +1,17 @@
-use clap::Parser;
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
 use human_bytes::human_bytes;
+use maildir::Maildir;
+use message::Message;
+use spool::export::{write_mbox, ExportedMessage, MboxReader};
+use spool::local_disk::LocalDiskSpool;
 use spool::rocks::RocksSpool;
-use spool::{Spool, SpoolEntry};
-use std::path::PathBuf;
+use spool::{Spool, SpoolEntry, SpoolId};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::runtime::Handle;
 
 /// KumoMTA Spool Utility
@@ -17,14 +26,69 @@ struct Opt {
     #[arg(long)]
     data: PathBuf,
 
+    /// The kind of spool that is stored in the meta and data paths
+    #[arg(long, value_enum, default_value_t = SpoolKind::RocksDb)]
+    kind: SpoolKind,
+
     #[command(subcommand)]
     cmd: SubCommand,
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SpoolKind {
+    LocalDisk,
+    RocksDb,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Mbox,
+    Maildir,
+}
+
 #[derive(Debug, Parser)]
 enum SubCommand {
     MetaSize,
     DataSize,
+    /// List the id, sender, recipient and queue of each spooled message
+    List,
+    /// Export the spooled messages to an mbox file or a maildir.
+    /// The spooled metadata is preserved in an X-KumoMTA-Spool-Meta
+    /// header, so that the messages can be imported again.
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Mbox)]
+        format: ExportFormat,
+        /// The mbox file or maildir to create
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Import messages that were produced by the export subcommand.
+    /// Each imported message is assigned a new spool id.
+    Import {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Mbox)]
+        format: ExportFormat,
+        /// The mbox file or maildir to import
+        #[arg(long)]
+        input: PathBuf,
+    },
+    /// Read every entry in the meta and data spools, verifying the
+    /// storage checksums, and report entries that are corrupt, have
+    /// invalid metadata, or are missing their corresponding meta or
+    /// data entry.
+    Verify {
+        /// Move the problematic entries into the quarantine area
+        /// of their respective spools
+        #[arg(long)]
+        quarantine: bool,
+    },
+}
+
+fn open_spool(kind: SpoolKind, path: &Path) -> anyhow::Result<Box<dyn Spool>> {
+    let spool: Box<dyn Spool> = match kind {
+        SpoolKind::LocalDisk => Box::new(LocalDiskSpool::new(path, false, Handle::current())?),
+        SpoolKind::RocksDb => Box::new(RocksSpool::new(path, false, None, Handle::current())?),
+    };
+    Ok(spool)
 }
 
 async fn show_size_stats(label: &str, spool: &dyn Spool) -> anyhow::Result<()> {
@@ -77,19 +141,269 @@ async fn show_size_stats(label: &str, spool: &dyn Spool) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn list(meta_spool: &dyn Spool) -> anyhow::Result<()> {
+    let (tx, rx) = flume::bounded(1024);
+    meta_spool.enumerate(tx)?;
+    while let Ok(entry) = rx.recv_async().await {
+        match entry {
+            SpoolEntry::Item { id, data } => {
+                let result = Message::new_from_spool(id, data)
+                    .and_then(|msg| Ok((msg.sender()?, msg.recipient()?, msg.get_queue_name()?)));
+                match result {
+                    Ok((sender, recipient, queue)) => {
+                        println!("{id}\t{sender}\t{recipient}\t{queue}");
+                    }
+                    Err(err) => eprintln!("ERROR: entry {id} has invalid metadata: {err:#}"),
+                }
+            }
+            SpoolEntry::Corrupt { id, error } => {
+                eprintln!("ERROR: entry {id} is corrupt: {error}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn export(
+    meta_spool: &dyn Spool,
+    data_spool: &dyn Spool,
+    format: ExportFormat,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let mut mbox = None;
+    let mut maildir = None;
+    match format {
+        ExportFormat::Mbox => {
+            let file = File::options()
+                .write(true)
+                .create_new(true)
+                .open(output)
+                .with_context(|| format!("failed to create {}", output.display()))?;
+            mbox.replace(BufWriter::new(file));
+        }
+        ExportFormat::Maildir => {
+            let dir = Maildir::from(output.to_path_buf());
+            dir.create_dirs()
+                .with_context(|| format!("failed to create maildir {}", output.display()))?;
+            maildir.replace(dir);
+        }
+    }
+
+    let (tx, rx) = flume::bounded(1024);
+    meta_spool.enumerate(tx)?;
+    let mut exported = 0;
+    let mut failed = 0;
+    while let Ok(entry) = rx.recv_async().await {
+        match entry {
+            SpoolEntry::Item { id, data: meta } => {
+                let result = async {
+                    let data = data_spool.load(id).await?;
+                    let msg = ExportedMessage { meta, data };
+                    if let Some(mbox) = &mut mbox {
+                        write_mbox(mbox, id, &msg)?;
+                    }
+                    if let Some(maildir) = &maildir {
+                        maildir.store_new(&msg.to_bytes()?)?;
+                    }
+                    anyhow::Result::<()>::Ok(())
+                }
+                .await;
+                match result {
+                    Ok(()) => exported += 1,
+                    Err(err) => {
+                        eprintln!("ERROR: failed to export {id}: {err:#}");
+                        failed += 1;
+                    }
+                }
+            }
+            SpoolEntry::Corrupt { id, error } => {
+                eprintln!("ERROR: entry {id} is corrupt: {error}");
+                failed += 1;
+            }
+        }
+    }
+
+    if let Some(mut mbox) = mbox {
+        mbox.flush()?;
+    }
+
+    println!("exported {exported} messages, {failed} failed");
+    Ok(())
+}
+
+async fn import(
+    meta_spool: &dyn Spool,
+    data_spool: &dyn Spool,
+    format: ExportFormat,
+    input: &Path,
+) -> anyhow::Result<()> {
+    let messages: Box<dyn Iterator<Item = anyhow::Result<ExportedMessage>>> = match format {
+        ExportFormat::Mbox => {
+            let file =
+                File::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+            Box::new(MboxReader::new(BufReader::new(file)))
+        }
+        ExportFormat::Maildir => {
+            let dir = Maildir::from(input.to_path_buf());
+            Box::new(dir.list_new().chain(dir.list_cur()).map(|entry| {
+                let path = entry?.path().clone();
+                let bytes = std::fs::read(&path)?;
+                ExportedMessage::from_bytes(&bytes)
+                    .with_context(|| format!("importing {}", path.display()))
+            }))
+        }
+    };
+
+    let mut imported = 0;
+    let mut failed = 0;
+    for msg in messages {
+        let id = SpoolId::new();
+        let msg = match msg.and_then(|msg| {
+            // Don't allow metadata that kumod would be unable to load
+            Message::new_from_spool(id, msg.meta.clone())?;
+            Ok(msg)
+        }) {
+            Ok(msg) => msg,
+            Err(err) => {
+                eprintln!("ERROR: {err:#}");
+                failed += 1;
+                continue;
+            }
+        };
+
+        data_spool
+            .store(id, Arc::new(msg.data.into_boxed_slice()), true)
+            .await?;
+        meta_spool
+            .store(id, Arc::new(msg.meta.into_boxed_slice()), true)
+            .await?;
+        imported += 1;
+    }
+
+    println!("imported {imported} messages, {failed} failed");
+    Ok(())
+}
+
+async fn verify(
+    meta_spool: &dyn Spool,
+    data_spool: &dyn Spool,
+    quarantine: bool,
+) -> anyhow::Result<()> {
+    let mut meta_ids = HashSet::new();
+    let mut data_ids = HashSet::new();
+    let mut quarantine_meta = HashSet::new();
+    let mut quarantine_data = HashSet::new();
+
+    eprintln!("verifying meta...");
+    let (tx, rx) = flume::bounded(1024);
+    meta_spool.enumerate(tx)?;
+    while let Ok(entry) = rx.recv_async().await {
+        match entry {
+            SpoolEntry::Item { id, data } => match Message::new_from_spool(id, data) {
+                Ok(_) => {
+                    meta_ids.insert(id);
+                }
+                Err(err) => {
+                    eprintln!("ERROR: {id}: invalid metadata: {err:#}");
+                    quarantine_meta.insert(id);
+                }
+            },
+            SpoolEntry::Corrupt { id, error } => {
+                eprintln!("ERROR: {id}: corrupt metadata: {error}");
+                quarantine_meta.insert(id);
+            }
+        }
+    }
+
+    eprintln!("verifying data...");
+    let (tx, rx) = flume::bounded(1024);
+    data_spool.enumerate(tx)?;
+    while let Ok(entry) = rx.recv_async().await {
+        match entry {
+            SpoolEntry::Item { id, .. } => {
+                data_ids.insert(id);
+                if !meta_ids.contains(&id) && !quarantine_meta.contains(&id) {
+                    eprintln!("ERROR: {id}: data has no metadata");
+                    quarantine_data.insert(id);
+                }
+            }
+            SpoolEntry::Corrupt { id, error } => {
+                eprintln!("ERROR: {id}: corrupt data: {error}");
+                quarantine_data.insert(id);
+                if meta_ids.remove(&id) {
+                    quarantine_meta.insert(id);
+                }
+            }
+        }
+    }
+
+    for &id in &meta_ids {
+        if !data_ids.contains(&id) {
+            eprintln!("ERROR: {id}: metadata has no data");
+            quarantine_meta.insert(id);
+        }
+    }
+    // The data of a message with bad metadata cannot be used either
+    for &id in &quarantine_meta {
+        if data_ids.contains(&id) {
+            quarantine_data.insert(id);
+        }
+    }
+
+    let problems = quarantine_meta.union(&quarantine_data).count();
+    let total = meta_ids.union(&quarantine_meta).count();
+    println!("verified {total} messages, {problems} problems found");
+    if problems == 0 {
+        return Ok(());
+    }
+    anyhow::ensure!(
+        quarantine,
+        "{problems} problems found; use --quarantine to move them out of the spool"
+    );
+
+    let mut failed = 0;
+    for (label, spool, ids) in [
+        ("meta", meta_spool, &quarantine_meta),
+        ("data", data_spool, &quarantine_data),
+    ] {
+        for &id in ids {
+            if let Err(err) = spool.quarantine(id).await {
+                eprintln!("ERROR: failed to quarantine {label} for {id}: {err:#}");
+                failed += 1;
+            }
+        }
+    }
+    anyhow::ensure!(failed == 0, "failed to quarantine {failed} entries");
+    println!("quarantined {problems} messages");
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opts = Opt::parse();
 
-    let meta_spool = RocksSpool::new(&opts.meta, false, None, Handle::current())?;
-    let data_spool = RocksSpool::new(&opts.data, false, None, Handle::current())?;
+    let meta_spool = open_spool(opts.kind, &opts.meta)?;
+    let data_spool = open_spool(opts.kind, &opts.data)?;
 
     match opts.cmd {
         SubCommand::MetaSize => {
-            show_size_stats("meta", &meta_spool).await?;
+            show_size_stats("meta", &*meta_spool).await?;
         }
         SubCommand::DataSize => {
-            show_size_stats("data", &data_spool).await?;
+            show_size_stats("data", &*data_spool).await?;
+        }
+        SubCommand::List => {
+            list(&*meta_spool).await?;
+        }
+        SubCommand::Export { format, output } => {
+            export(&*meta_spool, &*data_spool, format, &output).await?;
+        }
+        SubCommand::Import { format, input } => {
+            import(&*meta_spool, &*data_spool, format, &input).await?;
+        }
+        SubCommand::Verify { quarantine } => {
+            verify(&*meta_spool, &*data_spool, quarantine).await?;
         }
     }
 
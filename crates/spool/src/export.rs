@@ -0,0 +1,217 @@
+//! Converts spooled messages to and from a form that can be stored
+//! in an mbox or maildir, so that the contents of a spool can be
+//! inspected with regular mail tooling and recovered into another spool.
+//!
+//! An exported message is its spooled data, prefixed with an
+//! `X-KumoMTA-Spool-Meta` header holding the spooled metadata,
+//! which allows the message to be imported again.
+use crate::SpoolId;
+use anyhow::Context;
+use std::io::{BufRead, Write};
+
+pub const META_HEADER: &str = "X-KumoMTA-Spool-Meta";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportedMessage {
+    /// The serialized metadata, as stored in the meta spool
+    pub meta: Vec<u8>,
+    /// The message content, as stored in the data spool
+    pub data: Vec<u8>,
+}
+
+impl ExportedMessage {
+    /// Returns the data with the metadata header prepended
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        // Round trip through Value to guarantee that the metadata
+        // is represented on a single line
+        let meta: serde_json::Value =
+            serde_json::from_slice(&self.meta).context("metadata is not valid JSON")?;
+        let mut bytes =
+            format!("{META_HEADER}: {}\r\n", serde_json::to_string(&meta)?).into_bytes();
+        bytes.extend_from_slice(&self.data);
+        Ok(bytes)
+    }
+
+    /// Parses the representation produced by to_bytes
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let prefix = format!("{META_HEADER}:");
+        anyhow::ensure!(
+            bytes.len() > prefix.len()
+                && bytes[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes()),
+            "message does not start with a {META_HEADER} header"
+        );
+        let end = bytes
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| anyhow::anyhow!("{META_HEADER} header is not terminated"))?;
+        let meta = std::str::from_utf8(&bytes[prefix.len()..end])
+            .with_context(|| format!("{META_HEADER} header is not UTF-8"))?
+            .trim();
+
+        Ok(Self {
+            meta: meta.as_bytes().to_vec(),
+            data: bytes[end + 1..].to_vec(),
+        })
+    }
+
+    /// The envelope sender to use in the mbox "From " line
+    fn mbox_sender(&self) -> String {
+        serde_json::from_slice::<serde_json::Value>(&self.meta)
+            .ok()
+            .and_then(|meta| meta.get("sender")?.as_str().map(|s| s.to_string()))
+            .filter(|sender| !sender.is_empty() && !sender.contains(char::is_whitespace))
+            .unwrap_or_else(|| "MAILER-DAEMON".to_string())
+    }
+}
+
+/// Returns true if line would be confused with, or is a quoted
+/// form of, the "From " line that separates mbox messages
+fn is_from_line(line: &[u8]) -> bool {
+    let unquoted = match line.iter().position(|&b| b != b'>') {
+        Some(idx) => &line[idx..],
+        None => return false,
+    };
+    unquoted.starts_with(b"From ")
+}
+
+/// Append msg to an mbox. Lines that look like "From " lines are
+/// quoted following the mboxrd convention, so that the content is
+/// recovered unchanged by MboxReader.
+pub fn write_mbox<W: Write>(out: &mut W, id: SpoolId, msg: &ExportedMessage) -> anyhow::Result<()> {
+    let bytes = msg.to_bytes()?;
+    writeln!(
+        out,
+        "From {} {}",
+        msg.mbox_sender(),
+        id.created().format("%a %b %e %H:%M:%S %Y")
+    )?;
+    for line in bytes.split_inclusive(|&b| b == b'\n') {
+        if is_from_line(line) {
+            out.write_all(b">")?;
+        }
+        out.write_all(line)?;
+    }
+    if !bytes.ends_with(b"\n") {
+        out.write_all(b"\n")?;
+    }
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Iterates the messages in an mbox that was produced by write_mbox
+pub struct MboxReader<R> {
+    reader: R,
+    in_message: bool,
+    done: bool,
+}
+
+impl<R: BufRead> MboxReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            in_message: false,
+            done: false,
+        }
+    }
+
+    fn finish(mut message: Vec<u8>) -> anyhow::Result<ExportedMessage> {
+        // Remove the blank line that separates messages
+        if message.ends_with(b"\n\n") {
+            message.pop();
+        }
+        ExportedMessage::from_bytes(&message)
+    }
+}
+
+impl<R: BufRead> Iterator for MboxReader<R> {
+    type Item = anyhow::Result<ExportedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut message = if self.in_message { Some(vec![]) } else { None };
+        loop {
+            let mut line = vec![];
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(0) => {
+                    self.done = true;
+                    return message.map(Self::finish);
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err.into()));
+                }
+            }
+
+            if line.starts_with(b"From ") {
+                self.in_message = true;
+                if let Some(message) = message.take() {
+                    return Some(Self::finish(message));
+                }
+                message = Some(vec![]);
+                continue;
+            }
+
+            // Anything prior to the first "From " line is ignored
+            if let Some(message) = &mut message {
+                if is_from_line(&line) {
+                    message.extend_from_slice(&line[1..]);
+                } else {
+                    message.extend_from_slice(&line);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_message(body: &str) -> ExportedMessage {
+        ExportedMessage {
+            meta: br#"{"sender":"sender@example.com","recipient":"rcpt@example.com"}"#.to_vec(),
+            data: format!("Subject: hello\r\n\r\n{body}").into_bytes(),
+        }
+    }
+
+    #[test]
+    fn mbox_round_trip() -> anyhow::Result<()> {
+        let messages = vec![
+            make_message("From the start\r\n>From quoted\r\nplain\r\n"),
+            make_message("no trailing newline"),
+            make_message("\r\n"),
+        ];
+
+        let mut mbox = vec![];
+        for msg in &messages {
+            write_mbox(&mut mbox, SpoolId::new(), msg)?;
+        }
+
+        let text = String::from_utf8(mbox.clone())?;
+        assert!(text.starts_with("From sender@example.com "));
+        assert!(text.contains("\r\n>From the start\r\n>>From quoted\r\n"));
+
+        let read: Vec<ExportedMessage> =
+            MboxReader::new(mbox.as_slice()).collect::<anyhow::Result<_>>()?;
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0], messages[0]);
+        // A newline is added to terminate the final line
+        assert_eq!(read[1].data, b"Subject: hello\r\n\r\nno trailing newline\n");
+        assert_eq!(read[2], messages[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_meta() {
+        assert_eq!(
+            format!(
+                "{:#}",
+                ExportedMessage::from_bytes(b"Subject: hello\r\n\r\n").unwrap_err()
+            ),
+            "message does not start with a X-KumoMTA-Spool-Meta header"
+        );
+    }
+}
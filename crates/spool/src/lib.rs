@@ -3,6 +3,7 @@ use flume::Sender;
 use once_cell::sync::OnceCell;
 use std::sync::Arc;
 
+pub mod export;
 pub mod local_disk;
 #[cfg(feature = "rocksdb")]
 pub mod rocks;
@@ -32,6 +33,11 @@ pub trait Spool: Send + Sync {
         force_sync: bool,
     ) -> anyhow::Result<()>;
 
+    /// Move the data associated with the provided Id out of the spool
+    /// and into its quarantine area, so that it is no longer enumerated
+    /// but remains available for offline inspection and recovery
+    async fn quarantine(&self, id: SpoolId) -> anyhow::Result<()>;
+
     /// Scan the contents of the spool, and emit a SpoolEntry for each item
     /// to the provided channel sender.
     /// The items are enumerated in an unspecified order.
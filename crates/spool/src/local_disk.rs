@@ -42,6 +42,10 @@ impl LocalDiskSpool {
         id.compute_path(&self.path.join("data"))
     }
 
+    fn compute_quarantine_path(&self, id: SpoolId) -> PathBuf {
+        self.path.join("quarantine").join(id.to_string())
+    }
+
     fn cleanup_dirs(path: &Path) {
         let new_dir = path.join("new");
         for entry in jwalk::WalkDir::new(new_dir) {
@@ -92,6 +96,17 @@ impl Spool for LocalDiskSpool {
             .with_context(|| format!("failed to remove {id} from {path:?}"))
     }
 
+    async fn quarantine(&self, id: SpoolId) -> anyhow::Result<()> {
+        let path = self.compute_path(id);
+        let quarantine_path = self.compute_quarantine_path(id);
+        tokio::fs::create_dir_all(quarantine_path.parent().unwrap())
+            .await
+            .with_context(|| format!("failed to create quarantine dir for {id}"))?;
+        tokio::fs::rename(&path, &quarantine_path)
+            .await
+            .with_context(|| format!("failed to move {id} from {path:?} to {quarantine_path:?}"))
+    }
+
     async fn store(
         &self,
         id: SpoolId,
@@ -355,4 +370,28 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn quarantine() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let spool = LocalDiskSpool::new(&location.path(), false, Handle::current())?;
+
+        let id = SpoolId::new();
+        spool
+            .store(id, Arc::new(b"corrupt".to_vec().into_boxed_slice()), false)
+            .await?;
+        spool.quarantine(id).await?;
+
+        assert!(spool.load(id).await.is_err());
+        assert_eq!(
+            std::fs::read(location.path().join("quarantine").join(id.to_string()))?,
+            b"corrupt"
+        );
+
+        let (tx, rx) = flume::bounded(32);
+        spool.enumerate(tx)?;
+        assert!(rx.recv_async().await.is_err());
+
+        Ok(())
+    }
 }
@@ -2,13 +2,16 @@ use crate::{Spool, SpoolEntry, SpoolId};
 use async_trait::async_trait;
 use flume::Sender;
 use rocksdb::{
-    DBCompressionType, ErrorKind, IteratorMode, LogLevel, Options, WriteBatch, WriteOptions, DB,
+    DBCompressionType, ErrorKind, IteratorMode, LogLevel, Options, ReadOptions, WriteBatch,
+    WriteOptions, DB,
 };
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 use tokio::runtime::Handle;
+use tokio::sync::watch;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RocksSpoolParams {
@@ -51,6 +54,13 @@ pub struct RocksSpoolParams {
         default = "RocksSpoolParams::default_obsolete_files_period"
     )]
     pub obsolete_files_period: Duration,
+
+    /// If set, every write is made durable before it is acknowledged,
+    /// but rather than syncing the write-ahead-log for each individual
+    /// write, the log is synced once per interval on behalf of all of
+    /// the writes that were made during that interval.
+    #[serde(default, with = "duration_serde")]
+    pub batch_sync_interval: Option<Duration>,
 }
 
 impl Default for RocksSpoolParams {
@@ -68,6 +78,7 @@ impl Default for RocksSpoolParams {
             memtable_huge_page_size: None,
             log_file_time_to_roll: Self::default_log_file_time_to_roll(),
             obsolete_files_period: Self::default_obsolete_files_period(),
+            batch_sync_interval: None,
         }
     }
 }
@@ -146,7 +157,85 @@ impl Into<LogLevel> for LogLevelDef {
 
 pub struct RocksSpool {
     db: Arc<DB>,
+    path: PathBuf,
     runtime: Handle,
+    batch_sync: Option<Arc<BatchSync>>,
+}
+
+/// Coordinates group commit of the write-ahead-log.
+/// Writers record the generation of the next sync and wait for
+/// the sync thread to report that it has completed.
+struct BatchSync {
+    /// The generation number that will be assigned to the next sync
+    next: AtomicU64,
+    /// Set when there are writes that have not yet been synced
+    pending: AtomicBool,
+    /// The outcome of the most recently completed sync
+    completed: watch::Sender<SyncStatus>,
+}
+
+#[derive(Clone, Default)]
+struct SyncStatus {
+    /// The generation number of the sync
+    generation: u64,
+    /// The error, if the sync failed
+    error: Option<Arc<String>>,
+}
+
+impl BatchSync {
+    fn start(db: &Arc<DB>, interval: Duration) -> anyhow::Result<Arc<Self>> {
+        let (completed, _) = watch::channel(SyncStatus::default());
+        let sync = Arc::new(Self {
+            next: AtomicU64::new(1),
+            pending: AtomicBool::new(false),
+            completed,
+        });
+
+        let db = Arc::downgrade(db);
+        let weak_sync = Arc::downgrade(&sync);
+        std::thread::Builder::new()
+            .name("rocksdb batch sync".to_string())
+            .spawn(move || Self::run(db, weak_sync, interval))?;
+
+        Ok(sync)
+    }
+
+    fn run(db: Weak<DB>, sync: Weak<Self>, interval: Duration) {
+        loop {
+            std::thread::sleep(interval);
+            let (Some(db), Some(sync)) = (db.upgrade(), sync.upgrade()) else {
+                return;
+            };
+            if !sync.pending.swap(false, Ordering::SeqCst) {
+                continue;
+            }
+            let generation = sync.next.fetch_add(1, Ordering::SeqCst);
+            // Report a failure to the waiters, rather than leaving them
+            // pending for a sync that may never succeed
+            let error = db
+                .flush_wal(true)
+                .err()
+                .map(|err| Arc::new(format!("{err:#}")));
+            sync.completed
+                .send_replace(SyncStatus { generation, error });
+        }
+    }
+
+    /// Wait until a sync covering all writes completed prior to
+    /// this call has been performed
+    async fn wait(&self) -> anyhow::Result<()> {
+        let mut rx = self.completed.subscribe();
+        let target = self.next.load(Ordering::SeqCst);
+        self.pending.store(true, Ordering::SeqCst);
+        let status = rx
+            .wait_for(|status| status.generation >= target)
+            .await?
+            .clone();
+        match status.error {
+            Some(err) => anyhow::bail!("rocksdb batch sync failed: {err}"),
+            None => Ok(()),
+        }
+    }
 }
 
 impl RocksSpool {
@@ -189,8 +278,17 @@ impl RocksSpool {
         opts.set_delete_obsolete_files_period_micros(p.obsolete_files_period.as_micros() as u64);
 
         let db = Arc::new(DB::open(&opts, path)?);
-
-        Ok(Self { db, runtime })
+        let batch_sync = match p.batch_sync_interval {
+            Some(interval) => Some(BatchSync::start(&db, interval)?),
+            None => None,
+        };
+
+        Ok(Self {
+            db,
+            path: path.to_path_buf(),
+            runtime,
+            batch_sync,
+        })
     }
 }
 
@@ -218,12 +316,12 @@ impl Spool for RocksSpool {
         force_sync: bool,
     ) -> anyhow::Result<()> {
         let mut opts = WriteOptions::default();
-        opts.set_sync(force_sync);
+        opts.set_sync(force_sync && self.batch_sync.is_none());
         opts.set_no_slowdown(true);
         let mut batch = WriteBatch::default();
         batch.put(id.as_bytes(), &*data);
 
-        match self.db.write_opt(batch, &opts) {
+        let result = match self.db.write_opt(batch, &opts) {
             Ok(()) => Ok(()),
             Err(err) if err.kind() == ErrorKind::Incomplete => {
                 let db = self.db.clone();
@@ -241,6 +339,11 @@ impl Spool for RocksSpool {
                     .await?
             }
             Err(err) => Err(err.into()),
+        };
+
+        match (result, &self.batch_sync) {
+            (Ok(()), Some(sync)) if force_sync => sync.wait().await,
+            (result, _) => result,
         }
     }
 
@@ -271,6 +374,45 @@ impl Spool for RocksSpool {
         }
     }
 
+    async fn quarantine(&self, id: SpoolId) -> anyhow::Result<()> {
+        // The quarantined entries are kept as individual files alongside
+        // the database, where they can be inspected without opening it
+        let db = self.db.clone();
+        let quarantine_dir = self.path.join("quarantine");
+        tokio::task::Builder::new()
+            .name("rocksdb quarantine")
+            .spawn_blocking_on(
+                move || {
+                    std::fs::create_dir_all(&quarantine_dir)?;
+                    // The entry is most likely being quarantined because it
+                    // is corrupt, so take its raw bytes as they are, without
+                    // verifying the checksums of the blocks that hold them.
+                    let mut opts = ReadOptions::default();
+                    opts.set_verify_checksums(false);
+                    match db.get_opt(id.as_bytes(), &opts) {
+                        Ok(Some(data)) => {
+                            std::fs::write(quarantine_dir.join(id.to_string()), data)?;
+                        }
+                        Ok(None) => anyhow::bail!("no such key {id}"),
+                        Err(err) => {
+                            // The value cannot be read at all; record why,
+                            // so that the entry stops being enumerated but
+                            // its loss is accounted for
+                            std::fs::write(
+                                quarantine_dir.join(format!("{id}.unreadable")),
+                                format!("{err:#}"),
+                            )?;
+                        }
+                    }
+                    let mut batch = WriteBatch::default();
+                    batch.delete(id.as_bytes());
+                    Ok(db.write(batch)?)
+                },
+                &self.runtime,
+            )?
+            .await?
+    }
+
     async fn cleanup(&self) -> anyhow::Result<()> {
         Ok(())
     }
@@ -284,8 +426,10 @@ impl Spool for RocksSpool {
                     let iter = db.iterator(IteratorMode::Start);
                     for entry in iter {
                         let (key, value) = entry?;
-                        let id = SpoolId::from_slice(&key)
-                            .ok_or_else(|| anyhow::anyhow!("invalid spool id {key:?}"))?;
+                        let Some(id) = SpoolId::from_slice(&key) else {
+                            eprintln!("{key:?} is not a spool id");
+                            continue;
+                        };
                         sender
                             .send(SpoolEntry::Item {
                                 id,
@@ -307,6 +451,97 @@ impl Spool for RocksSpool {
 mod test {
     use super::*;
 
+    #[tokio::test]
+    async fn batch_sync_failure_wakes_waiters() {
+        let (completed, _) = watch::channel(SyncStatus::default());
+        let sync = Arc::new(BatchSync {
+            next: AtomicU64::new(1),
+            pending: AtomicBool::new(false),
+            completed,
+        });
+
+        let waiter = tokio::spawn({
+            let sync = sync.clone();
+            async move { sync.wait().await }
+        });
+        while !sync.pending.load(Ordering::SeqCst) {
+            tokio::task::yield_now().await;
+        }
+
+        let generation = sync.next.fetch_add(1, Ordering::SeqCst);
+        sync.completed.send_replace(SyncStatus {
+            generation,
+            error: Some(Arc::new("disk on fire".to_string())),
+        });
+        let err = waiter.await.unwrap().unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "rocksdb batch sync failed: disk on fire"
+        );
+    }
+
+    #[tokio::test]
+    async fn rocks_spool_quarantine() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let spool = RocksSpool::new(&location.path(), false, None, Handle::current())?;
+
+        // The content is opaque to the spool, so undecodable garbage
+        // is quarantined verbatim
+        let id = SpoolId::new();
+        let garbage = vec![0xff, 0x00, 0xfe];
+        spool
+            .store(id, Arc::new(garbage.clone().into_boxed_slice()), false)
+            .await?;
+        spool.quarantine(id).await?;
+
+        assert!(spool.load(id).await.is_err());
+        assert_eq!(
+            std::fs::read(location.path().join("quarantine").join(id.to_string()))?,
+            garbage
+        );
+        assert!(spool.quarantine(SpoolId::new()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rocks_spool_batch_sync() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let params = RocksSpoolParams {
+            batch_sync_interval: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let spool = Arc::new(RocksSpool::new(
+            &location.path(),
+            false,
+            Some(params),
+            Handle::current(),
+        )?);
+
+        let mut tasks = vec![];
+        for i in 0..10 {
+            let spool = spool.clone();
+            tasks.push(tokio::spawn(async move {
+                let id = SpoolId::new();
+                spool
+                    .store(
+                        id,
+                        Arc::new(format!("I am {i}").as_bytes().to_vec().into_boxed_slice()),
+                        i % 2 == 0,
+                    )
+                    .await
+                    .map(|_| (i, id))
+            }));
+        }
+
+        for task in tasks {
+            let (i, id) = task.await??;
+            let text = String::from_utf8(spool.load(id).await?)?;
+            assert_eq!(text, format!("I am {i}"));
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn rocks_spool() -> anyhow::Result<()> {
         let location = tempfile::tempdir()?;
@@ -1,7 +1,8 @@
 //! This crate implements a throttling API based on a generic cell rate algorithm.
-//! The implementation uses an in-memory store, but can be adjusted in the future
-//! to support using a redis-cell equipped redis server to share the throttles
-//! among multiple machines.
+//! The implementation uses an in-memory store by default, but can be configured
+//! to use a redis-cell equipped redis server to share the throttles among
+//! multiple machines.  If the redis server becomes unreachable, the in-memory
+//! store is used until it becomes available again.
 #[cfg(feature = "impl")]
 use mod_redis::{Cmd, FromRedisValue, RedisConnection, RedisError};
 #[cfg(feature = "impl")]
@@ -13,6 +14,8 @@ use std::convert::TryFrom;
 #[cfg(feature = "impl")]
 use std::sync::Mutex;
 use std::time::Duration;
+#[cfg(feature = "impl")]
+use std::time::Instant;
 use thiserror::Error;
 
 #[cfg(feature = "impl")]
@@ -22,6 +25,13 @@ pub mod limit;
 static MEMORY: OnceCell<Mutex<MemoryStore>> = OnceCell::new();
 #[cfg(feature = "impl")]
 static REDIS: OnceCell<RedisConnection> = OnceCell::new();
+/// When set, redis was found to be unreachable and should not be
+/// tried again until this time has passed
+#[cfg(feature = "impl")]
+static REDIS_RETRY_AT: Mutex<Option<Instant>> = Mutex::new(None);
+/// How long to use local state after failing to reach redis
+#[cfg(feature = "impl")]
+const REDIS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -300,13 +310,56 @@ pub async fn throttle(
 ) -> Result<ThrottleResult, Error> {
     if force_local {
         local_throttle(key, limit, period, max_burst, quantity)
-    } else if let Some(redis) = REDIS.get().cloned() {
-        redis_throttle(redis, key, limit, period, max_burst, quantity).await
+    } else if let Some(redis) = redis_backend() {
+        match redis_throttle(redis, key, limit, period, max_burst, quantity).await {
+            Err(Error::AnyHow(err)) => {
+                redis_unavailable(&err);
+                local_throttle(key, limit, period, max_burst, quantity)
+            }
+            result => {
+                redis_available();
+                result
+            }
+        }
     } else {
         local_throttle(key, limit, period, max_burst, quantity)
     }
 }
 
+/// Returns the redis connection that should be used to share state,
+/// or None if redis is not configured or was recently found to be
+/// unreachable, in which case the local in-memory store should be used.
+#[cfg(feature = "impl")]
+pub(crate) fn redis_backend() -> Option<RedisConnection> {
+    let redis = REDIS.get()?;
+    let retry_at = REDIS_RETRY_AT.lock().unwrap();
+    match *retry_at {
+        Some(when) if Instant::now() < when => None,
+        _ => Some(redis.clone()),
+    }
+}
+
+/// Record that redis could not be reached, so that the local store
+/// is used for the next REDIS_RETRY_INTERVAL.
+#[cfg(feature = "impl")]
+pub(crate) fn redis_unavailable(err: &anyhow::Error) {
+    let mut retry_at = REDIS_RETRY_AT.lock().unwrap();
+    if retry_at.is_none() {
+        tracing::error!(
+            "redis throttle backend is unavailable, \
+             falling back to local throttles: {err:#}"
+        );
+    }
+    retry_at.replace(Instant::now() + REDIS_RETRY_INTERVAL);
+}
+
+#[cfg(feature = "impl")]
+pub(crate) fn redis_available() {
+    if REDIS_RETRY_AT.lock().unwrap().take().is_some() {
+        tracing::info!("redis throttle backend is available again");
+    }
+}
+
 #[cfg(feature = "impl")]
 pub fn use_redis(conn: RedisConnection) -> Result<(), Error> {
     REDIS
@@ -1,4 +1,4 @@
-use crate::{Error, REDIS};
+use crate::{redis_available, redis_backend, redis_unavailable, Error, REDIS};
 use anyhow::{anyhow, Context};
 use mod_redis::{RedisConnection, Script};
 use once_cell::sync::{Lazy, OnceCell};
@@ -59,8 +59,17 @@ enum Backend {
 
 impl LimitSpec {
     pub async fn acquire_lease<S: AsRef<str>>(&self, key: S) -> Result<LimitLease, Error> {
-        if let Some(redis) = REDIS.get().cloned() {
-            self.acquire_lease_redis(redis, key.as_ref()).await
+        if let Some(redis) = redis_backend() {
+            match self.acquire_lease_redis(redis, key.as_ref()).await {
+                Err(Error::AnyHow(err)) => {
+                    redis_unavailable(&err);
+                    self.acquire_lease_memory(key.as_ref()).await
+                }
+                result => {
+                    redis_available();
+                    result
+                }
+            }
         } else {
             self.acquire_lease_memory(key.as_ref()).await
         }
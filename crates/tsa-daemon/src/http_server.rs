@@ -6,7 +6,9 @@ use axum::{Json, Router};
 use chrono::DateTime;
 use config::CallbackSignature;
 use kumo_api_types::shaping::{Action, EgressPathConfigValue, Regex, Rule, Shaping, Trigger};
-use kumo_api_types::tsa::{ReadyQSuspension, SchedQSuspension, SuspensionEntry, Suspensions};
+use kumo_api_types::tsa::{
+    ReadyQSuspension, SchedQSuspension, SourceExclusion, SuspensionEntry, Suspensions,
+};
 use kumo_log_types::*;
 use kumo_server_common::http_server::auth::TrustedIpRequired;
 use kumo_server_common::http_server::{AppError, RouterAndDocs};
@@ -75,6 +77,15 @@ CREATE TABLE IF NOT EXISTS sched_q_suspensions (
     PRIMARY KEY (rule_hash, campaign, tenant, domain)
 );
 
+CREATE TABLE IF NOT EXISTS source_exclusions (
+    rule_hash text,
+    source text,
+    destination text,
+    reason text,
+    expires DATETIME,
+    PRIMARY KEY (rule_hash, source, destination)
+);
+
     "#;
 
     db.execute(query)?;
@@ -257,6 +268,60 @@ fn create_ready_q_suspension(
     Ok(())
 }
 
+fn create_source_exclusion(
+    rule_hash: &str,
+    rule: &Rule,
+    record: &JsonLogRecord,
+) -> anyhow::Result<()> {
+    let Some(source) = record.egress_source.as_deref() else {
+        tracing::error!(
+            "Cannot create source exclusion for {rule:?} \
+             because there is no egress_source in the record {record:?}"
+        );
+        return Ok(());
+    };
+
+    // Exclude the source from the provider as a whole when the
+    // destination is associated with one, as blocklisting is
+    // typically applied across all of the provider's MX hosts
+    let destination = record
+        .provider_name
+        .as_deref()
+        .unwrap_or(record.site.as_str());
+
+    let mut upsert = HISTORY.prepare(
+        "INSERT INTO source_exclusions
+                 (rule_hash, source, destination, reason, expires)
+                 VALUES
+                 ($hash, $source, $destination, $reason, $expires)
+                 ON CONFLICT (rule_hash, source, destination)
+                 DO UPDATE SET expires=$expires",
+    )?;
+
+    let expires = record.timestamp + chrono::Duration::from_std(rule.duration)?;
+    let expires_str = expires.to_rfc3339();
+
+    upsert.bind(("$hash", rule_hash))?;
+    upsert.bind(("$source", source))?;
+    upsert.bind(("$destination", destination))?;
+
+    let reason = format!("automation rule: {}", regex_list_to_string(&rule.regex));
+    upsert.bind(("$reason", reason.as_str()))?;
+    upsert.bind(("$expires", expires_str.as_str()))?;
+
+    upsert.next()?;
+
+    SuspensionSubscriberMgr::submit(SuspensionEntry::SourceExclusion(SourceExclusion {
+        rule_hash: rule_hash.to_string(),
+        source: source.to_string(),
+        destination: destination.to_string(),
+        reason,
+        expires,
+    }));
+
+    Ok(())
+}
+
 fn insert_record(rule_hash: &str, record: &JsonLogRecord, record_hash: &str) -> anyhow::Result<()> {
     let unix: i64 = record.timestamp.format("%s").to_string().parse()?;
     let mut insert = HISTORY
@@ -365,6 +430,9 @@ async fn publish_log_v1_impl(record: JsonLogRecord) -> anyhow::Result<()> {
                     Action::SuspendCampaign => {
                         create_tenant_suspension(&rule_hash, m, &record, true)?;
                     }
+                    Action::ExcludeSource => {
+                        create_source_exclusion(&rule_hash, m, &record)?;
+                    }
                     Action::SetConfig(config) => {
                         create_config(&rule_hash, m, &record, config, &domain, &source)?;
                     }
@@ -634,6 +702,30 @@ async fn do_get_suspension() -> anyhow::Result<Json<Suspensions>> {
 
     suspensions.sched_q = by_rule_hash.into_iter().map(|(_, v)| v).collect();
 
+    let mut stmt = HISTORY.prepare(
+        "SELECT * from source_exclusions where
+                                   unixepoch(expires) - unixepoch() > 0
+                                   order by expires, destination, source",
+    )?;
+
+    while let Ok(sqlite::State::Row) = stmt.next() {
+        let rule_hash: String = stmt.read("rule_hash")?;
+        let source: String = stmt.read("source")?;
+        let destination: String = stmt.read("destination")?;
+        let reason: String = stmt.read("reason")?;
+        let expires: String = stmt.read("expires")?;
+
+        let expires = DateTime::parse_from_rfc3339(&expires)?.to_utc();
+
+        suspensions.source_exclusions.push(SourceExclusion {
+            rule_hash,
+            source,
+            destination,
+            reason,
+            expires,
+        });
+    }
+
     Ok(Json(suspensions))
 }
 
@@ -670,6 +762,10 @@ async fn process_suspension_subscription_inner(mut socket: WebSocket) -> anyhow:
             let json = serde_json::to_string(&SuspensionEntry::ReadyQ(record.clone()))?;
             socket.send(Message::Text(json)).await?;
         }
+        for record in &suspensions.source_exclusions {
+            let json = serde_json::to_string(&SuspensionEntry::SourceExclusion(record.clone()))?;
+            socket.send(Message::Text(json)).await?;
+        }
     }
 
     // then wait for more to show up
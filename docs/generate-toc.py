@@ -355,10 +355,34 @@ TOC = [
                 "module: kumo.amqp",
                 "reference/kumo.amqp",
             ),
+            Gen(
+                "module: kumo.api.admin.bounce",
+                "reference/kumo.api.admin.bounce",
+            ),
+            Gen(
+                "module: kumo.api.admin.rebind",
+                "reference/kumo.api.admin.rebind",
+            ),
             Gen(
                 "module: kumo.api.inject",
                 "reference/kumo.api.inject",
             ),
+            Gen(
+                "module: kumo.bounce_alias",
+                "reference/kumo.bounce_alias",
+            ),
+            Gen(
+                "module: kumo.connection_filter",
+                "reference/kumo.connection_filter",
+            ),
+            Gen(
+                "module: kumo.counter",
+                "reference/kumo.counter",
+            ),
+            Gen(
+                "module: kumo.delivery_history",
+                "reference/kumo.delivery_history",
+            ),
             Gen(
                 "module: kumo.digest",
                 "reference/kumo.digest",
@@ -395,10 +419,18 @@ TOC = [
                 "module: kumo.regex_set_map",
                 "reference/kumo.regex_set_map",
             ),
+            Gen(
+                "module: kumo.reputation",
+                "reference/kumo.reputation",
+            ),
             Gen(
                 "module: kumo.secrets",
                 "reference/kumo.secrets",
             ),
+            Gen(
+                "module: kumo.seeds",
+                "reference/kumo.seeds",
+            ),
             Gen(
                 "module: kumo.serde",
                 "reference/kumo.serde",
@@ -407,10 +439,26 @@ TOC = [
                 "module: kumo.shaping",
                 "reference/kumo.shaping",
             ),
+            Gen(
+                "module: kumo.source_health",
+                "reference/kumo.source_health",
+            ),
+            Gen(
+                "module: kumo.suppression",
+                "reference/kumo.suppression",
+            ),
+            Gen(
+                "module: kumo.template",
+                "reference/kumo.template",
+            ),
             Gen(
                 "module: kumo.uuid",
                 "reference/kumo.uuid",
             ),
+            Gen(
+                "module: kumo.warmup",
+                "reference/kumo.warmup",
+            ),
             Gen(
                 "module: redis",
                 "reference/redis",